@@ -11,21 +11,37 @@ use std::{
     ops::{self, Bound, RangeBounds},
 };
 
+#[cfg(not(any(feature = "miri", target_family = "wasm")))]
 use memmap2::MmapMut;
 
 pub const BUF_SIZE: u16 = 4096;
 
-#[cfg(not(feature = "miri"))]
+#[cfg(not(any(feature = "miri", target_family = "wasm")))]
 pub const NUM_BUF: u32 = 64 * 1024;
 
+// there's no mmap on wasm: the pool is a regular (eagerly committed)
+// allocation, so keep it small.
+#[cfg(all(target_family = "wasm", not(feature = "miri")))]
+pub const NUM_BUF: u32 = 4 * 1024;
+
 #[cfg(feature = "miri")]
 pub const NUM_BUF: u32 = 64;
 
 thread_local! {
     static BUF_POOL: BufPool = const { BufPool::new_empty(BUF_SIZE, NUM_BUF) };
+    #[cfg(not(any(feature = "miri", target_family = "wasm")))]
     static BUF_POOL_DESTRUCTOR: RefCell<Option<MmapMut>> = const { RefCell::new(None) };
 }
 
+/// How many buffers of the current thread's pool are not in use, out of
+/// [NUM_BUF]. This doesn't allocate the pool if it wasn't already.
+pub fn num_free_bufs() -> usize {
+    BUF_POOL.with(|bp| match bp.inner.borrow().as_ref() {
+        Some(inner) => inner.free.len(),
+        None => bp.num_buf as usize,
+    })
+}
+
 type Result<T, E = Error> = std::result::Result<T, E>;
 
 #[derive(thiserror::Error, Debug)]
@@ -110,14 +126,14 @@ impl BufPool {
 
             let ptr: *mut u8;
 
-            #[cfg(feature = "miri")]
+            #[cfg(any(feature = "miri", target_family = "wasm"))]
             {
                 let mut map = vec![0; len];
                 ptr = map.as_mut_ptr();
                 std::mem::forget(map);
             }
 
-            #[cfg(not(feature = "miri"))]
+            #[cfg(not(any(feature = "miri", target_family = "wasm")))]
             {
                 let mut map = memmap2::MmapOptions::new().len(len).map_anon()?;
                 ptr = map.as_mut_ptr();
@@ -208,6 +224,7 @@ impl BufMut {
 
             _non_send: PhantomData,
         };
+        BUF_POOL.with(|bp| bp.inc(self.index));
 
         b.slice(range)
     }
@@ -491,6 +508,29 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn freeze_slice_test() -> eyre::Result<()> {
+        let total_bufs = BUF_POOL.with(|bp| bp.num_free())?;
+        let mut bm = BufMut::alloc().unwrap();
+        bm[..11].copy_from_slice(b"hello world");
+
+        // the slice holds a reference of its own: the buffer must stay
+        // allocated until both are gone, and be freed exactly once
+        let b = bm.freeze_slice(6..11);
+        drop(bm);
+        assert_eq!(&b[..], b"world");
+        assert_eq!(total_bufs - 1, BUF_POOL.with(|bp| bp.num_free())?);
+
+        let b2 = BufMut::alloc().unwrap();
+        assert_ne!(b2.index, b.index, "buffer handed out while still in use");
+        drop(b2);
+
+        drop(b);
+        assert_eq!(total_bufs, BUF_POOL.with(|bp| bp.num_free())?);
+
+        Ok(())
+    }
+
     #[test]
     fn split_test() -> eyre::Result<()> {
         let total_bufs = BUF_POOL.with(|bp| bp.num_free())?;
@@ -133,6 +133,31 @@ impl Piece {
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
+
+    /// Splits this piece in two at `at`, which must be in `0..=len`. Only
+    /// `Vec` and `HeaderName` pieces get copied.
+    pub fn split_at(self, at: usize) -> (Piece, Piece) {
+        assert!(at <= self.len(), "split point out of bounds");
+
+        match self {
+            Piece::Static(s) => {
+                let (left, right) = s.split_at(at);
+                (left.into(), right.into())
+            }
+            Piece::Vec(mut v) => {
+                let right = v.split_off(at);
+                (v.into(), right.into())
+            }
+            Piece::Roll(r) => {
+                let (left, right) = r.split_at(at);
+                (left.into(), right.into())
+            }
+            Piece::HeaderName(name) => {
+                let (left, right) = name.as_str().as_bytes().split_at(at);
+                (left.to_vec().into(), right.to_vec().into())
+            }
+        }
+    }
 }
 
 /// A list of [Piece], suitable for issuing vectored writes via io_uring.
@@ -133,14 +133,33 @@ impl RollMut {
         })
     }
 
+    /// A buffer without any storage, that takes one from the pool the first
+    /// time it's asked to make room. Lets owners give their buffer back while
+    /// they have nothing to put in it.
+    pub fn empty() -> Self {
+        Self {
+            storage: StorageMut::Box(BoxStorage {
+                buf: Rc::new(UnsafeCell::new(Vec::new().into_boxed_slice())),
+                off: 0,
+            }),
+            len: 0,
+        }
+    }
+
     /// Double the capacity of this buffer by reallocating it, copying the
     /// filled part into the new buffer. This method always uses a `Box<[u8]>`
     /// for storage.
     ///
     /// This method is somewhat expensive.
     pub fn grow(&mut self) {
-        let old_cap = self.storage.cap();
-        let new_cap = old_cap * 2;
+        self.grow_to(self.storage.cap() * 2)
+    }
+
+    /// Like [RollMut::grow], but reallocates to a storage size of `new_cap`
+    /// in one go, so that callers who know how much they need don't pay for
+    /// a copy at every doubling.
+    fn grow_to(&mut self, new_cap: usize) {
+        debug_assert!(new_cap >= self.len());
         // TODO: optimize via `MaybeUninit`?
         let b = vec![0; new_cap].into_boxed_slice();
         let mut bs = BoxStorage {
@@ -198,6 +217,12 @@ impl RollMut {
             return Ok(());
         }
 
+        if self.storage_size() == 0 {
+            trace!("in reserve: picking up a buffer for an empty roll");
+            self.storage = StorageMut::Buf(BufMut::alloc()?);
+            return Ok(());
+        }
+
         if self.len() < self.storage_size() {
             // we don't need to go up a buffer size
             trace!(len = %self.len(), cap = %self.cap(), storage_size = %self.storage_size(), "in reserve: reallocating");
@@ -210,18 +235,27 @@ impl RollMut {
         Ok(())
     }
 
-    /// Make sure we can hold "request_len"
+    /// Make sure we can hold "request_len". This reallocates at most once,
+    /// no matter how far `requested_len` is from the current capacity.
     pub fn reserve_at_least(&mut self, requested_len: usize) -> Result<(), eyre::Error> {
-        while self.cap() < requested_len {
-            if self.cap() < self.storage_size() {
-                // we don't need to go up a buffer size
-                self.realloc()?
-            } else {
-                self.grow()
-            }
+        if self.cap() >= requested_len {
+            return Ok(());
         }
 
-        Ok(())
+        let total_len = self.len() + requested_len;
+        if self.storage_size() == 0 && total_len <= BUF_SIZE as usize {
+            trace!(%requested_len, "in reserve_at_least: picking up a buffer for an empty roll");
+            self.storage = StorageMut::Buf(BufMut::alloc()?);
+            Ok(())
+        } else if total_len <= self.storage_size() {
+            // we don't need to go up a buffer size
+            self.realloc()
+        } else {
+            let new_cap = std::cmp::max(total_len, self.storage_size() * 2).next_power_of_two();
+            trace!(len = %self.len(), %requested_len, %new_cap, "in reserve_at_least: growing");
+            self.grow_to(new_cap);
+            Ok(())
+        }
     }
 
     /// The length (filled portion) of this buffer, that can be read
@@ -1038,6 +1072,59 @@ mod tests {
         test_roll_realloc_inner(rm);
     }
 
+    #[test]
+    fn test_roll_reserve_at_least() {
+        let mut rm = RollMut::alloc().unwrap();
+        rm.put("hello").unwrap();
+
+        rm.reserve_at_least(10).unwrap();
+        assert_eq!(rm.storage_size(), BUF_SIZE as usize);
+
+        let requested = BUF_SIZE as usize * 5;
+        rm.reserve_at_least(requested).unwrap();
+        assert!(rm.cap() >= requested);
+        assert_eq!(rm.storage_size(), (requested + 5).next_power_of_two());
+        assert_eq!(&rm[..], b"hello");
+    }
+
+    #[test]
+    fn test_roll_empty() {
+        let mut rm = RollMut::empty();
+        assert_eq!(rm.cap(), 0);
+        rm.reserve().unwrap();
+        assert_eq!(rm.cap(), BUF_SIZE as usize);
+
+        let mut rm = RollMut::empty();
+        let roll = rm
+            .put_to_roll(5, |slice| {
+                slice.copy_from_slice(b"hello");
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(&roll[..], b"hello");
+        assert_eq!(rm.storage_size(), BUF_SIZE as usize);
+
+        let mut rm = RollMut::empty();
+        let requested = BUF_SIZE as usize * 3;
+        rm.reserve_at_least(requested).unwrap();
+        assert!(rm.cap() >= requested);
+    }
+
+    #[test]
+    fn test_roll_filled_outlives_roll_mut() {
+        let mut rm = RollMut::alloc().unwrap();
+        let num_free = crate::num_free_bufs();
+        rm.put(b"hello").unwrap();
+
+        let filled = rm.filled();
+        drop(rm);
+        assert_eq!(&filled[..], b"hello");
+        assert_eq!(crate::num_free_bufs(), num_free);
+
+        drop(filled);
+        assert_eq!(crate::num_free_bufs(), num_free + 1);
+    }
+
     #[test]
     fn test_roll_realloc_big() {
         let mut rm = RollMut::alloc().unwrap();
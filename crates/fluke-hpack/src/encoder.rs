@@ -47,6 +47,7 @@
 use std::io;
 use std::num::Wrapping;
 
+use super::huffman::{huffman_encode_into, huffman_encoded_len};
 use super::HeaderTable;
 use super::STATIC_TABLE;
 
@@ -170,6 +171,33 @@ pub fn encode_integer(value: usize, prefix_size: u8) -> Vec<u8> {
 pub struct Encoder<'a> {
     /// The header table represents the encoder's context
     header_table: HeaderTable<'a>,
+
+    /// The smallest and the latest maximum table size set since the last
+    /// header block, which the decoder hasn't been told about yet
+    pending_size_update: Option<(usize, usize)>,
+
+    /// Whether string literals are Huffman-encoded when that's shorter
+    huffman: bool,
+}
+
+/// Whether a header that isn't in the header table as-is gets added to it,
+/// cf. HPACK spec, section 6.2.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Indexing {
+    /// Added to the dynamic table, so that later header blocks can refer to
+    /// it by index
+    #[default]
+    Incremental,
+
+    /// Left out of the dynamic table, e.g. because it's unlikely to be seen
+    /// again
+    Without,
+
+    /// Left out of the dynamic table, and intermediaries re-encoding it must
+    /// leave it out of theirs too. This is for sensitive values, like
+    /// credentials, that could otherwise be guessed by observing how well
+    /// they compress (cf. HPACK spec, section 7.1.3).
+    Never,
 }
 
 impl<'a> Default for Encoder<'a> {
@@ -184,11 +212,31 @@ impl<'a> Encoder<'a> {
     pub fn new() -> Encoder<'a> {
         Encoder {
             header_table: HeaderTable::with_static_table(STATIC_TABLE),
+            pending_size_update: None,
+            huffman: false,
         }
     }
 
+    /// Sets whether string literals are Huffman-encoded, which they are only
+    /// when that makes them shorter. Off by default.
+    pub fn set_huffman(&mut self, enabled: bool) {
+        self.huffman = enabled;
+    }
+
     /// Sets a new maximum dynamic table size for the encoder.
+    ///
+    /// The decoder is told with a dynamic table size update at the start of
+    /// the next header block (cf. HPACK spec, section 4.2). If the size went
+    /// down and back up since the last block, two updates are sent: first
+    /// the smallest size, then the latest one, so that both tables evict the
+    /// same entries.
     pub fn set_max_table_size(&mut self, new_max_size: usize) {
+        let current = self.header_table.dynamic_table.get_max_table_size();
+        self.pending_size_update = match self.pending_size_update {
+            Some((smallest, _)) => Some((smallest.min(new_max_size), new_max_size)),
+            None if new_max_size != current => Some((new_max_size, new_max_size)),
+            None => None,
+        };
         self.header_table
             .dynamic_table
             .set_max_table_size(new_max_size);
@@ -203,8 +251,9 @@ impl<'a> Encoder<'a> {
     /// already found in the header table and a literal otherwise. When a
     /// header isn't found in the table, it is added if the header name wasn't
     /// found either (i.e. there are never two header names with different
-    /// values in the produced header table). Strings are always encoded as
-    /// literals (Huffman encoding is not used).
+    /// values in the produced header table). Strings are encoded as raw
+    /// literals, unless Huffman encoding is turned on with
+    /// [Encoder::set_huffman].
     pub fn encode<'b, I>(&mut self, headers: I) -> Vec<u8>
     where
         I: IntoIterator<Item = (&'b [u8], &'b [u8])>,
@@ -223,39 +272,76 @@ impl<'a> Encoder<'a> {
         I: IntoIterator<Item = (&'b [u8], &'b [u8])>,
         W: io::Write,
     {
+        self.encode_size_update_into(writer)?;
         for header in headers {
             self.encode_header_into(header, writer)?;
         }
         Ok(())
     }
 
+    /// Encodes the dynamic table size updates owed to the decoder since the
+    /// last call to [Encoder::set_max_table_size], if any. They must come
+    /// first in a header block: `encode_into` does this already, this is for
+    /// callers that go header by header with `encode_header_into`.
+    pub fn encode_size_update_into<W: io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        if let Some((smallest, latest)) = self.pending_size_update.take() {
+            // `001xxxxx` is a dynamic table size update, cf. HPACK spec, section 6.3
+            if smallest < latest {
+                encode_integer_into(smallest, 5, 0x20, writer)?;
+            }
+            encode_integer_into(latest, 5, 0x20, writer)?;
+        }
+        Ok(())
+    }
+
     /// Encodes a single given header into the given `io::Write` instance.
     ///
     /// Any errors are propagated, similarly to the `encode_into` method, and it is the callers
-    /// responsiblity to make sure that the paired encoder sees them too.
+    /// responsiblity to make sure that the paired encoder sees them too. Pending dynamic table
+    /// size updates are not written, see [Encoder::encode_size_update_into].
     pub fn encode_header_into<W: io::Write>(
         &mut self,
         header: (&[u8], &[u8]),
         writer: &mut W,
     ) -> io::Result<()> {
-        match self.header_table.find_header(header) {
-            None => {
+        self.encode_header_into_with(header, Indexing::Incremental, writer)
+    }
+
+    /// Like [Encoder::encode_header_into], with control over whether the header may be added to
+    /// the dynamic table.
+    pub fn encode_header_into_with<W: io::Write>(
+        &mut self,
+        header: (&[u8], &[u8]),
+        indexing: Indexing,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        match (self.header_table.find_header(header), indexing) {
+            (Some((index, true)), Indexing::Incremental | Indexing::Without) => {
+                // The full header was found in one of the tables, so we
+                // just encode the index. Never-indexed headers are always
+                // sent as literals, so that intermediaries know to keep them
+                // that way.
+                self.encode_indexed(index, writer)?;
+            }
+            (None, Indexing::Incremental) => {
                 // The name of the header is in no tables: need to encode
                 // it with both a literal name and value.
-                self.encode_literal(&header, true, writer)?;
+                self.encode_literal(&header, Indexing::Incremental, writer)?;
                 self.header_table
                     .add_header(header.0.to_vec(), header.1.to_vec());
             }
-            Some((index, false)) => {
-                // The name of the header is at the given index, but the
-                // value does not match the current one: need to encode
-                // only the value as a literal.
-                self.encode_indexed_name((index, header.1), false, writer)?;
+            (None, indexing) => {
+                self.encode_literal(&header, indexing, writer)?;
             }
-            Some((index, true)) => {
-                // The full header was found in one of the tables, so we
-                // just encode the index.
-                self.encode_indexed(index, writer)?;
+            (Some((index, _)), indexing) => {
+                // The name of the header is at the given index, but the
+                // value does not match the current one (or mustn't be
+                // indexed): need to encode only the value as a literal.
+                let indexing = match indexing {
+                    Indexing::Incremental => Indexing::Without,
+                    indexing => indexing,
+                };
+                self.encode_indexed_name((index, header.1), indexing, writer)?;
             }
         };
         Ok(())
@@ -268,17 +354,16 @@ impl<'a> Encoder<'a> {
     /// # Parameters
     ///
     /// - `header` - the header to be encoded
-    /// - `should_index` - indicates whether the given header should be indexed, i.e.
-    ///                    inserted into the dynamic table
+    /// - `indexing` - whether the given header is inserted into the dynamic table
     /// - `buf` - The buffer into which the result is placed
     ///
     fn encode_literal<W: io::Write>(
         &mut self,
         header: &(&[u8], &[u8]),
-        should_index: bool,
+        indexing: Indexing,
         buf: &mut W,
     ) -> io::Result<()> {
-        let mask = if should_index { 0x40 } else { 0x0 };
+        let (mask, _) = literal_mask_and_prefix(indexing);
 
         buf.write_all(&[mask])?;
         self.encode_string_literal(header.0, buf)?;
@@ -289,14 +374,21 @@ impl<'a> Encoder<'a> {
     /// Encodes a string literal and places the result in the given buffer
     /// `buf`.
     ///
-    /// The function does not consider Huffman encoding for now, but always
-    /// produces a string literal representations, according to the HPACK spec
-    /// section 5.2.
+    /// The string is Huffman-encoded if that's turned on and makes it
+    /// shorter, according to the HPACK spec section 5.2.
     fn encode_string_literal<W: io::Write>(
         &mut self,
         octet_str: &[u8],
         buf: &mut W,
     ) -> io::Result<()> {
+        if self.huffman {
+            let len = huffman_encoded_len(octet_str);
+            if len < octet_str.len() {
+                // the most significant bit flags Huffman-encoded strings
+                encode_integer_into(len, 7, 0x80, buf)?;
+                return huffman_encode_into(octet_str, buf);
+            }
+        }
         encode_integer_into(octet_str.len(), 7, 0, buf)?;
         buf.write_all(octet_str)?;
         Ok(())
@@ -307,10 +399,10 @@ impl<'a> Encoder<'a> {
     fn encode_indexed_name<W: io::Write>(
         &mut self,
         header: (usize, &[u8]),
-        should_index: bool,
+        indexing: Indexing,
         buf: &mut W,
     ) -> io::Result<()> {
-        let (mask, prefix) = if should_index { (0x40, 6) } else { (0x0, 4) };
+        let (mask, prefix) = literal_mask_and_prefix(indexing);
 
         encode_integer_into(header.0, prefix, mask, buf)?;
         // So far, we rely on just one strategy for encoding string literals.
@@ -330,12 +422,23 @@ impl<'a> Encoder<'a> {
     }
 }
 
+/// The leading bits and the size of the index prefix of literal header
+/// representations, cf. HPACK spec, sections 6.2.1 to 6.2.3
+fn literal_mask_and_prefix(indexing: Indexing) -> (u8, u8) {
+    match indexing {
+        Indexing::Incremental => (0x40, 6),
+        Indexing::Without => (0x0, 4),
+        Indexing::Never => (0x10, 4),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use tracing::debug;
 
     use super::encode_integer;
     use super::Encoder;
+    use super::Indexing;
 
     use super::super::Decoder;
 
@@ -473,4 +576,102 @@ mod tests {
 
         assert!(is_decodable(&result, &headers));
     }
+
+    /// Tests that table size changes are announced at the start of the next
+    /// block, and that the decoder follows them.
+    #[test]
+    fn test_size_update_is_signaled() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        let headers = vec![(b"custom-key".to_vec(), b"custom-value".to_vec())];
+        let encode =
+            |encoder: &mut Encoder| encoder.encode(headers.iter().map(|h| (&h.0[..], &h.1[..])));
+
+        // setting the size it already has is not a change
+        encoder.set_max_table_size(4096);
+        let result = encode(&mut encoder);
+        assert_eq!(result[0], 0x40);
+        assert_eq!(decoder.decode(&result).unwrap(), headers);
+
+        // shrinking evicts everything, both sides
+        encoder.set_max_table_size(0);
+        let result = encode(&mut encoder);
+        assert_eq!(result[0], 0x20);
+        assert_eq!(decoder.decode(&result).unwrap(), headers);
+        assert_eq!(encoder.header_table.dynamic_table.len(), 0);
+
+        // down then back up between two blocks: the smallest size comes first
+        encoder.set_max_table_size(256);
+        encoder.set_max_table_size(32);
+        encoder.set_max_table_size(1337);
+        let result = encode(&mut encoder);
+        assert_eq!(&result[..4], &[0x20 | 31, 1, 0x20 | 31, 154]);
+        assert_eq!(result[4], 10);
+        assert_eq!(decoder.decode(&result).unwrap(), headers);
+
+        // only announced once
+        let result = encode(&mut encoder);
+        assert_eq!(result, [0x80 | 62]);
+        assert_eq!(decoder.decode(&result).unwrap(), headers);
+    }
+
+    /// Tests that headers that mustn't be indexed stay out of the dynamic
+    /// table, and that never-indexed ones are flagged as such even when
+    /// they're in it.
+    #[test]
+    fn test_indexing() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        let mut encode = |header: (&[u8], &[u8]), indexing| {
+            let mut result = Vec::new();
+            encoder
+                .encode_header_into_with(header, indexing, &mut result)
+                .unwrap();
+            assert_eq!(
+                decoder.decode(&result).unwrap(),
+                [(header.0.to_vec(), header.1.to_vec())]
+            );
+            result
+        };
+
+        let secret = (&b"x-secret"[..], &b"hunter2"[..]);
+        assert_eq!(encode(secret, Indexing::Never)[0], 0x10);
+        assert_eq!(encode(secret, Indexing::Without)[0], 0x00);
+        assert_eq!(encode(secret, Indexing::Incremental)[0], 0x40);
+        assert_eq!(encode(secret, Indexing::Incremental), [0x80 | 62]);
+        assert_eq!(encode(secret, Indexing::Without), [0x80 | 62]);
+        // the name is indexed, but not the value
+        assert_eq!(encode(secret, Indexing::Never)[0], 0x10 | 15);
+        assert_eq!(encode(secret, Indexing::Never)[1..3], [62 - 15, 7]);
+
+        // `authorization` is in the static table
+        assert_eq!(
+            encode((b"authorization", b"Basic Zm9vOmJhcg=="), Indexing::Never)[0],
+            0x10 | 15
+        );
+        assert_eq!(encoder.header_table.dynamic_table.len(), 1);
+    }
+
+    /// Tests that strings are Huffman-encoded only when asked to, and when
+    /// that's shorter.
+    #[test]
+    fn test_huffman() {
+        let mut encoder = Encoder::new();
+        let headers = vec![
+            (b"custom-key".to_vec(), b"custom-value".to_vec()),
+            (b"x-bytes".to_vec(), b"\xff\xfe\xfd".to_vec()),
+        ];
+        let raw_len = encoder
+            .encode(headers.iter().map(|h| (&h.0[..], &h.1[..])))
+            .len();
+
+        let mut encoder = Encoder::new();
+        encoder.set_huffman(true);
+        let result = encoder.encode(headers.iter().map(|h| (&h.0[..], &h.1[..])));
+        assert!(result.len() < raw_len);
+        // name and value of the first header are flagged as Huffman-encoded
+        assert_eq!(result[0], 0x40);
+        assert_eq!(result[1] & 0x80, 0x80);
+        assert!(is_decodable(&result, &headers));
+    }
 }
@@ -3,6 +3,7 @@
 //! (HPACK-draft-10, Appendix B)
 
 use std::collections::HashMap;
+use std::io;
 
 /// Represents a symbol that can be inserted into a Huffman-encoded octet
 /// string.
@@ -158,6 +159,47 @@ impl HuffmanDecoder {
     }
 }
 
+/// How many octets [huffman_encode_into] produces for `buf`
+pub fn huffman_encoded_len(buf: &[u8]) -> usize {
+    let bits: usize = buf
+        .iter()
+        .map(|&b| HUFFMAN_CODE_TABLE[b as usize].1 as usize)
+        .sum();
+    bits.div_ceil(8)
+}
+
+/// Huffman-encodes `buf` into `writer`. The last octet is padded with the
+/// most-significant bits of the EOS symbol, i.e. ones.
+pub fn huffman_encode_into<W: io::Write>(buf: &[u8], writer: &mut W) -> io::Result<()> {
+    let mut out = [0u8; 64];
+    let mut out_len = 0;
+    // codes are at most 30 bits long, added when fewer than 8 are pending
+    let mut acc: u64 = 0;
+    let mut acc_bits = 0;
+
+    for &b in buf {
+        let (code, code_bits) = HUFFMAN_CODE_TABLE[b as usize];
+        acc = (acc << code_bits) | code as u64;
+        acc_bits += code_bits as u32;
+        while acc_bits >= 8 {
+            acc_bits -= 8;
+            out[out_len] = (acc >> acc_bits) as u8;
+            out_len += 1;
+            if out_len == out.len() {
+                writer.write_all(&out)?;
+                out_len = 0;
+            }
+        }
+        acc &= (1 << acc_bits) - 1;
+    }
+    if acc_bits > 0 {
+        let padding = 8 - acc_bits;
+        out[out_len] = ((acc << padding) | ((1 << padding) - 1)) as u8;
+        out_len += 1;
+    }
+    writer.write_all(&out[..out_len])
+}
+
 /// A helper struct that represents an iterator over individual bits of all
 /// bytes found in a wrapped Iterator over bytes.
 /// Bits are represented as `bool`s, where `true` corresponds to a set bit and
@@ -478,6 +520,8 @@ static HUFFMAN_CODE_TABLE: &[(u32, u8)] = &[
 
 #[cfg(test)]
 mod tests {
+    use super::huffman_encode_into;
+    use super::huffman_encoded_len;
     use super::BitIterator;
     use super::HuffmanDecoder;
     use super::HuffmanDecoderError;
@@ -702,4 +746,29 @@ mod tests {
             );
         }
     }
+
+    /// Tests that encoding gives the examples of the HPACK spec (Appendix
+    /// C.4), and that whatever is encoded decodes back to the same.
+    #[test]
+    fn test_huffman_encode() {
+        let encode = |buf: &[u8]| {
+            let mut res = Vec::new();
+            huffman_encode_into(buf, &mut res).unwrap();
+            assert_eq!(res.len(), huffman_encoded_len(buf));
+            res
+        };
+
+        assert_eq!(
+            encode(b"www.example.com"),
+            [0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff]
+        );
+        assert_eq!(encode(b"no-cache"), [0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbf]);
+        assert_eq!(encode(b""), []);
+
+        let all: Vec<u8> = (0..=255).chain((0..=255).rev()).collect();
+        for buf in [&all[..], b"custom-value", b"\0\xff\x7f"] {
+            let mut decoder = HuffmanDecoder::new();
+            assert_eq!(decoder.decode(&encode(buf)).unwrap(), buf);
+        }
+    }
 }
@@ -9,7 +9,7 @@ use tracing::debug;
 
 // Re-export the main HPACK API entry points.
 pub use self::decoder::Decoder;
-pub use self::encoder::Encoder;
+pub use self::encoder::{Encoder, Indexing};
 
 pub mod decoder;
 pub mod encoder;
@@ -92,7 +92,6 @@ impl DynamicTable {
     }
 
     /// Returns the maximum size of the table in octets.
-    #[cfg(test)]
     fn get_max_table_size(&self) -> usize {
         self.max_size
     }
@@ -8,6 +8,9 @@ use crate::{
 mod chan;
 pub use chan::*;
 
+mod transport;
+pub use transport::*;
+
 mod buf_or_slice;
 use buf_or_slice::*;
 
@@ -129,6 +132,20 @@ pub trait WriteOwned {
         Ok(())
     }
 
+    /// Write out anything this transport buffered, for transports that
+    /// buffer writes. Others have nothing to do.
+    async fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Hint that several writes are about to follow each other (`true`), so
+    /// partially-filled packets may be held back until the hint is lifted
+    /// (`false`), like `TCP_CORK` does on Linux. Transports that can't do
+    /// that ignore it.
+    fn set_corked(&mut self, _corked: bool) -> std::io::Result<()> {
+        Ok(())
+    }
+
     async fn shutdown(&mut self, how: Shutdown) -> std::io::Result<()>;
 }
 
@@ -1,4 +1,4 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, io, rc::Rc};
 
 use crate::{
     buf::{IoBuf, IoBufMut},
@@ -34,11 +34,11 @@ enum ChanReadState {
     // Data may still come in
     Live,
 
-    // [ChanReaderSend] was dropped, no more data is coming
+    // [ChanReadSend] was dropped, no more data is coming
     Eof,
 
-    // [ChanReaderSend::rest] was called
-    Reset,
+    // [ChanReadSend::close_with_error] (or [ChanReadSend::reset]) was called
+    Failed(InjectedError),
 }
 
 impl ChanRead {
@@ -63,11 +63,23 @@ impl ChanRead {
 impl ChanReadSend {
     /// Sever this connection abnormally - read will eventually return [std::io::ErrorKind::ConnectionReset]
     pub fn reset(self) {
+        self.close_with_error(io::ErrorKind::ConnectionReset.into())
+    }
+
+    /// Once whatever was already sent has been read, reads fail with `err`
+    /// (the first one gets `err` itself, later ones an error of the same
+    /// kind).
+    pub fn close_with_error(self, err: io::Error) {
         let mut guarded = self.inner.guarded.borrow_mut();
-        guarded.state = ChanReadState::Reset;
+        guarded.state = ChanReadState::Failed(InjectedError::new(err));
         // let it drop, which will notify waiters
     }
 
+    /// Half-close: once whatever was already sent has been read, reads
+    /// return 0 (EOF), like they would after the peer shut down its write
+    /// side. Same as dropping this.
+    pub fn close(self) {}
+
     /// Send a chunk of data. Readers will not be able to read _more_ than the
     /// length of this chunk in a single call, but may read less (if their buffer
     /// is too small).
@@ -92,8 +104,8 @@ impl ChanReadSend {
                     // can't send after dropping
                     ChanReadState::Eof => unreachable!(),
 
-                    // can't send after calling abort
-                    ChanReadState::Reset => unreachable!(),
+                    // can't send after closing with an error
+                    ChanReadState::Failed(_) => unreachable!(),
                 }
             }
             self.inner.notify.notified().await
@@ -135,15 +147,15 @@ impl ReadOwned for ChanRead {
                     return (Ok(n), buf);
                 }
 
-                match guarded.state {
+                match &mut guarded.state {
                     ChanReadState::Live => {
                         // muffin
                     }
                     ChanReadState::Eof => {
                         return (Ok(0), buf);
                     }
-                    ChanReadState::Reset => {
-                        return (Err(std::io::ErrorKind::ConnectionReset.into()), buf);
+                    ChanReadState::Failed(err) => {
+                        return (Err(err.take()), buf);
                     }
                 }
             }
@@ -153,34 +165,144 @@ impl ReadOwned for ChanRead {
     }
 }
 
+/// Sends everything written to it as `Vec<u8>` chunks over a channel. The
+/// channel holds a single chunk: writes wait for the receiver to pick up the
+/// previous one.
 pub struct ChanWrite {
-    tx: mpsc::Sender<Vec<u8>>,
+    // `None` once the write side was shut down
+    tx: Option<mpsc::Sender<Vec<u8>>>,
+    max_chunk_len: usize,
+    shared: Rc<ChanWriteShared>,
+}
+
+/// Lets a [ChanWrite] fail from the outside, see [ChanWrite::handle]
+#[derive(Clone)]
+pub struct ChanWriteHandle {
+    shared: Rc<ChanWriteShared>,
+}
+
+#[derive(Default)]
+struct ChanWriteShared {
+    notify: tokio::sync::Notify,
+    error: RefCell<Option<InjectedError>>,
 }
 
 impl ChanWrite {
     pub fn new() -> (mpsc::Receiver<Vec<u8>>, Self) {
+        Self::with_capacity(usize::MAX)
+    }
+
+    /// Like [ChanWrite::new], but writes take at most `capacity` bytes at a
+    /// time (writing the rest is left to the caller, like with a socket
+    /// whose send buffer is full), so at most `capacity` bytes wait for the
+    /// receiver.
+    pub fn with_capacity(capacity: usize) -> (mpsc::Receiver<Vec<u8>>, Self) {
+        assert!(capacity > 0, "capacity must be non-zero");
+
         let (tx, rx) = mpsc::channel(1);
-        (rx, Self { tx })
+        (
+            rx,
+            Self {
+                tx: Some(tx),
+                max_chunk_len: capacity,
+                shared: Default::default(),
+            },
+        )
+    }
+
+    /// Returns a handle that can make writes fail, even once this has been
+    /// moved into whatever is being tested.
+    pub fn handle(&self) -> ChanWriteHandle {
+        ChanWriteHandle {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl ChanWriteHandle {
+    /// Makes pending and future writes fail with `err` (the first one gets
+    /// `err` itself, later ones an error of the same kind).
+    pub fn close_with_error(&self, err: io::Error) {
+        *self.shared.error.borrow_mut() = Some(InjectedError::new(err));
+        self.shared.notify.notify_waiters();
+    }
+}
+
+impl ChanWriteShared {
+    fn take_error(&self) -> Option<io::Error> {
+        self.error.borrow_mut().as_mut().map(|err| err.take())
+    }
+
+    /// Resolves once an error was injected
+    async fn failed(&self) -> io::Error {
+        loop {
+            let notified = self.notify.notified();
+            if let Some(err) = self.take_error() {
+                return err;
+            }
+            notified.await;
+        }
     }
 }
 
 impl WriteOwned for ChanWrite {
     async fn write<B: IoBuf>(&mut self, buf: B) -> BufResult<usize, B> {
-        let slice = unsafe { std::slice::from_raw_parts(buf.stable_ptr(), buf.bytes_init()) };
-        match self.tx.send(slice.to_vec()).await {
-            Ok(()) => (Ok(buf.bytes_init()), buf),
-            Err(_) => (Err(std::io::ErrorKind::BrokenPipe.into()), buf),
+        if let Some(err) = self.shared.take_error() {
+            return (Err(err), buf);
+        }
+        let Some(tx) = &self.tx else {
+            return (Err(io::ErrorKind::BrokenPipe.into()), buf);
+        };
+
+        let n = std::cmp::min(buf.bytes_init(), self.max_chunk_len);
+        let slice = unsafe { std::slice::from_raw_parts(buf.stable_ptr(), n) };
+        tokio::select! {
+            res = tx.send(slice.to_vec()) => match res {
+                Ok(()) => (Ok(n), buf),
+                Err(_) => (Err(io::ErrorKind::BrokenPipe.into()), buf),
+            },
+            err = self.shared.failed() => (Err(err), buf),
         }
     }
 
-    async fn shutdown(&mut self, _how: std::net::Shutdown) -> std::io::Result<()> {
+    /// Shutting down the write side closes the channel: the receiver gets
+    /// `None` once it has received everything, and later writes fail.
+    async fn shutdown(&mut self, how: std::net::Shutdown) -> std::io::Result<()> {
+        if let Some(err) = self.shared.take_error() {
+            return Err(err);
+        }
+        if how != std::net::Shutdown::Read {
+            self.tx = None;
+        }
         Ok(())
     }
 }
 
+/// An error injected into a [ChanRead] or [ChanWrite]. [io::Error] isn't
+/// `Clone`, so it's only returned once, and errors of the same kind are
+/// returned afterwards.
+struct InjectedError {
+    kind: io::ErrorKind,
+    err: Option<io::Error>,
+}
+
+impl InjectedError {
+    fn new(err: io::Error) -> Self {
+        Self {
+            kind: err.kind(),
+            err: Some(err),
+        }
+    }
+
+    fn take(&mut self) -> io::Error {
+        self.err.take().unwrap_or_else(|| self.kind.into())
+    }
+}
+
 #[cfg(all(test, not(feature = "miri")))]
 mod tests {
-    use super::{ChanRead, ReadOwned};
+    use super::{ChanRead, ChanWrite, ReadOwned};
+    use crate::io::WriteOwned;
     use std::{cell::RefCell, rc::Rc};
 
     #[test]
@@ -284,4 +406,62 @@ mod tests {
             }
         })
     }
+
+    #[test]
+    fn test_chan_writer() {
+        crate::start(async move {
+            let (mut rx, mut cw) = ChanWrite::with_capacity(4);
+
+            // a short write: the rest is left to the caller, and the
+            // channel is full until the receiver catches up
+            let (res, buf) = cw.write(b"hello world".to_vec()).await;
+            let n = res.unwrap();
+            assert_eq!(n, 4);
+            let pending = crate::spawn(async move {
+                cw.write_all(buf[n..].to_vec()).await.unwrap();
+                cw
+            });
+            let mut received = rx.recv().await.unwrap();
+            assert_eq!(received, b"hell");
+            while received.len() < 11 {
+                let chunk = rx.recv().await.unwrap();
+                assert!(chunk.len() <= 4, "writes take at most 4 bytes");
+                received.extend(chunk);
+            }
+            assert_eq!(received, b"hello world");
+            let mut cw = pending.await.unwrap();
+
+            // an injected error fails the write that's stuck waiting for the
+            // receiver, and later ones
+            let handle = cw.handle();
+            cw.write(b"full".to_vec()).await.0.unwrap();
+            crate::spawn(async move {
+                handle.close_with_error(std::io::ErrorKind::TimedOut.into());
+            });
+            let err = cw.write(b"stuck".to_vec()).await.0.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+            let err = cw.write(b"later".to_vec()).await.0.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+            // shutting down the write side closes the channel
+            let (mut rx, mut cw) = ChanWrite::new();
+            cw.write_all(b"bye".to_vec()).await.unwrap();
+            cw.shutdown(std::net::Shutdown::Write).await.unwrap();
+            assert_eq!(rx.recv().await.unwrap(), b"bye");
+            assert!(rx.recv().await.is_none());
+            let err = cw.write(b"more".to_vec()).await.0.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+
+            // errors injected on the read side come after buffered data
+            let (send, mut cr) = ChanRead::new();
+            crate::spawn(async move {
+                send.send("last").await.unwrap();
+                send.close_with_error(std::io::ErrorKind::UnexpectedEof.into());
+            });
+            let (res, buf) = cr.read(vec![0u8; 16]).await;
+            assert_eq!(&buf[..res.unwrap()], b"last");
+            let err = cr.read(vec![0u8; 16]).await.0.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        })
+    }
 }
@@ -0,0 +1,182 @@
+use std::{
+    net::{Shutdown, SocketAddr},
+    path::PathBuf,
+};
+
+use super::{IntoHalves, ReadOwned, WriteOwned};
+
+/// A connection fluke can serve requests over, or send requests through.
+///
+/// Beyond splitting into halves, a transport can be shut down, and may know
+/// where it's connected from and to, and whether it's encrypted. All of that
+/// is optional: the defaults shut down the write half, and know nothing.
+///
+/// It's implemented for TCP and Unix sockets, for [WithTls] wrappers, and for
+/// any `(read, write)` pair of halves, which covers in-memory transports like
+/// [ChanRead](super::ChanRead) / [ChanWrite](super::ChanWrite), and lets
+/// anything else (vsock, QUIC streams, tunnels) plug in.
+#[allow(async_fn_in_trait)] // we never require Send
+pub trait Transport: IntoHalves {
+    /// Shuts the connection down without serving it (e.g. to turn a client
+    /// away). Once split, that's [WriteOwned::shutdown] on the write half.
+    async fn shutdown(self, how: Shutdown) -> std::io::Result<()>
+    where
+        Self: Sized,
+    {
+        let (_, mut w) = self.into_halves();
+        w.shutdown(how).await
+    }
+
+    fn local_addr(&self) -> Option<TransportAddr> {
+        None
+    }
+
+    fn peer_addr(&self) -> Option<TransportAddr> {
+        None
+    }
+
+    fn tls_info(&self) -> Option<TlsInfo> {
+        None
+    }
+}
+
+/// Where one end of a [Transport] is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransportAddr {
+    /// TCP over IPv4 or IPv6
+    Inet(SocketAddr),
+
+    /// A Unix domain socket, `None` for unnamed ones (e.g. the client end
+    /// of most connections)
+    Unix(Option<PathBuf>),
+
+    /// Anything else, in whatever form the transport likes
+    Other(String),
+}
+
+impl From<SocketAddr> for TransportAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Inet(addr)
+    }
+}
+
+#[cfg(unix)]
+impl From<std::os::unix::net::SocketAddr> for TransportAddr {
+    fn from(addr: std::os::unix::net::SocketAddr) -> Self {
+        Self::Unix(addr.as_pathname().map(|p| p.to_owned()))
+    }
+}
+
+/// What's known about a TLS session a [Transport] runs over
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// Protocol negotiated with ALPN, e.g. `b"h2"`
+    pub alpn_protocol: Option<Vec<u8>>,
+
+    /// Server name the client asked for with SNI
+    pub server_name: Option<String>,
+}
+
+/// A snapshot of what a [Transport] knows about its connection, taken
+/// before it's split into halves.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnInfo {
+    pub local_addr: Option<TransportAddr>,
+    pub peer_addr: Option<TransportAddr>,
+    pub tls: Option<TlsInfo>,
+}
+
+impl ConnInfo {
+    pub fn of(transport: &impl Transport) -> Self {
+        Self {
+            local_addr: transport.local_addr(),
+            peer_addr: transport.peer_addr(),
+            tls: transport.tls_info(),
+        }
+    }
+}
+
+/// Halves that were already split know nothing about their connection, but
+/// can still be served over.
+impl<R: ReadOwned, W: WriteOwned> IntoHalves for (R, W) {
+    type Read = R;
+    type Write = W;
+
+    fn into_halves(self) -> (Self::Read, Self::Write) {
+        self
+    }
+}
+
+impl<R: ReadOwned, W: WriteOwned> Transport for (R, W) {}
+
+/// A transport TLS was set up on, whether it's decrypted in userland (e.g.
+/// a `tokio_rustls` stream) or by the kernel (kTLS, where `inner` is just a
+/// TCP socket).
+pub struct WithTls<T> {
+    pub inner: T,
+    pub tls: TlsInfo,
+}
+
+impl<T: IntoHalves> IntoHalves for WithTls<T> {
+    type Read = T::Read;
+    type Write = T::Write;
+
+    fn into_halves(self) -> (Self::Read, Self::Write) {
+        self.inner.into_halves()
+    }
+}
+
+impl<T: Transport> Transport for WithTls<T> {
+    fn local_addr(&self) -> Option<TransportAddr> {
+        self.inner.local_addr()
+    }
+
+    fn peer_addr(&self) -> Option<TransportAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn tls_info(&self) -> Option<TlsInfo> {
+        Some(self.tls.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Shutdown;
+
+    use super::{ConnInfo, TlsInfo, Transport, WithTls};
+    use crate::io::{ChanRead, ChanWrite};
+
+    #[test]
+    fn test_conn_info() {
+        let (_, r) = ChanRead::new();
+        let (_, w) = ChanWrite::new();
+        let halves = (r, w);
+        assert_eq!(ConnInfo::of(&halves), ConnInfo::default());
+
+        let tls = TlsInfo {
+            alpn_protocol: Some(b"h2".to_vec()),
+            server_name: Some("example.org".into()),
+        };
+        let info = ConnInfo::of(&WithTls {
+            inner: halves,
+            tls: tls.clone(),
+        });
+        assert_eq!(info.tls, Some(tls));
+        assert_eq!(info.peer_addr, None);
+    }
+
+    #[test]
+    fn test_transport_shutdown() {
+        crate::start(async move {
+            let (_tx, r) = ChanRead::new();
+            let (mut rx, w) = ChanWrite::new();
+            let transport = WithTls {
+                inner: (r, w),
+                tls: Default::default(),
+            };
+            transport.shutdown(Shutdown::Both).await.unwrap();
+            assert!(rx.recv().await.is_none());
+        });
+    }
+}
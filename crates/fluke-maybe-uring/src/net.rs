@@ -1,4 +1,4 @@
-use crate::io::IntoHalves;
+use crate::io::{IntoHalves, Transport, TransportAddr};
 
 #[cfg(all(target_os = "linux", feature = "tokio-uring"))]
 mod net_uring;
@@ -20,3 +20,40 @@ impl IntoHalves for tokio::net::TcpStream {
         self.into_split()
     }
 }
+
+impl Transport for tokio::net::TcpStream {
+    fn local_addr(&self) -> Option<TransportAddr> {
+        tokio::net::TcpStream::local_addr(self).ok().map(Into::into)
+    }
+
+    fn peer_addr(&self) -> Option<TransportAddr> {
+        tokio::net::TcpStream::peer_addr(self).ok().map(Into::into)
+    }
+}
+
+#[cfg(unix)]
+impl IntoHalves for tokio::net::UnixStream {
+    type Read = tokio::net::unix::OwnedReadHalf;
+    type Write = tokio::net::unix::OwnedWriteHalf;
+
+    fn into_halves(self) -> (Self::Read, Self::Write) {
+        self.into_split()
+    }
+}
+
+#[cfg(unix)]
+impl Transport for tokio::net::UnixStream {
+    fn local_addr(&self) -> Option<TransportAddr> {
+        let addr = tokio::net::UnixStream::local_addr(self).ok()?;
+        Some(TransportAddr::Unix(
+            addr.as_pathname().map(|p| p.to_owned()),
+        ))
+    }
+
+    fn peer_addr(&self) -> Option<TransportAddr> {
+        let addr = tokio::net::UnixStream::peer_addr(self).ok()?;
+        Some(TransportAddr::Unix(
+            addr.as_pathname().map(|p| p.to_owned()),
+        ))
+    }
+}
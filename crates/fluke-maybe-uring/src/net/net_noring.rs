@@ -11,11 +11,22 @@ pub struct TcpListener {
 }
 
 impl TcpListener {
+    /// Not available on WASI, which has no way to open a listening socket:
+    /// use [TcpListener::from_std] with a socket preopened by the runtime.
+    #[cfg(not(target_family = "wasm"))]
     pub async fn bind(addr: SocketAddr) -> std::io::Result<Self> {
         let tok = TokListener::bind(addr).await?;
         Ok(Self { tok })
     }
 
+    /// Wraps a listener that's already bound, e.g. one the WASI runtime
+    /// preopened for us. It's switched to non-blocking mode.
+    pub fn from_std(listener: std::net::TcpListener) -> std::io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        let tok = TokListener::from_std(listener)?;
+        Ok(Self { tok })
+    }
+
     pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
         self.tok.local_addr()
     }
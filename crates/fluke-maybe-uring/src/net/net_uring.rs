@@ -5,7 +5,7 @@ use std::{
 
 use crate::{
     buf::{tokio_uring_compat::BufCompat, IoBuf, IoBufMut},
-    io::{IntoHalves, ReadOwned, WriteOwned},
+    io::{IntoHalves, ReadOwned, Transport, TransportAddr, WriteOwned},
     BufResult,
 };
 pub use tokio_uring::net::{TcpListener as TokListener, TcpStream as TokStream};
@@ -60,6 +60,25 @@ impl WriteOwned for TcpWriteHalf {
         (res, list)
     }
 
+    fn set_corked(&mut self, corked: bool) -> std::io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let value: libc::c_int = corked.into();
+        let ret = unsafe {
+            libc::setsockopt(
+                self.0.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_CORK,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
     async fn shutdown(&mut self, how: Shutdown) -> std::io::Result<()> {
         self.0.shutdown(how)
     }
@@ -74,3 +93,27 @@ impl IntoHalves for TcpStream {
         (TcpReadHalf(self_rc.clone()), TcpWriteHalf(self_rc))
     }
 }
+
+impl Transport for TcpStream {
+    fn local_addr(&self) -> Option<TransportAddr> {
+        with_std_stream(self, |s| s.local_addr())
+    }
+
+    fn peer_addr(&self) -> Option<TransportAddr> {
+        with_std_stream(self, |s| s.peer_addr())
+    }
+}
+
+/// tokio-uring streams don't expose their addresses, so borrow the socket
+/// as a std stream (without closing it afterwards) to ask.
+fn with_std_stream(
+    stream: &TcpStream,
+    f: impl FnOnce(&std::net::TcpStream) -> std::io::Result<SocketAddr>,
+) -> Option<TransportAddr> {
+    use std::os::fd::{AsRawFd, FromRawFd};
+
+    let std_stream = std::mem::ManuallyDrop::new(unsafe {
+        std::net::TcpStream::from_raw_fd(stream.as_raw_fd())
+    });
+    f(&std_stream).ok().map(Into::into)
+}
@@ -0,0 +1,107 @@
+//! Chunked bodies, as bytes or built from chunks that may be subtly off,
+//! must never panic nor cost more than their length, and those that are
+//! well-formed must decode to what they were built from.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use fluke::fuzzing::{read_h1_chunked_body, MAX_CHUNK_EXT_LEN};
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Arbitrary, Debug)]
+enum Input {
+    Raw(Vec<u8>),
+    Structured(Vec<Chunk>),
+}
+
+#[derive(Arbitrary, Debug)]
+struct Chunk {
+    data: Vec<u8>,
+    size: Size,
+    ext: Option<String>,
+    terminator: Terminator,
+}
+
+#[derive(Arbitrary, Debug)]
+enum Size {
+    Lower,
+    Upper,
+    LeadingZeroes(u8),
+    Off(i8),
+    Raw(String),
+}
+
+#[derive(Arbitrary, Debug)]
+enum Terminator {
+    Crlf,
+    Lf,
+    Missing,
+    Raw(Vec<u8>),
+}
+
+impl Chunk {
+    /// Whether this is exactly what a chunk should look like. Empty ones
+    /// aren't: a zero size ends the body.
+    fn is_well_formed(&self) -> bool {
+        let size_ok = match self.size {
+            Size::Lower | Size::Upper => true,
+            Size::LeadingZeroes(n) => n as usize + format!("{:x}", self.data.len()).len() <= 16,
+            Size::Off(_) | Size::Raw(_) => false,
+        };
+        let ext_ok = self.ext.as_ref().map_or(true, |ext| {
+            ext.len() <= MAX_CHUNK_EXT_LEN && !ext.contains(['\r', '\n'])
+        });
+        !self.data.is_empty() && size_ok && ext_ok && matches!(self.terminator, Terminator::Crlf)
+    }
+
+    fn render(&self, out: &mut Vec<u8>) {
+        let len = self.data.len();
+        let size = match &self.size {
+            Size::Lower => format!("{len:x}"),
+            Size::Upper => format!("{len:X}"),
+            Size::LeadingZeroes(n) => format!("{}{len:x}", "0".repeat(*n as usize)),
+            Size::Off(delta) => format!("{:x}", len.saturating_add_signed(*delta as isize)),
+            Size::Raw(size) => size.clone(),
+        };
+        out.extend_from_slice(size.as_bytes());
+        if let Some(ext) = &self.ext {
+            out.push(b';');
+            out.extend_from_slice(ext.as_bytes());
+        }
+        out.extend_from_slice(b"\r\n");
+        out.extend_from_slice(&self.data);
+        match &self.terminator {
+            Terminator::Crlf => out.extend_from_slice(b"\r\n"),
+            Terminator::Lf => out.push(b'\n'),
+            Terminator::Missing => {}
+            Terminator::Raw(bytes) => out.extend_from_slice(bytes),
+        }
+    }
+}
+
+fuzz_target!(|input: (Input, Vec<u8>)| {
+    let (input, read_sizes) = input;
+    let (body, expected) = match input {
+        Input::Raw(body) => (body, None),
+        Input::Structured(chunks) => {
+            let mut body = Vec::new();
+            for chunk in &chunks {
+                chunk.render(&mut body);
+            }
+            body.extend_from_slice(b"0\r\n\r\n");
+            let expected = chunks
+                .iter()
+                .all(Chunk::is_well_formed)
+                .then(|| chunks.iter().flat_map(|chunk| chunk.data.clone()).collect());
+            (body, expected)
+        }
+    };
+
+    let decoded: Option<Vec<u8>> = read_h1_chunked_body(&body, &read_sizes);
+    if let Some(decoded) = &decoded {
+        assert!(decoded.len() <= body.len());
+    }
+    if let Some(expected) = expected {
+        assert_eq!(decoded, Some(expected));
+    }
+});
@@ -0,0 +1,161 @@
+//! Request heads, as bytes or built from parts and then mangled, must parse
+//! the same whether they arrive all at once or in pieces, and never panic.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use fluke::fuzzing::{parse_h1_request_head, read_h1_request_head};
+use libfuzzer_sys::fuzz_target;
+
+/// Like the server's default `max_http_header_len`
+const MAX_LEN: usize = 64 * 1024;
+
+#[derive(Arbitrary, Debug)]
+enum Input {
+    Raw(Vec<u8>),
+    Structured(Head),
+}
+
+#[derive(Arbitrary, Debug)]
+struct Head {
+    method: Method,
+    target: Target,
+    version: Version,
+    headers: Vec<(Name, Vec<u8>)>,
+    body: Vec<u8>,
+    mutations: Vec<Mutation>,
+}
+
+#[derive(Arbitrary, Debug)]
+enum Method {
+    Get,
+    Post,
+    Connect,
+    Options,
+    Other(String),
+}
+
+#[derive(Arbitrary, Debug)]
+enum Target {
+    Origin(String),
+    Absolute(String),
+    Authority(String),
+    Asterisk,
+}
+
+#[derive(Arbitrary, Debug)]
+enum Version {
+    Http11,
+    Http10,
+    Other(String),
+}
+
+/// Headers that matter for framing and routing come up more often than
+/// they would by chance
+#[derive(Arbitrary, Debug)]
+enum Name {
+    ContentLength,
+    TransferEncoding,
+    Host,
+    Connection,
+    Expect,
+    Other(String),
+}
+
+#[derive(Arbitrary, Debug)]
+enum Mutation {
+    Insert { at: u16, byte: u8 },
+    Remove { at: u16 },
+    Replace { at: u16, byte: u8 },
+    Repeat { at: u16, len: u8, times: u8 },
+}
+
+impl Head {
+    fn render(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match &self.method {
+            Method::Get => out.extend_from_slice(b"GET"),
+            Method::Post => out.extend_from_slice(b"POST"),
+            Method::Connect => out.extend_from_slice(b"CONNECT"),
+            Method::Options => out.extend_from_slice(b"OPTIONS"),
+            Method::Other(method) => out.extend_from_slice(method.as_bytes()),
+        }
+        out.push(b' ');
+        match &self.target {
+            Target::Origin(path) => {
+                out.push(b'/');
+                out.extend_from_slice(path.as_bytes());
+            }
+            Target::Absolute(rest) => {
+                out.extend_from_slice(b"http://");
+                out.extend_from_slice(rest.as_bytes());
+            }
+            Target::Authority(authority) => out.extend_from_slice(authority.as_bytes()),
+            Target::Asterisk => out.push(b'*'),
+        }
+        out.push(b' ');
+        match &self.version {
+            Version::Http11 => out.extend_from_slice(b"HTTP/1.1"),
+            Version::Http10 => out.extend_from_slice(b"HTTP/1.0"),
+            Version::Other(version) => out.extend_from_slice(version.as_bytes()),
+        }
+        out.extend_from_slice(b"\r\n");
+        for (name, value) in &self.headers {
+            match name {
+                Name::ContentLength => out.extend_from_slice(b"content-length"),
+                Name::TransferEncoding => out.extend_from_slice(b"transfer-encoding"),
+                Name::Host => out.extend_from_slice(b"host"),
+                Name::Connection => out.extend_from_slice(b"connection"),
+                Name::Expect => out.extend_from_slice(b"expect"),
+                Name::Other(name) => out.extend_from_slice(name.as_bytes()),
+            }
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value);
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(b"\r\n");
+        out.extend_from_slice(&self.body);
+
+        for mutation in &self.mutations {
+            let len = out.len();
+            let at = |at: u16| at as usize % (len + 1);
+            match *mutation {
+                Mutation::Insert { at: i, byte } => out.insert(at(i), byte),
+                Mutation::Remove { at: i } if !out.is_empty() => {
+                    out.remove(at(i) % len);
+                }
+                Mutation::Replace { at: i, byte } if !out.is_empty() => {
+                    let i = at(i) % len;
+                    out[i] = byte;
+                }
+                Mutation::Repeat { at: i, len, times } => {
+                    let start = at(i);
+                    let end = std::cmp::min(start + len as usize, out.len());
+                    if out.len() + (end - start) * times as usize <= MAX_LEN {
+                        let repeated = out[start..end].repeat(times as usize);
+                        out.splice(end..end, repeated);
+                    }
+                }
+                _ => {}
+            }
+        }
+        out
+    }
+}
+
+fuzz_target!(|input: (Input, Vec<u8>)| {
+    let (input, read_sizes) = input;
+    let bytes = match input {
+        Input::Raw(bytes) => bytes,
+        Input::Structured(head) => head.render(),
+    };
+    if bytes.len() > MAX_LEN {
+        return;
+    }
+
+    let parsed = parse_h1_request_head(&bytes);
+    if let Some(head) = &parsed {
+        assert!(head.consumed <= bytes.len());
+    }
+    assert_eq!(parsed, read_h1_request_head(&bytes, &read_sizes));
+});
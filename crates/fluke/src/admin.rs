@@ -0,0 +1,246 @@
+//! A driver serving runtime statistics as JSON, behind the `admin` feature,
+//! for debugging deployments without metrics infrastructure. It's meant for
+//! a listener of its own, that only operators can reach: it tells whoever
+//! asks who's connected.
+//!
+//! - `GET /stats`: the thread's load and buffer pool, and totals for each
+//!   [ConnectionRegistry]
+//! - `GET /connections`: every connection of each registry, with what its
+//!   protocol's handle knows about it
+//!
+//! Everything is per thread, like the pool and the registries are.
+
+use std::time::Instant;
+
+use fluke_maybe_uring::io::TransportAddr;
+use http::{header, StatusCode};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    json::write_json, Body, ConnectionProtocol, ConnectionRegistry, Encoder, ExpectResponseHeaders,
+    LiveConnection, Load, Method, Request, Responder, Response, ResponseDone, ServerDriver,
+};
+
+/// See the [module documentation](self)
+pub struct AdminDriver {
+    registries: Vec<(String, ConnectionRegistry)>,
+    started_at: Instant,
+}
+
+impl Default for AdminDriver {
+    fn default() -> Self {
+        Self {
+            registries: vec![],
+            started_at: Instant::now(),
+        }
+    }
+}
+
+impl AdminDriver {
+    /// Reports on the connections in `registry` under `name`, typically
+    /// that of the listener it's for
+    pub fn with_registry(mut self, name: impl Into<String>, registry: ConnectionRegistry) -> Self {
+        self.registries.push((name.into(), registry));
+        self
+    }
+
+    /// What `GET /stats` serves
+    pub fn stats(&self) -> Value {
+        let load = Load::current();
+        let num_free = fluke_buffet::num_free_bufs();
+
+        let mut listeners = Map::new();
+        for (name, registry) in &self.registries {
+            let (mut h1, mut h2, mut sniffing) = (0, 0, 0);
+            let (mut open_streams, mut running_handlers, mut busy) = (0, 0, 0);
+            for live in registry.connections() {
+                match live.protocol() {
+                    ConnectionProtocol::Sniffing => sniffing += 1,
+                    ConnectionProtocol::H1(handle) => {
+                        h1 += 1;
+                        busy += handle.is_busy() as usize;
+                    }
+                    ConnectionProtocol::H2(handle) => {
+                        h2 += 1;
+                        open_streams += handle.open_streams();
+                        running_handlers += handle.running_handlers();
+                    }
+                }
+            }
+            listeners.insert(
+                name.clone(),
+                json!({
+                    "connections": registry.len(),
+                    "h1_connections": h1,
+                    "h1_busy_connections": busy,
+                    "h2_connections": h2,
+                    "h2_open_streams": open_streams,
+                    "h2_running_handlers": running_handlers,
+                    "sniffing_connections": sniffing,
+                }),
+            );
+        }
+
+        json!({
+            "uptime_secs": self.started_at.elapsed().as_secs_f64(),
+            "active_handlers": load.active_handlers,
+            "event_loop_lag_ms": load.event_loop_lag.map(|lag| lag.as_secs_f64() * 1000.0),
+            "buf_pool": {
+                "buf_size": fluke_buffet::BUF_SIZE,
+                "total": fluke_buffet::NUM_BUF,
+                "free": num_free,
+                "usage": load.buf_pool_usage,
+            },
+            "listeners": listeners,
+        })
+    }
+
+    /// What `GET /connections` serves
+    pub fn connections(&self) -> Value {
+        let mut listeners = Map::new();
+        for (name, registry) in &self.registries {
+            let connections: Vec<Value> = registry.connections().iter().map(connection).collect();
+            listeners.insert(name.clone(), Value::Array(connections));
+        }
+        Value::Object(listeners)
+    }
+}
+
+fn connection(live: &LiveConnection) -> Value {
+    let mut value = json!({
+        "id": live.id(),
+        "label": live.label(),
+        "peer_addr": live.conn_info().peer_addr.as_ref().map(addr),
+        "tls": live.conn_info().tls.is_some(),
+        "age_secs": live.accepted_at().elapsed().as_secs_f64(),
+    });
+    let details = match live.protocol() {
+        ConnectionProtocol::Sniffing => json!({ "protocol": "sniffing" }),
+        ConnectionProtocol::H1(handle) => json!({
+            "protocol": "h1",
+            "requests": handle.requests(),
+            "busy": handle.is_busy(),
+            "shutting_down": handle.is_shutting_down(),
+        }),
+        ConnectionProtocol::H2(handle) => {
+            let stats = handle.stats();
+            json!({
+                "protocol": "h2",
+                "last_stream_id": handle.last_stream_id(),
+                "open_streams": handle.open_streams(),
+                "running_handlers": handle.running_handlers(),
+                "reaped_streams": handle.reaped_streams(),
+                "draining": handle.is_draining(),
+                "refusing_new_streams": handle.is_refusing_new_streams(),
+                "pings_received": stats.pings_received,
+                "settings_received": stats.settings_received,
+                "window_updates_received": stats.window_updates_received,
+                "rst_streams_received": stats.rst_streams_received,
+                "priority_updates_received": stats.priority_updates_received,
+            })
+        }
+    };
+    if let (Value::Object(value), Value::Object(details)) = (&mut value, details) {
+        value.extend(details);
+    }
+    value
+}
+
+fn addr(addr: &TransportAddr) -> String {
+    match addr {
+        TransportAddr::Inet(addr) => addr.to_string(),
+        TransportAddr::Unix(Some(path)) => path.display().to_string(),
+        TransportAddr::Unix(None) => "unix (unnamed)".to_string(),
+        TransportAddr::Other(addr) => addr.clone(),
+    }
+}
+
+impl ServerDriver for AdminDriver {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        _req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        let mut res = Response::default();
+        res.headers.insert(header::CACHE_CONTROL, "no-store".into());
+
+        let value: fn(&Self) -> Value = match req.uri.path() {
+            "/stats" => Self::stats,
+            "/connections" => Self::connections,
+            _ => {
+                res.status = StatusCode::NOT_FOUND;
+                return respond.write_final_response_with_body(res, &mut ()).await;
+            }
+        };
+        if req.method != Method::Get {
+            res.status = StatusCode::METHOD_NOT_ALLOWED;
+            res.headers.insert(header::ALLOW, "GET".into());
+            return respond.write_final_response_with_body(res, &mut ()).await;
+        }
+        write_json(respond, res, &value(self)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, time::Duration};
+
+    use fluke_buffet::RollMut;
+    use fluke_maybe_uring::io::{ChanRead, ChanWrite};
+
+    use super::AdminDriver;
+    use crate::{AutoConf, ConnectionRegistry};
+
+    #[test]
+    fn test_admin_driver() {
+        crate::maybe_uring::start(async move {
+            let registry = ConnectionRegistry::default();
+            let (_client_tx, read) = ChanRead::new();
+            let (_client_rx, write) = ChanWrite::new();
+            let driver = Rc::new(AdminDriver::default().with_registry("main", registry.clone()));
+            let _client = crate::maybe_uring::spawn({
+                let registry = registry.clone();
+                let driver = driver.clone();
+                async move {
+                    let conf = Rc::new(AutoConf::default());
+                    let buf = RollMut::alloc().unwrap();
+                    registry.serve_auto((read, write), conf, buf, driver).await
+                }
+            });
+            tokio::time::sleep(Duration::from_millis(10)).await;
+
+            let stats = driver.stats();
+            assert_eq!(stats["listeners"]["main"]["connections"], 1);
+            assert_eq!(stats["listeners"]["main"]["sniffing_connections"], 1);
+            let connections = driver.connections();
+            assert_eq!(connections["main"][0]["protocol"], "sniffing");
+
+            let (tx, read) = ChanRead::new();
+            let (mut rx, write) = ChanWrite::new();
+            let conf = Rc::new(crate::h1::ServerConf::default());
+            let serve = crate::maybe_uring::spawn(crate::h1::serve(
+                (read, write),
+                conf,
+                RollMut::alloc().unwrap(),
+                driver.clone(),
+            ));
+            tx.send(
+                b"GET /stats HTTP/1.1\r\n\r\nPOST /stats HTTP/1.1\r\nconnection: close\r\n\r\n"
+                    .to_vec(),
+            )
+            .await
+            .unwrap();
+            let mut out = vec![];
+            while let Some(bytes) = rx.recv().await {
+                out.extend(bytes);
+            }
+            let out = String::from_utf8(out).unwrap();
+            assert!(out.starts_with("HTTP/1.1 200 OK\r\n"), "{out}");
+            assert!(out.contains("content-type: application/json\r\n"), "{out}");
+            assert!(out.contains("\"sniffing_connections\":1"), "{out}");
+            assert!(out.contains("HTTP/1.1 405 Method Not Allowed\r\n"), "{out}");
+            serve.await.unwrap().unwrap();
+        });
+    }
+}
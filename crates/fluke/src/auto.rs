@@ -0,0 +1,213 @@
+use std::rc::Rc;
+
+use eyre::Context;
+use fluke_buffet::RollMut;
+use fluke_maybe_uring::io::{
+    ConnInfo, IntoHalves, ReadOwned, TlsInfo, Transport, TransportAddr, WriteOwned,
+};
+use tracing::debug;
+
+use crate::{h1, h2, ConnectionProtocol, LiveConnection, ServerDriver};
+
+/// Configuration for [serve_auto]: whichever protocol the client speaks is
+/// served with its own configuration.
+#[derive(Default)]
+pub struct AutoConf {
+    pub h1: Rc<h1::ServerConf>,
+    pub h2: Rc<h2::ServerConf>,
+}
+
+/// Serves a connection over HTTP/2 if the client starts with the h2
+/// connection preface (prior knowledge, cf.
+/// <https://httpwg.org/specs/rfc9113.html#known-http>), and over HTTP/1.1
+/// otherwise, so that both can be served on the same port without TLS
+/// (and ALPN) to tell them apart.
+///
+/// Only as many bytes as it takes to tell are read before handing the
+/// connection over, along with them.
+pub async fn serve_auto(
+    transport: impl Transport,
+    conf: Rc<AutoConf>,
+    client_buf: RollMut,
+    driver: Rc<impl ServerDriver + 'static>,
+) -> eyre::Result<()> {
+    serve_auto_inner(transport, conf, client_buf, driver, None).await
+}
+
+/// [serve_auto], reporting to `live` (if any) which protocol the connection
+/// speaks, and handing it the protocol's connection handle
+pub(crate) async fn serve_auto_inner(
+    transport: impl Transport,
+    conf: Rc<AutoConf>,
+    client_buf: RollMut,
+    driver: Rc<impl ServerDriver + 'static>,
+    live: Option<&LiveConnection>,
+) -> eyre::Result<()> {
+    let conn_info = ConnInfo::of(&transport);
+    let (mut transport_r, transport_w) = transport.into_halves();
+
+    let read_preface = read_preface(&mut transport_r, client_buf);
+    let client_buf = match live {
+        Some(live) => tokio::select! {
+            client_buf = read_preface => client_buf?,
+            _ = live.shutdown_requested() => {
+                debug!("shut down before the client said anything");
+                return Ok(());
+            }
+        },
+        None => read_preface.await?,
+    };
+    let Some(client_buf) = client_buf else {
+        debug!("client went away before sending anything");
+        return Ok(());
+    };
+
+    let transport = Sniffed {
+        halves: (transport_r, transport_w),
+        conn_info,
+    };
+    if client_buf[..].starts_with(h2::parse::PREFACE) {
+        debug!("got the h2 connection preface, serving h2");
+        let handle = h2::ConnectionHandle::default();
+        if let Some(live) = live {
+            live.set_protocol(ConnectionProtocol::H2(handle.clone()));
+        }
+        h2::serve_with_handle(transport, conf.h2.clone(), client_buf, driver, handle).await
+    } else {
+        debug!("no h2 connection preface, serving h1");
+        let handle = h1::ConnectionHandle::default();
+        if let Some(live) = live {
+            live.set_protocol(ConnectionProtocol::H1(handle.clone()));
+        }
+        let outcome =
+            h1::serve_with_handle(transport, conf.h1.clone(), client_buf, driver, handle).await?;
+        debug!(?outcome, "h1 connection done");
+        Ok(())
+    }
+}
+
+/// Reads as many bytes as it takes to tell whether the client starts with
+/// the h2 connection preface. Returns `None` if it went away before sending
+/// anything.
+async fn read_preface(
+    transport_r: &mut impl ReadOwned,
+    mut client_buf: RollMut,
+) -> eyre::Result<Option<RollMut>> {
+    let preface = h2::parse::PREFACE;
+    while client_buf.len() < preface.len() && preface.starts_with(&client_buf[..]) {
+        if client_buf.cap() == 0 {
+            client_buf.reserve()?;
+        }
+        let missing = preface.len() - client_buf.len();
+        let res;
+        (res, client_buf) = client_buf.read_into(missing, transport_r).await;
+        if res.wrap_err("reading connection preface")? == 0 {
+            if client_buf.is_empty() {
+                return Ok(None);
+            }
+            // whatever protocol it was, it's incomplete: h1 gets to say so
+            break;
+        }
+    }
+    Ok(Some(client_buf))
+}
+
+/// The halves of a transport that was split to look at its first bytes,
+/// which still knows what the transport knew
+struct Sniffed<R, W> {
+    halves: (R, W),
+    conn_info: ConnInfo,
+}
+
+impl<R: ReadOwned, W: WriteOwned> IntoHalves for Sniffed<R, W> {
+    type Read = R;
+    type Write = W;
+
+    fn into_halves(self) -> (R, W) {
+        self.halves
+    }
+}
+
+impl<R: ReadOwned, W: WriteOwned> Transport for Sniffed<R, W> {
+    fn local_addr(&self) -> Option<TransportAddr> {
+        self.conn_info.local_addr.clone()
+    }
+
+    fn peer_addr(&self) -> Option<TransportAddr> {
+        self.conn_info.peer_addr.clone()
+    }
+
+    fn tls_info(&self) -> Option<TlsInfo> {
+        self.conn_info.tls.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, time::Duration};
+
+    use fluke_buffet::RollMut;
+    use fluke_maybe_uring::io::{ChanRead, ChanWrite};
+    use http::StatusCode;
+
+    use super::{serve_auto, AutoConf};
+    use crate::{
+        Body, Encoder, ExpectResponseHeaders, Request, Responder, Response, ResponseDone,
+        ServerDriver,
+    };
+
+    struct NoContent;
+
+    impl ServerDriver for NoContent {
+        async fn handle<E: Encoder>(
+            &self,
+            _req: Request,
+            _req_body: &mut impl Body,
+            respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            let res = Response {
+                status: StatusCode::NO_CONTENT,
+                ..Default::default()
+            };
+            respond.write_final_response_with_body(res, &mut ()).await
+        }
+    }
+
+    #[test]
+    fn test_serve_auto() {
+        crate::maybe_uring::start(async move {
+            // the h1 request is sent in bits, the first of which could
+            // still be the start of the h2 preface
+            for (input, h2) in [
+                (&[&b"P"[..], b"OST / HTTP/1.1\r\n\r\n"][..], false),
+                (&[&b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"[..]], true),
+            ] {
+                let (tx, read) = ChanRead::new();
+                let (mut rx, write) = ChanWrite::new();
+                let serve = crate::maybe_uring::spawn(serve_auto(
+                    (read, write),
+                    Rc::new(AutoConf::default()),
+                    RollMut::alloc().unwrap(),
+                    Rc::new(NoContent),
+                ));
+                for bit in input {
+                    tx.send(bit.to_vec()).await.unwrap();
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+
+                // h2 servers start with a SETTINGS frame
+                let mut out = vec![];
+                while out.len() < 12 {
+                    out.extend(rx.recv().await.unwrap());
+                }
+                if h2 {
+                    assert_eq!(out[3], 0x4, "{out:?}");
+                } else {
+                    assert!(out.starts_with(b"HTTP/1.1 204"), "{out:?}");
+                }
+                drop(tx);
+                serve.abort();
+            }
+        });
+    }
+}
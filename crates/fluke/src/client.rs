@@ -0,0 +1,304 @@
+//! A fetch-style helper on top of the [h1 client](crate::h1::request), for
+//! when all that's wanted is a response to a URL: [fetch] resolves the host,
+//! connects, sends the request, and hands back the response head along with
+//! a body that streams in as it's read.
+//!
+//! fluke doesn't come with a TLS implementation: `https` URLs need a
+//! [Connector] that brings one, see [fetch_with].
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    rc::Rc,
+};
+
+use fluke_buffet::Piece;
+use http::{header, uri::Scheme, Uri};
+use tokio::sync::oneshot;
+use tracing::debug;
+
+use crate::{
+    h1::{self, ClientDriver},
+    maybe_uring::io::Transport,
+    proxy::{send_chunks, Resolver, SystemResolver, UpstreamBody},
+    Body, BodyChunk, Headers, Method, Request, Response,
+};
+
+/// What to send along with the URL, see [fetch]
+pub struct FetchOptions {
+    pub method: Method,
+
+    /// Sent as they are, along with a `host` header if there isn't one, and
+    /// a `content-length` for the body
+    pub headers: Headers,
+
+    /// `None` sends no body at all
+    pub body: Option<Piece>,
+
+    /// Where hostnames get resolved, [SystemResolver] by default. Hosts that
+    /// are IP addresses aren't resolved.
+    pub resolver: Rc<dyn Resolver>,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            method: Method::Get,
+            headers: Default::default(),
+            body: None,
+            resolver: Rc::new(SystemResolver),
+        }
+    }
+}
+
+/// How [fetch_with] gets a transport to a server
+#[allow(async_fn_in_trait)] // we never require Send
+pub trait Connector {
+    type Transport: Transport + 'static;
+
+    /// Connects to `addr`, with TLS if `tls` is set, in which case
+    /// `server_name` is what to send with SNI and check the certificate
+    /// against.
+    async fn connect(
+        &self,
+        addr: SocketAddr,
+        server_name: &str,
+        tls: bool,
+    ) -> eyre::Result<Self::Transport>;
+}
+
+/// Connects over plain TCP, which only does for `http` URLs
+#[cfg(feature = "maybe-uring-net")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpConnector;
+
+#[cfg(feature = "maybe-uring-net")]
+impl Connector for TcpConnector {
+    type Transport = crate::maybe_uring::net::TcpStream;
+
+    async fn connect(
+        &self,
+        addr: SocketAddr,
+        _server_name: &str,
+        tls: bool,
+    ) -> eyre::Result<Self::Transport> {
+        if tls {
+            return Err(eyre::eyre!(
+                "https needs a connector that does TLS, see `fetch_with`"
+            ));
+        }
+        Ok(crate::maybe_uring::net::TcpStream::connect(addr).await?)
+    }
+}
+
+/// Sends a request to `url`, over plain TCP: see [fetch_with] for `https`.
+#[cfg(feature = "maybe-uring-net")]
+pub async fn fetch(url: &str, options: FetchOptions) -> eyre::Result<(Response, FetchBody)> {
+    fetch_with(&TcpConnector, url, options).await
+}
+
+/// Sends a request to `url` over a transport from `connector`, and returns
+/// the final response head once it's in. Informational responses are
+/// ignored. The body is read on the connection's own task, as the returned
+/// [FetchBody] is: dropping it before the end closes the connection.
+///
+/// Each call makes a connection of its own. Trying every address the host
+/// resolves to in turn, the first one that connects is used.
+pub async fn fetch_with<C: Connector>(
+    connector: &C,
+    url: &str,
+    options: FetchOptions,
+) -> eyre::Result<(Response, FetchBody)> {
+    let uri: Uri = url.parse()?;
+    let tls = match uri.scheme() {
+        Some(scheme) if *scheme == Scheme::HTTP => false,
+        Some(scheme) if *scheme == Scheme::HTTPS => true,
+        _ => return Err(eyre::eyre!("can only fetch http and https URLs, not {url}")),
+    };
+    let authority = uri
+        .authority()
+        .ok_or_else(|| eyre::eyre!("no host in {url}"))?;
+    let host = authority.host();
+    let port = authority.port_u16().unwrap_or(if tls { 443 } else { 80 });
+
+    // IPv6 addresses are bracketed in URLs
+    let ip = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs = match ip.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => options.resolver.resolve(host, port).await?.addrs,
+    };
+    let mut transport = None;
+    for addr in addrs {
+        match connector.connect(addr, host, tls).await {
+            Ok(t) => {
+                transport = Some(t);
+                break;
+            }
+            Err(e) => debug!(%addr, "couldn't connect: {e}"),
+        }
+    }
+    let transport = transport.ok_or_else(|| eyre::eyre!("couldn't connect to {authority}"))?;
+
+    let mut req = Request {
+        method: options.method,
+        uri: uri.path_and_query().map_or("/", |pq| pq.as_str()).parse()?,
+        headers: options.headers,
+        ..Default::default()
+    };
+    if !req.headers.contains_key(header::HOST) {
+        req.headers.insert(
+            header::HOST,
+            authority.as_str().to_owned().into_bytes().into(),
+        );
+    }
+
+    let (head_tx, head_rx) = oneshot::channel();
+    let exchange = crate::maybe_uring::spawn(async move {
+        let mut body = FetchRequestBody(options.body);
+        h1::request(transport, req, &mut body, Fetch { head_tx }).await?;
+        Ok::<_, eyre::Report>(())
+    });
+
+    match head_rx.await {
+        Ok(head) => Ok(head),
+        // the exchange failed before the response head was in
+        Err(_) => Err(match exchange.await {
+            Ok(Err(e)) => e,
+            Ok(Ok(())) => eyre::eyre!("exchange ended without a response"),
+            Err(e) => e.into(),
+        }),
+    }
+}
+
+/// Hands the response head and body chunks over to whoever called
+/// [fetch_with]
+struct Fetch {
+    head_tx: oneshot::Sender<(Response, FetchBody)>,
+}
+
+impl ClientDriver for Fetch {
+    type Return = ();
+
+    async fn on_final_response(self, res: Response, body: &mut impl Body) -> eyre::Result<()> {
+        let (chunks_tx, fetch_body) = UpstreamBody::channel(body.content_len());
+        if self.head_tx.send((res, fetch_body)).is_err() {
+            debug!("nobody's waiting for the response anymore");
+            return Ok(());
+        }
+        send_chunks(body, chunks_tx).await
+    }
+}
+
+/// The response body returned by [fetch]
+pub type FetchBody = UpstreamBody;
+
+/// [FetchOptions::body], sent in one go
+#[derive(Debug)]
+struct FetchRequestBody(Option<Piece>);
+
+impl Body for FetchRequestBody {
+    fn content_len(&self) -> Option<u64> {
+        Some(self.0.as_ref().map_or(0, |piece| piece.len() as u64))
+    }
+
+    fn eof(&self) -> bool {
+        self.0.is_none()
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        Ok(match self.0.take() {
+            Some(piece) => BodyChunk::Chunk(piece),
+            None => BodyChunk::Done { trailers: None },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, net::SocketAddr};
+
+    use fluke_maybe_uring::io::{ChanRead, ChanWrite};
+    use http::StatusCode;
+
+    use super::{fetch_with, Connector, FetchOptions};
+    use crate::{Body, BodyChunk, Method};
+
+    /// Hands out a single channel transport, and remembers where it was
+    /// asked to connect
+    #[derive(Default)]
+    struct Chan {
+        transport: RefCell<Option<(ChanRead, ChanWrite)>>,
+        connected_to: RefCell<Option<(SocketAddr, String, bool)>>,
+    }
+
+    impl Connector for Chan {
+        type Transport = (ChanRead, ChanWrite);
+
+        async fn connect(
+            &self,
+            addr: SocketAddr,
+            server_name: &str,
+            tls: bool,
+        ) -> eyre::Result<Self::Transport> {
+            *self.connected_to.borrow_mut() = Some((addr, server_name.to_owned(), tls));
+            Ok(self.transport.borrow_mut().take().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_fetch() {
+        crate::maybe_uring::start(async move {
+            let (tx, read) = ChanRead::new();
+            let (mut rx, write) = ChanWrite::new();
+            let connector = Chan {
+                transport: RefCell::new(Some((read, write))),
+                ..Default::default()
+            };
+
+            let server = crate::maybe_uring::spawn(async move {
+                let mut req = vec![];
+                while !req.ends_with(b"\r\n\r\nping") {
+                    req.extend(rx.recv().await.unwrap());
+                }
+                let req = String::from_utf8(req).unwrap();
+                assert!(req.starts_with("POST /hello?a=b HTTP/1.1\r\n"), "{req}");
+                assert!(req.contains("host: 127.0.0.1:8080\r\n"), "{req}");
+                assert!(req.contains("content-length: 4\r\n"), "{req}");
+
+                tx.send("HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n")
+                    .await
+                    .unwrap();
+                tx.send("3\r\npon\r\n").await.unwrap();
+                tx.send("1\r\ng\r\n0\r\n\r\n").await.unwrap();
+                (tx, rx)
+            });
+
+            let options = FetchOptions {
+                method: Method::Post,
+                body: Some("ping".into()),
+                ..Default::default()
+            };
+            let (res, mut body) =
+                fetch_with(&connector, "http://127.0.0.1:8080/hello?a=b", options)
+                    .await
+                    .unwrap();
+            assert_eq!(
+                connector.connected_to.take(),
+                Some(("127.0.0.1:8080".parse().unwrap(), "127.0.0.1".into(), false))
+            );
+            assert_eq!(res.status, StatusCode::OK);
+            assert_eq!(body.content_len(), None);
+
+            let mut out = vec![];
+            while let BodyChunk::Chunk(chunk) = body.next_chunk().await.unwrap() {
+                out.extend_from_slice(&chunk[..]);
+            }
+            assert_eq!(out, b"pong");
+            assert!(body.eof());
+            drop(server.await.unwrap());
+
+            // nothing to connect to
+            let err = fetch_with(&connector, "ftp://example.org/", Default::default()).await;
+            assert!(err.is_err());
+        });
+    }
+}
@@ -0,0 +1,338 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use fluke_buffet::Piece;
+use tokio::sync::{Notify, Semaphore};
+
+use crate::{Body, BodyChunk, Encoder, ExpectResponseBody, Headers, Responder, ResponseDone};
+
+/// How [copy_body] pumps a body
+#[derive(Debug, Clone, Copy)]
+pub struct CopyConf {
+    /// How many bytes may be read from the source ahead of what's been
+    /// written to the destination. Over HTTP/2, writes wait for flow control
+    /// window, so this is what bounds memory use when the peer is slow.
+    /// A chunk larger than this is still read, but only once all
+    /// previous ones have been written.
+    pub max_in_flight: usize,
+}
+
+impl Default for CopyConf {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 64 * 1024,
+        }
+    }
+}
+
+/// What [copy_body] transferred
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CopyStats {
+    pub bytes: u64,
+    pub chunks: u64,
+
+    /// Whether the source had trailers. They're forwarded to `dst`, which
+    /// may drop them, see [Responder::finish_body].
+    pub trailers: bool,
+
+    /// The most bytes that were read but not yet written at any point
+    pub max_in_flight: usize,
+
+    pub elapsed: Duration,
+}
+
+/// Pumps `src` into `dst` until the end of `src`, then finishes `dst`
+/// with the trailers of `src`, if any.
+///
+/// Reading and writing happen concurrently, bounded by
+/// [CopyConf::max_in_flight]: a slow destination slows down reads from the
+/// source, and a slow source doesn't stall writes of what's already been
+/// read.
+pub async fn copy_body<E: Encoder>(
+    src: &mut impl Body,
+    mut dst: Responder<E, ExpectResponseBody>,
+    conf: &CopyConf,
+) -> eyre::Result<(Responder<E, ResponseDone>, CopyStats)> {
+    let start = Instant::now();
+    let max_in_flight =
+        conf.max_in_flight
+            .clamp(1, std::cmp::min(Semaphore::MAX_PERMITS, u32::MAX as usize)) as u32;
+    let budget = Semaphore::new(max_in_flight as usize);
+    let queue: RefCell<VecDeque<Piece>> = Default::default();
+    let done = Cell::new(false);
+    let trailers: RefCell<Option<Box<Headers>>> = Default::default();
+    let wakeup = Notify::new();
+    let mut stats = CopyStats::default();
+
+    let read = async {
+        loop {
+            match src.next_chunk().await? {
+                BodyChunk::Chunk(chunk) => {
+                    let permits = std::cmp::min(chunk.len(), max_in_flight as usize) as u32;
+                    budget.acquire_many(permits).await?.forget();
+                    queue.borrow_mut().push_back(chunk);
+                }
+                BodyChunk::Done { trailers: t } => {
+                    *trailers.borrow_mut() = t;
+                    done.set(true);
+                    wakeup.notify_one();
+                    return Ok::<_, eyre::Report>(());
+                }
+            }
+            wakeup.notify_one();
+        }
+    };
+
+    let write = async {
+        let mut in_flight = 0;
+        loop {
+            let chunk = queue.borrow_mut().pop_front();
+            let Some(chunk) = chunk else {
+                if done.get() {
+                    return Ok::<_, eyre::Report>(());
+                }
+                wakeup.notified().await;
+                continue;
+            };
+
+            in_flight = std::cmp::max(
+                in_flight,
+                chunk.len() + queue.borrow().iter().map(|c| c.len()).sum::<usize>(),
+            );
+            let len = chunk.len();
+            dst.write_chunk(chunk).await?;
+            stats.bytes += len as u64;
+            stats.chunks += 1;
+            stats.max_in_flight = in_flight;
+            budget.add_permits(std::cmp::min(len, max_in_flight as usize));
+        }
+    };
+
+    tokio::try_join!(read, write)?;
+
+    let trailers = trailers.into_inner();
+    stats.trailers = trailers.is_some();
+    let dst = dst.finish_body(trailers).await?;
+    stats.elapsed = start.elapsed();
+    Ok((dst, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        collections::VecDeque,
+        rc::Rc,
+        time::Duration,
+    };
+
+    use fluke_buffet::Piece;
+    use tokio::sync::Semaphore;
+
+    use super::{copy_body, CopyConf};
+    use crate::{
+        Body, BodyChunk, BodyWriteMode, Encoder, ExpectResponseBody, ExpectResponseHeaders,
+        Headers, Responder, Response,
+    };
+
+    /// Yields its chunks in order (failing where there's `None`), then ends
+    /// with trailers if asked to. Counts the chunks that were read.
+    struct Source {
+        chunks: VecDeque<Option<&'static str>>,
+        trailers: bool,
+        read: Rc<Cell<usize>>,
+    }
+
+    impl Source {
+        fn new(chunks: impl IntoIterator<Item = Option<&'static str>>) -> Self {
+            Self {
+                chunks: chunks.into_iter().collect(),
+                trailers: false,
+                read: Default::default(),
+            }
+        }
+    }
+
+    impl Body for Source {
+        fn content_len(&self) -> Option<u64> {
+            None
+        }
+
+        fn eof(&self) -> bool {
+            self.chunks.is_empty()
+        }
+
+        async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+            match self.chunks.pop_front() {
+                Some(Some(chunk)) => {
+                    self.read.set(self.read.get() + 1);
+                    Ok(BodyChunk::Chunk(chunk.into()))
+                }
+                Some(None) => Err(eyre::eyre!("source went away")),
+                None => Ok(BodyChunk::Done {
+                    trailers: self.trailers.then(Default::default),
+                }),
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Written {
+        body: Vec<u8>,
+        ended: bool,
+        trailers: bool,
+    }
+
+    /// Writes a chunk for each permit of `gate`, or fails if `fail` is set
+    struct Sink {
+        gate: Rc<Semaphore>,
+        fail: bool,
+        written: Rc<RefCell<Written>>,
+    }
+
+    impl Encoder for Sink {
+        async fn write_response(&mut self, _res: Response) -> eyre::Result<()> {
+            Ok(())
+        }
+
+        async fn write_body_chunk(
+            &mut self,
+            chunk: Piece,
+            _mode: BodyWriteMode,
+        ) -> eyre::Result<()> {
+            self.gate.acquire().await?.forget();
+            if self.fail {
+                return Err(eyre::eyre!("sink went away"));
+            }
+            self.written.borrow_mut().body.extend_from_slice(&chunk[..]);
+            Ok(())
+        }
+
+        async fn write_body_end(&mut self, _mode: BodyWriteMode) -> eyre::Result<()> {
+            self.written.borrow_mut().ended = true;
+            Ok(())
+        }
+
+        async fn write_trailers(&mut self, _trailers: Box<Headers>) -> eyre::Result<()> {
+            let mut written = self.written.borrow_mut();
+            written.ended = true;
+            written.trailers = true;
+            Ok(())
+        }
+    }
+
+    async fn responder(sink: Sink) -> Responder<Sink, ExpectResponseBody> {
+        let respond = Responder {
+            encoder: sink,
+            state: ExpectResponseHeaders,
+        };
+        respond
+            .write_final_response(Response::default())
+            .await
+            .unwrap()
+    }
+
+    /// Lets whatever can make progress do so
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    #[test]
+    fn test_copy_body_backpressure() {
+        crate::maybe_uring::start(async move {
+            tokio::time::pause();
+            let gate = Rc::new(Semaphore::new(0));
+            let written: Rc<RefCell<Written>> = Default::default();
+            let mut src = Source::new([Some("ab"); 8]);
+            src.trailers = true;
+            let read = src.read.clone();
+            let dst = responder(Sink {
+                gate: gate.clone(),
+                fail: false,
+                written: written.clone(),
+            })
+            .await;
+
+            let copy = crate::maybe_uring::spawn(async move {
+                let conf = CopyConf { max_in_flight: 4 };
+                copy_body(&mut src, dst, &conf)
+                    .await
+                    .map(|(_, stats)| stats)
+            });
+
+            // nothing gets written: two chunks fit in flight, the third one
+            // waits for room
+            settle().await;
+            assert_eq!(read.get(), 3);
+            assert!(written.borrow().body.is_empty());
+
+            // writing one makes room for the next one
+            gate.add_permits(1);
+            settle().await;
+            assert_eq!(read.get(), 4);
+            assert_eq!(written.borrow().body, b"ab");
+
+            gate.add_permits(Semaphore::MAX_PERMITS - 1);
+            let stats = copy.await.unwrap().unwrap();
+            assert_eq!(stats.bytes, 16);
+            assert_eq!(stats.chunks, 8);
+            assert_eq!(stats.max_in_flight, 4);
+            assert!(stats.trailers);
+
+            let written = written.borrow();
+            assert_eq!(written.body, b"ab".repeat(8));
+            assert!(written.ended);
+            assert!(written.trailers);
+        });
+    }
+
+    #[test]
+    fn test_copy_body_source_error() {
+        crate::maybe_uring::start(async move {
+            let written: Rc<RefCell<Written>> = Default::default();
+            let mut src = Source::new([Some("ab"), Some("cd"), None, Some("ef")]);
+            let dst = responder(Sink {
+                gate: Rc::new(Semaphore::new(Semaphore::MAX_PERMITS)),
+                fail: false,
+                written: written.clone(),
+            })
+            .await;
+
+            let err = copy_body(&mut src, dst, &Default::default())
+                .await
+                .err()
+                .unwrap();
+            assert!(err.to_string().contains("source went away"), "{err}");
+
+            // the body isn't ended as if it were complete
+            assert!(!written.borrow().ended);
+            assert_eq!(src.chunks.len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_copy_body_sink_error() {
+        crate::maybe_uring::start(async move {
+            let written: Rc<RefCell<Written>> = Default::default();
+            let mut src = Source::new([Some("ab"); 100]);
+            let read = src.read.clone();
+            let dst = responder(Sink {
+                gate: Rc::new(Semaphore::new(Semaphore::MAX_PERMITS)),
+                fail: true,
+                written: written.clone(),
+            })
+            .await;
+
+            let conf = CopyConf { max_in_flight: 4 };
+            let err = copy_body(&mut src, dst, &conf).await.err().unwrap();
+            assert!(err.to_string().contains("sink went away"), "{err}");
+
+            // the source isn't read any further than what was in flight
+            assert!(read.get() <= 3, "{} chunks read", read.get());
+            assert!(!written.borrow().ended);
+        });
+    }
+}
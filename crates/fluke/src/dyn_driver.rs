@@ -0,0 +1,207 @@
+use std::fmt::Debug;
+
+use futures_util::future::LocalBoxFuture;
+
+use crate::{
+    Body, BodyChunk, BodyWriteMode, Encoder, ExpectResponseHeaders, Headers, Request,
+    RequestLimits, Responder, Response, ResponseDone, ServerDriver,
+};
+use fluke_buffet::Piece;
+
+/// An object-safe version of [ServerDriver], for when the driver is picked
+/// at runtime (from configuration, a plugin, etc.) and has to be boxed.
+///
+/// Any [ServerDriver] is a [DynServerDriver], and a boxed [DynServerDriver]
+/// is a [ServerDriver] that can be passed to [h1::serve](crate::h1::serve) or
+/// [h2::serve](crate::h2::serve). This costs a heap allocation per call into
+/// the body or the encoder, compared to a driver that's known statically.
+pub trait DynServerDriver {
+    fn handle_dyn<'a>(
+        &'a self,
+        req: Request,
+        req_body: &'a mut dyn DynBody,
+        respond: Responder<DynEncoderRef<'a>, ExpectResponseHeaders>,
+    ) -> LocalBoxFuture<'a, eyre::Result<Responder<DynEncoderRef<'a>, ResponseDone>>>;
+
+    /// See [ServerDriver::request_limits]
+    fn request_limits_dyn(&self, _req: &Request, _limits: &mut RequestLimits) {}
+
+    /// See [ServerDriver::error_response]
+    fn error_response_dyn(&self, err: &eyre::Report) -> (Response, Piece);
+}
+
+impl<D: ServerDriver> DynServerDriver for D {
+    fn handle_dyn<'a>(
+        &'a self,
+        req: Request,
+        mut req_body: &'a mut dyn DynBody,
+        respond: Responder<DynEncoderRef<'a>, ExpectResponseHeaders>,
+    ) -> LocalBoxFuture<'a, eyre::Result<Responder<DynEncoderRef<'a>, ResponseDone>>> {
+        Box::pin(async move { self.handle(req, &mut req_body, respond).await })
+    }
+
+    fn request_limits_dyn(&self, req: &Request, limits: &mut RequestLimits) {
+        self.request_limits(req, limits)
+    }
+
+    fn error_response_dyn(&self, err: &eyre::Report) -> (Response, Piece) {
+        self.error_response(err)
+    }
+}
+
+impl ServerDriver for Box<dyn DynServerDriver> {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        // the encoder is lent to the dynamic driver rather than boxed, so
+        // that it can be handed back (along with e.g. the h1 transport)
+        // once the response is done.
+        let Responder { mut encoder, state } = respond;
+        let respond = Responder {
+            encoder: DynEncoderRef {
+                inner: &mut encoder,
+            },
+            state,
+        };
+        self.as_ref().handle_dyn(req, req_body, respond).await?;
+
+        Ok(Responder {
+            encoder,
+            state: ResponseDone,
+        })
+    }
+
+    fn request_limits(&self, req: &Request, limits: &mut RequestLimits) {
+        self.as_ref().request_limits_dyn(req, limits)
+    }
+
+    fn error_response(&self, err: &eyre::Report) -> (Response, Piece) {
+        self.as_ref().error_response_dyn(err)
+    }
+}
+
+/// An object-safe version of [Body], see [DynServerDriver]
+pub trait DynBody: Debug {
+    fn content_len(&self) -> Option<u64>;
+    fn eof(&self) -> bool;
+    fn next_chunk(&mut self) -> LocalBoxFuture<'_, eyre::Result<BodyChunk>>;
+}
+
+impl<B: Body> DynBody for B {
+    fn content_len(&self) -> Option<u64> {
+        Body::content_len(self)
+    }
+
+    fn eof(&self) -> bool {
+        Body::eof(self)
+    }
+
+    fn next_chunk(&mut self) -> LocalBoxFuture<'_, eyre::Result<BodyChunk>> {
+        Box::pin(Body::next_chunk(self))
+    }
+}
+
+impl Body for &mut dyn DynBody {
+    fn content_len(&self) -> Option<u64> {
+        (**self).content_len()
+    }
+
+    fn eof(&self) -> bool {
+        (**self).eof()
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        (**self).next_chunk().await
+    }
+}
+
+/// An object-safe version of [Encoder], see [DynServerDriver]
+pub trait DynEncoder {
+    fn write_response(&mut self, res: Response) -> LocalBoxFuture<'_, eyre::Result<()>>;
+    fn write_body_chunk(
+        &mut self,
+        chunk: Piece,
+        mode: BodyWriteMode,
+    ) -> LocalBoxFuture<'_, eyre::Result<()>>;
+    fn write_body_end(&mut self, mode: BodyWriteMode) -> LocalBoxFuture<'_, eyre::Result<()>>;
+    fn write_last_body_chunk(
+        &mut self,
+        chunk: Piece,
+        mode: BodyWriteMode,
+    ) -> LocalBoxFuture<'_, eyre::Result<()>>;
+    fn write_trailers(&mut self, trailers: Box<Headers>) -> LocalBoxFuture<'_, eyre::Result<()>>;
+    fn set_corked(&mut self, corked: bool) -> eyre::Result<()>;
+}
+
+impl<E: Encoder> DynEncoder for E {
+    fn write_response(&mut self, res: Response) -> LocalBoxFuture<'_, eyre::Result<()>> {
+        Box::pin(Encoder::write_response(self, res))
+    }
+
+    fn write_body_chunk(
+        &mut self,
+        chunk: Piece,
+        mode: BodyWriteMode,
+    ) -> LocalBoxFuture<'_, eyre::Result<()>> {
+        Box::pin(Encoder::write_body_chunk(self, chunk, mode))
+    }
+
+    fn write_body_end(&mut self, mode: BodyWriteMode) -> LocalBoxFuture<'_, eyre::Result<()>> {
+        Box::pin(Encoder::write_body_end(self, mode))
+    }
+
+    fn write_last_body_chunk(
+        &mut self,
+        chunk: Piece,
+        mode: BodyWriteMode,
+    ) -> LocalBoxFuture<'_, eyre::Result<()>> {
+        Box::pin(Encoder::write_last_body_chunk(self, chunk, mode))
+    }
+
+    fn write_trailers(&mut self, trailers: Box<Headers>) -> LocalBoxFuture<'_, eyre::Result<()>> {
+        Box::pin(Encoder::write_trailers(self, trailers))
+    }
+
+    fn set_corked(&mut self, corked: bool) -> eyre::Result<()> {
+        Encoder::set_corked(self, corked)
+    }
+}
+
+/// The [Encoder] a [DynServerDriver] responds with: it borrows whatever
+/// encoder the connection uses.
+pub struct DynEncoderRef<'a> {
+    inner: &'a mut dyn DynEncoder,
+}
+
+impl Encoder for DynEncoderRef<'_> {
+    async fn write_response(&mut self, res: Response) -> eyre::Result<()> {
+        self.inner.write_response(res).await
+    }
+
+    async fn write_body_chunk(&mut self, chunk: Piece, mode: BodyWriteMode) -> eyre::Result<()> {
+        self.inner.write_body_chunk(chunk, mode).await
+    }
+
+    async fn write_body_end(&mut self, mode: BodyWriteMode) -> eyre::Result<()> {
+        self.inner.write_body_end(mode).await
+    }
+
+    async fn write_last_body_chunk(
+        &mut self,
+        chunk: Piece,
+        mode: BodyWriteMode,
+    ) -> eyre::Result<()> {
+        self.inner.write_last_body_chunk(chunk, mode).await
+    }
+
+    async fn write_trailers(&mut self, trailers: Box<Headers>) -> eyre::Result<()> {
+        self.inner.write_trailers(trailers).await
+    }
+
+    fn set_corked(&mut self, corked: bool) -> eyre::Result<()> {
+        self.inner.set_corked(corked)
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    ops::Range,
+};
+
+use fluke_buffet::RollMut;
+
+use crate::{Body, BodyChunk};
+
+/// A range of a file, as a [Body]: typically [FileResponse::range](super::FileResponse::range),
+/// to be sent with [FileResponse::response](super::FileResponse::response).
+///
+/// The file is read into pool buffers with blocking reads, which is fine
+/// for local filesystems. It failing to have as many bytes as announced
+/// (because it was truncated while being served) is an error.
+pub struct FileBody {
+    file: File,
+    pos: u64,
+    range: Range<u64>,
+    buf: RollMut,
+}
+
+impl FileBody {
+    pub fn new(file: File, range: Range<u64>) -> Self {
+        Self {
+            file,
+            pos: range.start,
+            range,
+            buf: RollMut::empty(),
+        }
+    }
+}
+
+impl fmt::Debug for FileBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileBody")
+            .field("pos", &self.pos)
+            .field("range", &self.range)
+            .finish()
+    }
+}
+
+impl Body for FileBody {
+    fn content_len(&self) -> Option<u64> {
+        Some(self.range.end - self.range.start)
+    }
+
+    fn eof(&self) -> bool {
+        self.pos >= self.range.end
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        if self.eof() {
+            return Ok(BodyChunk::Done { trailers: None });
+        }
+
+        self.buf.reserve()?;
+        let len = std::cmp::min(self.buf.cap() as u64, self.range.end - self.pos) as usize;
+        self.file.seek(SeekFrom::Start(self.pos))?;
+        self.buf
+            .put_with(len, |slice| self.file.read_exact(slice))
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => eyre::eyre!(
+                    "file is shorter than the {} bytes announced",
+                    self.range.end
+                ),
+                _ => e.into(),
+            })?;
+        let chunk = self.buf.take_all();
+        self.pos += len as u64;
+        Ok(BodyChunk::Chunk(chunk.into()))
+    }
+}
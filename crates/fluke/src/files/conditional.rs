@@ -0,0 +1,393 @@
+use std::{
+    fmt::Write as _,
+    fs::Metadata,
+    ops::Range,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use http::{header, HeaderName, StatusCode};
+
+use crate::{Headers, Method, Request, Response};
+
+use super::{fmt_http_date, parse_http_date};
+
+/// A strong entity tag, quotes included, cf. <https://httpwg.org/specs/rfc9110.html#field.etag>
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ETag(String);
+
+impl ETag {
+    /// Derived from the size and modification time of a file: cheap, and
+    /// good enough unless files get rewritten with different contents of the
+    /// same size within the timestamp granularity of the filesystem.
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let mut tag = format!("\"{:x}", metadata.len());
+        if let Some(mtime) = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        {
+            write!(tag, "-{:x}.{:x}", mtime.as_secs(), mtime.subsec_nanos()).unwrap();
+        }
+        tag.push('"');
+        Self(tag)
+    }
+
+    /// Derived from a hash of the contents, e.g. computed when files are
+    /// published, for artifacts that get rebuilt with identical contents.
+    pub fn from_hash(hash: &[u8]) -> Self {
+        let mut tag = String::with_capacity(hash.len() * 2 + 2);
+        tag.push('"');
+        for b in hash {
+            write!(tag, "{b:02x}").unwrap();
+        }
+        tag.push('"');
+        Self(tag)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether `list` (the value of `if-match` or `if-none-match`) matches,
+    /// with weak comparison if `weak` is set, and strong comparison
+    /// otherwise: then, weak tags in `list` never match.
+    fn matches(&self, list: &str, weak: bool) -> bool {
+        list.split(',').map(str::trim).any(|tag| {
+            if tag == "*" {
+                return true;
+            }
+            match tag.strip_prefix("W/") {
+                Some(tag) => weak && tag == self.0,
+                None => tag == self.0,
+            }
+        })
+    }
+}
+
+/// What a file is identified by for conditional requests, see
+/// [FileResponse::new]
+#[derive(Debug, Clone)]
+pub struct Validators {
+    pub etag: ETag,
+
+    /// Compared with a one-second granularity
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// An [ETag::from_metadata], and the modification time
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        Self {
+            etag: ETag::from_metadata(metadata),
+            last_modified: metadata.modified().ok(),
+        }
+    }
+}
+
+/// How to respond to a request for a file, once conditional request headers
+/// (`if-match`, `if-none-match`, `if-modified-since`,
+/// `if-unmodified-since`) and `range` / `if-range` have been looked at, cf.
+/// <https://httpwg.org/specs/rfc9110.html#evaluation>.
+pub struct FileResponse {
+    /// 200, 206, 304, 412 or 416, with `etag`, `last-modified`,
+    /// `accept-ranges`, `content-length` and `content-range` set as needed.
+    /// `content-type` is left to the caller.
+    pub response: Response,
+
+    /// The bytes of the file to send as the body: empty for responses that
+    /// have none, including responses to `HEAD` requests.
+    pub range: Range<u64>,
+}
+
+impl FileResponse {
+    /// `len` is the size of the file. Only single ranges are served:
+    /// requests for several get the whole file, which is allowed and saves
+    /// sending `multipart/byteranges`.
+    pub fn new(req: &Request, len: u64, validators: &Validators) -> Self {
+        let mut res = Response::default();
+        let h = &mut res.headers;
+        h.insert(
+            header::ETAG,
+            validators.etag.as_str().to_owned().into_bytes().into(),
+        );
+        // dates are sent, and compared, with one-second granularity
+        let last_modified = validators.last_modified.map(truncate_to_secs);
+        if let Some(last_modified) = last_modified {
+            h.insert(
+                header::LAST_MODIFIED,
+                fmt_http_date(last_modified).into_bytes().into(),
+            );
+        }
+        h.insert(header::ACCEPT_RANGES, "bytes".into());
+
+        let status = Self::evaluate(req, len, validators, last_modified);
+        let range = match status {
+            Ok(range) => range,
+            Err(status) => {
+                res.status = status;
+                if status == StatusCode::RANGE_NOT_SATISFIABLE {
+                    res.headers.insert(
+                        header::CONTENT_RANGE,
+                        format!("bytes */{len}").into_bytes().into(),
+                    );
+                }
+                // a 304 has no body either way, and a `content-length` there
+                // would be taken for that of the file
+                if status != StatusCode::NOT_MODIFIED {
+                    res.headers.insert(header::CONTENT_LENGTH, "0".into());
+                }
+                return Self {
+                    response: res,
+                    range: 0..0,
+                };
+            }
+        };
+
+        if let Some(range) = &range {
+            res.status = StatusCode::PARTIAL_CONTENT;
+            res.headers.insert(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{len}", range.start, range.end - 1)
+                    .into_bytes()
+                    .into(),
+            );
+        }
+        let range = range.unwrap_or(0..len);
+        res.headers.insert(
+            header::CONTENT_LENGTH,
+            format!("{}", range.end - range.start).into_bytes().into(),
+        );
+
+        Self {
+            response: res,
+            range: if req.method == Method::Head {
+                0..0
+            } else {
+                range
+            },
+        }
+    }
+
+    /// The range to send (`None` for the whole file), or the status to
+    /// respond with instead
+    fn evaluate(
+        req: &Request,
+        len: u64,
+        validators: &Validators,
+        last_modified: Option<SystemTime>,
+    ) -> Result<Option<Range<u64>>, StatusCode> {
+        let headers = &req.headers;
+        let get_or_head = matches!(req.method, Method::Get | Method::Head);
+
+        if let Some(if_match) = header_str(headers, header::IF_MATCH) {
+            if !validators.etag.matches(if_match, false) {
+                return Err(StatusCode::PRECONDITION_FAILED);
+            }
+        } else if let (Some(since), Some(last_modified)) = (
+            header_date(headers, header::IF_UNMODIFIED_SINCE),
+            last_modified,
+        ) {
+            if last_modified > since {
+                return Err(StatusCode::PRECONDITION_FAILED);
+            }
+        }
+
+        if let Some(if_none_match) = header_str(headers, header::IF_NONE_MATCH) {
+            if validators.etag.matches(if_none_match, true) {
+                return Err(if get_or_head {
+                    StatusCode::NOT_MODIFIED
+                } else {
+                    StatusCode::PRECONDITION_FAILED
+                });
+            }
+        } else if let (true, Some(since), Some(last_modified)) = (
+            get_or_head,
+            header_date(headers, header::IF_MODIFIED_SINCE),
+            last_modified,
+        ) {
+            if last_modified <= since {
+                return Err(StatusCode::NOT_MODIFIED);
+            }
+        }
+
+        if req.method != Method::Get {
+            return Ok(None);
+        }
+        let Some(range) = header_str(headers, header::RANGE) else {
+            return Ok(None);
+        };
+        if let Some(if_range) = header_str(headers, header::IF_RANGE) {
+            // a date only validates if it's exactly the modification time
+            let still_valid = if if_range.starts_with('"') {
+                validators.etag.as_str() == if_range
+            } else {
+                matches!(
+                    (parse_http_date(if_range), last_modified),
+                    (Some(date), Some(last_modified)) if date == last_modified
+                )
+            };
+            if !still_valid {
+                return Ok(None);
+            }
+        }
+
+        match parse_range(range, len) {
+            RangeSpec::Satisfiable(range) => Ok(Some(range)),
+            RangeSpec::Unsatisfiable => Err(StatusCode::RANGE_NOT_SATISFIABLE),
+            RangeSpec::Ignored => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum RangeSpec {
+    Satisfiable(Range<u64>),
+    Unsatisfiable,
+
+    /// Malformed, in another unit, or with several ranges
+    Ignored,
+}
+
+/// Parses a `range` header, cf. <https://httpwg.org/specs/rfc9110.html#field.range>
+fn parse_range(value: &str, len: u64) -> RangeSpec {
+    let Some((unit, spec)) = value.split_once('=') else {
+        return RangeSpec::Ignored;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") || spec.contains(',') {
+        return RangeSpec::Ignored;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return RangeSpec::Ignored;
+    };
+    let parse = |s: &str| {
+        (!s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()))
+            .then(|| s.parse::<u64>().ok())
+            .flatten()
+    };
+
+    match (start, end) {
+        // the last `end` bytes
+        ("", end) => match parse(end) {
+            Some(0) => RangeSpec::Unsatisfiable,
+            Some(_) if len == 0 => RangeSpec::Unsatisfiable,
+            Some(suffix) => RangeSpec::Satisfiable(len.saturating_sub(suffix)..len),
+            None => RangeSpec::Ignored,
+        },
+        (start, end) => {
+            let Some(start) = parse(start) else {
+                return RangeSpec::Ignored;
+            };
+            let end = match end {
+                "" => None,
+                end => match parse(end) {
+                    Some(end) if end >= start => Some(end),
+                    _ => return RangeSpec::Ignored,
+                },
+            };
+            if start >= len {
+                return RangeSpec::Unsatisfiable;
+            }
+            let end = end.map_or(len, |end| std::cmp::min(end + 1, len));
+            RangeSpec::Satisfiable(start..end)
+        }
+    }
+}
+
+fn header_str(headers: &Headers, name: HeaderName) -> Option<&str> {
+    headers
+        .get(name)
+        .and_then(|v| std::str::from_utf8(v).ok())
+        .map(str::trim)
+}
+
+fn header_date(headers: &Headers, name: HeaderName) -> Option<SystemTime> {
+    header_str(headers, name).and_then(parse_http_date)
+}
+
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => UNIX_EPOCH + std::time::Duration::from_secs(d.as_secs()),
+        Err(_) => time,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use http::{header, StatusCode};
+
+    use super::{parse_range, ETag, FileResponse, RangeSpec, Validators};
+    use crate::{files::fmt_http_date, Method, Request};
+
+    #[test]
+    fn test_file_response() {
+        assert_eq!(parse_range("bytes=0-99", 50), RangeSpec::Satisfiable(0..50));
+        assert_eq!(parse_range("bytes=10-", 50), RangeSpec::Satisfiable(10..50));
+        assert_eq!(parse_range("bytes=-10", 50), RangeSpec::Satisfiable(40..50));
+        assert_eq!(parse_range("bytes=50-", 50), RangeSpec::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 50), RangeSpec::Unsatisfiable);
+        assert_eq!(parse_range("bytes=5-1", 50), RangeSpec::Ignored);
+        assert_eq!(parse_range("bytes=0-1,5-6", 50), RangeSpec::Ignored);
+        assert_eq!(parse_range("lines=0-1", 50), RangeSpec::Ignored);
+
+        let mtime = UNIX_EPOCH + Duration::from_millis(784_111_777_500);
+        let validators = Validators {
+            etag: ETag::from_hash(&[0xab, 0xcd]),
+            last_modified: Some(mtime),
+        };
+        let respond = |headers: &[(header::HeaderName, &str)]| {
+            let mut req = Request::default();
+            for (name, value) in headers {
+                req.headers
+                    .insert(name.clone(), value.to_string().into_bytes().into());
+            }
+            FileResponse::new(&req, 100, &validators)
+        };
+        let date = fmt_http_date(mtime);
+
+        let res = respond(&[]);
+        assert_eq!(res.response.status, StatusCode::OK);
+        assert_eq!(res.range, 0..100);
+        assert_eq!(&res.response.headers[header::ETAG][..], b"\"abcd\"");
+
+        let res = respond(&[(header::IF_NONE_MATCH, "W/\"abcd\"")]);
+        assert_eq!(res.response.status, StatusCode::NOT_MODIFIED);
+        let res = respond(&[(header::IF_MODIFIED_SINCE, &date)]);
+        assert_eq!(res.response.status, StatusCode::NOT_MODIFIED);
+        let res = respond(&[(header::IF_MATCH, "W/\"abcd\"")]);
+        assert_eq!(res.response.status, StatusCode::PRECONDITION_FAILED);
+
+        let res = respond(&[(header::RANGE, "bytes=10-19")]);
+        assert_eq!(res.response.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.range, 10..20);
+        assert_eq!(
+            &res.response.headers[header::CONTENT_RANGE][..],
+            b"bytes 10-19/100"
+        );
+
+        // resuming works while the file is unchanged
+        let res = respond(&[(header::RANGE, "bytes=10-"), (header::IF_RANGE, "\"abcd\"")]);
+        assert_eq!(res.range, 10..100);
+        let res = respond(&[(header::RANGE, "bytes=10-"), (header::IF_RANGE, &date)]);
+        assert_eq!(res.range, 10..100);
+        let res = respond(&[(header::RANGE, "bytes=10-"), (header::IF_RANGE, "\"old\"")]);
+        assert_eq!(res.response.status, StatusCode::OK);
+        assert_eq!(res.range, 0..100);
+
+        let res = respond(&[(header::RANGE, "bytes=200-")]);
+        assert_eq!(res.response.status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            &res.response.headers[header::CONTENT_RANGE][..],
+            b"bytes */100"
+        );
+
+        let req = Request {
+            method: Method::Head,
+            ..Default::default()
+        };
+        let res = FileResponse::new(&req, 100, &validators);
+        assert_eq!(res.range, 0..0);
+        assert_eq!(&res.response.headers[header::CONTENT_LENGTH][..], b"100");
+    }
+}
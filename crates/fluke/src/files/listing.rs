@@ -0,0 +1,272 @@
+use std::{fmt::Write as _, path::Path, time::UNIX_EPOCH};
+
+use fluke_buffet::RollMut;
+use http::header;
+
+use crate::{
+    Encoder, ExpectResponseBody, ExpectResponseHeaders, Headers, Responder, Response, ResponseDone,
+};
+
+use super::ResolveOptions;
+
+/// What a directory listing is rendered as, see [write_listing]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ListingFormat {
+    /// A page with a link to every entry
+    #[default]
+    Html,
+
+    /// An array of `{"name", "type", "size", "modified"}` objects, `type`
+    /// being `"file"` or `"directory"`, `size` in bytes (files only), and
+    /// `modified` in seconds since the Unix epoch, when known.
+    Json,
+}
+
+impl ListingFormat {
+    /// JSON for clients that ask for it and not for HTML, HTML otherwise
+    pub fn negotiate(headers: &Headers) -> Self {
+        let accepts = |media_type: &str| {
+            headers
+                .get_all(header::ACCEPT)
+                .iter()
+                .filter_map(|value| std::str::from_utf8(value).ok())
+                .flat_map(|value| value.split(','))
+                .any(|item| {
+                    let media_range = item.split(';').next().unwrap_or_default();
+                    media_range.trim().eq_ignore_ascii_case(media_type)
+                })
+        };
+
+        if accepts("application/json") && !accepts("text/html") {
+            ListingFormat::Json
+        } else {
+            ListingFormat::Html
+        }
+    }
+}
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<u64>,
+}
+
+/// Responds with a listing of `dir`, the path of a [Resolved::Directory](super::Resolved::Directory),
+/// found at `request_path` (which should end with a slash, so that relative
+/// links work).
+///
+/// Directories come first, then files, sorted by name. Hidden entries are
+/// left out unless [ResolveOptions::allow_hidden] is set, and so are
+/// symlinks (they might lead out of the root), special files, and names
+/// that aren't valid UTF-8 (they couldn't be requested).
+///
+/// The directory is read in full with blocking syscalls, then the listing
+/// is sent in pool-sized chunks as it's rendered.
+pub async fn write_listing<E: Encoder>(
+    respond: Responder<E, ExpectResponseHeaders>,
+    dir: &Path,
+    request_path: &str,
+    format: ListingFormat,
+    options: &ResolveOptions,
+) -> eyre::Result<Responder<E, ResponseDone>> {
+    let mut entries = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if name.starts_with('.') && !options.allow_hidden {
+            continue;
+        }
+        // doesn't follow symlinks
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() && !metadata.is_dir() {
+            continue;
+        }
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+        });
+    }
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+    let mut res = Response::default();
+    let content_type = match format {
+        ListingFormat::Html => "text/html; charset=utf-8",
+        ListingFormat::Json => "application/json",
+    };
+    res.headers
+        .insert(header::CONTENT_TYPE, content_type.into());
+    res.headers
+        .insert(header::X_CONTENT_TYPE_OPTIONS, "nosniff".into());
+    res.headers.insert(header::CACHE_CONTROL, "no-cache".into());
+    let respond = respond.write_final_response(res).await?;
+
+    let mut out = ChunkedOut {
+        respond,
+        buf: RollMut::empty(),
+    };
+    let mut line = String::new();
+    match format {
+        ListingFormat::Html => {
+            let title = html_escape(request_path);
+            write!(
+                line,
+                "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {title}</title></head>\n<body>\n<h1>Index of {title}</h1>\n<ul>\n"
+            )?;
+            if request_path != "/" {
+                line.push_str("<li><a href=\"../\">../</a></li>\n");
+            }
+            out.put(&line).await?;
+
+            for entry in &entries {
+                line.clear();
+                let slash = if entry.is_dir { "/" } else { "" };
+                write!(
+                    line,
+                    "<li><a href=\"./{}{slash}\">{}{slash}</a>",
+                    percent_encode(&entry.name),
+                    html_escape(&entry.name),
+                )?;
+                if !entry.is_dir {
+                    write!(line, " ({} bytes)", entry.size)?;
+                }
+                line.push_str("</li>\n");
+                out.put(&line).await?;
+            }
+            out.put("</ul>\n</body>\n</html>\n").await?;
+        }
+        ListingFormat::Json => {
+            out.put("[").await?;
+            for (i, entry) in entries.iter().enumerate() {
+                line.clear();
+                if i > 0 {
+                    line.push(',');
+                }
+                line.push_str("{\"name\":");
+                json_escape(&mut line, &entry.name);
+                if entry.is_dir {
+                    line.push_str(",\"type\":\"directory\"");
+                } else {
+                    write!(line, ",\"type\":\"file\",\"size\":{}", entry.size)?;
+                }
+                if let Some(modified) = entry.modified {
+                    write!(line, ",\"modified\":{modified}")?;
+                }
+                line.push('}');
+                out.put(&line).await?;
+            }
+            out.put("]").await?;
+        }
+    }
+    out.finish().await
+}
+
+/// Fills pool buffers, and sends each one as a body chunk once it's full
+struct ChunkedOut<E: Encoder> {
+    respond: Responder<E, ExpectResponseBody>,
+    buf: RollMut,
+}
+
+impl<E: Encoder> ChunkedOut<E> {
+    async fn put(&mut self, s: &str) -> eyre::Result<()> {
+        if self.buf.cap() < s.len() {
+            if !self.buf.is_empty() {
+                self.respond.write_chunk(self.buf.take_all().into()).await?;
+            }
+            self.buf.reserve_at_least(s.len())?;
+        }
+        self.buf.put(s)?;
+        Ok(())
+    }
+
+    async fn finish(mut self) -> eyre::Result<Responder<E, ResponseDone>> {
+        if self.buf.is_empty() {
+            self.respond.finish_body(None).await
+        } else {
+            self.respond
+                .write_last_chunk(self.buf.take_all().into())
+                .await
+        }
+    }
+}
+
+fn html_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Encodes everything but unreserved characters, so that names are taken
+/// as a single path segment (and never as a scheme, with `javascript:`)
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            write!(out, "%{b:02X}").unwrap();
+        }
+    }
+    out
+}
+
+fn json_escape(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header;
+
+    use super::{html_escape, json_escape, percent_encode, ListingFormat};
+    use crate::Headers;
+
+    #[test]
+    fn test_listing_escapes() {
+        assert_eq!(
+            html_escape("<img src=x onerror='a&b'>"),
+            "&lt;img src=x onerror=&#39;a&amp;b&#39;&gt;"
+        );
+        assert_eq!(percent_encode("a b/c:é"), "a%20b%2Fc%3A%C3%A9");
+
+        let mut json = String::new();
+        json_escape(&mut json, "a\"\\\n");
+        assert_eq!(json, r#""a\"\\\u000a""#);
+
+        let mut headers = Headers::default();
+        assert_eq!(ListingFormat::negotiate(&headers), ListingFormat::Html);
+        headers.insert(header::ACCEPT, "application/json;q=0.9".into());
+        assert_eq!(ListingFormat::negotiate(&headers), ListingFormat::Json);
+        headers.insert(
+            header::ACCEPT,
+            "text/html,application/xhtml+xml,application/json".into(),
+        );
+        assert_eq!(ListingFormat::negotiate(&headers), ListingFormat::Html);
+    }
+}
@@ -0,0 +1,208 @@
+use std::{collections::HashMap, path::Path};
+
+/// What files nothing is known about are served as
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Extensions (lowercase, sorted) and the `content-type` to serve them with
+const BUILTIN: &[(&str, &str)] = &[
+    ("7z", "application/x-7z-compressed"),
+    ("aac", "audio/aac"),
+    ("avif", "image/avif"),
+    ("bmp", "image/bmp"),
+    ("css", "text/css; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("eot", "application/vnd.ms-fontobject"),
+    ("flac", "audio/flac"),
+    ("gif", "image/gif"),
+    ("gz", "application/gzip"),
+    ("htm", "text/html; charset=utf-8"),
+    ("html", "text/html; charset=utf-8"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("ics", "text/calendar; charset=utf-8"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("jsonld", "application/ld+json"),
+    ("m4a", "audio/mp4"),
+    ("map", "application/json"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("oga", "audio/ogg"),
+    ("ogg", "audio/ogg"),
+    ("ogv", "video/ogg"),
+    ("opus", "audio/opus"),
+    ("otf", "font/otf"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("rss", "application/rss+xml"),
+    ("svg", "image/svg+xml"),
+    ("tar", "application/x-tar"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("toml", "application/toml"),
+    ("ttf", "font/ttf"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("wasm", "application/wasm"),
+    ("wav", "audio/wav"),
+    ("webm", "video/webm"),
+    ("webmanifest", "application/manifest+json"),
+    ("webp", "image/webp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("xml", "application/xml"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("zip", "application/zip"),
+    ("zst", "application/zstd"),
+];
+
+/// Picks the `content-type` of files being served, from their extension,
+/// and optionally from their first bytes when the extension doesn't say.
+///
+/// Comes with a table of common web formats, which can be added to (or
+/// overridden) with [MimeTable::insert].
+///
+/// Meant for the path of a [Resolved::File](super::Resolved::File): that of
+/// the index file, for directories.
+#[derive(Debug, Clone, Default)]
+pub struct MimeTable {
+    /// Lowercase extensions
+    custom: HashMap<String, String>,
+
+    /// Look at the first bytes of files whose extension isn't known, see
+    /// [sniff_content_type]
+    pub sniff: bool,
+}
+
+impl MimeTable {
+    /// Serves files with extension `ext` (without the dot, matched without
+    /// regard to case) as `content_type`
+    pub fn insert(&mut self, ext: &str, content_type: impl Into<String>) {
+        self.custom
+            .insert(ext.to_ascii_lowercase(), content_type.into());
+    }
+
+    /// Looks up the extension of `path`
+    pub fn lookup(&self, path: &Path) -> Option<&str> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        if let Some(content_type) = self.custom.get(&ext) {
+            return Some(content_type);
+        }
+        BUILTIN
+            .binary_search_by(|(builtin, _)| (*builtin).cmp(&ext[..]))
+            .ok()
+            .map(|i| BUILTIN[i].1)
+    }
+
+    /// The `content-type` to serve `path` with. `first_bytes` (as many as
+    /// are at hand, 512 is plenty) is only looked at if [MimeTable::sniff]
+    /// is set and the extension isn't known.
+    pub fn content_type(&self, path: &Path, first_bytes: Option<&[u8]>) -> &str {
+        if let Some(content_type) = self.lookup(path) {
+            return content_type;
+        }
+        match first_bytes {
+            Some(bytes) if self.sniff => sniff_content_type(bytes),
+            _ => None,
+        }
+        .unwrap_or(DEFAULT_CONTENT_TYPE)
+    }
+}
+
+/// Recognizes a few binary formats by their magic number, and tells plain
+/// text from the rest.
+///
+/// This never returns `text/html` or anything else a browser would run
+/// scripts from: a file that merely looks like HTML is served as text, so
+/// that uploads can't be made to pass for pages. Responses should still
+/// come with `x-content-type-options: nosniff`, so browsers don't sniff
+/// their own way.
+pub fn sniff_content_type(bytes: &[u8]) -> Option<&'static str> {
+    const MAGIC: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"\x28\xb5\x2f\xfd", "application/zstd"),
+        (b"\0asm", "application/wasm"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+        (b"OggS", "audio/ogg"),
+        (b"fLaC", "audio/flac"),
+        (b"ID3", "audio/mpeg"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+    ];
+
+    if let Some((_, content_type)) = MAGIC.iter().find(|(magic, _)| bytes.starts_with(magic)) {
+        return Some(content_type);
+    }
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+
+    // the last character may have been cut short
+    let text = match std::str::from_utf8(bytes) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    let control = bytes
+        .iter()
+        .any(|&b| b.is_ascii_control() && !b.is_ascii_whitespace() && b != 0x1b);
+    (!bytes.is_empty() && text && !control).then_some("text/plain; charset=utf-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{sniff_content_type, MimeTable, BUILTIN, DEFAULT_CONTENT_TYPE};
+
+    #[test]
+    fn test_mime_table() {
+        assert!(BUILTIN.windows(2).all(|w| w[0].0 < w[1].0), "sorted");
+
+        let mut table = MimeTable::default();
+        assert_eq!(
+            table.lookup(Path::new("a/b.CSS")),
+            Some("text/css; charset=utf-8")
+        );
+        assert_eq!(table.lookup(Path::new("Makefile")), None);
+        assert_eq!(
+            table.content_type(Path::new("x.bin"), Some(b"\x89PNG\r\n\x1a\n")),
+            DEFAULT_CONTENT_TYPE
+        );
+
+        table.insert("BIN", "application/x-custom");
+        table.insert("js", "application/javascript");
+        table.sniff = true;
+        assert_eq!(
+            table.lookup(Path::new("x.bin")),
+            Some("application/x-custom")
+        );
+        assert_eq!(
+            table.lookup(Path::new("x.js")),
+            Some("application/javascript")
+        );
+        assert_eq!(
+            table.content_type(Path::new("x"), Some(b"\x89PNG\r\n\x1a\n")),
+            "image/png"
+        );
+
+        assert_eq!(
+            sniff_content_type(b"<!doctype html><script>"),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(
+            sniff_content_type(b"caf\xc3"),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(sniff_content_type(b"\x00\x01\x02"), None);
+        assert_eq!(sniff_content_type(b""), None);
+    }
+}
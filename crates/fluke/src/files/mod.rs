@@ -0,0 +1,19 @@
+//! Helpers for serving files from a directory
+
+mod resolve;
+pub use resolve::*;
+
+mod mime;
+pub use mime::*;
+
+mod listing;
+pub use listing::*;
+
+mod date;
+pub use date::*;
+
+mod conditional;
+pub use conditional::*;
+
+mod body;
+pub use body::*;
@@ -0,0 +1,580 @@
+use std::{
+    fs::{File, Metadata},
+    io,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use http::{header, StatusCode};
+use tracing::debug;
+
+use crate::{Headers, Request};
+
+/// What [resolve_path] can be told, beyond the root and the request path
+#[derive(Debug, Clone)]
+pub struct ResolveOptions {
+    /// Tried in order when the request path leads to a directory
+    pub index_files: Rc<[String]>,
+
+    /// Serve files and directories whose name starts with a `.`. Off by
+    /// default: those are rarely meant to be public (`.git`, `.env`, etc.)
+    pub allow_hidden: bool,
+
+    /// Resolve directories that have none of the index files to
+    /// [Resolved::Directory], so that they can be listed (see
+    /// [write_listing](super::write_listing)). Off by default, in which case
+    /// they're [ResolveError::NotFound]: listings give away file names that
+    /// might not be meant to be found.
+    pub list_directories: bool,
+
+    /// Codings that files may have precompressed copies in, in order of
+    /// preference: `app.js.br` next to `app.js`, say. With [resolve_request],
+    /// those are served instead of the file when the client accepts them,
+    /// see [Resolved::File]. Empty by default.
+    pub precompressed: Rc<[ContentCoding]>,
+}
+
+impl Default for ResolveOptions {
+    fn default() -> Self {
+        Self {
+            index_files: Rc::new(["index.html".to_owned()]),
+            allow_hidden: false,
+            list_directories: false,
+            precompressed: Rc::new([]),
+        }
+    }
+}
+
+/// Where a request path led, see [resolve_path]
+#[derive(Debug)]
+pub enum Resolved {
+    /// A regular file, opened for reading
+    File {
+        /// Where the file is, under the root. For precompressed copies, this
+        /// is still the path of the original, which the `content-type`
+        /// should be picked from.
+        path: PathBuf,
+        file: File,
+        metadata: Metadata,
+
+        /// Set when `file` is a precompressed copy of the file at `path`,
+        /// see [ResolveOptions::precompressed]. [ContentCoding::set_headers]
+        /// then says so in the response.
+        encoding: Option<ContentCoding>,
+    },
+
+    /// A directory that has none of the index files, opened for reading.
+    /// Only with [ResolveOptions::list_directories].
+    Directory {
+        /// Where the directory is, under the root
+        path: PathBuf,
+        dir: File,
+    },
+
+    /// A directory, asked for without a trailing slash: relative links
+    /// in its index wouldn't work, so clients should be redirected to
+    /// `location` (with the query, if any, appended).
+    Redirect { location: String },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ResolveError {
+    /// Malformed percent-encoding, encoded separators or NUL bytes, `..`
+    /// segments
+    #[error("request path is invalid or tries to escape the root")]
+    InvalidPath,
+
+    /// Nothing there, or nothing we're willing to serve: hidden files,
+    /// special files (sockets, FIFOs, devices), symlinks leading out of the
+    /// root.
+    #[error("no such file")]
+    NotFound,
+
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl ResolveError {
+    /// The status to respond with
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ResolveError::InvalidPath => StatusCode::BAD_REQUEST,
+            ResolveError::NotFound => StatusCode::NOT_FOUND,
+            ResolveError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Finds what `request_path` (the path of a request, without the query)
+/// leads to under `root`, with [ResolveOptions::default]: see
+/// [resolve_path_with].
+pub fn resolve_path(root: &Path, request_path: &str) -> Result<Resolved, ResolveError> {
+    resolve_path_with(root, request_path, &ResolveOptions::default())
+}
+
+/// Finds what the path of `req` leads to under `root`, like
+/// [resolve_path_with], and also looks for precompressed copies of files in
+/// the codings `req` accepts, if [ResolveOptions::precompressed] lists any.
+pub fn resolve_request(
+    root: &Path,
+    req: &Request,
+    options: &ResolveOptions,
+) -> Result<Resolved, ResolveError> {
+    let accept_encoding = req
+        .headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| std::str::from_utf8(v).ok());
+    resolve(root, req.uri.path(), accept_encoding, options)
+}
+
+/// Finds what `request_path` (the path of a request, without the query)
+/// leads to under `root`, and opens it.
+///
+/// The path is percent-decoded, and rejected if it has `..` segments, or
+/// anything that decodes to a path separator or a NUL byte. On Linux, it's
+/// then opened with `openat2(2)` and `RESOLVE_BENEATH`, so that symlinks
+/// can't lead out of `root` either, even if they're swapped in while we
+/// look (symlinks to absolute paths aren't followed at all). Elsewhere (or on kernels older than 5.6, or where seccomp filters
+/// `openat2` out), symlinks are resolved first and checked against `root`,
+/// which leaves a window for a symlink to be swapped in.
+///
+/// This makes blocking syscalls (a few opens and stats), which is fine for
+/// local filesystems.
+pub fn resolve_path_with(
+    root: &Path,
+    request_path: &str,
+    options: &ResolveOptions,
+) -> Result<Resolved, ResolveError> {
+    resolve(root, request_path, None, options)
+}
+
+fn resolve(
+    root: &Path,
+    request_path: &str,
+    accept_encoding: Option<&str>,
+    options: &ResolveOptions,
+) -> Result<Resolved, ResolveError> {
+    let mut rel = PathBuf::new();
+    let mut raw_segments = vec![];
+    for raw in request_path.split('/') {
+        if raw.is_empty() || raw == "." {
+            continue;
+        }
+
+        let segment = percent_decode_segment(raw)?;
+        if segment == "." {
+            continue;
+        }
+        if segment == ".." {
+            return Err(ResolveError::InvalidPath);
+        }
+        if segment.starts_with('.') && !options.allow_hidden {
+            debug!(%segment, "refusing to serve hidden file");
+            return Err(ResolveError::NotFound);
+        }
+        rel.push(segment);
+        raw_segments.push(raw);
+    }
+
+    let root_dir = File::open(root)?;
+    let file = open_beneath(&root_dir, root, &rel)?;
+    let metadata = file.metadata()?;
+
+    if metadata.is_file() {
+        return precompressed(
+            &root_dir,
+            root,
+            &rel,
+            file,
+            metadata,
+            accept_encoding,
+            options,
+        );
+    }
+    if !metadata.is_dir() {
+        return Err(ResolveError::NotFound);
+    }
+
+    if !request_path.ends_with('/') {
+        // rebuilt from the segments, so that e.g. `//evil.example` doesn't
+        // become a redirect to another host
+        let mut location = String::with_capacity(request_path.len() + 1);
+        for raw in &raw_segments {
+            location.push('/');
+            location.push_str(raw);
+        }
+        location.push('/');
+        return Ok(Resolved::Redirect { location });
+    }
+
+    for index in options.index_files.iter() {
+        let index_rel = rel.join(index);
+        match open_beneath(&root_dir, root, &index_rel) {
+            Ok(file) => {
+                let metadata = file.metadata()?;
+                if metadata.is_file() {
+                    return precompressed(
+                        &root_dir,
+                        root,
+                        &index_rel,
+                        file,
+                        metadata,
+                        accept_encoding,
+                        options,
+                    );
+                }
+            }
+            Err(ResolveError::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    if !options.list_directories {
+        return Err(ResolveError::NotFound);
+    }
+    Ok(Resolved::Directory {
+        path: root.join(&rel),
+        dir: file,
+    })
+}
+
+/// Content codings files may have precompressed copies in, see
+/// [ResolveOptions::precompressed]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentCoding {
+    /// `.br` files
+    Brotli,
+    /// `.gz` files
+    Gzip,
+    /// `.zst` files
+    Zstd,
+}
+
+impl ContentCoding {
+    /// As found in `accept-encoding` and `content-encoding`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ContentCoding::Brotli => "br",
+            ContentCoding::Gzip => "gzip",
+            ContentCoding::Zstd => "zstd",
+        }
+    }
+
+    /// Of precompressed copies, appended to the name of the original
+    pub fn extension(self) -> &'static str {
+        match self {
+            ContentCoding::Brotli => "br",
+            ContentCoding::Gzip => "gz",
+            ContentCoding::Zstd => "zst",
+        }
+    }
+
+    /// Whether `accept_encoding` (the value of the header) lists this coding
+    /// (or `*`) with a non-zero weight
+    pub fn is_accepted(self, accept_encoding: &str) -> bool {
+        let mut wildcard = false;
+        for item in accept_encoding.split(',') {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or_default().trim();
+            let accepted = !params.any(|param| {
+                matches!(
+                    param.trim().split_once('='),
+                    Some((q, weight)) if q.trim().eq_ignore_ascii_case("q")
+                        && weight.trim().parse::<f32>().is_ok_and(|w| w <= 0.0)
+                )
+            });
+
+            let named = coding.eq_ignore_ascii_case(self.as_str())
+                || (self == ContentCoding::Gzip && coding.eq_ignore_ascii_case("x-gzip"));
+            if named {
+                return accepted;
+            }
+            if coding == "*" {
+                wildcard = accepted;
+            }
+        }
+        wildcard
+    }
+
+    /// Sets `content-encoding` to `encoding` (if any) on the headers of a
+    /// response to a request resolved with [ResolveOptions::precompressed],
+    /// and `vary: accept-encoding` either way, so that caches don't hand out
+    /// the wrong copy.
+    pub fn set_headers(encoding: Option<Self>, headers: &mut Headers) {
+        if let Some(encoding) = encoding {
+            headers.insert(header::CONTENT_ENCODING, encoding.as_str().into());
+        }
+        headers.append(header::VARY, "accept-encoding".into());
+    }
+}
+
+/// The first precompressed copy of the file at `rel` that's accepted and
+/// exists, or the file itself
+fn precompressed(
+    root_dir: &File,
+    root: &Path,
+    rel: &Path,
+    file: File,
+    metadata: Metadata,
+    accept_encoding: Option<&str>,
+    options: &ResolveOptions,
+) -> Result<Resolved, ResolveError> {
+    let path = root.join(rel);
+    let accept_encoding = accept_encoding.unwrap_or_default();
+    for &encoding in options.precompressed.iter() {
+        if !encoding.is_accepted(accept_encoding) {
+            continue;
+        }
+
+        let mut sidecar = rel.as_os_str().to_owned();
+        sidecar.push(".");
+        sidecar.push(encoding.extension());
+        match open_beneath(root_dir, root, Path::new(&sidecar)) {
+            Ok(file) => {
+                let metadata = file.metadata()?;
+                if metadata.is_file() {
+                    return Ok(Resolved::File {
+                        path,
+                        file,
+                        metadata,
+                        encoding: Some(encoding),
+                    });
+                }
+            }
+            Err(ResolveError::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(Resolved::File {
+        path,
+        file,
+        metadata,
+        encoding: None,
+    })
+}
+
+fn percent_decode_segment(raw: &str) -> Result<String, ResolveError> {
+    let bytes = raw.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3).ok_or(ResolveError::InvalidPath)?;
+            let hex = std::str::from_utf8(hex).map_err(|_| ResolveError::InvalidPath)?;
+            let byte = u8::from_str_radix(hex, 16).map_err(|_| ResolveError::InvalidPath)?;
+            out.push(byte);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    if out.iter().any(|&b| matches!(b, b'/' | b'\\' | 0)) {
+        return Err(ResolveError::InvalidPath);
+    }
+    String::from_utf8(out).map_err(|_| ResolveError::InvalidPath)
+}
+
+/// Maps errors from opening a path to what the client gets to know
+fn open_error(e: io::Error) -> ResolveError {
+    match e.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied => ResolveError::NotFound,
+        _ => {
+            // a file where a directory was expected, a symlink loop, or (for
+            // openat2) a symlink leading out of the root
+            #[cfg(target_os = "linux")]
+            if matches!(
+                e.raw_os_error(),
+                Some(libc::ENOTDIR | libc::ELOOP | libc::EXDEV)
+            ) {
+                return ResolveError::NotFound;
+            }
+            ResolveError::Io(e)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_beneath(root_dir: &File, root: &Path, rel: &Path) -> Result<File, ResolveError> {
+    use std::{
+        ffi::CString,
+        os::fd::{AsRawFd, FromRawFd},
+        os::unix::ffi::OsStrExt,
+    };
+
+    // openat2 wants a non-empty path
+    let rel_c = if rel.as_os_str().is_empty() {
+        CString::new(".")
+    } else {
+        CString::new(rel.as_os_str().as_bytes())
+    }
+    .map_err(|_| ResolveError::InvalidPath)?;
+
+    // `open_how` is non-exhaustive, the kernel wants zeroes in whatever we
+    // don't set.
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    // O_NONBLOCK, so that opening a FIFO doesn't hang
+    how.flags = (libc::O_RDONLY | libc::O_CLOEXEC | libc::O_NONBLOCK) as u64;
+    how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS;
+
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            root_dir.as_raw_fd(),
+            rel_c.as_ptr(),
+            &how as *const libc::open_how,
+            std::mem::size_of::<libc::open_how>(),
+        )
+    };
+    if fd >= 0 {
+        return Ok(unsafe { File::from_raw_fd(fd as _) });
+    }
+
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ENOSYS | libc::EPERM) => {
+            debug!("openat2 is unavailable, resolving symlinks by hand");
+            open_checked(root, rel)
+        }
+        _ => Err(open_error(e)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_beneath(_root_dir: &File, root: &Path, rel: &Path) -> Result<File, ResolveError> {
+    open_checked(root, rel)
+}
+
+/// Resolves symlinks in `rel`, checks the result is still under `root`,
+/// then opens it.
+fn open_checked(root: &Path, rel: &Path) -> Result<File, ResolveError> {
+    let root = root.canonicalize()?;
+    let path = root.join(rel).canonicalize().map_err(open_error)?;
+    if !path.starts_with(&root) {
+        debug!(?path, "symlink leads out of the root");
+        return Err(ResolveError::NotFound);
+    }
+
+    // opening a FIFO would hang
+    let metadata = std::fs::metadata(&path).map_err(open_error)?;
+    if !metadata.is_file() && !metadata.is_dir() {
+        return Err(ResolveError::NotFound);
+    }
+    File::open(&path).map_err(open_error)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+
+    use http::header;
+
+    use super::{
+        resolve_path, resolve_path_with, resolve_request, ContentCoding, ResolveError,
+        ResolveOptions, Resolved,
+    };
+    use crate::Request;
+
+    #[test]
+    fn test_resolve_path() {
+        let tmp = std::env::temp_dir().join(format!("fluke-resolve-{}", std::process::id()));
+        let root = tmp.join("root");
+        fs::create_dir_all(root.join("sub/empty")).unwrap();
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::write(root.join("hello world.txt"), "hi").unwrap();
+        fs::write(root.join("sub/index.html"), "index").unwrap();
+        fs::write(root.join("app.js"), "app").unwrap();
+        fs::write(root.join("app.js.gz"), "gzipped app").unwrap();
+        fs::write(tmp.join("secret"), "nope").unwrap();
+        std::os::unix::fs::symlink("../secret", root.join("escape")).unwrap();
+        std::os::unix::fs::symlink(tmp.join("secret"), root.join("escape-abs")).unwrap();
+        std::os::unix::fs::symlink("hello world.txt", root.join("inside")).unwrap();
+
+        let file_path = |res: Result<Resolved, ResolveError>| match res {
+            Ok(Resolved::File { path, .. }) => path,
+            other => panic!("expected a file, got {other:?}"),
+        };
+        let not_found = |res| matches!(res, Err(ResolveError::NotFound));
+        let invalid = |res| matches!(res, Err(ResolveError::InvalidPath));
+
+        assert_eq!(
+            file_path(resolve_path(&root, "/hello%20world.txt")),
+            root.join("hello world.txt")
+        );
+        assert_eq!(
+            file_path(resolve_path(&root, "//sub/./")),
+            root.join("sub/index.html")
+        );
+        assert_eq!(
+            file_path(resolve_path(&root, "/inside")),
+            root.join("inside")
+        );
+        assert!(matches!(
+            resolve_path(&root, "//sub"),
+            Ok(Resolved::Redirect { location }) if location == "/sub/"
+        ));
+        assert!(not_found(resolve_path(&root, "/sub/empty/")));
+
+        assert!(invalid(resolve_path(&root, "/../secret")));
+        assert!(invalid(resolve_path(&root, "/%2e%2e/secret")));
+        assert!(invalid(resolve_path(&root, "/sub%2f..%2f..%2fsecret")));
+        assert!(invalid(resolve_path(&root, "/bad%zz")));
+        assert!(not_found(resolve_path(&root, "/escape")));
+        assert!(not_found(resolve_path(&root, "/escape-abs")));
+        assert!(not_found(resolve_path(&root, "/.git/")));
+        assert!(not_found(resolve_path(&root, "/missing")));
+
+        let options = ResolveOptions {
+            allow_hidden: true,
+            list_directories: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            resolve_path_with(&root, "/sub/empty/", &options),
+            Ok(Resolved::Directory { path, .. }) if path == root.join("sub/empty")
+        ));
+        assert!(matches!(
+            resolve_path_with(&root, "/.git/", &options),
+            Ok(Resolved::Directory { .. })
+        ));
+
+        assert!(ContentCoding::Gzip.is_accepted("deflate, x-gzip;q=0.5"));
+        assert!(ContentCoding::Brotli.is_accepted("gzip;q=0, *"));
+        assert!(!ContentCoding::Brotli.is_accepted("br;q=0, *"));
+        assert!(!ContentCoding::Zstd.is_accepted(""));
+
+        let options = ResolveOptions {
+            precompressed: [ContentCoding::Brotli, ContentCoding::Gzip].into(),
+            ..Default::default()
+        };
+        let encoding = |accept_encoding: &str| {
+            let mut req = Request {
+                uri: "/app.js".parse().unwrap(),
+                ..Default::default()
+            };
+            req.headers.insert(
+                header::ACCEPT_ENCODING,
+                accept_encoding.to_owned().into_bytes().into(),
+            );
+            match resolve_request(&root, &req, &options) {
+                Ok(Resolved::File {
+                    path,
+                    metadata,
+                    encoding,
+                    ..
+                }) => {
+                    assert_eq!(path, root.join("app.js"));
+                    assert_eq!(metadata.len(), if encoding.is_some() { 11 } else { 3 });
+                    encoding
+                }
+                other => panic!("expected a file, got {other:?}"),
+            }
+        };
+        assert_eq!(encoding("br, gzip"), Some(ContentCoding::Gzip));
+        assert_eq!(encoding("identity"), None);
+
+        fs::remove_dir_all(&tmp).unwrap();
+    }
+}
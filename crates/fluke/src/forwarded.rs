@@ -0,0 +1,249 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use http::header;
+
+use fluke_maybe_uring::io::TransportAddr;
+
+use crate::Headers;
+
+/// A range of IP addresses, like `10.0.0.0/8` or `fd00::/8`. A lone address
+/// is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    /// Returns `None` if `prefix_len` is longer than `addr`
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        (prefix_len <= max_len).then_some(Self { addr, prefix_len })
+    }
+
+    /// IPv4 addresses mapped to IPv6 (`::ffff:a.b.c.d`, as reported by
+    /// dual-stack sockets) count as the IPv4 ones.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for IpNet {
+    fn from(addr: IpAddr) -> Self {
+        let prefix_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        Self { addr, prefix_len }
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("invalid IP range, expected something like `10.0.0.0/8`")]
+pub struct InvalidIpNet;
+
+impl FromStr for IpNet {
+    type Err = InvalidIpNet;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((addr, prefix_len)) = s.split_once('/') else {
+            return s.parse::<IpAddr>().map(Self::from).map_err(|_| InvalidIpNet);
+        };
+        let addr = addr.parse().map_err(|_| InvalidIpNet)?;
+        let prefix_len = prefix_len.parse().map_err(|_| InvalidIpNet)?;
+        Self::new(addr, prefix_len).ok_or(InvalidIpNet)
+    }
+}
+
+/// Which header proxies in front of us say who they forwarded requests for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ForwardedHeader {
+    /// `x-forwarded-for: client, proxy1, proxy2`, the de facto standard
+    #[default]
+    XForwardedFor,
+
+    /// `forwarded: for=client, for=proxy1`, cf. <https://www.rfc-editor.org/rfc/rfc7239>
+    Forwarded,
+}
+
+/// Which peers get to tell us who the client really is, when we sit behind
+/// load balancers or other proxies. The default trusts nobody: the client
+/// is whoever connected to us.
+///
+/// Forwarding headers are read from the end, since that's where each proxy
+/// appends the address it got the request from: the client is the last
+/// address that's not a trusted proxy's. Anything before that was sent by
+/// the client itself, and can't be trusted.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    pub nets: Vec<IpNet>,
+    pub header: ForwardedHeader,
+}
+
+impl TrustedProxies {
+    pub fn is_trusted(&self, addr: IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(addr))
+    }
+
+    /// Figures out the client's address for a request received from `peer`.
+    /// `None` if the peer isn't reached over IP (and isn't trusted either).
+    pub fn client_addr(&self, peer: Option<&TransportAddr>, headers: &Headers) -> Option<IpAddr> {
+        let mut client = match peer {
+            Some(TransportAddr::Inet(addr)) => addr.ip().to_canonical(),
+            _ => return None,
+        };
+        if !self.is_trusted(client) {
+            return Some(client);
+        }
+
+        let name = match self.header {
+            ForwardedHeader::XForwardedFor => header::HeaderName::from_static("x-forwarded-for"),
+            ForwardedHeader::Forwarded => header::FORWARDED,
+        };
+        // every header line counts, in order, as if they were one list
+        let hops: Vec<&[u8]> = headers
+            .get_all(name)
+            .iter()
+            .flat_map(|value| value.split(|&b| b == b','))
+            .collect();
+        for hop in hops.into_iter().rev() {
+            let addr = match self.header {
+                ForwardedHeader::XForwardedFor => parse_node(hop),
+                ForwardedHeader::Forwarded => forwarded_for(hop).and_then(parse_node),
+            };
+            // `unknown`, obfuscated identifiers, garbage: the trusted proxy
+            // that added it is the last we know of
+            let Some(addr) = addr else {
+                break;
+            };
+            client = addr;
+            if !self.is_trusted(client) {
+                break;
+            }
+        }
+        Some(client)
+    }
+}
+
+/// The `for` parameter of one element of a `forwarded` header
+fn forwarded_for(element: &[u8]) -> Option<&[u8]> {
+    element.split(|&b| b == b';').find_map(|pair| {
+        let pair = crate::trim_ows(pair);
+        let eq = pair.iter().position(|&b| b == b'=')?;
+        let (name, value) = (&pair[..eq], &pair[eq + 1..]);
+        name.eq_ignore_ascii_case(b"for").then_some(value)
+    })
+}
+
+/// Parses an address as found in forwarding headers: maybe quoted, maybe
+/// with a port, with IPv6 ones maybe in brackets.
+fn parse_node(node: &[u8]) -> Option<IpAddr> {
+    let node = crate::trim_ows(node);
+    let node = node
+        .strip_prefix(b"\"")
+        .and_then(|n| n.strip_suffix(b"\""))
+        .unwrap_or(node);
+    let node = std::str::from_utf8(node).ok()?;
+
+    let addr = node
+        .parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| {
+            let bracketed = node.strip_prefix('[').and_then(|n| n.strip_suffix(']'));
+            bracketed.ok_or(()).and_then(|n| n.parse().map_err(|_| ()))
+        })
+        .ok()?;
+    Some(addr.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+
+    use fluke_maybe_uring::io::TransportAddr;
+    use http::header;
+
+    use super::{ForwardedHeader, IpNet, TrustedProxies};
+    use crate::Headers;
+
+    #[test]
+    fn test_trusted_proxies() {
+        let net: IpNet = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("fd00::/8"
+            .parse::<IpNet>()
+            .unwrap()
+            .contains("fd12::1".parse().unwrap()));
+
+        let peer = |ip: &str| {
+            let addr: IpAddr = ip.parse().unwrap();
+            Some(TransportAddr::Inet(SocketAddr::new(addr, 4242)))
+        };
+        let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+
+        let mut trusted = TrustedProxies {
+            nets: vec!["10.0.0.0/8".parse().unwrap()],
+            header: ForwardedHeader::XForwardedFor,
+        };
+        let mut headers = Headers::default();
+        headers.insert("x-forwarded-for", "1.1.1.1, 2.2.2.2, 10.0.0.2".into());
+
+        // untrusted peers don't get a say
+        assert_eq!(
+            trusted.client_addr(peer("3.3.3.3").as_ref(), &headers),
+            ip("3.3.3.3")
+        );
+        // trusted ones do, up to the first address that isn't trusted
+        assert_eq!(
+            trusted.client_addr(peer("10.0.0.1").as_ref(), &headers),
+            ip("2.2.2.2")
+        );
+        assert_eq!(trusted.client_addr(None, &headers), None);
+
+        trusted.header = ForwardedHeader::Forwarded;
+        let mut headers = Headers::default();
+        headers.insert(
+            header::FORWARDED,
+            "for=1.1.1.1, for=\"[2001:db8::1]:4711\";proto=https".into(),
+        );
+        headers.append(header::FORWARDED, "for=10.0.0.3".into());
+        assert_eq!(
+            trusted.client_addr(peer("10.0.0.1").as_ref(), &headers),
+            ip("2001:db8::1")
+        );
+
+        headers.append(header::FORWARDED, "for=unknown".into());
+        assert_eq!(
+            trusted.client_addr(peer("10.0.0.1").as_ref(), &headers),
+            ip("10.0.0.1")
+        );
+    }
+}
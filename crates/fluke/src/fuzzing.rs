@@ -0,0 +1,163 @@
+//! Entry points for the fuzz targets in `fuzz/`, which can't reach the
+//! parsers otherwise. Not meant for anything else: only built with the
+//! `fuzzing` feature, and may change at any time.
+//!
+//! Everything reads from memory, in pieces of the given sizes (cycled, zeroes
+//! count as ones), so the same input can be checked against itself arriving
+//! all at once and a few bytes at a time.
+
+use futures_util::FutureExt;
+
+use fluke_buffet::RollMut;
+use fluke_maybe_uring::{buf::IoBufMut, io::ReadOwned, BufResult};
+
+use crate::{
+    h1::{
+        body::{H1Body, H1BodyKind},
+        parse,
+    },
+    util::read_and_parse,
+    Body, BodyChunk, Request,
+};
+
+pub use crate::h1::parse::MAX_CHUNK_EXT_LEN;
+
+/// What a request head was parsed into, in a form that can be compared
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParsedHead {
+    /// How many bytes the head took, what's left is the body (or the next
+    /// request)
+    pub consumed: usize,
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, Vec<u8>)>,
+
+    /// How the body is framed, or why it's refused
+    pub framing: String,
+}
+
+impl ParsedHead {
+    fn new(consumed: usize, req: &Request) -> Self {
+        Self {
+            consumed,
+            method: req.method.as_str().to_owned(),
+            target: req.uri.to_string(),
+            version: format!("{:?}", req.version),
+            headers: req
+                .headers
+                .iter()
+                .map(|(name, value)| (name.as_str().to_owned(), value[..].to_vec()))
+                .collect(),
+            framing: match H1BodyKind::from_headers(&req.headers) {
+                Ok(kind) => format!("{kind:?}"),
+                Err(e) => format!("refused: {e}"),
+            },
+        }
+    }
+}
+
+/// Parses `input` as a request head, all of it at once, the way tests and
+/// other parsers would. `None` if it's invalid or incomplete.
+pub fn parse_h1_request_head(input: &[u8]) -> Option<ParsedHead> {
+    let buf = roll_of(input);
+    let (rest, req) = parse::request(buf.filled()).ok()?;
+    Some(ParsedHead::new(input.len() - rest.len(), &req))
+}
+
+/// Reads a request head out of `input` like the server does, in pieces of
+/// `read_sizes`. `None` if it's invalid or incomplete.
+pub fn read_h1_request_head(input: &[u8], read_sizes: &[u8]) -> Option<ParsedHead> {
+    let mut reads = Reads::new(input, read_sizes);
+    let res = read_and_parse(
+        parse::complete_head(parse::request),
+        &mut reads,
+        RollMut::alloc().unwrap(),
+        // never in the way: it's the parser that's being fuzzed
+        input.len() + 1,
+    )
+    .now_or_never()
+    .expect("reads from memory never wait");
+
+    let (buf, req) = res.ok()??;
+    let consumed = input.len() - reads.remaining() - buf.len();
+    Some(ParsedHead::new(consumed, &req))
+}
+
+/// Decodes `input` as a chunked body, read in pieces of `read_sizes`. Its
+/// data if it's valid and complete, `None` otherwise.
+///
+/// Panics if decoding yields more chunks than there are bytes of input:
+/// work is bounded by the input's length, not by what it claims.
+pub fn read_h1_chunked_body(input: &[u8], read_sizes: &[u8]) -> Option<Vec<u8>> {
+    let reads = Reads::new(input, read_sizes);
+    let mut body = H1Body::new(reads, RollMut::alloc().unwrap(), H1BodyKind::Chunked);
+
+    let mut data = Vec::new();
+    let mut num_chunks = 0;
+    loop {
+        let chunk = body
+            .next_chunk()
+            .now_or_never()
+            .expect("reads from memory never wait")
+            .ok()?;
+        match chunk {
+            BodyChunk::Chunk(chunk) => {
+                assert!(!chunk.is_empty(), "empty chunks are never handed out");
+                data.extend_from_slice(&chunk[..]);
+            }
+            BodyChunk::Done { .. } => break,
+        }
+        num_chunks += 1;
+        assert!(num_chunks <= input.len(), "more chunks than input bytes");
+    }
+    assert!(body.eof());
+    Some(data)
+}
+
+fn roll_of(input: &[u8]) -> RollMut {
+    let mut buf = RollMut::alloc().unwrap();
+    buf.reserve_at_least(input.len()).unwrap();
+    buf.put(input).unwrap();
+    buf
+}
+
+/// Hands out `input` in pieces of `read_sizes`, then EOF
+struct Reads<'a> {
+    input: &'a [u8],
+    read_sizes: &'a [u8],
+    num_reads: usize,
+}
+
+impl<'a> Reads<'a> {
+    fn new(input: &'a [u8], read_sizes: &'a [u8]) -> Self {
+        Self {
+            input,
+            read_sizes,
+            num_reads: 0,
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.input.len()
+    }
+}
+
+impl ReadOwned for Reads<'_> {
+    async fn read<B: IoBufMut>(&mut self, mut buf: B) -> BufResult<usize, B> {
+        let size = match self.read_sizes {
+            [] => usize::MAX,
+            sizes => std::cmp::max(sizes[self.num_reads % sizes.len()], 1) as usize,
+        };
+        self.num_reads += 1;
+
+        let n = size.min(self.input.len()).min(buf.bytes_total());
+        let out = unsafe { std::slice::from_raw_parts_mut(buf.stable_mut_ptr(), n) };
+        out.copy_from_slice(&self.input[..n]);
+        unsafe {
+            buf.set_init(n);
+        }
+        self.input = &self.input[n..];
+        (Ok(n), buf)
+    }
+}
@@ -1,12 +1,17 @@
 use std::fmt;
 
+use http::{header, HeaderName};
 use tracing::debug;
 
-use crate::{util::read_and_parse, Body, BodyChunk, BodyErrorReason};
-use fluke_buffet::{Piece, PieceList, RollMut};
+use crate::{
+    util::{read_and_parse, SemanticError},
+    Body, BodyChunk, BodyErrorReason, BodyWriteMode, Headers,
+};
+use fluke_buffet::{Piece, PieceList, Roll, RollMut};
 use fluke_maybe_uring::io::{ReadOwned, WriteOwned};
 
-/// An HTTP/1.1 body, either chunked or content-length.
+/// An HTTP/1.1 body, either chunked or content-length, or for responses
+/// only, delimited by the connection closing.
 pub(crate) struct H1Body<T> {
     transport_r: T,
     buf: Option<RollMut>,
@@ -17,6 +22,8 @@ pub(crate) struct H1Body<T> {
 enum Decoder {
     Chunked(ChunkedDecoder),
     ContentLength(ContentLengthDecoder),
+    #[cfg_attr(not(feature = "client"), allow(dead_code))]
+    UntilClose(UntilCloseDecoder),
 }
 
 #[derive(Debug)]
@@ -34,12 +41,69 @@ struct ContentLengthDecoder {
     read: u64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
+struct UntilCloseDecoder {
+    closed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum H1BodyKind {
     Chunked,
     ContentLength(u64),
 }
 
+impl H1BodyKind {
+    /// How the body of a message with `headers` is delimited, cf.
+    /// <https://httpwg.org/specs/rfc9112.html#message.body.length>.
+    ///
+    /// Anything two parsers could read differently gets refused rather than
+    /// guessed at, since that's what request smuggling feeds on: both
+    /// `content-length` and `transfer-encoding`, codings that don't end
+    /// with `chunked`, `content-length` values that disagree or aren't
+    /// plain digits.
+    pub(crate) fn from_headers(headers: &Headers) -> Result<Self, SemanticError> {
+        let codings: Vec<&[u8]> = headers
+            .get_all(header::TRANSFER_ENCODING)
+            .iter()
+            .flat_map(|value| value.split(|&b| b == b','))
+            .map(crate::trim_ows)
+            .collect();
+        if !codings.is_empty() {
+            if headers.contains_key(header::CONTENT_LENGTH) {
+                return Err(SemanticError::AmbiguousFraming);
+            }
+            return match codings.split_last() {
+                Some((last, rest))
+                    if last.eq_ignore_ascii_case(b"chunked")
+                        && !rest
+                            .iter()
+                            .any(|c| c.is_empty() || c.eq_ignore_ascii_case(b"chunked")) =>
+                {
+                    if rest.is_empty() {
+                        Ok(Self::Chunked)
+                    } else {
+                        Err(SemanticError::UnsupportedTransferCoding)
+                    }
+                }
+                _ => Err(SemanticError::AmbiguousFraming),
+            };
+        }
+
+        let mut len = None;
+        for value in headers.get_all(header::CONTENT_LENGTH) {
+            for part in value.split(|&b| b == b',') {
+                let part = crate::from_digits(crate::trim_ows(part))
+                    .ok_or(SemanticError::AmbiguousFraming)?;
+                if len.is_some_and(|len| len != part) {
+                    return Err(SemanticError::AmbiguousFraming);
+                }
+                len = Some(part);
+            }
+        }
+        Ok(Self::ContentLength(len.unwrap_or_default()))
+    }
+}
+
 impl<T> fmt::Debug for H1Body<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("H1Body")
@@ -63,6 +127,43 @@ impl<T: ReadOwned> H1Body<T> {
         }
     }
 
+    /// A response body that says nothing of its length, and ends when the
+    /// server closes the connection: the connection can't be reused after it.
+    #[cfg(feature = "client")]
+    pub(crate) fn until_close(transport_r: T, buf: RollMut) -> Self {
+        H1Body {
+            transport_r,
+            buf: Some(buf),
+            state: Decoder::UntilClose(Default::default()),
+        }
+    }
+
+    /// Reads and discards the rest of the body, unless that means reading more
+    /// than `max_len` bytes. Returns true if the body was fully read.
+    pub(crate) async fn drain(&mut self, max_len: u64) -> eyre::Result<bool> {
+        if let Decoder::ContentLength(state) = &self.state {
+            if state.len - state.read > max_len {
+                return Ok(false);
+            }
+        }
+
+        let mut drained = 0;
+        while !self.eof() {
+            match self.next_chunk().await? {
+                BodyChunk::Chunk(chunk) => {
+                    drained += chunk.len() as u64;
+                    if drained > max_len {
+                        return Ok(false);
+                    }
+                }
+                BodyChunk::Done { .. } => break,
+            }
+        }
+        debug!(%drained, "drained request body");
+
+        Ok(self.eof())
+    }
+
     /// Returns the inner buffer and transport, but only if the body has been
     /// fully read.
     pub(crate) fn into_inner(self) -> Option<(RollMut, T)> {
@@ -79,6 +180,7 @@ impl<T: ReadOwned> Body for H1Body<T> {
         match &self.state {
             Decoder::Chunked(_) => None,
             Decoder::ContentLength(state) => Some(state.len),
+            Decoder::UntilClose(_) => None,
         }
     }
 
@@ -92,6 +194,9 @@ impl<T: ReadOwned> Body for H1Body<T> {
             Decoder::ContentLength(state) => {
                 state.next_chunk(&mut self.buf, &mut self.transport_r).await
             }
+            Decoder::UntilClose(state) => {
+                state.next_chunk(&mut self.buf, &mut self.transport_r).await
+            }
         }
     }
 
@@ -99,6 +204,7 @@ impl<T: ReadOwned> Body for H1Body<T> {
         match &self.state {
             Decoder::Chunked(state) => state.eof(),
             Decoder::ContentLength(state) => state.eof(),
+            Decoder::UntilClose(state) => state.closed,
         }
     }
 }
@@ -141,6 +247,39 @@ impl ContentLengthDecoder {
     }
 }
 
+impl UntilCloseDecoder {
+    async fn next_chunk(
+        &mut self,
+        buf_slot: &mut Option<RollMut>,
+        transport: &mut impl ReadOwned,
+    ) -> eyre::Result<BodyChunk> {
+        if self.closed {
+            return Ok(BodyChunk::Done { trailers: None });
+        }
+
+        let mut buf = buf_slot
+            .take()
+            .ok_or_else(|| BodyErrorReason::CalledNextChunkAfterError.as_err())?;
+
+        if buf.is_empty() {
+            buf.reserve()?;
+
+            let res;
+            (res, buf) = buf.read_into(usize::MAX, transport).await;
+            res.map_err(|e| BodyErrorReason::ErrorWhileReadingChunkData.with_cx(e))?;
+        }
+
+        let chunk = buf.take_all();
+        buf_slot.replace(buf);
+        if chunk.is_empty() {
+            debug!("connection closed, that's the end of the body");
+            self.closed = true;
+            return Ok(BodyChunk::Done { trailers: None });
+        }
+        Ok(BodyChunk::Chunk(chunk.into()))
+    }
+}
+
 impl ChunkedDecoder {
     async fn next_chunk(
         &mut self,
@@ -160,27 +299,41 @@ impl ChunkedDecoder {
             }
 
             if let ChunkedDecoder::ReadingChunkHeader = self {
-                let (next_buf, chunk_size) =
-                    read_and_parse(super::parse::chunk_size, transport, buf, 16)
-                        .await
-                        .map_err(|e| BodyErrorReason::InvalidChunkSize.with_cx(e))?
-                        .ok_or_else(|| BodyErrorReason::ClosedWhileReadingChunkSize.as_err())?;
+                let (next_buf, chunk_size) = read_and_parse(
+                    super::parse::chunk_size,
+                    transport,
+                    buf,
+                    MAX_CHUNK_HEADER_LEN,
+                )
+                .await
+                .map_err(|e| BodyErrorReason::InvalidChunkSize.with_cx(e))?
+                .ok_or_else(|| BodyErrorReason::ClosedWhileReadingChunkSize.as_err())?;
                 buf = next_buf;
 
                 if chunk_size == 0 {
-                    // that's the final chunk, look for the final CRLF
-                    let (next_buf, _) = read_and_parse(super::parse::crlf, transport, buf, 2)
-                        .await
-                        .map_err(|e| BodyErrorReason::InvalidChunkTerminator.with_cx(e))?
-                        .ok_or_else(|| {
-                            BodyErrorReason::ClosedWhileReadingChunkTerminator.as_err()
-                        })?;
+                    // that's the final chunk: then come trailers, if any,
+                    // and the final CRLF
+                    let (next_buf, trailers) = read_and_parse(
+                        super::parse::headers_and_crlf,
+                        transport,
+                        buf,
+                        MAX_TRAILERS_LEN,
+                    )
+                    .await
+                    .map_err(|e| BodyErrorReason::InvalidChunkTerminator.with_cx(e))?
+                    .ok_or_else(|| BodyErrorReason::ClosedWhileReadingChunkTerminator.as_err())?;
                     buf = next_buf;
                     *self = ChunkedDecoder::Done;
                     buf_slot.replace(buf);
 
-                    // TODO: trailers
-                    return Ok(BodyChunk::Done { trailers: None });
+                    let mut trailers = trailers;
+                    for name in FORBIDDEN_TRAILERS {
+                        if trailers.remove(name).is_some() {
+                            debug!(%name, "dropping field that can't be a trailer");
+                        }
+                    }
+                    let trailers = (!trailers.is_empty()).then(|| Box::new(trailers));
+                    return Ok(BodyChunk::Done { trailers });
                 }
 
                 *self = ChunkedDecoder::ReadingChunk { remain: chunk_size }
@@ -231,28 +384,49 @@ impl ChunkedDecoder {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum BodyWriteMode {
-    // we're doing chunked transfer encoding
-    Chunked,
-
-    // we set a length and are writing exactly the number of bytes we promised
-    ContentLength,
-
-    // we didn't set a content-length and we're not doing chunked transfer
-    // encoding, so we're not sending a body at all.
-    Empty,
-}
-
+#[cfg(feature = "client")]
 pub(crate) async fn write_h1_body(
     transport: &mut impl WriteOwned,
     body: &mut impl Body,
     mode: BodyWriteMode,
+    scratch: &mut RollMut,
 ) -> eyre::Result<()> {
+    let mut peeked = None;
     loop {
-        match body.next_chunk().await? {
-            BodyChunk::Chunk(chunk) => write_h1_body_chunk(transport, chunk, mode).await?,
-            BodyChunk::Done { .. } => {
+        let next = match peeked.take() {
+            Some(next) => next,
+            None => body.next_chunk().await?,
+        };
+        match next {
+            BodyChunk::Chunk(chunk) => {
+                if body.eof() {
+                    // no more data is coming, so the body end can go out
+                    // along with this chunk.
+                    match body.next_chunk().await? {
+                        BodyChunk::Done { trailers: None } => {
+                            // TODO: check that we've sent what we announced
+                            // in terms of content length
+                            write_h1_body_last_chunk(transport, chunk, mode, scratch).await?;
+                            break;
+                        }
+                        next => peeked = Some(next),
+                    }
+                }
+                write_h1_body_chunk(transport, chunk, mode, scratch).await?;
+            }
+            BodyChunk::Done {
+                trailers: Some(trailers),
+            } if mode == BodyWriteMode::Chunked => {
+                // trailers go between the last chunk and the final CRLF
+                let mut list = PieceList::default().with("0\r\n");
+                super::encode::encode_headers(*trailers, &mut list)?;
+                transport.writev_all(list.with("\r\n")).await?;
+                break;
+            }
+            BodyChunk::Done { trailers } => {
+                if trailers.is_some() {
+                    debug!(?mode, "body isn't chunked, dropping trailers");
+                }
                 // TODO: check that we've sent what we announced in terms of
                 // content length
                 write_h1_body_end(transport, mode).await?;
@@ -264,23 +438,66 @@ pub(crate) async fn write_h1_body(
     Ok(())
 }
 
+/// Largest chunk-size line: 16 hex digits for a `u64`, then CRLF
+const MAX_CHUNK_SIZE_LINE_LEN: usize = 16 + 2;
+
+/// Longest chunk-size line we read: 16 hex digits, extensions, CRLF
+const MAX_CHUNK_HEADER_LEN: usize = 16 + 1 + super::parse::MAX_CHUNK_EXT_LEN + 2;
+
+/// Max length of the trailer section of a chunked body, final CRLF included
+const MAX_TRAILERS_LEN: usize = 16 * 1024;
+
+/// Fields that are needed before the content is, and so can't be sent as
+/// trailers, cf. <https://httpwg.org/specs/rfc9110.html#trailers.limitations>:
+/// framing, routing and content format. They're dropped when read, so that
+/// nothing down the line takes them for header fields.
+const FORBIDDEN_TRAILERS: &[HeaderName] = &[
+    header::CONTENT_LENGTH,
+    header::TRANSFER_ENCODING,
+    header::TRAILER,
+    header::HOST,
+    header::CONTENT_ENCODING,
+    header::CONTENT_TYPE,
+    header::CONTENT_RANGE,
+];
+
+/// Writes the `size\r\n` line that precedes a chunk into `scratch`, without
+/// going through `format!`.
+fn chunk_size_line(scratch: &mut RollMut, size: usize) -> eyre::Result<Roll> {
+    const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+    let mut line = [0u8; MAX_CHUNK_SIZE_LINE_LEN];
+    let num_digits = std::cmp::max(1, (usize::BITS - size.leading_zeros()).div_ceil(4)) as usize;
+    for (i, digit) in line[..num_digits].iter_mut().rev().enumerate() {
+        *digit = HEX_DIGITS[(size >> (i * 4)) & 0xf];
+    }
+    line[num_digits..num_digits + 2].copy_from_slice(b"\r\n");
+    let line = &line[..num_digits + 2];
+
+    scratch.put_to_roll(line.len(), |buf| {
+        buf.copy_from_slice(line);
+        Ok(())
+    })
+}
+
 pub(crate) async fn write_h1_body_chunk(
     transport: &mut impl WriteOwned,
     chunk: Piece,
     mode: BodyWriteMode,
+    scratch: &mut RollMut,
 ) -> eyre::Result<()> {
     match mode {
         BodyWriteMode::Chunked => {
             transport
                 .writev_all(
                     PieceList::default()
-                        .with(format!("{:x}\r\n", chunk.len()).into_bytes())
+                        .with(chunk_size_line(scratch, chunk.len())?)
                         .with(chunk)
                         .with("\r\n"),
                 )
                 .await?;
         }
-        BodyWriteMode::ContentLength => {
+        BodyWriteMode::ContentLength | BodyWriteMode::CloseDelimited => {
             transport.write_all(chunk).await?;
         }
         BodyWriteMode::Empty => {
@@ -292,6 +509,34 @@ pub(crate) async fn write_h1_body_chunk(
     Ok(())
 }
 
+/// Like [write_h1_body_chunk] followed by [write_h1_body_end], but with
+/// chunked transfer encoding, the terminating zero-length chunk goes out in
+/// the same write as the last chunk.
+pub(crate) async fn write_h1_body_last_chunk(
+    transport: &mut impl WriteOwned,
+    chunk: Piece,
+    mode: BodyWriteMode,
+    scratch: &mut RollMut,
+) -> eyre::Result<()> {
+    match mode {
+        BodyWriteMode::Chunked => {
+            transport
+                .writev_all(
+                    PieceList::default()
+                        .with(chunk_size_line(scratch, chunk.len())?)
+                        .with(chunk)
+                        .with("\r\n0\r\n\r\n"),
+                )
+                .await?;
+            Ok(())
+        }
+        _ => {
+            write_h1_body_chunk(transport, chunk, mode, scratch).await?;
+            write_h1_body_end(transport, mode).await
+        }
+    }
+}
+
 pub(crate) async fn write_h1_body_end(
     transport: &mut impl WriteOwned,
     mode: BodyWriteMode,
@@ -307,6 +552,107 @@ pub(crate) async fn write_h1_body_end(
         BodyWriteMode::Empty => {
             // nothing to do
         }
+        BodyWriteMode::CloseDelimited => {
+            // nothing to do, the server closes the connection once the
+            // response is done
+        }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use fluke_buffet::RollMut;
+    use fluke_maybe_uring::io::ChanRead;
+
+    use super::{H1Body, H1BodyKind, MAX_TRAILERS_LEN};
+    use crate::{Body, BodyChunk, Headers};
+
+    /// Decodes `input` as a chunked body, returns its data and trailers
+    async fn decode_chunked(
+        input: impl Into<Vec<u8>>,
+    ) -> eyre::Result<(Vec<u8>, Option<Box<Headers>>)> {
+        let (tx, read) = ChanRead::new();
+        tx.send(input).await.unwrap();
+        drop(tx);
+
+        let mut body = H1Body::new(read, RollMut::alloc().unwrap(), H1BodyKind::Chunked);
+        let mut out = vec![];
+        loop {
+            match body.next_chunk().await? {
+                BodyChunk::Chunk(chunk) => out.extend_from_slice(&chunk[..]),
+                BodyChunk::Done { trailers } => return Ok((out, trailers)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_h1_chunked_trailers() {
+        crate::maybe_uring::start(async move {
+            // absent
+            let (out, trailers) = decode_chunked("3\r\nabc\r\n0\r\n\r\n").await.unwrap();
+            assert_eq!(out, b"abc");
+            assert!(trailers.is_none());
+
+            // present
+            let (out, trailers) =
+                decode_chunked("3\r\nabc\r\n0\r\nx-checksum: 1234\r\nx-other: a\r\n\r\n")
+                    .await
+                    .unwrap();
+            assert_eq!(out, b"abc");
+            let trailers = trailers.unwrap();
+            assert_eq!(trailers.len(), 2);
+            assert_eq!(&trailers.get("x-checksum").unwrap()[..], b"1234");
+
+            // framing fields are dropped, the others kept
+            let (_, trailers) = decode_chunked(
+                "0\r\ncontent-length: 5\r\ntransfer-encoding: chunked\r\nhost: example.org\r\nx-checksum: 1234\r\n\r\n",
+            )
+            .await
+            .unwrap();
+            let trailers = trailers.unwrap();
+            assert_eq!(
+                trailers
+                    .keys()
+                    .map(|name| name.as_str())
+                    .collect::<Vec<_>>(),
+                vec!["x-checksum"]
+            );
+
+            // ...and if that's all there was, there are no trailers
+            let (_, trailers) = decode_chunked("0\r\ncontent-length: 5\r\n\r\n")
+                .await
+                .unwrap();
+            assert!(trailers.is_none());
+
+            // over the limit
+            let input = format!(
+                "3\r\nabc\r\n0\r\nx-big: {}\r\n\r\n",
+                "a".repeat(MAX_TRAILERS_LEN)
+            );
+            assert!(decode_chunked(input).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_h1_chunked_trailers_leave_the_rest() {
+        crate::maybe_uring::start(async move {
+            let (tx, read) = ChanRead::new();
+            tx.send("0\r\nx-checksum: 1234\r\n\r\nGET / HTTP/1.1\r\n")
+                .await
+                .unwrap();
+            drop(tx);
+
+            let mut body = H1Body::new(read, RollMut::alloc().unwrap(), H1BodyKind::Chunked);
+            let BodyChunk::Done { trailers } = body.next_chunk().await.unwrap() else {
+                panic!("expected the end of the body");
+            };
+            assert!(trailers.is_some());
+            assert!(body.eof());
+
+            // what follows the body is left for the next request
+            let (buf, _) = body.into_inner().unwrap();
+            assert_eq!(&buf[..], b"GET / HTTP/1.1\r\n");
+        });
+    }
+}
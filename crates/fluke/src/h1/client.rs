@@ -1,13 +1,20 @@
+use std::time::Duration;
+
 use eyre::Context;
-use http::header;
+use http::{header, StatusCode, Version};
+use tokio::sync::oneshot;
 use tracing::debug;
 
-use crate::{types::Request, util::read_and_parse, Body, HeadersExt, Response};
+use crate::{
+    types::Request,
+    util::{read_and_parse, SemanticError},
+    Body, BodyWriteMode, HeadersExt, Method, Response,
+};
 use fluke_buffet::{PieceList, RollMut};
-use fluke_maybe_uring::io::{ReadOwned, WriteOwned};
+use fluke_maybe_uring::io::{Transport, WriteOwned};
 
 use super::{
-    body::{write_h1_body, BodyWriteMode, H1Body, H1BodyKind},
+    body::{write_h1_body, H1Body, H1BodyKind},
     encode::encode_request,
 };
 
@@ -17,7 +24,21 @@ pub struct ClientConf {}
 pub trait ClientDriver {
     type Return;
 
-    async fn on_informational_response(&mut self, res: Response) -> eyre::Result<()>;
+    /// Called for each informational (1xx) response received before the
+    /// final one, in order: there may be any number of them, e.g. a
+    /// `100 Continue` then a `103 Early Hints`, cf.
+    /// <https://httpwg.org/specs/rfc9110.html#status.1xx>. Ignores them by
+    /// default.
+    ///
+    /// `100 Continue` also tells [request] to send the body of a request
+    /// with `expect: 100-continue`, whatever this does with it.
+    /// `101 Switching Protocols` is never passed here: it ends the exchange
+    /// with an error, since the connection isn't HTTP anymore.
+    async fn on_informational_response(&mut self, res: Response) -> eyre::Result<()> {
+        debug!(status = %res.status, "ignoring informational response");
+        Ok(())
+    }
+
     async fn on_final_response(
         self,
         res: Response,
@@ -25,21 +46,41 @@ pub trait ClientDriver {
     ) -> eyre::Result<Self::Return>;
 }
 
+/// How long a request with `expect: 100-continue` waits for a `100 Continue`
+/// before sending its body anyway, cf.
+/// <https://httpwg.org/specs/rfc9110.html#field.expect>
+const EXPECT_CONTINUE_TIMEOUT: Duration = Duration::from_secs(1);
+
 /// Perform an HTTP/1.1 request against an HTTP/1.1 server
 ///
-/// The transport halves will be returned unless the server requested connection
-/// close or the request body wasn't fully drained
-pub async fn request<R, W, D>(
-    (mut transport_r, mut transport_w): (R, W),
+/// If the request has `expect: 100-continue`, its body is only sent once
+/// the server answers with `100 Continue` (or hasn't answered at all for a
+/// second). If the final response comes first, the body isn't sent at all.
+///
+/// The transport's halves are returned if the connection can carry another
+/// request, which takes:
+///
+/// - neither the request nor the response saying `connection: close`, and
+///   an HTTP/1.0 response saying `connection: keep-alive`
+/// - a response body with a length, rather than one that lasts until the
+///   connection closes, that the driver read to the end
+/// - nothing past the response body, and the request body sent in full
+pub async fn request<T, D>(
+    transport: T,
     mut req: Request,
     body: &mut impl Body,
-    driver: D,
-) -> eyre::Result<(Option<(R, W)>, D::Return)>
+    mut driver: D,
+) -> eyre::Result<(Option<(T::Read, T::Write)>, D::Return)>
 where
-    R: ReadOwned,
-    W: WriteOwned,
+    T: Transport,
     D: ClientDriver,
 {
+    let (mut transport_r, mut transport_w) = transport.into_halves();
+
+    // what the response is read against, once `req` is sent
+    let method = req.method.clone();
+    let req_conn_close = req.headers.is_connection_close();
+
     let mode = match body.content_len() {
         Some(0) => BodyWriteMode::Empty,
         Some(len) => {
@@ -52,20 +93,38 @@ where
         None => BodyWriteMode::Chunked,
     };
 
-    let mut buf = RollMut::alloc()?;
+    let buf = RollMut::alloc()?;
+    let mut out_scratch = RollMut::alloc()?;
+
+    // told whether to go ahead with the body, once the server said
+    let (go_ahead_tx, go_ahead_rx) = oneshot::channel::<bool>();
+    let mut go_ahead_tx = Some(go_ahead_tx);
+    let go_ahead_rx = match mode {
+        BodyWriteMode::Empty => None,
+        _ => req.headers.expects_100_continue().then_some(go_ahead_rx),
+    };
 
     let mut list = PieceList::default();
-    encode_request(req, &mut list, &mut buf)?;
+    encode_request(req, &mut list, &mut out_scratch)?;
     transport_w
         .writev_all(list)
         .await
         .wrap_err("writing request headers")?;
 
-    // TODO: handle `expect: 100-continue` (don't start sending body until we get a 100 response)
-
     let send_body_fut = {
         async move {
-            match write_h1_body(&mut transport_w, body, mode).await {
+            if let Some(go_ahead_rx) = go_ahead_rx {
+                match tokio::time::timeout(EXPECT_CONTINUE_TIMEOUT, go_ahead_rx).await {
+                    Ok(Ok(true)) => debug!("got 100 Continue, sending request body"),
+                    Err(_) => debug!("no 100 Continue yet, sending request body anyway"),
+                    Ok(_) => {
+                        debug!("got a final response first, not sending request body");
+                        return Ok((transport_w, false));
+                    }
+                }
+            }
+
+            match write_h1_body(&mut transport_w, body, mode, &mut out_scratch).await {
                 Err(err) => {
                     // TODO: find way to report this error to the driver without
                     // spawning, without ref-counting the driver, etc.
@@ -73,7 +132,7 @@ where
                 }
                 Ok(_) => {
                     debug!("done writing request body");
-                    Ok::<_, eyre::Report>(transport_w)
+                    Ok::<_, eyre::Report>((transport_w, true))
                 }
             }
         }
@@ -81,47 +140,58 @@ where
 
     let recv_res_fut = {
         async move {
-            let (buf, res) = read_and_parse(
-                super::parse::response,
-                &mut transport_r,
-                buf,
-                // TODO: make this configurable
-                64 * 1024,
-            )
-            .await
-            .map_err(|e| eyre::eyre!("error reading response headers from server: {e:?}"))?
-            .ok_or_else(|| eyre::eyre!("server went away before sending response headers"))?;
-            debug!("client received response");
-            res.debug_print();
-
-            if res.status.is_informational() {
-                todo!("handle informational responses");
-            }
+            let mut buf = buf;
+            let res = loop {
+                let res;
+                (buf, res) = read_and_parse(
+                    super::parse::complete_head(super::parse::response),
+                    &mut transport_r,
+                    buf,
+                    // TODO: make this configurable
+                    64 * 1024,
+                )
+                .await
+                .map_err(|e| eyre::eyre!("error reading response headers from server: {e:?}"))?
+                .ok_or_else(|| eyre::eyre!("server went away before sending response headers"))?;
+                debug!("client received response");
+                res.debug_print();
 
-            let chunked = res.headers.is_chunked_transfer_encoding();
-
-            // TODO: handle 204/304 separately
-            let content_len = res.headers.content_length().unwrap_or_default();
-
-            let mut res_body = H1Body::new(
-                transport_r,
-                buf,
-                if chunked {
-                    // TODO: even with chunked transfer-encoding, we can announce
-                    // a content length - we should probably detect errors there?
-                    H1BodyKind::Chunked
-                } else {
-                    H1BodyKind::ContentLength(content_len)
-                },
-            );
+                if res.status == StatusCode::SWITCHING_PROTOCOLS {
+                    return Err(eyre::eyre!(
+                        "server switched protocols, which plain requests can't follow"
+                    ));
+                }
+                if !res.status.is_informational() {
+                    // the body goes out only if the server asked for it already
+                    if let Some(go_ahead_tx) = go_ahead_tx.take() {
+                        _ = go_ahead_tx.send(false);
+                    }
+                    break res;
+                }
+                if res.status == StatusCode::CONTINUE {
+                    if let Some(go_ahead_tx) = go_ahead_tx.take() {
+                        _ = go_ahead_tx.send(true);
+                    }
+                }
+                driver.on_informational_response(res).await?;
+            };
 
-            let conn_close = res.headers.is_connection_close();
+            let body_kind = response_body_kind(&method, &res)
+                .map_err(|e| eyre::eyre!("refusing response from server: {e}"))?;
+            let reusable = !req_conn_close && keeps_alive(&res) && body_kind.is_some();
+            if !reusable {
+                debug!("connection can't be reused after this response");
+            }
+            let mut res_body = match body_kind {
+                Some(body_kind) => H1Body::new(transport_r, buf, body_kind),
+                None => H1Body::until_close(transport_r, buf),
+            };
 
             let ret = driver.on_final_response(res, &mut res_body).await?;
 
-            let transport_r = match (conn_close, res_body.into_inner()) {
-                // can only re-use the body if conn_close is false and the body was fully draided
-                (false, Some((_buf, transport_r))) => Some(transport_r),
+            // whatever's past the body isn't the answer to anything we sent
+            let transport_r = match res_body.into_inner() {
+                Some((buf, transport_r)) if reusable && buf.is_empty() => Some(transport_r),
                 _ => None,
             };
 
@@ -131,9 +201,310 @@ where
 
     // TODO: cancel sending the body if we get a response early?
     let (send_res, recv_res) = tokio::try_join!(send_body_fut, recv_res_fut)?;
-    let transport_w = send_res;
+    let (transport_w, body_sent) = send_res;
     let (transport_r, ret) = recv_res;
 
-    let transport = transport_r.map(|transport_r| (transport_r, transport_w));
+    // the server may still be waiting for a body we didn't send
+    let transport = transport_r
+        .filter(|_| body_sent || matches!(mode, BodyWriteMode::Empty))
+        .map(|transport_r| (transport_r, transport_w));
     Ok((transport, ret))
 }
+
+/// How the body of a response to a `method` request is delimited, `None`
+/// meaning it lasts until the server closes the connection, cf.
+/// <https://httpwg.org/specs/rfc9112.html#message.body.length>
+fn response_body_kind(
+    method: &Method,
+    res: &Response,
+) -> Result<Option<H1BodyKind>, SemanticError> {
+    // whatever their headers say
+    if *method == Method::Head
+        || res.status == StatusCode::NO_CONTENT
+        || res.status == StatusCode::NOT_MODIFIED
+    {
+        return Ok(Some(H1BodyKind::ContentLength(0)));
+    }
+    if !res.headers.contains_key(header::TRANSFER_ENCODING)
+        && !res.headers.contains_key(header::CONTENT_LENGTH)
+    {
+        return Ok(None);
+    }
+    H1BodyKind::from_headers(&res.headers).map(Some)
+}
+
+/// Whether the server means to keep the connection open after `res`:
+/// HTTP/1.0 connections aren't persistent unless asked for
+fn keeps_alive(res: &Response) -> bool {
+    if res.headers.is_connection_close() {
+        return false;
+    }
+    res.version != Version::HTTP_10 || res.headers.is_connection_keep_alive()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use fluke_buffet::Piece;
+    use fluke_maybe_uring::io::{ChanRead, ChanWrite};
+    use http::{header, StatusCode};
+
+    use super::{request, ClientDriver};
+    use crate::{Body, BodyChunk, Method, Request, Response};
+
+    struct Collect;
+
+    impl ClientDriver for Collect {
+        type Return = Vec<u8>;
+
+        async fn on_final_response(
+            self,
+            _res: Response,
+            body: &mut impl Body,
+        ) -> eyre::Result<Self::Return> {
+            let mut out = vec![];
+            while let BodyChunk::Chunk(chunk) = body.next_chunk().await? {
+                out.extend_from_slice(&chunk[..]);
+            }
+            Ok(out)
+        }
+    }
+
+    #[test]
+    fn test_h1_client_refuses_ambiguous_framing() {
+        crate::maybe_uring::start(async move {
+            for (response, body) in [
+                ("HTTP/1.1 200 OK\r\ncontent-length: 3\r\n\r\nabc", Some(&b"abc"[..])),
+                (
+                    "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n3;x=y\r\nabc\r\n0\r\n\r\n",
+                    Some(&b"abc"[..]),
+                ),
+                // a proxy reading these differently than the next hop would
+                // desync the connection it forwards them on
+                (
+                    "HTTP/1.1 200 OK\r\ncontent-length: 5\r\ntransfer-encoding: chunked\r\n\r\n0\r\n\r\n",
+                    None,
+                ),
+                (
+                    "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked, identity\r\n\r\nabc",
+                    None,
+                ),
+                (
+                    "HTTP/1.1 200 OK\r\ncontent-length: 3\r\ncontent-length: 30\r\n\r\nabc",
+                    None,
+                ),
+            ] {
+                let (tx, read) = ChanRead::new();
+                let (mut rx, write) = ChanWrite::new();
+                // whatever the request looks like, it goes nowhere
+                crate::maybe_uring::spawn(async move { while rx.recv().await.is_some() {} });
+                tx.send(response).await.unwrap();
+                drop(tx);
+
+                let res = request((read, write), Request::default(), &mut (), Collect).await;
+                assert_eq!(
+                    res.ok().map(|(_, body)| body).as_deref(),
+                    body,
+                    "{response:?}"
+                );
+            }
+        });
+    }
+
+    /// Collects the statuses of informational responses, then the body of
+    /// the final one
+    #[derive(Default)]
+    struct Record(Vec<StatusCode>);
+
+    impl ClientDriver for Record {
+        type Return = (Vec<StatusCode>, Vec<u8>);
+
+        async fn on_informational_response(&mut self, res: Response) -> eyre::Result<()> {
+            self.0.push(res.status);
+            Ok(())
+        }
+
+        async fn on_final_response(
+            self,
+            res: Response,
+            body: &mut impl Body,
+        ) -> eyre::Result<Self::Return> {
+            let mut statuses = self.0;
+            statuses.push(res.status);
+            Ok((statuses, Collect.on_final_response(res, body).await?))
+        }
+    }
+
+    /// A body of known length, in a single chunk
+    #[derive(Debug)]
+    struct Once(&'static str, bool);
+
+    impl Body for Once {
+        fn content_len(&self) -> Option<u64> {
+            Some(self.0.len() as u64)
+        }
+
+        fn eof(&self) -> bool {
+            self.1
+        }
+
+        async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+            if std::mem::replace(&mut self.1, true) {
+                return Ok(BodyChunk::Done { trailers: None });
+            }
+            Ok(BodyChunk::Chunk(Piece::from(self.0)))
+        }
+    }
+
+    #[test]
+    fn test_h1_client_informational_responses() {
+        crate::maybe_uring::start(async move {
+            let mut req = Request {
+                method: Method::Post,
+                ..Default::default()
+            };
+            req.headers.insert(header::EXPECT, "100-continue".into());
+
+            let (tx, read) = ChanRead::new();
+            let (mut rx, write) = ChanWrite::new();
+            let server = crate::maybe_uring::spawn(async move {
+                let mut head = vec![];
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.extend(rx.recv().await.unwrap());
+                }
+                // the body waits for the server to ask for it
+                let early = tokio::time::timeout(Duration::from_millis(100), rx.recv()).await;
+                assert!(early.is_err(), "body sent before 100 Continue");
+
+                tx.send("HTTP/1.1 100 Continue\r\n\r\n").await.unwrap();
+                assert_eq!(rx.recv().await.unwrap(), b"abc");
+                tx.send("HTTP/1.1 103 Early Hints\r\nlink: </a.css>\r\n\r\n")
+                    .await
+                    .unwrap();
+                tx.send("HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+                    .await
+                    .unwrap();
+                (tx, rx)
+            });
+
+            let mut body = Once("abc", false);
+            let (transport, (statuses, res_body)) =
+                request((read, write), req.clone(), &mut body, Record::default())
+                    .await
+                    .unwrap();
+            assert_eq!(
+                statuses,
+                [
+                    StatusCode::CONTINUE,
+                    StatusCode::from_u16(103).unwrap(),
+                    StatusCode::OK
+                ]
+            );
+            assert_eq!(res_body, b"ok");
+            assert!(transport.is_some());
+            drop(server.await.unwrap());
+
+            // answered right away: the body isn't sent, and the connection
+            // can't be reused, since the server may still be expecting it
+            let (tx, read) = ChanRead::new();
+            let (mut rx, write) = ChanWrite::new();
+            tx.send("HTTP/1.1 417 Expectation Failed\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            let server = crate::maybe_uring::spawn(async move {
+                let mut out = vec![];
+                while let Some(chunk) = rx.recv().await {
+                    out.extend(chunk);
+                }
+                out
+            });
+            let mut body = Once("abc", false);
+            let (transport, (statuses, _)) =
+                request((read, write), req, &mut body, Record::default())
+                    .await
+                    .unwrap();
+            assert_eq!(statuses, [StatusCode::EXPECTATION_FAILED]);
+            assert!(transport.is_none());
+            drop(tx);
+            assert!(server.await.unwrap().ends_with(b"\r\n\r\n"));
+        });
+    }
+
+    #[test]
+    fn test_h1_client_connection_reuse() {
+        crate::maybe_uring::start(async move {
+            for (method, response, reusable, body) in [
+                (
+                    Method::Get,
+                    "HTTP/1.1 200 OK\r\ncontent-length: 3\r\n\r\nabc",
+                    true,
+                    "abc",
+                ),
+                (
+                    Method::Get,
+                    "HTTP/1.1 200 OK\r\nconnection: keep-alive, close\r\ncontent-length: 3\r\n\r\nabc",
+                    false,
+                    "abc",
+                ),
+                // the body lasts until the connection closes
+                (Method::Get, "HTTP/1.1 200 OK\r\n\r\nabc", false, "abc"),
+                (
+                    Method::Get,
+                    "HTTP/1.0 200 OK\r\ncontent-length: 3\r\n\r\nabc",
+                    false,
+                    "abc",
+                ),
+                (
+                    Method::Get,
+                    "HTTP/1.0 200 OK\r\nconnection: keep-alive\r\ncontent-length: 3\r\n\r\nabc",
+                    true,
+                    "abc",
+                ),
+                // no body, whatever the headers say
+                (
+                    Method::Head,
+                    "HTTP/1.1 200 OK\r\ncontent-length: 3\r\n\r\n",
+                    true,
+                    "",
+                ),
+                (Method::Get, "HTTP/1.1 204 No Content\r\n\r\n", true, ""),
+                // that's not the answer to anything
+                (
+                    Method::Get,
+                    "HTTP/1.1 200 OK\r\ncontent-length: 3\r\n\r\nabcdef",
+                    false,
+                    "abc",
+                ),
+            ] {
+                let (tx, read) = ChanRead::new();
+                let (mut rx, write) = ChanWrite::new();
+                crate::maybe_uring::spawn(async move { while rx.recv().await.is_some() {} });
+                tx.send(response).await.unwrap();
+                drop(tx);
+
+                let req = Request {
+                    method,
+                    ..Default::default()
+                };
+                let (transport, res_body) = request((read, write), req, &mut (), Collect)
+                    .await
+                    .unwrap();
+                assert_eq!(transport.is_some(), reusable, "{response:?}");
+                assert_eq!(res_body, body.as_bytes(), "{response:?}");
+            }
+
+            // the request can close the connection too
+            let (tx, read) = ChanRead::new();
+            let (mut rx, write) = ChanWrite::new();
+            crate::maybe_uring::spawn(async move { while rx.recv().await.is_some() {} });
+            tx.send("HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            let mut req = Request::default();
+            req.headers.insert(header::CONNECTION, "close".into());
+            let (transport, _) = request((read, write), req, &mut (), Collect).await.unwrap();
+            assert!(transport.is_none());
+        });
+    }
+}
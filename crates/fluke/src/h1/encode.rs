@@ -1,17 +1,25 @@
-use std::io::Write;
+use std::rc::Rc;
 
 use eyre::Context;
-use http::{StatusCode, Version};
+use http::{header, HeaderName, StatusCode, Version};
+use tracing::debug;
 
 use crate::{
-    types::{Headers, Request, Response},
-    Encoder,
+    types::{retain_allowed_trailers, Headers, Response},
+    BodyWriteMode, CorrelationId, Encoder, HeadersExt,
 };
 use fluke_buffet::{Piece, PieceList, RollMut};
 use fluke_maybe_uring::io::WriteOwned;
 
-use super::body::{write_h1_body_chunk, write_h1_body_end, BodyWriteMode};
+use super::{
+    body::{write_h1_body_chunk, write_h1_body_end, write_h1_body_last_chunk},
+    ConnectionHandle,
+};
+
+#[cfg(feature = "client")]
+use {crate::types::Request, std::io::Write};
 
+#[cfg(feature = "client")]
 pub(crate) fn encode_request(
     req: Request,
     list: &mut PieceList,
@@ -21,7 +29,7 @@ pub(crate) fn encode_request(
     list.push(" ");
 
     assert_eq!(out_scratch.len(), 0);
-    out_scratch.write_all(req.uri.path().as_bytes())?;
+    out_scratch.write_all(req.uri.path_and_query().as_bytes())?;
     list.push(out_scratch.take_all());
 
     match req.version {
@@ -149,13 +157,113 @@ where
     T: WriteOwned,
 {
     pub(crate) transport_w: T,
+
+    /// Set once we've written response headers that require closing the
+    /// connection after the response, see [H1Encoder::write_response]
+    pub(crate) close_after_response: bool,
+
+    /// Whether the client sent `te: trailers`
+    accepts_trailers: bool,
+
+    /// Forbidden trailer fields we're allowed to send anyway
+    allowed_trailers: Rc<[HeaderName]>,
+
+    /// Where chunk-size lines get written, kept across responses
+    pub(crate) out_scratch: RollMut,
+
+    /// Whether to honor [Encoder::set_corked], see [ServerConf::cork_responses](super::ServerConf::cork_responses)
+    cork: bool,
+
+    /// Echoed in the final response, see [ServerConf::request_id_header](super::ServerConf::request_id_header)
+    pub(crate) correlation_id: Option<CorrelationId>,
+
+    /// Once it's shutting down, final responses announce that the
+    /// connection closes after them
+    pub(crate) conn_handle: ConnectionHandle,
+
+    /// This is the last request the connection carries, see
+    /// [ServerConf::max_requests_per_connection](super::ServerConf::max_requests_per_connection):
+    /// the final response announces that the connection closes after it
+    pub(crate) last_request: bool,
+
+    /// The version of the request being answered: HTTP/1.0 clients get no
+    /// interim responses, no chunked bodies, and a connection that closes
+    /// after the response unless they asked otherwise
+    pub(crate) request_version: Version,
+
+    /// The client sent `connection: keep-alive`, which only matters to
+    /// HTTP/1.0 exchanges
+    pub(crate) keep_alive_requested: bool,
+}
+
+impl<T> H1Encoder<T>
+where
+    T: WriteOwned,
+{
+    pub(crate) fn new(
+        transport_w: T,
+        accepts_trailers: bool,
+        allowed_trailers: Rc<[HeaderName]>,
+        out_scratch: RollMut,
+        cork: bool,
+    ) -> Self {
+        Self {
+            transport_w,
+            close_after_response: false,
+            accepts_trailers,
+            allowed_trailers,
+            out_scratch,
+            cork,
+            correlation_id: None,
+            conn_handle: Default::default(),
+            last_request: false,
+            request_version: Version::HTTP_11,
+            keep_alive_requested: false,
+        }
+    }
 }
 
 impl<T> Encoder for H1Encoder<T>
 where
     T: WriteOwned,
 {
-    async fn write_response(&mut self, res: Response) -> eyre::Result<()> {
+    async fn write_response(&mut self, mut res: Response) -> eyre::Result<()> {
+        let informational = res.status.is_informational();
+        if informational && self.request_version == Version::HTTP_10 {
+            // cf. <https://httpwg.org/specs/rfc9110.html#status.1xx>
+            debug!(status = %res.status, "not sending interim response to HTTP/1.0 client");
+            return Ok(());
+        }
+
+        // HTTP/1.0 connections aren't persistent unless asked for, and
+        // bodies without a content-length are delimited by closing the
+        // connection: let the client know whether we're about to.
+        if !informational
+            && (self.request_version == Version::HTTP_10 || res.version == Version::HTTP_10)
+        {
+            let keep_alive = if self.request_version == Version::HTTP_10 {
+                self.keep_alive_requested
+            } else {
+                res.headers.is_connection_keep_alive()
+            };
+            let close_delimited = !res.means_empty_body() && res.headers.content_length().is_none();
+            let connection = if keep_alive && !close_delimited {
+                "keep-alive"
+            } else {
+                "close"
+            };
+            res.headers.insert(header::CONNECTION, connection.into());
+        }
+        if !informational && (self.last_request || self.conn_handle.is_shutting_down()) {
+            res.headers.insert(header::CONNECTION, "close".into());
+        }
+        if res.headers.is_connection_close() {
+            self.close_after_response = true;
+        }
+
+        if let (false, Some(correlation_id)) = (informational, &self.correlation_id) {
+            correlation_id.apply(&mut res.headers);
+        }
         let mut list = PieceList::default();
         encode_response(res, &mut list)?;
 
@@ -164,13 +272,30 @@ where
             .await
             .wrap_err("writing response headers upstream")?;
 
+        if informational {
+            // the client may be waiting on this before it does anything else,
+            // e.g. send the request body after a `100 Continue`
+            self.transport_w
+                .flush()
+                .await
+                .wrap_err("writing response headers upstream")?;
+        }
+
         Ok(())
     }
 
     // TODO: move `mode` into `H1Encoder`? we don't need it for h2
     async fn write_body_chunk(&mut self, chunk: Piece, mode: BodyWriteMode) -> eyre::Result<()> {
         // TODO: inline
-        write_h1_body_chunk(&mut self.transport_w, chunk, mode).await
+        write_h1_body_chunk(&mut self.transport_w, chunk, mode, &mut self.out_scratch).await
+    }
+
+    async fn write_last_body_chunk(
+        &mut self,
+        chunk: Piece,
+        mode: BodyWriteMode,
+    ) -> eyre::Result<()> {
+        write_h1_body_last_chunk(&mut self.transport_w, chunk, mode, &mut self.out_scratch).await
     }
 
     async fn write_body_end(&mut self, mode: BodyWriteMode) -> eyre::Result<()> {
@@ -178,10 +303,32 @@ where
         write_h1_body_end(&mut self.transport_w, mode).await
     }
 
-    async fn write_trailers(&mut self, trailers: Box<Headers>) -> eyre::Result<()> {
+    fn set_corked(&mut self, corked: bool) -> eyre::Result<()> {
+        if self.cork {
+            self.transport_w
+                .set_corked(corked)
+                .wrap_err("setting cork on transport")?;
+        }
+        Ok(())
+    }
+
+    fn chunked_bodies(&self) -> bool {
+        self.request_version != Version::HTTP_10
+    }
+
+    async fn write_trailers(&mut self, mut trailers: Box<Headers>) -> eyre::Result<()> {
         // TODO: check all preconditions
+        if !self.accepts_trailers {
+            debug!("client didn't send te: trailers, dropping trailers");
+            trailers.clear();
+        }
+        retain_allowed_trailers(&mut trailers, &self.allowed_trailers);
+
+        // trailers go between the last chunk and the final CRLF
         let mut list = PieceList::default();
+        list.push("0\r\n");
         encode_headers(*trailers, &mut list)?;
+        list.push("\r\n");
 
         self.transport_w
             .writev_all(list)
@@ -0,0 +1,85 @@
+use std::{cell::Cell, rc::Rc, time::Duration};
+
+use tokio::sync::Notify;
+
+/// A handle to a connection being served by [serve_with_handle](super::serve_with_handle),
+/// that lets embedders inspect and close it from the outside.
+///
+/// Cloning it is cheap, all clones refer to the same connection.
+#[derive(Clone, Default)]
+pub struct ConnectionHandle {
+    inner: Rc<ConnectionHandleInner>,
+}
+
+#[derive(Default)]
+struct ConnectionHandleInner {
+    requests: Cell<u64>,
+    busy: Cell<bool>,
+
+    /// Set by [ConnectionHandle::shutdown]
+    drain_timeout: Cell<Option<Duration>>,
+    shutdown_notify: Notify,
+}
+
+impl ConnectionHandle {
+    /// How many request heads were read on this connection so far
+    pub fn requests(&self) -> u64 {
+        self.inner.requests.get()
+    }
+
+    /// Whether a request is being handled, as opposed to the connection
+    /// waiting for the next one
+    pub fn is_busy(&self) -> bool {
+        self.inner.busy.get()
+    }
+
+    /// Shuts the connection down gracefully: right away if it's waiting for
+    /// the next request, otherwise once the response to the current one is
+    /// written out. No other request is read from it, pipelined ones
+    /// included. If that takes longer than `drain_timeout`, the connection
+    /// is closed regardless.
+    ///
+    /// [serve_with_handle](super::serve_with_handle) returns once the
+    /// connection is closed. Calling this again while it's shutting down
+    /// does nothing.
+    pub fn shutdown(&self, drain_timeout: Duration) {
+        if self.is_shutting_down() {
+            return;
+        }
+        self.inner.drain_timeout.set(Some(drain_timeout));
+        self.inner.shutdown_notify.notify_waiters();
+    }
+
+    /// Whether [ConnectionHandle::shutdown] was called
+    pub fn is_shutting_down(&self) -> bool {
+        self.inner.drain_timeout.get().is_some()
+    }
+
+    /// Resolves once [ConnectionHandle::shutdown] is called, with its drain
+    /// timeout
+    pub(crate) async fn shutdown_requested(&self) -> Duration {
+        loop {
+            let notified = self.inner.shutdown_notify.notified();
+            if let Some(drain_timeout) = self.inner.drain_timeout.get() {
+                return drain_timeout;
+            }
+            notified.await;
+        }
+    }
+
+    /// Resolves once the drain timeout passed to
+    /// [ConnectionHandle::shutdown] has elapsed
+    pub(crate) async fn drain_timed_out(&self) {
+        let drain_timeout = self.shutdown_requested().await;
+        tokio::time::sleep(drain_timeout).await
+    }
+
+    pub(crate) fn record_request(&self) {
+        self.inner.requests.set(self.inner.requests.get() + 1);
+        self.inner.busy.set(true);
+    }
+
+    pub(crate) fn set_idle(&self) {
+        self.inner.busy.set(false);
+    }
+}
@@ -1,12 +1,21 @@
 //! HTTP/1.1 <https://httpwg.org/specs/rfc9112.html>
 //! HTTP semantics <https://httpwg.org/specs/rfc9110.html>
 
+#[cfg(feature = "client")]
 mod client;
+#[cfg(feature = "client")]
 pub use client::*;
 
 mod server;
 pub use server::*;
 
+mod handle;
+pub use handle::*;
+
 pub(crate) mod body;
 pub(crate) mod encode;
 pub(crate) mod parse;
+mod state;
+
+#[cfg(test)]
+pub(crate) mod testing;
@@ -3,25 +3,76 @@
 //! HTTP/1.1 <https://httpwg.org/specs/rfc9112.html>
 //! HTTP semantics <https://httpwg.org/specs/rfc9110.html>
 
-use http::{header::HeaderName, StatusCode, Version};
+use std::cell::Cell;
+
+use http::{header::HeaderName, Version};
 use nom::{
-    bytes::streaming::{tag, take, take_until, take_while1},
-    combinator::{map_res, opt},
+    bytes::streaming::{tag, take, take_until, take_while, take_while1},
+    combinator::{map_res, opt, verify},
+    error::{make_error, ErrorKind},
     sequence::{preceded, terminated},
     IResult,
 };
 
 use crate::{
-    types::{Headers, Request, Response},
+    types::{Headers, Request, RequestUri},
     Method,
 };
 use fluke_buffet::{PieceStr, Roll, RollStr};
 
+#[cfg(feature = "client")]
+use {crate::types::Response, http::StatusCode};
+
 const CRLF: &[u8] = b"\r\n";
 
-/// Parses a chunked transfer coding chunk size (hex text followed by CRLF)
+/// Longest chunk extension we put up with, see [chunk_size]
+pub const MAX_CHUNK_EXT_LEN: usize = 256;
+
+/// Most header records a message head may have, however large the head is
+/// allowed to be: a `HeaderMap` panics past 32768 entries, and short
+/// records are cheap to send but not to store.
+pub const MAX_HEADER_RECORDS: usize = 1024;
+
+/// Wraps a message head parser (like [request]) so that it only runs once
+/// the empty line that ends the head has arrived. Whoever reads the head
+/// runs the parser over everything buffered after each read, and a head
+/// trickling in a few bytes at a time would otherwise be parsed over and
+/// over, for work quadratic in its length. Looking for the empty line
+/// picks up where the last look left off.
+///
+/// The returned parser expects the same buffer to grow between calls, so
+/// make a new one for each head.
+pub fn complete_head<O>(
+    parser: impl Fn(Roll) -> IResult<Roll, O>,
+) -> impl Fn(Roll) -> IResult<Roll, O> {
+    let scanned = Cell::new(0);
+    move |i: Roll| {
+        // the empty line may straddle what was scanned and what's new
+        let from = std::cmp::min(scanned.get().saturating_sub(3), i.len());
+        if memchr::memmem::find(&i[from..], b"\r\n\r\n").is_none() {
+            scanned.set(i.len());
+            return Err(nom::Err::Incomplete(nom::Needed::Unknown));
+        }
+        parser(i)
+    }
+}
+
+/// Parses a chunked transfer coding chunk size (hex text, maybe followed by
+/// extensions, then CRLF)
 pub fn chunk_size(i: Roll) -> IResult<Roll, u64> {
-    terminated(u64_text_hex, tag(CRLF))(i)
+    terminated(u64_text_hex, terminated(opt(chunk_ext), tag(CRLF)))(i)
+}
+
+/// Chunk extensions (`;name=value`) are skipped: we don't know any, cf.
+/// <https://httpwg.org/specs/rfc9112.html#chunked.extension>. A bare CR or
+/// LF in there ends up failing the CRLF that must follow.
+fn chunk_ext(i: Roll) -> IResult<Roll, ()> {
+    let (i, _) = take_while(|c| c == b' ' || c == b'\t')(i)?;
+    let (i, _) = tag(&b";"[..])(i)?;
+    let (i, _) = verify(take_while(|c| c != b'\r' && c != b'\n'), |ext: &Roll| {
+        ext.len() <= MAX_CHUNK_EXT_LEN
+    })(i)?;
+    Ok((i, ()))
 }
 
 pub fn crlf(i: Roll) -> IResult<Roll, ()> {
@@ -40,9 +91,16 @@ pub fn request(i: Roll) -> IResult<Roll, Request> {
         method,
         // TODO: should this take the host header into account?
         // check what hyper does.
-        uri: path.parse().unwrap(),
+        uri: path,
+        protocol: None,
         version,
         headers,
+        transport_security: Default::default(),
+        conn_info: Default::default(),
+        id: Default::default(),
+        correlation_id: None,
+        client_addr: None,
+        stream_id: None,
     };
     Ok((i, request))
 }
@@ -71,10 +129,23 @@ fn is_delimiter(c: u8) -> bool {
     memchr::memchr(c, br#"(),/:;<=>?@[\]{}""#).is_some()
 }
 
-fn path(i: Roll) -> IResult<Roll, RollStr> {
-    let (i, path) = take_while1(is_uri_char)(i)?;
+/// Parses a request target. The common origin form (`/path?query`) is kept
+/// as-is, anything else (absolute form, authority form) gets parsed into its
+/// parts.
+fn path(i: Roll) -> IResult<Roll, RequestUri> {
+    let (rest, path) = take_while1(is_uri_char)(i.clone())?;
     let path = unsafe { path.to_string_unchecked() };
-    Ok((i, path))
+    if path.starts_with('/') || &path[..] == "*" {
+        return Ok((rest, RequestUri::new(None, None, path.into())));
+    }
+
+    match path.parse() {
+        Ok(uri) => Ok((rest, uri)),
+        Err(_) => Err(nom::Err::Error(nom::error::Error::new(
+            i,
+            nom::error::ErrorKind::Verify,
+        ))),
+    }
 }
 
 /// Returns true if `c` is a character that can be found in an URI
@@ -88,6 +159,7 @@ fn is_uri_char(c: u8) -> bool {
 }
 
 // Looks like `HTTP/1.1 200 OK\r\n` or `HTTP/1.1 404 Not Found\r\n`, then headers
+#[cfg(feature = "client")]
 pub fn response(i: Roll) -> IResult<Roll, Response> {
     let (i, version) = terminated(http_version, space1)(i)?;
     let (i, code) = terminated(status_code, space1)(i)?;
@@ -103,19 +175,23 @@ pub fn response(i: Roll) -> IResult<Roll, Response> {
 }
 
 /// Parses an HTTP/1.1 status code
+#[cfg(feature = "client")]
 fn status_code(i: Roll) -> IResult<Roll, StatusCode> {
     let (i, code) = map_res(take(3_usize), |r: Roll| StatusCode::from_bytes(&r[..]))(i)?;
     Ok((i, code))
 }
 
 /// Parses text as a hex u64
+/// At most 16 hex digits (leading zeroes included), which is all a `u64`
+/// can take
 fn u64_text_hex(i: Roll) -> IResult<Roll, u64> {
-    // TODO: limit how many digits we read
-    let f = take_while1(nom::character::is_hex_digit);
-    // FIXME: this is inefficient (calling `to_vec` just to parse)
-    let f = map_res(f, |s: Roll| String::from_utf8(s.to_vec()));
-    let mut f = map_res(f, |s| u64::from_str_radix(&s, 16));
-    f(i)
+    let (i, digits) = verify(take_while1(nom::character::is_hex_digit), |s: &Roll| {
+        s.len() <= 16
+    })(i)?;
+    let n = digits[..].iter().fold(0u64, |n, &c| {
+        (n << 4) | (c as char).to_digit(16).unwrap() as u64
+    });
+    Ok((i, n))
 }
 
 pub fn http_version(i: Roll) -> IResult<Roll, Version> {
@@ -144,18 +220,31 @@ pub fn headers_and_crlf(mut i: Roll) -> IResult<Roll, Headers> {
             return Ok((i, headers));
         }
 
+        if headers.len() == MAX_HEADER_RECORDS {
+            return Err(nom::Err::Error(make_error(i, ErrorKind::TooLarge)));
+        }
         let (i_next, (name, value)) = header(i)?;
         headers.append(name, value.into());
         i = i_next;
     }
 }
 
-/// Parse a single header line
+/// Parse a single header line. Values can't have CR, LF or NUL in them,
+/// which also rules out obsolete line folding, cf.
+/// <https://httpwg.org/specs/rfc9112.html#line.folding>: a folded line
+/// doesn't start with a valid field name either. Neither does one with
+/// whitespace before its colon. The server answers request heads that don't
+/// parse with a `400 Bad Request`.
 fn header(i: Roll) -> IResult<Roll, (HeaderName, Roll)> {
     let (i, name) = map_res(take_until_and_consume(b":"), |s: Roll| {
         HeaderName::from_bytes(&s[..])
     })(i)?;
-    let (i, value) = preceded(space1, take_until_and_consume(CRLF))(i)?;
+    let (i, value) = preceded(
+        space1,
+        verify(take_until_and_consume(CRLF), |value: &Roll| {
+            !value.iter().any(|&b| matches!(b, b'\r' | b'\n' | b'\0'))
+        }),
+    )(i)?;
 
     Ok((i, (name, value)))
 }
@@ -182,4 +271,66 @@ mod tests {
         assert!(is_delimiter(b'\\'));
         assert!(!is_delimiter(b'B'));
     }
+
+    #[test]
+    fn test_h1_parse_request_target() {
+        use fluke_buffet::RollMut;
+
+        use crate::h1::parse::path;
+
+        let parse = |input: &str| {
+            let mut buf = RollMut::alloc().unwrap();
+            buf.put(input).unwrap();
+            path(buf.filled()).unwrap().1
+        };
+
+        let uri = parse("/search?q=fluke ");
+        assert_eq!(uri.path(), "/search");
+        assert_eq!(uri.query(), Some("q=fluke"));
+        assert_eq!(uri.authority(), None);
+
+        let uri = parse("http://example.org/a/b ");
+        assert_eq!(uri.scheme(), Some(&http::uri::Scheme::HTTP));
+        assert_eq!(uri.authority(), Some("example.org"));
+        assert_eq!(uri.path_and_query(), "/a/b");
+        assert_eq!(uri.to_uri().unwrap(), "http://example.org/a/b");
+    }
+
+    #[test]
+    fn test_h1_parse_hardening() {
+        use fluke_buffet::RollMut;
+
+        use crate::h1::parse::{chunk_size, complete_head, request, MAX_HEADER_RECORDS};
+
+        let roll = |input: &[u8]| {
+            let mut buf = RollMut::alloc().unwrap();
+            buf.reserve_at_least(input.len()).unwrap();
+            buf.put(input).unwrap();
+            buf.filled()
+        };
+
+        // the head is only parsed once it's all there, however it arrives
+        let head = b"GET / HTTP/1.1\r\nhost: example.org\r\n\r\nbody";
+        let parse = complete_head(request);
+        for len in 0..head.len() - 4 {
+            assert!(matches!(parse(roll(&head[..len])), Err(e) if e.is_incomplete()));
+        }
+        let (rest, req) = parse(roll(head)).unwrap();
+        assert_eq!(&rest[..], b"body");
+        assert_eq!(req.uri.path(), "/");
+
+        let mut many = b"GET / HTTP/1.1\r\n".to_vec();
+        for _ in 0..=MAX_HEADER_RECORDS {
+            many.extend_from_slice(b"a: b\r\n");
+        }
+        many.extend_from_slice(b"\r\n");
+        assert!(request(roll(&many)).is_err());
+
+        assert_eq!(chunk_size(roll(b"00000000000000ff\r\n")).unwrap().1, 255);
+        assert_eq!(
+            chunk_size(roll(b"FFFFFFFFFFFFFFFF\r\n")).unwrap().1,
+            u64::MAX
+        );
+        assert!(chunk_size(roll(b"000000000000000001\r\n")).is_err());
+    }
 }
@@ -1,17 +1,26 @@
-use std::rc::Rc;
+use std::{net::Shutdown, rc::Rc, time::Duration};
 
 use eyre::Context;
+use http::{HeaderName, Version};
 use tracing::debug;
 
 use crate::{
     h1::body::{H1Body, H1BodyKind},
-    util::{read_and_parse, SemanticError},
-    ExpectResponseHeaders, HeadersExt, Responder, ServerDriver,
+    stall::watch_write_stalls,
+    util::{read_and_parse, read_when_idle, SemanticError},
+    write_buf::BufferedWrite,
+    ActiveHandler, Body, CorrelationId, ExpectResponseHeaders, HeadersExt, Load, LoadShedder,
+    Request, RequestIds, RequestLimits, Responder, ServerDriver, TransportSecurity, TrustedProxies,
+    WriteStallPolicy,
 };
 use fluke_buffet::RollMut;
-use fluke_maybe_uring::io::{ReadOwned, WriteOwned};
+use fluke_maybe_uring::io::{ConnInfo, ReadOwned, Transport, WriteOwned};
 
-use super::encode::H1Encoder;
+use super::{
+    encode::H1Encoder,
+    state::{ConnEvent, ConnState, Exchange},
+    ConnectionHandle,
+};
 
 pub struct ServerConf {
     /// Max length of the request line + HTTP headers
@@ -22,6 +31,86 @@ pub struct ServerConf {
 
     /// Max number of header records
     pub max_header_records: usize,
+
+    /// Fields that may normally not be sent as trailers, but that we should
+    /// let through anyway, see [is_forbidden_trailer](crate::is_forbidden_trailer)
+    pub allowed_trailers: Rc<[HeaderName]>,
+
+    /// Max length of a request body announced with `content-length`. Larger
+    /// requests get a 413 Content Too Large response without reaching the
+    /// driver. Can be adjusted per request, see [ServerDriver::request_limits].
+    pub max_request_body_len: u64,
+
+    /// How much of a request body that was left unread when the response
+    /// went out (because of a 413, or a driver responding early) we're willing
+    /// to read and throw away to keep the connection alive. Past that, the
+    /// connection gets closed instead. Can be adjusted per request, see
+    /// [ServerDriver::request_limits].
+    pub max_drain_len: u64,
+
+    /// Max length of the request target, e.g. `/path?query`. Longer ones
+    /// get a 414 URI Too Long response.
+    pub max_uri_len: usize,
+
+    /// Give pool buffers back between requests, rather than holding on to
+    /// them while a keep-alive connection sits idle. This costs a copy of the
+    /// first bytes of each request, but lets lots of idle connections stay
+    /// open without exhausting the pool.
+    pub release_idle_buffers: bool,
+
+    /// Cork the transport while writing responses whose headers and body go
+    /// out in separate writes (see [WriteOwned::set_corked]), so that small
+    /// responses fit in as few packets as possible. This costs a couple
+    /// syscalls per response.
+    pub cork_responses: bool,
+
+    /// Gather response writes in a buffer of that size, which gets written
+    /// out when full, once the handler is done, when an interim response is
+    /// sent, or on the first write after `write_flush_after` has elapsed.
+    /// Since handlers may take their time between writes, this is only
+    /// suitable for drivers that don't stream responses. Zero (the default)
+    /// turns buffering off.
+    pub write_buffer_size: usize,
+
+    /// See `write_buffer_size`
+    pub write_flush_after: Duration,
+
+    /// Whether connections served with this configuration are encrypted,
+    /// reported to the driver via [Request::transport_security](crate::Request::transport_security)
+    pub transport_security: TransportSecurity,
+
+    /// Consulted once each request head is read: requests it sheds get a 503
+    /// Service Unavailable response without reaching the driver.
+    pub load_shedder: Option<Rc<dyn LoadShedder>>,
+
+    /// How long a write to the transport may be pending (typically because
+    /// the peer stopped reading) before `write_stall_policy` is applied. `None`
+    /// (the default) never looks for stalls.
+    pub write_stall_timeout: Option<Duration>,
+
+    /// See `write_stall_timeout`
+    pub write_stall_policy: WriteStallPolicy,
+
+    /// Header carrying a correlation id, typically `x-request-id`. When set,
+    /// each request gets one in [Request::correlation_id](crate::Request::correlation_id):
+    /// the one it came with, or one made from its [Request::id](crate::Request::id),
+    /// and it's echoed in the response.
+    pub request_id_header: Option<HeaderName>,
+
+    /// Peers whose forwarding headers are believed when filling in
+    /// [Request::client_addr](crate::Request::client_addr)
+    pub trusted_proxies: TrustedProxies,
+
+    /// How many requests a connection may carry: the response to the last
+    /// one says `connection: close`, and the connection is closed after it,
+    /// so that clients move on to a fresh one. `None` (the default) doesn't
+    /// limit them.
+    pub max_requests_per_connection: Option<u64>,
+
+    /// How long a connection may wait for a request, between requests or
+    /// before the first one, before it's closed. `None` (the default) keeps
+    /// it open for as long as the client does.
+    pub idle_timeout: Option<Duration>,
 }
 
 impl Default for ServerConf {
@@ -30,6 +119,22 @@ impl Default for ServerConf {
             max_http_header_len: 64 * 1024,
             max_header_record_len: 4 * 1024,
             max_header_records: 128,
+            max_uri_len: 8 * 1024,
+            max_request_body_len: u64::MAX,
+            max_drain_len: 64 * 1024,
+            allowed_trailers: Rc::new([]),
+            release_idle_buffers: true,
+            cork_responses: true,
+            write_buffer_size: 0,
+            write_flush_after: Duration::from_micros(200),
+            transport_security: Default::default(),
+            load_shedder: None,
+            write_stall_timeout: None,
+            write_stall_policy: Default::default(),
+            request_id_header: None,
+            trusted_proxies: Default::default(),
+            max_requests_per_connection: None,
+            idle_timeout: None,
         }
     }
 }
@@ -41,29 +146,271 @@ pub enum ServeOutcome {
     ClientClosedConnectionBetweenRequests,
     // TODO: return buffer there so we can see what they did write?
     ClientDidntSpeakHttp11,
+    /// The client switched to HTTP/2 with `Upgrade: h2c`, and the h2
+    /// connection that followed is over, see [serve_with_h2c]
+    UpgradedToH2c,
+    /// The connection was shut down through its handle, see
+    /// [ConnectionHandle::shutdown]
+    ShutDown,
+    /// No request came in for [ServerConf::idle_timeout]
+    IdleTimeout,
+}
+
+/// How [serve_requests] left the connection
+enum Served<R, W> {
+    Closed(ServeOutcome),
+
+    /// The client asked to switch to h2 and got a `101 Switching Protocols`:
+    /// the rest of the connection, `req` included, is for h2 to serve.
+    #[cfg_attr(not(feature = "h2"), allow(dead_code))]
+    H2c(Box<H2cHandoff<R, W>>),
 }
 
+#[cfg_attr(not(feature = "h2"), allow(dead_code))]
+struct H2cHandoff<R, W> {
+    transport_r: R,
+    transport_w: W,
+    client_buf: RollMut,
+    req: Request,
+    #[cfg(feature = "h2")]
+    peer_settings: crate::h2::parse::Settings,
+}
+
+/// Serve HTTP/1.1 requests on a connection until either side closes it.
+///
+/// Requests on a connection are handled one at a time: the driver's handler
+/// runs inline, on the connection's own task, and is never spawned, so very
+/// cheap handlers don't pay for a spawn/join on every request.
+///
+/// If a handler fails, possibly halfway through a response, the connection
+/// is closed and the error is returned.
 pub async fn serve(
-    (mut transport_r, mut transport_w): (impl ReadOwned, impl WriteOwned),
+    transport: impl Transport,
     conf: Rc<ServerConf>,
-    mut client_buf: RollMut,
+    client_buf: RollMut,
     driver: impl ServerDriver,
 ) -> eyre::Result<ServeOutcome> {
+    serve_with_handle(transport, conf, client_buf, driver, Default::default()).await
+}
+
+/// Like [serve], but the connection can be inspected and shut down through
+/// `handle`, see [ConnectionHandle].
+pub async fn serve_with_handle(
+    transport: impl Transport,
+    conf: Rc<ServerConf>,
+    client_buf: RollMut,
+    driver: impl ServerDriver,
+    handle: ConnectionHandle,
+) -> eyre::Result<ServeOutcome> {
+    let conn_info = Rc::new(ConnInfo::of(&transport));
+    let (transport_r, transport_w) = transport.into_halves();
+    let transport_w =
+        BufferedWrite::new(transport_w, conf.write_buffer_size, conf.write_flush_after);
+    let pending_write = transport_w.pending_write();
+
+    let served = watch_write_stalls(
+        serve_requests(
+            transport_r,
+            transport_w,
+            &conf,
+            conn_info,
+            client_buf,
+            &driver,
+            false,
+            &handle,
+        ),
+        pending_write,
+        conf.write_stall_timeout,
+        conf.write_stall_policy,
+    );
+    let served = tokio::select! {
+        served = served => served?,
+        _ = handle.drain_timed_out() => {
+            debug!("drain timeout elapsed, closing connection mid-request");
+            return Ok(ServeOutcome::ShutDown);
+        }
+    };
+    match served {
+        Served::Closed(outcome) => Ok(outcome),
+        Served::H2c(_) => unreachable!("h2c upgrade while not accepting them"),
+    }
+}
+
+/// Like [serve], but clients may also switch to HTTP/2 with `Upgrade: h2c`
+/// (cf. <https://www.rfc-editor.org/rfc/rfc7540#section-3.2>), in which case
+/// the rest of the connection is served like [h2::serve](crate::h2::serve)
+/// would with `h2_conf`, starting with the response to the request that
+/// asked for the upgrade, on stream 1.
+///
+/// Only requests without a body are upgraded, others are served over
+/// HTTP/1.1 as if they hadn't asked.
+#[cfg(feature = "h2")]
+pub async fn serve_with_h2c(
+    transport: impl Transport,
+    conf: Rc<ServerConf>,
+    h2_conf: Rc<crate::h2::ServerConf>,
+    client_buf: RollMut,
+    driver: Rc<impl ServerDriver + 'static>,
+) -> eyre::Result<ServeOutcome> {
+    let conn_info = Rc::new(ConnInfo::of(&transport));
+    let (transport_r, transport_w) = transport.into_halves();
+    let transport_w =
+        BufferedWrite::new(transport_w, conf.write_buffer_size, conf.write_flush_after);
+    let pending_write = transport_w.pending_write();
+
+    let served = watch_write_stalls(
+        serve_requests(
+            transport_r,
+            transport_w,
+            &conf,
+            conn_info,
+            client_buf,
+            driver.as_ref(),
+            true,
+            &ConnectionHandle::default(),
+        ),
+        pending_write,
+        conf.write_stall_timeout,
+        conf.write_stall_policy,
+    )
+    .await?;
+    match served {
+        Served::Closed(outcome) => Ok(outcome),
+        Served::H2c(handoff) => {
+            let H2cHandoff {
+                transport_r,
+                transport_w,
+                client_buf,
+                req,
+                peer_settings,
+            } = *handoff;
+            crate::h2::serve_upgraded(
+                transport_r,
+                transport_w,
+                h2_conf,
+                client_buf,
+                driver,
+                req,
+                peer_settings,
+            )
+            .await?;
+            Ok(ServeOutcome::UpgradedToH2c)
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn serve_requests<R: ReadOwned, W: WriteOwned>(
+    mut transport_r: R,
+    mut transport_w: BufferedWrite<W>,
+    conf: &ServerConf,
+    conn_info: Rc<ConnInfo>,
+    mut client_buf: RollMut,
+    driver: &impl ServerDriver,
+    accept_h2c: bool,
+    handle: &ConnectionHandle,
+) -> eyre::Result<Served<R, W>> {
+    // chunk-size lines are formatted into this, across all responses. it
+    // only picks up a buffer once there's one to format.
+    let mut out_scratch = RollMut::empty();
+    let mut state = ConnState::Idle;
+    let mut request_ids = RequestIds::new();
+    let mut requests = 0;
+
     loop {
-        let req;
-        (client_buf, req) = match read_and_parse(
-            super::parse::request,
+        handle.set_idle();
+        if handle.is_shutting_down() {
+            debug!("shutting down between requests");
+            return close(state.next(ConnEvent::ShutDown), &mut transport_w)
+                .await
+                .map(Served::Closed);
+        }
+
+        let idle = client_buf.is_empty();
+        let idle_timeout = || async {
+            match conf.idle_timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        if !idle {
+            state = state.next(ConnEvent::BytesBuffered);
+        } else if conf.release_idle_buffers {
+            // nothing of the next request is here yet: hand our buffers back
+            // to the pool while we wait for it. `client_buf` is only
+            // reassigned once the read completes, so it has to go now.
+            out_scratch = RollMut::empty();
+            drop(std::mem::replace(&mut client_buf, RollMut::empty()));
+            let read = tokio::select! {
+                read = read_when_idle(&mut transport_r) => read,
+                _ = handle.shutdown_requested() => {
+                    debug!("shutting down while waiting for a request");
+                    return close(state.next(ConnEvent::ShutDown), &mut transport_w)
+                        .await
+                        .map(Served::Closed);
+                }
+                _ = idle_timeout() => {
+                    debug!(timeout = ?conf.idle_timeout, "connection idle, closing it");
+                    return close(state.next(ConnEvent::IdleTimeout), &mut transport_w)
+                        .await
+                        .map(Served::Closed);
+                }
+            };
+            client_buf = match read {
+                Ok(Some(client_buf)) => {
+                    state = state.next(ConnEvent::BytesBuffered);
+                    client_buf
+                }
+                Ok(None) => {
+                    debug!("client went away before sending request headers");
+                    return close(state.next(ConnEvent::PeerClosed), &mut transport_w)
+                        .await
+                        .map(Served::Closed);
+                }
+                Err(e) => {
+                    debug!(?e, "error reading from idle connection");
+                    return close(state.next(ConnEvent::HeadInvalid), &mut transport_w)
+                        .await
+                        .map(Served::Closed);
+                }
+            };
+        }
+
+        let read_head = read_and_parse(
+            super::parse::complete_head(super::parse::request),
             &mut transport_r,
             client_buf,
             conf.max_http_header_len,
-        )
-        .await
-        {
+        );
+        let read_head = if idle && !conf.release_idle_buffers {
+            tokio::select! {
+                read = read_head => read,
+                _ = handle.shutdown_requested() => {
+                    debug!("shutting down while waiting for a request");
+                    return close(state.next(ConnEvent::ShutDown), &mut transport_w)
+                        .await
+                        .map(Served::Closed);
+                }
+                _ = idle_timeout() => {
+                    debug!(timeout = ?conf.idle_timeout, "connection idle, closing it");
+                    return close(state.next(ConnEvent::IdleTimeout), &mut transport_w)
+                        .await
+                        .map(Served::Closed);
+                }
+            }
+        } else {
+            read_head.await
+        };
+
+        let mut req;
+        (client_buf, req) = match read_head {
             Ok(t) => match t {
                 Some(t) => t,
                 None => {
                     debug!("client went away before sending request headers");
-                    return Ok(ServeOutcome::ClientClosedConnectionBetweenRequests);
+                    return close(state.next(ConnEvent::PeerClosed), &mut transport_w)
+                        .await
+                        .map(Served::Closed);
                 }
             },
             Err(e) => {
@@ -75,45 +422,820 @@ pub async fn serve(
                 }
 
                 debug!(?e, "error reading request header from downstream");
-                return Ok(ServeOutcome::ClientDidntSpeakHttp11);
+                return close(state.next(ConnEvent::HeadInvalid), &mut transport_w)
+                    .await
+                    .map(Served::Closed);
             }
         };
+        handle.record_request();
+        requests += 1;
+        let last_request = conf
+            .max_requests_per_connection
+            .is_some_and(|max| requests >= max);
+        req.transport_security = conf.transport_security;
+        req.conn_info = conn_info.clone();
+        req.id = request_ids.next();
+        req.correlation_id = conf
+            .request_id_header
+            .as_ref()
+            .map(|header| CorrelationId::for_request(header, &req.headers, req.id));
+        req.client_addr = conf
+            .trusted_proxies
+            .client_addr(conn_info.peer_addr.as_ref(), &req.headers);
         debug!("got request {req:?}");
 
-        let chunked = req.headers.is_chunked_transfer_encoding();
-        let connection_close = req.headers.is_connection_close();
-        let content_len = req.headers.content_length().unwrap_or_default();
+        if req.uri.path_and_query().len() > conf.max_uri_len {
+            let se = SemanticError::UriTooLong;
+            transport_w
+                .write_all(se.as_http_response())
+                .await
+                .wrap_err("writing error response downstream")?;
 
-        let mut req_body = H1Body::new(
-            transport_r,
-            client_buf,
-            if chunked {
-                H1BodyKind::Chunked
-            } else {
-                H1BodyKind::ContentLength(content_len)
-            },
-        );
+            debug!(uri_len = %req.uri.path_and_query().len(), "request target too long");
+            return close(state.next(ConnEvent::HeadInvalid), &mut transport_w)
+                .await
+                .map(Served::Closed);
+        }
 
-        let responder = Responder {
-            encoder: H1Encoder { transport_w },
-            state: ExpectResponseHeaders,
+        let body_kind = match H1BodyKind::from_headers(&req.headers) {
+            Ok(body_kind) => body_kind,
+            Err(se) => {
+                transport_w
+                    .write_all(se.as_http_response())
+                    .await
+                    .wrap_err("writing error response downstream")?;
+
+                debug!(headers = ?req.headers, "refusing request: {se}");
+                return close(state.next(ConnEvent::HeadInvalid), &mut transport_w)
+                    .await
+                    .map(Served::Closed);
+            }
         };
+        let (chunked, content_len) = match body_kind {
+            H1BodyKind::Chunked => (true, 0),
+            H1BodyKind::ContentLength(len) => (false, len),
+        };
+        let accepts_trailers = req.headers.accepts_trailers();
+
+        #[cfg(feature = "h2")]
+        if accept_h2c && !chunked && content_len == 0 {
+            if let Some(peer_settings) = h2c_upgrade(&mut req) {
+                debug!("switching to h2c");
+                transport_w
+                    .write_all(
+                        &b"HTTP/1.1 101 Switching Protocols\r\nconnection: Upgrade\r\nupgrade: h2c\r\n\r\n"[..],
+                    )
+                    .await
+                    .wrap_err("writing 101 response downstream")?;
+                transport_w
+                    .flush()
+                    .await
+                    .wrap_err("writing 101 response downstream")?;
+                return Ok(Served::H2c(Box::new(H2cHandoff {
+                    transport_r,
+                    transport_w: transport_w.into_inner(),
+                    client_buf,
+                    req,
+                    peer_settings,
+                })));
+            }
+        }
+        #[cfg(not(feature = "h2"))]
+        let _ = accept_h2c;
+        // HTTP/1.0 connections close after each response, unless the
+        // client asked to keep them alive
+        let request_version = req.version;
+        let keep_alive_requested = req.headers.is_connection_keep_alive();
+        let exchange = Exchange {
+            connection_close: req.headers.is_connection_close()
+                || (request_version == Version::HTTP_10 && !keep_alive_requested),
+            expects_100_continue: req.headers.expects_100_continue(),
+            has_body: chunked || content_len > 0,
+        };
+        state = state.next(ConnEvent::HeadRead(exchange));
+
+        let mut limits = RequestLimits {
+            max_request_body_len: conf.max_request_body_len,
+            max_drain_len: conf.max_drain_len,
+            ..Default::default()
+        };
+        driver.request_limits(&req, &mut limits);
+
+        let mut req_body = H1Body::new(transport_r, client_buf, body_kind);
+
+        let shed = conf
+            .load_shedder
+            .as_ref()
+            .and_then(|ls| ls.shed(&Load::current()));
+        let too_large = !chunked && content_len > limits.max_request_body_len;
 
-        let resp = driver
-            .handle(req, &mut req_body, responder)
+        let close_after_response;
+        if shed.is_some() || too_large {
+            // a client waiting for `100 Continue` won't send the body, and one
+            // that's sending a huge body isn't worth reading it from.
+            close_after_response = last_request
+                || exchange.connection_close
+                || exchange.expects_100_continue
+                || content_len > limits.max_drain_len;
+
+            let mut res = match shed {
+                Some(retry_after) => {
+                    debug!(?retry_after, %close_after_response, "overloaded, shedding request");
+                    let secs = retry_after.as_secs() + (retry_after.subsec_nanos() > 0) as u64;
+                    format!("HTTP/1.1 503 Service Unavailable\r\nretry-after: {secs}\r\n")
+                }
+                None => {
+                    debug!(%content_len, %close_after_response, "request body too large, responding early");
+                    "HTTP/1.1 413 Content Too Large\r\n".to_string()
+                }
+            };
+            if let Some(correlation_id) = &req.correlation_id {
+                res.push_str(&format!(
+                    "{}: {}\r\n",
+                    correlation_id.header, correlation_id.value
+                ));
+            }
+            res.push_str("content-length: 0\r\n");
+            if close_after_response {
+                res.push_str("connection: close\r\n");
+            } else if request_version == Version::HTTP_10 {
+                res.push_str("connection: keep-alive\r\n");
+            }
+            res.push_str("\r\n");
+            transport_w
+                .write_all(res.into_bytes())
+                .await
+                .wrap_err("writing error response downstream")?;
+        } else {
+            let mut encoder = H1Encoder::new(
+                transport_w,
+                accepts_trailers,
+                conf.allowed_trailers.clone(),
+                out_scratch,
+                conf.cork_responses,
+            );
+            encoder.correlation_id = req.correlation_id.clone();
+            encoder.conn_handle = handle.clone();
+            encoder.last_request = last_request;
+            encoder.request_version = request_version;
+            encoder.keep_alive_requested = keep_alive_requested;
+            let responder = Responder {
+                encoder,
+                state: ExpectResponseHeaders,
+            };
+
+            let _active = ActiveHandler::enter();
+            let resp = match driver.handle(req, &mut req_body, responder).await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    // the transport went away along with the responder, and
+                    // there's no telling how much of the response made it
+                    // out: the connection can't be reused.
+                    debug!(
+                        ?e,
+                        "handler failed, response incomplete, closing connection"
+                    );
+                    return Err(e.wrap_err("handling request"));
+                }
+            };
+
+            let encoder = resp.into_inner();
+            close_after_response = encoder.close_after_response;
+            transport_w = encoder.transport_w;
+            out_scratch = encoder.out_scratch;
+        }
+
+        if matches!(state, ConnState::ReadingBody(_)) && req_body.eof() {
+            state = state.next(ConnEvent::BodyRead);
+        }
+        state = state.next(ConnEvent::ResponseDone {
+            close_after_response,
+        });
+        transport_w
+            .flush()
             .await
-            .wrap_err("handling request")?;
+            .wrap_err("writing response downstream")?;
 
-        // TODO: if we sent `connection: close` we should close now
-        transport_w = resp.into_inner().transport_w;
+        if let ConnState::Draining(_) = state {
+            // the response went out without the request body being read in
+            // full: to keep the connection alive, the rest of it needs to be
+            // skipped.
+            let complete = req_body.drain(limits.max_drain_len).await?;
+            state = state.next(ConnEvent::Drained { complete });
+        }
+
+        if let ConnState::Closing(_) = state {
+            return close(state, &mut transport_w).await.map(Served::Closed);
+        }
 
         (client_buf, transport_r) = req_body
             .into_inner()
             .ok_or_else(|| eyre::eyre!("request body not drained, have to close connection"))?;
+    }
+}
+
+/// Wraps up a connection that reached [ConnState::Closing]
+async fn close(state: ConnState, transport_w: &mut impl WriteOwned) -> eyre::Result<ServeOutcome> {
+    let ConnState::Closing(outcome) = state else {
+        unreachable!("closing h1 connection in {state:?}")
+    };
+
+    transport_w
+        .flush()
+        .await
+        .wrap_err("writing response downstream")?;
+
+    if matches!(
+        outcome,
+        ServeOutcome::ServerRequestedConnectionClose
+            | ServeOutcome::ShutDown
+            | ServeOutcome::IdleTimeout
+    ) {
+        debug!("we're closing the connection");
+        transport_w
+            .shutdown(Shutdown::Write)
+            .await
+            .wrap_err("shutting down connection after response")?;
+    }
+    Ok(outcome)
+}
+
+/// The `HTTP2-Settings` request header, cf. <https://www.rfc-editor.org/rfc/rfc7540#section-3.2.1>
+#[cfg(feature = "h2")]
+const HTTP2_SETTINGS: HeaderName = HeaderName::from_static("http2-settings");
+
+/// If `req` asks to switch to h2 (an `h2c` token in `upgrade`, listed in
+/// `connection` along with `HTTP2-Settings`, and exactly one valid
+/// `HTTP2-Settings` header), strips those hop-by-hop headers and returns
+/// the client's settings.
+#[cfg(feature = "h2")]
+fn h2c_upgrade(req: &mut Request) -> Option<crate::h2::parse::Settings> {
+    let has_token = |name: HeaderName, token: &[u8]| {
+        req.headers.get_all(name).iter().any(|value| {
+            value
+                .split(|&b| b == b',')
+                .any(|t| crate::trim_ows(t).eq_ignore_ascii_case(token))
+        })
+    };
+    if !has_token(http::header::UPGRADE, b"h2c")
+        || !has_token(http::header::CONNECTION, b"upgrade")
+        || !has_token(http::header::CONNECTION, b"http2-settings")
+    {
+        return None;
+    }
+
+    let mut values = req.headers.get_all(HTTP2_SETTINGS).iter();
+    let (Some(value), None) = (values.next(), values.next()) else {
+        return None;
+    };
+    let settings = crate::h2::parse::Settings::from_http2_settings_header(value)?;
+
+    req.headers.remove(http::header::UPGRADE);
+    req.headers.remove(http::header::CONNECTION);
+    req.headers.remove(HTTP2_SETTINGS);
+    Some(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, time::Duration};
+
+    use fluke_buffet::RollMut;
+    use fluke_maybe_uring::io::{ChanRead, ChanWrite};
+    use http::{header, StatusCode};
+
+    use super::{serve, ServeOutcome, ServerConf};
+    use crate::{
+        h1::testing::{smuggling_corpus, Record},
+        Body, BodyChunk, Encoder, ExpectResponseHeaders, Headers, Load, LoadShedder, Method,
+        Request, Responder, Response, ResponseDone, ServerDriver,
+    };
+
+    /// Serves `input` as sent by a client, returns what the driver saw and
+    /// what was written back
+    async fn serve_raw(input: Vec<u8>) -> (Vec<(String, Option<Vec<u8>>)>, Vec<u8>) {
+        let driver = Rc::new(Record::default());
+        let out = serve_with(input, driver.clone()).await;
+        (driver.seen.take(), out)
+    }
+
+    /// Serves `input` as sent by a client with `driver`, returns what was
+    /// written back
+    async fn serve_with(input: Vec<u8>, driver: impl ServerDriver) -> Vec<u8> {
+        serve_with_conf(input, driver, ServerConf::default()).await
+    }
+
+    /// Like [serve_with], with `conf`
+    async fn serve_with_conf(
+        input: Vec<u8>,
+        driver: impl ServerDriver,
+        conf: ServerConf,
+    ) -> Vec<u8> {
+        let (tx, read) = ChanRead::new();
+        let (mut rx, write) = ChanWrite::new();
+        let send = crate::maybe_uring::spawn(async move { tx.send(input).await.unwrap() });
+        let collect = crate::maybe_uring::spawn(async move {
+            let mut out = vec![];
+            while let Some(bytes) = rx.recv().await {
+                out.extend(bytes);
+            }
+            out
+        });
+
+        _ = serve(
+            (read, write),
+            Rc::new(conf),
+            RollMut::alloc().unwrap(),
+            driver,
+        )
+        .await;
+        send.await.unwrap();
+        collect.await.unwrap()
+    }
+
+    #[test]
+    fn test_h1_smuggling_corpus() {
+        crate::maybe_uring::start(async move {
+            for (name, head, rest, expected_seen, expected_res) in smuggling_corpus() {
+                let input = format!("{head}\r\n\r\n{rest}").into_bytes();
+                let (seen, out) = serve_raw(input).await;
+
+                let expected_seen: Vec<(String, Option<Vec<u8>>)> = expected_seen
+                    .iter()
+                    .map(|(path, body)| (path.to_string(), body.map(|b| b.to_vec())))
+                    .collect();
+                assert_eq!(seen, expected_seen, "{name}");
+
+                let out = String::from_utf8_lossy(&out);
+                match expected_res {
+                    Some(status_line) => assert!(out.starts_with(status_line), "{name}: {out:?}"),
+                    None => assert!(out.is_empty(), "{name}: {out:?}"),
+                }
+            }
+        });
+    }
+
+    /// Responds to each path with something representative, see
+    /// `test_h1_golden_responses`
+    struct Golden;
+
+    impl ServerDriver for Golden {
+        async fn handle<E: Encoder>(
+            &self,
+            req: Request,
+            req_body: &mut impl Body,
+            mut respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            if req.uri.path() == "/continue" {
+                let res = Response {
+                    status: StatusCode::CONTINUE,
+                    ..Default::default()
+                };
+                respond.write_interim_response(res).await?;
+            }
+            while let BodyChunk::Chunk(_) = req_body.next_chunk().await? {}
+
+            let mut res = Response {
+                version: req.version,
+                ..Default::default()
+            };
+            let text_plain = |res: &mut Response| {
+                res.headers
+                    .insert(header::CONTENT_TYPE, "text/plain".into());
+            };
+            match req.uri.path() {
+                "/content-length" => {
+                    text_plain(&mut res);
+                    res.headers.insert(header::CONTENT_LENGTH, "5".into());
+                    let respond = respond.write_final_response(res).await?;
+                    if req.method == Method::Head {
+                        respond.finish_body(None).await
+                    } else {
+                        respond.write_last_chunk("hello".into()).await
+                    }
+                }
+                "/chunked" | "/close-delimited" => {
+                    text_plain(&mut res);
+                    let mut respond = respond.write_final_response(res).await?;
+                    respond.write_chunk("hello".into()).await?;
+                    respond.write_chunk(", world".into()).await?;
+                    respond.finish_body(None).await
+                }
+                "/trailers" => {
+                    let mut respond = respond.write_final_response(res).await?;
+                    respond.write_chunk("hello".into()).await?;
+                    let mut trailers = Headers::default();
+                    trailers.insert("x-checksum", "abc123".into());
+                    respond.finish_body(Some(Box::new(trailers))).await
+                }
+                "/no-content" => {
+                    res.status = StatusCode::NO_CONTENT;
+                    respond
+                        .write_final_response(res)
+                        .await?
+                        .finish_body(None)
+                        .await
+                }
+                "/not-modified" => {
+                    res.status = StatusCode::NOT_MODIFIED;
+                    res.headers.insert(header::ETAG, "\"v1\"".into());
+                    respond
+                        .write_final_response(res)
+                        .await?
+                        .finish_body(None)
+                        .await
+                }
+                "/continue" => respond.write_final_response_with_body(res, &mut ()).await,
+                _ => {
+                    res.status = StatusCode::NOT_FOUND;
+                    respond.write_final_response_with_body(res, &mut ()).await
+                }
+            }
+        }
+    }
 
-        if connection_close {
-            debug!("client requested connection close");
-            return Ok(ServeOutcome::ClientRequestedConnectionClose);
+    /// Checks `out` against `testdata/golden/h1/{name}.http`, or writes it
+    /// there when `FLUKE_BLESS` is set
+    fn check_golden(name: &str, out: &[u8]) {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/golden/h1")
+            .join(format!("{name}.http"));
+        if std::env::var_os("FLUKE_BLESS").is_some() {
+            std::fs::write(&path, out).unwrap();
+            return;
         }
+        let golden = std::fs::read(&path).unwrap_or_else(|e| {
+            panic!(
+                "reading {}: {e} (run with FLUKE_BLESS=1 to create it)",
+                path.display()
+            )
+        });
+        assert_eq!(
+            String::from_utf8_lossy(out),
+            String::from_utf8_lossy(&golden),
+            "{name}: wire output changed, run with FLUKE_BLESS=1 if that's on purpose"
+        );
+    }
+
+    /// The exact bytes written for representative responses, so that
+    /// changes to how they're encoded (or to how writes are coalesced)
+    /// can't go unnoticed
+    #[test]
+    fn test_h1_golden_responses() {
+        let cases = [
+            (
+                "content-length",
+                "GET /content-length HTTP/1.1\r\nhost: example.org\r\n\r\n",
+            ),
+            ("chunked", "GET /chunked HTTP/1.1\r\n\r\n"),
+            ("trailers", "GET /trailers HTTP/1.1\r\nte: trailers\r\n\r\n"),
+            ("not-found", "GET /nope HTTP/1.1\r\n\r\n"),
+            ("no-content", "DELETE /no-content HTTP/1.1\r\n\r\n"),
+            ("not-modified", "GET /not-modified HTTP/1.1\r\n\r\n"),
+            ("head", "HEAD /content-length HTTP/1.1\r\n\r\n"),
+            (
+                "continue",
+                "POST /continue HTTP/1.1\r\nexpect: 100-continue\r\ncontent-length: 3\r\n\r\nabc",
+            ),
+            ("http10", "GET /close-delimited HTTP/1.0\r\n\r\n"),
+            (
+                "pipelined",
+                "GET /content-length HTTP/1.1\r\n\r\nGET /chunked HTTP/1.1\r\n\r\n",
+            ),
+        ];
+
+        crate::maybe_uring::start(async move {
+            for (name, input) in cases {
+                let out = serve_with(input.as_bytes().to_vec(), Golden).await;
+                check_golden(name, &out);
+            }
+        });
+    }
+
+    #[test]
+    fn test_h1_keep_alive_controls() {
+        crate::maybe_uring::start(async move {
+            let driver = Rc::new(Record::default());
+            let conf = ServerConf {
+                max_requests_per_connection: Some(2),
+                ..Default::default()
+            };
+            let input = "GET /1 HTTP/1.1\r\n\r\nGET /2 HTTP/1.1\r\n\r\nGET /3 HTTP/1.1\r\n\r\n";
+            let out = serve_with_conf(input.into(), driver.clone(), conf).await;
+            let out = String::from_utf8(out).unwrap();
+            let seen: Vec<_> = driver
+                .seen
+                .take()
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect();
+            assert_eq!(seen, ["/1", "/2"]);
+            let (first, second) = out.split_at(out.rfind("HTTP/1.1 200").unwrap());
+            assert!(!first.contains("connection: close"), "{out}");
+            assert!(second.contains("connection: close\r\n"), "{out}");
+
+            // the client keeps the connection open, without sending anything
+            let (_tx, read) = ChanRead::new();
+            let (_rx, write) = ChanWrite::new();
+            let conf = ServerConf {
+                idle_timeout: Some(Duration::from_millis(50)),
+                ..Default::default()
+            };
+            let outcome = serve(
+                (read, write),
+                Rc::new(conf),
+                RollMut::alloc().unwrap(),
+                Rc::new(Record::default()),
+            )
+            .await
+            .unwrap();
+            assert_eq!(outcome, ServeOutcome::IdleTimeout);
+        });
+    }
+
+    #[test]
+    fn test_h1_load_shedding() {
+        /// Sheds every request
+        struct ShedAll;
+
+        impl LoadShedder for ShedAll {
+            fn shed(&self, _load: &Load) -> Option<Duration> {
+                Some(Duration::from_millis(1500))
+            }
+        }
+
+        let big_body = "a".repeat(100);
+        // what's sent, and whether the connection should close after the
+        // first response rather than go on to the second request
+        let cases = [
+            ("no body", String::new(), false),
+            (
+                "drained body",
+                "content-length: 5\r\n\r\nhello".into(),
+                false,
+            ),
+            (
+                "body over max_drain_len",
+                format!("content-length: 100\r\n\r\n{big_body}"),
+                true,
+            ),
+            (
+                "expects 100-continue",
+                "content-length: 5\r\nexpect: 100-continue\r\n\r\nhello".into(),
+                true,
+            ),
+            (
+                "connection: close",
+                "connection: close\r\n\r\n".into(),
+                true,
+            ),
+        ];
+
+        crate::maybe_uring::start(async move {
+            for (name, rest, close) in cases {
+                let rest = if rest.is_empty() { "\r\n".into() } else { rest };
+                let input = format!("POST /1 HTTP/1.1\r\n{rest}GET /2 HTTP/1.1\r\n\r\n");
+                let driver = Rc::new(Record::default());
+                let conf = ServerConf {
+                    max_drain_len: 10,
+                    load_shedder: Some(Rc::new(ShedAll)),
+                    ..Default::default()
+                };
+                let out = serve_with_conf(input.into_bytes(), driver.clone(), conf).await;
+                let out = String::from_utf8(out).unwrap();
+
+                assert!(driver.seen.take().is_empty(), "{name}");
+                let responses: Vec<_> = out.split("HTTP/1.1 ").skip(1).collect();
+                assert_eq!(responses.len(), if close { 1 } else { 2 }, "{name}: {out}");
+                for res in &responses {
+                    assert!(
+                        res.starts_with("503 Service Unavailable\r\n"),
+                        "{name}: {out}"
+                    );
+                    // rounded up to the second
+                    assert!(res.contains("\r\nretry-after: 2\r\n"), "{name}: {out}");
+                    assert!(res.contains("\r\ncontent-length: 0\r\n"), "{name}: {out}");
+                }
+                assert_eq!(
+                    responses[0].contains("\r\nconnection: close\r\n"),
+                    close,
+                    "{name}: {out}"
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_h1_idle_connection_releases_buffers() {
+        crate::maybe_uring::start(async move {
+            let before = fluke_buffet::num_free_bufs();
+            let (tx, read) = ChanRead::new();
+            let (mut rx, write) = ChanWrite::new();
+            let served = crate::maybe_uring::spawn(serve(
+                (read, write),
+                Rc::new(ServerConf::default()),
+                RollMut::alloc().unwrap(),
+                Rc::new(Record::default()),
+            ));
+
+            tx.send("GET / HTTP/1.1\r\n\r\n").await.unwrap();
+            let mut out = vec![];
+            while !out.ends_with(b"\r\n\r\n") {
+                out.extend(rx.recv().await.unwrap());
+            }
+            assert!(out.starts_with(b"HTTP/1.1 200"));
+
+            // the client keeps the connection open, without sending anything
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert_eq!(fluke_buffet::num_free_bufs(), before);
+
+            drop(tx);
+            assert_eq!(
+                served.await.unwrap().unwrap(),
+                ServeOutcome::ClientClosedConnectionBetweenRequests
+            );
+        });
+    }
+
+    #[test]
+    fn test_h1_http10_keep_alive() {
+        /// Streams a body of unknown length, leaving the response version
+        /// for the encoder to figure out
+        struct Stream;
+
+        impl ServerDriver for Stream {
+            async fn handle<E: Encoder>(
+                &self,
+                _req: Request,
+                _req_body: &mut impl Body,
+                mut respond: Responder<E, ExpectResponseHeaders>,
+            ) -> eyre::Result<Responder<E, ResponseDone>> {
+                let res = Response {
+                    status: StatusCode::CONTINUE,
+                    ..Default::default()
+                };
+                respond.write_interim_response(res).await?;
+                let mut respond = respond.write_final_response(Default::default()).await?;
+                respond.write_chunk("hello".into()).await?;
+                respond.finish_body(None).await
+            }
+        }
+
+        crate::maybe_uring::start(async move {
+            let driver = Rc::new(Record::default());
+            let input = "GET /1 HTTP/1.0\r\nconnection: keep-alive\r\n\r\nGET /2 HTTP/1.0\r\n\r\nGET /3 HTTP/1.0\r\n\r\n";
+            let out = serve_with(input.into(), driver.clone()).await;
+            let out = String::from_utf8(out).unwrap();
+            let seen: Vec<_> = driver
+                .seen
+                .take()
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect();
+            assert_eq!(seen, ["/1", "/2"]);
+            let (first, second) = out.split_at(out.rfind("HTTP/1.1 200").unwrap());
+            assert!(first.contains("connection: keep-alive\r\n"), "{out}");
+            assert!(second.contains("connection: close\r\n"), "{out}");
+
+            // no interim responses and no chunked bodies: asking for
+            // keep-alive doesn't help when the body ends with the connection
+            let input = "GET / HTTP/1.0\r\nconnection: keep-alive\r\n\r\n";
+            let out = serve_with(input.into(), Stream).await;
+            let out = String::from_utf8(out).unwrap();
+            assert!(out.starts_with("HTTP/1.1 200 OK\r\n"), "{out}");
+            assert!(out.contains("connection: close\r\n"), "{out}");
+            assert!(!out.contains("transfer-encoding"), "{out}");
+            assert!(out.ends_with("\r\n\r\nhello"), "{out}");
+        });
+    }
+
+    /// The head of a request that asks to switch to h2, with `extra` header
+    /// lines, and an `HTTP2-Settings` header per value in `settings`
+    #[cfg(feature = "h2")]
+    fn h2c_request(extra: &str, settings: &[&str]) -> String {
+        let mut head = format!(
+            "GET / HTTP/1.1\r\nhost: example.org\r\nconnection: Upgrade, HTTP2-Settings\r\nupgrade: h2c\r\n{extra}"
+        );
+        for value in settings {
+            head.push_str(&format!("http2-settings: {value}\r\n"));
+        }
+        head + "\r\n"
+    }
+
+    #[cfg(feature = "h2")]
+    #[test]
+    fn test_h1_h2c_upgrade() {
+        const SWITCHING: &str =
+            "HTTP/1.1 101 Switching Protocols\r\nconnection: Upgrade\r\nupgrade: h2c\r\n\r\n";
+
+        crate::maybe_uring::start(async move {
+            let (tx, read) = ChanRead::new();
+            let (mut rx, write) = ChanWrite::new();
+            let driver = Rc::new(Record::default());
+            let served = crate::maybe_uring::spawn(super::serve_with_h2c(
+                (read, write),
+                Default::default(),
+                Default::default(),
+                RollMut::alloc().unwrap(),
+                driver.clone(),
+            ));
+
+            // the client sends its preface right after the request, it
+            // doesn't have to wait for the 101
+            let mut input = h2c_request("", &["AAMAAABkAAQAAP__"]).into_bytes();
+            input.extend(crate::h2::parse::PREFACE);
+            input.extend([0, 0, 0, 0x4, 0, 0, 0, 0, 0]);
+            tx.send(input).await.unwrap();
+
+            let mut out = vec![];
+            while out.len() < SWITCHING.len() {
+                out.extend(rx.recv().await.unwrap());
+            }
+            assert_eq!(String::from_utf8_lossy(&out[..SWITCHING.len()]), SWITCHING);
+            out.drain(..SWITCHING.len());
+
+            // then comes h2, which answers the request on stream 1
+            let mut dec = fluke_hpack::Decoder::new();
+            let headers = loop {
+                if out.len() >= 9 {
+                    let len = u32::from_be_bytes([0, out[0], out[1], out[2]]) as usize;
+                    if out.len() >= 9 + len {
+                        let frame: Vec<u8> = out.drain(..9 + len).collect();
+                        let stream_id = u32::from_be_bytes(frame[5..9].try_into().unwrap());
+                        if frame[3] == 0x1 {
+                            assert_eq!(stream_id, 1);
+                            break dec.decode(&frame[9..]).unwrap();
+                        }
+                        continue;
+                    }
+                }
+                out.extend(rx.recv().await.expect("server hung up"));
+            };
+            assert_eq!(headers[0], (b":status".to_vec(), b"200".to_vec()));
+            assert_eq!(driver.seen.take(), [("/".to_owned(), Some(vec![]))]);
+
+            drop(tx);
+            while rx.recv().await.is_some() {}
+            assert_eq!(served.await.unwrap().unwrap(), ServeOutcome::UpgradedToH2c);
+        });
+    }
+
+    #[cfg(feature = "h2")]
+    #[test]
+    fn test_h1_h2c_upgrade_declined() {
+        let cases = [
+            ("bad settings", h2c_request("", &["AAMAAABk*AAQAAP__"])),
+            (
+                "duplicated settings",
+                h2c_request("", &["AAMAAABk", "AAQAAP__"]),
+            ),
+            (
+                "request body",
+                h2c_request("content-length: 3\r\n", &["AAMAAABkAAQAAP__"]) + "abc",
+            ),
+        ];
+
+        crate::maybe_uring::start(async move {
+            for (name, input) in cases {
+                let driver = Rc::new(Record::default());
+                let (tx, read) = ChanRead::new();
+                let (mut rx, write) = ChanWrite::new();
+                let send =
+                    crate::maybe_uring::spawn(
+                        async move { tx.send(input.into_bytes()).await.unwrap() },
+                    );
+                let collect = crate::maybe_uring::spawn(async move {
+                    let mut out = vec![];
+                    while let Some(bytes) = rx.recv().await {
+                        out.extend(bytes);
+                    }
+                    out
+                });
+                let outcome = super::serve_with_h2c(
+                    (read, write),
+                    Default::default(),
+                    Default::default(),
+                    RollMut::alloc().unwrap(),
+                    driver.clone(),
+                )
+                .await
+                .unwrap();
+                send.await.unwrap();
+                let out = String::from_utf8(collect.await.unwrap()).unwrap();
+
+                assert_ne!(outcome, ServeOutcome::UpgradedToH2c, "{name}");
+                assert!(out.starts_with("HTTP/1.1 200 OK\r\n"), "{name}: {out}");
+                let body = if name == "request body" {
+                    &b"abc"[..]
+                } else {
+                    b""
+                };
+                assert_eq!(
+                    driver.seen.take(),
+                    [("/".to_owned(), Some(body.to_vec()))],
+                    "{name}"
+                );
+            }
+        });
     }
 }
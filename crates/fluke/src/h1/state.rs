@@ -0,0 +1,259 @@
+//! The life of an HTTP/1.1 server connection, as a state machine that
+//! [serve](super::serve) walks through. Keeping transitions here, away from
+//! any I/O, lets them be tested one by one.
+
+use super::ServeOutcome;
+
+/// What we learned from a request head that matters past the driver call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Exchange {
+    /// The client sent `connection: close`, or is an HTTP/1.0 client that
+    /// didn't send `connection: keep-alive`
+    pub(crate) connection_close: bool,
+
+    /// The client sent `expect: 100-continue`, and may not send the body
+    /// unless told to
+    pub(crate) expects_100_continue: bool,
+
+    /// The request has a body (chunked, or with a non-zero content-length)
+    pub(crate) has_body: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConnState {
+    /// Between requests, nothing of the next request is buffered
+    Idle,
+
+    /// Part of the next request head is buffered
+    ReadingHead,
+
+    /// The request is being handled, its body hasn't been read in full
+    ReadingBody(Exchange),
+
+    /// The request is being handled, its body has been read in full (or it
+    /// didn't have one)
+    Responding(Exchange),
+
+    /// The response went out before the request body was read in full, the
+    /// rest of it is being skipped so the connection can be reused
+    Draining(Exchange),
+
+    /// The connection is done
+    Closing(ServeOutcome),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConnEvent {
+    /// Bytes of the next request are buffered (e.g. pipelined requests)
+    BytesBuffered,
+
+    /// The client closed its side before sending a full request head
+    PeerClosed,
+
+    /// The client sent something that isn't an acceptable request head
+    HeadInvalid,
+
+    /// A request head was read
+    HeadRead(Exchange),
+
+    /// The request body was read until the end
+    BodyRead,
+
+    /// The response was written in full
+    ResponseDone {
+        /// We sent `connection: close` (or the response is delimited by
+        /// closing the connection)
+        close_after_response: bool,
+    },
+
+    /// Skipping the rest of the request body is over
+    Drained {
+        /// Whether all of it was skipped, rather than us giving up
+        complete: bool,
+    },
+
+    /// The connection was shut down through its handle, see
+    /// [ConnectionHandle::shutdown](super::ConnectionHandle::shutdown)
+    ShutDown,
+
+    /// No request came in for [ServerConf::idle_timeout](super::ServerConf::idle_timeout)
+    IdleTimeout,
+}
+
+impl ConnState {
+    /// Returns the state after `event`. Panics on events that make no sense
+    /// in the current state, since those are bugs in `serve`.
+    pub(crate) fn next(self, event: ConnEvent) -> ConnState {
+        use ConnEvent as E;
+        use ConnState as S;
+
+        match (self, event) {
+            (S::Idle, E::BytesBuffered) => S::ReadingHead,
+            (S::Idle | S::ReadingHead, E::PeerClosed) => {
+                S::Closing(ServeOutcome::ClientClosedConnectionBetweenRequests)
+            }
+            (S::Idle | S::ReadingHead, E::HeadInvalid) => {
+                S::Closing(ServeOutcome::ClientDidntSpeakHttp11)
+            }
+            (S::Idle, E::ShutDown) => S::Closing(ServeOutcome::ShutDown),
+            (S::Idle, E::IdleTimeout) => S::Closing(ServeOutcome::IdleTimeout),
+            (S::Idle | S::ReadingHead, E::HeadRead(ex)) => {
+                if ex.has_body {
+                    S::ReadingBody(ex)
+                } else {
+                    S::Responding(ex)
+                }
+            }
+            (S::ReadingBody(ex), E::BodyRead) => S::Responding(ex),
+            (
+                S::ReadingBody(_) | S::Responding(_),
+                E::ResponseDone {
+                    close_after_response: true,
+                },
+            ) => S::Closing(ServeOutcome::ServerRequestedConnectionClose),
+            (S::ReadingBody(ex), E::ResponseDone { .. }) => {
+                if ex.expects_100_continue {
+                    // the client may be waiting for us to ask for the body,
+                    // and there's no telling what comes next on the wire.
+                    S::Closing(ServeOutcome::ServerRequestedConnectionClose)
+                } else {
+                    S::Draining(ex)
+                }
+            }
+            (S::Responding(ex), E::ResponseDone { .. })
+            | (S::Draining(ex), E::Drained { complete: true }) => {
+                if ex.connection_close {
+                    S::Closing(ServeOutcome::ClientRequestedConnectionClose)
+                } else {
+                    S::Idle
+                }
+            }
+            (S::Draining(_), E::Drained { complete: false }) => {
+                S::Closing(ServeOutcome::ServerRequestedConnectionClose)
+            }
+            (state, event) => unreachable!("h1 connection got {event:?} while in {state:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnEvent as E, ConnState as S, Exchange};
+    use crate::h1::ServeOutcome;
+
+    const PLAIN: Exchange = Exchange {
+        connection_close: false,
+        expects_100_continue: false,
+        has_body: false,
+    };
+
+    const WITH_BODY: Exchange = Exchange {
+        has_body: true,
+        ..PLAIN
+    };
+
+    #[test]
+    fn test_h1_state_reading_head() {
+        assert_eq!(S::Idle.next(E::BytesBuffered), S::ReadingHead);
+        assert_eq!(
+            S::Idle.next(E::ShutDown),
+            S::Closing(ServeOutcome::ShutDown)
+        );
+        assert_eq!(
+            S::Idle.next(E::IdleTimeout),
+            S::Closing(ServeOutcome::IdleTimeout)
+        );
+        for state in [S::Idle, S::ReadingHead] {
+            assert_eq!(
+                state.next(E::PeerClosed),
+                S::Closing(ServeOutcome::ClientClosedConnectionBetweenRequests)
+            );
+            assert_eq!(
+                state.next(E::HeadInvalid),
+                S::Closing(ServeOutcome::ClientDidntSpeakHttp11)
+            );
+            assert_eq!(state.next(E::HeadRead(PLAIN)), S::Responding(PLAIN));
+            assert_eq!(
+                state.next(E::HeadRead(WITH_BODY)),
+                S::ReadingBody(WITH_BODY)
+            );
+        }
+    }
+
+    #[test]
+    fn test_h1_state_reading_body() {
+        let state = S::ReadingBody(WITH_BODY);
+        assert_eq!(state.next(E::BodyRead), S::Responding(WITH_BODY));
+
+        // early response: whatever's left of the body is skipped
+        let done = E::ResponseDone {
+            close_after_response: false,
+        };
+        assert_eq!(state.next(done), S::Draining(WITH_BODY));
+
+        let continue_ex = Exchange {
+            expects_100_continue: true,
+            ..WITH_BODY
+        };
+        assert_eq!(
+            S::ReadingBody(continue_ex).next(done),
+            S::Closing(ServeOutcome::ServerRequestedConnectionClose)
+        );
+
+        assert_eq!(
+            state.next(E::ResponseDone {
+                close_after_response: true
+            }),
+            S::Closing(ServeOutcome::ServerRequestedConnectionClose)
+        );
+    }
+
+    #[test]
+    fn test_h1_state_responding() {
+        let done = E::ResponseDone {
+            close_after_response: false,
+        };
+        assert_eq!(S::Responding(PLAIN).next(done), S::Idle);
+
+        let close_ex = Exchange {
+            connection_close: true,
+            ..PLAIN
+        };
+        assert_eq!(
+            S::Responding(close_ex).next(done),
+            S::Closing(ServeOutcome::ClientRequestedConnectionClose)
+        );
+
+        assert_eq!(
+            S::Responding(PLAIN).next(E::ResponseDone {
+                close_after_response: true
+            }),
+            S::Closing(ServeOutcome::ServerRequestedConnectionClose)
+        );
+    }
+
+    #[test]
+    fn test_h1_state_draining() {
+        let state = S::Draining(WITH_BODY);
+        assert_eq!(state.next(E::Drained { complete: true }), S::Idle);
+        assert_eq!(
+            state.next(E::Drained { complete: false }),
+            S::Closing(ServeOutcome::ServerRequestedConnectionClose)
+        );
+
+        let close_ex = Exchange {
+            connection_close: true,
+            ..WITH_BODY
+        };
+        assert_eq!(
+            S::Draining(close_ex).next(E::Drained { complete: true }),
+            S::Closing(ServeOutcome::ClientRequestedConnectionClose)
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_h1_state_rejects_nonsense() {
+        S::Idle.next(E::BodyRead);
+    }
+}
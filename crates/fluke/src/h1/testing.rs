@@ -0,0 +1,203 @@
+//! Helpers shared by the tests of the h1 server and of what's built on top
+//! of it, like the [proxy](crate::proxy)
+
+use std::cell::RefCell;
+
+use http::StatusCode;
+
+use crate::{
+    Body, BodyChunk, Encoder, ExpectResponseHeaders, Request, Responder, Response, ResponseDone,
+    ServerDriver,
+};
+
+/// Remembers which requests it got, along with their body (`None` if it
+/// couldn't be read)
+#[derive(Default)]
+pub(crate) struct Record {
+    pub(crate) seen: RefCell<Vec<(String, Option<Vec<u8>>)>>,
+}
+
+impl ServerDriver for Record {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        let path = req.uri.path().to_owned();
+        let mut body = vec![];
+        loop {
+            match req_body.next_chunk().await {
+                Ok(BodyChunk::Chunk(chunk)) => body.extend_from_slice(&chunk[..]),
+                Ok(BodyChunk::Done { .. }) => break,
+                Err(e) => {
+                    self.seen.borrow_mut().push((path, None));
+                    return Err(e);
+                }
+            }
+        }
+        self.seen.borrow_mut().push((path, Some(body)));
+
+        let res = Response {
+            status: StatusCode::OK,
+            ..Default::default()
+        };
+        respond.write_final_response_with_body(res, &mut ()).await
+    }
+}
+
+/// A request head, without the final CRLF, and what follows, along with
+/// the requests a server should see, and how the first response starts
+/// (`None` for no response at all), under a name
+pub(crate) type SmugglingCase = (
+    &'static str,
+    &'static str,
+    String,
+    &'static [(&'static str, Option<&'static [u8]>)],
+    Option<&'static str>,
+);
+
+/// Requests that servers frame differently, and which could smuggle a
+/// request past one that frames them unlike the next hop, cf.
+/// <https://httpwg.org/specs/rfc9112.html#message.body.length>
+pub(crate) fn smuggling_corpus() -> Vec<SmugglingCase> {
+    const OK: Option<&str> = Some("HTTP/1.1 200");
+    const BAD_REQUEST: Option<&str> = Some("HTTP/1.1 400");
+    const NOT_IMPLEMENTED: Option<&str> = Some("HTTP/1.1 501");
+
+    let long_ext = format!("3;{}\r\nabc\r\n0\r\n\r\n", "x".repeat(300));
+    #[allow(clippy::type_complexity)]
+    let corpus: &[(&str, &str, &str, &[(&str, Option<&[u8]>)], Option<&str>)] = &[
+        (
+            "pipelined, well-formed",
+            "POST /a HTTP/1.1\r\ncontent-length: 3",
+            "abcGET /b HTTP/1.1\r\n\r\n",
+            &[("/a", Some(b"abc")), ("/b", Some(b""))],
+            OK,
+        ),
+        (
+            "CL.TE",
+            "POST /a HTTP/1.1\r\ncontent-length: 6\r\ntransfer-encoding: chunked",
+            "0\r\n\r\nGET /smuggled HTTP/1.1\r\n\r\n",
+            &[],
+            BAD_REQUEST,
+        ),
+        (
+            "TE.CL",
+            "POST /a HTTP/1.1\r\ntransfer-encoding: chunked\r\ncontent-length: 4",
+            "5c\r\nGET /smuggled HTTP/1.1\r\n\r\n0\r\n\r\n",
+            &[],
+            BAD_REQUEST,
+        ),
+        (
+            "TE.TE, chunked not last",
+            "POST /a HTTP/1.1\r\ntransfer-encoding: chunked\r\ntransfer-encoding: identity",
+            "0\r\n\r\n",
+            &[],
+            BAD_REQUEST,
+        ),
+        (
+            "TE.TE, chunked twice",
+            "POST /a HTTP/1.1\r\ntransfer-encoding: chunked, chunked",
+            "0\r\n\r\n",
+            &[],
+            BAD_REQUEST,
+        ),
+        (
+            "TE, look-alike coding",
+            "POST /a HTTP/1.1\r\ntransfer-encoding: xchunked",
+            "0\r\n\r\n",
+            &[],
+            BAD_REQUEST,
+        ),
+        (
+            "TE, unsupported coding",
+            "POST /a HTTP/1.1\r\ntransfer-encoding: gzip, chunked",
+            "0\r\n\r\n",
+            &[],
+            NOT_IMPLEMENTED,
+        ),
+        (
+            "TE, space before colon",
+            "POST /a HTTP/1.1\r\ntransfer-encoding : chunked",
+            "0\r\n\r\n",
+            &[],
+            BAD_REQUEST,
+        ),
+        (
+            "CL.CL, different values",
+            "POST /a HTTP/1.1\r\ncontent-length: 0\r\ncontent-length: 5",
+            "GET /smuggled HTTP/1.1\r\n\r\n",
+            &[],
+            BAD_REQUEST,
+        ),
+        (
+            "CL.CL, same values",
+            "POST /a HTTP/1.1\r\ncontent-length: 3, 3",
+            "abc",
+            &[("/a", Some(b"abc"))],
+            OK,
+        ),
+        (
+            "CL, signed",
+            "POST /a HTTP/1.1\r\ncontent-length: +3",
+            "abc",
+            &[],
+            BAD_REQUEST,
+        ),
+        (
+            "CL, not a number",
+            "POST /a HTTP/1.1\r\ncontent-length: 3 3",
+            "abc",
+            &[],
+            BAD_REQUEST,
+        ),
+        (
+            "obs-fold",
+            "POST /a HTTP/1.1\r\nx-foo: bar\r\n transfer-encoding: chunked",
+            "0\r\n\r\n",
+            &[],
+            BAD_REQUEST,
+        ),
+        (
+            "bare LF in a header value",
+            "POST /a HTTP/1.1\r\nx-foo: bar\ntransfer-encoding: chunked",
+            "0\r\n\r\n",
+            &[],
+            BAD_REQUEST,
+        ),
+        (
+            "chunk extensions",
+            "POST /a HTTP/1.1\r\ntransfer-encoding: chunked",
+            "3;name=\"value\"\r\nabc\r\n0;last\r\n\r\n",
+            &[("/a", Some(b"abc"))],
+            OK,
+        ),
+        (
+            "chunk extension too long",
+            "POST /a HTTP/1.1\r\ntransfer-encoding: chunked",
+            long_ext.as_str(),
+            &[("/a", None)],
+            None,
+        ),
+        (
+            "bare LF after chunk size",
+            "POST /a HTTP/1.1\r\ntransfer-encoding: chunked",
+            "3\nabc\r\n0\r\n\r\n",
+            &[("/a", None)],
+            None,
+        ),
+        (
+            "chunk size overflow",
+            "POST /a HTTP/1.1\r\ntransfer-encoding: chunked",
+            "10000000000000003\r\nabc\r\n0\r\n\r\n",
+            &[("/a", None)],
+            None,
+        ),
+    ];
+
+    corpus
+        .iter()
+        .map(|&(name, head, rest, seen, res)| (name, head, rest.to_owned(), seen, res))
+        .collect()
+}
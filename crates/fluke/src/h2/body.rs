@@ -1,8 +1,12 @@
-use tokio::sync::mpsc;
+use std::{cell::RefCell, rc::Rc};
+
+use tokio::sync::{mpsc, Notify};
 
 use crate::{Body, BodyChunk, Headers};
 use fluke_buffet::Piece;
 
+use super::parse::StreamId;
+
 pub(crate) enum PieceOrTrailers {
     Piece(Piece),
     Trailers(Box<Headers>),
@@ -12,12 +16,55 @@ pub(crate) type H2BodySender = mpsc::Sender<H2BodyItem>;
 // FIXME: don't use eyre, do proper error handling
 pub(crate) type H2BodyItem = eyre::Result<PieceOrTrailers>;
 
+/// Request body data handlers have read, which the connection hasn't
+/// accounted for yet: reading lets the peer send more, see
+/// [IncomingWindow](super::types::IncomingWindow).
+#[derive(Debug, Default)]
+pub(crate) struct ConsumedData {
+    pending: RefCell<Vec<(StreamId, u32)>>,
+    notify: Notify,
+}
+
+impl ConsumedData {
+    pub(crate) fn add(&self, stream_id: StreamId, len: u32) {
+        let mut pending = self.pending.borrow_mut();
+        match pending.last_mut() {
+            Some((id, pending_len)) if *id == stream_id => *pending_len += len,
+            _ => pending.push((stream_id, len)),
+        }
+        self.notify.notify_one();
+    }
+
+    /// Resolves once there's something to take
+    pub(crate) async fn wait(&self) {
+        self.notify.notified().await
+    }
+
+    pub(crate) fn take(&self) -> Vec<(StreamId, u32)> {
+        std::mem::take(&mut *self.pending.borrow_mut())
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct H2Body {
+    pub(crate) stream_id: StreamId,
     pub(crate) content_length: Option<u64>,
     pub(crate) eof: bool,
     // TODO: more specific error handling
     pub(crate) rx: mpsc::Receiver<H2BodyItem>,
+    pub(crate) consumed: Rc<ConsumedData>,
+}
+
+impl Drop for H2Body {
+    fn drop(&mut self) {
+        // data that was sent our way but never read still takes up room in
+        // the connection's window
+        while let Ok(Ok(PieceOrTrailers::Piece(piece))) = self.rx.try_recv() {
+            if !piece.is_empty() {
+                self.consumed.add(self.stream_id, piece.len() as u32);
+            }
+        }
+    }
 }
 
 impl Body for H2Body {
@@ -35,7 +82,12 @@ impl Body for H2Body {
         } else {
             match self.rx.recv().await {
                 Some(maybe_piece_or_trailers) => match maybe_piece_or_trailers? {
-                    PieceOrTrailers::Piece(piece) => BodyChunk::Chunk(piece),
+                    PieceOrTrailers::Piece(piece) => {
+                        if !piece.is_empty() {
+                            self.consumed.add(self.stream_id, piece.len() as u32);
+                        }
+                        BodyChunk::Chunk(piece)
+                    }
                     PieceOrTrailers::Trailers(trailers) => {
                         self.eof = true;
                         BodyChunk::Done {
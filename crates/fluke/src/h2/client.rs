@@ -0,0 +1,1670 @@
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    collections::{HashMap, VecDeque},
+    net::Shutdown,
+    rc::Rc,
+    sync::atomic::AtomicU32,
+};
+
+use byteorder::{BigEndian, WriteBytesExt};
+use enumflags2::BitFlags;
+use eyre::Context;
+use http::{header, HeaderName, StatusCode, Version};
+use nom::Finish;
+use tokio::sync::{mpsc, oneshot};
+use tracing::debug;
+
+use fluke_buffet::{Piece, PieceList, Roll, RollMut};
+use fluke_maybe_uring::io::{ConnInfo, Transport, WriteOwned};
+
+use crate::{
+    h1::ClientDriver, types::is_connection_specific, Body, BodyChunk, Headers, HeadersExt, Method,
+    Request, Response,
+};
+
+use super::{
+    parse::{
+        self, parse_reserved_and_u31, ContinuationFlags, DataFlags, Frame, FrameType, HeadersFlags,
+        KnownErrorCode, PingFlags, Settings, SettingsFlags, StreamId,
+    },
+    server::deframe_loop,
+    types::{H2ConnectionError, MAX_WINDOW_SIZE},
+};
+
+/// HTTP/2 client configuration
+pub struct ClientConf {
+    /// Max decoded size of a response header block, advertised as
+    /// SETTINGS_MAX_HEADER_LIST_SIZE. Larger ones fail the request.
+    pub max_header_list_size: u32,
+
+    /// How much of each response body the server may send before we've
+    /// read it, advertised as SETTINGS_INITIAL_WINDOW_SIZE
+    pub initial_window_size: u32,
+
+    /// How many bytes are read from the transport at a time
+    pub read_chunk_size: usize,
+
+    /// How many frames read from the server may wait to be processed
+    pub max_buffered_frames: usize,
+}
+
+impl Default for ClientConf {
+    fn default() -> Self {
+        Self {
+            max_header_list_size: 64 * 1024,
+            initial_window_size: Settings::default().initial_window_size,
+            read_chunk_size: 16 * 1024,
+            max_buffered_frames: 32,
+        }
+    }
+}
+
+/// An HTTP/2 connection to a server, which requests are multiplexed over,
+/// see [ClientConnection::request].
+///
+/// Frames are read and written by a task of its own, spawned by
+/// [ClientConnection::connect]. It keeps going until this and every
+/// [SendRequest] handle are dropped and all requests are done, then says
+/// goodbye with a GOAWAY frame.
+pub struct ClientConnection {
+    send_request: SendRequest,
+}
+
+/// A handle to a [ClientConnection] that requests can be sent through, see
+/// [ClientConnection::send_request]. Clones can be moved to other tasks,
+/// which then send requests concurrently: each gets a stream of its own,
+/// with its own flow control.
+#[derive(Clone)]
+pub struct SendRequest {
+    cmd_tx: mpsc::UnboundedSender<Command>,
+}
+
+impl ClientConnection {
+    /// Sends the connection preface and our settings over `transport`, then
+    /// serves the connection on a task of its own. ALPN (or prior
+    /// knowledge) must have settled on h2 already.
+    pub async fn connect<T>(transport: T, conf: Rc<ClientConf>) -> eyre::Result<Self>
+    where
+        T: Transport,
+        T::Read: 'static,
+        T::Write: 'static,
+    {
+        let conn_info = ConnInfo::of(&transport);
+        let (transport_r, transport_w) = transport.into_halves();
+
+        let mut cx = ClientContext::new(conf, transport_w, conn_info.tls.is_some())?;
+        cx.write_preface().await?;
+
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        crate::maybe_uring::spawn(async move {
+            if let Err(e) = cx.work(transport_r, cmd_rx).await {
+                debug!("h2 client connection failed: {e:?}");
+            }
+        });
+        Ok(Self {
+            send_request: SendRequest { cmd_tx },
+        })
+    }
+
+    /// Returns a handle requests can be sent through, alongside this
+    pub fn send_request(&self) -> SendRequest {
+        self.send_request.clone()
+    }
+
+    /// See [SendRequest::request]
+    pub async fn request<D: ClientDriver>(
+        &self,
+        req: Request,
+        body: &mut impl Body,
+        driver: D,
+    ) -> eyre::Result<D::Return> {
+        self.send_request.request(req, body, driver).await
+    }
+}
+
+impl SendRequest {
+    /// Sends `req` with `body` on a new stream, and hands the response over
+    /// to `driver`: informational responses first, then the final one,
+    /// with its body.
+    ///
+    /// Stream ids are handed out as requests' headers are written, so they
+    /// go up in the order requests are sent, whichever handle they're sent
+    /// through.
+    /// Requests past the server's SETTINGS_MAX_CONCURRENT_STREAMS wait,
+    /// in order, for earlier ones to be done.
+    ///
+    /// Connection-specific headers are dropped, and `host` turns into
+    /// `:authority` unless the request target has one.
+    pub async fn request<D: ClientDriver>(
+        &self,
+        mut req: Request,
+        body: &mut impl Body,
+        mut driver: D,
+    ) -> eyre::Result<D::Return> {
+        let end_stream = match body.content_len() {
+            Some(0) => true,
+            Some(len) => {
+                req.headers
+                    .insert(header::CONTENT_LENGTH, len.to_string().into_bytes().into());
+                false
+            }
+            None => false,
+        };
+
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let (opened_tx, opened_rx) = oneshot::channel();
+        self.send(Command::Open(OpenStream {
+            req,
+            end_stream,
+            events: events_tx,
+            opened: opened_tx,
+        }))?;
+        let stream_id = opened_rx
+            .await
+            .map_err(|_| eyre::eyre!("h2 connection closed"))??;
+        // resets the stream if we're dropped (or fail) halfway through
+        let mut guard = StreamGuard {
+            cmd_tx: &self.cmd_tx,
+            stream_id,
+            done: false,
+        };
+
+        let send_body = async {
+            if !end_stream {
+                self.send_body(stream_id, body).await?;
+            }
+            Ok::<_, eyre::Report>(())
+        };
+        let recv_res = async {
+            let res = loop {
+                match events.recv().await {
+                    Some(Ok(StreamEvent::Headers(res))) if res.status.is_informational() => {
+                        driver.on_informational_response(res).await?;
+                    }
+                    Some(Ok(StreamEvent::Headers(res))) => break res,
+                    Some(Ok(_)) => {
+                        return Err(eyre::eyre!(
+                            "server sent a response body before its headers"
+                        ))
+                    }
+                    Some(Err(e)) => return Err(e),
+                    None => return Err(eyre::eyre!("h2 connection closed")),
+                }
+            };
+            res.debug_print();
+
+            let mut res_body = H2ClientBody {
+                stream_id,
+                content_length: res.headers.content_length(),
+                eof: false,
+                events,
+                cmd_tx: self.cmd_tx.clone(),
+            };
+            let ret = driver.on_final_response(res, &mut res_body).await?;
+            Ok::<_, eyre::Report>((ret, res_body.eof))
+        };
+
+        // TODO: stop sending the body if the response comes early?
+        let (_, (ret, res_eof)) = tokio::try_join!(send_body, recv_res)?;
+        // the driver may not have read the whole response body, in which
+        // case the server is told to stop sending it
+        guard.done = res_eof;
+        Ok(ret)
+    }
+
+    async fn send_body(&self, stream_id: StreamId, body: &mut impl Body) -> eyre::Result<()> {
+        loop {
+            let (written_tx, written_rx) = oneshot::channel();
+            match body.next_chunk().await? {
+                BodyChunk::Chunk(chunk) => {
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                    self.send(Command::Data {
+                        stream_id,
+                        data: PendingData::Chunk(chunk),
+                        written: written_tx,
+                    })?;
+                }
+                BodyChunk::Done { trailers } => {
+                    let data = match trailers {
+                        Some(trailers) => PendingData::EndWithTrailers(trailers),
+                        None => PendingData::End,
+                    };
+                    self.send(Command::Data {
+                        stream_id,
+                        data,
+                        written: written_tx,
+                    })?;
+                    written_rx
+                        .await
+                        .map_err(|_| eyre::eyre!("stream was reset while sending the body"))?;
+                    debug!(%stream_id, "done writing request body");
+                    return Ok(());
+                }
+            }
+            // one chunk at a time, as flow control allows
+            written_rx
+                .await
+                .map_err(|_| eyre::eyre!("stream was reset while sending the body"))?;
+        }
+    }
+
+    fn send(&self, cmd: Command) -> eyre::Result<()> {
+        self.cmd_tx
+            .send(cmd)
+            .map_err(|_| eyre::eyre!("h2 connection closed"))
+    }
+}
+
+/// Resets its stream when dropped, unless it's `done`
+struct StreamGuard<'a> {
+    cmd_tx: &'a mpsc::UnboundedSender<Command>,
+    stream_id: StreamId,
+    done: bool,
+}
+
+impl Drop for StreamGuard<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            _ = self.cmd_tx.send(Command::Cancel {
+                stream_id: self.stream_id,
+            });
+        }
+    }
+}
+
+/// What requests ask of the connection task
+enum Command {
+    /// Sends the request's HEADERS on a new stream, whose id is sent back,
+    /// once the server lets us open one more
+    Open(OpenStream),
+
+    /// Sends body data (or its end), and says so once it's written
+    Data {
+        stream_id: StreamId,
+        data: PendingData,
+        written: oneshot::Sender<()>,
+    },
+
+    /// That much of a response body was read, the server may send more
+    Consumed { stream_id: StreamId, len: u32 },
+
+    /// The request was dropped or failed: RST_STREAM with CANCEL
+    Cancel { stream_id: StreamId },
+}
+
+/// A request waiting for a stream of its own
+struct OpenStream {
+    req: Request,
+    end_stream: bool,
+    events: mpsc::UnboundedSender<eyre::Result<StreamEvent>>,
+    opened: oneshot::Sender<eyre::Result<StreamId>>,
+}
+
+/// What the connection task tells a request about its stream
+enum StreamEvent {
+    Headers(Response),
+    Data(Piece),
+    Trailers(Box<Headers>),
+    End,
+}
+
+enum PendingData {
+    Chunk(Piece),
+    End,
+    EndWithTrailers(Box<Headers>),
+}
+
+/// A stream the connection task knows about
+struct ClientStream {
+    events: mpsc::UnboundedSender<eyre::Result<StreamEvent>>,
+
+    /// How much DATA we may still send on it, cf. RFC 9113 section 6.9
+    outgoing_window: i64,
+
+    /// Whether final (non-1xx) headers were received, after which HEADERS
+    /// carry trailers
+    got_final: bool,
+
+    send_closed: bool,
+    recv_closed: bool,
+}
+
+/// Reads and writes frames for a [ClientConnection]
+struct ClientContext<W: WriteOwned> {
+    conf: Rc<ClientConf>,
+    transport_w: W,
+    tls: bool,
+
+    hpack_dec: fluke_hpack::Decoder<'static>,
+    hpack_enc: fluke_hpack::Encoder<'static>,
+    out_scratch: RollMut,
+
+    /// Where header blocks split across CONTINUATION frames are reassembled
+    continuation_scratch: Vec<u8>,
+
+    peer_settings: Settings,
+    streams: HashMap<StreamId, ClientStream>,
+    next_stream_id: u32,
+
+    /// Requests waiting for a stream, in the order they were sent: the
+    /// server's SETTINGS_MAX_CONCURRENT_STREAMS caps how many of `streams`
+    /// there may be, cf. RFC 9113 section 5.1.2
+    queued_streams: VecDeque<OpenStream>,
+
+    /// Set once the server sent GOAWAY: no new streams may be opened
+    goaway: bool,
+
+    /// How much DATA we may still send on the connection as a whole
+    outgoing_window: i64,
+
+    /// Request bodies waiting for flow control to let them through, in the
+    /// order they were written
+    pending_data: VecDeque<(StreamId, PendingData, oneshot::Sender<()>)>,
+}
+
+impl<W: WriteOwned> ClientContext<W> {
+    fn new(conf: Rc<ClientConf>, transport_w: W, tls: bool) -> eyre::Result<Self> {
+        let mut hpack_dec = fluke_hpack::Decoder::new();
+        hpack_dec
+            .set_max_allowed_table_size(Settings::default().header_table_size.try_into().unwrap());
+
+        Ok(Self {
+            conf,
+            transport_w,
+            tls,
+            hpack_dec,
+            hpack_enc: fluke_hpack::Encoder::new(),
+            out_scratch: RollMut::alloc()?,
+            continuation_scratch: Vec::new(),
+            peer_settings: Default::default(),
+            streams: Default::default(),
+            next_stream_id: 1,
+            queued_streams: Default::default(),
+            goaway: false,
+            outgoing_window: Settings::default().initial_window_size as i64,
+            pending_data: Default::default(),
+        })
+    }
+
+    /// The settings we send: no push, and the limits from our configuration
+    fn self_settings(&self) -> Settings {
+        Settings {
+            enable_push: false,
+            max_concurrent_streams: 0,
+            initial_window_size: self.conf.initial_window_size,
+            max_header_list_size: self.conf.max_header_list_size,
+            ..Default::default()
+        }
+    }
+
+    async fn write_preface(&mut self) -> eyre::Result<()> {
+        self.transport_w
+            .write_all(parse::PREFACE)
+            .await
+            .wrap_err("writing h2 preface")?;
+
+        let payload = self.self_settings().into_roll(&mut self.out_scratch)?;
+        let frame = Frame::new(
+            FrameType::Settings(Default::default()),
+            StreamId::CONNECTION,
+        );
+        self.write_frame(frame, payload).await?;
+        Ok(())
+    }
+
+    async fn work(
+        &mut self,
+        transport_r: impl fluke_maybe_uring::io::ReadOwned,
+        mut cmd_rx: mpsc::UnboundedReceiver<Command>,
+    ) -> eyre::Result<()> {
+        let (tx, mut rx) = mpsc::channel::<(Frame, Roll)>(self.conf.max_buffered_frames);
+        let deframe_task = deframe_loop(
+            RollMut::alloc()?,
+            transport_r,
+            tx,
+            Rc::new(AtomicU32::new(self.self_settings().max_frame_size)),
+            self.conf.read_chunk_size,
+            Rc::new(Cell::new(false)),
+        );
+        let mut deframe_task = std::pin::pin!(deframe_task);
+
+        // frames keep being read while we're busy writing, which may take a
+        // while if the server isn't reading because it's busy writing too
+        let res = {
+            let process_task = self.process_loop(&mut rx, &mut cmd_rx);
+            let mut process_task = std::pin::pin!(process_task);
+            tokio::select! {
+                res = &mut process_task => res,
+                res = &mut deframe_task => {
+                    debug!(?res, "h2 client deframe task finished");
+                    match res {
+                        // frames it already read still need processing
+                        Ok(()) => process_task.await,
+                        Err(e) => Err(e),
+                    }
+                }
+            }
+        };
+
+        if let Err(err) = &res {
+            let error_code = err.as_known_error_code();
+            debug!("Connection error: {err} ({err:?}) (code {error_code:?})");
+            let additional_debug_data = format!("{err}").into_bytes();
+            _ = self.send_goaway(error_code, &additional_debug_data).await;
+        }
+
+        // whatever is still in flight won't complete
+        for (_, stream) in self.streams.drain() {
+            _ = stream
+                .events
+                .send(Err(eyre::eyre!("h2 connection closed mid-stream")));
+        }
+        _ = self.transport_w.shutdown(Shutdown::Both).await;
+        res.map_err(Into::into)
+    }
+
+    async fn process_loop(
+        &mut self,
+        rx: &mut mpsc::Receiver<(Frame, Roll)>,
+        cmd_rx: &mut mpsc::UnboundedReceiver<Command>,
+    ) -> Result<(), H2ConnectionError> {
+        loop {
+            tokio::select! {
+                frame = rx.recv() => match frame {
+                    Some((frame, payload)) => self.process_frame(frame, payload, rx).await?,
+                    None => {
+                        debug!("server hung up");
+                        break;
+                    }
+                },
+                cmd = cmd_rx.recv() => match cmd {
+                    Some(cmd) => self.handle_command(cmd).await?,
+                    None => {
+                        debug!("client connection dropped, all requests are done");
+                        self.send_goaway(KnownErrorCode::NoError, b"").await?;
+                        break;
+                    }
+                },
+            }
+            // streams may have closed, or the server may allow more of them
+            self.open_queued_streams().await?;
+
+            if self.goaway && self.streams.is_empty() {
+                debug!("server is going away and all streams are done");
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_command(&mut self, cmd: Command) -> Result<(), H2ConnectionError> {
+        match cmd {
+            Command::Open(open) => {
+                if self.goaway {
+                    _ = open.opened.send(Err(eyre::eyre!(
+                        "server is going away, the request can be retried on a new connection"
+                    )));
+                    return Ok(());
+                }
+                self.queued_streams.push_back(open);
+            }
+            Command::Data {
+                stream_id,
+                data,
+                written,
+            } => {
+                if self.streams.contains_key(&stream_id) {
+                    self.pending_data.push_back((stream_id, data, written));
+                    self.write_pending_data().await?;
+                }
+                // otherwise, the stream was reset: dropping `written` tells
+                // whoever's sending the body
+            }
+            Command::Consumed { stream_id, len } => {
+                self.send_window_update(StreamId::CONNECTION, len).await?;
+                if self
+                    .streams
+                    .get(&stream_id)
+                    .map_or(false, |stream| !stream.recv_closed)
+                {
+                    self.send_window_update(stream_id, len).await?;
+                }
+            }
+            Command::Cancel { stream_id } => {
+                if self.streams.contains_key(&stream_id) {
+                    debug!(%stream_id, "request dropped, cancelling stream");
+                    self.rst(stream_id, KnownErrorCode::Cancel).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Opens streams for queued requests, as long as the server's
+    /// SETTINGS_MAX_CONCURRENT_STREAMS allows. Every stream we know about
+    /// counts: they're all ours, and open or half-closed.
+    async fn open_queued_streams(&mut self) -> Result<(), H2ConnectionError> {
+        while self.streams.len() < self.peer_settings.max_concurrent_streams as usize {
+            let Some(open) = self.queued_streams.pop_front() else {
+                break;
+            };
+            if open.opened.is_closed() {
+                // the request was dropped while it waited
+                continue;
+            }
+            self.open_stream(open).await?;
+        }
+        Ok(())
+    }
+
+    async fn open_stream(&mut self, open: OpenStream) -> Result<(), H2ConnectionError> {
+        let OpenStream {
+            req,
+            end_stream,
+            events,
+            opened,
+        } = open;
+        let block = match self.encode_request(&req) {
+            Ok(block) => block,
+            Err(e) => {
+                _ = opened.send(Err(e));
+                return Ok(());
+            }
+        };
+
+        // ids are handed out as HEADERS go out, so they're always
+        // numerically increasing on the wire
+        let stream_id = StreamId(self.next_stream_id);
+        self.next_stream_id += 2;
+        self.streams.insert(
+            stream_id,
+            ClientStream {
+                events,
+                outgoing_window: self.peer_settings.initial_window_size as i64,
+                got_final: false,
+                send_closed: end_stream,
+                recv_closed: false,
+            },
+        );
+
+        let mut flags = BitFlags::from(HeadersFlags::EndHeaders);
+        if end_stream {
+            flags |= HeadersFlags::EndStream;
+        }
+        let frame = Frame::new(FrameType::Headers(flags), stream_id);
+        self.write_frame(frame, block).await?;
+        _ = opened.send(Ok(stream_id));
+        Ok(())
+    }
+
+    /// HPACK-encodes a request's header block: pseudo-headers first, then
+    /// regular headers minus connection-specific ones
+    fn encode_request(&mut self, req: &Request) -> eyre::Result<Roll> {
+        let authority = match req.uri.authority() {
+            Some(authority) => Some(authority.as_bytes()),
+            None => req.headers.get(header::HOST).map(|host| &host[..]),
+        };
+
+        let mut block: Vec<(&[u8], &[u8])> =
+            vec![(&b":method"[..], req.method.as_str().as_bytes())];
+        // plain CONNECT requests only have `:authority`, cf. RFC 9113
+        // section 8.5, extended ones have everything, cf. RFC 8441 section 4
+        let plain_connect = req.method == Method::Connect && req.protocol.is_none();
+        if !plain_connect {
+            let scheme = match req.uri.scheme() {
+                Some(scheme) => scheme.as_str(),
+                None if self.tls => "https",
+                None => "http",
+            };
+            block.push((b":scheme", scheme.as_bytes()));
+            block.push((b":path", req.uri.path_and_query().as_bytes()));
+        }
+        match authority {
+            Some(authority) => block.push((b":authority", authority)),
+            None if plain_connect => {
+                return Err(eyre::eyre!("CONNECT requests need an authority"));
+            }
+            None => {}
+        }
+        if let Some(protocol) = &req.protocol {
+            block.push((b":protocol", protocol.as_bytes()));
+        }
+
+        for (name, value) in req.headers.iter() {
+            if is_connection_specific(name) || *name == header::HOST {
+                continue;
+            }
+            if *name == header::TE && !value.eq_ignore_ascii_case(b"trailers") {
+                continue;
+            }
+            block.push((name.as_str().as_bytes(), value));
+        }
+
+        self.encode_block(block)
+    }
+
+    fn encode_block(&mut self, block: Vec<(&[u8], &[u8])>) -> eyre::Result<Roll> {
+        assert_eq!(self.out_scratch.len(), 0);
+        self.hpack_enc
+            .encode_into(block, &mut self.out_scratch)
+            .wrap_err("hpack-encoding headers")?;
+        Ok(self.out_scratch.take_all())
+    }
+
+    async fn write_pending_data(&mut self) -> Result<(), H2ConnectionError> {
+        let mut index = 0;
+        while index < self.pending_data.len() {
+            let stream_id = self.pending_data[index].0;
+            let Some(stream) = self.streams.get(&stream_id) else {
+                // reset since: dropping `written` tells the sender
+                self.pending_data.remove(index);
+                continue;
+            };
+
+            let (_, data, written) = self.pending_data.remove(index).unwrap();
+            match data {
+                PendingData::Chunk(chunk) => {
+                    let window = std::cmp::min(self.outgoing_window, stream.outgoing_window);
+                    if window <= 0 {
+                        // this stream has to wait, others may not
+                        self.pending_data
+                            .insert(index, (stream_id, PendingData::Chunk(chunk), written));
+                        index += 1;
+                        continue;
+                    }
+
+                    let max_len =
+                        std::cmp::min(window as usize, self.peer_settings.max_frame_size as usize);
+                    let (chunk, rest) = if chunk.len() > max_len {
+                        let (chunk, rest) = chunk.split_at(max_len);
+                        (chunk, Some(rest))
+                    } else {
+                        (chunk, None)
+                    };
+                    self.outgoing_window -= chunk.len() as i64;
+                    if let Some(stream) = self.streams.get_mut(&stream_id) {
+                        stream.outgoing_window -= chunk.len() as i64;
+                    }
+
+                    let frame = Frame::new(FrameType::Data(Default::default()), stream_id);
+                    self.write_frame(frame, chunk).await?;
+                    match rest {
+                        Some(rest) => {
+                            self.pending_data
+                                .insert(index, (stream_id, PendingData::Chunk(rest), written));
+                        }
+                        None => {
+                            _ = written.send(());
+                        }
+                    }
+                }
+                PendingData::End => {
+                    let frame = Frame::new(FrameType::Data(DataFlags::EndStream.into()), stream_id);
+                    self.write_frame(frame, Roll::empty()).await?;
+                    self.close_send(stream_id);
+                    _ = written.send(());
+                }
+                PendingData::EndWithTrailers(trailers) => {
+                    let block = trailers
+                        .iter()
+                        .filter(|(name, _)| !is_connection_specific(name))
+                        .map(|(name, value)| (name.as_str().as_bytes(), &value[..]))
+                        .collect();
+                    let payload = self.encode_block(block)?;
+                    let flags = HeadersFlags::EndHeaders | HeadersFlags::EndStream;
+                    let frame = Frame::new(FrameType::Headers(flags), stream_id);
+                    self.write_frame(frame, payload).await?;
+                    self.close_send(stream_id);
+                    _ = written.send(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// We're done sending on `stream_id`, which is closed if the server is too
+    fn close_send(&mut self, stream_id: StreamId) {
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.send_closed = true;
+            if stream.recv_closed {
+                self.streams.remove(&stream_id);
+            }
+        }
+    }
+
+    /// The server is done sending on `stream_id`, which is closed if we are too
+    fn close_recv(&mut self, stream_id: StreamId) {
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            _ = stream.events.send(Ok(StreamEvent::End));
+            stream.recv_closed = true;
+            if stream.send_closed {
+                self.streams.remove(&stream_id);
+            }
+        }
+    }
+
+    async fn process_frame(
+        &mut self,
+        frame: Frame,
+        mut payload: Roll,
+        rx: &mut mpsc::Receiver<(Frame, Roll)>,
+    ) -> Result<(), H2ConnectionError> {
+        match frame.frame_type {
+            FrameType::Data(flags) => {
+                // flow control counts padding too, but nobody reads it
+                let padding = frame.len - payload.len() as u32;
+                let end_stream = flags.contains(DataFlags::EndStream);
+                let delivered = match self.streams.get(&frame.stream_id) {
+                    Some(stream) if stream.recv_closed => false,
+                    Some(stream) if !stream.got_final => {
+                        // cf. https://httpwg.org/specs/rfc9113.html#rfc.section.8.1
+                        debug!(stream_id = %frame.stream_id, "server sent data before response headers");
+                        _ = stream.events.send(Err(eyre::eyre!(
+                            "server sent a response body before its headers"
+                        )));
+                        self.rst(frame.stream_id, KnownErrorCode::ProtocolError)
+                            .await?;
+                        false
+                    }
+                    Some(stream) => stream
+                        .events
+                        .send(Ok(StreamEvent::Data(payload.into())))
+                        .is_ok(),
+                    None => false,
+                };
+                if !delivered {
+                    // nobody will read it, so the connection window is
+                    // replenished right away
+                    debug!(stream_id = %frame.stream_id, "ignoring data for stream that's gone");
+                    if frame.len > 0 {
+                        self.send_window_update(StreamId::CONNECTION, frame.len)
+                            .await?;
+                    }
+                    return Ok(());
+                }
+                if padding > 0 {
+                    self.send_window_update(StreamId::CONNECTION, padding)
+                        .await?;
+                    if !end_stream {
+                        self.send_window_update(frame.stream_id, padding).await?;
+                    }
+                }
+                if end_stream {
+                    self.close_recv(frame.stream_id);
+                }
+            }
+            FrameType::Headers(flags) => {
+                if flags.contains(HeadersFlags::Priority) {
+                    // 5 bytes of priority information, which servers have
+                    // no business sending
+                    payload = payload.split_at(std::cmp::min(5, payload.len())).1;
+                }
+                self.read_headers(frame.stream_id, flags, payload, rx)
+                    .await?;
+            }
+            FrameType::RstStream => {
+                let code = (payload.len() == 4)
+                    .then(|| u32::from_be_bytes(payload[..4].try_into().unwrap()));
+                self.pending_data.retain(|(id, ..)| *id != frame.stream_id);
+                if let Some(stream) = self.streams.remove(&frame.stream_id) {
+                    let code = code.and_then(KnownErrorCode::from_repr);
+                    debug!(stream_id = %frame.stream_id, ?code, "server reset stream");
+                    _ = stream
+                        .events
+                        .send(Err(eyre::eyre!("server reset the stream: {code:?}")));
+                }
+            }
+            FrameType::Settings(flags) => {
+                if frame.stream_id != StreamId::CONNECTION {
+                    return Err(H2ConnectionError::SettingsWithNonZeroStreamId {
+                        stream_id: frame.stream_id,
+                    });
+                }
+                if flags.contains(SettingsFlags::Ack) {
+                    debug!("server acknowledged our settings");
+                    return Ok(());
+                }
+
+                let (_, settings) = nom::combinator::complete(Settings::parse)(payload)
+                    .finish()
+                    .map_err(|_| {
+                        H2ConnectionError::ReadError(eyre::eyre!("could not parse settings frame"))
+                    })?;
+                debug!("server sent us {settings:#?}");
+                self.hpack_enc
+                    .set_max_table_size(settings.header_table_size as usize);
+
+                let delta = settings.initial_window_size as i64
+                    - self.peer_settings.initial_window_size as i64;
+                for stream in self.streams.values_mut() {
+                    stream.outgoing_window += delta;
+                    if stream.outgoing_window > MAX_WINDOW_SIZE {
+                        return Err(H2ConnectionError::InitialWindowSizeOverflow);
+                    }
+                }
+                self.peer_settings = settings;
+
+                let frame = Frame::new(
+                    FrameType::Settings(SettingsFlags::Ack.into()),
+                    StreamId::CONNECTION,
+                );
+                self.write_frame(frame, Roll::empty()).await?;
+                self.write_pending_data().await?;
+            }
+            FrameType::PushPromise => {
+                return Err(H2ConnectionError::ServerSentPushPromise);
+            }
+            FrameType::Ping(flags) => {
+                if frame.stream_id != StreamId::CONNECTION {
+                    return Err(H2ConnectionError::PingFrameWithNonZeroStreamId {
+                        stream_id: frame.stream_id,
+                    });
+                }
+                if frame.len != 8 {
+                    return Err(H2ConnectionError::PingFrameInvalidLength { len: frame.len });
+                }
+                if !flags.contains(PingFlags::Ack) {
+                    let frame =
+                        Frame::new(FrameType::Ping(PingFlags::Ack.into()), StreamId::CONNECTION);
+                    self.write_frame(frame, payload).await?;
+                }
+            }
+            FrameType::GoAway => {
+                if frame.stream_id != StreamId::CONNECTION {
+                    return Err(H2ConnectionError::GoAwayWithNonZeroStreamId {
+                        stream_id: frame.stream_id,
+                    });
+                }
+                let (_, (_, last_stream_id)) = parse_reserved_and_u31(payload)
+                    .finish()
+                    .map_err(|err| eyre::eyre!("parsing error: {err:?}"))?;
+                debug!(%last_stream_id, "server is going away");
+                self.goaway = true;
+                for open in self.queued_streams.drain(..) {
+                    _ = open.opened.send(Err(eyre::eyre!(
+                        "server is going away, the request can be retried on a new connection"
+                    )));
+                }
+
+                // streams past the last one the server will process never
+                // reached it, cf. RFC 9113 section 6.8
+                let refused: Vec<StreamId> = self
+                    .streams
+                    .keys()
+                    .filter(|id| id.0 > last_stream_id)
+                    .copied()
+                    .collect();
+                for stream_id in refused {
+                    if let Some(stream) = self.streams.remove(&stream_id) {
+                        _ = stream.events.send(Err(eyre::eyre!(
+                            "server went away before processing the request, it can be retried"
+                        )));
+                    }
+                }
+            }
+            FrameType::WindowUpdate => {
+                if payload.len() != 4 {
+                    return Err(H2ConnectionError::WindowUpdateInvalidLength {
+                        len: payload.len(),
+                    });
+                }
+                let (_, (_, increment)) = parse_reserved_and_u31(payload)
+                    .finish()
+                    .map_err(|err| eyre::eyre!("parsing error: {err:?}"))?;
+                if increment == 0 {
+                    return Err(H2ConnectionError::WindowUpdateZeroIncrement);
+                }
+
+                if frame.stream_id == StreamId::CONNECTION {
+                    self.outgoing_window += increment as i64;
+                    if self.outgoing_window > MAX_WINDOW_SIZE {
+                        return Err(H2ConnectionError::WindowUpdateOverflow);
+                    }
+                } else if let Some(stream) = self.streams.get_mut(&frame.stream_id) {
+                    stream.outgoing_window += increment as i64;
+                    if stream.outgoing_window > MAX_WINDOW_SIZE {
+                        self.rst(frame.stream_id, KnownErrorCode::FlowControlError)
+                            .await?;
+                    }
+                }
+                self.write_pending_data().await?;
+            }
+            FrameType::Continuation(_) => {
+                return Err(H2ConnectionError::UnexpectedContinuationFrame {
+                    stream_id: frame.stream_id,
+                });
+            }
+            FrameType::Priority | FrameType::PriorityUpdate | FrameType::Unknown(_) => {
+                // nothing for clients to do about these
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes a header block (reading CONTINUATION frames as needed) and
+    /// hands it to its stream: as a response, or as trailers
+    async fn read_headers(
+        &mut self,
+        stream_id: StreamId,
+        flags: BitFlags<HeadersFlags>,
+        payload: Roll,
+        rx: &mut mpsc::Receiver<(Frame, Roll)>,
+    ) -> Result<(), H2ConnectionError> {
+        let end_stream = flags.contains(HeadersFlags::EndStream);
+
+        let payload = if flags.contains(HeadersFlags::EndHeaders) {
+            Cow::Borrowed(&payload[..])
+        } else {
+            let scratch = &mut self.continuation_scratch;
+            scratch.clear();
+            scratch.extend_from_slice(&payload[..]);
+            loop {
+                let Some((frame, payload)) = rx.recv().await else {
+                    return Err(H2ConnectionError::ExpectedContinuationFrame {
+                        stream_id,
+                        frame_type: None,
+                    });
+                };
+                let FrameType::Continuation(flags) = frame.frame_type else {
+                    return Err(H2ConnectionError::ExpectedContinuationFrame {
+                        stream_id,
+                        frame_type: Some(frame.frame_type),
+                    });
+                };
+                if frame.stream_id != stream_id {
+                    return Err(H2ConnectionError::ExpectedContinuationForStream {
+                        stream_id,
+                        continuation_stream_id: frame.stream_id,
+                    });
+                }
+                scratch.extend_from_slice(&payload[..]);
+                if flags.contains(ContinuationFlags::EndHeaders) {
+                    break;
+                }
+            }
+            Cow::Owned(std::mem::take(scratch))
+        };
+
+        // the block is decoded even if nobody wants it, to keep the hpack
+        // dynamic table in sync with the server's
+        let max_header_list_size = self.conf.max_header_list_size as usize;
+        let mut header_list_size = 0;
+        let mut status: Option<StatusCode> = None;
+        let mut headers = Headers::default();
+        let mut malformed: Option<String> = None;
+        self.hpack_dec
+            .decode_with_cb(&payload[..], |key: Cow<[u8]>, value: Cow<[u8]>| {
+                header_list_size += key.len() + value.len() + 32;
+                if header_list_size > max_header_list_size {
+                    malformed.get_or_insert_with(|| "header list too large".into());
+                    return;
+                }
+
+                if key.first() == Some(&b':') {
+                    match (&key[..], StatusCode::from_bytes(&value)) {
+                        (b":status", Ok(code)) if status.is_none() => status = Some(code),
+                        _ => {
+                            malformed.get_or_insert_with(|| {
+                                format!("bad pseudo-header {}", String::from_utf8_lossy(&key))
+                            });
+                        }
+                    }
+                    return;
+                }
+                match HeaderName::from_bytes(&key) {
+                    Ok(name) if !key.iter().any(|b| b.is_ascii_uppercase()) => {
+                        headers.append(name, Piece::from(value.into_owned()));
+                    }
+                    _ => {
+                        malformed.get_or_insert_with(|| {
+                            format!("bad header name {:?}", String::from_utf8_lossy(&key))
+                        });
+                    }
+                }
+            })
+            .map_err(|e| H2ConnectionError::CompressionError(format!("{e:?}")))?;
+        if let Cow::Owned(mut scratch) = payload {
+            // hand the allocation back for next time
+            scratch.clear();
+            self.continuation_scratch = scratch;
+        }
+
+        let Some(stream) = self.streams.get_mut(&stream_id) else {
+            if stream_id.is_server_initiated() || stream_id.0 >= self.next_stream_id {
+                return Err(H2ConnectionError::ReadError(eyre::eyre!(
+                    "server sent headers for stream {stream_id}, which we never opened"
+                )));
+            }
+            debug!(%stream_id, "ignoring headers for stream that's gone");
+            return Ok(());
+        };
+        if stream.recv_closed {
+            return Err(H2ConnectionError::StreamClosed { stream_id });
+        }
+
+        if let Some(reason) = malformed {
+            debug!(%stream_id, "malformed response: {reason}");
+            _ = stream
+                .events
+                .send(Err(eyre::eyre!("malformed response: {reason}")));
+            return self.rst(stream_id, KnownErrorCode::ProtocolError).await;
+        }
+
+        let event = if stream.got_final {
+            if !end_stream {
+                _ = stream.events.send(Err(eyre::eyre!(
+                    "server sent trailers without ending the stream"
+                )));
+                return self.rst(stream_id, KnownErrorCode::ProtocolError).await;
+            }
+            StreamEvent::Trailers(Box::new(headers))
+        } else {
+            let Some(status) = status else {
+                _ = stream
+                    .events
+                    .send(Err(eyre::eyre!("server sent a response without :status")));
+                return self.rst(stream_id, KnownErrorCode::ProtocolError).await;
+            };
+            stream.got_final = !status.is_informational();
+            StreamEvent::Headers(Response {
+                version: Version::HTTP_2,
+                status,
+                headers,
+            })
+        };
+        _ = stream.events.send(Ok(event));
+
+        if end_stream {
+            self.close_recv(stream_id);
+        }
+        Ok(())
+    }
+
+    async fn rst(
+        &mut self,
+        stream_id: StreamId,
+        code: KnownErrorCode,
+    ) -> Result<(), H2ConnectionError> {
+        self.streams.remove(&stream_id);
+        self.pending_data.retain(|(id, ..)| *id != stream_id);
+
+        let payload = self.out_scratch.put_to_roll(4, |mut slice| {
+            slice.write_u32::<BigEndian>(code.repr())?;
+            Ok(())
+        })?;
+        let frame = Frame::new(FrameType::RstStream, stream_id);
+        self.write_frame(frame, payload).await
+    }
+
+    async fn send_window_update(
+        &mut self,
+        stream_id: StreamId,
+        increment: u32,
+    ) -> Result<(), H2ConnectionError> {
+        let payload = self.out_scratch.put_to_roll(4, |mut slice| {
+            slice.write_u32::<BigEndian>(increment)?;
+            Ok(())
+        })?;
+        let frame = Frame::new(FrameType::WindowUpdate, stream_id);
+        self.write_frame(frame, payload).await
+    }
+
+    async fn send_goaway(
+        &mut self,
+        error_code: KnownErrorCode,
+        additional_debug_data: &[u8],
+    ) -> Result<(), H2ConnectionError> {
+        // we don't accept streams from the server, so the last one we
+        // processed is always 0
+        let payload =
+            self.out_scratch
+                .put_to_roll(8 + additional_debug_data.len(), |mut slice| {
+                    slice.write_u32::<BigEndian>(0)?;
+                    slice.write_u32::<BigEndian>(error_code.repr())?;
+                    std::io::Write::write_all(&mut slice, additional_debug_data)?;
+                    Ok(())
+                })?;
+        let frame = Frame::new(FrameType::GoAway, StreamId::CONNECTION);
+        self.write_frame(frame, payload).await
+    }
+
+    async fn write_frame(
+        &mut self,
+        mut frame: Frame,
+        payload: impl Into<Piece>,
+    ) -> Result<(), H2ConnectionError> {
+        debug!(?frame, ">");
+        let payload = payload.into();
+
+        // TODO: split HEADERS into CONTINUATION frames past the server's
+        // max_frame_size
+        frame.len = payload.len() as u32;
+        let frame_roll = frame.into_roll(&mut self.out_scratch)?;
+        if payload.is_empty() {
+            self.transport_w
+                .write_all(frame_roll)
+                .await
+                .map_err(H2ConnectionError::WriteError)
+        } else {
+            self.transport_w
+                .writev_all(PieceList::default().with(frame_roll).with(payload))
+                .await
+                .map_err(H2ConnectionError::WriteError)
+        }
+    }
+}
+
+/// A response body read from an h2 stream. Reading it lets the server send
+/// more, cf. [ClientConf::initial_window_size].
+#[derive(Debug)]
+struct H2ClientBody {
+    stream_id: StreamId,
+    content_length: Option<u64>,
+    eof: bool,
+    events: mpsc::UnboundedReceiver<eyre::Result<StreamEvent>>,
+    cmd_tx: mpsc::UnboundedSender<Command>,
+}
+
+impl Body for H2ClientBody {
+    fn content_len(&self) -> Option<u64> {
+        self.content_length
+    }
+
+    fn eof(&self) -> bool {
+        self.eof
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        if self.eof {
+            return Ok(BodyChunk::Done { trailers: None });
+        }
+        let event = self
+            .events
+            .recv()
+            .await
+            .ok_or_else(|| eyre::eyre!("h2 connection closed"))??;
+        match event {
+            StreamEvent::Data(chunk) => {
+                if !chunk.is_empty() {
+                    _ = self.cmd_tx.send(Command::Consumed {
+                        stream_id: self.stream_id,
+                        len: chunk.len() as u32,
+                    });
+                }
+                Ok(BodyChunk::Chunk(chunk))
+            }
+            StreamEvent::Trailers(trailers) => {
+                self.eof = true;
+                Ok(BodyChunk::Done {
+                    trailers: Some(trailers),
+                })
+            }
+            StreamEvent::End => {
+                self.eof = true;
+                Ok(BodyChunk::Done { trailers: None })
+            }
+            StreamEvent::Headers(_) => Err(eyre::eyre!("unexpected headers in response body")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use http::{header, StatusCode};
+    use tokio::sync::{mpsc, oneshot};
+
+    use super::{ClientConf, ClientConnection, SendRequest};
+    use crate::{
+        h1::ClientDriver,
+        h2::parse::PREFACE,
+        maybe_uring::io::{ChanRead, ChanReadSend, ChanWrite},
+        Body, BodyChunk, Method, Request, Response,
+    };
+
+    struct Collect;
+
+    impl ClientDriver for Collect {
+        type Return = (StatusCode, Vec<u8>);
+
+        async fn on_final_response(
+            self,
+            res: Response,
+            body: &mut impl Body,
+        ) -> eyre::Result<Self::Return> {
+            let mut data = vec![];
+            while let BodyChunk::Chunk(chunk) = body.next_chunk().await? {
+                data.extend_from_slice(&chunk[..]);
+            }
+            Ok((res.status, data))
+        }
+    }
+
+    fn frame(ty: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut out = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        out.extend([ty, flags]);
+        out.extend(stream_id.to_be_bytes());
+        out.extend(payload);
+        out
+    }
+
+    /// Reads `(type, flags, stream id, payload)` off what the client wrote
+    async fn next_frame(
+        buf: &mut Vec<u8>,
+        rx: &mut mpsc::Receiver<Vec<u8>>,
+    ) -> (u8, u8, u32, Vec<u8>) {
+        loop {
+            if buf.len() >= 9 {
+                let len = u32::from_be_bytes([0, buf[0], buf[1], buf[2]]) as usize;
+                if buf.len() >= 9 + len {
+                    let stream_id = u32::from_be_bytes(buf[5..9].try_into().unwrap());
+                    let frame = (buf[3], buf[4], stream_id, buf[9..9 + len].to_vec());
+                    buf.drain(..9 + len);
+                    return frame;
+                }
+            }
+            buf.extend(rx.recv().await.expect("client hung up"));
+        }
+    }
+
+    /// Reads the client preface and settings, and sends ours
+    async fn accept(buf: &mut Vec<u8>, rx: &mut mpsc::Receiver<Vec<u8>>, tx: &ChanReadSend) {
+        while buf.len() < PREFACE.len() {
+            buf.extend(rx.recv().await.unwrap());
+        }
+        assert_eq!(&buf[..PREFACE.len()], PREFACE);
+        buf.drain(..PREFACE.len());
+
+        let (ty, flags, _, _) = next_frame(buf, rx).await;
+        assert_eq!((ty, flags), (0x4, 0), "client settings come first");
+        tx.send(frame(0x4, 0, 0, &[])).await.unwrap();
+    }
+
+    #[test]
+    fn test_h2_client_request() {
+        crate::maybe_uring::start(async move {
+            let (server_tx, client_r) = ChanRead::new();
+            let (mut server_rx, client_w) = ChanWrite::new();
+
+            let server = crate::maybe_uring::spawn(async move {
+                let mut buf = vec![];
+                accept(&mut buf, &mut server_rx, &server_tx).await;
+
+                let mut dec = fluke_hpack::Decoder::new();
+                let (stream_id, block) = loop {
+                    match next_frame(&mut buf, &mut server_rx).await {
+                        (0x1, flags, stream_id, block) => {
+                            assert_eq!(flags & 0x1, 0x1, "request without a body ends the stream");
+                            break (stream_id, block);
+                        }
+                        (0x4, flags, ..) => assert_eq!(flags, 0x1),
+                        other => panic!("unexpected frame {other:?}"),
+                    }
+                };
+                assert_eq!(stream_id, 1);
+                let headers = dec.decode(&block).unwrap();
+                let get = |name: &[u8]| {
+                    headers
+                        .iter()
+                        .find(|(n, _)| n == name)
+                        .map(|(_, v)| String::from_utf8(v.clone()).unwrap())
+                };
+                assert_eq!(get(b":method").as_deref(), Some("GET"));
+                assert_eq!(get(b":scheme").as_deref(), Some("http"));
+                assert_eq!(get(b":path").as_deref(), Some("/hello?a=b"));
+                assert_eq!(get(b":authority").as_deref(), Some("example.org"));
+                assert_eq!(get(b"host"), None);
+                assert_eq!(get(b"connection"), None);
+                assert_eq!(get(b"x-custom").as_deref(), Some("1"));
+
+                let mut enc = fluke_hpack::Encoder::new();
+                let early = enc.encode([(&b":status"[..], &b"103"[..])]);
+                server_tx.send(frame(0x1, 0x4, 1, &early)).await.unwrap();
+                let block = enc.encode([
+                    (&b":status"[..], &b"200"[..]),
+                    (&b"content-length"[..], &b"5"[..]),
+                ]);
+                server_tx.send(frame(0x1, 0x4, 1, &block)).await.unwrap();
+                server_tx.send(frame(0x0, 0x1, 1, b"hello")).await.unwrap();
+
+                // the body is read, then the connection is dropped
+                loop {
+                    match next_frame(&mut buf, &mut server_rx).await {
+                        (0x4, 0x1, ..) => {}
+                        (0x8, _, 0, increment) => assert_eq!(increment, 5u32.to_be_bytes()),
+                        (0x7, _, 0, payload) => {
+                            assert_eq!(&payload[4..8], &0u32.to_be_bytes(), "NO_ERROR");
+                            break;
+                        }
+                        other => panic!("unexpected frame {other:?}"),
+                    }
+                }
+            });
+
+            let client =
+                ClientConnection::connect((client_r, client_w), Rc::new(ClientConf::default()))
+                    .await
+                    .unwrap();
+
+            let mut req = Request {
+                method: Method::Get,
+                uri: "/hello?a=b".parse().unwrap(),
+                ..Default::default()
+            };
+            req.headers.insert(header::HOST, "example.org".into());
+            req.headers.insert(header::CONNECTION, "keep-alive".into());
+            req.headers.insert("x-custom", "1".into());
+
+            let (status, body) = client.request(req, &mut (), Collect).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, b"hello");
+
+            drop(client);
+            server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_client_concurrent_requests() {
+        crate::maybe_uring::start(async move {
+            let (server_tx, client_r) = ChanRead::new();
+            let (mut server_rx, client_w) = ChanWrite::new();
+
+            let server = crate::maybe_uring::spawn(async move {
+                let mut buf = vec![];
+                accept(&mut buf, &mut server_rx, &server_tx).await;
+
+                // both requests are in flight before either is answered
+                let mut dec = fluke_hpack::Decoder::new();
+                let mut streams = vec![];
+                while streams.len() < 2 {
+                    match next_frame(&mut buf, &mut server_rx).await {
+                        (0x1, _, stream_id, block) => {
+                            let headers = dec.decode(&block).unwrap();
+                            let (_, path) = headers.iter().find(|(n, _)| n == b":path").unwrap();
+                            streams.push((stream_id, path.clone()));
+                        }
+                        (0x4, 0x1, ..) => {}
+                        other => panic!("unexpected frame {other:?}"),
+                    }
+                }
+                streams.sort();
+                assert_eq!(
+                    streams.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+                    [1, 3],
+                    "client stream ids are odd and go up"
+                );
+
+                // answered in reverse, with their bodies interleaved
+                let mut enc = fluke_hpack::Encoder::new();
+                for (stream_id, _) in streams.iter().rev() {
+                    let block = enc.encode([(&b":status"[..], &b"200"[..])]);
+                    server_tx
+                        .send(frame(0x1, 0x4, *stream_id, &block))
+                        .await
+                        .unwrap();
+                }
+                for (stream_id, path) in streams.iter().rev() {
+                    server_tx
+                        .send(frame(0x0, 0, *stream_id, path))
+                        .await
+                        .unwrap();
+                }
+                for (stream_id, _) in &streams {
+                    server_tx
+                        .send(frame(0x0, 0x1, *stream_id, b"!"))
+                        .await
+                        .unwrap();
+                }
+
+                loop {
+                    match next_frame(&mut buf, &mut server_rx).await {
+                        (0x7, ..) => break,
+                        (0x4 | 0x8, ..) => {}
+                        other => panic!("unexpected frame {other:?}"),
+                    }
+                }
+            });
+
+            let client =
+                ClientConnection::connect((client_r, client_w), Rc::new(ClientConf::default()))
+                    .await
+                    .unwrap();
+
+            let get = |send_request: SendRequest, path: &'static str| async move {
+                let req = Request {
+                    method: Method::Get,
+                    uri: path.parse().unwrap(),
+                    ..Default::default()
+                };
+                send_request.request(req, &mut (), Collect).await.unwrap()
+            };
+            let a = crate::maybe_uring::spawn(get(client.send_request(), "/a"));
+            let b = crate::maybe_uring::spawn(get(client.send_request(), "/b"));
+            drop(client);
+
+            assert_eq!(a.await.unwrap(), (StatusCode::OK, b"/a!".to_vec()));
+            assert_eq!(b.await.unwrap(), (StatusCode::OK, b"/b!".to_vec()));
+            server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_client_max_concurrent_streams() {
+        crate::maybe_uring::start(async move {
+            let (server_tx, client_r) = ChanRead::new();
+            let (mut server_rx, client_w) = ChanWrite::new();
+            let (settled_tx, settled_rx) = oneshot::channel();
+
+            let server = crate::maybe_uring::spawn(async move {
+                let mut buf = vec![];
+                while buf.len() < PREFACE.len() {
+                    buf.extend(server_rx.recv().await.unwrap());
+                }
+                buf.drain(..PREFACE.len());
+                assert_eq!(next_frame(&mut buf, &mut server_rx).await.0, 0x4);
+                // a strict server: one stream at a time
+                let max_concurrent_streams = [0, 0x3, 0, 0, 0, 1];
+                server_tx
+                    .send(frame(0x4, 0, 0, &max_concurrent_streams))
+                    .await
+                    .unwrap();
+                assert_eq!(next_frame(&mut buf, &mut server_rx).await.0, 0x4);
+                settled_tx.send(()).unwrap();
+
+                let mut enc = fluke_hpack::Encoder::new();
+                for expected_id in [1, 3, 5] {
+                    let stream_id = loop {
+                        match next_frame(&mut buf, &mut server_rx).await {
+                            (0x1, _, stream_id, _) => break stream_id,
+                            (0x8, ..) => {}
+                            other => panic!("unexpected frame {other:?}"),
+                        }
+                    };
+                    assert_eq!(stream_id, expected_id);
+
+                    // the client is kept waiting on a ping: any HEADERS it
+                    // sends meanwhile would open a second stream
+                    let block = enc.encode([(&b":status"[..], &b"200"[..])]);
+                    server_tx
+                        .send(frame(0x1, 0x4, stream_id, &block))
+                        .await
+                        .unwrap();
+                    server_tx.send(frame(0x6, 0, 0, &[0; 8])).await.unwrap();
+                    loop {
+                        match next_frame(&mut buf, &mut server_rx).await {
+                            (0x6, 0x1, ..) => break,
+                            (0x8, ..) => {}
+                            other => panic!("unexpected frame {other:?}"),
+                        }
+                    }
+                    server_tx
+                        .send(frame(0x0, 0x1, stream_id, b"ok"))
+                        .await
+                        .unwrap();
+                }
+
+                loop {
+                    match next_frame(&mut buf, &mut server_rx).await {
+                        (0x7, ..) => break,
+                        (0x8, ..) => {}
+                        other => panic!("unexpected frame {other:?}"),
+                    }
+                }
+            });
+
+            let client =
+                ClientConnection::connect((client_r, client_w), Rc::new(ClientConf::default()))
+                    .await
+                    .unwrap();
+            settled_rx.await.unwrap();
+
+            let get = |send_request: SendRequest| async move {
+                let req = Request {
+                    method: Method::Get,
+                    uri: "/".parse().unwrap(),
+                    ..Default::default()
+                };
+                send_request.request(req, &mut (), Collect).await.unwrap()
+            };
+            let requests: Vec<_> = (0..3)
+                .map(|_| crate::maybe_uring::spawn(get(client.send_request())))
+                .collect();
+            drop(client);
+
+            for request in requests {
+                assert_eq!(request.await.unwrap(), (StatusCode::OK, b"ok".to_vec()));
+            }
+            server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_client_data_before_headers() {
+        crate::maybe_uring::start(async move {
+            let (server_tx, client_r) = ChanRead::new();
+            let (mut server_rx, client_w) = ChanWrite::new();
+
+            let server = crate::maybe_uring::spawn(async move {
+                let mut buf = vec![];
+                accept(&mut buf, &mut server_rx, &server_tx).await;
+
+                let stream_id = loop {
+                    match next_frame(&mut buf, &mut server_rx).await {
+                        (0x1, _, stream_id, _) => break stream_id,
+                        (0x4, 0x1, ..) => {}
+                        other => panic!("unexpected frame {other:?}"),
+                    }
+                };
+                // padded: 1 byte of pad length, 5 of data, 4 of padding
+                let mut padded = vec![4];
+                padded.extend(b"hello");
+                padded.extend([0; 4]);
+                server_tx
+                    .send(frame(0x0, 0x8, stream_id, &padded))
+                    .await
+                    .unwrap();
+
+                let mut credited = 0;
+                loop {
+                    match next_frame(&mut buf, &mut server_rx).await {
+                        (0x3, _, id, code) => {
+                            assert_eq!(id, stream_id);
+                            assert_eq!(code, 1u32.to_be_bytes(), "PROTOCOL_ERROR");
+                        }
+                        (0x8, _, 0, increment) => {
+                            credited += u32::from_be_bytes(increment.try_into().unwrap());
+                        }
+                        (0x7, ..) => break,
+                        other => panic!("unexpected frame {other:?}"),
+                    }
+                }
+                assert_eq!(credited, 10, "the whole frame, padding included");
+            });
+
+            let client =
+                ClientConnection::connect((client_r, client_w), Rc::new(ClientConf::default()))
+                    .await
+                    .unwrap();
+
+            let req = Request {
+                method: Method::Get,
+                uri: "/".parse().unwrap(),
+                ..Default::default()
+            };
+            let err = client.request(req, &mut (), Collect).await.unwrap_err();
+            assert!(err.to_string().contains("before its headers"), "{err}");
+
+            drop(client);
+            server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_client_padding_credited() {
+        crate::maybe_uring::start(async move {
+            let (server_tx, client_r) = ChanRead::new();
+            let (mut server_rx, client_w) = ChanWrite::new();
+
+            let server = crate::maybe_uring::spawn(async move {
+                let mut buf = vec![];
+                accept(&mut buf, &mut server_rx, &server_tx).await;
+
+                let stream_id = loop {
+                    match next_frame(&mut buf, &mut server_rx).await {
+                        (0x1, _, stream_id, _) => break stream_id,
+                        (0x4, 0x1, ..) => {}
+                        other => panic!("unexpected frame {other:?}"),
+                    }
+                };
+                let mut enc = fluke_hpack::Encoder::new();
+                let block = enc.encode([(&b":status"[..], &b"200"[..])]);
+                server_tx
+                    .send(frame(0x1, 0x4, stream_id, &block))
+                    .await
+                    .unwrap();
+                let mut padded = vec![4];
+                padded.extend(b"hello");
+                padded.extend([0; 4]);
+                server_tx
+                    .send(frame(0x0, 0x8 | 0x1, stream_id, &padded))
+                    .await
+                    .unwrap();
+
+                let mut credited = 0;
+                loop {
+                    match next_frame(&mut buf, &mut server_rx).await {
+                        (0x8, _, 0, increment) => {
+                            credited += u32::from_be_bytes(increment.try_into().unwrap());
+                        }
+                        (0x8, ..) => {}
+                        (0x7, ..) => break,
+                        other => panic!("unexpected frame {other:?}"),
+                    }
+                }
+                assert_eq!(credited, 10, "the whole frame, padding included");
+            });
+
+            let client =
+                ClientConnection::connect((client_r, client_w), Rc::new(ClientConf::default()))
+                    .await
+                    .unwrap();
+
+            let req = Request {
+                method: Method::Get,
+                uri: "/".parse().unwrap(),
+                ..Default::default()
+            };
+            let (status, body) = client.request(req, &mut (), Collect).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, b"hello");
+
+            drop(client);
+            server.await.unwrap();
+        });
+    }
+}
@@ -1,12 +1,14 @@
+use std::{cell::Cell, rc::Rc};
+
 use http::{StatusCode, Version};
 use tokio::sync::mpsc;
 use tracing::debug;
 
 use super::{
     parse::StreamId,
-    types::{H2Event, H2EventPayload},
+    types::{H2Event, H2EventPayload, StreamWindow},
 };
-use crate::{h1::body::BodyWriteMode, Encoder, Response};
+use crate::{BodyWriteMode, CorrelationId, Encoder, Response};
 
 pub(crate) enum EncoderState {
     ExpectResponseHeaders,
@@ -18,6 +20,14 @@ pub struct H2Encoder {
     pub(crate) stream_id: StreamId,
     pub(crate) tx: mpsc::Sender<H2Event>,
     pub(crate) state: EncoderState,
+    pub(crate) window: Rc<StreamWindow>,
+
+    /// Echoed in the final response, see [ServerConf::request_id_header](super::ServerConf::request_id_header)
+    pub(crate) correlation_id: Option<CorrelationId>,
+
+    /// Set when dropped before response headers were written, for the
+    /// stream's task to answer with [ServerDriver::error_response](crate::ServerDriver::error_response)
+    pub(crate) unanswered: Rc<Cell<bool>>,
 }
 
 impl H2Encoder {
@@ -38,12 +48,28 @@ impl H2Encoder {
 }
 
 impl Encoder for H2Encoder {
-    async fn write_response(&mut self, res: Response) -> eyre::Result<()> {
+    async fn write_response(&mut self, mut res: Response) -> eyre::Result<()> {
         // TODO: don't panic here
         assert!(matches!(self.state, EncoderState::ExpectResponseHeaders));
 
+        // there's no upgrading an h2 stream, cf. <https://httpwg.org/specs/rfc9113.html#informational-responses>
+        if res.status == StatusCode::SWITCHING_PROTOCOLS {
+            return Err(eyre::eyre!(
+                "101 Switching Protocols can't be sent over HTTP/2"
+            ));
+        }
+
+        let informational = res.status.is_informational();
+        if let (false, Some(correlation_id)) = (informational, &self.correlation_id) {
+            correlation_id.apply(&mut res.headers);
+        }
+
+        // interim responses are HEADERS frames of their own, without
+        // END_STREAM, and the final response headers still follow them
         self.send(H2EventPayload::Headers(res)).await?;
-        self.state = EncoderState::ExpectResponseBody;
+        if !informational {
+            self.state = EncoderState::ExpectResponseBody;
+        }
 
         Ok(())
     }
@@ -56,7 +82,16 @@ impl Encoder for H2Encoder {
     ) -> eyre::Result<()> {
         assert!(matches!(self.state, EncoderState::ExpectResponseBody));
 
-        self.send(H2EventPayload::BodyChunk(chunk)).await?;
+        // only hand the connection as much as the stream's send window
+        // allows, and no more than it can queue: while it can't write any of
+        // it out, the handler waits here instead of producing more
+        let mut chunk = chunk;
+        while !chunk.is_empty() {
+            let n = self.window.reserve(chunk.len()).await?;
+            let (head, tail) = chunk.split_at(n);
+            self.send(H2EventPayload::BodyChunk(head)).await?;
+            chunk = tail;
+        }
         Ok(())
     }
 
@@ -70,19 +105,32 @@ impl Encoder for H2Encoder {
         Ok(())
     }
 
-    // TODO: handle trailers
-    async fn write_trailers(&mut self, _trailers: Box<crate::Headers>) -> eyre::Result<()> {
-        assert!(matches!(self.state, EncoderState::ResponseDone));
+    async fn write_trailers(&mut self, trailers: Box<crate::Headers>) -> eyre::Result<()> {
+        assert!(matches!(self.state, EncoderState::ExpectResponseBody));
+
+        self.send(H2EventPayload::BodyEndWithTrailers(*trailers))
+            .await?;
+        self.state = EncoderState::ResponseDone;
 
-        todo!("write trailers")
+        Ok(())
     }
 }
 
+/// A handler that drops its responder without finishing the response (by
+/// returning an error, or panicking) gets a response sent on its behalf if
+/// it hadn't sent response headers yet: the stream's task sends the driver's
+/// error response, or a plain 500 is sent from here when panicking, as the
+/// task won't be around for it. Otherwise, the stream is reset with
+/// INTERNAL_ERROR, so the client doesn't mistake what it got for a complete
+/// response.
 impl Drop for H2Encoder {
     fn drop(&mut self) {
         let mut evs = vec![];
 
         match self.state {
+            EncoderState::ExpectResponseHeaders if !std::thread::panicking() => {
+                self.unanswered.set(true);
+            }
             EncoderState::ExpectResponseHeaders => {
                 evs.push(self.event(H2EventPayload::Headers(Response {
                     version: Version::HTTP_11,
@@ -92,8 +140,7 @@ impl Drop for H2Encoder {
                 evs.push(self.event(H2EventPayload::BodyEnd));
             }
             EncoderState::ExpectResponseBody => {
-                // TODO: this should probably be RST_STREAM instead
-                evs.push(self.event(H2EventPayload::BodyEnd));
+                evs.push(self.event(H2EventPayload::Abort));
             }
             EncoderState::ResponseDone => {
                 // ah, good.
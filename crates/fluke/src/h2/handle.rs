@@ -0,0 +1,262 @@
+use std::{cell::Cell, rc::Rc, time::Duration};
+
+use tokio::sync::Notify;
+
+use super::{parse::Settings, ConnStats, ControlFrameKind};
+
+/// A handle to a connection being served by [serve_with_handle](super::serve_with_handle),
+/// that lets embedders inspect and steer it from the outside.
+///
+/// Cloning it is cheap, all clones refer to the same connection.
+#[derive(Clone, Default)]
+pub struct ConnectionHandle {
+    inner: Rc<ConnectionHandleInner>,
+}
+
+#[derive(Default)]
+struct ConnectionHandleInner {
+    last_stream_id: Cell<u32>,
+    refuse_new_streams: Cell<bool>,
+    reaped_streams: Cell<u64>,
+    open_streams: Cell<usize>,
+    running_handlers: Cell<usize>,
+
+    /// Settings changes waiting to be sent to the peer
+    settings_update: Cell<Option<SettingsUpdate>>,
+    settings_update_notify: Notify,
+    settings_ack_pending: Cell<bool>,
+
+    /// Set by [ConnectionHandle::shutdown], until the connection picks it up
+    shutdown: Cell<Option<Duration>>,
+    shutdown_notify: Notify,
+    shutting_down: Cell<bool>,
+
+    /// Set once either side sent GOAWAY
+    draining: Cell<bool>,
+    draining_notify: Notify,
+
+    stats: Cell<ConnStats>,
+}
+
+/// Settings that can be changed while a connection is being served, see
+/// [ConnectionHandle::update_settings]. Fields left to `None` keep their
+/// current value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SettingsUpdate {
+    /// SETTINGS_MAX_CONCURRENT_STREAMS. Lowering it doesn't affect streams
+    /// that are already open: new ones get refused until enough of those
+    /// are done.
+    pub max_concurrent_streams: Option<u32>,
+
+    /// SETTINGS_INITIAL_WINDOW_SIZE, at most 2^31-1
+    pub initial_window_size: Option<u32>,
+
+    /// SETTINGS_MAX_FRAME_SIZE, between 2^14 and 2^24-1
+    pub max_frame_size: Option<u32>,
+
+    /// SETTINGS_HEADER_TABLE_SIZE. The peer tells us when it shrinks its
+    /// HPACK dynamic table to fit, so lowering it only frees memory once the
+    /// peer has sent its next header block.
+    pub header_table_size: Option<u32>,
+}
+
+impl ConnectionHandle {
+    /// The id of the last stream that was accepted on this connection, or 0
+    /// if none were accepted yet. This is what gets sent in GOAWAY frames.
+    pub fn last_stream_id(&self) -> u32 {
+        self.inner.last_stream_id.get()
+    }
+
+    /// Start refusing new streams with REFUSED_STREAM, which clients can
+    /// safely retry on another connection. Streams that were already accepted
+    /// keep being served. This is typically used to drain a connection before
+    /// sending GOAWAY and closing it.
+    pub fn refuse_new_streams(&self) {
+        self.inner.refuse_new_streams.set(true);
+    }
+
+    /// Undo [ConnectionHandle::refuse_new_streams]
+    pub fn accept_new_streams(&self) {
+        self.inner.refuse_new_streams.set(false);
+    }
+
+    /// Whether new streams are currently being refused
+    pub fn is_refusing_new_streams(&self) -> bool {
+        self.inner.refuse_new_streams.get()
+    }
+
+    /// How many streams were reset with NO_ERROR because their response was
+    /// complete and their request body was being ignored, while the peer
+    /// hadn't ended its side. A steadily growing count points at handlers
+    /// that respond without reading request bodies.
+    pub fn reaped_streams(&self) -> u64 {
+        self.inner.reaped_streams.get()
+    }
+
+    /// How many streams the connection is currently keeping state for,
+    /// whether they're open or half-closed
+    pub fn open_streams(&self) -> usize {
+        self.inner.open_streams.get()
+    }
+
+    /// How many driver handlers are currently running for this connection.
+    /// A handler may outlive its stream, e.g. if it keeps going after the
+    /// peer reset it.
+    pub fn running_handlers(&self) -> usize {
+        self.inner.running_handlers.get()
+    }
+
+    /// Counters for this connection so far
+    pub fn stats(&self) -> ConnStats {
+        self.inner.stats.get()
+    }
+
+    /// Sends the peer new settings, e.g. to accept fewer concurrent streams
+    /// while the server is overloaded. Updates made before the connection
+    /// gets to send them are merged.
+    ///
+    /// New settings only take effect once the peer acknowledges them: until
+    /// then, it may still be acting on the previous ones, and we keep
+    /// accepting what they allow.
+    pub fn update_settings(&self, update: SettingsUpdate) -> eyre::Result<()> {
+        if let Some(size) = update.initial_window_size {
+            if size > Settings::MAX_INITIAL_WINDOW_SIZE {
+                return Err(eyre::eyre!("initial window size {size} is too large"));
+            }
+        }
+        if let Some(size) = update.max_frame_size {
+            if !Settings::MAX_FRAME_SIZE_ALLOWED_RANGE.contains(&size) {
+                return Err(eyre::eyre!("max frame size {size} is out of range"));
+            }
+        }
+
+        let pending = self.inner.settings_update.get().unwrap_or_default();
+        self.inner.settings_update.set(Some(SettingsUpdate {
+            max_concurrent_streams: update
+                .max_concurrent_streams
+                .or(pending.max_concurrent_streams),
+            initial_window_size: update.initial_window_size.or(pending.initial_window_size),
+            max_frame_size: update.max_frame_size.or(pending.max_frame_size),
+            header_table_size: update.header_table_size.or(pending.header_table_size),
+        }));
+        self.inner.settings_update_notify.notify_one();
+        Ok(())
+    }
+
+    /// Shuts the connection down gracefully: new streams get refused, the
+    /// peer is sent GOAWAY with [ConnectionHandle::last_stream_id] (so it
+    /// knows which of its requests it can retry elsewhere), then the
+    /// connection is closed once every accepted stream is done and its
+    /// handler returned, or once `drain_timeout` has elapsed, whichever comes
+    /// first.
+    ///
+    /// [serve_with_handle](super::serve_with_handle) returns once the
+    /// connection is closed. Calling this again while the connection is
+    /// draining does nothing.
+    pub fn shutdown(&self, drain_timeout: Duration) {
+        if self.inner.shutting_down.get() {
+            return;
+        }
+        self.inner.shutting_down.set(true);
+        self.refuse_new_streams();
+        self.inner.shutdown.set(Some(drain_timeout));
+        self.inner.shutdown_notify.notify_one();
+    }
+
+    /// Whether [ConnectionHandle::shutdown] was called
+    pub fn is_shutting_down(&self) -> bool {
+        self.inner.shutting_down.get()
+    }
+
+    /// Whether the connection is winding down: either side sent GOAWAY (we
+    /// do on [ConnectionHandle::shutdown]), so no new streams will be
+    /// served, and the connection closes once the accepted ones are done.
+    ///
+    /// Drivers that hold on to a clone of this handle can use it to cut
+    /// long-running responses (streams, long polls) short.
+    pub fn is_draining(&self) -> bool {
+        self.inner.draining.get()
+    }
+
+    /// Resolves once the connection starts draining, see
+    /// [ConnectionHandle::is_draining]
+    pub async fn draining(&self) {
+        let notified = self.inner.draining_notify.notified();
+        if self.is_draining() {
+            return;
+        }
+        notified.await
+    }
+
+    /// Whether we sent settings (initial ones, or from
+    /// [ConnectionHandle::update_settings]) that the peer hasn't
+    /// acknowledged yet
+    pub fn is_settings_ack_pending(&self) -> bool {
+        self.inner.settings_ack_pending.get()
+    }
+
+    /// Resolves once there's a settings update to send, see
+    /// [ConnectionHandle::take_settings_update]
+    pub(crate) async fn settings_updated(&self) {
+        self.inner.settings_update_notify.notified().await
+    }
+
+    pub(crate) fn take_settings_update(&self) -> Option<SettingsUpdate> {
+        self.inner.settings_update.take()
+    }
+
+    /// Resolves once [ConnectionHandle::shutdown] is called, see
+    /// [ConnectionHandle::take_shutdown]
+    pub(crate) async fn shutdown_requested(&self) {
+        self.inner.shutdown_notify.notified().await
+    }
+
+    /// The drain timeout passed to [ConnectionHandle::shutdown], if it was
+    /// called and nobody took it yet
+    pub(crate) fn take_shutdown(&self) -> Option<Duration> {
+        self.inner.shutdown.take()
+    }
+
+    pub(crate) fn set_draining(&self) {
+        if !self.inner.draining.replace(true) {
+            self.inner.draining_notify.notify_waiters();
+        }
+    }
+
+    pub(crate) fn set_settings_ack_pending(&self, pending: bool) {
+        self.inner.settings_ack_pending.set(pending);
+    }
+
+    pub(crate) fn record_reaped_stream(&self) {
+        self.inner
+            .reaped_streams
+            .set(self.inner.reaped_streams.get() + 1);
+    }
+
+    pub(crate) fn record_control_frame(&self, kind: ControlFrameKind) {
+        let mut stats = self.inner.stats.get();
+        match kind {
+            ControlFrameKind::Ping => stats.pings_received += 1,
+            ControlFrameKind::Settings => stats.settings_received += 1,
+            ControlFrameKind::WindowUpdate => stats.window_updates_received += 1,
+            ControlFrameKind::RstStream => stats.rst_streams_received += 1,
+            ControlFrameKind::PriorityUpdate => stats.priority_updates_received += 1,
+        }
+        self.inner.stats.set(stats);
+    }
+
+    pub(crate) fn record_control_frame_flood(&self, kind: ControlFrameKind) {
+        let mut stats = self.inner.stats.get();
+        stats.control_frame_flood = Some(kind);
+        self.inner.stats.set(stats);
+    }
+
+    pub(crate) fn set_occupancy(&self, open_streams: usize, running_handlers: usize) {
+        self.inner.open_streams.set(open_streams);
+        self.inner.running_handlers.set(running_handlers);
+    }
+
+    pub(crate) fn set_last_stream_id(&self, stream_id: u32) {
+        self.inner.last_stream_id.set(stream_id);
+    }
+}
@@ -4,7 +4,25 @@
 mod server;
 pub use server::*;
 
+#[cfg(feature = "client")]
+mod client;
+#[cfg(feature = "client")]
+pub use client::*;
+
+mod handle;
+pub use handle::*;
+
+mod stats;
+pub use stats::*;
+
+mod priority;
+pub use priority::*;
+
+mod observe;
+pub use observe::*;
+
 pub(crate) mod parse;
+pub use parse::{KnownErrorCode, StreamId};
 
 mod body;
 mod encode;
@@ -0,0 +1,35 @@
+use fluke_maybe_uring::io::ConnInfo;
+
+use super::parse::{KnownErrorCode, StreamId};
+
+/// Gets told about the errors that end h2 streams and connections, which
+/// are otherwise only logged: counting them per peer is how abusive or
+/// broken clients get spotted. See `error_observer` in
+/// [ServerConf](super::ServerConf).
+///
+/// Both methods do nothing by default.
+pub trait ErrorObserver {
+    /// The connection is being closed with GOAWAY because of `err`: the peer
+    /// broke the protocol, didn't acknowledge our settings in time, or
+    /// something went wrong on our side (`InternalError`).
+    fn on_connection_error(&self, conn: &ConnInfo, err: &ProtocolError) {
+        _ = (conn, err);
+    }
+
+    /// `stream_id` is being reset because of `err`. Not all resets are the
+    /// peer's fault: streams refused because we're busy or draining have
+    /// `RefusedStream`, those whose request body is ignored `NoError`.
+    fn on_stream_reset(&self, conn: &ConnInfo, stream_id: StreamId, err: &ProtocolError) {
+        _ = (conn, stream_id, err);
+    }
+}
+
+/// An error as sent to the peer, see [ErrorObserver]
+#[derive(Debug, Clone)]
+pub struct ProtocolError {
+    /// What the peer is told
+    pub code: KnownErrorCode,
+
+    /// What went wrong, as logged
+    pub message: String,
+}
@@ -2,8 +2,14 @@
 //!
 //! HTTP/2 <https://httpwg.org/specs/rfc9113.html>
 //! HTTP semantics <https://httpwg.org/specs/rfc9110.html>
+//!
+//! Parsers here read from any [ParseInput]: a [Roll] when serving
+//! connections, or a plain byte slice, e.g. in tests.
 
-use std::{fmt, ops::RangeInclusive};
+use std::{
+    fmt,
+    ops::{RangeFrom, RangeInclusive},
+};
 
 use enum_repr::EnumRepr;
 use enumflags2::{bitflags, BitFlags};
@@ -11,15 +17,37 @@ use nom::{
     combinator::map,
     number::streaming::{be_u16, be_u24, be_u32, be_u8},
     sequence::tuple,
-    IResult,
+    Compare, Finish, IResult, InputIter, InputLength, InputTake, Slice,
 };
 
 use fluke_buffet::{Roll, RollMut};
 
+/// What frames can be parsed from: a [Roll] when serving connections, or a
+/// `&[u8]` anywhere else.
+pub trait ParseInput:
+    Clone
+    + InputLength
+    + InputTake
+    + InputIter<Item = u8>
+    + Slice<RangeFrom<usize>>
+    + for<'a> Compare<&'a [u8]>
+{
+}
+
+impl<I> ParseInput for I where
+    I: Clone
+        + InputLength
+        + InputTake
+        + InputIter<Item = u8>
+        + Slice<RangeFrom<usize>>
+        + for<'a> Compare<&'a [u8]>
+{
+}
+
 /// This is sent by h2 clients after negotiating over ALPN, or when doing h2c.
 pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
 
-pub fn preface(i: Roll) -> IResult<Roll, ()> {
+pub fn preface<I: ParseInput>(i: I) -> IResult<I, ()> {
     let (i, _) = nom::bytes::streaming::tag(PREFACE)(i)?;
     Ok((i, ()))
 }
@@ -38,6 +66,8 @@ pub enum RawFrameType {
     GoAway = 0x07,
     WindowUpdate = 0x08,
     Continuation = 0x09,
+    /// cf. https://www.rfc-editor.org/rfc/rfc9218#section-7.1
+    PriorityUpdate = 0x10,
 }
 
 /// Typed flags for various frame types
@@ -53,6 +83,7 @@ pub enum FrameType {
     GoAway,
     WindowUpdate,
     Continuation(BitFlags<ContinuationFlags>),
+    PriorityUpdate,
     Unknown(EncodedFrameType),
 }
 
@@ -107,7 +138,7 @@ pub struct EncodedFrameType {
 }
 
 impl EncodedFrameType {
-    fn parse(i: Roll) -> IResult<Roll, Self> {
+    fn parse<I: ParseInput>(i: I) -> IResult<I, Self> {
         let (i, (ty, flags)) = tuple((be_u8, be_u8))(i)?;
         Ok((i, Self { ty, flags }))
     }
@@ -135,6 +166,7 @@ impl FrameType {
             FrameType::GoAway => (RawFrameType::GoAway, 0).into(),
             FrameType::WindowUpdate => (RawFrameType::WindowUpdate, 0).into(),
             FrameType::Continuation(f) => (RawFrameType::Continuation, f.bits()).into(),
+            FrameType::PriorityUpdate => (RawFrameType::PriorityUpdate, 0).into(),
             FrameType::Unknown(ft) => ft,
         }
     }
@@ -162,6 +194,7 @@ impl FrameType {
                 RawFrameType::Continuation => FrameType::Continuation(
                     BitFlags::<ContinuationFlags>::from_bits_truncate(ft.flags),
                 ),
+                RawFrameType::PriorityUpdate => FrameType::PriorityUpdate,
             },
             None => FrameType::Unknown(ft),
         }
@@ -236,6 +269,7 @@ impl fmt::Debug for Frame {
             FrameType::GoAway => "GoAway",
             FrameType::WindowUpdate => "WindowUpdate",
             FrameType::Continuation(_) => "Continuation",
+            FrameType::PriorityUpdate => "PriorityUpdate",
             FrameType::Unknown(EncodedFrameType { ty, flags }) => {
                 return write!(f, "UnknownFrame({:#x}, {:#x})", ty, flags)
             }
@@ -314,7 +348,7 @@ impl Frame {
     /// Parse a frame from the given slice. This also takes the payload from the
     /// slice, and copies it to the heap, which may not be ideal for a production
     /// implementation.
-    pub fn parse(i: Roll) -> IResult<Roll, Self> {
+    pub fn parse<I: ParseInput>(i: I) -> IResult<I, Self> {
         let (i, (len, frame_type, (reserved, stream_id))) = tuple((
             be_u24,
             EncodedFrameType::parse,
@@ -351,19 +385,19 @@ impl Frame {
 
 /// See https://httpwg.org/specs/rfc9113.html#FrameHeader - the first bit
 /// is reserved, and the rest is a 31-bit stream id
-pub fn parse_reserved_and_u31(i: Roll) -> IResult<Roll, (u8, u32)> {
-    fn reserved(i: (Roll, usize)) -> IResult<(Roll, usize), u8> {
+pub fn parse_reserved_and_u31<I: ParseInput>(i: I) -> IResult<I, (u8, u32)> {
+    fn reserved<I: ParseInput>(i: (I, usize)) -> IResult<(I, usize), u8> {
         nom::bits::streaming::take(1_usize)(i)
     }
 
-    fn stream_id(i: (Roll, usize)) -> IResult<(Roll, usize), u32> {
+    fn stream_id<I: ParseInput>(i: (I, usize)) -> IResult<(I, usize), u32> {
         nom::bits::streaming::take(31_usize)(i)
     }
 
     nom::bits::bits(tuple((reserved, stream_id)))(i)
 }
 
-fn parse_reserved_and_stream_id(i: Roll) -> IResult<Roll, (u8, StreamId)> {
+fn parse_reserved_and_stream_id<I: ParseInput>(i: I) -> IResult<I, (u8, StreamId)> {
     parse_reserved_and_u31(i).map(|(i, (reserved, stream_id))| (i, (reserved, StreamId(stream_id))))
 }
 
@@ -377,7 +411,7 @@ pub(crate) struct PrioritySpec {
 }
 
 impl PrioritySpec {
-    pub(crate) fn parse(i: Roll) -> IResult<Roll, Self> {
+    pub(crate) fn parse<I: ParseInput>(i: I) -> IResult<I, Self> {
         map(
             tuple((parse_reserved_and_stream_id, be_u8)),
             |((exclusive, stream_dependency), weight)| Self {
@@ -547,6 +581,12 @@ pub struct Settings {
     /// For any given request, a lower limit than what is advertised MAY be
     /// enforced. The initial value of this setting is unlimited.
     pub max_header_list_size: u32,
+
+    /// SETTINGS_ENABLE_CONNECT_PROTOCOL: whether the sender accepts extended
+    /// CONNECT requests, with a `:protocol` pseudo-header, cf.
+    /// <https://www.rfc-editor.org/rfc/rfc8441#section-3>. Once a sender has
+    /// sent 1, it must not send 0.
+    pub enable_connect_protocol: bool,
 }
 
 impl Default for Settings {
@@ -559,6 +599,7 @@ impl Default for Settings {
             initial_window_size: (1 << 16) - 1,
             max_frame_size: (1 << 14),
             max_header_list_size: 0,
+            enable_connect_protocol: false,
         }
     }
 }
@@ -572,17 +613,19 @@ enum SettingIdentifier {
     InitialWindowSize = 0x04,
     MaxFrameSize = 0x05,
     MaxHeaderListSize = 0x06,
+    EnableConnectProtocol = 0x08,
 }
 
 impl Settings {
-    const MAX_INITIAL_WINDOW_SIZE: u32 = (1 << 31) - 1;
-    const MAX_FRAME_SIZE_ALLOWED_RANGE: RangeInclusive<u32> = (1 << 14)..=((1 << 24) - 1);
+    pub(crate) const MAX_INITIAL_WINDOW_SIZE: u32 = (1 << 31) - 1;
+    pub(crate) const MAX_FRAME_SIZE_ALLOWED_RANGE: RangeInclusive<u32> =
+        (1 << 14)..=((1 << 24) - 1);
 
-    pub fn parse(mut i: Roll) -> IResult<Roll, Self> {
-        tracing::trace!("parsing settings frame, roll length: {}", i.len());
+    pub fn parse<I: ParseInput>(mut i: I) -> IResult<I, Self> {
+        tracing::trace!("parsing settings frame, length: {}", i.input_len());
         let mut settings = Self::default();
 
-        while !i.is_empty() {
+        while i.input_len() > 0 {
             let (rest, (id, value)) = tuple((be_u16, be_u32))(i)?;
             tracing::trace!(%id, %value, "Got setting pair");
             match SettingIdentifier::from_repr(id) {
@@ -632,6 +675,18 @@ impl Settings {
                     SettingIdentifier::MaxHeaderListSize => {
                         settings.max_header_list_size = value;
                     }
+                    SettingIdentifier::EnableConnectProtocol => {
+                        settings.enable_connect_protocol = match value {
+                            0 => false,
+                            1 => true,
+                            _ => {
+                                return Err(nom::Err::Error(nom::error::Error::new(
+                                    rest,
+                                    nom::error::ErrorKind::Digit,
+                                )));
+                            }
+                        }
+                    }
                 },
             }
             i = rest;
@@ -666,6 +721,10 @@ impl Settings {
                 SettingIdentifier::MaxHeaderListSize as u16,
                 self.max_header_list_size,
             ),
+            (
+                SettingIdentifier::EnableConnectProtocol as u16,
+                self.enable_connect_protocol as u32,
+            ),
         ]
         .into_iter()
     }
@@ -689,4 +748,90 @@ impl Settings {
         self.write_into(&mut scratch)?;
         Ok(scratch.take_all())
     }
+
+    /// Decodes the `HTTP2-Settings` header of an `Upgrade: h2c` request: a
+    /// SETTINGS payload in base64url, without padding, cf.
+    /// <https://www.rfc-editor.org/rfc/rfc7540#section-3.2.1>
+    pub(crate) fn from_http2_settings_header(value: &[u8]) -> Option<Self> {
+        let mut payload = Vec::with_capacity(value.len() * 3 / 4);
+        let mut bits: u32 = 0;
+        let mut nbits = 0;
+        for &c in value {
+            let sextet = match c {
+                b'A'..=b'Z' => c - b'A',
+                b'a'..=b'z' => c - b'a' + 26,
+                b'0'..=b'9' => c - b'0' + 52,
+                b'-' => 62,
+                b'_' => 63,
+                _ => return None,
+            };
+            bits = (bits << 6) | sextet as u32;
+            nbits += 6;
+            if nbits >= 8 {
+                nbits -= 8;
+                payload.push((bits >> nbits) as u8);
+            }
+        }
+        // a lone trailing character, or leftover bits that aren't zero, aren't
+        // something an encoder would produce
+        if nbits >= 6 || bits & ((1 << nbits) - 1) != 0 {
+            return None;
+        }
+
+        let (_, settings) = nom::combinator::complete(Self::parse)(&payload[..])
+            .finish()
+            .ok()?;
+        Some(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Frame, FrameType, PingFlags, Settings, StreamId};
+
+    #[test]
+    fn test_h2_parse_from_slice() {
+        let input: &[u8] = &[0, 0, 8, 0x6, 0x1, 0, 0, 0, 0, 0xff];
+        let (rest, frame) = Frame::parse(input).unwrap();
+        assert_eq!(frame.len, 8);
+        assert_eq!(frame.stream_id, StreamId::CONNECTION);
+        assert!(matches!(frame.frame_type, FrameType::Ping(f) if f.contains(PingFlags::Ack)));
+        assert_eq!(rest, &[0xff]);
+
+        let mut payload = vec![];
+        let settings = Settings {
+            max_concurrent_streams: 7,
+            enable_connect_protocol: true,
+            ..Default::default()
+        };
+        settings.write_into(&mut payload).unwrap();
+        let (_, parsed) = Settings::parse(&payload[..]).unwrap();
+        assert_eq!(parsed.max_concurrent_streams, 7);
+        assert!(parsed.enable_connect_protocol);
+    }
+
+    #[test]
+    fn test_h2_settings_from_http2_settings_header() {
+        // as found in the `HTTP2-Settings` header of h2c upgrades
+        let parsed = Settings::from_http2_settings_header(b"AAMAAABkAAQAAP__").unwrap();
+        assert_eq!(parsed.max_concurrent_streams, 100);
+        assert_eq!(parsed.initial_window_size, 65535);
+        // an empty SETTINGS payload is fine
+        let parsed = Settings::from_http2_settings_header(b"").unwrap();
+        assert_eq!(
+            parsed.max_concurrent_streams,
+            Settings::default().max_concurrent_streams
+        );
+
+        // base64url has no padding here, cf. RFC 7540 section 3.2.1
+        assert!(Settings::from_http2_settings_header(b"AAMAAABk=").is_none());
+        assert!(Settings::from_http2_settings_header(b"AAMAAABkAAQAAP__==").is_none());
+        // that's the standard alphabet, not base64url
+        assert!(Settings::from_http2_settings_header(b"AAMAAABkAAQAAP//").is_none());
+        // not a whole setting
+        assert!(Settings::from_http2_settings_header(b"AAMAAA").is_none());
+        // trailing characters that don't make up a byte
+        assert!(Settings::from_http2_settings_header(b"AAMAAABkA").is_none());
+        assert!(Settings::from_http2_settings_header(b"AAMAAABkAB").is_none());
+    }
 }
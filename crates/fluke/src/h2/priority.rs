@@ -0,0 +1,110 @@
+use http::header::HeaderName;
+
+use crate::Headers;
+
+/// The `priority` request header, cf. <https://www.rfc-editor.org/rfc/rfc9218>
+pub const PRIORITY: HeaderName = HeaderName::from_static("priority");
+
+/// Extensible priorities of a request, from its `priority` header or from
+/// PRIORITY_UPDATE frames, cf. <https://www.rfc-editor.org/rfc/rfc9218>.
+///
+/// Over HTTP/2, response DATA of streams with a lower urgency goes out first
+/// when several are waiting on the connection window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Priority {
+    /// From 0 (most urgent) to 7 (least urgent)
+    pub urgency: u8,
+
+    /// Whether the response is useful bit by bit (e.g. a progressive image),
+    /// in which case it shares bandwidth with other incremental responses of
+    /// the same urgency. Otherwise, responses of the same urgency are sent
+    /// one after the other.
+    pub incremental: bool,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self {
+            urgency: 3,
+            incremental: false,
+        }
+    }
+}
+
+impl Priority {
+    /// Parses a priority field value, a structured fields dictionary like
+    /// `u=5, i`. Unknown keys, and values that are out of range or of the
+    /// wrong type, are ignored as the RFC says.
+    pub fn parse(value: &[u8]) -> Self {
+        let mut priority = Self::default();
+        let Ok(value) = std::str::from_utf8(value) else {
+            return priority;
+        };
+
+        for member in value.split(',') {
+            // parameters don't mean anything for priorities
+            let member = member.split(';').next().unwrap_or_default().trim();
+            let (key, value) = member.split_once('=').unwrap_or((member, "?1"));
+            match key {
+                "u" => {
+                    if let Ok(urgency @ 0..=7) = value.parse::<u8>() {
+                        priority.urgency = urgency;
+                    }
+                }
+                "i" => match value {
+                    "?1" => priority.incremental = true,
+                    "?0" => priority.incremental = false,
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+        priority
+    }
+
+    /// Parses the `priority` headers of a request, which default to
+    /// [Priority::default] if there are none.
+    pub fn from_headers(headers: &Headers) -> Self {
+        let mut value = vec![];
+        for (i, v) in headers.get_all(PRIORITY).iter().enumerate() {
+            if i > 0 {
+                value.push(b',');
+            }
+            value.extend_from_slice(v);
+        }
+        Self::parse(&value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Priority, PRIORITY};
+    use crate::Headers;
+
+    #[test]
+    fn test_priority_parse() {
+        assert_eq!(Priority::parse(b""), Priority::default());
+        assert_eq!(
+            Priority::parse(b"u=5, i"),
+            Priority {
+                urgency: 5,
+                incremental: true
+            }
+        );
+        // out of range and wrongly typed values are ignored
+        assert_eq!(Priority::parse(b"u=9, i=1"), Priority::default());
+        assert_eq!(
+            Priority::parse(b"i=?1;foo=bar, x=7, u=0"),
+            Priority {
+                urgency: 0,
+                incremental: true
+            }
+        );
+
+        // later members win, across header lines
+        let mut headers = Headers::default();
+        headers.append(PRIORITY, "u=1".into());
+        headers.append(PRIORITY, "u=6".into());
+        assert_eq!(Priority::from_headers(&headers).urgency, 6);
+    }
+}
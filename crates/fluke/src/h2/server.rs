@@ -1,66 +1,421 @@
 use std::{
     borrow::Cow,
+    cell::Cell,
+    collections::HashSet,
     io::Write,
     net::Shutdown,
     rc::Rc,
     sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
 };
 
 use byteorder::{BigEndian, WriteBytesExt};
 use enumflags2::BitFlags;
 use eyre::Context;
-use fluke_buffet::{Piece, PieceList, PieceStr, Roll, RollMut};
-use fluke_maybe_uring::io::{ReadOwned, WriteOwned};
-use http::{
-    header,
-    uri::{Authority, PathAndQuery, Scheme},
-    HeaderName, Version,
-};
+use fluke_buffet::{Piece, PieceList, PieceStr, Roll, RollMut, BUF_SIZE};
+use fluke_maybe_uring::io::{ConnInfo, ReadOwned, Transport, WriteOwned};
+use http::{header, uri::Scheme, HeaderName, StatusCode, Version};
 use nom::Finish;
 use smallvec::{smallvec, SmallVec};
 use tokio::sync::mpsc;
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 
 use crate::{
     h2::{
-        body::{H2Body, H2BodyItem, PieceOrTrailers},
+        body::{ConsumedData, H2Body, H2BodyItem, PieceOrTrailers},
         encode::{EncoderState, H2Encoder},
+        handle::{ConnectionHandle, SettingsUpdate},
+        observe::{ErrorObserver, ProtocolError},
         parse::{
             self, parse_reserved_and_u31, ContinuationFlags, DataFlags, Frame, FrameType,
-            HeadersFlags, PingFlags, PrioritySpec, Settings, SettingsFlags, StreamId,
+            HeadersFlags, KnownErrorCode, PingFlags, PrioritySpec, Settings, SettingsFlags,
+            StreamId,
         },
+        priority::Priority,
+        stats::{ControlFrameKind, ControlFrameLimits},
         types::{
             ConnState, H2ConnectionError, H2Event, H2EventPayload, H2StreamError,
-            HeadersOrTrailers, StreamState,
+            HeadersOrTrailers, PendingData, StreamIncoming, StreamOutgoing, StreamState,
+            MAX_WINDOW_SIZE,
         },
     },
-    util::read_and_parse,
-    ExpectResponseHeaders, Headers, Method, Request, Responder, ServerDriver,
+    stall::watch_write_stalls,
+    types::{is_connection_specific, retain_allowed_trailers},
+    util::{read_and_parse, read_when_idle},
+    write_buf::BufferedWrite,
+    ActiveHandler, AuthorityPolicy, CorrelationId, ExpectResponseHeaders, Headers, HeadersExt,
+    Load, LoadShedder, Method, Request, RequestIds, RequestLimits, RequestUri, Responder, Response,
+    ServerDriver, TransportSecurity, TrustedProxies, WriteStallPolicy,
 };
 
 /// HTTP/2 server configuration
 pub struct ServerConf {
     pub max_streams: u32,
+
+    /// Whether connections served with this configuration are encrypted. On
+    /// plaintext connections, requests with an `https` `:scheme` are refused,
+    /// unless `trust_forwarded_scheme` is set.
+    pub transport_security: TransportSecurity,
+
+    /// Accept `https` as `:scheme` on plaintext connections, for when TLS
+    /// is terminated by a trusted proxy in front of us.
+    pub trust_forwarded_scheme: bool,
+
+    /// Max decoded size of a header block, counted like
+    /// SETTINGS_MAX_HEADER_LIST_SIZE. Requests with larger ones get a 431
+    /// Request Header Fields Too Large response.
+    pub max_header_list_size: usize,
+
+    /// Max length of the `:path` pseudo-header. Longer ones get a 414 URI Too
+    /// Long response.
+    pub max_uri_len: usize,
+
+    /// Max length of a request body announced with `content-length`. Larger
+    /// requests get a 413 Content Too Large response without reaching the
+    /// driver. Can be adjusted per request, see [ServerDriver::request_limits].
+    pub max_request_body_len: u64,
+
+    /// What to do with unknown pseudo-headers and repeated singleton
+    /// headers (like `content-length`)
+    pub header_strictness: HeaderStrictness,
+
+    /// How many bytes the deframer tries to read from the transport at once.
+    /// Reads are also bounded by the free space left in the current buffer.
+    pub read_chunk_size: usize,
+
+    /// How many frames can be deframed ahead of processing. Once that many
+    /// frames are queued, we stop reading from the transport until the
+    /// processing side catches up. Zero is treated as one.
+    pub max_buffered_frames: usize,
+
+    /// Give pool buffers back while no streams are open, rather than holding
+    /// on to them while the connection sits idle. This costs a copy of the
+    /// first bytes received after that, but lets lots of idle connections
+    /// stay open without exhausting the pool.
+    pub release_idle_buffers: bool,
+
+    /// Frames are gathered in a buffer of that size rather than written one
+    /// by one. It gets written out when full, when the connection runs out of
+    /// frames and events to process, or on the first write after
+    /// `write_flush_after` has elapsed. Zero turns buffering off, for cases
+    /// where latency matters more than the number of writes.
+    pub write_buffer_size: usize,
+
+    /// See `write_buffer_size`
+    pub write_flush_after: Duration,
+
+    /// How much response body data a stream may have handed over to the
+    /// connection without it being written out yet, e.g. because the
+    /// connection window is exhausted. Past that, its handler waits in
+    /// `write_chunk` until some of it goes out, rather than producing more.
+    /// Zero is treated as one.
+    pub max_queued_body_data: usize,
+
+    /// Consulted before accepting each stream: streams it sheds are reset
+    /// with `REFUSED_STREAM`, without reaching the driver.
+    pub load_shedder: Option<Rc<dyn LoadShedder>>,
+
+    /// How long a write to the transport may be pending (typically because
+    /// the peer stopped reading) before `write_stall_policy` is applied. `None`
+    /// (the default) never looks for stalls.
+    pub write_stall_timeout: Option<Duration>,
+
+    /// See `write_stall_timeout`
+    pub write_stall_policy: WriteStallPolicy,
+
+    /// How many PING, SETTINGS, WINDOW_UPDATE and RST_STREAM frames peers
+    /// may send before they're considered to be flooding us
+    pub control_frame_limits: ControlFrameLimits,
+
+    /// Header carrying a correlation id, typically `x-request-id`. When set,
+    /// each request gets one in [Request::correlation_id]: the one it came
+    /// with, or one made from its [Request::id], and it's echoed in the
+    /// response.
+    pub request_id_header: Option<HeaderName>,
+
+    /// Fields that may normally not be sent as trailers, but that we should
+    /// let through anyway, see [is_forbidden_trailer](crate::is_forbidden_trailer)
+    pub allowed_trailers: Rc<[HeaderName]>,
+
+    /// How long the peer has to acknowledge the settings we send, before
+    /// the connection is closed with `SETTINGS_TIMEOUT`, cf.
+    /// <https://httpwg.org/specs/rfc9113.html#SettingsSync>. `None` waits
+    /// forever.
+    pub settings_ack_timeout: Option<Duration>,
+
+    /// How long the peer has to finish a header block once it started one,
+    /// i.e. send all of its CONTINUATION frames. Nothing else can happen on
+    /// the connection meanwhile, so it's closed with a GOAWAY if it takes
+    /// longer. `None` waits forever.
+    pub header_timeout: Option<Duration>,
+
+    /// How long a stream may go without the peer sending any of its request
+    /// body, while it's allowed to, before it's reset with `CANCEL`. Time
+    /// spent waiting on us to open the flow-control window doesn't count.
+    /// `None` (the default) waits forever. Can be adjusted per request, see
+    /// [ServerDriver::request_limits].
+    pub body_idle_timeout: Option<Duration>,
+
+    /// How long the connection may go without any streams open or handlers
+    /// running before it's closed with a `NO_ERROR` GOAWAY. Control frames
+    /// like PING don't keep it open. `None` (the default) keeps it open for
+    /// as long as the peer does.
+    pub idle_timeout: Option<Duration>,
+
+    /// Advertise SETTINGS_ENABLE_CONNECT_PROTOCOL and accept extended CONNECT
+    /// requests (RFC 8441), which is how WebSockets and other protocols are
+    /// tunneled over HTTP/2 streams, see [Request::protocol].
+    pub enable_connect_protocol: bool,
+
+    /// Peers whose forwarding headers are believed when filling in
+    /// [Request::client_addr]
+    pub trusted_proxies: TrustedProxies,
+
+    /// Which authorities requests may be for on a given connection. Those
+    /// it rejects get a 421 Misdirected Request without reaching the driver.
+    /// `None` (the default) accepts any authority.
+    pub authority_policy: Option<Rc<dyn AuthorityPolicy>>,
+
+    /// When to let the peer send more request body data, as handlers read
+    /// what it sent already
+    pub window_update_strategy: WindowUpdateStrategy,
+
+    /// Told about every connection error and stream reset, on top of them
+    /// being logged
+    pub error_observer: Option<Rc<dyn ErrorObserver>>,
+
+    /// SETTINGS_HEADER_TABLE_SIZE: how large the peer may make the HPACK
+    /// dynamic table we keep for its header blocks. It's 4096 until the peer
+    /// acknowledges our settings, whatever this says.
+    pub header_table_size: u32,
+
+    /// How response headers are compressed
+    pub hpack_encode_options: HpackEncodeOptions,
 }
 
 impl Default for ServerConf {
     fn default() -> Self {
-        Self { max_streams: 32 }
+        Self {
+            max_streams: 32,
+            transport_security: Default::default(),
+            trust_forwarded_scheme: false,
+            max_uri_len: 8 * 1024,
+            max_header_list_size: 64 * 1024,
+            max_request_body_len: u64::MAX,
+            header_strictness: Default::default(),
+            read_chunk_size: 16 * 1024,
+            max_buffered_frames: 32,
+            release_idle_buffers: true,
+            write_buffer_size: 16 * 1024,
+            write_flush_after: Duration::from_micros(200),
+            max_queued_body_data: 64 * 1024,
+            load_shedder: None,
+            write_stall_timeout: None,
+            write_stall_policy: Default::default(),
+            control_frame_limits: Default::default(),
+            request_id_header: None,
+            allowed_trailers: Rc::new([]),
+            settings_ack_timeout: Some(Duration::from_secs(10)),
+            header_timeout: Some(Duration::from_secs(10)),
+            body_idle_timeout: None,
+            idle_timeout: None,
+            enable_connect_protocol: false,
+            trusted_proxies: Default::default(),
+            authority_policy: None,
+            window_update_strategy: Default::default(),
+            error_observer: None,
+            header_table_size: Settings::default().header_table_size,
+            hpack_encode_options: Default::default(),
+        }
+    }
+}
+
+/// When to send WINDOW_UPDATE frames for request body data handlers have
+/// read, cf. <https://httpwg.org/specs/rfc9113.html#FlowControl>. Peers
+/// can't send more than the window they were given, so request bodies are
+/// only ever buffered up to SETTINGS_INITIAL_WINDOW_SIZE per stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WindowUpdateStrategy {
+    /// As soon as anything was read: the peer never waits on us, but every
+    /// chunk read costs a frame or two.
+    Eager,
+
+    /// Once what the peer may still send falls below that fraction of the
+    /// window, top it back up. `LowWatermark(0.5)` is the default.
+    LowWatermark(f32),
+}
+
+impl Default for WindowUpdateStrategy {
+    fn default() -> Self {
+        Self::LowWatermark(0.5)
+    }
+}
+
+/// How forgiving to be about headers RFC 9113 considers malformed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeaderStrictness {
+    /// Reset the stream with a `PROTOCOL_ERROR`, as required by
+    /// <https://httpwg.org/specs/rfc9113.html#rfc.section.8.1.1>
+    #[default]
+    Strict,
+
+    /// Ignore unknown pseudo-headers, and keep only the first occurrence of
+    /// singleton headers
+    Lenient,
+}
+
+/// How response headers are HPACK-encoded, cf.
+/// <https://www.rfc-editor.org/rfc/rfc7541>. The size of the dynamic table
+/// itself is up to the peer, see SETTINGS_HEADER_TABLE_SIZE.
+#[derive(Debug, Clone)]
+pub struct HpackEncodeOptions {
+    /// Huffman-encode header names and values, when that makes them shorter
+    pub huffman: bool,
+
+    /// Headers larger than this, counted like the dynamic table does (the
+    /// length of the name and value, plus 32), aren't added to the dynamic
+    /// table, so that large one-off values don't evict the headers every
+    /// response has. Zero leaves the dynamic table unused.
+    pub max_indexed_header_size: usize,
+
+    /// Headers that are never added to the dynamic table, and that proxies
+    /// must not add to theirs either. This is for secrets that could be
+    /// guessed by watching how well they compress alongside
+    /// attacker-controlled data, cf.
+    /// <https://www.rfc-editor.org/rfc/rfc7541#section-7.1.3>.
+    pub never_indexed: Rc<[HeaderName]>,
+}
+
+impl Default for HpackEncodeOptions {
+    fn default() -> Self {
+        Self {
+            huffman: true,
+            max_indexed_header_size: 1024,
+            never_indexed: Rc::new([
+                header::AUTHORIZATION,
+                header::PROXY_AUTHORIZATION,
+                header::SET_COOKIE,
+            ]),
+        }
+    }
+}
+
+impl HpackEncodeOptions {
+    fn indexing(&self, name: &[u8], value: &[u8]) -> fluke_hpack::Indexing {
+        if self
+            .never_indexed
+            .iter()
+            .any(|n| n.as_str().as_bytes() == name)
+        {
+            fluke_hpack::Indexing::Never
+        } else if name.len() + value.len() + 32 > self.max_indexed_header_size {
+            fluke_hpack::Indexing::Without
+        } else {
+            fluke_hpack::Indexing::Incremental
+        }
     }
 }
 
+/// Headers that may only appear once in a request
+const SINGLETON_HEADERS: &[HeaderName] =
+    &[header::CONTENT_LENGTH, header::CONTENT_TYPE, header::HOST];
+
 pub async fn serve(
-    (transport_r, transport_w): (impl ReadOwned, impl WriteOwned),
+    transport: impl Transport,
+    conf: Rc<ServerConf>,
+    client_buf: RollMut,
+    driver: Rc<impl ServerDriver + 'static>,
+) -> eyre::Result<()> {
+    serve_with_handle(transport, conf, client_buf, driver, Default::default()).await
+}
+
+/// Like [serve], but the connection can be inspected and controlled through
+/// `handle` while it's being served.
+pub async fn serve_with_handle(
+    transport: impl Transport,
+    conf: Rc<ServerConf>,
+    client_buf: RollMut,
+    driver: Rc<impl ServerDriver + 'static>,
+    handle: ConnectionHandle,
+) -> eyre::Result<()> {
+    let conn_info = Rc::new(ConnInfo::of(&transport));
+    let (transport_r, transport_w) = transport.into_halves();
+    serve_halves(
+        transport_r,
+        transport_w,
+        conn_info,
+        conf,
+        client_buf,
+        driver,
+        handle,
+        None,
+    )
+    .await
+}
+
+/// Serves h2 on a connection that was upgraded from HTTP/1.1 (`Upgrade:
+/// h2c`), once the `101 Switching Protocols` response went out: `req` is
+/// the request that asked for the upgrade, and gets answered on stream 1.
+/// `peer_settings` are those from its `HTTP2-Settings` header.
+pub(crate) async fn serve_upgraded(
+    transport_r: impl ReadOwned,
+    transport_w: impl WriteOwned,
+    conf: Rc<ServerConf>,
+    client_buf: RollMut,
+    driver: Rc<impl ServerDriver + 'static>,
+    req: Request,
+    peer_settings: Settings,
+) -> eyre::Result<()> {
+    let conn_info = req.conn_info.clone();
+    serve_halves(
+        transport_r,
+        transport_w,
+        conn_info,
+        conf,
+        client_buf,
+        driver,
+        Default::default(),
+        Some((req, peer_settings)),
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn serve_halves(
+    transport_r: impl ReadOwned,
+    transport_w: impl WriteOwned,
+    conn_info: Rc<ConnInfo>,
     conf: Rc<ServerConf>,
     client_buf: RollMut,
     driver: Rc<impl ServerDriver + 'static>,
+    handle: ConnectionHandle,
+    upgrade: Option<(Request, Settings)>,
 ) -> eyre::Result<()> {
     let mut state = ConnState::default();
     state.self_settings.max_concurrent_streams = conf.max_streams;
-
-    let mut cx = ServerContext::new(driver.clone(), state, transport_w)?;
-    cx.work(client_buf, transport_r).await?;
-    cx.transport_w.shutdown(Shutdown::Both).await?;
+    state.self_settings.enable_connect_protocol = conf.enable_connect_protocol;
+    state.self_settings.header_table_size = conf.header_table_size;
+
+    let mut cx = ServerContext::new(
+        driver.clone(),
+        conf.clone(),
+        state,
+        conn_info,
+        transport_w,
+        handle,
+    )?;
+    let pending_write = cx.transport_w.pending_write();
+    watch_write_stalls(
+        async {
+            cx.work(client_buf, transport_r, upgrade).await?;
+            cx.transport_w.shutdown(Shutdown::Both).await?;
+            Ok(())
+        },
+        pending_write,
+        conf.write_stall_timeout,
+        conf.write_stall_policy,
+    )
+    .await?;
 
     debug!("finished serving");
     Ok(())
@@ -69,50 +424,113 @@ pub async fn serve(
 /// Reads and processes h2 frames from the client.
 pub(crate) struct ServerContext<D: ServerDriver + 'static, W: WriteOwned> {
     driver: Rc<D>,
+    conf: Rc<ServerConf>,
+    handle: ConnectionHandle,
     state: ConnState,
+
+    /// Given to every request received on this connection
+    conn_info: Rc<ConnInfo>,
+
+    request_ids: RequestIds,
+
+    /// Streams on this connection whose handlers haven't returned yet
+    running_handlers: HashSet<StreamId>,
+
+    /// Set once we've started shutting down, see [ConnectionHandle::shutdown]
+    drain_deadline: Option<tokio::time::Instant>,
+
+    /// Since when there's been nothing going on, see [ServerConf::idle_timeout]
+    idle_since: Option<tokio::time::Instant>,
+
     hpack_dec: fluke_hpack::Decoder<'static>,
     hpack_enc: fluke_hpack::Encoder<'static>,
     out_scratch: RollMut,
 
-    /// Whether we've received a GOAWAY frame.
+    /// The largest HPACK dynamic table size the peer may currently be
+    /// encoding for: the one it last acknowledged, or any of those we sent
+    /// since. `hpack_dec` refuses size updates past it.
+    hpack_dec_max_table_size: u32,
+
+    /// Where header blocks split across CONTINUATION frames are reassembled
+    continuation_scratch: Vec<u8>,
+
+    /// Whether we've received a GOAWAY frame: the peer won't open new
+    /// streams, and we close the connection once it's drained
     pub goaway_recv: bool,
 
+    /// The last stream id we advertised in a GOAWAY frame, if we sent one:
+    /// streams past it are refused
+    goaway_sent: Option<StreamId>,
+
     /// TODO: encapsulate into a framer, don't
     /// allow direct access from context methods
-    transport_w: W,
+    transport_w: BufferedWrite<W>,
 
     ev_tx: mpsc::Sender<H2Event>,
     ev_rx: mpsc::Receiver<H2Event>,
+
+    /// Request body data read by handlers, which may warrant a WINDOW_UPDATE
+    consumed: Rc<ConsumedData>,
+
+    /// Our acknowledged SETTINGS_MAX_FRAME_SIZE, shared with the deframer
+    max_frame_size: Rc<AtomicU32>,
 }
 
 impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
-    pub(crate) fn new(driver: Rc<D>, state: ConnState, transport_w: W) -> eyre::Result<Self> {
+    pub(crate) fn new(
+        driver: Rc<D>,
+        conf: Rc<ServerConf>,
+        state: ConnState,
+        conn_info: Rc<ConnInfo>,
+        transport_w: W,
+        handle: ConnectionHandle,
+    ) -> eyre::Result<Self> {
+        // until our settings are acknowledged, the peer goes by the defaults
+        let hpack_dec_max_table_size = Settings::default().header_table_size;
         let mut hpack_dec = fluke_hpack::Decoder::new();
-        hpack_dec
-            .set_max_allowed_table_size(Settings::default().header_table_size.try_into().unwrap());
+        hpack_dec.set_max_allowed_table_size(hpack_dec_max_table_size as usize);
 
-        let hpack_enc = fluke_hpack::Encoder::new();
+        let mut hpack_enc = fluke_hpack::Encoder::new();
+        hpack_enc.set_huffman(conf.hpack_encode_options.huffman);
 
         let (ev_tx, ev_rx) = tokio::sync::mpsc::channel::<H2Event>(32);
+        let transport_w =
+            BufferedWrite::new(transport_w, conf.write_buffer_size, conf.write_flush_after);
+        let max_frame_size = Rc::new(AtomicU32::new(state.self_settings.max_frame_size));
 
         Ok(Self {
             driver,
+            conf,
+            handle,
             ev_tx,
             ev_rx,
+            consumed: Default::default(),
             state,
+            conn_info,
+            request_ids: RequestIds::new(),
+            running_handlers: Default::default(),
+            drain_deadline: None,
+            idle_since: None,
             hpack_dec,
             hpack_enc,
             out_scratch: RollMut::alloc()?,
+            hpack_dec_max_table_size,
+            continuation_scratch: Vec::new(),
             goaway_recv: false,
+            goaway_sent: None,
             transport_w,
+            max_frame_size,
         })
     }
 
-    /// Reads and process h2 frames from the client.
+    /// Reads and process h2 frames from the client. For connections upgraded
+    /// from HTTP/1.1, `upgrade` is the request that asked for it, with the
+    /// settings it came with.
     pub(crate) async fn work(
         &mut self,
         mut client_buf: RollMut,
         mut transport_r: impl ReadOwned,
+        upgrade: Option<(Request, Settings)>,
     ) -> eyre::Result<()> {
         // first read the preface
         {
@@ -137,31 +555,43 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
         // then send our initial settings
         {
             debug!("Sending initial settings");
-            let payload = self.state.self_settings.into_roll(&mut self.out_scratch)?;
-            let frame = Frame::new(
-                FrameType::Settings(Default::default()),
-                StreamId::CONNECTION,
-            );
-            self.write_frame(frame, payload).await?;
+            self.send_settings(self.state.self_settings).await?;
+        }
+
+        // the upgrade request is answered on stream 1, which it half-closed,
+        // cf. https://www.rfc-editor.org/rfc/rfc7540#section-3.2
+        if let Some((mut req, peer_settings)) = upgrade {
+            debug!("Serving the request that was upgraded to h2c on stream 1");
+            self.hpack_enc
+                .set_max_table_size(peer_settings.header_table_size as usize);
+            self.state.peer_settings = peer_settings;
+
+            let stream_id = StreamId(1);
+            self.state.last_stream_id = stream_id;
+            self.handle.set_last_stream_id(stream_id.0);
+            req.stream_id = Some(stream_id.0);
+            self.start_stream(stream_id, req, true).await?;
         }
 
         let mut goaway_err: Option<H2ConnectionError> = None;
 
         {
-            // read frames and send them into an mpsc buffer of size 1
-            let (tx, rx) = mpsc::channel::<(Frame, Roll)>(32);
+            // read frames and send them into a bounded mpsc buffer
+            let (tx, rx) = mpsc::channel::<(Frame, Roll)>(self.conf.max_buffered_frames.max(1));
 
-            // store max frame size setting as an atomic so we can share it across tasks
-            // FIXME: the process_task should update this
-            let max_frame_size = Rc::new(AtomicU32::new(self.state.self_settings.max_frame_size));
+            // set by the process task when no streams are open, so that the
+            // deframe task waits for the next frame without holding a buffer
+            let idle = Rc::new(Cell::new(self.conf.release_idle_buffers));
 
-            let mut deframe_task = std::pin::pin!(Self::deframe_loop(
+            let mut deframe_task = std::pin::pin!(deframe_loop(
                 client_buf,
                 transport_r,
                 tx,
-                max_frame_size
+                self.max_frame_size.clone(),
+                self.conf.read_chunk_size,
+                idle.clone(),
             ));
-            let mut process_task = std::pin::pin!(self.process_loop(rx));
+            let mut process_task = std::pin::pin!(self.process_loop(rx, idle));
 
             debug!("Starting both deframe & process tasks");
 
@@ -182,6 +612,10 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                         if !should_ignore_err {
                             return Err(e.wrap_err("h2 io"));
                         }
+                    } else if let Err(err) = res {
+                        // the peer sent frames we can't deframe: whatever it
+                        // sent before still gets processed
+                        goaway_err = Some(err);
                     }
 
                     if let Err(e) = (&mut process_task).await {
@@ -202,144 +636,92 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
         if let Some(err) = goaway_err {
             let error_code = err.as_known_error_code();
             debug!("Connection error: {err} ({err:?}) (code {error_code:?})");
+            if let Some(observer) = &self.conf.error_observer {
+                observer.on_connection_error(
+                    &self.conn_info,
+                    &ProtocolError {
+                        code: error_code,
+                        message: err.to_string(),
+                    },
+                );
+            }
 
             // TODO: don't heap-allocate here
             let additional_debug_data = format!("{err}").into_bytes();
-
-            // TODO: figure out graceful shutdown: this would involve sending a goaway
-            // before this point, and processing all the connections we've accepted
-            debug!(last_stream_id = %self.state.last_stream_id, ?error_code, "Sending GoAway");
-            let payload =
-                self.out_scratch
-                    .put_to_roll(8 + additional_debug_data.len(), |mut slice| {
-                        slice.write_u32::<BigEndian>(self.state.last_stream_id.0)?;
-                        slice.write_u32::<BigEndian>(error_code.repr())?;
-                        slice.write_all(additional_debug_data.as_slice())?;
-
-                        Ok(())
-                    })?;
-
-            let frame = Frame::new(FrameType::GoAway, StreamId::CONNECTION);
-            self.write_frame(frame, payload).await?;
+            self.send_goaway(error_code, &additional_debug_data).await?;
         }
 
         Ok(())
     }
 
-    async fn deframe_loop(
-        mut client_buf: RollMut,
-        mut transport_r: impl ReadOwned,
-        tx: mpsc::Sender<(Frame, Roll)>,
-        max_frame_size: Rc<AtomicU32>,
+    async fn send_goaway(
+        &mut self,
+        error_code: KnownErrorCode,
+        additional_debug_data: &[u8],
     ) -> Result<(), H2ConnectionError> {
-        'read_frames: loop {
-            const MAX_FRAME_HEADER_SIZE: usize = 128;
-            let frame;
-            trace!("Reading frame... Buffer length: {}", client_buf.len());
-            let frame_res = read_and_parse(
-                Frame::parse,
-                &mut transport_r,
-                client_buf,
-                MAX_FRAME_HEADER_SIZE,
-            )
-            .await;
-
-            let maybe_frame = match frame_res {
-                Ok(inner) => inner,
-                Err(e) => return Err(H2ConnectionError::ReadError(e)),
-            };
-            (client_buf, frame) = match maybe_frame {
-                Some((client_buf, frame)) => (client_buf, frame),
-                None => {
-                    debug!("Peer hung up");
-                    break 'read_frames;
-                }
-            };
-            trace!(
-                "Reading frame... done! New buffer length: {}",
-                client_buf.len()
-            );
-            debug!(?frame, "<");
-
-            let max_frame_size = max_frame_size.load(Ordering::Relaxed);
-            if frame.len > max_frame_size {
-                return Err(H2ConnectionError::FrameTooLarge {
-                    frame_type: frame.frame_type,
-                    frame_size: frame.len,
-                    max_frame_size,
-                });
-            }
-
-            trace!(
-                "Reading payload of size {}... Buffer length: {}",
-                frame.len,
-                client_buf.len()
-            );
-            let mut payload;
-            (client_buf, payload) = match read_and_parse(
-                nom::bytes::streaming::take(frame.len as usize),
-                &mut transport_r,
-                client_buf,
-                frame.len as usize,
-            )
-            .await?
-            {
-                Some((client_buf, payload)) => (client_buf, payload),
-                None => {
-                    return Err(H2ConnectionError::IncompleteFrame {
-                        frame_type: frame.frame_type,
-                        frame_size: frame.len,
-                    })
-                }
-            };
-            trace!(
-                "Reading payload... done! New buffer length: {}",
-                client_buf.len()
-            );
-
-            let has_padding = match frame.frame_type {
-                FrameType::Data(flags) => flags.contains(DataFlags::Padded),
-                FrameType::Headers(flags) => flags.contains(HeadersFlags::Padded),
-                _ => false,
-            };
-
-            if has_padding {
-                if payload.is_empty() {
-                    return Err(H2ConnectionError::PaddedFrameEmpty {
-                        frame_type: frame.frame_type,
-                    });
-                }
-
-                let padding_length_roll;
-                (padding_length_roll, payload) = payload.split_at(1);
-                let padding_length = padding_length_roll[0] as usize;
-                if payload.len() < padding_length {
-                    return Err(H2ConnectionError::PaddedFrameTooShort {
-                        frame_type: frame.frame_type,
-                        padding_length,
-                        frame_size: frame.len,
-                    });
-                }
-
-                // padding is on the end of the payload
-                let at = payload.len() - padding_length;
-                (payload, _) = payload.split_at(at);
-            }
-
-            if tx.send((frame, payload)).await.is_err() {
-                debug!("h2 deframer: receiver dropped, closing connection");
-                return Ok(());
-            }
-        }
-
+        debug!(last_stream_id = %self.state.last_stream_id, ?error_code, "Sending GoAway");
+        self.goaway_sent = Some(self.state.last_stream_id);
+        self.handle.set_draining();
+        let payload =
+            self.out_scratch
+                .put_to_roll(8 + additional_debug_data.len(), |mut slice| {
+                    slice.write_u32::<BigEndian>(self.state.last_stream_id.0)?;
+                    slice.write_u32::<BigEndian>(error_code.repr())?;
+                    slice.write_all(additional_debug_data)?;
+
+                    Ok(())
+                })?;
+
+        let frame = Frame::new(FrameType::GoAway, StreamId::CONNECTION);
+        self.write_frame(frame, payload).await?;
         Ok(())
     }
 
     async fn process_loop(
         &mut self,
         mut rx: mpsc::Receiver<(Frame, Roll)>,
+        idle: Rc<Cell<bool>>,
     ) -> Result<(), H2ConnectionError> {
+        let handle = self.handle.clone();
+        let consumed = self.consumed.clone();
+
         loop {
+            if self.is_draining() && self.is_drained() {
+                debug!("all streams are done, closing connection");
+                if self.goaway_sent.is_none() {
+                    // the peer went away first, tell it we're done too
+                    self.send_goaway(KnownErrorCode::NoError, &[]).await?;
+                }
+                break;
+            }
+
+            if self.conf.release_idle_buffers {
+                let now_idle = self.state.streams.is_empty();
+                if now_idle && !idle.get() {
+                    debug!("no more open streams, releasing buffers");
+                    self.out_scratch = RollMut::empty();
+                }
+                idle.set(now_idle);
+            }
+            handle.set_occupancy(self.state.streams.len(), self.running_handlers.len());
+
+            if self.is_drained() {
+                self.idle_since
+                    .get_or_insert_with(tokio::time::Instant::now);
+            } else {
+                self.idle_since = None;
+            }
+            let idle_deadline = self.idle_since.zip(self.conf.idle_timeout);
+            let body_idle_deadline = self.state.next_body_idle_deadline();
+
+            let drain_deadline = self.drain_deadline;
+            // only the oldest settings matter: the peer acknowledges them in
+            // order
+            let settings_ack_deadline = self
+                .conf
+                .settings_ack_timeout
+                .zip(self.state.pending_settings.front())
+                .map(|(timeout, (_, sent_at))| (*sent_at + timeout, timeout));
             tokio::select! {
                 biased;
 
@@ -358,6 +740,82 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                         None => unreachable!("the context owns a copy of the sender, and this method has &mut self, so the sender can't be dropped while this method is running"),
                     }
                 },
+
+                _ = consumed.wait() => {
+                    self.send_window_updates().await?;
+                }
+
+                _ = handle.settings_updated() => {
+                    if let Some(update) = handle.take_settings_update() {
+                        self.update_settings(update).await?;
+                    }
+                }
+
+                _ = handle.shutdown_requested() => {
+                    if let Some(drain_timeout) = handle.take_shutdown() {
+                        self.start_shutdown(drain_timeout).await?;
+                    }
+                }
+
+                _ = async {
+                    match drain_deadline {
+                        Some(deadline) => tokio::time::sleep_until(deadline).await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    debug!(
+                        streams = %self.state.streams.len(),
+                        running_handlers = %self.running_handlers.len(),
+                        "drain timeout elapsed, closing connection"
+                    );
+                    break;
+                }
+
+                timeout = async {
+                    match settings_ack_deadline {
+                        Some((deadline, timeout)) => {
+                            tokio::time::sleep_until(deadline).await;
+                            timeout
+                        }
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    return Err(H2ConnectionError::SettingsTimeout { timeout });
+                }
+
+                _ = async {
+                    match idle_deadline {
+                        Some((since, timeout)) => tokio::time::sleep_until(since + timeout).await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    debug!(timeout = ?self.conf.idle_timeout, "connection idle, closing it");
+                    self.send_goaway(KnownErrorCode::NoError, &[]).await?;
+                    break;
+                }
+
+                (stream_id, timeout) = async {
+                    match body_idle_deadline {
+                        Some((deadline, stream_id, timeout)) => {
+                            tokio::time::sleep_until(deadline).await;
+                            (stream_id, timeout)
+                        }
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    debug!(%stream_id, ?timeout, "request body idle, resetting stream");
+                    self.rst_incoming(stream_id, H2StreamError::BodyIdleTimeout { timeout })
+                        .await?;
+                }
+
+                // nothing else to do right now: write out what we've got
+                // before waiting on the peer or handlers.
+                _ = std::future::ready(()), if self.transport_w.has_buffered() => {
+                    self.transport_w
+                        .flush()
+                        .await
+                        .map_err(H2ConnectionError::WriteError)?;
+                }
             }
         }
 
@@ -370,50 +828,171 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                 let flags = HeadersFlags::EndHeaders;
                 let frame = Frame::new(FrameType::Headers(flags.into()), ev.stream_id);
 
-                // TODO: don't allocate so much for headers. all `encode_into`
-                // wants is an `IntoIter`, we can definitely have a custom iterator
-                // that operates on all this instead of using a `Vec`.
-
-                // TODO: limit header size
-                let mut headers: Vec<(&[u8], &[u8])> = vec![];
-                headers.push((b":status", res.status.as_str().as_bytes()));
-                for (name, value) in res.headers.iter() {
-                    if name == http::header::TRANSFER_ENCODING {
-                        // do not set transfer-encoding: chunked when doing HTTP/2
-                        continue;
-                    }
-                    headers.push((name.as_str().as_bytes(), value));
-                }
-
-                assert_eq!(self.out_scratch.len(), 0);
-                self.hpack_enc
-                    .encode_into(headers, &mut self.out_scratch)
-                    .map_err(H2ConnectionError::WriteError)?;
-                let payload = self.out_scratch.take_all();
-
+                let payload = self.encode_header_block(Some(res.status), &res.headers)?;
                 self.write_frame(frame, payload).await?;
             }
             H2EventPayload::BodyChunk(chunk) => {
-                let flags = BitFlags::<DataFlags>::default();
-                let frame = Frame::new(FrameType::Data(flags), ev.stream_id);
-
-                self.write_frame(frame, chunk).await?;
+                self.state
+                    .pending_data
+                    .push_back((ev.stream_id, PendingData::Chunk(chunk)));
+                self.write_pending_data().await?;
             }
             H2EventPayload::BodyEnd => {
-                // FIXME: this should transition the stream to `Closed`
-                // state (or at the very least `HalfClosedLocal`).
-                // Either way, whoever owns the stream state should know
-                // about it, cf. https://github.com/bearcove/fluke/issues/123
+                // queued even if nothing else is, so it can't overtake data
+                // that's waiting on the window.
+                self.state
+                    .pending_data
+                    .push_back((ev.stream_id, PendingData::End));
+                self.write_pending_data().await?;
+            }
+            H2EventPayload::BodyEndWithTrailers(trailers) => {
+                self.state
+                    .pending_data
+                    .push_back((ev.stream_id, PendingData::EndWithTrailers(trailers)));
+                self.write_pending_data().await?;
+            }
+            H2EventPayload::Abort => {
+                self.state.drop_pending_data(ev.stream_id);
 
-                let flags = DataFlags::EndStream;
-                let frame = Frame::new(FrameType::Data(flags.into()), ev.stream_id);
-                self.write_frame(frame, Roll::empty()).await?;
+                // the stream may already be gone, e.g. if the peer reset it
+                if self.state.streams.contains_key(&ev.stream_id) {
+                    self.rst(ev.stream_id, H2StreamError::ResponseIncomplete)
+                        .await?;
+                }
+            }
+            H2EventPayload::HandlerDone => {
+                self.running_handlers.remove(&ev.stream_id);
+                self.reap_stream(ev.stream_id).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes out queued response body data, most urgent streams first, as
+    /// far as the connection window allows. What doesn't fit waits for the
+    /// peer's next WINDOW_UPDATE, without holding up streams that are only
+    /// left to end.
+    async fn write_pending_data(&mut self) -> Result<(), H2ConnectionError> {
+        while let Some(index) = self.state.next_pending_data() {
+            let Some((stream_id, data)) = self.state.pending_data.remove(index) else {
+                unreachable!()
+            };
+            match data {
+                PendingData::Chunk(chunk) => {
+                    if self.state.priority(stream_id).incremental {
+                        self.state.last_incremental = stream_id;
+                    }
+
+                    // a frame can't be larger than the peer lets us send
+                    // either, the rest goes out in frames of its own.
+                    let max_len = std::cmp::min(
+                        self.state.outgoing_window as usize,
+                        self.state.peer_settings.max_frame_size as usize,
+                    );
+                    let chunk = if chunk.len() > max_len {
+                        let (chunk, rest) = chunk.split_at(max_len);
+                        self.state
+                            .pending_data
+                            .insert(index, (stream_id, PendingData::Chunk(rest)));
+                        chunk
+                    } else {
+                        chunk
+                    };
+                    self.state.outgoing_window -= chunk.len() as i64;
+                    self.state.dequeue_data(stream_id, chunk.len());
+
+                    let flags = BitFlags::<DataFlags>::default();
+                    let frame = Frame::new(FrameType::Data(flags), stream_id);
+                    self.write_frame(frame, chunk).await?;
+                }
+                PendingData::End => {
+                    // FIXME: this should transition the stream to `Closed`
+                    // state (or at the very least `HalfClosedLocal`).
+                    // Either way, whoever owns the stream state should know
+                    // about it, cf. https://github.com/bearcove/fluke/issues/123
+
+                    let flags = DataFlags::EndStream;
+                    let frame = Frame::new(FrameType::Data(flags.into()), stream_id);
+                    self.write_frame(frame, Roll::empty()).await?;
+
+                    // the handler may have returned before the response was
+                    // written out, without reading the request body.
+                    self.reap_stream(stream_id).await?;
+                }
+                PendingData::EndWithTrailers(mut trailers) => {
+                    retain_allowed_trailers(&mut trailers, &self.conf.allowed_trailers);
+                    let payload = self.encode_header_block(None, &trailers)?;
+
+                    let flags = HeadersFlags::EndHeaders | HeadersFlags::EndStream;
+                    let frame = Frame::new(FrameType::Headers(flags), stream_id);
+                    self.write_frame(frame, payload).await?;
+
+                    self.reap_stream(stream_id).await?;
+                }
             }
         }
+        if !self.state.pending_data.is_empty() {
+            debug!(pending = %self.state.pending_data.len(), "connection window exhausted");
+        }
 
         Ok(())
     }
 
+    /// HPACK-encodes a header block: `:status` (for responses, not trailers)
+    /// then `headers`, minus connection-specific ones and any that
+    /// `connection` lists, which peers would reject as malformed.
+    fn encode_header_block(
+        &mut self,
+        status: Option<StatusCode>,
+        headers: &Headers,
+    ) -> Result<Roll, H2ConnectionError> {
+        // TODO: don't allocate so much for headers, we can definitely have a
+        // custom iterator that operates on all this instead of using a `Vec`.
+
+        // TODO: limit header size
+        let mut block: Vec<(&[u8], &[u8])> = vec![];
+        if let Some(status) = &status {
+            block.push((b":status", status.as_str().as_bytes()));
+        }
+        let listed: Vec<&[u8]> = headers
+            .get_all(header::CONNECTION)
+            .iter()
+            .flat_map(|value| value.split(|&b| b == b','))
+            .map(crate::trim_ows)
+            .collect();
+        for (name, value) in headers.iter() {
+            if is_connection_specific(name) {
+                // e.g. `transfer-encoding: chunked`, which the
+                // responder sets without knowing the protocol
+                continue;
+            }
+            if listed
+                .iter()
+                .any(|l| l.eq_ignore_ascii_case(name.as_str().as_bytes()))
+            {
+                continue;
+            }
+            block.push((name.as_str().as_bytes(), value));
+        }
+
+        assert_eq!(self.out_scratch.len(), 0);
+        let options = &self.conf.hpack_encode_options;
+        self.hpack_enc
+            .encode_size_update_into(&mut self.out_scratch)
+            .map_err(H2ConnectionError::WriteError)?;
+        for (name, value) in block {
+            self.hpack_enc
+                .encode_header_into_with(
+                    (name, value),
+                    options.indexing(name, value),
+                    &mut self.out_scratch,
+                )
+                .map_err(H2ConnectionError::WriteError)?;
+        }
+        Ok(self.out_scratch.take_all())
+    }
+
     async fn write_frame(
         &mut self,
         mut frame: Frame,
@@ -422,48 +1001,48 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
         debug!(?frame, ">");
         let payload = payload.into();
 
-        match &frame.frame_type {
-            FrameType::Data(headers) => {
-                if headers.contains(DataFlags::EndStream) {
-                    // if the stream is open, this transitions to HalfClosedLocal.
-                    if let Some(ss) = self.state.streams.get_mut(&frame.stream_id) {
-                        match ss {
-                            StreamState::Open(_) => {
-                                // transition through StreamState::HalfClosedRemote
-                                // so we don't have to remove/re-insert.
-                                let mut entry = StreamState::HalfClosedRemote;
-                                std::mem::swap(&mut entry, ss);
-
-                                let body_tx = match entry {
-                                    StreamState::Open(body_tx) => body_tx,
-                                    _ => unreachable!(),
-                                };
-
-                                *ss = StreamState::HalfClosedLocal(body_tx);
-                            }
-                            _ => {
-                                // transition to closed
-                                if self.state.streams.remove(&frame.stream_id).is_some() {
-                                    debug!(
-                                        "Closed stream {} (wrote data w/EndStream), now have {} streams",
-                                        frame.stream_id,
-                                        self.state.streams.len()
-                                    );
-                                }
-                            }
+        // DATA ends the body, HEADERS too when they carry trailers (or when
+        // there's no body)
+        let end_stream = match &frame.frame_type {
+            FrameType::Data(flags) => flags.contains(DataFlags::EndStream),
+            FrameType::Headers(flags) => flags.contains(HeadersFlags::EndStream),
+            _ => false,
+        };
+        if end_stream {
+            // if the stream is open, this transitions to HalfClosedLocal.
+            if let Some(ss) = self.state.streams.get_mut(&frame.stream_id) {
+                match ss {
+                    StreamState::Open(..) => {
+                        // transition through StreamState::HalfClosedRemote
+                        // so we don't have to remove/re-insert.
+                        let mut entry = StreamState::HalfClosedRemote(StreamOutgoing::new(0, 0));
+                        std::mem::swap(&mut entry, ss);
+
+                        // we're done sending, the outgoing window goes away
+                        let incoming = match entry {
+                            StreamState::Open(incoming, _outgoing) => incoming,
+                            _ => unreachable!(),
+                        };
+
+                        *ss = StreamState::HalfClosedLocal(incoming);
+                    }
+                    _ => {
+                        // transition to closed
+                        if self.state.streams.remove(&frame.stream_id).is_some() {
+                            debug!(
+                                "Closed stream {} (wrote frame w/EndStream), now have {} streams",
+                                frame.stream_id,
+                                self.state.streams.len()
+                            );
                         }
                     }
                 }
             }
-            FrameType::Settings(_) => {
-                // TODO: keep track of whether our new settings have been acknowledged
-            }
-            _ => {
-                // muffin.
-            }
         }
 
-        // TODO: enforce max_frame_size from the peer settings, not just u32::max
+        // DATA frames are split to fit the peer's max_frame_size by
+        // `write_pending_data`. TODO: enforce it for HEADERS too, which means
+        // splitting them into CONTINUATION frames
         frame.len = payload
             .len()
             .try_into()
@@ -499,6 +1078,22 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
     ) -> Result<(), H2ConnectionError> {
         match frame.frame_type {
             FrameType::Data(flags) => {
+                // flow control counts padding too, but there's nobody to read
+                // it: it's given back right away, like data nobody will read.
+                self.state.incoming_window.receive(frame.len);
+                if self.state.incoming_window.is_exceeded() {
+                    return Err(H2ConnectionError::ReceiveWindowExceeded);
+                }
+                // what's not going to reach a handler
+                let mut unread = frame.len - payload.len() as u32;
+
+                if self.state.was_reset(frame.stream_id)
+                    && !self.state.streams.contains_key(&frame.stream_id)
+                {
+                    debug!(stream_id = %frame.stream_id, "ignoring data for stream we reset");
+                    return self.window_consumed(frame.stream_id, frame.len).await;
+                }
+
                 let ss = self.state.streams.get_mut(&frame.stream_id).ok_or(
                     H2ConnectionError::StreamClosed {
                         stream_id: frame.stream_id,
@@ -506,20 +1101,46 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                 )?;
 
                 match ss {
-                    StreamState::Open(body_tx) | StreamState::HalfClosedLocal(body_tx) => {
-                        if body_tx
+                    StreamState::Open(incoming, _) | StreamState::HalfClosedLocal(incoming) => {
+                        incoming.window.receive(frame.len);
+                        // cf. https://httpwg.org/specs/rfc9113.html#rfc.section.6.9.1
+                        let received = if incoming.window.is_exceeded() {
+                            Err(H2StreamError::ReceiveWindowExceeded)
+                        } else {
+                            incoming.receive(payload.len(), flags.contains(DataFlags::EndStream))
+                        };
+                        if let Err(e) = received {
+                            // the handler mustn't take what it got for the whole body
+                            debug!(stream_id = %frame.stream_id, "{e}, resetting stream");
+                            _ = incoming.body_tx.send(Err(e.clone().into())).await;
+                            self.rst(frame.stream_id, e).await?;
+                            return self.window_consumed(frame.stream_id, frame.len).await;
+                        }
+
+                        if incoming
+                            .body_tx
                             .send(Ok(PieceOrTrailers::Piece(payload.into())))
                             .await
                             .is_err()
                         {
-                            debug!("TODO: The body is being ignored, we should reset the stream");
+                            debug!(stream_id = %frame.stream_id, "request body is being ignored");
+                            if matches!(ss, StreamState::HalfClosedLocal(_)) {
+                                self.reap_stream(frame.stream_id).await?;
+                                return self.window_consumed(frame.stream_id, frame.len).await;
+                            }
+                            // otherwise, the handler is still running, or the
+                            // end of the response is on its way and the stream
+                            // gets reaped then.
+                            unread = frame.len;
                         }
 
                         if flags.contains(DataFlags::EndStream) {
                             // if we're HalfClosedLocal, this transitions to Closed
                             // otherwise, it transitions to HalfClosedRemote
-                            if matches!(ss, StreamState::Open(_)) {
-                                *ss = StreamState::HalfClosedRemote;
+                            if let StreamState::Open(_, outgoing) = ss {
+                                let outgoing =
+                                    std::mem::replace(outgoing, StreamOutgoing::new(0, 0));
+                                *ss = StreamState::HalfClosedRemote(outgoing);
                             } else if self.state.streams.remove(&frame.stream_id).is_some() {
                                 debug!(
                                     "Closed stream (read data w/EndStream) {}, now have {} streams",
@@ -529,15 +1150,20 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                             }
                         }
                     }
-                    StreamState::HalfClosedRemote => {
+                    StreamState::HalfClosedRemote(_) => {
                         debug!(
                             stream_id = %frame.stream_id,
                             "Received data for closed stream"
                         );
                         self.rst(frame.stream_id, H2StreamError::StreamClosed)
                             .await?;
+                        return self.window_consumed(frame.stream_id, frame.len).await;
                     }
                 }
+
+                if unread > 0 {
+                    self.window_consumed(frame.stream_id, unread).await?;
+                }
             }
             FrameType::Headers(flags) => {
                 if flags.contains(HeadersFlags::Priority) {
@@ -590,13 +1216,17 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                                 });
                             }
                             std::cmp::Ordering::Greater => {
-                                // TODO: if we're shutting down, ignore streams higher
-                                // than the last one we accepted.
-
                                 let max_concurrent_streams =
                                     self.state.self_settings.max_concurrent_streams;
                                 let num_streams_if_accept = self.state.streams.len() + 1;
-                                if num_streams_if_accept > max_concurrent_streams as _ {
+                                // once either side sent GOAWAY, nothing new
+                                // gets served: the peer retries elsewhere
+                                if self.handle.is_refusing_new_streams()
+                                    || self.goaway_recv
+                                    || self.goaway_sent.is_some()
+                                    || num_streams_if_accept > max_concurrent_streams as _
+                                    || self.is_overloaded()
+                                {
                                     // reset the stream, indicating we refused it
                                     self.rst(frame.stream_id, H2StreamError::RefusedStream)
                                         .await?;
@@ -605,12 +1235,13 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                                     mode = ReadHeadersMode::Skip;
                                 } else {
                                     self.state.last_stream_id = frame.stream_id;
+                                    self.handle.set_last_stream_id(frame.stream_id.0);
                                     mode = ReadHeadersMode::Process;
                                 }
                             }
                         }
                     }
-                    Some(StreamState::Open(_) | StreamState::HalfClosedLocal(_)) => {
+                    Some(StreamState::Open(..) | StreamState::HalfClosedLocal(_)) => {
                         headers_or_trailers = HeadersOrTrailers::Trailers;
                         debug!("Receiving trailers for stream {}", frame.stream_id);
 
@@ -625,7 +1256,7 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                                 .await?;
                         }
                     }
-                    Some(StreamState::HalfClosedRemote) => {
+                    Some(StreamState::HalfClosedRemote(_)) => {
                         return Err(H2ConnectionError::StreamClosed {
                             stream_id: frame.stream_id,
                         });
@@ -658,6 +1289,13 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                 };
                 debug!(?pri_spec, "received priority frame");
 
+                // note: we don't maintain a priority tree (RFC 9113 deprecates
+                // the RFC 7540 prioritization scheme), so nothing is retained
+                // from PRIORITY frames: there's no dependency cycle to prevent
+                // and no bookkeeping that a flood of them could grow. if a tree
+                // ever lands, it needs both, cf.
+                // https://httpwg.org/specs/rfc7540.html#rfc.section.5.3.4
+
                 if pri_spec.stream_dependency == frame.stream_id {
                     return Err(H2ConnectionError::HeadersInvalidPriority {
                         stream_id: frame.stream_id,
@@ -678,9 +1316,23 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                     .await?;
                     return Ok(());
                 }
+                if self.running_handlers.contains(&frame.stream_id) {
+                    self.count_control_frame(ControlFrameKind::RstStream)?;
+                } else {
+                    // cancelling a stream that's already been handled is
+                    // ordinary client behaviour, not a rapid reset
+                    self.handle
+                        .record_control_frame(ControlFrameKind::RstStream);
+                }
                 // TODO: do something with the error code?
 
+                self.state.drop_pending_data(frame.stream_id);
                 match self.state.streams.remove(&frame.stream_id) {
+                    None if self.state.is_closed(frame.stream_id) => {
+                        // both sides may reset a stream at once, or the
+                        // peer hadn't seen the end of it yet
+                        debug!(stream_id = %frame.stream_id, "ignoring rst for closed stream");
+                    }
                     None => {
                         return Err(H2ConnectionError::RstStreamForUnknownStream {
                             stream_id: frame.stream_id,
@@ -693,12 +1345,14 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                             self.state.streams.len()
                         );
                         match ss {
-                            StreamState::Open(body_tx) | StreamState::HalfClosedLocal(body_tx) => {
-                                _ = body_tx
+                            StreamState::Open(incoming, _)
+                            | StreamState::HalfClosedLocal(incoming) => {
+                                _ = incoming
+                                    .body_tx
                                     .send(Err(H2StreamError::ReceivedRstStream.into()))
                                     .await;
                             }
-                            StreamState::HalfClosedRemote => {
+                            StreamState::HalfClosedRemote(_) => {
                                 // good
                             }
                         }
@@ -706,6 +1360,7 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                 }
             }
             FrameType::Settings(s) => {
+                self.count_control_frame(ControlFrameKind::Settings)?;
                 if frame.stream_id != StreamId::CONNECTION {
                     return Err(H2ConnectionError::SettingsWithNonZeroStreamId {
                         stream_id: frame.stream_id,
@@ -713,12 +1368,42 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                 }
 
                 if s.contains(SettingsFlags::Ack) {
-                    debug!("Peer has acknowledged our settings, cool");
                     if !payload.is_empty() {
                         return Err(H2ConnectionError::SettingsAckWithPayload {
                             len: payload.len() as _,
                         });
                     }
+
+                    match self.state.pending_settings.pop_front() {
+                        Some((settings, _sent_at)) => {
+                            debug!("Peer has acknowledged our settings, applying them");
+                            self.max_frame_size
+                                .store(settings.max_frame_size, Ordering::Relaxed);
+                            for ss in self.state.streams.values_mut() {
+                                if let StreamState::Open(incoming, _)
+                                | StreamState::HalfClosedLocal(incoming) = ss
+                                {
+                                    incoming.window.resize(settings.initial_window_size);
+                                }
+                            }
+                            self.state.self_settings = settings;
+
+                            // from now on, the peer encodes for these settings or
+                            // for those we sent after them
+                            let max_table_size = self
+                                .state
+                                .pending_settings
+                                .iter()
+                                .map(|(pending, _)| pending.header_table_size)
+                                .fold(settings.header_table_size, std::cmp::max);
+                            self.set_hpack_dec_max_table_size(max_table_size);
+                        }
+                        None => {
+                            debug!("Peer acknowledged settings we didn't send, ignoring");
+                        }
+                    }
+                    self.handle
+                        .set_settings_ack_pending(!self.state.pending_settings.is_empty());
                 } else {
                     let (_, settings) =
                         match nom::combinator::complete(Settings::parse)(payload).finish() {
@@ -734,6 +1419,22 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                         .set_max_table_size(settings.header_table_size as usize);
 
                     debug!("Peer sent us {settings:#?}");
+
+                    // changing the initial window size shifts the window of
+                    // every stream we may still send on, cf. RFC 9113 section 6.9.2
+                    let delta = settings.initial_window_size as i64
+                        - self.state.peer_settings.initial_window_size as i64;
+                    if delta != 0 {
+                        for ss in self.state.streams.values() {
+                            if let StreamState::Open(_, outgoing)
+                            | StreamState::HalfClosedRemote(outgoing) = ss
+                            {
+                                outgoing
+                                    .adjust(delta)
+                                    .map_err(|_| H2ConnectionError::InitialWindowSizeOverflow)?;
+                            }
+                        }
+                    }
                     self.state.peer_settings = settings;
 
                     let frame = Frame::new(
@@ -748,6 +1449,7 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                 return Err(H2ConnectionError::ClientSentPushPromise);
             }
             FrameType::Ping(flags) => {
+                self.count_control_frame(ControlFrameKind::Ping)?;
                 if frame.stream_id != StreamId::CONNECTION {
                     return Err(H2ConnectionError::PingFrameWithNonZeroStreamId {
                         stream_id: frame.stream_id,
@@ -776,12 +1478,27 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                     });
                 }
 
-                self.goaway_recv = true;
+                if frame.len < 8 {
+                    return Err(H2ConnectionError::GoAwayInvalidLength { len: frame.len });
+                }
+                let (rest, (_, last_stream_id)) = parse_reserved_and_u31(payload)
+                    .finish()
+                    .map_err(|err| eyre::eyre!("parsing error: {err:?}"))?;
+                let error_code = u32::from_be_bytes(rest[..4].try_into().unwrap());
+                debug!(
+                    %last_stream_id,
+                    error_code = format_args!("0x{error_code:02x}"),
+                    debug_data = %String::from_utf8_lossy(&rest[4..]),
+                    "peer is going away, draining"
+                );
 
-                // TODO: this should probably have other effects than setting
-                // this flag.
+                // we never push, so `last_stream_id` has nothing to tell us:
+                // the streams it opened get served, and that's it.
+                self.goaway_recv = true;
+                self.handle.set_draining();
             }
             FrameType::WindowUpdate => {
+                self.count_control_frame(ControlFrameKind::WindowUpdate)?;
                 if payload.len() != 4 {
                     return Err(H2ConnectionError::WindowUpdateInvalidLength {
                         len: payload.len() as _,
@@ -798,16 +1515,35 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                 }
 
                 if frame.stream_id == StreamId::CONNECTION {
-                    debug!("TODO: ignoring connection-wide window update");
+                    self.state.outgoing_window += increment as i64;
+                    if self.state.outgoing_window > MAX_WINDOW_SIZE {
+                        return Err(H2ConnectionError::WindowUpdateOverflow);
+                    }
+                    debug!(window = %self.state.outgoing_window, "connection window opened");
+                    self.write_pending_data().await?;
                 } else {
                     match self.state.streams.get_mut(&frame.stream_id) {
+                        None if self.state.is_closed(frame.stream_id) => {
+                            // the peer may not know we're done with it yet,
+                            // cf. RFC 9113 section 6.9
+                            debug!(stream_id = %frame.stream_id, "ignoring window update for closed stream");
+                        }
                         None => {
                             return Err(H2ConnectionError::WindowUpdateForUnknownStream {
                                 stream_id: frame.stream_id,
                             });
                         }
-                        Some(_ss) => {
-                            debug!("TODO: handle window update for stream {}", frame.stream_id)
+                        Some(
+                            StreamState::Open(_, outgoing)
+                            | StreamState::HalfClosedRemote(outgoing),
+                        ) => {
+                            if outgoing.adjust(increment as i64).is_err() {
+                                self.rst(frame.stream_id, H2StreamError::WindowUpdateOverflow)
+                                    .await?;
+                            }
+                        }
+                        Some(StreamState::HalfClosedLocal(_)) => {
+                            // we're done sending on that stream, nothing to do
                         }
                     }
                 }
@@ -817,18 +1553,285 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                     stream_id: frame.stream_id,
                 });
             }
-            FrameType::Unknown(ft) => {
-                trace!(
-                    "ignoring unknown frame with type 0x{:x}, flags 0x{:x}",
-                    ft.ty,
-                    ft.flags
-                );
-            }
-        }
-
-        Ok(())
-    }
-
+            FrameType::PriorityUpdate => {
+                self.count_control_frame(ControlFrameKind::PriorityUpdate)?;
+                if frame.stream_id != StreamId::CONNECTION {
+                    return Err(H2ConnectionError::PriorityUpdateWithNonZeroStreamId {
+                        stream_id: frame.stream_id,
+                    });
+                }
+                if frame.len < 4 {
+                    return Err(H2ConnectionError::PriorityUpdateInvalidLength { len: frame.len });
+                }
+
+                let (field_value, (_, prioritized_stream_id)) = parse_reserved_and_u31(payload)
+                    .finish()
+                    .map_err(|err| eyre::eyre!("parsing error: {err:?}"))?;
+                let stream_id = StreamId(prioritized_stream_id);
+                if stream_id == StreamId::CONNECTION {
+                    return Err(H2ConnectionError::PriorityUpdateForConnection);
+                }
+                let priority = Priority::parse(&field_value[..]);
+                debug!(%stream_id, ?priority, "received priority update");
+
+                match self.state.streams.get_mut(&stream_id) {
+                    Some(
+                        StreamState::Open(_, outgoing) | StreamState::HalfClosedRemote(outgoing),
+                    ) => {
+                        outgoing.priority = priority;
+                    }
+                    Some(StreamState::HalfClosedLocal(_)) => {
+                        // we're done sending, nothing left to prioritize
+                    }
+                    None => {
+                        // we never push, so only the peer opens streams
+                        if !stream_id.is_server_initiated() && stream_id > self.state.last_stream_id
+                        {
+                            self.state.record_early_priority(stream_id, priority);
+                        }
+                    }
+                }
+            }
+            FrameType::Unknown(ft) => {
+                trace!(
+                    "ignoring unknown frame with type 0x{:x}, flags 0x{:x}",
+                    ft.ty,
+                    ft.flags
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Answer a request with an empty response of the given status, without
+    /// involving the driver. The response is a single HEADERS frame that
+    /// ends our side of the stream. `end_stream` is whether the request
+    /// ended with its HEADERS: if not, the peer may still be sending a body,
+    /// and the stream gets reset with NO_ERROR once the response is out so
+    /// that it stops, cf. <https://httpwg.org/specs/rfc9113.html#rfc.section.8.1>
+    async fn respond_with_status(
+        &mut self,
+        stream_id: StreamId,
+        status: StatusCode,
+        end_stream: bool,
+    ) -> Result<(), H2ConnectionError> {
+        // nothing is left to send on the stream once this is out, and nothing
+        // reads what the peer may still send: the stream is never tracked.
+        let flags = HeadersFlags::EndHeaders | HeadersFlags::EndStream;
+        let frame = Frame::new(FrameType::Headers(flags), stream_id);
+        let payload = self.encode_header_block(Some(status), &Default::default())?;
+        self.write_frame(frame, payload).await?;
+
+        if !end_stream {
+            self.rst(stream_id, H2StreamError::RequestBodyAbandoned)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Sends GOAWAY and starts waiting for accepted streams to be done, see
+    /// [ConnectionHandle::shutdown]
+    async fn start_shutdown(&mut self, drain_timeout: Duration) -> Result<(), H2ConnectionError> {
+        debug!(?drain_timeout, "shutting down, draining streams");
+        self.drain_deadline = Some(tokio::time::Instant::now() + drain_timeout);
+        self.send_goaway(KnownErrorCode::NoError, &[]).await
+    }
+
+    /// Whether we're waiting for accepted streams to be done before closing
+    /// the connection, because either side sent GOAWAY
+    fn is_draining(&self) -> bool {
+        self.drain_deadline.is_some() || self.goaway_recv
+    }
+
+    /// Whether nothing is left to do on this connection: no streams open,
+    /// no handlers running, no data waiting to go out
+    fn is_drained(&self) -> bool {
+        self.state.streams.is_empty()
+            && self.running_handlers.is_empty()
+            && self.state.pending_data.is_empty()
+    }
+
+    /// Sends a SETTINGS frame, the settings take effect once the peer
+    /// acknowledges it.
+    async fn send_settings(&mut self, settings: Settings) -> Result<(), H2ConnectionError> {
+        let payload = settings.into_roll(&mut self.out_scratch)?;
+        let frame = Frame::new(
+            FrameType::Settings(Default::default()),
+            StreamId::CONNECTION,
+        );
+        self.write_frame(frame, payload).await?;
+
+        self.state
+            .pending_settings
+            .push_back((settings, tokio::time::Instant::now()));
+        self.handle.set_settings_ack_pending(true);
+
+        // the peer may shrink or grow its table as soon as it reads this, so
+        // a larger table is allowed right away, but a smaller one has to
+        // wait for the acknowledgement
+        if settings.header_table_size > self.hpack_dec_max_table_size {
+            self.set_hpack_dec_max_table_size(settings.header_table_size);
+        }
+        Ok(())
+    }
+
+    fn set_hpack_dec_max_table_size(&mut self, size: u32) {
+        self.hpack_dec_max_table_size = size;
+        self.hpack_dec.set_max_allowed_table_size(size as usize);
+    }
+
+    /// Sends settings changed through [ConnectionHandle::update_settings]
+    async fn update_settings(&mut self, update: SettingsUpdate) -> Result<(), H2ConnectionError> {
+        // on top of the latest settings we sent, acknowledged or not
+        let mut settings = self
+            .state
+            .pending_settings
+            .back()
+            .map(|(settings, _)| *settings)
+            .unwrap_or(self.state.self_settings);
+        if let Some(v) = update.max_concurrent_streams {
+            settings.max_concurrent_streams = v;
+        }
+        if let Some(v) = update.initial_window_size {
+            settings.initial_window_size = v;
+        }
+        if let Some(v) = update.max_frame_size {
+            settings.max_frame_size = v;
+        }
+        if let Some(v) = update.header_table_size {
+            settings.header_table_size = v;
+        }
+
+        debug!(?update, "Sending updated settings");
+        self.send_settings(settings).await
+    }
+
+    /// Gets rid of a stream whose response went out in full, if nobody is
+    /// going to read the rest of its request body: otherwise, a peer that
+    /// never ends its side of the stream would keep it around (and counting
+    /// towards max concurrent streams) for the life of the connection.
+    ///
+    /// Called whenever one of the two halves of that condition might have
+    /// just become true: when the response ends, and once the handler returns.
+    async fn reap_stream(&mut self, stream_id: StreamId) -> Result<(), H2ConnectionError> {
+        let abandoned = matches!(
+            self.state.streams.get(&stream_id),
+            Some(StreamState::HalfClosedLocal(incoming)) if incoming.body_tx.is_closed()
+        );
+        if !abandoned {
+            return Ok(());
+        }
+
+        self.handle.record_reaped_stream();
+        debug!(
+            %stream_id,
+            reaped_streams = %self.handle.reaped_streams(),
+            "reaping stream whose request body was abandoned"
+        );
+        self.rst(stream_id, H2StreamError::RequestBodyAbandoned)
+            .await
+    }
+
+    /// Counts a control frame towards [ConnStats](super::ConnStats), fails
+    /// if the peer is flooding us with them
+    fn count_control_frame(&mut self, kind: ControlFrameKind) -> Result<(), H2ConnectionError> {
+        self.handle.record_control_frame(kind);
+
+        let limits = self.conf.control_frame_limits;
+        if !self.state.control_frames.record(kind, &limits) {
+            self.handle.record_control_frame_flood(kind);
+            return Err(H2ConnectionError::ControlFrameFlood {
+                kind,
+                error_code: limits.error_code,
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether the configured [LoadShedder] wants the next stream refused
+    fn is_overloaded(&self) -> bool {
+        let Some(ls) = self.conf.load_shedder.as_ref() else {
+            return false;
+        };
+        let load = Load::current();
+        let shed = ls.shed(&load).is_some();
+        if shed {
+            debug!(?load, "overloaded, refusing stream");
+        }
+        shed
+    }
+
+    /// Hands out the WINDOW_UPDATE frames warranted by what handlers read
+    /// since last time
+    async fn send_window_updates(&mut self) -> Result<(), H2ConnectionError> {
+        for (stream_id, len) in self.consumed.take() {
+            self.window_consumed(stream_id, len).await?;
+        }
+        Ok(())
+    }
+
+    /// Accounts for `len` bytes of DATA received on `stream_id` being read
+    /// (or dropped), and lets the peer send more, on the connection and on
+    /// the stream if it's still sending on it, once
+    /// [ServerConf::window_update_strategy] says so.
+    async fn window_consumed(
+        &mut self,
+        stream_id: StreamId,
+        len: u32,
+    ) -> Result<(), H2ConnectionError> {
+        let strategy = self.conf.window_update_strategy;
+        let was_exhausted = self.state.incoming_window.is_exhausted();
+        if let Some(increment) = self.state.incoming_window.consume(len, strategy) {
+            if was_exhausted {
+                // streams couldn't make progress until now, their idle
+                // timeouts start over
+                let now = tokio::time::Instant::now();
+                for ss in self.state.streams.values_mut() {
+                    if let StreamState::Open(incoming, _) | StreamState::HalfClosedLocal(incoming) =
+                        ss
+                    {
+                        incoming.last_progress = now;
+                    }
+                }
+            }
+            self.send_window_update(StreamId::CONNECTION, increment)
+                .await?;
+        }
+
+        let stream_increment = match self.state.streams.get_mut(&stream_id) {
+            Some(StreamState::Open(incoming, _) | StreamState::HalfClosedLocal(incoming)) => {
+                let increment = incoming.window.consume(len, strategy);
+                if increment.is_some() {
+                    incoming.last_progress = tokio::time::Instant::now();
+                }
+                increment
+            }
+            // the peer is done sending, or the stream is gone
+            _ => None,
+        };
+        if let Some(increment) = stream_increment {
+            self.send_window_update(stream_id, increment).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_window_update(
+        &mut self,
+        stream_id: StreamId,
+        increment: u32,
+    ) -> Result<(), H2ConnectionError> {
+        debug!(%stream_id, %increment, "Sending WindowUpdate");
+        let payload = self.out_scratch.put_to_roll(4, |mut slice| {
+            slice.write_u32::<BigEndian>(increment)?;
+            Ok(())
+        })?;
+
+        let frame = Frame::new(FrameType::WindowUpdate, stream_id)
+            .with_len((payload.len()).try_into().unwrap());
+        self.write_frame(frame, payload).await
+    }
+
     /// Send a RST_STREAM frame to the peer.
     async fn rst(
         &mut self,
@@ -836,9 +1839,21 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
         e: H2StreamError,
     ) -> Result<(), H2ConnectionError> {
         self.state.streams.remove(&stream_id);
+        self.state.record_reset(stream_id);
+        self.state.drop_pending_data(stream_id);
 
         let error_code = e.as_known_error_code();
         debug!("Sending rst because: {e} (known error code: {error_code:?})");
+        if let Some(observer) = &self.conf.error_observer {
+            observer.on_stream_reset(
+                &self.conn_info,
+                stream_id,
+                &ProtocolError {
+                    code: error_code,
+                    message: e.to_string(),
+                },
+            );
+        }
 
         debug!(%stream_id, ?error_code, "Sending RstStream");
         let payload = self.out_scratch.put_to_roll(4, |mut slice| {
@@ -853,6 +1868,175 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
         Ok(())
     }
 
+    /// Like [Self::rst], for a stream the peer is still sending on: whoever
+    /// reads its body gets `e` rather than a body that looks complete.
+    async fn rst_incoming(
+        &mut self,
+        stream_id: StreamId,
+        e: H2StreamError,
+    ) -> Result<(), H2ConnectionError> {
+        if let Some(StreamState::Open(incoming, _) | StreamState::HalfClosedLocal(incoming)) =
+            self.state.streams.remove(&stream_id)
+        {
+            _ = incoming.body_tx.send(Err(e.clone().into())).await;
+        }
+        self.rst(stream_id, e).await
+    }
+
+    /// Hands a request to the driver, on a task of its own, unless it goes
+    /// over limits. `end_stream` says whether the request has no body.
+    async fn start_stream(
+        &mut self,
+        stream_id: StreamId,
+        req: Request,
+        end_stream: bool,
+    ) -> Result<(), H2ConnectionError> {
+        if let Some(content_length @ 1..) = req.headers.content_length().filter(|_| end_stream) {
+            // there won't be any DATA to add up to it
+            return self
+                .rst(
+                    stream_id,
+                    H2StreamError::DataLengthDoesNotMatchContentLength {
+                        data_length: 0,
+                        content_length,
+                    },
+                )
+                .await;
+        }
+
+        let mut limits = RequestLimits {
+            max_request_body_len: self.conf.max_request_body_len,
+            body_idle_timeout: self.conf.body_idle_timeout,
+            ..Default::default()
+        };
+        self.driver.request_limits(&req, &mut limits);
+
+        if let Some(content_len) = req.headers.content_length() {
+            if content_len > limits.max_request_body_len {
+                debug!(%content_len, "request body too large, responding early");
+                self.respond_with_status(stream_id, StatusCode::PAYLOAD_TOO_LARGE, end_stream)
+                    .await?;
+                return Ok(());
+            }
+        }
+
+        let mut outgoing = StreamOutgoing::new(
+            self.state.peer_settings.initial_window_size,
+            self.conf.max_queued_body_data,
+        );
+        // PRIORITY_UPDATE overrides the header, cf. RFC 9218 section 7.1
+        outgoing.priority = match self.state.take_early_priority(stream_id) {
+            Some(priority) => priority,
+            None => Priority::from_headers(&req.headers),
+        };
+        let unanswered: Rc<Cell<bool>> = Default::default();
+        let encoder = H2Encoder {
+            stream_id,
+            tx: self.ev_tx.clone(),
+            state: EncoderState::ExpectResponseHeaders,
+            window: outgoing.window(),
+            correlation_id: req.correlation_id.clone(),
+            unanswered: unanswered.clone(),
+        };
+        // for answering in the handler's stead if it fails before responding
+        let window = outgoing.window();
+        let correlation_id = req.correlation_id.clone();
+        let responder = Responder {
+            encoder,
+            // TODO: why tf is this state encoded twice? is that really
+            // necessary? I know it's for typestates and H2Encoder needs
+            // to look up its state at runtime I guess, but.. that's not great?
+            state: ExpectResponseHeaders,
+        };
+
+        let (piece_tx, piece_rx) = mpsc::channel::<H2BodyItem>(1); // TODO: is 1 a sensible value here?
+
+        let content_length = req.headers.content_length();
+        let req_body = H2Body {
+            stream_id,
+            content_length: if end_stream { Some(0) } else { content_length },
+            eof: end_stream,
+            rx: piece_rx,
+            consumed: self.consumed.clone(),
+        };
+
+        self.state.streams.insert(
+            stream_id,
+            if end_stream {
+                StreamState::HalfClosedRemote(outgoing)
+            } else {
+                StreamState::Open(
+                    StreamIncoming::new(
+                        piece_tx,
+                        content_length,
+                        self.state.self_settings.initial_window_size,
+                        limits.body_idle_timeout,
+                    ),
+                    outgoing,
+                )
+            },
+        );
+        debug!(
+            "Just accepted stream, now have {} streams",
+            self.state.streams.len()
+        );
+
+        self.running_handlers.insert(stream_id);
+        fluke_maybe_uring::spawn({
+            let driver = self.driver.clone();
+            let ev_tx = self.ev_tx.clone();
+            let active = ActiveHandler::enter();
+            async move {
+                // dropped last, even if the handler panics
+                let _done = HandlerDone {
+                    stream_id,
+                    tx: ev_tx.clone(),
+                };
+                let _active = active;
+                let mut req_body = req_body;
+                let responder = responder;
+
+                let err = match driver.handle(req, &mut req_body, responder).await {
+                    Ok(_responder) => {
+                        debug!("Handler completed successfully, gave us a responder");
+                        None
+                    }
+                    Err(e) => {
+                        warn!(%stream_id, "Handler returned an error: {e:#}");
+                        Some(e)
+                    }
+                };
+                if unanswered.get() {
+                    let err = err.unwrap_or_else(|| eyre::eyre!("handler dropped its responder"));
+                    let (res, body) = driver.error_response(&err);
+                    // only created now: dropped unused while unwinding from a
+                    // panicking handler, it would answer a second time.
+                    let respond = Responder {
+                        encoder: H2Encoder {
+                            stream_id,
+                            tx: ev_tx.clone(),
+                            state: EncoderState::ExpectResponseHeaders,
+                            window,
+                            correlation_id,
+                            unanswered: Default::default(),
+                        },
+                        state: ExpectResponseHeaders,
+                    };
+                    if let Err(e) = write_error_response(respond, res, body).await {
+                        debug!(%stream_id, "Couldn't send error response: {e}");
+                    }
+                }
+
+                // nobody is reading the request body anymore, which
+                // may be enough to get rid of the stream once `_done` tells
+                // the connection about it.
+                drop(req_body);
+            }
+        });
+
+        Ok(())
+    }
+
     async fn read_headers(
         &mut self,
         headers_or_trailers: HeadersOrTrailers,
@@ -879,9 +2063,22 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
             let flags = (); // don't accidentally use the `flags` variable
 
             let mut fragments = smallvec![payload];
+            let deadline = self
+                .conf
+                .header_timeout
+                .map(|timeout| (tokio::time::Instant::now() + timeout, timeout));
 
             loop {
-                let (continuation_frame, continuation_payload) = match rx.recv().await {
+                let next = match deadline {
+                    Some((deadline, timeout)) => {
+                        match tokio::time::timeout_at(deadline, rx.recv()).await {
+                            Ok(next) => next,
+                            Err(_) => return Err(H2ConnectionError::HeaderTimeout { timeout }),
+                        }
+                    }
+                    None => rx.recv().await,
+                };
+                let (continuation_frame, continuation_payload) = match next {
                     Some(t) => t,
                     None => {
                         // even though this error is "for a stream", it's a
@@ -932,9 +2129,38 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
         let mut method: Option<Method> = None;
         let mut scheme: Option<Scheme> = None;
         let mut path: Option<PieceStr> = None;
-        let mut authority: Option<Authority> = None;
+        let mut authority: Option<PieceStr> = None;
+        let mut protocol: Option<PieceStr> = None;
 
         let mut headers = Headers::default();
+        let strict = self.conf.header_strictness == HeaderStrictness::Strict;
+        let enable_connect_protocol = self.conf.enable_connect_protocol;
+        let mut malformed: Option<H2StreamError> = None;
+
+        // counted like SETTINGS_MAX_HEADER_LIST_SIZE: name + value + 32 bytes
+        // of overhead per field.
+        let max_header_list_size = self.conf.max_header_list_size;
+        let mut header_list_size = 0;
+
+        let encoded_len = match &data {
+            Data::Single(payload) => payload.len(),
+            Data::Multi(fragments) => fragments.iter().map(|f| f.len()).sum(),
+        };
+        // decoded header values get copied here rather than into individual
+        // heap allocations, see `arena_piece`. each header block gets an arena
+        // of its own: its buffer goes back to the pool as soon as the request
+        // (or its trailers) is done with, even while other streams are open.
+        let mut arena = RollMut::empty();
+        if encoded_len > BUF_SIZE as usize {
+            // a big header block (think lots of cookies) would have its values
+            // scattered over a bunch of pool buffers: make room for all of it
+            // in a single, larger allocation instead.
+            let spill_len = std::cmp::min(encoded_len * 2, max_header_list_size);
+            if let Err(e) = arena.reserve_at_least(spill_len) {
+                debug!("could not reserve {spill_len} bytes for headers: {e}");
+            }
+        }
+        let arena = &mut arena;
 
         // TODO: find a way to propagate errors from here - probably will have to change
         // the function signature in fluke-hpack, or just write to some captured
@@ -946,53 +2172,113 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                 std::str::from_utf8(&value).unwrap_or("<non-utf8-value>"),
             );
 
-            if &key[..1] == b":" {
+            header_list_size += key.len() + value.len() + 32;
+            if header_list_size > max_header_list_size {
+                // keep decoding so the hpack state stays in sync with the
+                // peer's, but stop buffering anything.
+                return;
+            }
+
+            if key.first() == Some(&b':') {
                 if matches!(headers_or_trailers, HeadersOrTrailers::Trailers) {
-                    // TODO: proper error handling
-                    panic!("trailers cannot contain pseudo-headers");
+                    malformed.get_or_insert(H2StreamError::PseudoHeaderInTrailers {
+                        name: String::from_utf8_lossy(&key).into_owned(),
+                    });
+                    return;
                 }
 
                 // it's a pseudo-header!
                 // TODO: reject headers that occur after pseudo-headers
                 match &key[1..] {
                     b"method" => {
-                        // TODO: error handling
-                        let value: PieceStr = Piece::from(value.to_vec()).to_str().unwrap();
-                        if method.replace(Method::from(value)).is_some() {
-                            unreachable!(); // No duplicate allowed.
-                        }
+                        let value = arena_piece(arena, &value)
+                            .to_str()
+                            .ok()
+                            .filter(|v| !v.is_empty())
+                            .map(Method::from);
+                        set_pseudo_header(&mut method, ":method", value, &mut malformed);
                     }
                     b"scheme" => {
-                        // TODO: error handling
-                        let value: PieceStr = Piece::from(value.to_vec()).to_str().unwrap();
-                        if scheme.replace(value.parse().unwrap()).is_some() {
-                            unreachable!(); // No duplicate allowed.
-                        }
+                        let value = arena_piece(arena, &value)
+                            .to_str()
+                            .ok()
+                            .and_then(|v| v.parse().ok());
+                        set_pseudo_header(&mut scheme, ":scheme", value, &mut malformed);
                     }
                     b"path" => {
-                        // TODO: error handling
-                        let value: PieceStr = Piece::from(value.to_vec()).to_str().unwrap();
-                        if value.len() == 0 || path.replace(value).is_some() {
-                            unreachable!(); // No empty path nor duplicate allowed.
-                        }
+                        let value = arena_piece(arena, &value)
+                            .to_str()
+                            .ok()
+                            .filter(|v| !v.is_empty());
+                        set_pseudo_header(&mut path, ":path", value, &mut malformed);
                     }
                     b"authority" => {
-                        // TODO: error handling
-                        let value: PieceStr = Piece::from(value.to_vec()).to_str().unwrap();
-                        if authority.replace(value.parse().unwrap()).is_some() {
-                            unreachable!(); // No duplicate allowed. (h2spec doesn't seem to test for
-                                            // this case but rejecting duplicates seems reasonable.)
+                        // h2spec doesn't seem to test for duplicates, but
+                        // rejecting them seems reasonable.
+                        let value = arena_piece(arena, &value)
+                            .to_str()
+                            .ok()
+                            .filter(|v| v.parse::<http::uri::Authority>().is_ok());
+                        set_pseudo_header(&mut authority, ":authority", value, &mut malformed);
+                    }
+                    // only known if we advertised it, cf. https://www.rfc-editor.org/rfc/rfc8441#section-3
+                    b"protocol" if enable_connect_protocol => {
+                        let value = arena_piece(arena, &value).to_str();
+                        match value {
+                            Ok(value) if protocol.is_none() => protocol = Some(value),
+                            _ => {
+                                malformed.get_or_insert(H2StreamError::InvalidProtocolPseudoHeader);
+                            }
                         }
                     }
                     _ => {
-                        debug!("ignoring pseudo-header");
+                        if strict {
+                            malformed.get_or_insert(H2StreamError::UnknownPseudoHeader {
+                                name: String::from_utf8_lossy(&key).into_owned(),
+                            });
+                        } else {
+                            debug!("ignoring pseudo-header");
+                        }
                     }
                 }
             } else {
-                // TODO: what do we do in case of malformed header names?
-                // ignore it? return a 400?
-                let name = HeaderName::from_bytes(&key[..]).expect("malformed header name");
-                let value: Piece = value.to_vec().into();
+                // `HeaderName::from_bytes` would happily lowercase them, but
+                // field names must be sent lowercase over h2, cf.
+                // https://httpwg.org/specs/rfc9113.html#rfc.section.8.2.1
+                if key.iter().any(|b| b.is_ascii_uppercase()) {
+                    malformed.get_or_insert(H2StreamError::UppercaseHeaderName {
+                        name: String::from_utf8_lossy(&key).into_owned(),
+                    });
+                    return;
+                }
+                let name = match HeaderName::from_bytes(&key[..]) {
+                    Ok(name) => name,
+                    Err(_) => {
+                        malformed.get_or_insert(H2StreamError::InvalidHeaderName {
+                            name: String::from_utf8_lossy(&key).into_owned(),
+                        });
+                        return;
+                    }
+                };
+                // cf. https://httpwg.org/specs/rfc9113.html#ConnectionSpecific:
+                // `te` is the one exception, as long as it only says `trailers`
+                if is_connection_specific(&name) {
+                    malformed.get_or_insert(H2StreamError::ConnectionSpecificHeader { name });
+                    return;
+                }
+                if name == header::TE && !value.eq_ignore_ascii_case(b"trailers") {
+                    malformed.get_or_insert(H2StreamError::InvalidTeHeader);
+                    return;
+                }
+                if SINGLETON_HEADERS.contains(&name) && headers.contains_key(&name) {
+                    if strict {
+                        malformed.get_or_insert(H2StreamError::DuplicateSingletonHeader { name });
+                    } else {
+                        debug!(%name, "ignoring duplicate singleton header");
+                    }
+                    return;
+                }
+                let value = arena_piece(arena, &value);
                 headers.append(name, value);
             }
         };
@@ -1004,11 +2290,12 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                     .map_err(|e| H2ConnectionError::CompressionError(format!("{e:?}")))?;
             }
             Data::Multi(fragments) => {
-                let total_len = fragments.iter().map(|f| f.len()).sum();
-                // this is a slow path, let's do a little heap allocation. we could
-                // be using `RollMut` for this, but it would probably need to resize
-                // a bunch
-                let mut payload = Vec::with_capacity(total_len);
+                // this is a slow path: the fragments need to be contiguous for
+                // the hpack decoder. the scratch buffer is kept around for the
+                // whole connection, so this only allocates when a header block
+                // is bigger than any we've seen so far.
+                let payload = &mut self.continuation_scratch;
+                payload.clear();
                 for frag in &fragments {
                     payload.extend_from_slice(&frag[..]);
                 }
@@ -1018,6 +2305,35 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
             }
         };
 
+        if header_list_size > max_header_list_size {
+            debug!(%header_list_size, %max_header_list_size, "header list too large");
+            match headers_or_trailers {
+                HeadersOrTrailers::Headers => {
+                    self.respond_with_status(
+                        stream_id,
+                        StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                        end_stream,
+                    )
+                    .await?;
+                }
+                HeadersOrTrailers::Trailers => {
+                    self.rst_incoming(stream_id, H2StreamError::HeaderListTooLarge)
+                        .await?;
+                }
+            }
+            return Ok(());
+        }
+
+        if let Some(err) = malformed {
+            // the header block was still fully decoded above, so the hpack
+            // dynamic table stays in sync with the peer's.
+            match headers_or_trailers {
+                HeadersOrTrailers::Headers => self.rst(stream_id, err).await?,
+                HeadersOrTrailers::Trailers => self.rst_incoming(stream_id, err).await?,
+            }
+            return Ok(());
+        }
+
         match headers_or_trailers {
             HeadersOrTrailers::Headers => {
                 // TODO: cf. https://httpwg.org/specs/rfc9113.html#HttpRequest
@@ -1025,111 +2341,333 @@ impl<D: ServerDriver + 'static, W: WriteOwned> ServerContext<D, W> {
                 // field that identifies an entity that differs from the entity in the
                 // ":authority" pseudo-header field.
 
-                // TODO: proper error handling (return 400)
-                let method = method.unwrap();
-                let scheme = scheme.unwrap();
+                // cf. https://httpwg.org/specs/rfc9113.html#rfc.section.8.3.1:
+                // these are all required. CONNECT requests, which would have
+                // neither `:scheme` nor `:path`, aren't supported.
+                let (method, scheme, path) = match (method, scheme, path) {
+                    (Some(method), Some(scheme), Some(path)) => (method, scheme, path),
+                    (method, scheme, _) => {
+                        let name = if method.is_none() {
+                            ":method"
+                        } else if scheme.is_none() {
+                            ":scheme"
+                        } else {
+                            ":path"
+                        };
+                        self.rst(stream_id, H2StreamError::MissingPseudoHeader { name })
+                            .await?;
+                        return Ok(());
+                    }
+                };
+                if protocol.is_some() && method != Method::Connect {
+                    self.rst(stream_id, H2StreamError::InvalidProtocolPseudoHeader)
+                        .await?;
+                    return Ok(());
+                }
+                // cf. https://httpwg.org/specs/rfc9113.html#rfc.section.8.3.1:
+                // the client picks `:scheme`, so don't let it claim `https`
+                // over a connection we know is cleartext.
+                if scheme == Scheme::HTTPS
+                    && self.conf.transport_security == TransportSecurity::Plaintext
+                    && !self.conf.trust_forwarded_scheme
+                {
+                    self.rst(
+                        stream_id,
+                        H2StreamError::SchemeDoesNotMatchTransport {
+                            scheme,
+                            transport_security: self.conf.transport_security,
+                        },
+                    )
+                    .await?;
+                    return Ok(());
+                }
 
-                let path = path.unwrap();
-                let path_and_query: PathAndQuery = path.parse().unwrap();
+                if path.len() > self.conf.max_uri_len {
+                    debug!(uri_len = %path.len(), "request target too long");
+                    self.respond_with_status(stream_id, StatusCode::URI_TOO_LONG, end_stream)
+                        .await?;
+                    return Ok(());
+                }
 
                 let authority = match authority {
                     Some(authority) => Some(authority),
                     None => headers
                         .get(header::HOST)
-                        .map(|host| host.as_str().unwrap().parse().unwrap()),
+                        .and_then(|host| host.clone().to_str().ok()),
                 };
+                if let (Some(policy), Some(authority)) = (&self.conf.authority_policy, &authority) {
+                    if !policy.accepts(authority, self.conn_info.tls.as_ref()) {
+                        debug!(%authority, "request for an authority this connection doesn't serve");
+                        self.respond_with_status(
+                            stream_id,
+                            StatusCode::MISDIRECTED_REQUEST,
+                            end_stream,
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+                }
 
-                let mut uri_parts: http::uri::Parts = Default::default();
-                uri_parts.scheme = Some(scheme);
-                uri_parts.authority = authority;
-                uri_parts.path_and_query = Some(path_and_query);
-
-                let uri = http::uri::Uri::from_parts(uri_parts).unwrap();
-
+                // parsing this into an `http::Uri` is left to whoever needs it
+                let uri = RequestUri::new(Some(scheme), authority, path);
+
+                let id = self.request_ids.next();
+                let correlation_id = self
+                    .conf
+                    .request_id_header
+                    .as_ref()
+                    .map(|header| CorrelationId::for_request(header, &headers, id));
+                let client_addr = self
+                    .conf
+                    .trusted_proxies
+                    .client_addr(self.conn_info.peer_addr.as_ref(), &headers);
                 let req = Request {
                     method,
                     uri,
+                    protocol,
                     version: Version::HTTP_2,
                     headers,
+                    transport_security: self.conf.transport_security,
+                    conn_info: self.conn_info.clone(),
+                    id,
+                    correlation_id,
+                    client_addr,
+                    stream_id: Some(stream_id.0),
                 };
-
-                let responder = Responder {
-                    encoder: H2Encoder {
-                        stream_id,
-                        tx: self.ev_tx.clone(),
-                        state: EncoderState::ExpectResponseHeaders,
-                    },
-                    // TODO: why tf is this state encoded twice? is that really
-                    // necessary? I know it's for typestates and H2Encoder needs
-                    // to look up its state at runtime I guess, but.. that's not great?
-                    state: ExpectResponseHeaders,
+                debug!(%stream_id, "got request {req:?}");
+                self.start_stream(stream_id, req, end_stream).await?;
+            }
+            HeadersOrTrailers::Trailers => {
+                // trailers end the stream: if we're still sending, this
+                // transitions to HalfClosedRemote, otherwise to Closed.
+                let ss = match self.state.streams.remove(&stream_id) {
+                    Some(ss) => ss,
+                    None => unreachable!("stream should be open when we receive trailers"),
                 };
-
-                let (piece_tx, piece_rx) = mpsc::channel::<H2BodyItem>(1); // TODO: is 1 a sensible value here?
-
-                let req_body = H2Body {
-                    // FIXME: that's not right. h2 requests can still specify
-                    // a content-length
-                    content_length: if end_stream { Some(0) } else { None },
-                    eof: end_stream,
-                    rx: piece_rx,
+                let (mut incoming, outgoing) = match ss {
+                    StreamState::Open(incoming, outgoing) => (incoming, Some(outgoing)),
+                    StreamState::HalfClosedLocal(incoming) => (incoming, None),
+                    StreamState::HalfClosedRemote(_) => {
+                        unreachable!("stream should be open when we receive trailers")
+                    }
                 };
+                if let Err(e) = incoming.receive(0, true) {
+                    debug!(%stream_id, "{e}, resetting stream");
+                    _ = incoming.body_tx.send(Err(e.clone().into())).await;
+                    return self.rst(stream_id, e).await;
+                }
+                if incoming
+                    .body_tx
+                    .send(Ok(PieceOrTrailers::Trailers(Box::new(headers))))
+                    .await
+                    .is_err()
+                {
+                    // the body is being ignored, but there's no point in
+                    // resetting the stream since we just got the end of it
+                }
+                if let Some(outgoing) = outgoing {
+                    self.state
+                        .streams
+                        .insert(stream_id, StreamState::HalfClosedRemote(outgoing));
+                }
+            }
+        }
 
-                self.state.streams.insert(
-                    stream_id,
-                    if end_stream {
-                        StreamState::HalfClosedRemote
-                    } else {
-                        StreamState::Open(piece_tx)
-                    },
-                );
-                debug!(
-                    "Just accepted stream, now have {} streams",
-                    self.state.streams.len()
-                );
+        Ok(())
+    }
+}
 
-                fluke_maybe_uring::spawn({
-                    let driver = self.driver.clone();
-                    async move {
-                        let mut req_body = req_body;
-                        let responder = responder;
+/// Reads frames from `transport_r` and sends them to `tx`, with padding
+/// removed, until the peer hangs up. Shared by the server and the client.
+pub(crate) async fn deframe_loop(
+    mut client_buf: RollMut,
+    mut transport_r: impl ReadOwned,
+    tx: mpsc::Sender<(Frame, Roll)>,
+    max_frame_size: Rc<AtomicU32>,
+    read_chunk_size: usize,
+    idle: Rc<Cell<bool>>,
+) -> Result<(), H2ConnectionError> {
+    'read_frames: loop {
+        const MAX_FRAME_HEADER_SIZE: usize = 128;
+        let frame;
+
+        if client_buf.is_empty() && idle.get() {
+            trace!("Connection is idle, giving our buffer back while we wait");
+            drop(std::mem::replace(&mut client_buf, RollMut::empty()));
+            client_buf = match read_when_idle(&mut transport_r).await {
+                Ok(Some(client_buf)) => client_buf,
+                Ok(None) => {
+                    debug!("Peer hung up");
+                    break 'read_frames;
+                }
+                Err(e) => return Err(H2ConnectionError::ReadError(e)),
+            };
+        }
 
-                        match driver.handle(req, &mut req_body, responder).await {
-                            Ok(_responder) => {
-                                debug!("Handler completed successfully, gave us a responder");
-                            }
-                            Err(e) => {
-                                // TODO: actually handle that error.
-                                debug!("Handler returned an error: {e}")
-                            }
-                        }
-                    }
+        trace!("Reading frame... Buffer length: {}", client_buf.len());
+        // frame headers are tiny, so it's the read size rather than the
+        // parse limit that matters here: anything read past the header
+        // stays in `client_buf` for the next parse.
+        let frame_res = read_and_parse(
+            Frame::parse,
+            &mut transport_r,
+            client_buf,
+            std::cmp::max(MAX_FRAME_HEADER_SIZE, read_chunk_size),
+        )
+        .await;
+
+        let maybe_frame = match frame_res {
+            Ok(inner) => inner,
+            Err(e) => return Err(H2ConnectionError::ReadError(e)),
+        };
+        (client_buf, frame) = match maybe_frame {
+            Some((client_buf, frame)) => (client_buf, frame),
+            None => {
+                debug!("Peer hung up");
+                break 'read_frames;
+            }
+        };
+        trace!(
+            "Reading frame... done! New buffer length: {}",
+            client_buf.len()
+        );
+        debug!(?frame, "<");
+
+        let max_frame_size = max_frame_size.load(Ordering::Relaxed);
+        if frame.len > max_frame_size {
+            return Err(H2ConnectionError::FrameTooLarge {
+                frame_type: frame.frame_type,
+                frame_size: frame.len,
+                max_frame_size,
+            });
+        }
+
+        trace!(
+            "Reading payload of size {}... Buffer length: {}",
+            frame.len,
+            client_buf.len()
+        );
+        let mut payload;
+        (client_buf, payload) = match read_and_parse(
+            nom::bytes::streaming::take(frame.len as usize),
+            &mut transport_r,
+            client_buf,
+            std::cmp::max(frame.len as usize, read_chunk_size),
+        )
+        .await?
+        {
+            Some((client_buf, payload)) => (client_buf, payload),
+            None => {
+                return Err(H2ConnectionError::IncompleteFrame {
+                    frame_type: frame.frame_type,
+                    frame_size: frame.len,
+                })
+            }
+        };
+        trace!(
+            "Reading payload... done! New buffer length: {}",
+            client_buf.len()
+        );
+
+        let has_padding = match frame.frame_type {
+            FrameType::Data(flags) => flags.contains(DataFlags::Padded),
+            FrameType::Headers(flags) => flags.contains(HeadersFlags::Padded),
+            _ => false,
+        };
+
+        if has_padding {
+            if payload.is_empty() {
+                return Err(H2ConnectionError::PaddedFrameEmpty {
+                    frame_type: frame.frame_type,
                 });
             }
-            HeadersOrTrailers::Trailers => {
-                match self.state.streams.get_mut(&stream_id) {
-                    Some(StreamState::Open(body_tx)) => {
-                        if body_tx
-                            .send(Ok(PieceOrTrailers::Trailers(Box::new(headers))))
-                            .await
-                            .is_err()
-                        {
-                            // the body is being ignored, but there's no point in
-                            // resetting the stream since we just got the end of it
-                        }
-                    }
-                    _ => {
-                        unreachable!("stream state should be open when we receive trailers")
-                    }
-                }
-                self.state.streams.remove(&stream_id);
+
+            let padding_length_roll;
+            (padding_length_roll, payload) = payload.split_at(1);
+            let padding_length = padding_length_roll[0] as usize;
+            if payload.len() < padding_length {
+                return Err(H2ConnectionError::PaddedFrameTooShort {
+                    frame_type: frame.frame_type,
+                    padding_length,
+                    frame_size: frame.len,
+                });
             }
+
+            // padding is on the end of the payload
+            let at = payload.len() - padding_length;
+            (payload, _) = payload.split_at(at);
         }
 
-        Ok(())
+        if tx.send((frame, payload)).await.is_err() {
+            debug!("h2 deframer: receiver dropped, closing connection");
+            return Ok(());
+        }
+    }
+
+    Ok(())
+}
+
+/// Tells the connection that a stream's handler is done when dropped, which
+/// happens whether the handler returned or panicked: the connection counts
+/// running handlers to know when it's drained.
+struct HandlerDone {
+    stream_id: StreamId,
+    tx: mpsc::Sender<H2Event>,
+}
+
+impl Drop for HandlerDone {
+    fn drop(&mut self) {
+        let tx = self.tx.clone();
+        let ev = H2Event {
+            stream_id: self.stream_id,
+            payload: H2EventPayload::HandlerDone,
+        };
+        fluke_maybe_uring::spawn(async move {
+            if tx.send(ev).await.is_err() {
+                debug!("could not send event to h2 connection handler");
+            }
+        });
     }
 }
 
+/// Sends [ServerDriver::error_response] for a handler that failed before
+/// responding
+async fn write_error_response(
+    respond: Responder<H2Encoder, ExpectResponseHeaders>,
+    mut res: Response,
+    body: Piece,
+) -> eyre::Result<()> {
+    res.headers.insert(
+        header::CONTENT_LENGTH,
+        body.len().to_string().into_bytes().into(),
+    );
+    let mut respond = respond.write_final_response(res).await?;
+    if !body.is_empty() {
+        respond.write_chunk(body).await?;
+    }
+    respond.finish_body(None).await?;
+    Ok(())
+}
+
+/// Fills in a pseudo-header, which may only appear once, with a value
+/// that's `None` if it was invalid. Anything wrong ends up in `malformed`.
+fn set_pseudo_header<T>(
+    slot: &mut Option<T>,
+    name: &'static str,
+    value: Option<T>,
+    malformed: &mut Option<H2StreamError>,
+) {
+    let err = match value {
+        None => H2StreamError::InvalidPseudoHeaderValue { name },
+        Some(_) if slot.is_some() => H2StreamError::DuplicatePseudoHeader { name },
+        Some(value) => {
+            *slot = Some(value);
+            return;
+        }
+    };
+    malformed.get_or_insert(err);
+}
+
 enum ReadHeadersMode {
     // we're accepting the stream or processing trailers, we want to
     // process the headers we read.
@@ -1137,3 +2675,1505 @@ enum ReadHeadersMode {
     // we're refusing the stream, we want to skip over the headers we read.
     Skip,
 }
+
+/// Copies `bytes` into `arena` and returns them as a [Piece] referencing
+/// buffet memory, which saves a heap allocation per header value. The
+/// underlying buffer is released once every piece cut from it has been
+/// dropped, and a new one is picked up from the pool as needed. Falls back
+/// to a heap allocation if the pool is exhausted.
+fn arena_piece(arena: &mut RollMut, bytes: &[u8]) -> Piece {
+    if bytes.is_empty() {
+        // empty header values are fine, empty rolls aren't
+        return Piece::Static(b"");
+    }
+    match arena.put_to_roll(bytes.len(), |slice| {
+        slice.copy_from_slice(bytes);
+        Ok(())
+    }) {
+        Ok(roll) => roll.into(),
+        Err(_) => bytes.to_vec().into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc, time::Duration};
+
+    use fluke_buffet::RollMut;
+    use http::StatusCode;
+    use tokio::{sync::mpsc, task::JoinHandle};
+
+    use super::{serve_with_handle, ServerConf};
+    use crate::{
+        h2::{
+            parse::{KnownErrorCode, StreamId, PREFACE},
+            ConnectionHandle, ControlFrameLimits, ErrorObserver, ProtocolError, SettingsUpdate,
+        },
+        maybe_uring::io::{ChanRead, ChanReadSend, ChanWrite, ConnInfo},
+        Body, BodyChunk, Encoder, ExpectResponseHeaders, Headers, Load, LoadShedder, Request,
+        RequestLimits, Responder, Response, ResponseDone, ServerDriver, TransportSecurity,
+    };
+
+    const DATA: u8 = 0x0;
+    const HEADERS: u8 = 0x1;
+    const RST_STREAM: u8 = 0x3;
+    const SETTINGS: u8 = 0x4;
+    const PING: u8 = 0x6;
+    const GOAWAY: u8 = 0x7;
+    const WINDOW_UPDATE: u8 = 0x8;
+
+    const END_STREAM: u8 = 0x1;
+    const END_HEADERS: u8 = 0x4;
+    const ACK: u8 = 0x1;
+
+    /// The request fields of a plain `GET /`
+    const GET: [(&str, &str); 4] = [
+        (":method", "GET"),
+        (":scheme", "http"),
+        (":path", "/"),
+        (":authority", "example.org"),
+    ];
+
+    /// Reads each request's body, then answers 200 with `headers`, `body`
+    /// and `trailers`, remembering the request path along with the body it
+    /// read, or the error that cut it short
+    #[derive(Default)]
+    struct Answer {
+        headers: Headers,
+        body: Vec<u8>,
+        trailers: Option<Headers>,
+        seen: RefCell<Vec<(String, Result<Vec<u8>, String>)>>,
+    }
+
+    impl ServerDriver for Answer {
+        async fn handle<E: Encoder>(
+            &self,
+            req: Request,
+            req_body: &mut impl Body,
+            respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            let path = req.uri.path().to_owned();
+            let mut body = vec![];
+            let read = loop {
+                match req_body.next_chunk().await {
+                    Ok(BodyChunk::Chunk(chunk)) => body.extend_from_slice(&chunk[..]),
+                    Ok(BodyChunk::Done { .. }) => break Ok(body),
+                    Err(e) => break Err(e.to_string()),
+                }
+            };
+            self.seen.borrow_mut().push((path, read));
+
+            let res = Response {
+                status: StatusCode::OK,
+                headers: self.headers.clone(),
+                ..Default::default()
+            };
+            let mut respond = respond.write_final_response(res).await?;
+            if !self.body.is_empty() {
+                respond.write_chunk(self.body.clone().into()).await?;
+            }
+            respond
+                .finish_body(self.trailers.clone().map(Box::new))
+                .await
+        }
+    }
+
+    /// Answers 200 with as many bytes of body as the request path says,
+    /// e.g. `/5`, without reading the request body
+    struct PathLen;
+
+    impl ServerDriver for PathLen {
+        async fn handle<E: Encoder>(
+            &self,
+            req: Request,
+            _req_body: &mut impl Body,
+            respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            let len: usize = req.uri.path()[1..].parse()?;
+            let mut respond = respond.write_final_response(Response::default()).await?;
+            if len > 0 {
+                respond.write_chunk(vec![b'a'; len].into()).await?;
+            }
+            respond.finish_body(None).await
+        }
+    }
+
+    /// Panics instead of answering
+    struct Panic;
+
+    impl ServerDriver for Panic {
+        async fn handle<E: Encoder>(
+            &self,
+            _req: Request,
+            _req_body: &mut impl Body,
+            _respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            panic!("handler panicked on purpose")
+        }
+    }
+
+    /// Never reads the request body, nor answers. Requests for `/upload`
+    /// get five minutes between pieces of their body.
+    struct Stall;
+
+    impl ServerDriver for Stall {
+        fn request_limits(&self, req: &Request, limits: &mut RequestLimits) {
+            if req.uri.path() == "/upload" {
+                limits.body_idle_timeout = Some(Duration::from_secs(300));
+            }
+        }
+
+        async fn handle<E: Encoder>(
+            &self,
+            _req: Request,
+            _req_body: &mut impl Body,
+            _respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            std::future::pending().await
+        }
+    }
+
+    /// Answers extended CONNECT requests for `websocket` by echoing what the
+    /// client sends on the stream
+    struct Echo;
+
+    impl ServerDriver for Echo {
+        async fn handle<E: Encoder>(
+            &self,
+            req: Request,
+            req_body: &mut impl Body,
+            respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            let status = if req.protocol.as_deref() == Some("websocket") {
+                StatusCode::OK
+            } else {
+                StatusCode::BAD_REQUEST
+            };
+            let res = Response {
+                status,
+                ..Default::default()
+            };
+            let mut respond = respond.write_final_response(res).await?;
+            while let BodyChunk::Chunk(chunk) = req_body.next_chunk().await? {
+                respond.write_chunk(chunk).await?;
+            }
+            respond.finish_body(None).await
+        }
+    }
+
+    /// Remembers the errors it's told about: the stream they reset, if any,
+    /// and their code
+    #[derive(Default)]
+    struct Observed(RefCell<Vec<(Option<u32>, u32)>>);
+
+    impl ErrorObserver for Observed {
+        fn on_connection_error(&self, _conn: &ConnInfo, err: &ProtocolError) {
+            self.0.borrow_mut().push((None, err.code.repr()));
+        }
+
+        fn on_stream_reset(&self, _conn: &ConnInfo, stream_id: StreamId, err: &ProtocolError) {
+            self.0
+                .borrow_mut()
+                .push((Some(stream_id.0), err.code.repr()));
+        }
+    }
+
+    /// A frame the server wrote. The blocks of HEADERS frames come decoded,
+    /// which keeps the peer's HPACK state in sync.
+    #[derive(Debug)]
+    struct Received {
+        ty: u8,
+        flags: u8,
+        stream_id: u32,
+        payload: Vec<u8>,
+        headers: Vec<(String, String)>,
+    }
+
+    impl Received {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        }
+
+        /// The error code of a RST_STREAM or GOAWAY frame
+        fn error_code(&self) -> u32 {
+            let offset = if self.ty == GOAWAY { 4 } else { 0 };
+            u32::from_be_bytes(self.payload[offset..offset + 4].try_into().unwrap())
+        }
+    }
+
+    /// The client's side of a connection being served
+    struct Peer {
+        tx: ChanReadSend,
+        rx: mpsc::Receiver<Vec<u8>>,
+        buf: Vec<u8>,
+        enc: fluke_hpack::Encoder<'static>,
+        dec: fluke_hpack::Decoder<'static>,
+        /// The server's initial SETTINGS, as identifier and value pairs
+        settings: Vec<(u16, u32)>,
+        handle: ConnectionHandle,
+        served: JoinHandle<eyre::Result<()>>,
+    }
+
+    impl Peer {
+        /// Starts serving a connection, sends the preface along with
+        /// `settings` (identifier and value pairs), then goes through the
+        /// SETTINGS exchange.
+        async fn connect(
+            conf: ServerConf,
+            driver: Rc<impl ServerDriver + 'static>,
+            settings: &[(u16, u32)],
+        ) -> Self {
+            let (tx, read) = ChanRead::new();
+            let (rx, write) = ChanWrite::new();
+            let handle = ConnectionHandle::default();
+            let served = crate::maybe_uring::spawn(serve_with_handle(
+                (read, write),
+                Rc::new(conf),
+                RollMut::alloc().unwrap(),
+                driver,
+                handle.clone(),
+            ));
+            let mut peer = Self {
+                tx,
+                rx,
+                buf: vec![],
+                enc: fluke_hpack::Encoder::new(),
+                dec: fluke_hpack::Decoder::new(),
+                settings: vec![],
+                handle,
+                served,
+            };
+
+            let mut preface = PREFACE.to_vec();
+            preface.extend(frame(SETTINGS, 0, 0, &settings_payload(settings)));
+            peer.send(preface).await;
+
+            let (mut got_settings, mut got_ack) = (false, false);
+            while !(got_settings && got_ack) {
+                let frame = peer.next_frame().await;
+                assert_eq!(frame.ty, SETTINGS, "unexpected frame {frame:?}");
+                if frame.flags & ACK == ACK {
+                    got_ack = true;
+                } else {
+                    got_settings = true;
+                    peer.settings = frame
+                        .payload
+                        .chunks(6)
+                        .map(|pair| {
+                            let id = u16::from_be_bytes([pair[0], pair[1]]);
+                            (id, u32::from_be_bytes(pair[2..].try_into().unwrap()))
+                        })
+                        .collect();
+                    peer.send_frame(SETTINGS, ACK, 0, &[]).await;
+                }
+            }
+            peer
+        }
+
+        async fn send(&mut self, bytes: Vec<u8>) {
+            self.tx.send(bytes).await.unwrap();
+        }
+
+        async fn send_frame(&mut self, ty: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+            self.send(frame(ty, flags, stream_id, payload)).await;
+        }
+
+        /// Reads the SETTINGS frame the server sends next, and acknowledges it
+        async fn ack_settings(&mut self) -> Received {
+            let settings = self.next_frame().await;
+            assert_eq!((settings.ty, settings.flags), (SETTINGS, 0), "{settings:?}");
+            self.send_frame(SETTINGS, ACK, 0, &[]).await;
+            settings
+        }
+
+        /// Sends `fields` in a single HEADERS frame, which ends the stream if
+        /// `end_stream` is set
+        async fn send_headers(
+            &mut self,
+            stream_id: u32,
+            end_stream: bool,
+            fields: &[(&str, &str)],
+        ) {
+            let block = self.encode(fields);
+            let flags = END_HEADERS | if end_stream { END_STREAM } else { 0 };
+            self.send_frame(HEADERS, flags, stream_id, &block).await;
+        }
+
+        fn encode(&mut self, fields: &[(&str, &str)]) -> Vec<u8> {
+            self.enc
+                .encode(fields.iter().map(|(n, v)| (n.as_bytes(), v.as_bytes())))
+        }
+
+        /// Reads frames until the server resets `stream_id`, returns the
+        /// error code it gave
+        async fn stream_reset(&mut self, stream_id: u32) -> u32 {
+            loop {
+                let frame = self.next_frame().await;
+                assert_ne!(frame.ty, GOAWAY, "{frame:?}");
+                if frame.ty == RST_STREAM && frame.stream_id == stream_id {
+                    return frame.error_code();
+                }
+            }
+        }
+
+        /// Reads frames until `body` holds `len` bytes of the DATA sent on
+        /// `stream_id`, or until that DATA ends the stream, in which case
+        /// this returns true. Other frames are skipped.
+        async fn read_data(&mut self, stream_id: u32, body: &mut Vec<u8>, len: usize) -> bool {
+            while body.len() < len {
+                let frame = self.next_frame().await;
+                if frame.ty == DATA && frame.stream_id == stream_id {
+                    body.extend(frame.payload);
+                    if frame.flags & END_STREAM == END_STREAM {
+                        return true;
+                    }
+                }
+            }
+            false
+        }
+
+        /// Reads the next frame the server wrote, skipping WINDOW_UPDATE
+        /// frames, which go out whenever the server gets to them.
+        async fn next_frame(&mut self) -> Received {
+            loop {
+                let frame = self.next_raw_frame().await.expect("server hung up");
+                if frame.ty != WINDOW_UPDATE {
+                    return frame;
+                }
+            }
+        }
+
+        /// Reads the next frame the server wrote, `None` once the server
+        /// closed the connection
+        async fn next_raw_frame(&mut self) -> Option<Received> {
+            loop {
+                if self.buf.len() >= 9 {
+                    let len = u32::from_be_bytes([0, self.buf[0], self.buf[1], self.buf[2]]);
+                    let len = len as usize;
+                    if self.buf.len() >= 9 + len {
+                        let mut frame = Received {
+                            ty: self.buf[3],
+                            flags: self.buf[4],
+                            stream_id: u32::from_be_bytes(self.buf[5..9].try_into().unwrap()),
+                            payload: self.buf[9..9 + len].to_vec(),
+                            headers: vec![],
+                        };
+                        self.buf.drain(..9 + len);
+                        if frame.ty == HEADERS {
+                            frame.headers = self
+                                .dec
+                                .decode(&frame.payload)
+                                .unwrap()
+                                .into_iter()
+                                .map(|(n, v)| {
+                                    (String::from_utf8(n).unwrap(), String::from_utf8(v).unwrap())
+                                })
+                                .collect();
+                        }
+                        return Some(frame);
+                    }
+                }
+                match self.rx.recv().await {
+                    Some(bytes) => self.buf.extend(bytes),
+                    None => {
+                        assert!(self.buf.is_empty(), "server hung up mid-frame");
+                        return None;
+                    }
+                }
+            }
+        }
+
+        /// Reads frames until the server closes the connection, returns the
+        /// last GOAWAY it sent before that
+        async fn goaway(&mut self) -> Received {
+            let mut goaway = None;
+            while let Some(frame) = self.next_raw_frame().await {
+                if frame.ty == GOAWAY {
+                    goaway = Some(frame);
+                }
+            }
+            goaway.expect("server closed the connection without a GOAWAY")
+        }
+
+        /// Sends a PING, then reads frames until its acknowledgement, which
+        /// goes out after anything the server was about to send. Returns
+        /// the frames read until then.
+        async fn ping(&mut self) -> Vec<Received> {
+            self.send_frame(PING, 0, 0, &[0; 8]).await;
+            let mut frames = vec![];
+            loop {
+                let frame = self.next_frame().await;
+                if frame.ty == PING {
+                    assert_eq!(frame.flags, ACK);
+                    return frames;
+                }
+                frames.push(frame);
+            }
+        }
+
+        /// Hangs up, then waits for the server to be done with the connection
+        async fn hang_up(self) -> eyre::Result<()> {
+            let Self {
+                tx, mut rx, served, ..
+            } = self;
+            drop(tx);
+            while rx.recv().await.is_some() {}
+            served.await.unwrap()
+        }
+    }
+
+    /// The payload of a SETTINGS frame, from identifier and value pairs
+    fn settings_payload(settings: &[(u16, u32)]) -> Vec<u8> {
+        let mut payload = vec![];
+        for (id, value) in settings {
+            payload.extend(id.to_be_bytes());
+            payload.extend(value.to_be_bytes());
+        }
+        payload
+    }
+
+    fn frame(ty: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut out = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        out.extend([ty, flags]);
+        out.extend(stream_id.to_be_bytes());
+        out.extend(payload);
+        out
+    }
+
+    #[test]
+    fn test_h2_idle_connection_releases_buffers() {
+        crate::maybe_uring::start(async move {
+            let before = fluke_buffet::num_free_bufs();
+            let mut peer = Peer::connect(Default::default(), Rc::new(Answer::default()), &[]).await;
+
+            peer.send_headers(1, true, &GET).await;
+            let res = peer.next_frame().await;
+            assert_eq!(
+                (res.ty, res.stream_id, res.header(":status")),
+                (HEADERS, 1, Some("200"))
+            );
+            while peer.next_frame().await.flags & END_STREAM == 0 {}
+
+            // a frame that doesn't get answered, read once no streams are open
+            peer.send_frame(WINDOW_UPDATE, 0, 0, &1u32.to_be_bytes())
+                .await;
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert_eq!(fluke_buffet::num_free_bufs(), before);
+
+            peer.hang_up().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_header_buffers_returned_per_request() {
+        crate::maybe_uring::start(async move {
+            // so that buffers don't come back just because the connection
+            // went idle
+            let conf = ServerConf {
+                release_idle_buffers: false,
+                ..Default::default()
+            };
+            let mut peer = Peer::connect(conf, Rc::new(Answer::default()), &[]).await;
+            let connected = fluke_buffet::num_free_bufs();
+
+            // keeps the connection busy until its body ends
+            peer.send_headers(1, false, &GET).await;
+            peer.ping().await;
+            let busy = fluke_buffet::num_free_bufs();
+
+            let mut fields = GET.to_vec();
+            fields.push(("x-value", "hello"));
+            for stream_id in [3, 5, 7] {
+                peer.send_headers(stream_id, true, &fields).await;
+                while peer.next_frame().await.flags & END_STREAM == 0 {}
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert_eq!(fluke_buffet::num_free_bufs(), busy);
+
+            peer.send_frame(DATA, END_STREAM, 1, &[]).await;
+            while peer.next_frame().await.flags & END_STREAM == 0 {}
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert_eq!(fluke_buffet::num_free_bufs(), connected);
+
+            peer.hang_up().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_panicking_handler_answered_once() {
+        crate::maybe_uring::start(async move {
+            let mut peer = Peer::connect(Default::default(), Rc::new(Panic), &[]).await;
+
+            peer.send_headers(1, true, &GET).await;
+            let mut responses = vec![];
+            loop {
+                let frame = peer.next_frame().await;
+                if frame.ty == HEADERS {
+                    responses.push(frame.header(":status").map(str::to_owned));
+                }
+                if frame.ty == PING {
+                    break;
+                }
+                if frame.stream_id == 1 && frame.flags & END_STREAM == END_STREAM {
+                    // frames are processed before events: give a second
+                    // answer time to go out before the PING ACK does
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    peer.send_frame(PING, 0, 0, &[0; 8]).await;
+                }
+            }
+            assert_eq!(responses, [Some("500".to_owned())]);
+
+            peer.hang_up().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_panicking_handler_doesnt_hold_up_shutdown() {
+        crate::maybe_uring::start(async move {
+            let mut peer = Peer::connect(Default::default(), Rc::new(Panic), &[]).await;
+
+            peer.send_headers(1, true, &GET).await;
+            while peer.next_frame().await.flags & END_STREAM == 0 {}
+
+            peer.handle.shutdown(Duration::from_secs(60));
+            let goaway = tokio::time::timeout(Duration::from_secs(5), peer.goaway())
+                .await
+                .expect("the connection waited for the drain deadline");
+            assert_eq!(goaway.error_code(), KnownErrorCode::NoError.repr());
+
+            peer.hang_up().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_rapid_reset_counts_pending_handlers_only() {
+        crate::maybe_uring::start(async move {
+            let conf = || ServerConf {
+                control_frame_limits: ControlFrameLimits {
+                    max_rst_streams: 2,
+                    ..Default::default()
+                },
+                max_queued_body_data: 1 << 20,
+                ..Default::default()
+            };
+            let cancel = KnownErrorCode::Cancel.repr().to_be_bytes();
+
+            // responses whose handlers are done, that are stuck waiting on
+            // the connection window: the client loses interest in them
+            let driver = Rc::new(Answer {
+                body: vec![b'a'; 70_000],
+                ..Default::default()
+            });
+            let mut peer = Peer::connect(conf(), driver, &[(0x4, 1 << 20)]).await;
+            for stream_id in [1, 3, 5, 7] {
+                peer.send_headers(stream_id, true, &GET).await;
+                while peer.next_frame().await.ty != HEADERS {}
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert_eq!(peer.handle.running_handlers(), 0);
+            for stream_id in [1, 3, 5, 7] {
+                peer.send_frame(RST_STREAM, 0, stream_id, &cancel).await;
+            }
+            let frames = peer.ping().await;
+            assert!(frames.iter().all(|f| f.ty != GOAWAY), "{frames:?}");
+            peer.hang_up().await.unwrap();
+
+            // requests that get cancelled while their handlers wait on them
+            let mut peer = Peer::connect(conf(), Rc::new(Answer::default()), &[]).await;
+            for stream_id in [1, 3, 5] {
+                peer.send_headers(stream_id, false, &GET).await;
+                peer.send_frame(RST_STREAM, 0, stream_id, &cancel).await;
+            }
+            let goaway = peer.goaway().await;
+            assert_eq!(goaway.error_code(), KnownErrorCode::EnhanceYourCalm.repr());
+        });
+    }
+
+    #[test]
+    fn test_h2_rst_stream_for_closed_stream() {
+        crate::maybe_uring::start(async move {
+            let conf = ServerConf {
+                max_request_body_len: 10,
+                ..Default::default()
+            };
+            let mut peer = Peer::connect(conf, Rc::new(Answer::default()), &[]).await;
+            let cancel = KnownErrorCode::Cancel.repr().to_be_bytes();
+
+            // a stream that's over, then one we reset as the peer does
+            peer.send_headers(1, true, &GET).await;
+            while peer.next_frame().await.flags & END_STREAM == 0 {}
+            let mut fields = GET.to_vec();
+            fields.push(("content-length", "1000"));
+            peer.send_headers(3, false, &fields).await;
+            assert_eq!(peer.stream_reset(3).await, KnownErrorCode::NoError.repr());
+
+            for stream_id in [1, 3] {
+                peer.send_frame(RST_STREAM, 0, stream_id, &cancel).await;
+            }
+            let frames = peer.ping().await;
+            assert!(frames.iter().all(|f| f.ty != GOAWAY), "{frames:?}");
+
+            // a stream that was never opened is another matter
+            peer.send_frame(RST_STREAM, 0, 7, &cancel).await;
+            let goaway = peer.goaway().await;
+            assert_eq!(goaway.error_code(), KnownErrorCode::ProtocolError.repr());
+        });
+    }
+
+    #[test]
+    fn test_h2_load_shedding() {
+        /// Sheds every stream
+        struct ShedAll;
+
+        impl LoadShedder for ShedAll {
+            fn shed(&self, _load: &Load) -> Option<Duration> {
+                Some(Duration::from_secs(1))
+            }
+        }
+
+        crate::maybe_uring::start(async move {
+            let conf = ServerConf {
+                load_shedder: Some(Rc::new(ShedAll)),
+                ..Default::default()
+            };
+            let driver = Rc::new(Answer::default());
+            let mut peer = Peer::connect(conf, driver.clone(), &[]).await;
+
+            for stream_id in [1, 3] {
+                peer.send_headers(stream_id, true, &GET).await;
+                assert_eq!(
+                    peer.stream_reset(stream_id).await,
+                    KnownErrorCode::RefusedStream.repr()
+                );
+            }
+            let frames = peer.ping().await;
+            assert!(frames.iter().all(|f| f.ty != GOAWAY), "{frames:?}");
+            assert!(driver.seen.borrow().is_empty());
+
+            peer.hang_up().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_stream_window_exceeded() {
+        crate::maybe_uring::start(async move {
+            let driver = Rc::new(Answer::default());
+            let mut peer = Peer::connect(Default::default(), driver.clone(), &[]).await;
+            peer.handle
+                .update_settings(SettingsUpdate {
+                    initial_window_size: Some(10),
+                    ..Default::default()
+                })
+                .unwrap();
+            peer.ack_settings().await;
+
+            peer.send_headers(1, false, &GET).await;
+            peer.send_frame(DATA, 0, 1, &[b'a'; 11]).await;
+            let rst = loop {
+                let frame = peer.next_frame().await;
+                if frame.ty == RST_STREAM {
+                    break frame;
+                }
+            };
+            assert_eq!(rst.stream_id, 1);
+            assert_eq!(rst.error_code(), KnownErrorCode::FlowControlError.repr());
+
+            // the connection carries on
+            let frames = peer.ping().await;
+            assert!(frames.iter().all(|f| f.ty != GOAWAY), "{frames:?}");
+            let seen = driver.seen.take();
+            assert!(matches!(&seen[..], [(_, Err(_))]), "{seen:?}");
+
+            peer.hang_up().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_data_waits_for_connection_window() {
+        crate::maybe_uring::start(async move {
+            let driver = Rc::new(Answer {
+                body: vec![b'a'; 70_000],
+                ..Default::default()
+            });
+            // the stream window is large enough, the connection's isn't
+            let mut peer = Peer::connect(Default::default(), driver, &[(0x4, 1 << 20)]).await;
+
+            peer.send_headers(1, true, &GET).await;
+            let mut body = vec![];
+            assert!(!peer.read_data(1, &mut body, 65_535).await);
+            assert_eq!(body.len(), 65_535);
+            let frames = peer.ping().await;
+            assert!(frames.iter().all(|f| f.ty != DATA), "{frames:?}");
+
+            peer.send_frame(WINDOW_UPDATE, 0, 0, &10_000u32.to_be_bytes())
+                .await;
+            assert!(peer.read_data(1, &mut body, usize::MAX).await);
+            assert_eq!(body.len(), 70_000);
+
+            peer.hang_up().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_ends_dont_wait_for_connection_window() {
+        crate::maybe_uring::start(async move {
+            let mut peer =
+                Peer::connect(Default::default(), Rc::new(PathLen), &[(0x4, 1 << 20)]).await;
+            let request = |path| [GET[0], GET[1], (":path", path), GET[3]];
+
+            peer.send_headers(1, true, &request("/70000")).await;
+            let mut body = vec![];
+            assert!(!peer.read_data(1, &mut body, 65_535).await);
+
+            // the connection window is exhausted, which holds up stream 1
+            // but not a response that's only left to end
+            peer.send_headers(3, true, &request("/0")).await;
+            let res = peer.next_frame().await;
+            assert_eq!((res.ty, res.stream_id), (HEADERS, 3), "{res:?}");
+            let end = peer.next_frame().await;
+            assert_eq!(
+                (end.ty, end.flags, end.stream_id, end.payload.len()),
+                (DATA, END_STREAM, 3, 0)
+            );
+
+            peer.send_frame(WINDOW_UPDATE, 0, 0, &10_000u32.to_be_bytes())
+                .await;
+            assert!(peer.read_data(1, &mut body, usize::MAX).await);
+            assert_eq!(body.len(), 70_000);
+
+            peer.hang_up().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_window_update_for_closed_stream() {
+        crate::maybe_uring::start(async move {
+            let mut peer = Peer::connect(Default::default(), Rc::new(Answer::default()), &[]).await;
+
+            peer.send_headers(1, true, &GET).await;
+            while peer.next_frame().await.flags & END_STREAM == 0 {}
+
+            // the peer hadn't seen the end of the stream when it sent this
+            peer.send_frame(WINDOW_UPDATE, 0, 1, &100u32.to_be_bytes())
+                .await;
+            let frames = peer.ping().await;
+            assert!(frames.iter().all(|f| f.ty != GOAWAY), "{frames:?}");
+
+            // a stream that was never opened is another matter
+            peer.send_frame(WINDOW_UPDATE, 0, 5, &100u32.to_be_bytes())
+                .await;
+            let goaway = peer.goaway().await;
+            assert_eq!(goaway.error_code(), KnownErrorCode::ProtocolError.repr());
+        });
+    }
+
+    #[test]
+    fn test_h2_stream_window() {
+        crate::maybe_uring::start(async move {
+            let driver = Rc::new(Answer {
+                body: b"hello world!".to_vec(),
+                ..Default::default()
+            });
+            let mut peer = Peer::connect(Default::default(), driver, &[(0x4, 5)]).await;
+
+            peer.send_headers(1, true, &GET).await;
+            let mut body = vec![];
+            assert!(!peer.read_data(1, &mut body, 5).await);
+            let frames = peer.ping().await;
+            assert!(frames.iter().all(|f| f.ty != DATA), "{frames:?}");
+
+            // a larger SETTINGS_INITIAL_WINDOW_SIZE grows open streams'
+            // windows by the difference
+            let payload = settings_payload(&[(0x4, 8)]);
+            peer.send_frame(SETTINGS, 0, 0, &payload).await;
+            assert!(!peer.read_data(1, &mut body, 8).await);
+            let frames = peer.ping().await;
+            assert!(frames.iter().all(|f| f.ty != DATA), "{frames:?}");
+            assert_eq!(body, b"hello wo");
+
+            peer.send_frame(WINDOW_UPDATE, 0, 1, &100u32.to_be_bytes())
+                .await;
+            assert!(peer.read_data(1, &mut body, usize::MAX).await);
+            assert_eq!(body, b"hello world!");
+
+            peer.hang_up().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_settings_update() {
+        crate::maybe_uring::start(async move {
+            let mut peer = Peer::connect(Default::default(), Rc::new(Answer::default()), &[]).await;
+
+            // opened with the default window
+            peer.send_headers(1, false, &GET).await;
+            peer.handle
+                .update_settings(SettingsUpdate {
+                    initial_window_size: Some(10),
+                    header_table_size: Some(100),
+                    ..Default::default()
+                })
+                .unwrap();
+            let settings = peer.ack_settings().await;
+            let mut sent: Vec<_> = settings
+                .payload
+                .chunks(6)
+                .map(|pair| {
+                    let id = u16::from_be_bytes([pair[0], pair[1]]);
+                    (id, u32::from_be_bytes(pair[2..].try_into().unwrap()))
+                })
+                .filter(|(id, _)| [0x1, 0x4].contains(id))
+                .collect();
+            sent.sort();
+            assert_eq!(sent, [(0x1, 100), (0x4, 10)]);
+
+            // a smaller SETTINGS_INITIAL_WINDOW_SIZE shrinks open streams'
+            // windows by the difference
+            peer.send_frame(DATA, 0, 1, &[b'a'; 11]).await;
+            assert_eq!(
+                peer.stream_reset(1).await,
+                KnownErrorCode::FlowControlError.repr()
+            );
+
+            // the peer's HPACK encoder follows the new table size
+            peer.enc.set_max_table_size(100);
+            peer.send_headers(3, true, &GET).await;
+            let mut body = vec![];
+            assert!(peer.read_data(3, &mut body, usize::MAX).await);
+
+            // and can't go past it anymore: a dynamic table size update to
+            // 4096, then the request
+            let mut block = vec![0x3f, 0xe1, 0x1f];
+            block.extend(peer.encode(&GET));
+            peer.send_frame(HEADERS, END_HEADERS | END_STREAM, 5, &block)
+                .await;
+            let goaway = peer.goaway().await;
+            assert_eq!(goaway.error_code(), KnownErrorCode::CompressionError.repr());
+        });
+    }
+
+    #[test]
+    fn test_h2_data_split_at_max_frame_size() {
+        crate::maybe_uring::start(async move {
+            let driver = Rc::new(Answer {
+                body: vec![b'a'; 50_000],
+                ..Default::default()
+            });
+            // SETTINGS_MAX_FRAME_SIZE
+            let mut peer = Peer::connect(Default::default(), driver, &[(0x5, 20_000)]).await;
+
+            peer.send_headers(1, true, &GET).await;
+            let mut sizes = vec![];
+            loop {
+                let frame = peer.next_frame().await;
+                if frame.ty == DATA {
+                    sizes.push(frame.payload.len());
+                    if frame.flags & END_STREAM == END_STREAM {
+                        break;
+                    }
+                }
+            }
+            assert_eq!(sizes.iter().sum::<usize>(), 50_000);
+            assert_eq!(sizes.iter().max(), Some(&20_000), "{sizes:?}");
+
+            peer.hang_up().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_response_trailers() {
+        crate::maybe_uring::start(async move {
+            let mut trailers = Headers::default();
+            trailers.insert("x-checksum", "abc".into());
+            let driver = Rc::new(Answer {
+                body: b"hello".to_vec(),
+                trailers: Some(trailers),
+                ..Default::default()
+            });
+            let mut peer = Peer::connect(Default::default(), driver, &[]).await;
+
+            peer.send_headers(1, true, &GET).await;
+            let res = peer.next_frame().await;
+            assert_eq!((res.ty, res.header(":status")), (HEADERS, Some("200")));
+            assert_eq!(res.flags & END_STREAM, 0);
+            let mut body = vec![];
+            assert!(!peer.read_data(1, &mut body, 5).await);
+            assert_eq!(body, b"hello");
+
+            let trailers = peer.next_frame().await;
+            assert_eq!((trailers.ty, trailers.stream_id), (HEADERS, 1));
+            assert_eq!(
+                trailers.flags & END_STREAM,
+                END_STREAM,
+                "trailers end the stream"
+            );
+            assert_eq!(
+                trailers.headers,
+                [("x-checksum".to_owned(), "abc".to_owned())]
+            );
+
+            peer.hang_up().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_early_response_ends_stream() {
+        crate::maybe_uring::start(async move {
+            let conf = ServerConf {
+                max_request_body_len: 10,
+                ..Default::default()
+            };
+            let driver = Rc::new(Answer::default());
+            let mut peer = Peer::connect(conf, driver.clone(), &[]).await;
+
+            let mut fields = GET.to_vec();
+            fields.push(("content-length", "1000"));
+            peer.send_headers(1, false, &fields).await;
+            let res = peer.next_frame().await;
+            assert_eq!(
+                (res.ty, res.stream_id, res.header(":status")),
+                (HEADERS, 1, Some("413"))
+            );
+            assert_eq!(res.flags & END_STREAM, END_STREAM, "{res:?}");
+
+            // the peer was still sending a body, it's told to stop
+            let rst = peer.next_frame().await;
+            assert_eq!((rst.ty, rst.stream_id), (RST_STREAM, 1), "{rst:?}");
+            assert_eq!(rst.error_code(), KnownErrorCode::NoError.repr());
+            assert!(driver.seen.borrow().is_empty());
+
+            peer.hang_up().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_interim_response() {
+        /// Sends early hints, then the final response
+        struct EarlyHints;
+
+        impl ServerDriver for EarlyHints {
+            async fn handle<E: Encoder>(
+                &self,
+                _req: Request,
+                _req_body: &mut impl Body,
+                mut respond: Responder<E, ExpectResponseHeaders>,
+            ) -> eyre::Result<Responder<E, ResponseDone>> {
+                let mut headers = Headers::default();
+                headers.insert("link", "</style.css>; rel=preload".into());
+                let hints = Response {
+                    status: StatusCode::from_u16(103).unwrap(),
+                    headers,
+                    ..Default::default()
+                };
+                respond.write_interim_response(hints).await?;
+                let mut respond = respond.write_final_response(Response::default()).await?;
+                respond.write_chunk(b"hi".to_vec().into()).await?;
+                respond.finish_body(None).await
+            }
+        }
+
+        crate::maybe_uring::start(async move {
+            let mut peer = Peer::connect(Default::default(), Rc::new(EarlyHints), &[]).await;
+
+            peer.send_headers(1, true, &GET).await;
+            let hints = peer.next_frame().await;
+            assert_eq!(
+                (hints.ty, hints.stream_id, hints.header(":status")),
+                (HEADERS, 1, Some("103"))
+            );
+            assert_eq!(hints.flags & END_STREAM, 0, "{hints:?}");
+            assert_eq!(hints.header("link"), Some("</style.css>; rel=preload"));
+
+            let res = peer.next_frame().await;
+            assert_eq!(
+                (res.ty, res.stream_id, res.header(":status")),
+                (HEADERS, 1, Some("200"))
+            );
+            assert_eq!(res.header("link"), None);
+            let mut body = vec![];
+            assert!(peer.read_data(1, &mut body, usize::MAX).await);
+            assert_eq!(body, b"hi");
+
+            peer.hang_up().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_response_dropped_halfway() {
+        /// Fails after sending part of its response body
+        struct Truncate;
+
+        impl ServerDriver for Truncate {
+            async fn handle<E: Encoder>(
+                &self,
+                _req: Request,
+                _req_body: &mut impl Body,
+                respond: Responder<E, ExpectResponseHeaders>,
+            ) -> eyre::Result<Responder<E, ResponseDone>> {
+                let mut respond = respond.write_final_response(Response::default()).await?;
+                respond.write_chunk(b"partial".to_vec().into()).await?;
+                Err(eyre::eyre!("gave up halfway through"))
+            }
+        }
+
+        crate::maybe_uring::start(async move {
+            let mut peer = Peer::connect(Default::default(), Rc::new(Truncate), &[]).await;
+
+            peer.send_headers(1, true, &GET).await;
+            let res = peer.next_frame().await;
+            assert_eq!((res.ty, res.header(":status")), (HEADERS, Some("200")));
+            let mut body = vec![];
+            loop {
+                let frame = peer.next_frame().await;
+                assert_eq!(frame.stream_id, 1, "{frame:?}");
+                match frame.ty {
+                    DATA => {
+                        assert_eq!(frame.flags & END_STREAM, 0, "truncated body ended");
+                        body.extend(frame.payload);
+                    }
+                    RST_STREAM => {
+                        assert_eq!(frame.error_code(), KnownErrorCode::InternalError.repr());
+                        break;
+                    }
+                    _ => panic!("unexpected frame {frame:?}"),
+                }
+            }
+            assert_eq!(body, b"partial");
+
+            // the connection carries on
+            let frames = peer.ping().await;
+            assert!(frames.iter().all(|f| f.ty != GOAWAY), "{frames:?}");
+
+            peer.hang_up().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_settings_timeout() {
+        crate::maybe_uring::start(async move {
+            let conf = ServerConf {
+                settings_ack_timeout: Some(Duration::from_millis(50)),
+                ..Default::default()
+            };
+            let mut peer = Peer::connect(conf, Rc::new(Answer::default()), &[]).await;
+
+            // the initial settings were acknowledged
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let frames = peer.ping().await;
+            assert!(frames.iter().all(|f| f.ty != GOAWAY), "{frames:?}");
+
+            peer.handle
+                .update_settings(SettingsUpdate {
+                    max_concurrent_streams: Some(10),
+                    ..Default::default()
+                })
+                .unwrap();
+            let settings = peer.next_frame().await;
+            assert_eq!((settings.ty, settings.flags), (SETTINGS, 0));
+            let goaway = peer.goaway().await;
+            assert_eq!(goaway.error_code(), KnownErrorCode::SettingsTimeout.repr());
+        });
+    }
+
+    #[test]
+    fn test_h2_extended_connect() {
+        crate::maybe_uring::start(async move {
+            let connect = [
+                (":method", "CONNECT"),
+                (":protocol", "websocket"),
+                (":scheme", "http"),
+                (":path", "/chat"),
+                (":authority", "example.org"),
+            ];
+
+            let conf = ServerConf {
+                enable_connect_protocol: true,
+                ..Default::default()
+            };
+            let mut peer = Peer::connect(conf, Rc::new(Echo), &[]).await;
+            // SETTINGS_ENABLE_CONNECT_PROTOCOL
+            assert!(peer.settings.contains(&(0x8, 1)), "{:?}", peer.settings);
+
+            peer.send_headers(1, false, &connect).await;
+            let res = peer.next_frame().await;
+            assert_eq!((res.ty, res.header(":status")), (HEADERS, Some("200")));
+            assert_eq!(res.flags & END_STREAM, 0);
+            peer.send_frame(DATA, 0, 1, b"ping").await;
+            let mut body = vec![];
+            assert!(!peer.read_data(1, &mut body, 4).await);
+            assert_eq!(body, b"ping");
+            peer.send_frame(DATA, END_STREAM, 1, &[]).await;
+            assert!(peer.read_data(1, &mut body, usize::MAX).await);
+            peer.hang_up().await.unwrap();
+
+            // without advertising it, `:protocol` is an unknown pseudo-header
+            let mut peer = Peer::connect(Default::default(), Rc::new(Echo), &[]).await;
+            assert!(!peer.settings.contains(&(0x8, 1)), "{:?}", peer.settings);
+
+            peer.send_headers(1, false, &connect).await;
+            let rst = peer.next_frame().await;
+            assert_eq!((rst.ty, rst.stream_id), (RST_STREAM, 1));
+            assert_eq!(rst.error_code(), KnownErrorCode::ProtocolError.repr());
+            peer.hang_up().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_content_length_mismatch() {
+        crate::maybe_uring::start(async move {
+            let driver = Rc::new(Answer::default());
+            let mut peer = Peer::connect(Default::default(), driver.clone(), &[]).await;
+            let protocol_error = KnownErrorCode::ProtocolError.repr();
+            fn post(path: &str) -> [(&str, &str); 5] {
+                [
+                    (":method", "POST"),
+                    (":scheme", "http"),
+                    (":path", path),
+                    (":authority", "example.org"),
+                    ("content-length", "5"),
+                ]
+            }
+
+            // more DATA than announced
+            peer.send_headers(1, false, &post("/long")).await;
+            peer.send_frame(DATA, END_STREAM, 1, b"hello!").await;
+            assert_eq!(peer.stream_reset(1).await, protocol_error);
+
+            // less DATA than announced, by the time the stream ends
+            peer.send_headers(3, false, &post("/short")).await;
+            peer.send_frame(DATA, END_STREAM, 3, b"hell").await;
+            assert_eq!(peer.stream_reset(3).await, protocol_error);
+
+            // no DATA at all
+            peer.send_headers(5, true, &post("/empty")).await;
+            assert_eq!(peer.stream_reset(5).await, protocol_error);
+
+            // handlers don't mistake what they got for the whole body
+            let frames = peer.ping().await;
+            assert!(frames.iter().all(|f| f.ty != GOAWAY), "{frames:?}");
+            let mut seen = driver.seen.take();
+            seen.sort();
+            assert!(
+                matches!(&seen[..], [(long, Err(_)), (short, Err(_))] if long == "/long" && short == "/short"),
+                "{seen:?}"
+            );
+
+            peer.hang_up().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_malformed_pseudo_headers() {
+        crate::maybe_uring::start(async move {
+            let mut peer = Peer::connect(Default::default(), Rc::new(Answer::default()), &[]).await;
+            let protocol_error = KnownErrorCode::ProtocolError.repr();
+
+            let duplicate_method = [&[(":method", "POST")], &GET[..]].concat();
+            let missing_path = [GET[0], GET[1], GET[3]];
+            let invalid_authority = [GET[0], GET[1], GET[2], (":authority", "exa mple.org")];
+            // plain CONNECT has neither `:scheme` nor `:path`, and isn't supported
+            let connect = [(":method", "CONNECT"), (":authority", "example.org:443")];
+            for (stream_id, fields) in [
+                (1, &duplicate_method[..]),
+                (3, &missing_path[..]),
+                (5, &invalid_authority[..]),
+                (7, &connect[..]),
+            ] {
+                peer.send_headers(stream_id, true, fields).await;
+                assert_eq!(
+                    peer.stream_reset(stream_id).await,
+                    protocol_error,
+                    "{fields:?}"
+                );
+            }
+
+            // pseudo-headers in trailers
+            peer.send_headers(9, false, &GET).await;
+            peer.send_headers(9, true, &[(":path", "/")]).await;
+            assert_eq!(peer.stream_reset(9).await, protocol_error);
+
+            // none of that was worth closing the connection over
+            let frames = peer.ping().await;
+            assert!(frames.iter().all(|f| f.ty != GOAWAY), "{frames:?}");
+            peer.hang_up().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_scheme() {
+        crate::maybe_uring::start(async move {
+            let https = [GET[0], (":scheme", "https"), GET[2], GET[3]];
+            let protocol_error = KnownErrorCode::ProtocolError.repr();
+
+            // plaintext, as far as the server knows
+            let mut peer = Peer::connect(Default::default(), Rc::new(Answer::default()), &[]).await;
+            let missing = [GET[0], GET[2], GET[3]];
+            let invalid = [GET[0], (":scheme", "ht tp"), GET[2], GET[3]];
+            for (stream_id, fields) in [(1, &https[..]), (3, &missing[..]), (5, &invalid[..])] {
+                peer.send_headers(stream_id, true, fields).await;
+                assert_eq!(
+                    peer.stream_reset(stream_id).await,
+                    protocol_error,
+                    "{fields:?}"
+                );
+            }
+            let frames = peer.ping().await;
+            assert!(frames.iter().all(|f| f.ty != GOAWAY), "{frames:?}");
+            peer.hang_up().await.unwrap();
+
+            // encrypted, or behind a proxy that terminates TLS
+            for conf in [
+                ServerConf {
+                    transport_security: TransportSecurity::Tls,
+                    ..Default::default()
+                },
+                ServerConf {
+                    trust_forwarded_scheme: true,
+                    ..Default::default()
+                },
+            ] {
+                let mut peer = Peer::connect(conf, Rc::new(Answer::default()), &[]).await;
+                peer.send_headers(1, true, &https).await;
+                let res = peer.next_frame().await;
+                assert_eq!(
+                    (res.ty, res.stream_id, res.header(":status")),
+                    (HEADERS, 1, Some("200"))
+                );
+                peer.hang_up().await.unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_h2_connection_specific_headers() {
+        crate::maybe_uring::start(async move {
+            let mut headers = Headers::default();
+            headers.insert(http::header::CONNECTION, "x-hop".into());
+            headers.insert("x-hop", "1".into());
+            headers.insert("keep-alive", "timeout=5".into());
+            headers.insert(http::header::TRANSFER_ENCODING, "chunked".into());
+            headers.insert("x-kept", "1".into());
+            let driver = Rc::new(Answer {
+                headers,
+                ..Default::default()
+            });
+            let mut peer = Peer::connect(Default::default(), driver.clone(), &[]).await;
+            let protocol_error = KnownErrorCode::ProtocolError.repr();
+
+            for (stream_id, field) in [
+                (1, ("X-Upper", "1")),
+                (3, ("connection", "close")),
+                (5, ("keep-alive", "timeout=5")),
+                (7, ("transfer-encoding", "chunked")),
+                (9, ("te", "gzip")),
+            ] {
+                let fields = [&GET[..], &[field]].concat();
+                peer.send_headers(stream_id, true, &fields).await;
+                assert_eq!(
+                    peer.stream_reset(stream_id).await,
+                    protocol_error,
+                    "{field:?}"
+                );
+            }
+            assert!(driver.seen.borrow().is_empty());
+
+            // `te: trailers` is the one exception. the response loses the
+            // fields that only make sense for a single h1 connection.
+            let fields = [&GET[..], &[("te", "trailers")]].concat();
+            peer.send_headers(11, true, &fields).await;
+            let res = peer.next_frame().await;
+            assert_eq!((res.ty, res.stream_id), (HEADERS, 11));
+            assert_eq!(
+                res.headers,
+                [
+                    (":status".to_owned(), "200".to_owned()),
+                    ("x-kept".to_owned(), "1".to_owned())
+                ]
+            );
+
+            peer.hang_up().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_peer_goaway() {
+        crate::maybe_uring::start(async move {
+            let driver = Rc::new(Answer::default());
+            let mut peer = Peer::connect(Default::default(), driver.clone(), &[]).await;
+            let draining = crate::maybe_uring::spawn({
+                let handle = peer.handle.clone();
+                async move { handle.draining().await }
+            });
+
+            // a request that's still being sent when the peer goes away
+            peer.send_headers(1, false, &GET).await;
+            peer.ping().await;
+            assert!(!draining.is_finished());
+
+            let mut goaway = 0u32.to_be_bytes().to_vec();
+            goaway.extend(KnownErrorCode::NoError.repr().to_be_bytes());
+            peer.send_frame(GOAWAY, 0, 0, &goaway).await;
+            tokio::time::timeout(Duration::from_secs(5), draining)
+                .await
+                .expect("draining() didn't resolve")
+                .unwrap();
+            assert!(peer.handle.is_draining());
+
+            // new streams are refused...
+            peer.send_headers(3, true, &GET).await;
+            assert_eq!(
+                peer.stream_reset(3).await,
+                KnownErrorCode::RefusedStream.repr()
+            );
+
+            // ...while the ones in flight get served
+            peer.send_frame(DATA, END_STREAM, 1, b"hi").await;
+            let res = peer.next_frame().await;
+            assert_eq!(
+                (res.ty, res.stream_id, res.header(":status")),
+                (HEADERS, 1, Some("200"))
+            );
+            if res.flags & END_STREAM == 0 {
+                let mut body = vec![];
+                assert!(peer.read_data(1, &mut body, usize::MAX).await);
+            }
+            assert_eq!(driver.seen.take(), [("/".to_owned(), Ok(b"hi".to_vec()))]);
+
+            // then we go away too, and close the connection
+            let goaway = peer.goaway().await;
+            assert_eq!(goaway.stream_id, 0);
+            assert_eq!(goaway.error_code(), KnownErrorCode::NoError.repr());
+            peer.hang_up().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_h2_error_observer() {
+        crate::maybe_uring::start(async move {
+            let observed = Rc::new(Observed::default());
+            let conf = ServerConf {
+                error_observer: Some(observed.clone()),
+                ..Default::default()
+            };
+            let mut peer = Peer::connect(conf, Rc::new(Answer::default()), &[]).await;
+
+            // missing `:path`
+            peer.send_headers(1, true, &[GET[0], GET[1], GET[3]]).await;
+            peer.stream_reset(1).await;
+            assert_eq!(
+                observed.0.take(),
+                [(Some(1), KnownErrorCode::ProtocolError.repr())]
+            );
+
+            // a WINDOW_UPDATE that's too short
+            peer.send_frame(WINDOW_UPDATE, 0, 0, &[0, 0, 1]).await;
+            let goaway = peer.goaway().await;
+            assert_eq!(goaway.error_code(), KnownErrorCode::FrameSizeError.repr());
+            assert_eq!(
+                observed.0.take(),
+                [(None, KnownErrorCode::FrameSizeError.repr())]
+            );
+        });
+    }
+
+    #[test]
+    fn test_h2_header_timeout() {
+        crate::maybe_uring::start(async move {
+            tokio::time::pause();
+            let mut peer = Peer::connect(Default::default(), Rc::new(Answer::default()), &[]).await;
+
+            // a header block that's never finished: `header_timeout` is 10
+            // seconds by default
+            let block = peer.encode(&GET);
+            let start = tokio::time::Instant::now();
+            peer.send_frame(HEADERS, END_STREAM, 1, &block).await;
+            let goaway = peer.goaway().await;
+            assert!(start.elapsed() >= Duration::from_secs(10));
+            assert_eq!(goaway.error_code(), KnownErrorCode::ProtocolError.repr());
+        });
+    }
+
+    #[test]
+    fn test_h2_body_idle_timeout() {
+        crate::maybe_uring::start(async move {
+            tokio::time::pause();
+            let timeout = Duration::from_secs(30);
+            let conf = ServerConf {
+                body_idle_timeout: Some(timeout),
+                ..Default::default()
+            };
+            let mut peer = Peer::connect(conf, Rc::new(Stall), &[]).await;
+            peer.handle
+                .update_settings(SettingsUpdate {
+                    initial_window_size: Some(10),
+                    ..Default::default()
+                })
+                .unwrap();
+            peer.ack_settings().await;
+
+            // this one used up its window, which the handler never reopens
+            peer.send_headers(1, false, &GET).await;
+            peer.send_frame(DATA, 0, 1, &[b'a'; 10]).await;
+            // this one could send its body, but doesn't
+            let start = tokio::time::Instant::now();
+            peer.send_headers(3, false, &GET).await;
+            assert_eq!(peer.stream_reset(3).await, KnownErrorCode::Cancel.repr());
+            assert!(start.elapsed() >= timeout);
+
+            tokio::time::sleep(timeout * 10).await;
+            let frames = peer.ping().await;
+            assert!(frames.iter().all(|f| f.ty != RST_STREAM), "{frames:?}");
+        });
+    }
+
+    #[test]
+    fn test_h2_body_idle_timeout_per_request() {
+        crate::maybe_uring::start(async move {
+            tokio::time::pause();
+            let conf = ServerConf {
+                body_idle_timeout: Some(Duration::from_secs(30)),
+                ..Default::default()
+            };
+            let mut peer = Peer::connect(conf, Rc::new(Stall), &[]).await;
+
+            let start = tokio::time::Instant::now();
+            let upload = [GET[0], GET[1], (":path", "/upload"), GET[3]];
+            peer.send_headers(1, false, &upload).await;
+            peer.send_headers(3, false, &GET).await;
+
+            let cancel = KnownErrorCode::Cancel.repr();
+            assert_eq!(peer.stream_reset(3).await, cancel);
+            assert!(start.elapsed() < Duration::from_secs(60));
+            assert_eq!(peer.stream_reset(1).await, cancel);
+            assert!(start.elapsed() >= Duration::from_secs(300));
+        });
+    }
+
+    #[test]
+    fn test_h2_idle_timeout() {
+        crate::maybe_uring::start(async move {
+            tokio::time::pause();
+            let timeout = Duration::from_secs(60);
+            let conf = ServerConf {
+                idle_timeout: Some(timeout),
+                ..Default::default()
+            };
+            let mut peer = Peer::connect(conf, Rc::new(Answer::default()), &[]).await;
+
+            peer.send_headers(1, true, &GET).await;
+            while peer.next_frame().await.flags & END_STREAM == 0 {}
+            let start = tokio::time::Instant::now();
+
+            // control frames don't count as activity
+            tokio::time::sleep(timeout / 2).await;
+            peer.ping().await;
+            let goaway = peer.goaway().await;
+            assert!(start.elapsed() >= timeout);
+            assert!(start.elapsed() < timeout * 3 / 2, "the PING kept it open");
+            assert_eq!(goaway.error_code(), KnownErrorCode::NoError.repr());
+        });
+    }
+}
@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Counters for a connection being served, see [ConnectionHandle::stats](super::ConnectionHandle::stats)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnStats {
+    /// PING frames received, acknowledgements included
+    pub pings_received: u64,
+
+    /// SETTINGS frames received, acknowledgements included
+    pub settings_received: u64,
+
+    /// WINDOW_UPDATE frames received, for the connection or any stream
+    pub window_updates_received: u64,
+
+    /// RST_STREAM frames received, i.e. streams the peer cancelled
+    pub rst_streams_received: u64,
+
+    /// PRIORITY_UPDATE frames received
+    pub priority_updates_received: u64,
+
+    /// Set if the connection was closed because the peer went over
+    /// [ControlFrameLimits]
+    pub control_frame_flood: Option<ControlFrameKind>,
+}
+
+/// Frames that cost us work without opening streams, and so are cheap for
+/// a peer to flood us with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlFrameKind {
+    Ping,
+    Settings,
+    WindowUpdate,
+
+    /// Opening streams is limited by SETTINGS_MAX_CONCURRENT_STREAMS, but
+    /// a stream the peer resets right away doesn't count towards it, while
+    /// its handler still got spawned: this is the "rapid reset" attack
+    /// (CVE-2023-44487). Only resets of streams whose handler is still
+    /// running count towards the limit.
+    RstStream,
+
+    /// Each one may have to be buffered, if it's for a stream that isn't
+    /// open yet
+    PriorityUpdate,
+}
+
+/// How many control frames of each kind a peer may send within `window`
+/// before the connection is closed with `error_code`.
+#[derive(Debug, Clone, Copy)]
+pub struct ControlFrameLimits {
+    pub window: Duration,
+    pub max_pings: u32,
+    pub max_settings: u32,
+    pub max_window_updates: u32,
+    pub max_rst_streams: u32,
+    pub max_priority_updates: u32,
+    pub error_code: FloodErrorCode,
+}
+
+impl Default for ControlFrameLimits {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(1),
+            max_pings: 100,
+            max_settings: 100,
+            max_window_updates: 10_000,
+            max_rst_streams: 200,
+            max_priority_updates: 1000,
+            error_code: Default::default(),
+        }
+    }
+}
+
+impl ControlFrameLimits {
+    fn max(&self, kind: ControlFrameKind) -> u32 {
+        match kind {
+            ControlFrameKind::Ping => self.max_pings,
+            ControlFrameKind::Settings => self.max_settings,
+            ControlFrameKind::WindowUpdate => self.max_window_updates,
+            ControlFrameKind::RstStream => self.max_rst_streams,
+            ControlFrameKind::PriorityUpdate => self.max_priority_updates,
+        }
+    }
+}
+
+/// The error code sent in GOAWAY when a peer goes over [ControlFrameLimits]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FloodErrorCode {
+    /// ENHANCE_YOUR_CALM, which tells floods apart from other errors
+    #[default]
+    EnhanceYourCalm,
+
+    /// PROTOCOL_ERROR, which doesn't let on that there's a limit
+    ProtocolError,
+}
+
+/// Counts control frames over fixed windows of [ControlFrameLimits::window]
+#[derive(Default)]
+pub(crate) struct ControlFrameCounter {
+    window_start: Option<Instant>,
+    counts: [u32; 5],
+}
+
+impl ControlFrameCounter {
+    /// Counts a frame of kind `kind`, returns false if that puts the peer
+    /// over `limits`
+    pub(crate) fn record(&mut self, kind: ControlFrameKind, limits: &ControlFrameLimits) -> bool {
+        let now = Instant::now();
+        match self.window_start {
+            Some(start) if now.duration_since(start) < limits.window => {}
+            _ => {
+                self.window_start = Some(now);
+                self.counts = Default::default();
+            }
+        }
+
+        let count = &mut self.counts[kind as usize];
+        *count += 1;
+        *count <= limits.max(kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{ControlFrameCounter, ControlFrameKind, ControlFrameLimits, FloodErrorCode};
+    use crate::h2::{parse::KnownErrorCode, types::H2ConnectionError};
+
+    const KINDS: [ControlFrameKind; 5] = [
+        ControlFrameKind::Ping,
+        ControlFrameKind::Settings,
+        ControlFrameKind::WindowUpdate,
+        ControlFrameKind::RstStream,
+        ControlFrameKind::PriorityUpdate,
+    ];
+
+    #[test]
+    fn test_control_frame_counter() {
+        crate::maybe_uring::start(async move {
+            tokio::time::pause();
+            let limits = ControlFrameLimits {
+                max_pings: 1,
+                max_settings: 2,
+                max_window_updates: 3,
+                max_rst_streams: 4,
+                max_priority_updates: 5,
+                ..Default::default()
+            };
+            let mut counter = ControlFrameCounter::default();
+
+            // each kind has its own limit, and its own count
+            for (i, kind) in KINDS.into_iter().enumerate() {
+                for _ in 0..=i {
+                    assert!(counter.record(kind, &limits), "{kind:?}");
+                }
+                assert!(!counter.record(kind, &limits), "{kind:?}");
+            }
+
+            // counts are kept for the whole window...
+            tokio::time::advance(limits.window - Duration::from_millis(1)).await;
+            assert!(!counter.record(ControlFrameKind::Ping, &limits));
+
+            // ...then start over
+            tokio::time::advance(Duration::from_millis(1)).await;
+            for kind in KINDS {
+                assert!(counter.record(kind, &limits), "{kind:?}");
+            }
+            assert!(!counter.record(ControlFrameKind::Ping, &limits));
+        });
+    }
+
+    #[test]
+    fn test_control_frame_flood_error_code() {
+        let code = |error_code| {
+            H2ConnectionError::ControlFrameFlood {
+                kind: ControlFrameKind::Ping,
+                error_code,
+            }
+            .as_known_error_code()
+            .repr()
+        };
+        assert_eq!(FloodErrorCode::default(), FloodErrorCode::EnhanceYourCalm);
+        assert_eq!(
+            code(FloodErrorCode::EnhanceYourCalm),
+            KnownErrorCode::EnhanceYourCalm.repr()
+        );
+        assert_eq!(
+            code(FloodErrorCode::ProtocolError),
+            KnownErrorCode::ProtocolError.repr()
+        );
+    }
+}
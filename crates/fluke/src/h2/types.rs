@@ -1,19 +1,204 @@
-use std::{collections::HashMap, fmt};
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    rc::Rc,
+    time::Duration,
+};
 
 use fluke_buffet::Piece;
+use http::{uri::Scheme, HeaderName};
 
-use crate::Response;
+use crate::{Headers, Response, TransportSecurity};
 
 use super::{
     body::H2BodySender,
     parse::{FrameType, KnownErrorCode, Settings, StreamId},
+    stats::{ControlFrameCounter, ControlFrameKind, FloodErrorCode},
+    Priority, WindowUpdateStrategy,
 };
 
 pub(crate) struct ConnState {
     pub(crate) streams: HashMap<StreamId, StreamState>,
     pub(crate) last_stream_id: StreamId,
+    /// The settings we sent that were acknowledged last, which are the ones
+    /// we enforce
     pub(crate) self_settings: Settings,
     pub(crate) peer_settings: Settings,
+
+    /// Settings we sent that weren't acknowledged yet, oldest first, along
+    /// with when they were sent
+    pub(crate) pending_settings: VecDeque<(Settings, tokio::time::Instant)>,
+
+    /// Streams we sent RST_STREAM for, most recent last: frames the peer
+    /// sent before it got our RST_STREAM may still arrive for those, and must
+    /// be ignored rather than treated as errors.
+    pub(crate) reset_streams: VecDeque<StreamId>,
+
+    pub(crate) control_frames: ControlFrameCounter,
+
+    /// How many bytes of DATA we may still send before the peer opens the
+    /// connection window with WINDOW_UPDATE, cf. RFC 9113 section 6.9
+    pub(crate) outgoing_window: i64,
+
+    /// How many bytes of DATA the peer may still send on the connection as
+    /// a whole
+    pub(crate) incoming_window: IncomingWindow,
+
+    /// Response bodies waiting for `outgoing_window` to open, in the order
+    /// they were written, across all streams. They go out in order of
+    /// priority, see `next_pending_data`.
+    pub(crate) pending_data: VecDeque<(StreamId, PendingData)>,
+
+    /// Priorities from PRIORITY_UPDATE frames for streams the peer hasn't
+    /// opened yet, most recent last, applied once they are
+    pub(crate) early_priorities: VecDeque<(StreamId, Priority)>,
+
+    /// The incremental stream that pending data was last written for, so
+    /// that incremental streams of the same urgency take turns
+    pub(crate) last_incremental: StreamId,
+}
+
+impl ConnState {
+    /// How many reset streams we remember, see `reset_streams`
+    const MAX_RESET_STREAMS: usize = 64;
+
+    pub(crate) fn record_reset(&mut self, stream_id: StreamId) {
+        if self.reset_streams.len() == Self::MAX_RESET_STREAMS {
+            self.reset_streams.pop_front();
+        }
+        self.reset_streams.push_back(stream_id);
+    }
+
+    pub(crate) fn was_reset(&self, stream_id: StreamId) -> bool {
+        self.reset_streams.contains(&stream_id)
+    }
+
+    /// Whether `stream_id` was opened and has since been closed, or reset,
+    /// as opposed to still idle, cf. RFC 9113 section 5.1. Peers may still
+    /// send a few frames on closed streams, which are to be ignored.
+    pub(crate) fn is_closed(&self, stream_id: StreamId) -> bool {
+        if self.streams.contains_key(&stream_id) {
+            return false;
+        }
+        // we never push, so only the peer opens streams, in increasing order
+        self.was_reset(stream_id)
+            || (!stream_id.is_server_initiated() && stream_id <= self.last_stream_id)
+    }
+
+    /// Forgets response body data for a stream that's gone
+    pub(crate) fn drop_pending_data(&mut self, stream_id: StreamId) {
+        let mut dropped = 0;
+        self.pending_data.retain(|(id, data)| {
+            if *id != stream_id {
+                return true;
+            }
+            if let PendingData::Chunk(chunk) = data {
+                dropped += chunk.len();
+            }
+            false
+        });
+        self.dequeue_data(stream_id, dropped);
+    }
+
+    /// Lets a stream's encoder produce more body data, now that `len`
+    /// bytes of what it queued are gone
+    pub(crate) fn dequeue_data(&self, stream_id: StreamId, len: usize) {
+        if let Some(StreamState::Open(_, outgoing) | StreamState::HalfClosedRemote(outgoing)) =
+            self.streams.get(&stream_id)
+        {
+            outgoing.dequeue(len);
+        }
+    }
+
+    /// The stream whose body idle deadline comes first, when that is, and the
+    /// timeout that led to it, see [StreamIncoming::body_idle_deadline]
+    pub(crate) fn next_body_idle_deadline(
+        &self,
+    ) -> Option<(tokio::time::Instant, StreamId, Duration)> {
+        if self.incoming_window.is_exhausted() {
+            // no stream can make progress until we open the connection window
+            return None;
+        }
+        self.streams
+            .iter()
+            .filter_map(|(id, ss)| match ss {
+                StreamState::Open(incoming, _) | StreamState::HalfClosedLocal(incoming) => {
+                    let (deadline, timeout) = incoming.body_idle_deadline()?;
+                    Some((deadline, *id, timeout))
+                }
+                StreamState::HalfClosedRemote(_) => None,
+            })
+            .min()
+    }
+
+    /// The priority of a stream we may still send on
+    pub(crate) fn priority(&self, stream_id: StreamId) -> Priority {
+        match self.streams.get(&stream_id) {
+            Some(StreamState::Open(_, outgoing) | StreamState::HalfClosedRemote(outgoing)) => {
+                outgoing.priority
+            }
+            _ => Default::default(),
+        }
+    }
+
+    /// Records a PRIORITY_UPDATE for a stream that isn't open yet. At most
+    /// SETTINGS_MAX_CONCURRENT_STREAMS of them are remembered, cf. RFC 9218
+    /// section 7.1.
+    pub(crate) fn record_early_priority(&mut self, stream_id: StreamId, priority: Priority) {
+        self.early_priorities.retain(|(id, _)| *id != stream_id);
+        if self.early_priorities.len() >= self.self_settings.max_concurrent_streams as usize {
+            self.early_priorities.pop_front();
+        }
+        self.early_priorities.push_back((stream_id, priority));
+    }
+
+    /// Takes what PRIORITY_UPDATE said about a stream before it was opened
+    pub(crate) fn take_early_priority(&mut self, stream_id: StreamId) -> Option<Priority> {
+        let index = self
+            .early_priorities
+            .iter()
+            .position(|(id, _)| *id == stream_id)?;
+        self.early_priorities
+            .remove(index)
+            .map(|(_, priority)| priority)
+    }
+
+    /// Index of the pending data to write next: that of the most urgent
+    /// stream. Among streams of the same urgency, non-incremental ones go
+    /// first, one after the other in the order they were opened, then
+    /// incremental ones take turns, cf. RFC 9218 section 10.
+    ///
+    /// Each stream's data goes out in order, but streams don't wait on one
+    /// another: while the connection window is exhausted, streams whose
+    /// next entry is body data are skipped, and those about to end (which
+    /// needs no window) still get to.
+    pub(crate) fn next_pending_data(&self) -> Option<usize> {
+        let blocked = self.outgoing_window <= 0;
+        let mut seen = HashSet::new();
+        let mut best: Option<((u8, bool, u32), usize)> = None;
+        for (index, (stream_id, data)) in self.pending_data.iter().enumerate() {
+            if !seen.insert(*stream_id) {
+                // only the first entry of a stream may go out
+                continue;
+            }
+            if blocked && matches!(data, PendingData::Chunk(_)) {
+                continue;
+            }
+            let priority = self.priority(*stream_id);
+            let order = if priority.incremental {
+                // streams after the last one served come first
+                stream_id.0.wrapping_sub(self.last_incremental.0 + 1)
+            } else {
+                stream_id.0
+            };
+            let key = (priority.urgency, priority.incremental, order);
+            if best.map_or(true, |(best_key, _)| key < best_key) {
+                best = Some((key, index));
+            }
+        }
+        best.map(|(_, index)| index)
+    }
 }
 
 impl Default for ConnState {
@@ -21,12 +206,39 @@ impl Default for ConnState {
         Self {
             streams: Default::default(),
             last_stream_id: StreamId(0),
+            reset_streams: Default::default(),
+            control_frames: Default::default(),
+            outgoing_window: DEFAULT_WINDOW_SIZE,
+            incoming_window: IncomingWindow::new(DEFAULT_WINDOW_SIZE as u32),
+            pending_data: Default::default(),
+            early_priorities: Default::default(),
+            last_incremental: StreamId(0),
+            pending_settings: Default::default(),
             self_settings: Default::default(),
             peer_settings: Default::default(),
         }
     }
 }
 
+/// Initial size of the connection flow-control window, which SETTINGS can't
+/// change, cf. RFC 9113 section 6.9.2
+pub(crate) const DEFAULT_WINDOW_SIZE: i64 = 65_535;
+
+/// Largest a flow-control window may get, cf. RFC 9113 section 6.9.1
+pub(crate) const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+
+/// Response body data held back by flow control
+pub(crate) enum PendingData {
+    Chunk(Piece),
+
+    /// Ends the body: written as an empty DATA frame with END_STREAM
+    End,
+
+    /// Ends the body with trailers: written as a HEADERS frame with
+    /// END_STREAM
+    EndWithTrailers(Headers),
+}
+
 // cf. RFC 9113, 5.1 Stream States:
 //
 //                               +--------+
@@ -69,17 +281,260 @@ impl Default for ConnState {
 //     transitions are for the promised stream
 pub(crate) enum StreamState {
     // we have received full HEADERS
-    Open(H2BodySender),
+    Open(StreamIncoming, StreamOutgoing),
 
     // the peer has sent END_STREAM/RST_STREAM
-    HalfClosedRemote,
+    HalfClosedRemote(StreamOutgoing),
 
     // we have sent END_STREAM/RST_STREAM
-    HalfClosedLocal(H2BodySender),
+    HalfClosedLocal(StreamIncoming),
     //
     // Note: the "Closed" state is indicated by not having an entry in the map
 }
 
+/// What we receive on a stream, kept for as long as the peer may send on it
+pub(crate) struct StreamIncoming {
+    pub(crate) body_tx: H2BodySender,
+
+    /// From the request's `content-length` header
+    content_length: Option<u64>,
+
+    /// The length of DATA frames received so far, padding excluded
+    received: u64,
+
+    /// How much more the peer may send on this stream
+    pub(crate) window: IncomingWindow,
+
+    /// When the peer last sent DATA on this stream, or was let to send
+    /// more of it: what `body_idle_timeout` counts from
+    pub(crate) last_progress: tokio::time::Instant,
+
+    /// [ServerConf::body_idle_timeout](super::ServerConf::body_idle_timeout),
+    /// as adjusted for this request, see [crate::RequestLimits]
+    body_idle_timeout: Option<Duration>,
+}
+
+impl StreamIncoming {
+    /// `window_size` is our SETTINGS_INITIAL_WINDOW_SIZE
+    pub(crate) fn new(
+        body_tx: H2BodySender,
+        content_length: Option<u64>,
+        window_size: u32,
+        body_idle_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            body_tx,
+            content_length,
+            received: 0,
+            window: IncomingWindow::new(window_size),
+            last_progress: tokio::time::Instant::now(),
+            body_idle_timeout,
+        }
+    }
+
+    /// When the stream should be reset for the peer not sending any of its
+    /// body for its timeout, along with that timeout. There's no deadline
+    /// while the stream window is closed: the peer is waiting on us then,
+    /// not the other way around.
+    pub(crate) fn body_idle_deadline(&self) -> Option<(tokio::time::Instant, Duration)> {
+        let timeout = self.body_idle_timeout?;
+        (!self.window.is_exhausted()).then(|| (self.last_progress + timeout, timeout))
+    }
+
+    /// Counts a DATA frame of `len` bytes (or trailers, with a `len` of 0)
+    /// against `content-length`, which the request body must add up to,
+    /// cf. RFC 9113 section 8.1.1
+    pub(crate) fn receive(&mut self, len: usize, end_stream: bool) -> Result<(), H2StreamError> {
+        self.received += len as u64;
+        self.last_progress = tokio::time::Instant::now();
+        match self.content_length {
+            Some(content_length)
+                if self.received > content_length
+                    || (end_stream && self.received != content_length) =>
+            {
+                Err(H2StreamError::DataLengthDoesNotMatchContentLength {
+                    data_length: self.received,
+                    content_length,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Flow control for what the peer sends, on a stream or on the connection
+/// as a whole: what it may still send, and what it sent that was read since
+/// the last WINDOW_UPDATE, cf. RFC 9113 section 6.9.
+#[derive(Debug)]
+pub(crate) struct IncomingWindow {
+    /// What the window gets topped back up to
+    size: u32,
+
+    /// What the peer may still send, as far as it knows
+    available: i64,
+
+    /// Read, but not announced with WINDOW_UPDATE yet
+    unacked: u32,
+}
+
+impl IncomingWindow {
+    pub(crate) fn new(size: u32) -> Self {
+        Self {
+            size,
+            available: size as i64,
+            unacked: 0,
+        }
+    }
+
+    /// Counts `len` bytes of DATA (padding included) against the window
+    pub(crate) fn receive(&mut self, len: u32) {
+        self.available -= len as i64;
+    }
+
+    /// Whether the peer sent more than it was allowed to
+    pub(crate) fn is_exceeded(&self) -> bool {
+        self.available < 0
+    }
+
+    /// Whether the peer may not send anything until we open the window
+    pub(crate) fn is_exhausted(&self) -> bool {
+        self.available <= 0
+    }
+
+    /// Applies a new SETTINGS_INITIAL_WINDOW_SIZE, which grows or shrinks
+    /// what the peer may send right away, cf.
+    /// <https://httpwg.org/specs/rfc9113.html#InitialWindowSize>
+    pub(crate) fn resize(&mut self, size: u32) {
+        self.available += size as i64 - self.size as i64;
+        self.size = size;
+    }
+
+    /// Counts `len` bytes as read. Returns the increment of the WINDOW_UPDATE
+    /// to send, if `strategy` says it's time.
+    pub(crate) fn consume(&mut self, len: u32, strategy: WindowUpdateStrategy) -> Option<u32> {
+        self.unacked = self.unacked.saturating_add(len);
+        if self.unacked == 0 {
+            return None;
+        }
+
+        let due = match strategy {
+            WindowUpdateStrategy::Eager => true,
+            WindowUpdateStrategy::LowWatermark(ratio) => {
+                (self.available as f64) < self.size as f64 * ratio as f64
+            }
+        };
+        due.then(|| {
+            let increment = std::mem::take(&mut self.unacked);
+            self.available += increment as i64;
+            increment
+        })
+    }
+}
+
+/// Flow control for what we send on a stream, cf. RFC 9113 section 6.9.
+/// Kept with the stream's state for as long as we may send on it: dropping
+/// it closes the window, which the stream's encoder then gives up waiting on.
+pub(crate) struct StreamOutgoing {
+    window: Rc<StreamWindow>,
+
+    /// How response DATA is scheduled against other streams'
+    pub(crate) priority: Priority,
+}
+
+impl StreamOutgoing {
+    /// `size` is the peer's SETTINGS_INITIAL_WINDOW_SIZE, `max_queued` is
+    /// [ServerConf::max_queued_body_data](super::ServerConf::max_queued_body_data),
+    /// where zero is treated as one: nothing could ever be queued otherwise.
+    pub(crate) fn new(size: u32, max_queued: usize) -> Self {
+        Self {
+            window: Rc::new(StreamWindow {
+                size: Cell::new(size as i64),
+                queued: Cell::new(0),
+                max_queued: max_queued.max(1),
+                closed: Cell::new(false),
+                notify: Default::default(),
+            }),
+            priority: Default::default(),
+        }
+    }
+
+    /// What the stream's encoder waits on
+    pub(crate) fn window(&self) -> Rc<StreamWindow> {
+        self.window.clone()
+    }
+
+    /// Grows the window by `delta`, which may be negative when the peer
+    /// lowers SETTINGS_INITIAL_WINDOW_SIZE. Fails if that makes the window
+    /// exceed [MAX_WINDOW_SIZE].
+    pub(crate) fn adjust(&self, delta: i64) -> Result<(), WindowOverflow> {
+        let size = self.window.size.get() + delta;
+        if size > MAX_WINDOW_SIZE {
+            return Err(WindowOverflow);
+        }
+        self.window.size.set(size);
+        if size > 0 {
+            self.window.notify.notify_waiters();
+        }
+        Ok(())
+    }
+
+    /// Records that `len` bytes the encoder reserved are no longer queued,
+    /// because they were written out (or dropped), which lets it produce
+    /// more
+    pub(crate) fn dequeue(&self, len: usize) {
+        let queued = self.window.queued.get().saturating_sub(len);
+        self.window.queued.set(queued);
+        if queued < self.window.max_queued {
+            self.window.notify.notify_waiters();
+        }
+    }
+}
+
+impl Drop for StreamOutgoing {
+    fn drop(&mut self) {
+        self.window.closed.set(true);
+        self.window.notify.notify_waiters();
+    }
+}
+
+/// A stream's send window, as seen from its encoder
+pub(crate) struct StreamWindow {
+    size: Cell<i64>,
+    /// Bytes reserved by the encoder that the connection hasn't written out
+    /// yet: in the event channel, or waiting on the connection window
+    queued: Cell<usize>,
+    max_queued: usize,
+    closed: Cell<bool>,
+    notify: tokio::sync::Notify,
+}
+
+impl StreamWindow {
+    /// Waits for the window to open and for the connection to have written
+    /// out enough of what was queued before, then takes up to `max` bytes
+    /// of both. Fails if the stream is closed (e.g. reset) in the meantime.
+    pub(crate) async fn reserve(&self, max: usize) -> Result<usize, H2StreamError> {
+        loop {
+            let notified = self.notify.notified();
+            if self.closed.get() {
+                return Err(H2StreamError::StreamClosed);
+            }
+
+            let size = self.size.get();
+            let room = self.max_queued.saturating_sub(self.queued.get());
+            if size > 0 && room > 0 {
+                let n = std::cmp::min(std::cmp::min(size as usize, room), max);
+                self.size.set(size - n as i64);
+                self.queued.set(self.queued.get() + n);
+                return Ok(n);
+            }
+            notified.await;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct WindowOverflow;
+
 #[derive(Debug, thiserror::Error)]
 pub(crate) enum H2ConnectionError {
     #[error("frame too large: {frame_type:?} frame of size {frame_size} exceeds max frame size of {max_frame_size}")]
@@ -139,6 +594,9 @@ pub(crate) enum H2ConnectionError {
     #[error("client sent a push promise frame, clients aren't allowed to do that, cf. RFC9113 section 8.4")]
     ClientSentPushPromise,
 
+    #[error("server sent a push promise frame, but we disabled server push")]
+    ServerSentPushPromise,
+
     #[error("received window update for unknown stream {stream_id}")]
     WindowUpdateForUnknownStream { stream_id: StreamId },
 
@@ -166,17 +624,50 @@ pub(crate) enum H2ConnectionError {
     #[error("received settings frame with invalid length {len}")]
     SettingsAckWithPayload { len: u32 },
 
+    #[error("peer didn't acknowledge our settings within {timeout:?}")]
+    SettingsTimeout { timeout: Duration },
+
+    #[error("peer didn't finish sending a header block within {timeout:?}")]
+    HeaderTimeout { timeout: Duration },
+
     #[error("received settings frame with non-zero stream id")]
     SettingsWithNonZeroStreamId { stream_id: StreamId },
 
     #[error("received goaway frame with non-zero stream id")]
     GoAwayWithNonZeroStreamId { stream_id: StreamId },
 
+    #[error("received goaway frame with invalid length {len}")]
+    GoAwayInvalidLength { len: u32 },
+
     #[error("zero increment in window update frame for stream")]
     WindowUpdateZeroIncrement,
 
     #[error("received window update frame with invalid length {len}")]
     WindowUpdateInvalidLength { len: usize },
+
+    #[error("window update made the connection window exceed 2^31-1")]
+    WindowUpdateOverflow,
+
+    #[error("new initial window size made a stream window exceed 2^31-1")]
+    InitialWindowSizeOverflow,
+
+    #[error("peer sent more data than the connection window allows")]
+    ReceiveWindowExceeded,
+
+    #[error("received priority update frame with non-zero stream id")]
+    PriorityUpdateWithNonZeroStreamId { stream_id: StreamId },
+
+    #[error("received priority update frame with invalid length {len}")]
+    PriorityUpdateInvalidLength { len: u32 },
+
+    #[error("received priority update frame for the connection")]
+    PriorityUpdateForConnection,
+
+    #[error("peer sent too many {kind:?} frames")]
+    ControlFrameFlood {
+        kind: ControlFrameKind,
+        error_code: FloodErrorCode,
+    },
 }
 
 impl H2ConnectionError {
@@ -187,23 +678,35 @@ impl H2ConnectionError {
             H2ConnectionError::PaddedFrameEmpty { .. } => KnownErrorCode::FrameSizeError,
             H2ConnectionError::PaddedFrameTooShort { .. } => KnownErrorCode::FrameSizeError,
             H2ConnectionError::PingFrameInvalidLength { .. } => KnownErrorCode::FrameSizeError,
+            H2ConnectionError::GoAwayInvalidLength { .. } => KnownErrorCode::FrameSizeError,
             H2ConnectionError::SettingsAckWithPayload { .. } => KnownErrorCode::FrameSizeError,
             H2ConnectionError::WindowUpdateInvalidLength { .. } => KnownErrorCode::FrameSizeError,
+            H2ConnectionError::PriorityUpdateInvalidLength { .. } => KnownErrorCode::FrameSizeError,
+            // flow control errors
+            H2ConnectionError::WindowUpdateOverflow => KnownErrorCode::FlowControlError,
+            H2ConnectionError::InitialWindowSizeOverflow => KnownErrorCode::FlowControlError,
+            H2ConnectionError::ReceiveWindowExceeded => KnownErrorCode::FlowControlError,
+            // settings timeout
+            H2ConnectionError::SettingsTimeout { .. } => KnownErrorCode::SettingsTimeout,
             // compression errors
             H2ConnectionError::CompressionError(_) => KnownErrorCode::CompressionError,
             // stream closed error
             H2ConnectionError::StreamClosed { .. } => KnownErrorCode::StreamClosed,
             // internal errors
             H2ConnectionError::Internal(_) => KnownErrorCode::InternalError,
+            // floods, reported however the configuration says
+            H2ConnectionError::ControlFrameFlood { error_code, .. } => match error_code {
+                FloodErrorCode::EnhanceYourCalm => KnownErrorCode::EnhanceYourCalm,
+                FloodErrorCode::ProtocolError => KnownErrorCode::ProtocolError,
+            },
             // protocol errors
             _ => KnownErrorCode::ProtocolError,
         }
     }
 }
 
-#[derive(Debug, thiserror::Error)]
+#[derive(Debug, Clone, thiserror::Error)]
 pub(crate) enum H2StreamError {
-    #[allow(dead_code)]
     #[error("received {data_length} bytes in data frames but content-length announced {content_length} bytes")]
     DataLengthDoesNotMatchContentLength {
         data_length: u64,
@@ -227,6 +730,63 @@ pub(crate) enum H2StreamError {
 
     #[error("received RST_STREAM frame with invalid size, expected 4 got {frame_size}")]
     InvalidRstStreamFrameSize { frame_size: u32 },
+
+    #[error("request :scheme {scheme} does not match transport ({transport_security:?})")]
+    SchemeDoesNotMatchTransport {
+        scheme: Scheme,
+        transport_security: TransportSecurity,
+    },
+
+    #[error("received unknown pseudo-header {name}")]
+    UnknownPseudoHeader { name: String },
+
+    #[error("received :protocol more than once, or on a request that isn't a CONNECT")]
+    InvalidProtocolPseudoHeader,
+
+    #[error("received pseudo-header {name} more than once")]
+    DuplicatePseudoHeader { name: &'static str },
+
+    #[error("received invalid value for pseudo-header {name}")]
+    InvalidPseudoHeaderValue { name: &'static str },
+
+    #[error("request is missing pseudo-header {name}")]
+    MissingPseudoHeader { name: &'static str },
+
+    #[error("received pseudo-header {name} in trailers")]
+    PseudoHeaderInTrailers { name: String },
+
+    #[error("received invalid header name {name:?}")]
+    InvalidHeaderName { name: String },
+
+    #[error("received header name {name:?} with uppercase characters")]
+    UppercaseHeaderName { name: String },
+
+    #[error("received connection-specific header {name}")]
+    ConnectionSpecificHeader { name: HeaderName },
+
+    #[error("received te header with a value other than `trailers`")]
+    InvalidTeHeader,
+
+    #[error("received header {name} more than once")]
+    DuplicateSingletonHeader { name: HeaderName },
+
+    #[error("header list exceeds the configured maximum size")]
+    HeaderListTooLarge,
+
+    #[error("the handler dropped the response before finishing it")]
+    ResponseIncomplete,
+
+    #[error("the response is complete and nobody is reading the request body")]
+    RequestBodyAbandoned,
+
+    #[error("window update made the stream window exceed 2^31-1")]
+    WindowUpdateOverflow,
+
+    #[error("received more data than the stream window allowed")]
+    ReceiveWindowExceeded,
+
+    #[error("peer didn't send any of the request body for {timeout:?}")]
+    BodyIdleTimeout { timeout: Duration },
 }
 
 impl H2StreamError {
@@ -239,6 +799,11 @@ impl H2StreamError {
             RefusedStream => Code::RefusedStream,
             InvalidPriorityFrameSize { .. } => Code::FrameSizeError,
             InvalidRstStreamFrameSize { .. } => Code::FrameSizeError,
+            ResponseIncomplete => Code::InternalError,
+            RequestBodyAbandoned => Code::NoError,
+            WindowUpdateOverflow => Code::FlowControlError,
+            ReceiveWindowExceeded => Code::FlowControlError,
+            BodyIdleTimeout { .. } => Code::Cancel,
             _ => Code::ProtocolError,
         }
     }
@@ -260,6 +825,13 @@ pub(crate) enum H2EventPayload {
     Headers(Response),
     BodyChunk(Piece),
     BodyEnd,
+    BodyEndWithTrailers(Headers),
+
+    /// The response was dropped halfway through
+    Abort,
+
+    /// The handler returned, and dropped the request body along with it
+    HandlerDone,
 }
 
 impl fmt::Debug for H2EventPayload {
@@ -268,6 +840,9 @@ impl fmt::Debug for H2EventPayload {
             Self::Headers(_) => f.debug_tuple("Headers").finish(),
             Self::BodyChunk(_) => f.debug_tuple("BodyChunk").finish(),
             Self::BodyEnd => write!(f, "BodyEnd"),
+            Self::BodyEndWithTrailers(_) => f.debug_tuple("BodyEndWithTrailers").finish(),
+            Self::Abort => write!(f, "Abort"),
+            Self::HandlerDone => write!(f, "HandlerDone"),
         }
     }
 }
@@ -275,3 +850,121 @@ impl fmt::Debug for H2EventPayload {
 #[derive(thiserror::Error, Debug)]
 #[error("the peer closed the connection unexpectedly")]
 pub(crate) struct ConnectionClosed;
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use fluke_buffet::Piece;
+
+    use super::{ConnState, IncomingWindow, PendingData, StreamOutgoing, StreamState};
+    use crate::h2::{parse::StreamId, Priority, WindowUpdateStrategy};
+
+    #[test]
+    fn test_incoming_window() {
+        let strategy = WindowUpdateStrategy::LowWatermark(0.5);
+        let mut window = IncomingWindow::new(100);
+
+        window.receive(40);
+        assert_eq!(window.consume(40, strategy), None);
+        // below half the window: everything read so far is given back
+        window.receive(20);
+        assert_eq!(window.consume(10, strategy), Some(50));
+        assert_eq!(window.consume(10, strategy), None);
+        assert_eq!(window.consume(0, WindowUpdateStrategy::Eager), Some(10));
+        assert_eq!(window.consume(0, WindowUpdateStrategy::Eager), None);
+
+        assert!(!window.is_exhausted());
+        window.receive(100);
+        assert!(!window.is_exceeded());
+        assert!(window.is_exhausted());
+        window.receive(1);
+        assert!(window.is_exceeded());
+        window.resize(200);
+        assert!(!window.is_exceeded());
+    }
+
+    #[test]
+    fn test_stream_window_queue() {
+        crate::maybe_uring::start(async move {
+            let outgoing = StreamOutgoing::new(100, 30);
+            let window = outgoing.window();
+
+            // bounded by what may be queued, then by the window
+            assert_eq!(window.reserve(50).await.unwrap(), 30);
+            let blocked = tokio::time::timeout(Duration::from_millis(10), window.reserve(50));
+            assert!(blocked.await.is_err(), "nothing may be queued anymore");
+
+            outgoing.dequeue(20);
+            assert_eq!(window.reserve(50).await.unwrap(), 20);
+            outgoing.dequeue(30);
+            assert_eq!(window.reserve(50).await.unwrap(), 30);
+            outgoing.dequeue(50);
+            // 20 bytes left in the window
+            assert_eq!(window.reserve(50).await.unwrap(), 20);
+
+            drop(outgoing);
+            assert!(window.reserve(50).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_stream_window_zero_max_queued() {
+        crate::maybe_uring::start(async move {
+            let outgoing = StreamOutgoing::new(100, 0);
+            let window = outgoing.window();
+
+            let reserved = tokio::time::timeout(Duration::from_millis(10), window.reserve(50));
+            assert_eq!(reserved.await.expect("must not hang").unwrap(), 1);
+        });
+    }
+
+    #[test]
+    fn test_reset_streams_evicted_oldest_first() {
+        let mut state = ConnState::default();
+        let max = ConnState::MAX_RESET_STREAMS as u32;
+        for i in 0..=max {
+            state.record_reset(StreamId(2 * i + 1));
+        }
+
+        assert_eq!(state.reset_streams.len(), max as usize);
+        assert!(!state.was_reset(StreamId(1)), "oldest reset forgotten");
+        assert!(state.was_reset(StreamId(3)));
+        assert!(state.was_reset(StreamId(2 * max + 1)));
+    }
+
+    #[test]
+    fn test_next_pending_data() {
+        let mut state = ConnState::default();
+        for (stream_id, urgency) in [(1, 3), (3, 0)] {
+            let mut outgoing = StreamOutgoing::new(65_535, 1 << 20);
+            outgoing.priority = Priority {
+                urgency,
+                incremental: false,
+            };
+            state
+                .streams
+                .insert(StreamId(stream_id), StreamState::HalfClosedRemote(outgoing));
+        }
+        let chunk = || PendingData::Chunk(Piece::Static(b"data"));
+        state.pending_data.extend([
+            (StreamId(1), chunk()),
+            (StreamId(3), chunk()),
+            (StreamId(3), PendingData::End),
+            (StreamId(1), PendingData::End),
+        ]);
+
+        // the more urgent stream goes first, even though it was opened later
+        assert_eq!(state.next_pending_data(), Some(1));
+
+        // with the connection window exhausted, streams about to end don't
+        // wait for those blocked on it, but each stream's data stays in order
+        state.outgoing_window = 0;
+        assert_eq!(state.next_pending_data(), None);
+        // stream 3's data had gone out before the window ran out
+        state.pending_data.remove(1);
+        assert_eq!(state.next_pending_data(), Some(1));
+        state.pending_data.remove(1);
+        assert_eq!(state.next_pending_data(), None);
+    }
+}
@@ -0,0 +1,338 @@
+use std::{fmt, str::FromStr};
+
+use http::{header, StatusCode};
+use tracing::debug;
+
+use fluke_buffet::Piece;
+use fluke_maybe_uring::io::TlsInfo;
+
+use crate::{
+    Body, Encoder, ExpectResponseHeaders, Request, RequestLimits, Responder, Response,
+    ResponseDone, ServerDriver,
+};
+
+/// A host name [AllowedHosts] lets through. Parsed from `example.org`,
+/// which only matches itself, or `*.example.org`, which matches subdomains
+/// of `example.org` (at any depth) but not `example.org` itself.
+///
+/// Matching ignores case and a trailing dot. IP literals are written like
+/// they appear in URIs: `127.0.0.1`, `[::1]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+    Exact(String),
+    Subdomains(String),
+}
+
+impl HostPattern {
+    /// `host` must not have a port, see [AllowedHosts::allows] for that
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.strip_suffix('.').unwrap_or(host);
+        match self {
+            HostPattern::Exact(name) => host.eq_ignore_ascii_case(name),
+            HostPattern::Subdomains(parent) => {
+                // `.example.org` at the end, with something before it
+                let (host, parent) = (host.as_bytes(), parent.as_bytes());
+                host.len() > parent.len() + 1
+                    && host[host.len() - parent.len() - 1] == b'.'
+                    && host[host.len() - parent.len()..].eq_ignore_ascii_case(parent)
+            }
+        }
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostPattern::Exact(name) => f.write_str(name),
+            HostPattern::Subdomains(parent) => write!(f, "*.{parent}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("invalid host pattern, expected something like `example.org` or `*.example.org`")]
+pub struct InvalidHostPattern;
+
+impl FromStr for HostPattern {
+    type Err = InvalidHostPattern;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_suffix('.').unwrap_or(s).to_ascii_lowercase();
+        let (pattern, name) = match s.strip_prefix("*.") {
+            Some(parent) => (HostPattern::Subdomains(parent.to_owned()), parent),
+            None => (HostPattern::Exact(s.clone()), &s[..]),
+        };
+        // no ports, no more wildcards, nothing that can't be in a host
+        let valid = !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"-.[]:".contains(&b))
+            && (name.starts_with('[') || !name.contains(':'));
+        if valid {
+            Ok(pattern)
+        } else {
+            Err(InvalidHostPattern)
+        }
+    }
+}
+
+/// Which hosts requests may be addressed to, see [CheckHost]. The default
+/// allows none.
+#[derive(Debug, Clone, Default)]
+pub struct AllowedHosts {
+    pub patterns: Vec<HostPattern>,
+}
+
+impl AllowedHosts {
+    /// Whether `authority` (`host` or `host:port`, as found in `Host`,
+    /// `:authority` or an absolute request target) is allowed. The port
+    /// doesn't matter, authorities with user info never are.
+    pub fn allows(&self, authority: &str) -> bool {
+        match host_of(authority) {
+            Some(host) => self.patterns.iter().any(|p| p.matches(host)),
+            None => false,
+        }
+    }
+}
+
+/// The host part of an authority, `None` if it's malformed
+pub(crate) fn host_of(authority: &str) -> Option<&str> {
+    if authority.contains('@') {
+        return None;
+    }
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => {
+            let end = rest.find(']')?;
+            (&authority[..end + 2], &rest[end + 1..])
+        }
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, &authority[host.len()..]),
+            None => (authority, ""),
+        },
+    };
+    let port_ok = match port.strip_prefix(':') {
+        Some(port) => port.bytes().all(|b| b.is_ascii_digit()),
+        None => port.is_empty(),
+    };
+    (!host.is_empty() && port_ok).then_some(host)
+}
+
+/// Decides which authorities a connection may serve requests for, see
+/// `authority_policy` in [h2::ServerConf](crate::h2::ServerConf).
+///
+/// HTTP/2 clients reuse a connection for any host the server's certificate
+/// covers, cf. <https://httpwg.org/specs/rfc9113.html#rfc.section.9.1.1>,
+/// which isn't necessarily every host that can be served over it (think
+/// different client certificate requirements, or a different backend per
+/// SNI). Requests for authorities that are turned away get a 421
+/// Misdirected Request, so clients retry them on a connection of their own.
+///
+/// Closures taking the authority and the connection's [TlsInfo] (`None`
+/// for cleartext connections) work too.
+pub trait AuthorityPolicy {
+    /// `authority` is `host` or `host:port`, as sent by the client
+    fn accepts(&self, authority: &str, tls: Option<&TlsInfo>) -> bool;
+}
+
+impl<F> AuthorityPolicy for F
+where
+    F: Fn(&str, Option<&TlsInfo>) -> bool,
+{
+    fn accepts(&self, authority: &str, tls: Option<&TlsInfo>) -> bool {
+        self(authority, tls)
+    }
+}
+
+/// Accepts the hosts it allows, whatever the connection
+impl AuthorityPolicy for AllowedHosts {
+    fn accepts(&self, authority: &str, _tls: Option<&TlsInfo>) -> bool {
+        self.allows(authority)
+    }
+}
+
+/// An [AuthorityPolicy] that only accepts requests for the host the client
+/// asked for with SNI, i.e. no connection coalescing at all. Connections
+/// without SNI (including cleartext ones) accept any authority.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MatchSni;
+
+impl AuthorityPolicy for MatchSni {
+    fn accepts(&self, authority: &str, tls: Option<&TlsInfo>) -> bool {
+        let Some(server_name) = tls.and_then(|tls| tls.server_name.as_deref()) else {
+            return true;
+        };
+        match host_of(authority) {
+            Some(host) => {
+                let host = host.strip_suffix('.').unwrap_or(host);
+                host.eq_ignore_ascii_case(server_name)
+            }
+            None => false,
+        }
+    }
+}
+
+/// Wraps a [ServerDriver] so that it only sees requests for hosts in an
+/// allow-list. Others get a 421 Misdirected Request, cf.
+/// <https://httpwg.org/specs/rfc9110.html#status.421>, which tells h2
+/// clients that coalesced requests for several hosts onto one connection
+/// (because the certificate covered them all) to try a fresh connection
+/// instead. Requests without any host at all get a 400.
+///
+/// The host is taken from the request target when it has one (absolute-form
+/// targets in HTTP/1.1, `:authority` in HTTP/2), and from `Host` otherwise.
+/// When both are there, they must agree.
+pub struct CheckHost<D> {
+    pub inner: D,
+    pub conf: AllowedHosts,
+}
+
+impl<D> CheckHost<D> {
+    fn check(&self, req: &Request) -> Result<(), StatusCode> {
+        let host_header = req
+            .headers
+            .get(header::HOST)
+            .map(|host| std::str::from_utf8(host))
+            .transpose()
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        let authority = match (req.uri.authority(), host_header) {
+            (Some(authority), Some(host))
+                if !host_of(authority)
+                    .zip(host_of(host))
+                    .is_some_and(|(a, b)| a.eq_ignore_ascii_case(b)) =>
+            {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            (Some(authority), _) => authority,
+            (None, Some(host)) => host,
+            (None, None) => return Err(StatusCode::BAD_REQUEST),
+        };
+
+        if self.conf.allows(authority) {
+            Ok(())
+        } else {
+            Err(StatusCode::MISDIRECTED_REQUEST)
+        }
+    }
+}
+
+impl<D: ServerDriver> ServerDriver for CheckHost<D> {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        if let Err(status) = self.check(&req) {
+            debug!(
+                authority = ?req.uri.authority(),
+                host = ?req.headers.get(header::HOST),
+                %status,
+                "rejecting request for a host that's not allowed"
+            );
+            let res = Response {
+                status,
+                ..Default::default()
+            };
+            return respond.write_final_response_with_body(res, &mut ()).await;
+        }
+        self.inner.handle(req, req_body, respond).await
+    }
+
+    fn request_limits(&self, req: &Request, limits: &mut RequestLimits) {
+        self.inner.request_limits(req, limits)
+    }
+
+    fn error_response(&self, err: &eyre::Report) -> (Response, Piece) {
+        self.inner.error_response(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header, StatusCode};
+
+    use fluke_maybe_uring::io::TlsInfo;
+
+    use super::{AllowedHosts, AuthorityPolicy, CheckHost, HostPattern, MatchSni};
+    use crate::{Request, RequestUri};
+
+    #[test]
+    fn test_allowed_hosts() {
+        let conf = AllowedHosts {
+            patterns: vec![
+                "example.org".parse().unwrap(),
+                "*.Example.net.".parse().unwrap(),
+                "[::1]".parse().unwrap(),
+            ],
+        };
+        for (authority, allowed) in [
+            ("example.org", true),
+            ("EXAMPLE.org:8443", true),
+            ("example.org.", true),
+            ("www.example.org", false),
+            ("example.net", false),
+            ("a.b.example.net:80", true),
+            ("evilexample.net", false),
+            ("[::1]:8080", true),
+            ("[::2]", false),
+            ("user@example.org", false),
+            ("example.org:http", false),
+            ("", false),
+        ] {
+            assert_eq!(conf.allows(authority), allowed, "for {authority:?}");
+        }
+        assert!("*.".parse::<HostPattern>().is_err());
+        assert!("example.org:80".parse::<HostPattern>().is_err());
+        assert!("a.*.example.org".parse::<HostPattern>().is_err());
+
+        let check = CheckHost { inner: (), conf };
+        let req = |uri: &str, host: Option<&'static str>| {
+            let mut req = Request {
+                uri: uri.parse::<RequestUri>().unwrap(),
+                ..Default::default()
+            };
+            if let Some(host) = host {
+                req.headers.insert(header::HOST, host.into());
+            }
+            req
+        };
+        assert_eq!(check.check(&req("/", Some("example.org"))), Ok(()));
+        assert_eq!(
+            check.check(&req("/", Some("example.com"))),
+            Err(StatusCode::MISDIRECTED_REQUEST)
+        );
+        assert_eq!(check.check(&req("/", None)), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(
+            check.check(&req("http://example.org/", Some("example.org:80"))),
+            Ok(())
+        );
+        // absolute-form targets win over `Host`, so they'd better agree
+        assert_eq!(
+            check.check(&req("http://example.org/", Some("example.com"))),
+            Err(StatusCode::BAD_REQUEST)
+        );
+        assert_eq!(
+            check.check(&req("http://example.com/", None)),
+            Err(StatusCode::MISDIRECTED_REQUEST)
+        );
+    }
+
+    #[test]
+    fn test_authority_policy() {
+        let tls = TlsInfo {
+            server_name: Some("a.example.org".into()),
+            ..Default::default()
+        };
+        assert!(MatchSni.accepts("a.example.org:443", Some(&tls)));
+        assert!(MatchSni.accepts("A.Example.org.", Some(&tls)));
+        assert!(!MatchSni.accepts("b.example.org", Some(&tls)));
+        assert!(MatchSni.accepts("b.example.org", Some(&TlsInfo::default())));
+        assert!(MatchSni.accepts("b.example.org", None));
+
+        let covered = |authority: &str, tls: Option<&TlsInfo>| {
+            tls.is_some() && authority.ends_with(".example.org")
+        };
+        assert!(covered.accepts("b.example.org", Some(&tls)));
+        assert!(!covered.accepts("b.example.org", None));
+    }
+}
@@ -0,0 +1,173 @@
+//! Reading and writing JSON bodies with serde, behind the `json` feature.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+};
+
+use http::header;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Body, BodyChunk, Encoder, ExpectResponseHeaders, Responder, Response, ResponseDone};
+use fluke_buffet::{Piece, RollMut};
+
+#[derive(Debug, thiserror::Error)]
+pub enum JsonError {
+    /// Handlers will typically want to respond with a 413 Content Too Large
+    #[error("JSON body is larger than {max_len} bytes")]
+    TooLarge { max_len: u64 },
+
+    #[error("invalid JSON body: {0}")]
+    Invalid(#[from] serde_json::Error),
+}
+
+/// Reads a request (or response) body in full and deserializes it. Fails
+/// with [JsonError::TooLarge] as soon as the body is known to be larger than
+/// `max_len`, without reading the rest.
+///
+/// Chunks are kept as they were received and parsed from there: they're never
+/// copied into one contiguous buffer.
+pub async fn read_json<T: DeserializeOwned>(body: &mut impl Body, max_len: u64) -> eyre::Result<T> {
+    if matches!(body.content_len(), Some(len) if len > max_len) {
+        return Err(JsonError::TooLarge { max_len }.into());
+    }
+
+    let mut pieces = VecDeque::new();
+    let mut len = 0;
+    while let BodyChunk::Chunk(chunk) = body.next_chunk().await? {
+        len += chunk.len() as u64;
+        if len > max_len {
+            return Err(JsonError::TooLarge { max_len }.into());
+        }
+        pieces.push_back(chunk);
+    }
+
+    let reader = PiecesReader { pieces, pos: 0 };
+    Ok(serde_json::from_reader(reader).map_err(JsonError::from)?)
+}
+
+/// Sends `value` as the body of `res`, with chunked transfer encoding
+/// (or DATA frames over HTTP/2). Sets `content-type` unless `res` has one.
+///
+/// The value is serialized into pool buffers, which are sent as they are:
+/// large values are never laid out in one contiguous buffer.
+pub async fn write_json<E: Encoder>(
+    respond: Responder<E, ExpectResponseHeaders>,
+    mut res: Response,
+    value: &impl Serialize,
+) -> eyre::Result<Responder<E, ResponseDone>> {
+    let mut writer = PiecesWriter {
+        buf: RollMut::empty(),
+        pieces: vec![],
+    };
+    serde_json::to_writer(&mut writer, value).map_err(JsonError::from)?;
+    if !writer.buf.is_empty() {
+        writer.pieces.push(writer.buf.take_all().into());
+    }
+
+    res.headers
+        .entry(header::CONTENT_TYPE)
+        .or_insert_with(|| "application/json".into());
+    let mut respond = respond.write_final_response(res).await?;
+
+    let mut pieces = writer.pieces.into_iter();
+    let Some(mut last) = pieces.next() else {
+        return respond.finish_body(None).await;
+    };
+    for piece in pieces {
+        respond
+            .write_chunk(std::mem::replace(&mut last, piece))
+            .await?;
+    }
+    respond.write_last_chunk(last).await
+}
+
+/// Reads through a list of pieces as if they were contiguous
+struct PiecesReader {
+    pieces: VecDeque<Piece>,
+
+    /// How much of the front piece was read already
+    pos: usize,
+}
+
+impl Read for PiecesReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(front) = self.pieces.front() {
+            let rest = &front[self.pos..];
+            if rest.is_empty() {
+                self.pieces.pop_front();
+                self.pos = 0;
+                continue;
+            }
+
+            let n = std::cmp::min(rest.len(), buf.len());
+            buf[..n].copy_from_slice(&rest[..n]);
+            self.pos += n;
+            return Ok(n);
+        }
+        Ok(0)
+    }
+}
+
+/// Fills pool buffers one after the other
+struct PiecesWriter {
+    buf: RollMut,
+    pieces: Vec<Piece>,
+}
+
+impl Write for PiecesWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.cap() == 0 {
+            if !self.buf.is_empty() {
+                self.pieces.push(self.buf.take_all().into());
+            }
+            self.buf.reserve().map_err(io::Error::other)?;
+        }
+
+        let n = std::cmp::min(data.len(), self.buf.cap());
+        self.buf.put(&data[..n]).map_err(io::Error::other)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use fluke_buffet::{Piece, RollMut};
+
+    use super::{PiecesReader, PiecesWriter};
+
+    #[test]
+    fn test_json_pieces_roundtrip() {
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+
+        let mut writer = PiecesWriter {
+            buf: RollMut::empty(),
+            pieces: vec![],
+        };
+        writer.write_all(&data).unwrap();
+        writer.pieces.push(writer.buf.take_all().into());
+        assert!(writer.pieces.len() > 1);
+
+        let mut reader = PiecesReader {
+            pieces: writer.pieces.into(),
+            pos: 0,
+        };
+        let mut read_back = vec![];
+        reader.read_to_end(&mut read_back).unwrap();
+        assert_eq!(read_back, data);
+
+        let pieces: Vec<Piece> = vec![b"{\"a\":".as_slice().into(), b"[1,2]}".as_slice().into()];
+        let reader = PiecesReader {
+            pieces: pieces.into(),
+            pos: 0,
+        };
+        let v: serde_json::Value = serde_json::from_reader(reader).unwrap();
+        assert_eq!(v["a"][1], 2);
+    }
+}
@@ -0,0 +1,61 @@
+use std::time::{Duration, Instant};
+
+use tokio::task::JoinHandle;
+
+use crate::record_event_loop_lag;
+
+/// Spawns a task on the current thread that measures event-loop lag: it
+/// asks to be woken up every `interval`, and counts how late it actually is
+/// each time. Since all tasks on the thread are run in turn by the same event
+/// loop, that's about how long any of them waits to be polled.
+///
+/// Each sample is recorded for [Load::event_loop_lag](crate::Load::event_loop_lag),
+/// which is what a [LoadShedder](crate::LoadShedder) gets to see, then passed
+/// to `on_sample`, e.g. to feed a metrics histogram. Timers have a resolution
+/// of about a millisecond, so samples below that are noise.
+///
+/// The sampler runs until the returned handle is aborted, or the runtime
+/// shuts down.
+pub fn spawn_lag_sampler(
+    interval: Duration,
+    mut on_sample: impl FnMut(Duration) + 'static,
+) -> JoinHandle<()> {
+    fluke_maybe_uring::spawn(async move {
+        loop {
+            let before = Instant::now();
+            tokio::time::sleep(interval).await;
+            let lag = before.elapsed().saturating_sub(interval);
+
+            record_event_loop_lag(lag);
+            on_sample(lag);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc, time::Duration};
+
+    use super::spawn_lag_sampler;
+    use crate::Load;
+
+    #[test]
+    fn test_lag_sampler() {
+        crate::maybe_uring::start(async move {
+            let max_lag = Rc::new(Cell::new(Duration::ZERO));
+            let sampler = spawn_lag_sampler(Duration::from_millis(1), {
+                let max_lag = max_lag.clone();
+                move |lag| max_lag.set(max_lag.get().max(lag))
+            });
+
+            // let the sampler arm its timer, then hog the thread
+            tokio::task::yield_now().await;
+            std::thread::sleep(Duration::from_millis(50));
+            tokio::time::sleep(Duration::from_millis(5)).await;
+
+            assert!(max_lag.get() >= Duration::from_millis(40));
+            assert!(Load::current().event_loop_lag.is_some());
+            sampler.abort();
+        });
+    }
+}
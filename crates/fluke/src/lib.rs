@@ -1,14 +1,86 @@
+#[cfg(any(feature = "h1", feature = "h2"))]
 mod util;
+#[cfg(any(feature = "h1", feature = "h2"))]
+mod write_buf;
 
 mod types;
 pub use types::*;
 
+#[cfg(feature = "h1")]
 pub mod h1;
+#[cfg(feature = "h2")]
 pub mod h2;
 
 mod responder;
 pub use responder::*;
 
+mod copy;
+pub use copy::*;
+
+mod dyn_driver;
+pub use dyn_driver::*;
+
+mod overload;
+pub use overload::*;
+
+mod lag;
+pub use lag::*;
+
+mod stall;
+pub use stall::*;
+
+mod request_id;
+pub use request_id::*;
+
+mod forwarded;
+pub use forwarded::*;
+
+mod normalize;
+pub use normalize::*;
+
+mod host;
+pub use host::*;
+
+mod redirect;
+pub use redirect::*;
+
+mod security;
+pub use security::*;
+
+pub mod files;
+
+#[cfg(all(feature = "h1", feature = "h2"))]
+mod auto;
+#[cfg(all(feature = "h1", feature = "h2"))]
+pub use auto::*;
+
+#[cfg(all(feature = "h1", feature = "h2"))]
+mod registry;
+#[cfg(all(feature = "h1", feature = "h2"))]
+pub use registry::*;
+
+#[cfg(feature = "client")]
+pub mod proxy;
+
+#[cfg(feature = "client")]
+pub mod client;
+
+#[cfg(feature = "json")]
+pub mod json;
+
+#[cfg(feature = "admin")]
+pub mod admin;
+
+#[cfg(feature = "signatures")]
+pub mod signatures;
+
+#[cfg(feature = "sigv4")]
+pub mod sigv4;
+
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+
 pub use fluke_buffet as buffet;
 pub use fluke_maybe_uring as maybe_uring;
 
@@ -23,4 +95,43 @@ pub trait ServerDriver {
         req_body: &mut impl Body,
         respond: Responder<E, ExpectResponseHeaders>,
     ) -> eyre::Result<Responder<E, ResponseDone>>;
+
+    /// Called once the request head is read, before any limit is enforced
+    /// and before [ServerDriver::handle]: `limits` start out from the server
+    /// configuration, and can be adjusted for this request only.
+    fn request_limits(&self, _req: &Request, _limits: &mut RequestLimits) {}
+
+    /// The response sent in place of the one [ServerDriver::handle] failed
+    /// with `err` before starting, as a head and a body: an empty
+    /// `500 Internal Server Error` by default. Only used over HTTP/2, where
+    /// a handler that fails further along resets its stream with
+    /// `INTERNAL_ERROR`. Over HTTP/1.1, the connection is closed instead.
+    fn error_response(&self, _err: &eyre::Report) -> (Response, fluke_buffet::Piece) {
+        let res = Response {
+            status: http::StatusCode::INTERNAL_SERVER_ERROR,
+            ..Default::default()
+        };
+        (res, "".into())
+    }
+}
+
+/// So that a driver shared between connections (as h2 wants it) can
+/// be passed wherever a driver is taken by value
+impl<D: ServerDriver> ServerDriver for std::rc::Rc<D> {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        self.as_ref().handle(req, req_body, respond).await
+    }
+
+    fn request_limits(&self, req: &Request, limits: &mut RequestLimits) {
+        self.as_ref().request_limits(req, limits)
+    }
+
+    fn error_response(&self, err: &eyre::Report) -> (Response, fluke_buffet::Piece) {
+        self.as_ref().error_response(err)
+    }
 }
@@ -0,0 +1,304 @@
+use std::borrow::Cow;
+
+use http::{header, StatusCode};
+
+use fluke_buffet::{Piece, PieceStr};
+
+use crate::{
+    Body, Encoder, ExpectResponseHeaders, Request, RequestLimits, RequestUri, Responder, Response,
+    ResponseDone, ServerDriver,
+};
+
+/// How [NormalizePath] puts request paths in canonical form. Only paths
+/// starting with `/` are touched: `*` (for `OPTIONS`) and authority-form
+/// targets (for `CONNECT`) go through as received.
+#[derive(Debug, Clone, Copy)]
+pub struct PathNormalization {
+    /// What to do about percent-encoded bytes. This happens first, so with
+    /// [PercentDecoding::Unreserved], `%2e%2e` counts as a `..` segment.
+    pub percent_decoding: PercentDecoding,
+
+    /// Resolve `.` and `..` segments, cf. <https://www.rfc-editor.org/rfc/rfc3986#section-5.2.4>.
+    /// `..` never goes above the root.
+    pub remove_dot_segments: bool,
+
+    /// Collapse runs of `/` into a single one
+    pub merge_slashes: bool,
+
+    pub trailing_slash: TrailingSlash,
+
+    /// Answer requests whose path isn't canonical with a 308 Permanent
+    /// Redirect to the canonical one, rather than handing them to the driver
+    /// with the path rewritten. The query is kept either way.
+    pub redirect: bool,
+}
+
+impl Default for PathNormalization {
+    fn default() -> Self {
+        Self {
+            percent_decoding: Default::default(),
+            remove_dot_segments: true,
+            merge_slashes: true,
+            trailing_slash: Default::default(),
+            redirect: false,
+        }
+    }
+}
+
+/// See [PathNormalization::percent_decoding]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PercentDecoding {
+    /// Leave percent-encoded bytes as received
+    Keep,
+
+    /// Decode unreserved characters (letters, digits, `-`, `.`, `_` and `~`),
+    /// which mean the same encoded or not, and uppercase the hex digits of
+    /// everything else, cf. <https://www.rfc-editor.org/rfc/rfc3986#section-6.2.2>.
+    ///
+    /// Reserved characters, `%2F` in particular, stay encoded: decoding them
+    /// would change what the path means, and let `%2F..%2F` through as a
+    /// `..` segment.
+    #[default]
+    Unreserved,
+}
+
+/// See [PathNormalization::trailing_slash]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrailingSlash {
+    #[default]
+    Keep,
+
+    /// `/foo/` becomes `/foo`
+    Remove,
+
+    /// `/foo` becomes `/foo/`, for applications whose routes all end with a
+    /// slash
+    Add,
+}
+
+impl PathNormalization {
+    /// Returns the canonical form of `path`, which borrows from `path` if
+    /// it's already canonical.
+    pub fn normalize<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        if !path.starts_with('/') {
+            return Cow::Borrowed(path);
+        }
+
+        let decoded = match self.percent_decoding {
+            PercentDecoding::Keep => Cow::Borrowed(path),
+            PercentDecoding::Unreserved => normalize_percent_encoding(path),
+        };
+
+        // an empty last segment stands for a trailing slash
+        let mut segments: Vec<&str> = vec![];
+        let mut iter = decoded[1..].split('/').peekable();
+        while let Some(segment) = iter.next() {
+            let last = iter.peek().is_none();
+            match segment {
+                "." if self.remove_dot_segments => {}
+                ".." if self.remove_dot_segments => {
+                    segments.pop();
+                }
+                "" if self.merge_slashes && !last => continue,
+                _ => {
+                    segments.push(segment);
+                    continue;
+                }
+            }
+            if last {
+                // `/a/.` and `/a/b/..` both resolve to `/a/`
+                segments.push("");
+            }
+        }
+
+        match self.trailing_slash {
+            TrailingSlash::Keep => {}
+            TrailingSlash::Remove => {
+                if segments.len() > 1 && segments.last() == Some(&"") {
+                    segments.pop();
+                }
+            }
+            TrailingSlash::Add => {
+                if segments.last() != Some(&"") {
+                    segments.push("");
+                }
+            }
+        }
+
+        let mut normalized = String::with_capacity(path.len());
+        for segment in &segments {
+            normalized.push('/');
+            normalized.push_str(segment);
+        }
+
+        if normalized == path {
+            Cow::Borrowed(path)
+        } else {
+            Cow::Owned(normalized)
+        }
+    }
+
+    /// `uri` with a canonical path, if it didn't have one
+    fn normalize_uri(&self, uri: &RequestUri) -> Option<RequestUri> {
+        let path = match self.normalize(uri.path()) {
+            Cow::Borrowed(_) => return None,
+            Cow::Owned(path) => path,
+        };
+
+        let mut path_and_query = path;
+        if let Some(query) = uri.query() {
+            path_and_query.push('?');
+            path_and_query.push_str(query);
+        }
+        Some(RequestUri::new(
+            uri.scheme().cloned(),
+            uri.authority().map(|a| PieceStr::from(a.to_owned())),
+            path_and_query.into(),
+        ))
+    }
+}
+
+fn normalize_percent_encoding(path: &str) -> Cow<'_, str> {
+    let bytes = path.as_bytes();
+    if !bytes.contains(&b'%') {
+        return Cow::Borrowed(path);
+    }
+
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3);
+        match (bytes[i], hex) {
+            (b'%', Some(&[hi, lo])) if hi.is_ascii_hexdigit() && lo.is_ascii_hexdigit() => {
+                let decoded = (hex_value(hi) << 4) | hex_value(lo);
+                if decoded.is_ascii_alphanumeric() || b"-._~".contains(&decoded) {
+                    out.push(decoded);
+                } else {
+                    out.extend([b'%', hi.to_ascii_uppercase(), lo.to_ascii_uppercase()]);
+                }
+                i += 3;
+            }
+            // malformed escapes are left alone
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    // only ASCII was decoded, so this is still valid UTF-8
+    Cow::Owned(String::from_utf8(out).expect("decoding unreserved characters kept UTF-8"))
+}
+
+fn hex_value(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        _ => digit - b'A' + 10,
+    }
+}
+
+/// Wraps a [ServerDriver] so that it only ever sees canonical request
+/// paths, see [PathNormalization].
+///
+/// Rules on paths (routing, access control, limits) are easy to get around
+/// when `/admin`, `//admin`, `/public/../admin` and `/%61dmin` are all told
+/// apart: normalizing before the driver runs means they only need to match
+/// one form.
+pub struct NormalizePath<D> {
+    pub inner: D,
+    pub conf: PathNormalization,
+}
+
+impl<D: ServerDriver> ServerDriver for NormalizePath<D> {
+    async fn handle<E: Encoder>(
+        &self,
+        mut req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        let Some(uri) = self.conf.normalize_uri(&req.uri) else {
+            return self.inner.handle(req, req_body, respond).await;
+        };
+
+        if self.conf.redirect {
+            let location = uri.path_and_query();
+            // a path starting with `//` would be taken for a host by the
+            // client (`//evil.example/`): `/.` in front keeps it a path.
+            let location = if location.starts_with("//") {
+                format!("/.{location}")
+            } else {
+                location.to_owned()
+            };
+
+            let mut res = Response {
+                status: StatusCode::PERMANENT_REDIRECT,
+                ..Default::default()
+            };
+            res.headers
+                .insert(header::LOCATION, PieceStr::from(location).into_inner());
+            return respond.write_final_response_with_body(res, &mut ()).await;
+        }
+
+        req.uri = uri;
+        self.inner.handle(req, req_body, respond).await
+    }
+
+    fn request_limits(&self, req: &Request, limits: &mut RequestLimits) {
+        match self.conf.normalize_uri(&req.uri) {
+            // the driver gets to pick limits for the path it'll see
+            Some(uri) if !self.conf.redirect => {
+                let req = Request { uri, ..req.clone() };
+                self.inner.request_limits(&req, limits)
+            }
+            _ => self.inner.request_limits(req, limits),
+        }
+    }
+
+    fn error_response(&self, err: &eyre::Report) -> (Response, Piece) {
+        self.inner.error_response(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PathNormalization, PercentDecoding, TrailingSlash};
+
+    #[test]
+    fn test_normalize_path() {
+        let conf = PathNormalization::default();
+        for (path, expected) in [
+            ("/", "/"),
+            ("/a/b", "/a/b"),
+            ("/a//b///c", "/a/b/c"),
+            ("/a/./b/../c", "/a/c"),
+            ("/a/b/..", "/a/"),
+            ("/a/.", "/a/"),
+            ("/../../etc/passwd", "/etc/passwd"),
+            ("/%2e%2E/etc", "/etc"),
+            ("/%61dmin/%7euser", "/admin/~user"),
+            ("/a%2fb%2F..", "/a%2Fb%2F.."),
+            ("/100%", "/100%"),
+            ("*", "*"),
+        ] {
+            assert_eq!(conf.normalize(path), expected, "normalizing {path}");
+        }
+
+        let conf = PathNormalization {
+            percent_decoding: PercentDecoding::Keep,
+            merge_slashes: false,
+            trailing_slash: TrailingSlash::Remove,
+            ..Default::default()
+        };
+        assert_eq!(conf.normalize("/a//b/"), "/a//b");
+        assert_eq!(conf.normalize("/%61/"), "/%61");
+        assert_eq!(conf.normalize("/"), "/");
+
+        let conf = PathNormalization {
+            trailing_slash: TrailingSlash::Add,
+            ..Default::default()
+        };
+        assert_eq!(conf.normalize("/a"), "/a/");
+        assert_eq!(conf.normalize("/"), "/");
+    }
+}
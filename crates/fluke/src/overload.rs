@@ -0,0 +1,151 @@
+use std::{cell::Cell, time::Duration};
+
+thread_local! {
+    static ACTIVE_HANDLERS: Cell<usize> = const { Cell::new(0) };
+    static EVENT_LOOP_LAG: Cell<Option<Duration>> = const { Cell::new(None) };
+}
+
+/// Decides whether the current thread is too busy to take on more work.
+/// Consulted before a request (h1) or a stream (h2) is accepted, see
+/// `load_shedder` in [h1::ServerConf](crate::h1::ServerConf) and
+/// [h2::ServerConf](crate::h2::ServerConf).
+pub trait LoadShedder {
+    /// Returns `Some(retry_after)` to turn the request away. Over HTTP/1.1,
+    /// it gets a 503 Service Unavailable response with a `retry-after` header
+    /// (rounded up to the second). Over HTTP/2, the stream is reset with
+    /// `REFUSED_STREAM`, which tells the client it's safe to retry, but can't
+    /// carry a delay.
+    fn shed(&self, load: &Load) -> Option<Duration>;
+}
+
+/// How busy the current thread is
+#[derive(Debug, Clone, Copy)]
+pub struct Load {
+    /// How many driver handlers are running on this thread, across all
+    /// connections and both protocols
+    pub active_handlers: usize,
+
+    /// Last event-loop lag reported with [record_event_loop_lag], if any
+    pub event_loop_lag: Option<Duration>,
+
+    /// Share of this thread's buffer pool that's in use, from 0.0 to 1.0
+    pub buf_pool_usage: f32,
+}
+
+impl Load {
+    /// Takes a snapshot of the current thread's load
+    pub fn current() -> Self {
+        let num_free = fluke_buffet::num_free_bufs();
+        let num_buf = fluke_buffet::NUM_BUF as usize;
+        Self {
+            active_handlers: ACTIVE_HANDLERS.with(|c| c.get()),
+            event_loop_lag: EVENT_LOOP_LAG.with(|c| c.get()),
+            buf_pool_usage: (num_buf - num_free) as f32 / num_buf as f32,
+        }
+    }
+}
+
+/// Lets [Load::event_loop_lag] reflect how late the current thread's event
+/// loop is running tasks. [spawn_lag_sampler](crate::spawn_lag_sampler) calls
+/// this, but lag measured some other way can be reported here too.
+pub fn record_event_loop_lag(lag: Duration) {
+    EVENT_LOOP_LAG.with(|c| c.set(Some(lag)));
+}
+
+/// A [LoadShedder] that turns requests away as soon as any of its
+/// thresholds is exceeded. Thresholds left to `None` are ignored.
+#[derive(Debug, Clone, Copy)]
+pub struct LoadThresholds {
+    pub max_active_handlers: Option<usize>,
+    pub max_event_loop_lag: Option<Duration>,
+    pub max_buf_pool_usage: Option<f32>,
+
+    /// What clients are told to wait before retrying
+    pub retry_after: Duration,
+}
+
+impl Default for LoadThresholds {
+    fn default() -> Self {
+        Self {
+            max_active_handlers: None,
+            max_event_loop_lag: None,
+            max_buf_pool_usage: Some(0.9),
+            retry_after: Duration::from_secs(1),
+        }
+    }
+}
+
+impl LoadShedder for LoadThresholds {
+    fn shed(&self, load: &Load) -> Option<Duration> {
+        let over = matches!(self.max_active_handlers, Some(max) if load.active_handlers > max)
+            || matches!(
+                (self.max_event_loop_lag, load.event_loop_lag),
+                (Some(max), Some(lag)) if lag > max
+            )
+            || matches!(self.max_buf_pool_usage, Some(max) if load.buf_pool_usage > max);
+        over.then_some(self.retry_after)
+    }
+}
+
+/// Counts as an active handler in [Load] for as long as it's alive
+#[cfg(any(feature = "h1", feature = "h2"))]
+pub(crate) struct ActiveHandler(());
+
+#[cfg(any(feature = "h1", feature = "h2"))]
+impl ActiveHandler {
+    pub(crate) fn enter() -> Self {
+        ACTIVE_HANDLERS.with(|c| c.set(c.get() + 1));
+        Self(())
+    }
+}
+
+#[cfg(any(feature = "h1", feature = "h2"))]
+impl Drop for ActiveHandler {
+    fn drop(&mut self) {
+        ACTIVE_HANDLERS.with(|c| c.set(c.get() - 1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Load, LoadShedder, LoadThresholds};
+
+    #[test]
+    fn test_load_thresholds() {
+        let thresholds = LoadThresholds {
+            max_active_handlers: Some(10),
+            max_event_loop_lag: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let idle = Load {
+            active_handlers: 0,
+            event_loop_lag: None,
+            buf_pool_usage: 0.0,
+        };
+        assert_eq!(thresholds.shed(&idle), None);
+
+        let busy = [
+            Load {
+                active_handlers: 11,
+                ..idle
+            },
+            Load {
+                event_loop_lag: Some(Duration::from_millis(51)),
+                ..idle
+            },
+            Load {
+                buf_pool_usage: 0.95,
+                ..idle
+            },
+        ];
+        for load in busy {
+            assert_eq!(
+                thresholds.shed(&load),
+                Some(thresholds.retry_after),
+                "{load:?}"
+            );
+        }
+    }
+}
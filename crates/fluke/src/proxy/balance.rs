@@ -0,0 +1,75 @@
+use http::HeaderName;
+
+use crate::Request;
+
+/// How [UpstreamPool::pick](super::UpstreamPool::pick) chooses among the
+/// endpoints in rotation. It's passed with each pick, so that different
+/// routes can share a pool and still balance differently.
+#[derive(Debug, Clone, Default)]
+pub enum Balancer {
+    /// Each endpoint in turn, ignoring weights
+    #[default]
+    RoundRobin,
+
+    /// Each endpoint in turn, as many times as its weight, interleaved
+    /// (smooth weighted round-robin, like nginx does): weights of 3 and 1
+    /// give `a a b a`. Useful to roll out a new version gradually.
+    WeightedRoundRobin,
+
+    /// Requests with the same key go to the same endpoint, as long as it's
+    /// in rotation, which is good for cache affinity. When an endpoint
+    /// comes or goes, only its share of keys moves. Requests without a key
+    /// are balanced round-robin.
+    RingHash(HashKey),
+}
+
+/// What [Balancer::RingHash] hashes
+#[derive(Debug, Clone)]
+pub enum HashKey {
+    /// The first value of that header
+    Header(HeaderName),
+
+    /// The value of the cookie with that name
+    Cookie(String),
+
+    /// The IP address of the client, see [Request::client_addr]
+    ClientIp,
+}
+
+impl HashKey {
+    pub(crate) fn extract(&self, req: &Request) -> Option<Vec<u8>> {
+        match self {
+            HashKey::Header(name) => req.headers.get(name).map(|value| value.to_vec()),
+            HashKey::Cookie(name) => req
+                .headers
+                .get_all(http::header::COOKIE)
+                .iter()
+                .filter_map(|value| std::str::from_utf8(value).ok())
+                .flat_map(|value| value.split(';'))
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_bytes().to_vec()),
+            HashKey::ClientIp => req.client_addr.map(|ip| ip.to_string().into_bytes()),
+        }
+    }
+}
+
+/// Points each endpoint gets on the ring, per unit of weight: enough for
+/// keys to be spread evenly
+pub(crate) const RING_POINTS_PER_WEIGHT: u32 = 100;
+
+/// FNV-1a, then mixed so that similar inputs land far apart on the ring.
+/// Unlike std's hasher, it's stable across builds, so that gateways running
+/// different versions agree on where keys go.
+pub(crate) fn ring_hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &b in data {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    // splitmix64's finalizer
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
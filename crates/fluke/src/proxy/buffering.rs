@@ -0,0 +1,200 @@
+use std::{collections::VecDeque, fmt};
+
+use fluke_buffet::Piece;
+use http::StatusCode;
+use tracing::{debug, warn};
+
+use crate::{
+    Body, BodyChunk, Encoder, ExpectResponseHeaders, Headers, Responder, Response, ResponseDone,
+};
+
+/// How much of an upstream response body is read before its head is sent
+/// back, see [ProxyHooks::response_buffering](super::ProxyHooks::response_buffering)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BodyBuffering {
+    /// Chunks are sent back as soon as they're read: nothing is buffered
+    /// past what's in flight, which suits large and streaming bodies.
+    #[default]
+    Stream,
+
+    /// Bodies of up to that many bytes are read in full before anything is
+    /// sent back, so that:
+    ///
+    ///   * the response gets an accurate `content-length`, even when the
+    ///     upstream didn't send one, or when the body was rewritten (e.g.
+    ///     compressed) on the way
+    ///   * an upstream that fails halfway through gets a `502 Bad Gateway`
+    ///     rather than a truncated response, which clients can safely retry
+    ///
+    /// Longer bodies are streamed once that much was read.
+    UpTo(usize),
+}
+
+/// Sends `res` back with `body`, buffered as `buffering` says
+pub(crate) async fn respond_buffered<E: Encoder>(
+    respond: Responder<E, ExpectResponseHeaders>,
+    res: Response,
+    body: &mut impl Body,
+    buffering: BodyBuffering,
+) -> eyre::Result<Responder<E, ResponseDone>> {
+    let max_len = match buffering {
+        BodyBuffering::UpTo(max_len) => max_len,
+        BodyBuffering::Stream => {
+            return respond.write_final_response_with_body(res, body).await;
+        }
+    };
+
+    let mut body = match Prebuffered::read(body, max_len).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!(
+                ?e,
+                "upstream response body failed before anything was sent back"
+            );
+            let res = Response {
+                status: StatusCode::BAD_GATEWAY,
+                ..Default::default()
+            };
+            return respond.write_final_response_with_body(res, &mut ()).await;
+        }
+    };
+    respond.write_final_response_with_body(res, &mut body).await
+}
+
+/// A body whose first chunks were read ahead of time, and maybe all of it
+struct Prebuffered<'a, B: Body> {
+    chunks: VecDeque<Piece>,
+    len: u64,
+
+    /// What's left to read, `None` if the whole body was buffered
+    rest: Option<&'a mut B>,
+    trailers: Option<Box<Headers>>,
+}
+
+impl<B: Body> fmt::Debug for Prebuffered<'_, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Prebuffered")
+            .field("buffered", &self.chunks.len())
+            .field("len", &self.len)
+            .field("rest", &self.rest)
+            .finish()
+    }
+}
+
+impl<'a, B: Body> Prebuffered<'a, B> {
+    /// Reads `body` until its end, or until more than `max_len` bytes were
+    /// read. Bodies announced as longer than that aren't read at all.
+    async fn read(body: &'a mut B, max_len: usize) -> eyre::Result<Self> {
+        let mut this = Self {
+            chunks: Default::default(),
+            len: 0,
+            rest: None,
+            trailers: None,
+        };
+        if matches!(body.content_len(), Some(len) if len > max_len as u64) {
+            debug!(%max_len, "response body announced as too large to buffer, streaming it");
+            this.rest = Some(body);
+            return Ok(this);
+        }
+
+        while this.len <= max_len as u64 {
+            match body.next_chunk().await? {
+                BodyChunk::Chunk(chunk) => {
+                    this.len += chunk.len() as u64;
+                    this.chunks.push_back(chunk);
+                }
+                BodyChunk::Done { trailers } => {
+                    this.trailers = trailers;
+                    return Ok(this);
+                }
+            }
+        }
+
+        debug!(%max_len, "response body too large to buffer, streaming the rest");
+        this.rest = Some(body);
+        Ok(this)
+    }
+}
+
+impl<B: Body> Body for Prebuffered<'_, B> {
+    fn content_len(&self) -> Option<u64> {
+        match &self.rest {
+            Some(rest) => rest.content_len(),
+            None => Some(self.len),
+        }
+    }
+
+    fn eof(&self) -> bool {
+        self.chunks.is_empty() && self.rest.as_ref().map_or(true, |rest| rest.eof())
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        if let Some(chunk) = self.chunks.pop_front() {
+            return Ok(BodyChunk::Chunk(chunk));
+        }
+        match &mut self.rest {
+            Some(rest) => rest.next_chunk().await,
+            None => Ok(BodyChunk::Done {
+                trailers: self.trailers.take(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use fluke_buffet::Piece;
+
+    use super::Prebuffered;
+    use crate::{Body, BodyChunk};
+
+    /// A body of unknown length
+    #[derive(Debug)]
+    struct Chunks(VecDeque<&'static str>);
+
+    impl Body for Chunks {
+        fn content_len(&self) -> Option<u64> {
+            None
+        }
+
+        fn eof(&self) -> bool {
+            self.0.is_empty()
+        }
+
+        async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+            Ok(match self.0.pop_front() {
+                Some(chunk) => BodyChunk::Chunk(Piece::from(chunk)),
+                None => BodyChunk::Done { trailers: None },
+            })
+        }
+    }
+
+    async fn collect(body: &mut Prebuffered<'_, Chunks>) -> Vec<u8> {
+        let mut out = vec![];
+        while let BodyChunk::Chunk(chunk) = body.next_chunk().await.unwrap() {
+            out.extend_from_slice(&chunk[..]);
+        }
+        out
+    }
+
+    #[test]
+    fn test_prebuffered_body() {
+        crate::maybe_uring::start(async move {
+            // small enough: the length becomes known
+            let mut inner = Chunks(["ab", "cd"].into());
+            let mut body = Prebuffered::read(&mut inner, 4).await.unwrap();
+            assert_eq!(body.content_len(), Some(4));
+            assert!(body.rest.is_none());
+            assert_eq!(collect(&mut body).await, b"abcd");
+
+            // too large: what was read comes first, then the rest
+            let mut inner = Chunks(["ab", "cd", "ef", "gh"].into());
+            let mut body = Prebuffered::read(&mut inner, 3).await.unwrap();
+            assert_eq!(body.content_len(), None);
+            assert_eq!(body.chunks.len(), 2);
+            assert_eq!(collect(&mut body).await, b"abcdefgh");
+        });
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    rc::{Rc, Weak},
+    time::Duration,
+};
+
+use futures_util::future::LocalBoxFuture;
+use tracing::{debug, warn};
+
+use super::UpstreamPool;
+
+/// What a [Resolver] found for a hostname
+#[derive(Debug, Clone, Default)]
+pub struct Resolution {
+    pub addrs: Vec<SocketAddr>,
+
+    /// How long the addresses may be cached for, if the resolver knows.
+    /// Otherwise, [DiscoveryConf::refresh_interval] is used.
+    pub ttl: Option<Duration>,
+}
+
+/// Resolves hostnames for [discover]. The default is [SystemResolver]:
+/// implement this to use a DNS client that knows about TTLs, or some other
+/// service registry.
+pub trait Resolver {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> LocalBoxFuture<'a, io::Result<Resolution>>;
+}
+
+/// Resolves hostnames with the system's resolver (`getaddrinfo`), on a
+/// thread of its own since it blocks. It doesn't know about TTLs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> LocalBoxFuture<'a, io::Result<Resolution>> {
+        let host = host.to_owned();
+        Box::pin(async move {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            std::thread::Builder::new()
+                .name("fluke-resolver".into())
+                .spawn(move || {
+                    let addrs = (host.as_str(), port)
+                        .to_socket_addrs()
+                        .map(|addrs| addrs.collect());
+                    _ = tx.send(addrs);
+                })?;
+            let addrs = rx
+                .await
+                .map_err(|_| io::Error::other("resolver thread panicked"))??;
+            Ok(Resolution { addrs, ttl: None })
+        })
+    }
+}
+
+/// Where [discover] gets the endpoints of an [UpstreamPool] from, and how
+/// often
+#[derive(Debug, Clone)]
+pub struct DiscoveryConf {
+    /// Resolved to as many endpoints as it has addresses, like a headless
+    /// service
+    pub host: String,
+    pub port: u16,
+
+    /// How often to re-resolve `host` when the resolver doesn't say
+    pub refresh_interval: Duration,
+
+    /// TTLs are clamped to `min_refresh..=max_refresh`, so that a TTL of 0
+    /// doesn't have us re-resolving in a loop
+    pub min_refresh: Duration,
+
+    /// See `min_refresh`
+    pub max_refresh: Duration,
+
+    /// How soon to try again when resolving fails, or finds no addresses
+    pub retry_interval: Duration,
+}
+
+impl DiscoveryConf {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            refresh_interval: Duration::from_secs(30),
+            min_refresh: Duration::from_secs(1),
+            max_refresh: Duration::from_secs(300),
+            retry_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Keeps the endpoints of `pool` in sync with the addresses `conf.host`
+/// resolves to, re-resolving it as often as its TTL says. Endpoints whose
+/// address went away are drained (see [UpstreamPool::draining]), the others
+/// keep their circuit breaker.
+///
+/// Resolution failures keep the current endpoints, and so do empty results:
+/// a flaky DNS server shouldn't empty the pool.
+///
+/// Meant to be spawned: it runs until the pool is dropped.
+pub async fn discover(pool: Weak<UpstreamPool>, resolver: Rc<dyn Resolver>, conf: DiscoveryConf) {
+    loop {
+        let Some(upgraded) = pool.upgrade() else {
+            debug!(host = %conf.host, "upstream pool is gone, stopping discovery");
+            return;
+        };
+        let delay = refresh(&upgraded, resolver.as_ref(), &conf).await;
+        drop(upgraded);
+
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Resolves `conf.host` once and updates `pool`, returns how long until the
+/// next refresh
+async fn refresh(pool: &UpstreamPool, resolver: &dyn Resolver, conf: &DiscoveryConf) -> Duration {
+    match resolver.resolve(&conf.host, conf.port).await {
+        Ok(res) if !res.addrs.is_empty() => {
+            debug!(host = %conf.host, addrs = ?res.addrs, ttl = ?res.ttl, "resolved upstream");
+            pool.set_addrs(res.addrs.into_iter().map(|addr| (addr, 1)));
+            res.ttl
+                .unwrap_or(conf.refresh_interval)
+                .clamp(conf.min_refresh, conf.max_refresh)
+        }
+        Ok(_) => {
+            warn!(host = %conf.host, "upstream resolved to no addresses, keeping the current ones");
+            conf.retry_interval
+        }
+        Err(e) => {
+            warn!(host = %conf.host, "could not resolve upstream, keeping the current ones: {e}");
+            conf.retry_interval
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::VecDeque, io, net::SocketAddr, time::Duration};
+
+    use futures_util::future::LocalBoxFuture;
+
+    use super::{refresh, DiscoveryConf, Resolution, Resolver};
+    use crate::{
+        proxy::{Balancer, OutlierConf, UpstreamPool},
+        Request,
+    };
+
+    struct Scripted(RefCell<VecDeque<io::Result<Resolution>>>);
+
+    impl Resolver for Scripted {
+        fn resolve<'a>(
+            &'a self,
+            _host: &'a str,
+            _port: u16,
+        ) -> LocalBoxFuture<'a, io::Result<Resolution>> {
+            let res = self.0.borrow_mut().pop_front().unwrap();
+            Box::pin(async move { res })
+        }
+    }
+
+    #[test]
+    fn test_discovery_refresh() {
+        let addr = |port: u16| -> SocketAddr { ([10, 0, 0, 1], port).into() };
+        let found = |ports: &[u16], ttl: Option<u64>| {
+            Ok(Resolution {
+                addrs: ports.iter().map(|&port| addr(port)).collect(),
+                ttl: ttl.map(Duration::from_secs),
+            })
+        };
+        let resolver = Scripted(RefCell::new(VecDeque::from([
+            found(&[1, 2], Some(0)),
+            found(&[2, 3], None),
+            Err(io::ErrorKind::TimedOut.into()),
+            found(&[], Some(60)),
+            found(&[1, 2, 3], Some(3600)),
+        ])));
+        let conf = DiscoveryConf::new("upstream.internal", 80);
+        let pool = UpstreamPool::new([], OutlierConf::default());
+        let ports = |pool: &UpstreamPool| -> Vec<u16> {
+            pool.endpoints().iter().map(|e| e.addr.port()).collect()
+        };
+
+        crate::maybe_uring::start(async move {
+            assert_eq!(refresh(&pool, &resolver, &conf).await, conf.min_refresh);
+            assert_eq!(ports(&pool), [1, 2]);
+
+            // a request is in flight to 1 when it goes away
+            let req = Request::default();
+            let one = pool.pick(&Balancer::RoundRobin, &req).unwrap();
+            assert_eq!(one.addr, addr(1));
+            let two = pool.endpoints()[1].clone();
+
+            assert_eq!(
+                refresh(&pool, &resolver, &conf).await,
+                conf.refresh_interval
+            );
+            assert_eq!(ports(&pool), [2, 3]);
+            assert!(std::rc::Rc::ptr_eq(&pool.endpoints()[0], &two));
+            assert_eq!(pool.draining()[0].addr, addr(1));
+            for _ in 0..4 {
+                assert_ne!(
+                    pool.pick(&Balancer::RoundRobin, &req).unwrap().addr,
+                    addr(1)
+                );
+            }
+
+            // failures and empty results change nothing
+            for _ in 0..2 {
+                assert_eq!(refresh(&pool, &resolver, &conf).await, conf.retry_interval);
+                assert_eq!(ports(&pool), [2, 3]);
+            }
+
+            // once it's back, it's not draining anymore
+            assert_eq!(refresh(&pool, &resolver, &conf).await, conf.max_refresh);
+            assert_eq!(ports(&pool), [1, 2, 3]);
+            assert!(std::rc::Rc::ptr_eq(&pool.endpoints()[0], &one));
+            assert!(pool.draining().is_empty());
+        });
+    }
+}
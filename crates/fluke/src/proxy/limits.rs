@@ -0,0 +1,198 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::{Body, BodyChunk};
+
+/// How much an upstream response body may weigh, and how long it may take,
+/// see [ProxyHooks::response_limits](super::ProxyHooks::response_limits).
+/// `None` means no limit, which is the default for both.
+///
+/// Unlike [ProxyTimeouts](super::ProxyTimeouts), which are about an upstream
+/// that stalls, these are about one that keeps going: a body that never ends,
+/// or that's trickled out forever.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResponseLimits {
+    /// How many bytes of body the upstream may send
+    pub max_body_len: Option<u64>,
+
+    /// How long reading the body may take, from the response head coming in
+    pub max_body_duration: Option<Duration>,
+}
+
+/// Which of the [ResponseLimits] an upstream response went over. If its
+/// head wasn't sent back yet, the client gets a `502 Bad Gateway`,
+/// otherwise the response is cut short with this as the error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ResponseLimitExceeded {
+    #[error("upstream response body is larger than {limit} bytes")]
+    BodyLen { limit: u64 },
+
+    #[error("upstream response body took longer than {limit:?} to read")]
+    BodyDuration { limit: Duration },
+}
+
+impl ResponseLimits {
+    /// Errors out if a body announced as `content_len` long is over the limit
+    /// already, so that it's not read at all
+    pub(crate) fn check_announced(
+        &self,
+        content_len: Option<u64>,
+    ) -> Result<(), ResponseLimitExceeded> {
+        match (content_len, self.max_body_len) {
+            (Some(len), Some(limit)) if len > limit => {
+                Err(ResponseLimitExceeded::BodyLen { limit })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Errors out with [ResponseLimitExceeded] once the body it reads goes over
+/// its [ResponseLimits]. The chunk that goes over isn't passed along.
+#[derive(Debug)]
+pub(crate) struct LimitedBody<'a, B: Body> {
+    inner: &'a mut B,
+    limits: ResponseLimits,
+    read: u64,
+
+    /// When reading has to be done by, with the limit it comes from
+    deadline: Option<(Instant, Duration)>,
+}
+
+impl<'a, B: Body> LimitedBody<'a, B> {
+    /// The duration limit counts from now
+    pub(crate) fn new(inner: &'a mut B, limits: ResponseLimits) -> Self {
+        Self {
+            inner,
+            limits,
+            read: 0,
+            deadline: limits
+                .max_body_duration
+                .map(|limit| (Instant::now() + limit, limit)),
+        }
+    }
+}
+
+impl<B: Body> Body for LimitedBody<'_, B> {
+    fn content_len(&self) -> Option<u64> {
+        self.inner.content_len()
+    }
+
+    fn eof(&self) -> bool {
+        self.inner.eof()
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        let chunk = match self.deadline {
+            Some((deadline, limit)) => {
+                match tokio::time::timeout_at(deadline, self.inner.next_chunk()).await {
+                    Ok(chunk) => chunk?,
+                    Err(_) => return Err(ResponseLimitExceeded::BodyDuration { limit }.into()),
+                }
+            }
+            None => self.inner.next_chunk().await?,
+        };
+
+        if let (BodyChunk::Chunk(chunk), Some(limit)) = (&chunk, self.limits.max_body_len) {
+            self.read += chunk.len() as u64;
+            if self.read > limit {
+                return Err(ResponseLimitExceeded::BodyLen { limit }.into());
+            }
+        }
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, time::Duration};
+
+    use fluke_buffet::Piece;
+
+    use super::{LimitedBody, ResponseLimitExceeded, ResponseLimits};
+    use crate::{Body, BodyChunk};
+
+    /// A body of unknown length, whose chunks take 20ms each to come in
+    #[derive(Debug)]
+    struct SlowChunks(VecDeque<&'static str>);
+
+    impl Body for SlowChunks {
+        fn content_len(&self) -> Option<u64> {
+            None
+        }
+
+        fn eof(&self) -> bool {
+            self.0.is_empty()
+        }
+
+        async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(match self.0.pop_front() {
+                Some(chunk) => BodyChunk::Chunk(Piece::from(chunk)),
+                None => BodyChunk::Done { trailers: None },
+            })
+        }
+    }
+
+    /// Reads the body until it's done, or until it errors out with
+    /// [ResponseLimitExceeded], returning what was read
+    async fn read(
+        body: &mut LimitedBody<'_, SlowChunks>,
+    ) -> (Vec<u8>, Option<ResponseLimitExceeded>) {
+        let mut out = vec![];
+        loop {
+            match body.next_chunk().await {
+                Ok(BodyChunk::Chunk(chunk)) => out.extend_from_slice(&chunk[..]),
+                Ok(BodyChunk::Done { .. }) => return (out, None),
+                Err(e) => return (out, Some(*e.downcast_ref().unwrap())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_limited_body() {
+        crate::maybe_uring::start(async move {
+            let limits = ResponseLimits {
+                max_body_len: Some(4),
+                ..Default::default()
+            };
+            assert!(limits.check_announced(Some(4)).is_ok());
+            assert!(limits.check_announced(None).is_ok());
+            assert_eq!(
+                limits.check_announced(Some(5)),
+                Err(ResponseLimitExceeded::BodyLen { limit: 4 })
+            );
+
+            let mut inner = SlowChunks(["ab", "cd"].into());
+            let (out, err) = read(&mut LimitedBody::new(&mut inner, limits)).await;
+            assert_eq!((&out[..], err), (&b"abcd"[..], None));
+
+            // the chunk that goes over isn't passed along
+            let mut inner = SlowChunks(["ab", "cd", "e"].into());
+            let (out, err) = read(&mut LimitedBody::new(&mut inner, limits)).await;
+            assert_eq!(
+                (&out[..], err),
+                (
+                    &b"abcd"[..],
+                    Some(ResponseLimitExceeded::BodyLen { limit: 4 })
+                )
+            );
+
+            let limit = Duration::from_millis(50);
+            let limits = ResponseLimits {
+                max_body_duration: Some(limit),
+                ..Default::default()
+            };
+            let mut inner = SlowChunks(["ab", "cd", "ef", "gh"].into());
+            let (out, err) = read(&mut LimitedBody::new(&mut inner, limits)).await;
+            assert_eq!(
+                (&out[..], err),
+                (
+                    &b"abcd"[..],
+                    Some(ResponseLimitExceeded::BodyDuration { limit })
+                )
+            );
+        });
+    }
+}
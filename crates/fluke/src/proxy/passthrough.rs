@@ -0,0 +1,235 @@
+use std::fmt;
+
+use tokio::sync::{mpsc, oneshot};
+use tracing::debug;
+
+use super::{strip_hop_by_hop, to_downstream};
+use crate::{h1::ClientDriver, Body, BodyChunk, Response};
+
+/// A [ClientDriver] that hands the upstream response over as a head (minus
+/// its hop-by-hop headers) and an [UpstreamBody], for a handler to respond
+/// with however it likes, see [pass_through]. Informational responses are
+/// ignored.
+pub struct PassThrough {
+    head_tx: oneshot::Sender<(Response, UpstreamBody)>,
+}
+
+/// Makes a [PassThrough] driver, along with where the response it gets
+/// shows up.
+///
+/// The driver reads the upstream body as the [UpstreamBody] is read, so
+/// [h1::request](crate::h1::request) has to keep running while it is:
+/// typically, it's joined with whatever sends the response back. One chunk
+/// is in flight at most, so a client that reads slowly slows down reads
+/// from the upstream just as well.
+pub fn pass_through() -> (PassThrough, oneshot::Receiver<(Response, UpstreamBody)>) {
+    let (head_tx, head_rx) = oneshot::channel();
+    (PassThrough { head_tx }, head_rx)
+}
+
+impl ClientDriver for PassThrough {
+    type Return = ();
+
+    async fn on_final_response(self, mut res: Response, body: &mut impl Body) -> eyre::Result<()> {
+        strip_hop_by_hop(&mut res.headers);
+        to_downstream(&mut res);
+        let (chunks_tx, upstream_body) = UpstreamBody::channel(body.content_len());
+        if self.head_tx.send((res, upstream_body)).is_err() {
+            debug!("nobody's waiting for the response anymore");
+            return Ok(());
+        }
+        send_chunks(body, chunks_tx).await
+    }
+}
+
+/// Sends `body` over `chunks_tx` chunk by chunk, until it's done, it fails,
+/// or the receiving [UpstreamBody] is dropped
+pub(crate) async fn send_chunks(
+    body: &mut impl Body,
+    chunks_tx: mpsc::Sender<eyre::Result<BodyChunk>>,
+) -> eyre::Result<()> {
+    loop {
+        let chunk = body.next_chunk().await;
+        let done = !matches!(chunk, Ok(BodyChunk::Chunk(_)));
+        if chunks_tx.send(chunk).await.is_err() {
+            debug!("response body dropped before the end");
+            return Ok(());
+        }
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+/// A response body read from the upstream as it's read from this, see
+/// [pass_through]. Errors reading from the upstream are passed along.
+pub struct UpstreamBody {
+    content_len: Option<u64>,
+    eof: bool,
+    chunks: mpsc::Receiver<eyre::Result<BodyChunk>>,
+}
+
+impl UpstreamBody {
+    /// A body whose chunks are sent with [send_chunks]
+    pub(crate) fn channel(
+        content_len: Option<u64>,
+    ) -> (mpsc::Sender<eyre::Result<BodyChunk>>, Self) {
+        // one chunk in flight at most: the body is read as fast as the
+        // receiving end reads it
+        let (chunks_tx, chunks) = mpsc::channel(1);
+        let body = Self {
+            content_len,
+            eof: false,
+            chunks,
+        };
+        (chunks_tx, body)
+    }
+}
+
+impl fmt::Debug for UpstreamBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UpstreamBody")
+            .field("content_len", &self.content_len)
+            .field("eof", &self.eof)
+            .finish()
+    }
+}
+
+impl Body for UpstreamBody {
+    fn content_len(&self) -> Option<u64> {
+        self.content_len
+    }
+
+    fn eof(&self) -> bool {
+        self.eof
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        if self.eof {
+            return Ok(BodyChunk::Done { trailers: None });
+        }
+        let chunk = self
+            .chunks
+            .recv()
+            .await
+            .ok_or_else(|| eyre::eyre!("connection closed while reading the response body"))?;
+        self.eof = !matches!(chunk, Ok(BodyChunk::Chunk(_)));
+        chunk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc, time::Duration};
+
+    use http::header;
+
+    use super::pass_through;
+    use crate::{
+        h1,
+        maybe_uring::io::{ChanRead, ChanWrite},
+        Body, BodyChunk, Method, Request,
+    };
+
+    #[test]
+    fn test_pass_through() {
+        crate::maybe_uring::start(async move {
+            tokio::time::pause();
+            let (up_tx, up_read) = ChanRead::new();
+            let (mut up_rx, up_write) = ChanWrite::new();
+            let (driver, head_rx) = pass_through();
+            let exchange = crate::maybe_uring::spawn(async move {
+                let req = Request {
+                    method: Method::Get,
+                    uri: "/".parse().unwrap(),
+                    ..Default::default()
+                };
+                h1::request((up_read, up_write), req, &mut (), driver).await
+            });
+
+            let mut sent = vec![];
+            while !sent.ends_with(b"\r\n\r\n") {
+                sent.extend(up_rx.recv().await.unwrap());
+            }
+            up_tx
+                .send("HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\nconnection: x-hop\r\nx-hop: 1\r\nx-kept: 1\r\n\r\n")
+                .await
+                .unwrap();
+            let (res, mut body) = head_rx.await.unwrap();
+            assert!(!res.headers.contains_key(header::TRANSFER_ENCODING));
+            assert!(!res.headers.contains_key("x-hop"));
+            assert!(res.headers.contains_key("x-kept"));
+            assert_eq!(body.content_len(), None);
+
+            // nothing reads the body yet: the upstream can't send it all
+            let chunks_sent = Rc::new(Cell::new(0));
+            let upstream = crate::maybe_uring::spawn({
+                let chunks_sent = chunks_sent.clone();
+                async move {
+                    for _ in 0..8 {
+                        up_tx.send("2\r\nab\r\n").await.unwrap();
+                        chunks_sent.set(chunks_sent.get() + 1);
+                    }
+                    up_tx.send("0\r\n\r\n").await.unwrap();
+                    up_tx
+                }
+            });
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            assert!(chunks_sent.get() < 8, "{} chunks sent", chunks_sent.get());
+
+            let mut out = vec![];
+            while let BodyChunk::Chunk(chunk) = body.next_chunk().await.unwrap() {
+                out.extend_from_slice(&chunk[..]);
+            }
+            assert_eq!(out, b"ab".repeat(8));
+            assert!(body.eof());
+            assert_eq!(chunks_sent.get(), 8);
+
+            let (transport, ()) = exchange.await.unwrap().unwrap();
+            assert!(transport.is_some());
+            drop(upstream.await.unwrap());
+        });
+    }
+
+    #[test]
+    fn test_pass_through_upstream_error() {
+        crate::maybe_uring::start(async move {
+            let (up_tx, up_read) = ChanRead::new();
+            let (mut up_rx, up_write) = ChanWrite::new();
+            let (driver, head_rx) = pass_through();
+            let exchange = crate::maybe_uring::spawn(async move {
+                let req = Request {
+                    method: Method::Get,
+                    uri: "/".parse().unwrap(),
+                    ..Default::default()
+                };
+                h1::request((up_read, up_write), req, &mut (), driver).await
+            });
+
+            let mut sent = vec![];
+            while !sent.ends_with(b"\r\n\r\n") {
+                sent.extend(up_rx.recv().await.unwrap());
+            }
+            up_tx
+                .send("HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nabc")
+                .await
+                .unwrap();
+            let (_res, mut body) = head_rx.await.unwrap();
+            assert_eq!(body.content_len(), Some(10));
+
+            // the upstream goes away halfway through the body
+            up_tx.reset();
+            let mut out = vec![];
+            let err = loop {
+                match body.next_chunk().await {
+                    Ok(BodyChunk::Chunk(chunk)) => out.extend_from_slice(&chunk[..]),
+                    Ok(BodyChunk::Done { .. }) => panic!("body should have failed"),
+                    Err(e) => break e,
+                }
+            };
+            assert_eq!(out, b"abc", "{err}");
+            assert!(body.eof());
+            let _ = exchange.await.unwrap();
+        });
+    }
+}
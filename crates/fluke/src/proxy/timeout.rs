@@ -0,0 +1,326 @@
+use std::{
+    cell::{Cell, RefCell},
+    future::Future,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use http::StatusCode;
+use tokio::sync::Notify;
+use tracing::warn;
+
+use super::{check_forwardable, forward_with, refuse, ForwardResponse, ProxyHooks};
+use crate::{
+    h1::ClientDriver, maybe_uring::io::Transport, Body, BodyChunk, Encoder, ExpectResponseHeaders,
+    Request, Responder, Response, ResponseDone,
+};
+
+/// How long each part of a proxied request may take, see
+/// [forward_with_timeouts]. `None` means no limit, which is the default for
+/// all of them.
+///
+/// A single timeout doesn't fit upstreams that stream their responses: it
+/// either cuts off long streams, or waits forever on a server that never
+/// answers. Here, `first_byte` bounds the wait for a response, and
+/// `between_bytes` how long a stream may stall once it's going.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProxyTimeouts {
+    /// Establishing the connection to the upstream server
+    pub connect: Option<Duration>,
+
+    /// From the request starting to go out to the upstream response head
+    /// coming back. Interim responses don't count.
+    pub first_byte: Option<Duration>,
+
+    /// Between two chunks of the upstream response body (or the head and the
+    /// first chunk)
+    pub between_bytes: Option<Duration>,
+
+    /// For the whole exchange, connecting included
+    pub total: Option<Duration>,
+}
+
+/// Which of the [ProxyTimeouts] ran out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ProxyTimeout {
+    #[error("timed out connecting to upstream")]
+    Connect,
+
+    #[error("timed out waiting for the upstream response head")]
+    FirstByte,
+
+    #[error("upstream response body stalled")]
+    BetweenBytes,
+
+    #[error("upstream exchange took too long")]
+    Total,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Connecting,
+
+    /// Since when
+    AwaitingHead(Instant),
+
+    /// Since the head or the last chunk of the body was read
+    Streaming(Instant),
+}
+
+/// Where an exchange is at, for [watchdog] to know which deadline applies
+#[derive(Debug)]
+struct Clock {
+    start: Instant,
+    phase: Cell<Phase>,
+    changed: Notify,
+}
+
+impl Clock {
+    fn new(start: Instant) -> Self {
+        Self {
+            start,
+            phase: Cell::new(Phase::Connecting),
+            changed: Notify::new(),
+        }
+    }
+
+    fn set(&self, phase: Phase) {
+        self.phase.set(phase);
+        self.changed.notify_one();
+    }
+
+    /// The next deadline, and which timeout it is, if any applies
+    fn deadline(&self, timeouts: &ProxyTimeouts) -> Option<(Instant, ProxyTimeout)> {
+        let phase = match self.phase.get() {
+            Phase::Connecting => timeouts
+                .connect
+                .map(|d| (self.start + d, ProxyTimeout::Connect)),
+            Phase::AwaitingHead(since) => timeouts
+                .first_byte
+                .map(|d| (since + d, ProxyTimeout::FirstByte)),
+            Phase::Streaming(since) => timeouts
+                .between_bytes
+                .map(|d| (since + d, ProxyTimeout::BetweenBytes)),
+        };
+        let total = timeouts
+            .total
+            .map(|d| (self.start + d, ProxyTimeout::Total));
+        match (phase, total) {
+            (Some(phase), Some(total)) => Some(std::cmp::min_by_key(total, phase, |t| t.0)),
+            (phase, total) => phase.or(total),
+        }
+    }
+}
+
+/// Resolves once the deadline of whatever phase `clock` is in has passed
+async fn watchdog(clock: &Clock, timeouts: &ProxyTimeouts) -> ProxyTimeout {
+    loop {
+        let deadline = clock.deadline(timeouts);
+        if let Some((deadline, timeout)) = deadline {
+            if Instant::now() >= deadline {
+                return timeout;
+            }
+        }
+
+        let sleep = async {
+            match deadline {
+                Some((deadline, _)) => tokio::time::sleep_until(deadline.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = sleep => {},
+            _ = clock.changed.notified() => {},
+        }
+    }
+}
+
+/// Like [forward], connecting first, and giving up when one of `timeouts`
+/// runs out. What happens then depends on how far along the response is:
+///
+///   * if its head wasn't sent yet, the client gets a `504 Gateway Timeout`
+///   * otherwise, the response is cut short: the [ProxyTimeout] is returned
+///     as an error, which makes the server close the connection (HTTP/1.1) or
+///     reset the stream (HTTP/2), so that the client can tell.
+///
+/// Either way, the upstream connection is dropped. Requests that can't be
+/// forwarded (see [check_forwardable]) get a `501 Not Implemented` without
+/// connecting.
+///
+/// [forward]: super::forward
+pub async fn forward_with_timeouts<T: Transport, E: Encoder>(
+    connect: impl Future<Output = eyre::Result<T>>,
+    req: Request,
+    req_body: &mut impl Body,
+    respond: Responder<E, ExpectResponseHeaders>,
+    hooks: Option<Rc<dyn ProxyHooks>>,
+    timeouts: &ProxyTimeouts,
+) -> eyre::Result<(Option<(T::Read, T::Write)>, Responder<E, ResponseDone>)> {
+    if let Err(e) = check_forwardable(&req) {
+        return Ok((None, refuse(respond, e).await?));
+    }
+
+    let clock = Clock::new(Instant::now());
+    // the responder stays here until the upstream response head comes in,
+    // so that a 504 can still be sent if it doesn't.
+    let respond = RefCell::new(Some(respond));
+
+    let exchange = async {
+        let transport = connect.await?;
+        clock.set(Phase::AwaitingHead(Instant::now()));
+        let driver = TimedForward {
+            respond: &respond,
+            hooks: hooks.clone(),
+            clock: &clock,
+        };
+        forward_with(transport, req, req_body, driver, hooks.as_deref()).await
+    };
+
+    let timed_out = tokio::select! {
+        res = exchange => return res,
+        timed_out = watchdog(&clock, timeouts) => timed_out,
+    };
+
+    match respond.into_inner() {
+        Some(respond) => {
+            warn!(%timed_out, "responding with 504");
+            let res = Response {
+                status: StatusCode::GATEWAY_TIMEOUT,
+                ..Default::default()
+            };
+            let respond = respond.write_final_response_with_body(res, &mut ()).await?;
+            Ok((None, respond))
+        }
+        None => {
+            warn!(%timed_out, "response already started, cutting it short");
+            Err(timed_out.into())
+        }
+    }
+}
+
+/// A [ForwardResponse] that's only handed the responder once the upstream
+/// response head is in, and that tells the [Clock] about body progress
+struct TimedForward<'a, E: Encoder> {
+    respond: &'a RefCell<Option<Responder<E, ExpectResponseHeaders>>>,
+    hooks: Option<Rc<dyn ProxyHooks>>,
+    clock: &'a Clock,
+}
+
+impl<E: Encoder> ClientDriver for TimedForward<'_, E> {
+    type Return = Responder<E, ResponseDone>;
+
+    async fn on_informational_response(&mut self, res: Response) -> eyre::Result<()> {
+        let respond = self
+            .respond
+            .borrow_mut()
+            .take()
+            .expect("responder is there until the final response");
+        let mut forward = ForwardResponse {
+            respond,
+            hooks: self.hooks.clone(),
+        };
+        let res = forward.on_informational_response(res).await;
+        *self.respond.borrow_mut() = Some(forward.respond);
+        res
+    }
+
+    async fn on_final_response(
+        self,
+        res: Response,
+        body: &mut impl Body,
+    ) -> eyre::Result<Self::Return> {
+        self.clock.set(Phase::Streaming(Instant::now()));
+        let respond = self
+            .respond
+            .borrow_mut()
+            .take()
+            .expect("responder is there until the final response");
+        let forward = ForwardResponse {
+            respond,
+            hooks: self.hooks,
+        };
+        let mut body = ClockedBody {
+            inner: body,
+            clock: self.clock,
+        };
+        forward.on_final_response(res, &mut body).await
+    }
+}
+
+/// Moves the [Clock] along as chunks are read
+#[derive(Debug)]
+struct ClockedBody<'a, B: Body> {
+    inner: &'a mut B,
+    clock: &'a Clock,
+}
+
+impl<B: Body> Body for ClockedBody<'_, B> {
+    fn content_len(&self) -> Option<u64> {
+        self.inner.content_len()
+    }
+
+    fn eof(&self) -> bool {
+        self.inner.eof()
+    }
+
+    async fn next_chunk(&mut self) -> eyre::Result<BodyChunk> {
+        let chunk = self.inner.next_chunk().await?;
+        self.clock.set(Phase::Streaming(Instant::now()));
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Clock, Phase, ProxyTimeout, ProxyTimeouts};
+
+    #[test]
+    fn test_proxy_timeout_deadlines() {
+        let secs = |n| Some(Duration::from_secs(n));
+        let start = Instant::now();
+        let at = |n| start + Duration::from_secs(n);
+        let clock = Clock::new(start);
+
+        assert_eq!(clock.deadline(&ProxyTimeouts::default()), None);
+
+        let timeouts = ProxyTimeouts {
+            connect: secs(1),
+            first_byte: secs(10),
+            between_bytes: secs(5),
+            total: secs(60),
+        };
+        assert_eq!(
+            clock.deadline(&timeouts),
+            Some((at(1), ProxyTimeout::Connect))
+        );
+        clock.set(Phase::AwaitingHead(at(1)));
+        assert_eq!(
+            clock.deadline(&timeouts),
+            Some((at(11), ProxyTimeout::FirstByte))
+        );
+        clock.set(Phase::Streaming(at(4)));
+        assert_eq!(
+            clock.deadline(&timeouts),
+            Some((at(9), ProxyTimeout::BetweenBytes))
+        );
+
+        // a steady stream still runs into the total timeout
+        clock.set(Phase::Streaming(at(58)));
+        assert_eq!(
+            clock.deadline(&timeouts),
+            Some((at(60), ProxyTimeout::Total))
+        );
+
+        // phases without a timeout of their own only have the total one
+        let total_only = ProxyTimeouts {
+            total: secs(60),
+            ..Default::default()
+        };
+        assert_eq!(
+            clock.deadline(&total_only),
+            Some((at(60), ProxyTimeout::Total))
+        );
+    }
+}
@@ -0,0 +1,622 @@
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    net::SocketAddr,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use http::StatusCode;
+use tracing::{debug, warn};
+
+use super::{
+    balance::{ring_hash, RING_POINTS_PER_WEIGHT},
+    Balancer, ProxyTimeouts,
+};
+use crate::Request;
+
+/// When an [Endpoint] gets taken out of rotation, and for how long, see
+/// [BreakerState]
+#[derive(Debug, Clone, Copy)]
+pub struct OutlierConf {
+    /// Outcomes are counted over fixed windows of that length
+    pub window: Duration,
+
+    /// The error rate of a window is only looked at once it has seen that
+    /// many requests
+    pub min_requests: u32,
+
+    /// Share of failed requests, from 0.0 to 1.0, over which the endpoint
+    /// gets ejected
+    pub max_error_rate: f32,
+
+    /// The endpoint gets ejected after that many failures in a row, however
+    /// few requests the window has seen
+    pub max_consecutive_failures: u32,
+
+    /// Successful requests that took longer than this count as failures.
+    /// `None` only looks at errors.
+    pub max_latency: Option<Duration>,
+
+    /// How long an endpoint stays ejected the first time. It doubles each
+    /// time the endpoint fails its probes, up to `max_ejection`.
+    pub base_ejection: Duration,
+
+    /// See `base_ejection`
+    pub max_ejection: Duration,
+
+    /// How many probe requests must succeed, once the ejection is over, for
+    /// the endpoint to be back in rotation
+    pub probes: u32,
+}
+
+impl Default for OutlierConf {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(10),
+            min_requests: 20,
+            max_error_rate: 0.5,
+            max_consecutive_failures: 5,
+            max_latency: None,
+            base_ejection: Duration::from_secs(30),
+            max_ejection: Duration::from_secs(300),
+            probes: 3,
+        }
+    }
+}
+
+/// The circuit breaker state of an [Endpoint]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// In rotation
+    Closed,
+
+    /// Ejected: not picked until `until`
+    Open { until: Instant },
+
+    /// The ejection is over: probe requests are let through one at a time,
+    /// until [OutlierConf::probes] of them succeed (and the breaker closes)
+    /// or one fails (and it opens again, for longer).
+    HalfOpen,
+}
+
+/// How a request to an [Endpoint] went, see [Endpoint::report]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Success {
+        latency: Duration,
+    },
+
+    /// Couldn't connect, timed out, got a server error, etc.
+    Failure,
+}
+
+impl Outcome {
+    /// 5xx responses are failures, anything else is a success
+    pub fn for_response(status: StatusCode, latency: Duration) -> Self {
+        if status.is_server_error() {
+            Outcome::Failure
+        } else {
+            Outcome::Success { latency }
+        }
+    }
+}
+
+/// An upstream server, with its circuit breaker
+pub struct Endpoint {
+    pub addr: SocketAddr,
+
+    /// Relative share of requests, for [Balancer::WeightedRoundRobin] and
+    /// [Balancer::RingHash]. Zero takes the endpoint out of rotation for
+    /// the former.
+    pub weight: u32,
+
+    conf: OutlierConf,
+    breaker: RefCell<Breaker>,
+
+    /// For smooth weighted round-robin
+    current_weight: Cell<i64>,
+
+    /// Requests picked but not reported yet
+    in_flight: Cell<u32>,
+}
+
+struct Breaker {
+    state: BreakerState,
+    window_start: Instant,
+    requests: u32,
+    failures: u32,
+    consecutive_failures: u32,
+
+    /// Ejections since the breaker was last closed, for the backoff
+    ejections: u32,
+    probe_started: Option<Instant>,
+    probe_successes: u32,
+}
+
+impl fmt::Debug for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Endpoint")
+            .field("addr", &self.addr)
+            .field("weight", &self.weight)
+            .field("state", &self.breaker.borrow().state)
+            .field("in_flight", &self.in_flight.get())
+            .finish()
+    }
+}
+
+impl Endpoint {
+    pub fn new(addr: SocketAddr, weight: u32, conf: OutlierConf) -> Self {
+        Self {
+            addr,
+            weight,
+            conf,
+            breaker: RefCell::new(Breaker {
+                state: BreakerState::Closed,
+                window_start: Instant::now(),
+                requests: 0,
+                failures: 0,
+                consecutive_failures: 0,
+                ejections: 0,
+                probe_started: None,
+                probe_successes: 0,
+            }),
+            current_weight: Cell::new(0),
+            in_flight: Cell::new(0),
+        }
+    }
+
+    /// Requests sent to this endpoint that weren't reported yet
+    pub fn in_flight(&self) -> u32 {
+        self.in_flight.get()
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.state_at(Instant::now())
+    }
+
+    fn state_at(&self, now: Instant) -> BreakerState {
+        let mut breaker = self.breaker.borrow_mut();
+        if let BreakerState::Open { until } = breaker.state {
+            if now >= until {
+                debug!(addr = %self.addr, "ejection over, probing endpoint");
+                breaker.state = BreakerState::HalfOpen;
+                breaker.probe_started = None;
+                breaker.probe_successes = 0;
+            }
+        }
+        breaker.state
+    }
+
+    /// Whether a request may be sent to this endpoint now. Half-open
+    /// breakers let a probe through once the previous one was reported, or
+    /// after [OutlierConf::window] if it never was.
+    fn is_available_at(&self, now: Instant) -> bool {
+        match self.state_at(now) {
+            BreakerState::Closed => true,
+            BreakerState::Open { .. } => false,
+            BreakerState::HalfOpen => match self.breaker.borrow().probe_started {
+                Some(started) => now.duration_since(started) >= self.conf.window,
+                None => true,
+            },
+        }
+    }
+
+    /// Marks the endpoint as picked, which starts a probe if the breaker is
+    /// half-open
+    fn acquire_at(&self, now: Instant) {
+        self.in_flight.set(self.in_flight.get() + 1);
+        let mut breaker = self.breaker.borrow_mut();
+        if breaker.state == BreakerState::HalfOpen {
+            breaker.probe_started = Some(now);
+        }
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> bool {
+        let available = self.is_available_at(now);
+        if available {
+            self.acquire_at(now);
+        }
+        available
+    }
+
+    /// Records how a request sent to this endpoint went, which may eject it
+    /// or put it back in rotation
+    pub fn report(&self, outcome: Outcome) {
+        self.report_at(outcome, Instant::now())
+    }
+
+    fn report_at(&self, outcome: Outcome, now: Instant) {
+        self.in_flight.set(self.in_flight.get().saturating_sub(1));
+        let failed = match outcome {
+            Outcome::Success { latency } => self.conf.max_latency.is_some_and(|max| latency > max),
+            Outcome::Failure => true,
+        };
+
+        let state = self.state_at(now);
+        let mut breaker = self.breaker.borrow_mut();
+        match state {
+            // a request that was sent before the endpoint got ejected
+            BreakerState::Open { .. } => {}
+            BreakerState::HalfOpen => {
+                breaker.probe_started = None;
+                if failed {
+                    self.eject(&mut breaker, now);
+                } else {
+                    breaker.probe_successes += 1;
+                    if breaker.probe_successes >= self.conf.probes {
+                        debug!(addr = %self.addr, "endpoint is back in rotation");
+                        breaker.state = BreakerState::Closed;
+                        breaker.ejections = 0;
+                        breaker.window_start = now;
+                        breaker.requests = 0;
+                        breaker.failures = 0;
+                        breaker.consecutive_failures = 0;
+                    }
+                }
+            }
+            BreakerState::Closed => {
+                if now.duration_since(breaker.window_start) >= self.conf.window {
+                    breaker.window_start = now;
+                    breaker.requests = 0;
+                    breaker.failures = 0;
+                }
+                breaker.requests += 1;
+                if failed {
+                    breaker.failures += 1;
+                    breaker.consecutive_failures += 1;
+                } else {
+                    breaker.consecutive_failures = 0;
+                }
+
+                let error_rate = breaker.failures as f32 / breaker.requests as f32;
+                if breaker.consecutive_failures >= self.conf.max_consecutive_failures
+                    || (breaker.requests >= self.conf.min_requests
+                        && error_rate > self.conf.max_error_rate)
+                {
+                    self.eject(&mut breaker, now);
+                }
+            }
+        }
+    }
+
+    fn eject(&self, breaker: &mut Breaker, now: Instant) {
+        let backoff = 1u32 << std::cmp::min(breaker.ejections, 16);
+        let duration = std::cmp::min(
+            self.conf.base_ejection.saturating_mul(backoff),
+            self.conf.max_ejection,
+        );
+        warn!(addr = %self.addr, ?duration, "ejecting upstream endpoint");
+
+        breaker.ejections += 1;
+        breaker.state = BreakerState::Open {
+            until: now + duration,
+        };
+        breaker.requests = 0;
+        breaker.failures = 0;
+        breaker.consecutive_failures = 0;
+        breaker.probe_started = None;
+    }
+}
+
+/// A set of upstream servers to forward requests to, which takes those
+/// that fail (see [OutlierConf]) out of rotation.
+///
+/// The set may change over time, see [UpstreamPool::set_addrs] and
+/// [discover](super::discover).
+pub struct UpstreamPool {
+    conf: OutlierConf,
+    endpoints: RefCell<Vec<Rc<Endpoint>>>,
+    next: Cell<usize>,
+
+    /// Points for [Balancer::RingHash], as (hash, endpoint index), sorted
+    ring: RefCell<Vec<(u64, usize)>>,
+
+    /// Endpoints that were removed from the set while requests were in
+    /// flight to them
+    draining: RefCell<Vec<Rc<Endpoint>>>,
+
+    timeouts: Cell<ProxyTimeouts>,
+}
+
+impl UpstreamPool {
+    /// A pool of endpoints of equal weight
+    pub fn new(addrs: impl IntoIterator<Item = SocketAddr>, conf: OutlierConf) -> Self {
+        Self::new_weighted(addrs.into_iter().map(|addr| (addr, 1)), conf)
+    }
+
+    pub fn new_weighted(
+        addrs: impl IntoIterator<Item = (SocketAddr, u32)>,
+        conf: OutlierConf,
+    ) -> Self {
+        let pool = Self {
+            conf,
+            endpoints: Default::default(),
+            next: Cell::new(0),
+            ring: Default::default(),
+            draining: Default::default(),
+            timeouts: Default::default(),
+        };
+        pool.set_addrs(addrs);
+        pool
+    }
+
+    /// How long requests to this upstream may take, to be passed to
+    /// [forward_with_timeouts](super::forward_with_timeouts). None of them
+    /// time out by default.
+    pub fn timeouts(&self) -> ProxyTimeouts {
+        self.timeouts.get()
+    }
+
+    /// See [UpstreamPool::timeouts]
+    pub fn set_timeouts(&self, timeouts: ProxyTimeouts) {
+        self.timeouts.set(timeouts);
+    }
+
+    /// The endpoints in the set, in rotation or not
+    pub fn endpoints(&self) -> Vec<Rc<Endpoint>> {
+        self.endpoints.borrow().clone()
+    }
+
+    /// Endpoints that were removed from the set, but still have requests in
+    /// flight. They aren't picked anymore, and are forgotten once those are
+    /// reported. Connections to them can be closed as soon as they're idle.
+    pub fn draining(&self) -> Vec<Rc<Endpoint>> {
+        let mut draining = self.draining.borrow_mut();
+        draining.retain(|endpoint| endpoint.in_flight() > 0);
+        draining.clone()
+    }
+
+    /// Replaces the set of endpoints. Those whose address (and weight) is
+    /// still in the set are kept as they are, along with their circuit
+    /// breaker, and removed ones are drained, see [UpstreamPool::draining].
+    pub fn set_addrs(&self, addrs: impl IntoIterator<Item = (SocketAddr, u32)>) {
+        let mut old = std::mem::take(&mut *self.endpoints.borrow_mut());
+        let mut draining = self.draining.borrow_mut();
+
+        let mut endpoints = vec![];
+        for (addr, weight) in addrs {
+            if endpoints.iter().any(|e: &Rc<Endpoint>| e.addr == addr) {
+                continue;
+            }
+            let same = |e: &Rc<Endpoint>| e.addr == addr && e.weight == weight;
+            let endpoint = if let Some(index) = old.iter().position(same) {
+                old.swap_remove(index)
+            } else if let Some(index) = draining.iter().position(same) {
+                debug!(%addr, "upstream endpoint is back before it was drained");
+                draining.swap_remove(index)
+            } else {
+                debug!(%addr, %weight, "adding upstream endpoint");
+                Rc::new(Endpoint::new(addr, weight, self.conf))
+            };
+            endpoints.push(endpoint);
+        }
+
+        for endpoint in old {
+            let in_flight = endpoint.in_flight();
+            debug!(addr = %endpoint.addr, %in_flight, "removing upstream endpoint");
+            if endpoint.in_flight() > 0 {
+                draining.push(endpoint);
+            }
+        }
+        draining.retain(|endpoint| endpoint.in_flight() > 0);
+
+        let mut ring = vec![];
+        for (index, endpoint) in endpoints.iter().enumerate() {
+            for i in 0..endpoint.weight.saturating_mul(RING_POINTS_PER_WEIGHT) {
+                let point = ring_hash(format!("{}-{i}", endpoint.addr).as_bytes());
+                ring.push((point, index));
+            }
+        }
+        ring.sort_unstable();
+
+        *self.endpoints.borrow_mut() = endpoints;
+        *self.ring.borrow_mut() = ring;
+    }
+
+    /// Picks an endpoint in rotation for `req`, the way `balancer` says.
+    /// Returns `None` if they're all ejected (or waiting on a probe), in
+    /// which case there's nobody to forward to: a 503 Service Unavailable is
+    /// in order.
+    ///
+    /// How the request went must then be reported with [Endpoint::report].
+    pub fn pick(&self, balancer: &Balancer, req: &Request) -> Option<Rc<Endpoint>> {
+        self.pick_at(balancer, req, Instant::now())
+    }
+
+    fn pick_at(&self, balancer: &Balancer, req: &Request, now: Instant) -> Option<Rc<Endpoint>> {
+        match balancer {
+            Balancer::RoundRobin => self.round_robin(now),
+            Balancer::WeightedRoundRobin => self.weighted_round_robin(now),
+            Balancer::RingHash(key) => match key.extract(req) {
+                Some(key) => self.ring_hash(&key, now),
+                None => self.round_robin(now),
+            },
+        }
+    }
+
+    fn round_robin(&self, now: Instant) -> Option<Rc<Endpoint>> {
+        let endpoints = self.endpoints.borrow();
+        let len = endpoints.len();
+        let start = self.next.get();
+        for i in 0..len {
+            let index = (start + i) % len;
+            let endpoint = &endpoints[index];
+            if endpoint.try_acquire_at(now) {
+                self.next.set(index + 1);
+                return Some(endpoint.clone());
+            }
+        }
+        None
+    }
+
+    fn weighted_round_robin(&self, now: Instant) -> Option<Rc<Endpoint>> {
+        let mut total = 0;
+        let endpoints = self.endpoints.borrow();
+        let mut best: Option<&Rc<Endpoint>> = None;
+        for endpoint in endpoints.iter() {
+            if endpoint.weight == 0 || !endpoint.is_available_at(now) {
+                continue;
+            }
+            let current = endpoint.current_weight.get() + endpoint.weight as i64;
+            endpoint.current_weight.set(current);
+            total += endpoint.weight as i64;
+            if best.map_or(true, |best| current > best.current_weight.get()) {
+                best = Some(endpoint);
+            }
+        }
+
+        let best = best?;
+        best.current_weight.set(best.current_weight.get() - total);
+        best.acquire_at(now);
+        Some(best.clone())
+    }
+
+    fn ring_hash(&self, key: &[u8], now: Instant) -> Option<Rc<Endpoint>> {
+        let endpoints = self.endpoints.borrow();
+        let ring = self.ring.borrow();
+        let hash = ring_hash(key);
+        let start = ring.partition_point(|&(point, _)| point < hash);
+        // walk the ring clockwise, past endpoints out of rotation
+        for i in 0..ring.len() {
+            let (_, index) = ring[(start + i) % ring.len()];
+            let endpoint = &endpoints[index];
+            if endpoint.try_acquire_at(now) {
+                return Some(endpoint.clone());
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        time::{Duration, Instant},
+    };
+
+    use http::HeaderName;
+
+    use super::{BreakerState, Outcome, OutlierConf, UpstreamPool};
+    use crate::{
+        proxy::{Balancer, HashKey},
+        Request,
+    };
+
+    #[test]
+    fn test_outlier_detection() {
+        let conf = OutlierConf {
+            max_consecutive_failures: 3,
+            max_latency: Some(Duration::from_secs(1)),
+            probes: 2,
+            ..Default::default()
+        };
+        let pool = UpstreamPool::new(
+            [
+                "127.0.0.1:1".parse().unwrap(),
+                "127.0.0.1:2".parse().unwrap(),
+            ],
+            conf,
+        );
+        let [a, b] = &pool.endpoints()[..] else {
+            unreachable!()
+        };
+        let fast = Outcome::Success {
+            latency: Duration::from_millis(10),
+        };
+        let slow = Outcome::Success {
+            latency: Duration::from_secs(2),
+        };
+
+        let rr = Balancer::RoundRobin;
+        let req = Request::default();
+        let now = Instant::now();
+        assert_eq!(pool.pick_at(&rr, &req, now).unwrap().addr, a.addr);
+        assert_eq!(pool.pick_at(&rr, &req, now).unwrap().addr, b.addr);
+
+        // failures in a row (slow responses included) eject the endpoint
+        a.report_at(Outcome::Failure, now);
+        a.report_at(slow, now);
+        a.report_at(Outcome::Failure, now);
+        assert!(matches!(a.state_at(now), BreakerState::Open { .. }));
+        for _ in 0..3 {
+            assert_eq!(pool.pick_at(&rr, &req, now).unwrap().addr, b.addr);
+        }
+
+        // then it gets probed one request at a time
+        let later = now + conf.base_ejection;
+        assert_eq!(a.state_at(later), BreakerState::HalfOpen);
+        assert!(a.try_acquire_at(later));
+        assert!(!a.try_acquire_at(later));
+
+        // a failed probe ejects it for twice as long
+        a.report_at(Outcome::Failure, later);
+        assert_eq!(
+            a.state_at(later),
+            BreakerState::Open {
+                until: later + conf.base_ejection * 2
+            }
+        );
+
+        let later = later + conf.base_ejection * 2;
+        for _ in 0..2 {
+            assert!(a.try_acquire_at(later));
+            a.report_at(fast, later);
+        }
+        assert_eq!(a.state_at(later), BreakerState::Closed);
+
+        // a high error rate ejects it too, once there are enough requests
+        for i in 0..conf.min_requests {
+            assert_eq!(b.state_at(later), BreakerState::Closed);
+            let outcome = if i % 3 == 2 { fast } else { Outcome::Failure };
+            b.report_at(outcome, later);
+        }
+        assert!(matches!(b.state_at(later), BreakerState::Open { .. }));
+    }
+
+    #[test]
+    fn test_balancers() {
+        let addrs = ["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"].map(|a| a.parse().unwrap());
+        let now = Instant::now();
+        let req = Request::default();
+
+        let pool = UpstreamPool::new_weighted(addrs.into_iter().zip([3, 1, 0]), Default::default());
+        let picks: Vec<u16> = (0..8)
+            .map(|_| {
+                let endpoint = pool.pick_at(&Balancer::WeightedRoundRobin, &req, now);
+                endpoint.unwrap().addr.port()
+            })
+            .collect();
+        assert_eq!(picks, [1, 1, 2, 1, 1, 1, 2, 1]);
+
+        let pool = UpstreamPool::new(addrs, Default::default());
+        let header = HeaderName::from_static("x-user");
+        let balancer = Balancer::RingHash(HashKey::Header(header.clone()));
+        let pick = |user: &str| {
+            let mut req = Request::default();
+            req.headers
+                .insert(header.clone(), user.to_owned().into_bytes().into());
+            pool.pick_at(&balancer, &req, now).unwrap()
+        };
+
+        // the same key always goes to the same endpoint, and keys are spread
+        let mut ports = HashSet::new();
+        for i in 0..30 {
+            let user = format!("user-{i}");
+            let endpoint = pick(&user);
+            assert_eq!(pick(&user).addr, endpoint.addr);
+            ports.insert(endpoint.addr.port());
+        }
+        assert_eq!(ports.len(), 3);
+
+        // unless that endpoint is out of rotation
+        let endpoint = pick("alice");
+        for _ in 0..5 {
+            endpoint.report_at(Outcome::Failure, now);
+        }
+        let other = pick("alice");
+        assert_ne!(other.addr, endpoint.addr);
+        assert_eq!(pick("alice").addr, other.addr);
+    }
+}
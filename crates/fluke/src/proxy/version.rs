@@ -0,0 +1,133 @@
+use http::{header, StatusCode, Version};
+use tracing::warn;
+
+use crate::{Encoder, ExpectResponseHeaders, Method, Request, Responder, Response, ResponseDone};
+
+/// Why a request can't be forwarded to an HTTP/1.1 upstream, see
+/// [check_forwardable]. [forward](super::forward) answers those with a
+/// `501 Not Implemented`, without reaching the upstream.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum UntranslatableRequest {
+    #[error("CONNECT sets up a tunnel, which can't be forwarded as a plain request")]
+    Connect,
+
+    #[error("extended CONNECT for {protocol:?} can't be forwarded as a plain request")]
+    ExtendedConnect { protocol: String },
+}
+
+/// Errors out on requests a plain HTTP/1.1 request to the upstream can't
+/// stand for, whichever version they came in over: tunnels set up with
+/// `CONNECT`, and protocols bootstrapped over an HTTP/2 stream with an
+/// extended CONNECT (WebSockets have [websocket_handshake](super::websocket_handshake)
+/// for that).
+///
+/// Requests asking for an HTTP/1.1 upgrade are forwarded without their
+/// `upgrade` header, like a server that doesn't know the protocol would
+/// handle them.
+pub fn check_forwardable(req: &Request) -> Result<(), UntranslatableRequest> {
+    if let Some(protocol) = &req.protocol {
+        return Err(UntranslatableRequest::ExtendedConnect {
+            protocol: protocol[..].to_owned(),
+        });
+    }
+    if req.method == Method::Connect {
+        return Err(UntranslatableRequest::Connect);
+    }
+    Ok(())
+}
+
+/// Answers a request [check_forwardable] refused
+pub(crate) async fn refuse<E: Encoder>(
+    respond: Responder<E, ExpectResponseHeaders>,
+    e: UntranslatableRequest,
+) -> eyre::Result<Responder<E, ResponseDone>> {
+    warn!(%e, "responding with 501");
+    let res = Response {
+        status: StatusCode::NOT_IMPLEMENTED,
+        ..Default::default()
+    };
+    respond.write_final_response_with_body(res, &mut ()).await
+}
+
+/// Turns `req`, as it came in over whichever version, into an HTTP/1.1
+/// request, once its hop-by-hop headers are gone:
+///
+/// - `host` is set from the `:authority` of HTTP/2 requests (or the
+///   authority of absolute-form HTTP/1.1 targets) if it's missing, and the
+///   target is sent in origin-form
+/// - the `cookie` fields HTTP/2 clients may split a cookie list into are
+///   joined back into one, cf. <https://httpwg.org/specs/rfc9113.html#n-compressing-the-cookie-header-field>
+/// - `te: trailers` is passed along if the client sent it, so that the
+///   upstream's trailers make it back: trailers are carried over from and
+///   to chunked bodies either way
+pub(crate) fn to_upstream(req: &mut Request, accepts_trailers: bool) {
+    req.version = Version::HTTP_11;
+
+    if !req.headers.contains_key(header::HOST) {
+        if let Some(authority) = req.uri.authority() {
+            let host = authority.to_owned().into_bytes().into();
+            req.headers.insert(header::HOST, host);
+        }
+    }
+
+    let mut cookies = req.headers.get_all(header::COOKIE).iter();
+    if let (Some(_), Some(_)) = (cookies.next(), cookies.next()) {
+        let joined: Vec<&[u8]> = req
+            .headers
+            .get_all(header::COOKIE)
+            .iter()
+            .map(|value| &value[..])
+            .collect();
+        let joined = joined.join(&b"; "[..]);
+        req.headers.insert(header::COOKIE, joined.into());
+    }
+
+    if accepts_trailers {
+        req.headers.insert(header::TE, "trailers".into());
+    }
+}
+
+/// The upstream response, as sent back to our own client: in whichever
+/// version the client speaks, which is for the encoder to say.
+pub(crate) fn to_downstream(res: &mut Response) {
+    res.version = Version::HTTP_11;
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header, Version};
+
+    use super::{check_forwardable, to_upstream, UntranslatableRequest};
+    use crate::{Method, Request};
+
+    #[test]
+    fn test_h2_request_to_upstream() {
+        let mut req = Request {
+            method: Method::Get,
+            uri: "https://example.org/a?b".parse().unwrap(),
+            version: Version::HTTP_2,
+            ..Default::default()
+        };
+        req.headers.append(header::COOKIE, "a=1".into());
+        req.headers.append(header::COOKIE, "b=2".into());
+        assert_eq!(check_forwardable(&req), Ok(()));
+
+        to_upstream(&mut req, true);
+        assert_eq!(req.version, Version::HTTP_11);
+        assert_eq!(&req.headers[header::HOST][..], b"example.org");
+        assert_eq!(&req.headers[header::COOKIE][..], b"a=1; b=2");
+        assert_eq!(req.headers.get_all(header::COOKIE).iter().count(), 1);
+        assert_eq!(&req.headers[header::TE][..], b"trailers");
+
+        req.protocol = Some("websocket".into());
+        assert_eq!(
+            check_forwardable(&req),
+            Err(UntranslatableRequest::ExtendedConnect {
+                protocol: "websocket".into()
+            })
+        );
+        req.protocol = None;
+        req.method = Method::Connect;
+        assert_eq!(check_forwardable(&req), Err(UntranslatableRequest::Connect));
+    }
+}
@@ -0,0 +1,561 @@
+use std::{
+    cell::Cell,
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    net::Shutdown,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use eyre::Context;
+use fluke_buffet::{PieceList, RollMut};
+use http::{header, StatusCode, Version};
+use tracing::debug;
+
+use super::{strip_hop_by_hop, ProxyHooks};
+use crate::{
+    h1::{encode::encode_request, parse},
+    maybe_uring::io::{ReadOwned, Transport, WriteOwned},
+    util::read_and_parse,
+    Headers, Method, Request, Response,
+};
+
+/// Why a request isn't a WebSocket opening handshake we can forward, cf.
+/// <https://www.rfc-editor.org/rfc/rfc6455#section-4.2.1>
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum WebSocketHandshakeError {
+    #[error("websocket handshakes must be GET requests")]
+    NotGet,
+
+    #[error("websocket handshakes must be HTTP/1.1 requests")]
+    NotHttp11,
+
+    #[error("missing `upgrade: websocket`")]
+    NotUpgradingToWebSocket,
+
+    #[error("missing `connection: upgrade`")]
+    MissingConnectionUpgrade,
+
+    #[error("missing or invalid `sec-websocket-key`")]
+    InvalidKey,
+
+    #[error("unsupported `sec-websocket-version`, only 13 is")]
+    UnsupportedVersion,
+}
+
+/// Checks that `req` asks to switch to WebSocket. Requests that don't are
+/// best answered with a `400 Bad Request`, or a `426 Upgrade Required` for
+/// [WebSocketHandshakeError::UnsupportedVersion].
+pub fn check_websocket_handshake(req: &Request) -> Result<(), WebSocketHandshakeError> {
+    use WebSocketHandshakeError::*;
+
+    if req.method != Method::Get {
+        return Err(NotGet);
+    }
+    if req.version != Version::HTTP_11 {
+        return Err(NotHttp11);
+    }
+    if !has_token(&req.headers, header::UPGRADE, b"websocket") {
+        return Err(NotUpgradingToWebSocket);
+    }
+    if !has_token(&req.headers, header::CONNECTION, b"upgrade") {
+        return Err(MissingConnectionUpgrade);
+    }
+
+    // the base64 encoding of 16 bytes, which is all we check: the upstream
+    // hashes it, and the client checks the result.
+    let mut keys = req.headers.get_all(header::SEC_WEBSOCKET_KEY).iter();
+    let (Some(key), None) = (keys.next(), keys.next()) else {
+        return Err(InvalidKey);
+    };
+    let key = crate::trim_ows(key);
+    let valid_key = key.len() == 24
+        && key.ends_with(b"==")
+        && key[..22]
+            .iter()
+            .all(|&b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/');
+    if !valid_key {
+        return Err(InvalidKey);
+    }
+
+    let mut versions = req.headers.get_all(header::SEC_WEBSOCKET_VERSION).iter();
+    match (versions.next(), versions.next()) {
+        (Some(version), None) if crate::trim_ows(version) == b"13" => Ok(()),
+        _ => Err(UnsupportedVersion),
+    }
+}
+
+fn has_token(headers: &Headers, name: header::HeaderName, token: &[u8]) -> bool {
+    headers.get_all(name).iter().any(|value| {
+        value
+            .split(|&b| b == b',')
+            .any(|t| crate::trim_ows(t).eq_ignore_ascii_case(token))
+    })
+}
+
+/// Puts back the headers that switch a connection to WebSocket, after
+/// [strip_hop_by_hop] removed them along with the others
+fn restore_upgrade(headers: &mut Headers) {
+    headers.insert(header::CONNECTION, "upgrade".into());
+    headers.insert(header::UPGRADE, "websocket".into());
+}
+
+/// A connection that switched protocols, and whatever was read from it
+/// past the head of the message that switched it
+pub struct Upgraded<R, W> {
+    pub transport_r: R,
+    pub transport_w: W,
+    pub buf: RollMut,
+}
+
+/// How the upstream answered a WebSocket handshake, see [websocket_handshake]
+pub enum WebSocketHandshake<R, W> {
+    /// `101 Switching Protocols`: `res` goes back to the client, then the
+    /// client connection gets spliced with `upstream`, see [splice_websocket]
+    Accepted {
+        res: Response,
+        upstream: Upgraded<R, W>,
+    },
+
+    /// Anything else, which goes back to the client as-is. Its body isn't
+    /// read, and the upstream connection is dropped.
+    Refused(Response),
+}
+
+/// Sends the opening handshake `req` (see [check_websocket_handshake]) to
+/// the upstream server at the other end of `transport`, and reads its
+/// response head. Hop-by-hop headers are stripped both ways, except for
+/// those that switch protocols, then `hooks` get to change the rest. Body
+/// hooks don't apply: neither message has one.
+pub async fn websocket_handshake<T: Transport>(
+    transport: T,
+    mut req: Request,
+    hooks: Option<Rc<dyn ProxyHooks>>,
+) -> eyre::Result<WebSocketHandshake<T::Read, T::Write>> {
+    let (mut transport_r, mut transport_w) = transport.into_halves();
+
+    strip_hop_by_hop(&mut req.headers);
+    restore_upgrade(&mut req.headers);
+    req.headers.remove(header::CONTENT_LENGTH);
+    if let Some(hooks) = &hooks {
+        hooks.on_request(&mut req);
+    }
+
+    let mut out_scratch = RollMut::alloc()?;
+    let mut list = PieceList::default();
+    encode_request(req, &mut list, &mut out_scratch)?;
+    transport_w
+        .writev_all(list)
+        .await
+        .wrap_err("writing websocket handshake")?;
+
+    let (buf, mut res) = read_and_parse(
+        parse::response,
+        &mut transport_r,
+        RollMut::alloc()?,
+        64 * 1024,
+    )
+    .await
+    .map_err(|e| eyre::eyre!("error reading websocket handshake response from upstream: {e:?}"))?
+    .ok_or_else(|| eyre::eyre!("upstream went away before answering the websocket handshake"))?;
+
+    let switched = res.status == StatusCode::SWITCHING_PROTOCOLS;
+    if switched && !has_token(&res.headers, header::UPGRADE, b"websocket") {
+        return Err(eyre::eyre!(
+            "upstream switched protocols, but not to websocket"
+        ));
+    }
+
+    strip_hop_by_hop(&mut res.headers);
+    if let Some(hooks) = &hooks {
+        hooks.on_response(&mut res);
+    }
+    if !switched {
+        debug!(status = %res.status, "upstream refused the websocket handshake");
+        return Ok(WebSocketHandshake::Refused(res));
+    }
+
+    restore_upgrade(&mut res.headers);
+    Ok(WebSocketHandshake::Accepted {
+        res,
+        upstream: Upgraded {
+            transport_r,
+            transport_w,
+            buf,
+        },
+    })
+}
+
+/// How [splice_websocket] relays a connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WebSocketConf {
+    /// How long both directions may go without a byte being relayed
+    /// before the connection is closed. `None` means no limit.
+    pub idle_timeout: Option<Duration>,
+}
+
+/// What [splice_websocket] relayed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WebSocketStats {
+    pub from_client: u64,
+    pub from_upstream: u64,
+
+    /// Whether the connection was closed for being idle
+    pub timed_out: bool,
+}
+
+/// The close code for "going away", cf.
+/// <https://www.rfc-editor.org/rfc/rfc6455#section-7.4.1>
+const CLOSE_GOING_AWAY: u16 = 1001;
+
+/// Relays bytes between a client and an upstream server that both switched
+/// to WebSocket, until both are done sending.
+///
+/// Frames aren't buffered, but their boundaries are followed, so that when
+/// one side goes away without a closing handshake (or both go idle for
+/// longer than [WebSocketConf::idle_timeout]), the other gets a close frame
+/// rather than just seeing its connection drop. Close frames sent by either
+/// side are relayed like any other.
+pub async fn splice_websocket(
+    client: Upgraded<impl ReadOwned, impl WriteOwned>,
+    upstream: Upgraded<impl ReadOwned, impl WriteOwned>,
+    conf: &WebSocketConf,
+) -> eyre::Result<WebSocketStats> {
+    let activity = Cell::new(Instant::now());
+
+    let from_client = pump(
+        client.transport_r,
+        client.buf,
+        upstream.transport_w,
+        // frames sent by a client must be masked, and that's our role
+        // towards the upstream
+        true,
+        &activity,
+        conf.idle_timeout,
+    );
+    let from_upstream = pump(
+        upstream.transport_r,
+        upstream.buf,
+        client.transport_w,
+        false,
+        &activity,
+        conf.idle_timeout,
+    );
+    let (from_client, from_upstream) = tokio::try_join!(from_client, from_upstream)?;
+
+    let stats = WebSocketStats {
+        from_client: from_client.0,
+        from_upstream: from_upstream.0,
+        timed_out: from_client.1 || from_upstream.1,
+    };
+    debug!(?stats, "websocket connection done");
+    Ok(stats)
+}
+
+/// Relays one direction of a spliced connection, returns how many bytes
+/// it did, and whether it went idle
+async fn pump(
+    mut src: impl ReadOwned,
+    mut buf: RollMut,
+    mut dst: impl WriteOwned,
+    masked: bool,
+    activity: &Cell<Instant>,
+    idle_timeout: Option<Duration>,
+) -> eyre::Result<(u64, bool)> {
+    let mut frames = Frames::default();
+    let mut relayed = 0;
+
+    loop {
+        if !buf.is_empty() {
+            let roll = buf.take_all();
+            frames.feed(&roll[..]);
+            relayed += roll.len() as u64;
+            tokio::select! {
+                res = dst.write_all(roll) => res.wrap_err("relaying websocket bytes")?,
+                _ = idle(activity, idle_timeout) => {
+                    // halfway through a frame: there's no closing it cleanly
+                    debug!("websocket connection idle while writing, closing it");
+                    _ = dst.shutdown(Shutdown::Write).await;
+                    return Ok((relayed, true));
+                }
+            }
+            activity.set(Instant::now());
+        }
+
+        if buf.cap() == 0 {
+            buf.reserve()?;
+        }
+        let res;
+        tokio::select! {
+            read = buf.read_into(usize::MAX, &mut src) => (res, buf) = read,
+            _ = idle(activity, idle_timeout) => {
+                debug!("websocket connection idle, closing it");
+                frames.close(&mut dst, masked).await?;
+                return Ok((relayed, true));
+            }
+        }
+
+        if res.wrap_err("reading websocket bytes")? == 0 {
+            debug!(%relayed, "websocket peer done sending");
+            frames.close(&mut dst, masked).await?;
+            return Ok((relayed, false));
+        }
+        activity.set(Instant::now());
+    }
+}
+
+/// Resolves once nothing was relayed for `idle_timeout`
+async fn idle(activity: &Cell<Instant>, idle_timeout: Option<Duration>) {
+    let Some(idle_timeout) = idle_timeout else {
+        return std::future::pending().await;
+    };
+    loop {
+        let deadline = activity.get() + idle_timeout;
+        if Instant::now() >= deadline {
+            return;
+        }
+        tokio::time::sleep_until(deadline.into()).await;
+    }
+}
+
+/// Follows frame boundaries in one direction of a WebSocket connection,
+/// cf. <https://www.rfc-editor.org/rfc/rfc6455#section-5.2>. Only frame
+/// headers are looked at.
+#[derive(Debug, Default)]
+struct Frames {
+    /// The header of the next frame, as far as it was received
+    header: [u8; 14],
+    header_len: usize,
+
+    /// What's left of the current frame's payload
+    remaining: u64,
+
+    /// Whether a close frame went by: nothing may follow it
+    closed: bool,
+}
+
+impl Frames {
+    fn feed(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            if self.remaining > 0 {
+                let n = std::cmp::min(self.remaining, bytes.len() as u64);
+                self.remaining -= n;
+                bytes = &bytes[n as usize..];
+                continue;
+            }
+
+            self.header[self.header_len] = bytes[0];
+            self.header_len += 1;
+            bytes = &bytes[1..];
+            if self.header_len < 2 {
+                continue;
+            }
+
+            let (len_len, short_len) = match self.header[1] & 0x7f {
+                126 => (2, None),
+                127 => (8, None),
+                len => (0, Some(len as u64)),
+            };
+            let mask_len = if self.header[1] & 0x80 != 0 { 4 } else { 0 };
+            if self.header_len < 2 + len_len + mask_len {
+                continue;
+            }
+
+            self.remaining = short_len.unwrap_or_else(|| {
+                self.header[2..2 + len_len]
+                    .iter()
+                    .fold(0, |len, &b| len << 8 | b as u64)
+            });
+            if self.header[0] & 0x0f == 0x8 {
+                self.closed = true;
+            }
+            self.header_len = 0;
+        }
+    }
+
+    /// Whether a frame may be sent without cutting into another one
+    fn at_boundary(&self) -> bool {
+        self.header_len == 0 && self.remaining == 0
+    }
+
+    /// Sends a close frame to `dst` unless one went by already (or one
+    /// can't be sent without corrupting the stream), then shuts it down
+    async fn close(&self, dst: &mut impl WriteOwned, masked: bool) -> eyre::Result<()> {
+        if !self.closed && self.at_boundary() {
+            let frame = close_frame(CLOSE_GOING_AWAY, masked);
+            if let Err(e) = dst.write_all(frame).await {
+                debug!("could not send websocket close frame: {e}");
+            }
+        }
+        match dst.shutdown(Shutdown::Write).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotConnected => Ok(()),
+            Err(e) => Err(e).wrap_err("shutting down websocket connection"),
+        }
+    }
+}
+
+/// A close frame with `code` and no reason. Frames sent to servers must be
+/// masked, with a key that they can't predict.
+fn close_frame(code: u16, masked: bool) -> Vec<u8> {
+    let mut payload = code.to_be_bytes();
+    if !masked {
+        return vec![0x88, payload.len() as u8, payload[0], payload[1]];
+    }
+
+    let key = (RandomState::new().hash_one(Instant::now()) as u32).to_be_bytes();
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= key[i % 4];
+    }
+    let mut frame = vec![0x88, 0x80 | payload.len() as u8];
+    frame.extend_from_slice(&key);
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use fluke_buffet::RollMut;
+    use http::{header, StatusCode, Version};
+
+    use super::{
+        check_websocket_handshake, splice_websocket, websocket_handshake, Frames, Upgraded,
+        WebSocketConf, WebSocketHandshake, WebSocketHandshakeError,
+    };
+    use crate::{
+        maybe_uring::io::{ChanRead, ChanWrite},
+        Method, Request,
+    };
+
+    fn handshake() -> Request {
+        let mut req = Request {
+            method: Method::Get,
+            version: Version::HTTP_11,
+            ..Default::default()
+        };
+        req.headers.insert(header::UPGRADE, "websocket".into());
+        req.headers
+            .insert(header::CONNECTION, "keep-alive, Upgrade".into());
+        req.headers
+            .insert(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==".into());
+        req.headers.insert(header::SEC_WEBSOCKET_VERSION, "13".into());
+        req
+    }
+
+    #[test]
+    fn test_websocket_handshake_and_frames() {
+        assert_eq!(check_websocket_handshake(&handshake()), Ok(()));
+
+        let mut req = handshake();
+        req.headers
+            .insert(header::SEC_WEBSOCKET_KEY, "not base64 at all".into());
+        assert_eq!(
+            check_websocket_handshake(&req),
+            Err(WebSocketHandshakeError::InvalidKey)
+        );
+        let mut req = handshake();
+        req.headers.insert(header::SEC_WEBSOCKET_VERSION, "8".into());
+        assert_eq!(
+            check_websocket_handshake(&req),
+            Err(WebSocketHandshakeError::UnsupportedVersion)
+        );
+        let mut req = handshake();
+        req.headers.remove(header::CONNECTION);
+        assert_eq!(
+            check_websocket_handshake(&req),
+            Err(WebSocketHandshakeError::MissingConnectionUpgrade)
+        );
+
+        // a masked text frame with a 16-bit length, fed in bits
+        let mut frames = Frames::default();
+        let mut text = vec![0x81, 0x80 | 126, 0x01, 0x00, 1, 2, 3, 4];
+        text.extend(vec![0; 256]);
+        for (i, bit) in text.chunks(3).enumerate() {
+            assert_eq!(frames.at_boundary(), i == 0);
+            frames.feed(bit);
+        }
+        assert!(frames.at_boundary());
+        assert!(!frames.closed);
+
+        // an unmasked close frame, followed by the start of another frame
+        frames.feed(&[0x88, 0x02, 0x03, 0xe8, 0x81]);
+        assert!(frames.closed);
+        assert!(!frames.at_boundary());
+    }
+
+    #[test]
+    fn test_splice_websocket() {
+        crate::maybe_uring::start(async move {
+            for upstream_goes_away in [false, true] {
+                let (up_tx, up_read) = ChanRead::new();
+                let (mut up_rx, up_write) = ChanWrite::new();
+                let mut req = handshake();
+                req.headers.insert("keep-alive", "timeout=5".into());
+                let hs = crate::maybe_uring::spawn(websocket_handshake(
+                    (up_read, up_write),
+                    req,
+                    None,
+                ));
+
+                let mut sent = vec![];
+                while !sent.ends_with(b"\r\n\r\n") {
+                    sent.extend(up_rx.recv().await.unwrap());
+                }
+                let sent = String::from_utf8(sent).unwrap().to_lowercase();
+                assert!(sent.contains("upgrade: websocket"), "{sent}");
+                assert!(!sent.contains("keep-alive"), "{sent}");
+
+                // the upstream's first frame comes along with its response
+                up_tx
+                    .send(&b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n\x81\x02hi"[..])
+                    .await
+                    .unwrap();
+                let WebSocketHandshake::Accepted { res, upstream } = hs.await.unwrap().unwrap()
+                else {
+                    panic!("upstream handshake should have been accepted");
+                };
+                assert_eq!(res.status, StatusCode::SWITCHING_PROTOCOLS);
+                assert_eq!(upstream.buf.len(), 4);
+
+                let (cl_tx, cl_read) = ChanRead::new();
+                let (mut cl_rx, cl_write) = ChanWrite::new();
+                let client = Upgraded {
+                    transport_r: cl_read,
+                    transport_w: cl_write,
+                    buf: RollMut::alloc().unwrap(),
+                };
+                let conf = WebSocketConf {
+                    idle_timeout: Some(Duration::from_millis(200)),
+                };
+                let splice = crate::maybe_uring::spawn(async move {
+                    splice_websocket(client, upstream, &conf).await
+                });
+                assert_eq!(cl_rx.recv().await.unwrap(), b"\x81\x02hi");
+                cl_tx.send(&b"\x81\x82abcd\x09\x0b"[..]).await.unwrap();
+                assert_eq!(up_rx.recv().await.unwrap(), b"\x81\x82abcd\x09\x0b");
+
+                if upstream_goes_away {
+                    // the client gets a close frame, and sends its own
+                    drop(up_tx);
+                    assert_eq!(cl_rx.recv().await.unwrap(), b"\x88\x02\x03\xe9");
+                    assert!(cl_rx.recv().await.is_none());
+                    drop(cl_tx);
+                    assert_eq!(&up_rx.recv().await.unwrap()[..2], b"\x88\x82");
+                    assert!(up_rx.recv().await.is_none());
+                    assert!(!splice.await.unwrap().unwrap().timed_out);
+                } else {
+                    // both sides get a close frame once idle
+                    let (to_client, to_upstream) = tokio::join!(cl_rx.recv(), up_rx.recv());
+                    assert_eq!(to_client.unwrap(), b"\x88\x02\x03\xe9");
+                    assert_eq!(&to_upstream.unwrap()[..2], b"\x88\x82");
+                    let stats = splice.await.unwrap().unwrap();
+                    assert!(stats.timed_out);
+                    assert_eq!(stats.from_client, 8);
+                    assert_eq!(stats.from_upstream, 4);
+                }
+            }
+        });
+    }
+}
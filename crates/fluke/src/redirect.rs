@@ -0,0 +1,232 @@
+use std::time::Duration;
+
+use fluke_buffet::{Piece, PieceStr};
+use http::{header, HeaderName, StatusCode};
+
+use crate::{
+    host_of, Body, Encoder, ExpectResponseHeaders, Headers, Request, RequestLimits, Responder,
+    Response, ResponseDone, ServerDriver, TransportSecurity, WithHeaders,
+};
+
+/// A [ServerDriver] for cleartext listeners (typically on port 80) that
+/// sends every request over to HTTPS: same host, same path and query.
+/// Requests that don't say which host they're for get a 400.
+#[derive(Debug, Clone)]
+pub struct RedirectToHttps {
+    /// 308 Permanent Redirect (the default) has clients repeat the request
+    /// as is, 301 Moved Permanently lets them turn a `POST` into a `GET`,
+    /// which is what old clients do anyway.
+    pub status: StatusCode,
+
+    /// Port the HTTPS listener is on, `None` for the default (443)
+    pub https_port: Option<u16>,
+}
+
+impl Default for RedirectToHttps {
+    fn default() -> Self {
+        Self {
+            status: StatusCode::PERMANENT_REDIRECT,
+            https_port: None,
+        }
+    }
+}
+
+impl RedirectToHttps {
+    /// Where `req` gets redirected to, `None` if it has no host (or one that
+    /// can't go in a URL as is)
+    pub fn location(&self, req: &Request) -> Option<String> {
+        let authority = match req.uri.authority() {
+            Some(authority) => authority,
+            None => std::str::from_utf8(req.headers.get(header::HOST)?).ok()?,
+        };
+        let host = host_of(authority)?;
+        // no path, user info or anything else smuggled in the host
+        if !host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-._[]:".contains(&b))
+        {
+            return None;
+        }
+
+        // `*` (for `OPTIONS`) and authority-form targets have no path
+        let path_and_query = match req.uri.path_and_query() {
+            pq if pq.starts_with('/') => pq,
+            _ => "/",
+        };
+        Some(match self.https_port {
+            None | Some(443) => format!("https://{host}{path_and_query}"),
+            Some(port) => format!("https://{host}:{port}{path_and_query}"),
+        })
+    }
+}
+
+impl ServerDriver for RedirectToHttps {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        _req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        let mut res = Response::default();
+        match self.location(&req) {
+            Some(location) => {
+                res.status = self.status;
+                res.headers
+                    .insert(header::LOCATION, PieceStr::from(location).into_inner());
+            }
+            None => res.status = StatusCode::BAD_REQUEST,
+        }
+        respond.write_final_response_with_body(res, &mut ()).await
+    }
+}
+
+/// HTTP Strict Transport Security: tells browsers to only ever use HTTPS
+/// for this host, for `max_age`, cf. <https://www.rfc-editor.org/rfc/rfc6797>.
+/// See [AddHsts].
+#[derive(Debug, Clone, Copy)]
+pub struct Hsts {
+    pub max_age: Duration,
+
+    /// Whether subdomains of this host are HTTPS-only too
+    pub include_subdomains: bool,
+
+    /// Consent to being put on browsers' built-in lists of HTTPS-only
+    /// hosts, cf. <https://hstspreload.org/>. Hard to undo.
+    pub preload: bool,
+}
+
+impl Default for Hsts {
+    fn default() -> Self {
+        Self {
+            max_age: Duration::from_secs(365 * 24 * 60 * 60),
+            include_subdomains: false,
+            preload: false,
+        }
+    }
+}
+
+impl Hsts {
+    /// The value of the `strict-transport-security` header
+    pub fn header_value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age.as_secs());
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+}
+
+/// Wraps a [ServerDriver] so that its responses over TLS carry a
+/// `strict-transport-security` header, unless it set one already. It's
+/// never sent over cleartext, where browsers ignore it anyway: anyone on
+/// the path could have added it.
+///
+/// Goes well with [RedirectToHttps] on the cleartext listener.
+pub struct AddHsts<D> {
+    pub inner: D,
+    pub conf: Hsts,
+}
+
+impl<D: ServerDriver> ServerDriver for AddHsts<D> {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        if req.transport_security != TransportSecurity::Tls {
+            return self.inner.handle(req, req_body, respond).await;
+        }
+
+        let mut headers = Headers::default();
+        headers.insert(
+            HeaderName::from_static("strict-transport-security"),
+            self.conf.header_value().into_bytes().into(),
+        );
+        let Responder { mut encoder, state } = respond;
+        let respond = Responder {
+            encoder: WithHeaders {
+                inner: &mut encoder,
+                headers: &headers,
+            },
+            state,
+        };
+        self.inner.handle(req, req_body, respond).await?;
+
+        Ok(Responder {
+            encoder,
+            state: ResponseDone,
+        })
+    }
+
+    fn request_limits(&self, req: &Request, limits: &mut RequestLimits) {
+        self.inner.request_limits(req, limits)
+    }
+
+    fn error_response(&self, err: &eyre::Report) -> (Response, Piece) {
+        self.inner.error_response(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::header;
+
+    use super::{Hsts, RedirectToHttps};
+    use crate::{Request, RequestUri};
+
+    #[test]
+    fn test_redirect_to_https() {
+        let req = |uri: &str, host: Option<&'static str>| {
+            let mut req = Request {
+                uri: uri.parse::<RequestUri>().unwrap(),
+                ..Default::default()
+            };
+            if let Some(host) = host {
+                req.headers.insert(header::HOST, host.into());
+            }
+            req
+        };
+
+        let redirect = RedirectToHttps::default();
+        assert_eq!(
+            redirect
+                .location(&req("/a/b?c=d", Some("example.org:80")))
+                .as_deref(),
+            Some("https://example.org/a/b?c=d")
+        );
+        assert_eq!(
+            redirect
+                .location(&req("http://example.org/x", None))
+                .as_deref(),
+            Some("https://example.org/x")
+        );
+        assert_eq!(redirect.location(&req("/", None)), None);
+        assert_eq!(redirect.location(&req("/", Some("evil.example/x?"))), None);
+
+        let redirect = RedirectToHttps {
+            https_port: Some(8443),
+            ..Default::default()
+        };
+        assert_eq!(
+            redirect.location(&req("*", Some("[::1]"))).as_deref(),
+            Some("https://[::1]:8443/")
+        );
+
+        assert_eq!(Hsts::default().header_value(), "max-age=31536000");
+        let hsts = Hsts {
+            max_age: Duration::from_secs(60),
+            include_subdomains: true,
+            preload: true,
+        };
+        assert_eq!(
+            hsts.header_value(),
+            "max-age=60; includeSubDomains; preload"
+        );
+    }
+}
@@ -0,0 +1,292 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use fluke_buffet::RollMut;
+use fluke_maybe_uring::io::{ConnInfo, Transport};
+use tokio::sync::Notify;
+use tracing::Instrument;
+
+use crate::{auto::serve_auto_inner, h1, h2, AutoConf, ServerDriver};
+
+/// The connections being served, typically those accepted by one listener,
+/// so that operators can look them up and act on a specific one (e.g. kick
+/// a misbehaving client) without restarting the process. Connections get
+/// in with [ConnectionRegistry::serve_auto], and leave once they're done.
+///
+/// Cloning it is cheap, all clones refer to the same registry.
+#[derive(Clone, Default)]
+pub struct ConnectionRegistry {
+    inner: Rc<RegistryInner>,
+}
+
+#[derive(Default)]
+struct RegistryInner {
+    next_id: Cell<u64>,
+    connections: RefCell<BTreeMap<u64, LiveConnection>>,
+}
+
+impl ConnectionRegistry {
+    /// Like [serve_auto](crate::serve_auto), with the connection listed in
+    /// the registry until it's done. Everything it logs is in a `conn` span
+    /// carrying its id, and its label once it has one, see
+    /// [LiveConnection::set_label].
+    pub async fn serve_auto(
+        &self,
+        transport: impl Transport,
+        conf: Rc<AutoConf>,
+        client_buf: RollMut,
+        driver: Rc<impl ServerDriver + 'static>,
+    ) -> eyre::Result<()> {
+        let id = self.inner.next_id.get();
+        self.inner.next_id.set(id + 1);
+        let live = LiveConnection {
+            inner: Rc::new(LiveInner {
+                id,
+                conn_info: ConnInfo::of(&transport),
+                accepted_at: Instant::now(),
+                span: tracing::info_span!("conn", id, label = tracing::field::Empty),
+                label: Default::default(),
+                protocol: RefCell::new(ConnectionProtocol::Sniffing),
+                shutdown: Default::default(),
+                shutdown_notify: Default::default(),
+            }),
+        };
+        self.inner.connections.borrow_mut().insert(id, live.clone());
+        let _deregister = Deregister {
+            registry: &self.inner,
+            id,
+        };
+
+        let span = live.inner.span.clone();
+        serve_auto_inner(transport, conf, client_buf, driver, Some(&live))
+            .instrument(span)
+            .await
+    }
+
+    /// The connections being served, oldest first
+    pub fn connections(&self) -> Vec<LiveConnection> {
+        self.inner.connections.borrow().values().cloned().collect()
+    }
+
+    /// The connection with that id, if it's still being served
+    pub fn get(&self, id: u64) -> Option<LiveConnection> {
+        self.inner.connections.borrow().get(&id).cloned()
+    }
+
+    /// How many connections are being served
+    pub fn len(&self) -> usize {
+        self.inner.connections.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Shuts every connection being served down, see [LiveConnection::shutdown]
+    pub fn shutdown_all(&self, drain_timeout: Duration) {
+        for live in self.connections() {
+            live.shutdown(drain_timeout);
+        }
+    }
+}
+
+/// Takes a connection out of the registry once it's done, however it ends
+struct Deregister<'a> {
+    registry: &'a RegistryInner,
+    id: u64,
+}
+
+impl Drop for Deregister<'_> {
+    fn drop(&mut self) {
+        self.registry.connections.borrow_mut().remove(&self.id);
+    }
+}
+
+/// A connection listed in a [ConnectionRegistry]. It can be held on to
+/// after the connection is done, it just doesn't do anything anymore.
+///
+/// Cloning it is cheap, all clones refer to the same connection.
+#[derive(Clone)]
+pub struct LiveConnection {
+    inner: Rc<LiveInner>,
+}
+
+struct LiveInner {
+    id: u64,
+    conn_info: ConnInfo,
+    accepted_at: Instant,
+    span: tracing::Span,
+    label: RefCell<Option<String>>,
+    protocol: RefCell<ConnectionProtocol>,
+
+    /// Set by [LiveConnection::shutdown], for the protocol's handle to pick
+    /// up if it's called before we know which protocol it is
+    shutdown: Cell<Option<Duration>>,
+    shutdown_notify: Notify,
+}
+
+/// What a [LiveConnection] is served over, along with the protocol's
+/// handle, to inspect and steer it further
+#[derive(Clone)]
+pub enum ConnectionProtocol {
+    /// The client hasn't sent enough to tell yet
+    Sniffing,
+    H1(h1::ConnectionHandle),
+    H2(h2::ConnectionHandle),
+}
+
+impl LiveConnection {
+    /// Unique among the connections served through the same registry. Also
+    /// recorded on the connection's span.
+    pub fn id(&self) -> u64 {
+        self.inner.id
+    }
+
+    /// Addresses (and TLS details) of the connection
+    pub fn conn_info(&self) -> &ConnInfo {
+        &self.inner.conn_info
+    }
+
+    /// When the connection started being served
+    pub fn accepted_at(&self) -> Instant {
+        self.inner.accepted_at
+    }
+
+    pub fn protocol(&self) -> ConnectionProtocol {
+        self.inner.protocol.borrow().clone()
+    }
+
+    pub fn label(&self) -> Option<String> {
+        self.inner.label.borrow().clone()
+    }
+
+    /// Attaches a label to the connection, e.g. the tenant or the user
+    /// agent the operator is after. It's recorded on the connection's span,
+    /// so it shows up in everything it logs from then on.
+    pub fn set_label(&self, label: impl Into<String>) {
+        let label = label.into();
+        self.inner.span.record("label", label.as_str());
+        *self.inner.label.borrow_mut() = Some(label);
+    }
+
+    /// Shuts the connection down gracefully, like
+    /// [h1::ConnectionHandle::shutdown] or [h2::ConnectionHandle::shutdown]
+    /// do depending on the protocol. A connection whose protocol isn't known
+    /// yet is closed right away.
+    pub fn shutdown(&self, drain_timeout: Duration) {
+        match &*self.inner.protocol.borrow() {
+            ConnectionProtocol::Sniffing => {
+                self.inner.shutdown.set(Some(drain_timeout));
+                self.inner.shutdown_notify.notify_waiters();
+            }
+            ConnectionProtocol::H1(handle) => handle.shutdown(drain_timeout),
+            ConnectionProtocol::H2(handle) => handle.shutdown(drain_timeout),
+        }
+    }
+
+    /// Resolves once [LiveConnection::shutdown] is called while sniffing
+    pub(crate) async fn shutdown_requested(&self) {
+        loop {
+            let notified = self.inner.shutdown_notify.notified();
+            if self.inner.shutdown.get().is_some() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    pub(crate) fn set_protocol(&self, protocol: ConnectionProtocol) {
+        if let Some(drain_timeout) = self.inner.shutdown.get() {
+            match &protocol {
+                ConnectionProtocol::Sniffing => {}
+                ConnectionProtocol::H1(handle) => handle.shutdown(drain_timeout),
+                ConnectionProtocol::H2(handle) => handle.shutdown(drain_timeout),
+            }
+        }
+        *self.inner.protocol.borrow_mut() = protocol;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, time::Duration};
+
+    use fluke_buffet::RollMut;
+    use fluke_maybe_uring::io::{ChanRead, ChanWrite};
+    use http::StatusCode;
+
+    use super::{ConnectionProtocol, ConnectionRegistry};
+    use crate::{
+        AutoConf, Body, Encoder, ExpectResponseHeaders, Request, Responder, Response, ResponseDone,
+        ServerDriver,
+    };
+
+    struct NoContent;
+
+    impl ServerDriver for NoContent {
+        async fn handle<E: Encoder>(
+            &self,
+            _req: Request,
+            _req_body: &mut impl Body,
+            respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            let res = Response {
+                status: StatusCode::NO_CONTENT,
+                ..Default::default()
+            };
+            respond.write_final_response_with_body(res, &mut ()).await
+        }
+    }
+
+    #[test]
+    fn test_registry_shutdown() {
+        crate::maybe_uring::start(async move {
+            let registry = ConnectionRegistry::default();
+            let (tx, read) = ChanRead::new();
+            let (mut rx, write) = ChanWrite::new();
+            let serve = crate::maybe_uring::spawn({
+                let registry = registry.clone();
+                async move {
+                    registry
+                        .serve_auto(
+                            (read, write),
+                            Rc::new(AutoConf::default()),
+                            RollMut::alloc().unwrap(),
+                            Rc::new(NoContent),
+                        )
+                        .await
+                }
+            });
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            assert_eq!(registry.len(), 1);
+            let live = registry.connections().remove(0);
+            assert!(matches!(live.protocol(), ConnectionProtocol::Sniffing));
+            live.set_label("test client");
+            assert_eq!(live.label().as_deref(), Some("test client"));
+
+            tx.send(b"GET / HTTP/1.1\r\n\r\n".to_vec()).await.unwrap();
+            let mut out = vec![];
+            while !out.ends_with(b"\r\n\r\n") {
+                out.extend(rx.recv().await.unwrap());
+            }
+            assert!(out.starts_with(b"HTTP/1.1 204"), "{out:?}");
+            let ConnectionProtocol::H1(handle) = live.protocol() else {
+                panic!("not served over h1");
+            };
+            assert_eq!(handle.requests(), 1);
+
+            // idle: the connection is closed right away, and leaves
+            registry
+                .get(live.id())
+                .unwrap()
+                .shutdown(Duration::from_secs(5));
+            serve.await.unwrap().unwrap();
+            assert!(registry.is_empty());
+            assert!(registry.get(live.id()).is_none());
+        });
+    }
+}
@@ -0,0 +1,147 @@
+use std::fmt;
+#[cfg(any(feature = "h1", feature = "h2"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use fluke_buffet::PieceStr;
+use http::HeaderName;
+
+use crate::{Headers, Request};
+
+/// Longest correlation id we'll take from a client. Anything longer (or with
+/// anything but visible ASCII in it) is replaced, so clients can't use it to
+/// bloat or forge log lines.
+pub const MAX_CORRELATION_ID_LEN: usize = 128;
+
+#[cfg(any(feature = "h1", feature = "h2"))]
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Tells a request apart from all others served by this process: which
+/// connection it came in on, and where it came on that connection. Cheap
+/// enough to make for every request, it's mostly meant for logs.
+///
+/// Displayed as `{conn_id}-{seq}`, in hex.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct RequestId {
+    /// Unique per connection, across all threads
+    pub conn_id: u64,
+
+    /// Starts at 1 for the first request of a connection (or the first
+    /// stream, over HTTP/2)
+    pub seq: u64,
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:x}-{:x}", self.conn_id, self.seq)
+    }
+}
+
+/// Hands out [RequestId]s to the requests of a single connection
+#[cfg(any(feature = "h1", feature = "h2"))]
+pub(crate) struct RequestIds {
+    conn_id: u64,
+    last_seq: u64,
+}
+
+#[cfg(any(feature = "h1", feature = "h2"))]
+impl RequestIds {
+    pub(crate) fn new() -> Self {
+        Self {
+            conn_id: NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed),
+            last_seq: 0,
+        }
+    }
+
+    pub(crate) fn next(&mut self) -> RequestId {
+        self.last_seq += 1;
+        RequestId {
+            conn_id: self.conn_id,
+            seq: self.last_seq,
+        }
+    }
+}
+
+/// An id that follows a request from service to service in `header`
+/// (typically `x-request-id`), so their logs can be tied together. See
+/// `request_id_header` in [h1::ServerConf](crate::h1::ServerConf) and
+/// [h2::ServerConf](crate::h2::ServerConf).
+#[derive(Debug, Clone)]
+pub struct CorrelationId {
+    pub header: HeaderName,
+    pub value: PieceStr,
+}
+
+impl CorrelationId {
+    /// Takes the id the client sent in `header` if there's a sensible one,
+    /// otherwise makes one from `id`.
+    #[cfg(any(feature = "h1", feature = "h2"))]
+    pub(crate) fn for_request(header: &HeaderName, headers: &Headers, id: RequestId) -> Self {
+        let value = headers
+            .get(header)
+            .filter(|v| {
+                (1..=MAX_CORRELATION_ID_LEN).contains(&v.len())
+                    && v.iter().all(|b| b.is_ascii_graphic())
+            })
+            .and_then(|v| v.clone().to_str().ok())
+            .unwrap_or_else(|| id.to_string().into());
+
+        Self {
+            header: header.clone(),
+            value,
+        }
+    }
+
+    /// Sets the id on `headers`, those of a response or an upstream
+    /// request, replacing whatever value they had for it.
+    pub fn apply(&self, headers: &mut Headers) {
+        headers.insert(self.header.clone(), self.value.clone().into_inner());
+    }
+}
+
+impl Request {
+    /// Carries this request's correlation id (if it has one) over to
+    /// `upstream`, a request made on its behalf, e.g. by a proxy.
+    pub fn forward_correlation_id(&self, upstream: &mut Request) {
+        if let Some(correlation_id) = &self.correlation_id {
+            correlation_id.apply(&mut upstream.headers);
+        }
+    }
+}
+
+#[cfg(all(test, any(feature = "h1", feature = "h2")))]
+mod tests {
+    use http::HeaderName;
+
+    use super::{CorrelationId, RequestIds};
+    use crate::{Headers, Request};
+
+    #[test]
+    fn test_correlation_id() {
+        let header = HeaderName::from_static("x-request-id");
+        let mut ids = RequestIds::new();
+        let first = ids.next();
+        let second = ids.next();
+        assert_eq!(first.conn_id, second.conn_id);
+        assert_eq!((first.seq, second.seq), (1, 2));
+        assert_ne!(RequestIds::new().next(), first);
+
+        // made up when missing or unreasonable
+        let mut headers = Headers::default();
+        let cid = CorrelationId::for_request(&header, &headers, first);
+        assert_eq!(&cid.value[..], first.to_string());
+
+        headers.insert(header.clone(), "evil\r\nlog: line".into());
+        let cid = CorrelationId::for_request(&header, &headers, first);
+        assert_eq!(&cid.value[..], first.to_string());
+
+        // kept otherwise, and forwarded
+        headers.insert(header.clone(), "abc-123".into());
+        let req = Request {
+            correlation_id: Some(CorrelationId::for_request(&header, &headers, first)),
+            ..Default::default()
+        };
+        let mut upstream = Request::default();
+        req.forward_correlation_id(&mut upstream);
+        assert_eq!(&upstream.headers.get(&header).unwrap()[..], b"abc-123");
+    }
+}
@@ -1,6 +1,6 @@
-use http::header;
+use http::{header, StatusCode, Version};
 
-use crate::{h1::body::BodyWriteMode, Body, BodyChunk, Headers, HeadersExt, Response};
+use crate::{Body, BodyChunk, Headers, HeadersExt, Response};
 use fluke_buffet::Piece;
 
 pub trait ResponseState {}
@@ -30,7 +30,9 @@ where
     E: Encoder,
 {
     /// Send an informational status code, cf. <https://httpwg.org/specs/rfc9110.html#status.1xx>
-    /// Errors out if the response status is not 1xx
+    /// (e.g. 100 Continue, or 103 Early Hints). Can be called several times
+    /// before the final response, over either protocol.
+    /// Errors out if the response status is not 1xx, or if it's 101 over HTTP/2
     pub async fn write_interim_response(&mut self, res: Response) -> eyre::Result<()> {
         if !res.status.is_informational() {
             return Err(eyre::eyre!("interim response must have status code 1xx"));
@@ -63,6 +65,10 @@ where
                         .insert(header::CONTENT_LENGTH, format!("{len}").into_bytes().into());
                     BodyWriteMode::ContentLength
                 }
+                None if res.version == Version::HTTP_10 || !self.encoder.chunked_bodies() => {
+                    // no chunked transfer encoding in HTTP/1.0
+                    BodyWriteMode::CloseDelimited
+                }
                 None => {
                     res.headers
                         .insert(header::TRANSFER_ENCODING, "chunked".into());
@@ -81,6 +87,49 @@ where
     /// Writes a response with the given body. Sets `content-length` or
     /// `transfer-encoding` as needed.
     pub async fn write_final_response_with_body(
+        mut self,
+        res: Response,
+        body: &mut impl Body,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        // the headers and a body of known length go out in separate writes,
+        // back to back: let the transport merge them into full packets.
+        let corked = matches!(body.content_len(), Some(len) if len > 0);
+        if corked {
+            self.encoder.set_corked(true)?;
+        }
+
+        let mut this = self.write_final_response_and_body(res, body).await?;
+        if corked {
+            this.encoder.set_corked(false)?;
+        }
+        Ok(this)
+    }
+
+    /// Responds with 426 Upgrade Required, telling the client to retry over
+    /// one of `protocols` (e.g. `h2c`, or `websocket`), as a comma-separated
+    /// list in order of preference.
+    ///
+    /// Over HTTP/2, the `upgrade` and `connection` headers are connection-specific
+    /// and don't get sent: the response is a plain 426.
+    pub async fn write_upgrade_required(
+        self,
+        protocols: impl Into<Piece>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        let mut res = Response {
+            status: StatusCode::UPGRADE_REQUIRED,
+            ..Default::default()
+        };
+        res.headers.insert(header::UPGRADE, protocols.into());
+        res.headers.insert(header::CONNECTION, "upgrade".into());
+        res.headers.insert(header::CONTENT_LENGTH, "0".into());
+
+        self.write_final_response(res)
+            .await?
+            .finish_body(None)
+            .await
+    }
+
+    async fn write_final_response_and_body(
         self,
         mut res: Response,
         body: &mut impl Body,
@@ -97,9 +146,24 @@ where
 
         let mut this = self.write_final_response(res).await?;
 
+        let mut peeked = None;
         loop {
-            match body.next_chunk().await? {
+            let next = match peeked.take() {
+                Some(next) => next,
+                None => body.next_chunk().await?,
+            };
+            match next {
                 BodyChunk::Chunk(chunk) => {
+                    if body.eof() {
+                        // no more data is coming: unless there are trailers,
+                        // the body end can go out along with this chunk.
+                        match body.next_chunk().await? {
+                            BodyChunk::Done { trailers: None } => {
+                                return this.write_last_chunk(chunk).await;
+                            }
+                            next => peeked = Some(next),
+                        }
+                    }
                     this.write_chunk(chunk).await?;
                 }
                 BodyChunk::Done { trailers } => {
@@ -122,19 +186,43 @@ where
         self.encoder.write_body_chunk(chunk, self.state.mode).await
     }
 
+    /// Send the last response body chunk and finish the body, without
+    /// trailers. Over HTTP/1.1 with chunked transfer encoding, this saves a
+    /// write compared to [Responder::write_chunk] then [Responder::finish_body].
+    pub async fn write_last_chunk(
+        mut self,
+        chunk: Piece,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        self.encoder
+            .write_last_body_chunk(chunk, self.state.mode)
+            .await?;
+
+        Ok(Responder {
+            state: ResponseDone,
+            encoder: self.encoder,
+        })
+    }
+
     /// Finish the body, with optional trailers, cf. <https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/TE>
     /// Errors out if the sent body doesn't match the announced content-length.
-    /// Errors out if trailers that weren't announced are being sent, or if the client
-    /// didn't explicitly announce it accepted trailers, or if the response is a 204,
-    /// 205 or 304, or if the body wasn't sent with chunked transfer encoding.
+    ///
+    /// Trailers are only sent if the response had no `content-length`. Over
+    /// HTTP/1.1, the client must also have announced it accepted them, and
+    /// they go after the last chunk. Over HTTP/2, they're sent in a HEADERS
+    /// frame that ends the stream. Fields that may not be sent as trailers
+    /// (see [is_forbidden_trailer](crate::is_forbidden_trailer)) are dropped,
+    /// unless allowed in the server configuration.
     pub async fn finish_body(
         mut self,
         trailers: Option<Box<Headers>>,
     ) -> eyre::Result<Responder<E, ResponseDone>> {
-        self.encoder.write_body_end(self.state.mode).await?;
-
-        if let Some(trailers) = trailers {
-            self.encoder.write_trailers(trailers).await?;
+        match trailers {
+            Some(trailers) if self.state.mode == BodyWriteMode::Chunked => {
+                self.encoder.write_trailers(trailers).await?;
+            }
+            _ => {
+                self.encoder.write_body_end(self.state.mode).await?;
+            }
         }
 
         // TODO: check announced content-length size vs actual, etc.
@@ -155,10 +243,95 @@ where
     }
 }
 
+/// Lends an [Encoder] to a driver wrapped by a layer, adding `headers` to
+/// the final response unless the driver set them already.
+pub(crate) struct WithHeaders<'a, E> {
+    pub(crate) inner: &'a mut E,
+    pub(crate) headers: &'a Headers,
+}
+
+impl<E: Encoder> Encoder for WithHeaders<'_, E> {
+    async fn write_response(&mut self, mut res: Response) -> eyre::Result<()> {
+        if !res.status.is_informational() {
+            for name in self.headers.keys() {
+                if res.headers.contains_key(name) {
+                    continue;
+                }
+                for value in self.headers.get_all(name) {
+                    res.headers.append(name.clone(), value.clone());
+                }
+            }
+        }
+        self.inner.write_response(res).await
+    }
+
+    async fn write_body_chunk(&mut self, chunk: Piece, mode: BodyWriteMode) -> eyre::Result<()> {
+        self.inner.write_body_chunk(chunk, mode).await
+    }
+
+    async fn write_body_end(&mut self, mode: BodyWriteMode) -> eyre::Result<()> {
+        self.inner.write_body_end(mode).await
+    }
+
+    async fn write_last_body_chunk(
+        &mut self,
+        chunk: Piece,
+        mode: BodyWriteMode,
+    ) -> eyre::Result<()> {
+        self.inner.write_last_body_chunk(chunk, mode).await
+    }
+
+    async fn write_trailers(&mut self, trailers: Box<Headers>) -> eyre::Result<()> {
+        self.inner.write_trailers(trailers).await
+    }
+
+    fn set_corked(&mut self, corked: bool) -> eyre::Result<()> {
+        self.inner.set_corked(corked)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyWriteMode {
+    // we're doing chunked transfer encoding
+    Chunked,
+
+    // we set a length and are writing exactly the number of bytes we promised
+    ContentLength,
+
+    // we didn't set a content-length and we're not doing chunked transfer
+    // encoding, so we're not sending a body at all.
+    Empty,
+
+    // HTTP/1.0 has no chunked transfer encoding: without a content-length,
+    // the body ends when we close the connection.
+    CloseDelimited,
+}
+
 #[allow(async_fn_in_trait)] // we never require Send
 pub trait Encoder {
     async fn write_response(&mut self, res: Response) -> eyre::Result<()>;
     async fn write_body_chunk(&mut self, chunk: Piece, mode: BodyWriteMode) -> eyre::Result<()>;
     async fn write_body_end(&mut self, mode: BodyWriteMode) -> eyre::Result<()>;
+    /// Writes a body chunk then ends the body, encoders may do it in one go
+    async fn write_last_body_chunk(
+        &mut self,
+        chunk: Piece,
+        mode: BodyWriteMode,
+    ) -> eyre::Result<()> {
+        self.write_body_chunk(chunk, mode).await?;
+        self.write_body_end(mode).await
+    }
+    /// Ends the body with the given trailers, instead of `write_body_end`
     async fn write_trailers(&mut self, trailers: Box<Headers>) -> eyre::Result<()>;
+    /// Hint that several writes are about to follow each other, see
+    /// [WriteOwned::set_corked](fluke_maybe_uring::io::WriteOwned::set_corked)
+    fn set_corked(&mut self, _corked: bool) -> eyre::Result<()> {
+        Ok(())
+    }
+    /// Whether bodies of unknown length can go out with chunked
+    /// transfer-encoding: HTTP/1.0 clients don't know about it, so those
+    /// bodies end with the connection instead
+    fn chunked_bodies(&self) -> bool {
+        true
+    }
 }
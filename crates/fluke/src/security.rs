@@ -0,0 +1,207 @@
+use fluke_buffet::Piece;
+use http::HeaderName;
+
+use crate::{
+    Body, Encoder, ExpectResponseHeaders, Headers, Hsts, Request, RequestLimits, Responder,
+    Response, ResponseDone, ServerDriver, TransportSecurity, WithHeaders,
+};
+
+/// Response headers that tell browsers to lock things down, see
+/// [AddSecurityHeaders]. Each is skipped when `None` (or `false`).
+#[derive(Debug, Clone)]
+pub struct SecurityHeaders {
+    /// `strict-transport-security`, only ever sent over TLS
+    pub hsts: Option<Hsts>,
+
+    /// `x-content-type-options: nosniff`: browsers stick to the
+    /// `content-type` we send rather than guessing from the contents
+    pub nosniff: bool,
+
+    /// `referrer-policy`, cf. <https://www.w3.org/TR/referrer-policy/>
+    pub referrer_policy: Option<String>,
+
+    /// `content-security-policy`, as is
+    pub content_security_policy: Option<String>,
+
+    /// Who may put our pages in a frame, like `'none'` or `'self'
+    /// https://example.org`: a `frame-ancestors` directive appended to
+    /// `content_security_policy`, cf.
+    /// <https://www.w3.org/TR/CSP3/#directive-frame-ancestors>
+    pub frame_ancestors: Option<String>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        Self {
+            hsts: Some(Hsts::default()),
+            nosniff: true,
+            referrer_policy: Some("strict-origin-when-cross-origin".into()),
+            content_security_policy: None,
+            frame_ancestors: Some("'self'".into()),
+        }
+    }
+}
+
+impl SecurityHeaders {
+    /// The headers to add to a response, `tls` being whether it's sent over
+    /// TLS
+    pub fn headers(&self, tls: bool) -> Headers {
+        let mut headers = Headers::default();
+        let mut add = |name: &'static str, value: String| {
+            headers.insert(HeaderName::from_static(name), value.into_bytes().into());
+        };
+
+        if let Some(hsts) = self.hsts.as_ref().filter(|_| tls) {
+            add("strict-transport-security", hsts.header_value());
+        }
+        if self.nosniff {
+            add("x-content-type-options", "nosniff".into());
+        }
+        if let Some(policy) = &self.referrer_policy {
+            add("referrer-policy", policy.clone());
+        }
+        let csp = match (&self.content_security_policy, &self.frame_ancestors) {
+            (None, None) => None,
+            (Some(csp), None) => Some(csp.clone()),
+            (None, Some(ancestors)) => Some(format!("frame-ancestors {ancestors}")),
+            (Some(csp), Some(ancestors)) => Some(format!(
+                "{}; frame-ancestors {ancestors}",
+                csp.trim_end().trim_end_matches(';')
+            )),
+        };
+        if let Some(csp) = csp {
+            add("content-security-policy", csp);
+        }
+        headers
+    }
+}
+
+/// Which [SecurityHeaders] go on which responses: `routes` are path
+/// prefixes with headers of their own, the longest that matches wins, and
+/// `defaults` go everywhere else.
+///
+/// Prefixes match whole segments: `/docs` matches `/docs` and `/docs/a`,
+/// but not `/docsearch`.
+#[derive(Debug, Clone, Default)]
+pub struct SecurityHeadersConf {
+    pub defaults: SecurityHeaders,
+    pub routes: Vec<(String, SecurityHeaders)>,
+}
+
+impl SecurityHeadersConf {
+    pub fn for_path(&self, path: &str) -> &SecurityHeaders {
+        self.routes
+            .iter()
+            .filter(|(prefix, _)| {
+                let prefix = prefix.trim_end_matches('/');
+                path.strip_prefix(prefix)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .max_by_key(|(prefix, _)| prefix.trim_end_matches('/').len())
+            .map_or(&self.defaults, |(_, headers)| headers)
+    }
+}
+
+/// Wraps a [ServerDriver] so that its responses carry [SecurityHeaders],
+/// except for those it already set itself: the driver always has the last
+/// word.
+pub struct AddSecurityHeaders<D> {
+    pub inner: D,
+    pub conf: SecurityHeadersConf,
+}
+
+impl<D: ServerDriver> ServerDriver for AddSecurityHeaders<D> {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        let tls = req.transport_security == TransportSecurity::Tls;
+        let headers = self.conf.for_path(req.uri.path()).headers(tls);
+        if headers.is_empty() {
+            return self.inner.handle(req, req_body, respond).await;
+        }
+
+        let Responder { mut encoder, state } = respond;
+        let respond = Responder {
+            encoder: WithHeaders {
+                inner: &mut encoder,
+                headers: &headers,
+            },
+            state,
+        };
+        self.inner.handle(req, req_body, respond).await?;
+
+        Ok(Responder {
+            encoder,
+            state: ResponseDone,
+        })
+    }
+
+    fn request_limits(&self, req: &Request, limits: &mut RequestLimits) {
+        self.inner.request_limits(req, limits)
+    }
+
+    fn error_response(&self, err: &eyre::Report) -> (Response, Piece) {
+        self.inner.error_response(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SecurityHeaders, SecurityHeadersConf};
+
+    #[test]
+    fn test_security_headers() {
+        let defaults = SecurityHeaders::default();
+        let headers = defaults.headers(false);
+        assert!(!headers.contains_key("strict-transport-security"));
+        assert_eq!(
+            headers.get("x-content-type-options").map(|v| &v[..]),
+            Some(&b"nosniff"[..])
+        );
+        assert_eq!(
+            headers.get("content-security-policy").map(|v| &v[..]),
+            Some(&b"frame-ancestors 'self'"[..])
+        );
+        assert!(defaults
+            .headers(true)
+            .contains_key("strict-transport-security"));
+
+        let embeddable = SecurityHeaders {
+            content_security_policy: Some("default-src 'self';".into()),
+            frame_ancestors: Some("https://example.org".into()),
+            referrer_policy: None,
+            ..Default::default()
+        };
+        let headers = embeddable.headers(false);
+        assert_eq!(
+            headers.get("content-security-policy").map(|v| &v[..]),
+            Some(&b"default-src 'self'; frame-ancestors https://example.org"[..])
+        );
+        assert!(!headers.contains_key("referrer-policy"));
+
+        let conf = SecurityHeadersConf {
+            defaults,
+            routes: vec![
+                ("/embed/".into(), embeddable),
+                (
+                    "/embed/raw".into(),
+                    SecurityHeaders {
+                        nosniff: false,
+                        ..Default::default()
+                    },
+                ),
+            ],
+        };
+        assert!(conf.for_path("/").nosniff);
+        assert_eq!(
+            conf.for_path("/embedded").frame_ancestors.as_deref(),
+            Some("'self'")
+        );
+        assert!(conf.for_path("/embed").referrer_policy.is_none());
+        assert!(conf.for_path("/embed/a").referrer_policy.is_none());
+        assert!(!conf.for_path("/embed/raw/b").nosniff);
+    }
+}
@@ -0,0 +1,548 @@
+//! HTTP Message Signatures (RFC 9421) for requests, behind the `signatures`
+//! feature: signing outgoing requests, and verifying incoming ones, either
+//! by hand or with the [VerifySignatures] driver wrapper.
+//!
+//! Only HMAC-SHA256 keys come built in, see [HmacSha256Key]: other
+//! algorithms can be plugged in by implementing [SigningKey] and
+//! [VerifyingKey]. Covering the body takes a `content-digest` header
+//! (RFC 9530), which this doesn't compute or check.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Write as _},
+    rc::Rc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use fluke_buffet::{Piece, PieceStr};
+use hmac::{Hmac, Mac};
+use http::{header, HeaderName, StatusCode};
+use sha2::Sha256;
+use tracing::debug;
+
+use crate::{
+    Body, Encoder, ExpectResponseHeaders, Headers, Request, RequestLimits, Responder, Response,
+    ResponseDone, ServerDriver, TransportSecurity,
+};
+
+pub const SIGNATURE_INPUT: HeaderName = HeaderName::from_static("signature-input");
+pub const SIGNATURE: HeaderName = HeaderName::from_static("signature");
+
+#[derive(Debug, thiserror::Error)]
+pub enum SignatureError {
+    #[error("no signature labeled {0:?}")]
+    Missing(String),
+
+    #[error("malformed signature-input or signature header")]
+    Malformed,
+
+    #[error("unsupported component {0:?}")]
+    UnsupportedComponent(String),
+
+    #[error("covered component {0:?} is missing from the message")]
+    MissingComponent(String),
+
+    #[error("component {0:?} must be covered by the signature")]
+    NotCovered(String),
+
+    #[error("unknown key {0:?}")]
+    UnknownKey(String),
+
+    #[error("signature was created too long ago, or has expired")]
+    Expired,
+
+    #[error("signature doesn't match")]
+    Mismatch,
+}
+
+/// Produces signatures, see [sign_request]
+pub trait SigningKey {
+    /// Goes in the `alg` parameter, e.g. `hmac-sha256`
+    fn alg(&self) -> &str;
+
+    fn sign(&self, signature_base: &[u8]) -> Vec<u8>;
+}
+
+/// Checks signatures, see [verify_request]
+pub trait VerifyingKey {
+    fn alg(&self) -> &str;
+
+    /// Implementations should compare in constant time
+    fn verify(&self, signature_base: &[u8], signature: &[u8]) -> bool;
+}
+
+/// A shared secret, for the `hmac-sha256` algorithm
+#[derive(Clone)]
+pub struct HmacSha256Key(pub Vec<u8>);
+
+impl fmt::Debug for HmacSha256Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HmacSha256Key(..)")
+    }
+}
+
+impl HmacSha256Key {
+    fn mac(&self, signature_base: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC takes keys of any size");
+        mac.update(signature_base);
+        mac
+    }
+}
+
+impl SigningKey for HmacSha256Key {
+    fn alg(&self) -> &str {
+        "hmac-sha256"
+    }
+
+    fn sign(&self, signature_base: &[u8]) -> Vec<u8> {
+        self.mac(signature_base).finalize().into_bytes().to_vec()
+    }
+}
+
+impl VerifyingKey for HmacSha256Key {
+    fn alg(&self) -> &str {
+        "hmac-sha256"
+    }
+
+    fn verify(&self, signature_base: &[u8], signature: &[u8]) -> bool {
+        self.mac(signature_base).verify_slice(signature).is_ok()
+    }
+}
+
+/// What a signature covers and says about itself: the value of one
+/// `signature-input` member, cf. <https://www.rfc-editor.org/rfc/rfc9421#section-2.3>
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SignatureParams {
+    /// Derived components (`@method`, `@target-uri`, `@authority`,
+    /// `@scheme`, `@request-target`, `@path`, `@query`) and lowercase
+    /// header names, in order
+    pub components: Vec<String>,
+
+    /// Seconds since the Unix epoch
+    pub created: Option<u64>,
+    pub expires: Option<u64>,
+    pub keyid: Option<String>,
+    pub alg: Option<String>,
+    pub nonce: Option<String>,
+    pub tag: Option<String>,
+}
+
+impl fmt::Display for SignatureParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_char('(')?;
+        for (i, component) in self.components.iter().enumerate() {
+            if i > 0 {
+                f.write_char(' ')?;
+            }
+            write!(f, "\"{component}\"")?;
+        }
+        f.write_char(')')?;
+
+        if let Some(created) = self.created {
+            write!(f, ";created={created}")?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, ";expires={expires}")?;
+        }
+        for (name, value) in [
+            ("nonce", &self.nonce),
+            ("alg", &self.alg),
+            ("keyid", &self.keyid),
+            ("tag", &self.tag),
+        ] {
+            if let Some(value) = value {
+                write!(f, ";{name}=\"{value}\"")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The signature base for `req`, cf. <https://www.rfc-editor.org/rfc/rfc9421#section-2.5>:
+/// what actually gets signed.
+pub fn signature_base(req: &Request, params: &SignatureParams) -> Result<String, SignatureError> {
+    build_signature_base(req, &params.components, &params.to_string())
+}
+
+/// `raw_params` is the serialized [SignatureParams]: as received, when
+/// verifying, so that parameters stay in the order they were signed in.
+fn build_signature_base(
+    req: &Request,
+    components: &[String],
+    raw_params: &str,
+) -> Result<String, SignatureError> {
+    let mut base = String::new();
+    for component in components {
+        let value = component_value(req, component)?;
+        writeln!(base, "\"{component}\": {value}").unwrap();
+    }
+    write!(base, "\"@signature-params\": {raw_params}").unwrap();
+    Ok(base)
+}
+
+fn component_value(req: &Request, component: &str) -> Result<String, SignatureError> {
+    let scheme = || match req.uri.scheme() {
+        Some(scheme) => scheme.as_str().to_ascii_lowercase(),
+        None => match req.transport_security {
+            TransportSecurity::Plaintext => "http".to_owned(),
+            TransportSecurity::Tls => "https".to_owned(),
+        },
+    };
+    let authority = || {
+        req.uri
+            .authority()
+            .or_else(|| {
+                req.headers
+                    .get(header::HOST)
+                    .and_then(|v| std::str::from_utf8(v).ok())
+            })
+            .map(str::to_ascii_lowercase)
+            .ok_or_else(|| SignatureError::MissingComponent(component.to_owned()))
+    };
+    let path = || match req.uri.path() {
+        "" => "/",
+        path => path,
+    };
+
+    Ok(match component {
+        "@method" => req.method.as_str().to_owned(),
+        "@target-uri" => {
+            let mut uri = format!("{}://{}{}", scheme(), authority()?, path());
+            if let Some(query) = req.uri.query() {
+                write!(uri, "?{query}").unwrap();
+            }
+            uri
+        }
+        "@authority" => authority()?,
+        "@scheme" => scheme(),
+        "@request-target" => req.uri.path_and_query().to_owned(),
+        "@path" => path().to_owned(),
+        "@query" => format!("?{}", req.uri.query().unwrap_or_default()),
+        _ if component.starts_with('@') => {
+            return Err(SignatureError::UnsupportedComponent(component.to_owned()))
+        }
+        _ => {
+            let name = HeaderName::from_bytes(component.as_bytes())
+                .map_err(|_| SignatureError::UnsupportedComponent(component.to_owned()))?;
+            if name.as_str() != component {
+                // names are covered in lowercase
+                return Err(SignatureError::UnsupportedComponent(component.to_owned()));
+            }
+            let mut value = String::new();
+            for (i, v) in req.headers.get_all(&name).iter().enumerate() {
+                let v = std::str::from_utf8(v)
+                    .map_err(|_| SignatureError::UnsupportedComponent(component.to_owned()))?;
+                if i > 0 {
+                    value.push_str(", ");
+                }
+                value.push_str(v.trim());
+            }
+            if !req.headers.contains_key(&name) {
+                return Err(SignatureError::MissingComponent(component.to_owned()));
+            }
+            value
+        }
+    })
+}
+
+/// Signs `req` with `key`, adding `signature-input` and `signature` headers
+/// with a member labeled `label`. `alg` is set from the key if `params`
+/// doesn't have it.
+pub fn sign_request(
+    req: &mut Request,
+    label: &str,
+    mut params: SignatureParams,
+    key: &dyn SigningKey,
+) -> Result<(), SignatureError> {
+    if params.alg.is_none() {
+        params.alg = Some(key.alg().to_owned());
+    }
+    let base = signature_base(req, &params)?;
+    let signature = BASE64.encode(key.sign(base.as_bytes()));
+
+    for (name, value) in [
+        (SIGNATURE_INPUT, format!("{label}={params}")),
+        (SIGNATURE, format!("{label}=:{signature}:")),
+    ] {
+        req.headers.append(name, PieceStr::from(value).into_inner());
+    }
+    Ok(())
+}
+
+/// What [verify_request] requires of signatures
+#[derive(Clone)]
+pub struct VerifyConf {
+    /// The signature to check. `None` takes the first one in
+    /// `signature-input`.
+    pub label: Option<String>,
+
+    /// Components signatures must cover, e.g. `@method` and
+    /// `@target-uri`: a signature that covers nothing proves nothing.
+    pub required_components: Vec<String>,
+
+    /// Signatures without `created`, or created longer ago than this (or in
+    /// the future by more than this), are refused. `None` accepts any age.
+    pub max_age: Option<Duration>,
+
+    pub keys: Rc<dyn KeyStore>,
+}
+
+/// Finds the key to verify signatures with, from their `keyid`
+pub trait KeyStore {
+    fn key(&self, keyid: &str) -> Option<Rc<dyn VerifyingKey>>;
+}
+
+impl KeyStore for HashMap<String, Rc<dyn VerifyingKey>> {
+    fn key(&self, keyid: &str) -> Option<Rc<dyn VerifyingKey>> {
+        self.get(keyid).cloned()
+    }
+}
+
+/// Verifies a signature on `req` (see [VerifyConf]), and returns its
+/// parameters. The key is looked up by `keyid`, and must be for the
+/// signature's `alg`, if it has one.
+pub fn verify_request(req: &Request, conf: &VerifyConf) -> Result<SignatureParams, SignatureError> {
+    let inputs = dictionary(&req.headers, SIGNATURE_INPUT)?;
+    let (label, raw_params) = match &conf.label {
+        Some(label) => inputs
+            .iter()
+            .find(|(l, _)| l == label)
+            .ok_or_else(|| SignatureError::Missing(label.clone()))?,
+        None => inputs
+            .first()
+            .ok_or_else(|| SignatureError::Missing("*".to_owned()))?,
+    };
+    let params = parse_params(raw_params)?;
+
+    let signatures = dictionary(&req.headers, SIGNATURE)?;
+    let signature = signatures
+        .iter()
+        .find(|(l, _)| l == label)
+        .ok_or_else(|| SignatureError::Missing(label.clone()))?
+        .1
+        .strip_prefix(':')
+        .and_then(|s| s.strip_suffix(':'))
+        .and_then(|s| BASE64.decode(s).ok())
+        .ok_or(SignatureError::Malformed)?;
+
+    for required in &conf.required_components {
+        if !params.components.contains(required) {
+            return Err(SignatureError::NotCovered(required.clone()));
+        }
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if let Some(max_age) = conf.max_age {
+        let created = params.created.ok_or(SignatureError::Expired)?;
+        if now.abs_diff(created) > max_age.as_secs() {
+            return Err(SignatureError::Expired);
+        }
+    }
+    if matches!(params.expires, Some(expires) if expires < now) {
+        return Err(SignatureError::Expired);
+    }
+
+    let keyid = params.keyid.clone().unwrap_or_default();
+    let key = conf
+        .keys
+        .key(&keyid)
+        .ok_or_else(|| SignatureError::UnknownKey(keyid.clone()))?;
+    if matches!(&params.alg, Some(alg) if alg != key.alg()) {
+        debug!(%keyid, alg = ?params.alg, "signature algorithm doesn't match the key");
+        return Err(SignatureError::Mismatch);
+    }
+
+    let base = build_signature_base(req, &params.components, raw_params)?;
+    if !key.verify(base.as_bytes(), &signature) {
+        return Err(SignatureError::Mismatch);
+    }
+    Ok(params)
+}
+
+/// Members of a structured field dictionary, as `(key, raw value)`. Only
+/// what `signature-input` and `signature` need is supported.
+fn dictionary(
+    headers: &Headers,
+    name: HeaderName,
+) -> Result<Vec<(String, String)>, SignatureError> {
+    let mut members = vec![];
+    for value in headers.get_all(name).iter() {
+        let value = std::str::from_utf8(value).map_err(|_| SignatureError::Malformed)?;
+        let mut rest = value.trim();
+        while !rest.is_empty() {
+            let (key, value) = rest.split_once('=').ok_or(SignatureError::Malformed)?;
+            let value = value.trim_start();
+            // commas only appear in values inside strings
+            let mut in_string = false;
+            let end = value
+                .char_indices()
+                .find(|&(_, c)| {
+                    if c == '"' {
+                        in_string = !in_string;
+                    }
+                    c == ',' && !in_string
+                })
+                .map_or(value.len(), |(i, _)| i);
+            members.push((key.trim().to_owned(), value[..end].trim().to_owned()));
+            rest = value[end..].trim_start_matches(',').trim_start();
+        }
+    }
+    Ok(members)
+}
+
+/// Parses a `signature-input` member value: an inner list of strings, then
+/// parameters
+fn parse_params(s: &str) -> Result<SignatureParams, SignatureError> {
+    let s = s.strip_prefix('(').ok_or(SignatureError::Malformed)?;
+    let (list, rest) = s.split_once(')').ok_or(SignatureError::Malformed)?;
+
+    let mut params = SignatureParams::default();
+    for item in list.split_ascii_whitespace() {
+        let component = item
+            .strip_prefix('"')
+            .and_then(|item| item.strip_suffix('"'))
+            .ok_or_else(|| SignatureError::UnsupportedComponent(item.to_owned()))?;
+        params.components.push(component.to_owned());
+    }
+
+    for param in rest.split(';').skip(1) {
+        let (name, value) = param.split_once('=').ok_or(SignatureError::Malformed)?;
+        let string = || {
+            value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .map(str::to_owned)
+                .ok_or(SignatureError::Malformed)
+        };
+        let integer = || value.parse().map_err(|_| SignatureError::Malformed);
+        match name.trim() {
+            "created" => params.created = Some(integer()?),
+            "expires" => params.expires = Some(integer()?),
+            "keyid" => params.keyid = Some(string()?),
+            "alg" => params.alg = Some(string()?),
+            "nonce" => params.nonce = Some(string()?),
+            "tag" => params.tag = Some(string()?),
+            // still part of the signature base, as received
+            _ => {}
+        }
+    }
+    Ok(params)
+}
+
+/// Wraps a [ServerDriver] so that it only sees requests with a valid
+/// signature (see [verify_request]). Others get a 401 Unauthorized.
+pub struct VerifySignatures<D> {
+    pub inner: D,
+    pub conf: VerifyConf,
+}
+
+impl<D: ServerDriver> ServerDriver for VerifySignatures<D> {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> eyre::Result<Responder<E, ResponseDone>> {
+        if let Err(e) = verify_request(&req, &self.conf) {
+            debug!(id = %req.id, "refusing request: {e}");
+            let res = Response {
+                status: StatusCode::UNAUTHORIZED,
+                ..Default::default()
+            };
+            return respond.write_final_response_with_body(res, &mut ()).await;
+        }
+        self.inner.handle(req, req_body, respond).await
+    }
+
+    fn request_limits(&self, req: &Request, limits: &mut RequestLimits) {
+        self.inner.request_limits(req, limits)
+    }
+
+    fn error_response(&self, err: &eyre::Report) -> (Response, Piece) {
+        self.inner.error_response(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, rc::Rc};
+
+    use http::header;
+
+    use super::{
+        sign_request, signature_base, verify_request, HmacSha256Key, SignatureError,
+        SignatureParams, VerifyConf, VerifyingKey,
+    };
+    use crate::{Method, Request};
+
+    #[test]
+    fn test_message_signatures() {
+        let mut req = Request {
+            method: Method::Post,
+            uri: "/foo?param=Value&Pet=dog".parse().unwrap(),
+            ..Default::default()
+        };
+        req.headers.insert(header::HOST, "example.com".into());
+        req.headers
+            .insert(header::CONTENT_TYPE, "application/json".into());
+
+        let params = SignatureParams {
+            components: ["@method", "@authority", "@path", "content-type"]
+                .map(String::from)
+                .to_vec(),
+            created: Some(1618884473),
+            keyid: Some("test-shared-secret".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            signature_base(&req, &params).unwrap(),
+            "\"@method\": POST\n\"@authority\": example.com\n\"@path\": /foo\n\"content-type\": application/json\n\"@signature-params\": (\"@method\" \"@authority\" \"@path\" \"content-type\");created=1618884473;keyid=\"test-shared-secret\""
+        );
+
+        let key = HmacSha256Key(b"secret".to_vec());
+        sign_request(&mut req, "sig1", params, &key).unwrap();
+
+        let verifying: Rc<dyn VerifyingKey> = Rc::new(key);
+        let conf = VerifyConf {
+            label: None,
+            required_components: vec!["@method".to_owned()],
+            max_age: None,
+            keys: Rc::new(HashMap::from([(
+                "test-shared-secret".to_owned(),
+                verifying,
+            )])),
+        };
+        let params = verify_request(&req, &conf).unwrap();
+        assert_eq!(params.alg.as_deref(), Some("hmac-sha256"));
+
+        let mut tampered = req.clone();
+        tampered
+            .headers
+            .insert(header::CONTENT_TYPE, "text/plain".into());
+        assert!(matches!(
+            verify_request(&tampered, &conf),
+            Err(SignatureError::Mismatch)
+        ));
+
+        let strict = VerifyConf {
+            required_components: vec!["@target-uri".to_owned()],
+            ..conf.clone()
+        };
+        assert!(matches!(
+            verify_request(&req, &strict),
+            Err(SignatureError::NotCovered(_))
+        ));
+        let fresh = VerifyConf {
+            max_age: Some(std::time::Duration::from_secs(300)),
+            ..conf
+        };
+        assert!(matches!(
+            verify_request(&req, &fresh),
+            Err(SignatureError::Expired)
+        ));
+    }
+}
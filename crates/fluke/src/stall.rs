@@ -0,0 +1,143 @@
+use std::time::Duration;
+#[cfg(any(feature = "h1", feature = "h2"))]
+use std::{cell::Cell, future::Future, rc::Rc, time::Instant};
+
+#[cfg(any(feature = "h1", feature = "h2"))]
+use tracing::warn;
+
+/// What to do about a connection whose transport hasn't accepted a write in
+/// a while, typically because the peer stopped reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteStallPolicy {
+    /// Log a warning once per stall, and keep waiting
+    #[default]
+    Log,
+
+    /// Close the connection, failing with [WriteStalled]. Over HTTP/2, this
+    /// takes down every stream on the connection: when the transport itself
+    /// is stuck, there's no getting a RST_STREAM frame through either.
+    Abort,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("a write has been pending for over {timeout:?}, the peer isn't reading")]
+pub struct WriteStalled {
+    pub timeout: Duration,
+}
+
+/// When the write currently pending on a transport started, if any. Kept up
+/// to date by [BufferedWrite](crate::write_buf::BufferedWrite).
+#[cfg(any(feature = "h1", feature = "h2"))]
+pub(crate) type PendingWrite = Rc<Cell<Option<Instant>>>;
+
+/// Marks a write as pending for as long as it's alive, which includes
+/// writes whose future gets dropped halfway.
+#[cfg(any(feature = "h1", feature = "h2"))]
+pub(crate) struct PendingWriteGuard<'a>(&'a Cell<Option<Instant>>);
+
+#[cfg(any(feature = "h1", feature = "h2"))]
+impl<'a> PendingWriteGuard<'a> {
+    pub(crate) fn start(pending: &'a Cell<Option<Instant>>) -> Self {
+        pending.set(Some(Instant::now()));
+        Self(pending)
+    }
+}
+
+#[cfg(any(feature = "h1", feature = "h2"))]
+impl Drop for PendingWriteGuard<'_> {
+    fn drop(&mut self) {
+        self.0.set(None);
+    }
+}
+
+/// Runs `work` (serving a connection) and applies `policy` whenever a write
+/// has been pending for longer than `timeout`. With no timeout, this is
+/// just `work`.
+#[cfg(any(feature = "h1", feature = "h2"))]
+pub(crate) async fn watch_write_stalls<T>(
+    work: impl Future<Output = eyre::Result<T>>,
+    pending: PendingWrite,
+    timeout: Option<Duration>,
+    policy: WriteStallPolicy,
+) -> eyre::Result<T> {
+    let Some(timeout) = timeout else {
+        return work.await;
+    };
+
+    tokio::select! {
+        res = work => res,
+        stalled = watchdog(&pending, timeout, policy) => Err(stalled.into()),
+    }
+}
+
+/// Only returns if `policy` says to abort
+#[cfg(any(feature = "h1", feature = "h2"))]
+async fn watchdog(
+    pending: &Cell<Option<Instant>>,
+    timeout: Duration,
+    policy: WriteStallPolicy,
+) -> WriteStalled {
+    // the stall we've already warned about, if any
+    let mut reported = None;
+
+    loop {
+        let next_check = match pending.get() {
+            Some(since) if reported != Some(since) && since.elapsed() >= timeout => {
+                warn!(?timeout, ?policy, "write stalled, peer isn't reading");
+                if policy == WriteStallPolicy::Abort {
+                    return WriteStalled { timeout };
+                }
+                reported = Some(since);
+                timeout
+            }
+            Some(since) if reported != Some(since) => timeout.saturating_sub(since.elapsed()),
+            _ => timeout,
+        };
+        tokio::time::sleep(next_check).await;
+    }
+}
+
+#[cfg(all(test, any(feature = "h1", feature = "h2")))]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        watch_write_stalls, PendingWrite, PendingWriteGuard, WriteStallPolicy, WriteStalled,
+    };
+
+    #[test]
+    fn test_watch_write_stalls() {
+        crate::maybe_uring::start(async move {
+            let timeout = Some(Duration::from_millis(10));
+
+            // a write that never completes
+            let pending = PendingWrite::default();
+            let stuck = {
+                let pending = pending.clone();
+                async move {
+                    let _pending = PendingWriteGuard::start(&pending);
+                    std::future::pending::<eyre::Result<()>>().await
+                }
+            };
+            let err = watch_write_stalls(stuck, pending, timeout, WriteStallPolicy::Abort)
+                .await
+                .unwrap_err();
+            assert!(err.downcast_ref::<WriteStalled>().is_some());
+
+            // a slow write is only logged about
+            let pending = PendingWrite::default();
+            let slow = {
+                let pending = pending.clone();
+                async move {
+                    let _pending = PendingWriteGuard::start(&pending);
+                    tokio::time::sleep(Duration::from_millis(30)).await;
+                    Ok(())
+                }
+            };
+            watch_write_stalls(slow, pending.clone(), timeout, WriteStallPolicy::Log)
+                .await
+                .unwrap();
+            assert!(pending.get().is_none());
+        });
+    }
+}
@@ -1,6 +1,6 @@
 //! Types for HTTP headers
 
-use http::{header, HeaderMap};
+use http::{header, HeaderMap, HeaderName};
 
 use fluke_buffet::Piece;
 
@@ -10,14 +10,22 @@ pub trait HeadersExt {
     /// Returns the content-length header
     fn content_length(&self) -> Option<u64>;
 
-    /// Returns true if we have a `connection: close` header
+    /// Returns true if the `connection` header lists `close`
     fn is_connection_close(&self) -> bool;
 
+    /// Returns true if the `connection` header lists `keep-alive`, which
+    /// HTTP/1.0 connections need to be persistent
+    fn is_connection_keep_alive(&self) -> bool;
+
     /// Returns true if we have a `transfer-encoding: chunked` header
     fn is_chunked_transfer_encoding(&self) -> bool;
 
     /// Returns true if the client expects a `100-continue` response
     fn expects_100_continue(&self) -> bool;
+
+    /// Returns true if the `te` header lists `trailers`, i.e. the client is
+    /// willing to receive trailer fields
+    fn accepts_trailers(&self) -> bool;
 }
 
 impl HeadersExt for HeaderMap<Piece> {
@@ -28,8 +36,19 @@ impl HeadersExt for HeaderMap<Piece> {
     }
 
     fn is_connection_close(&self) -> bool {
-        self.get(header::CONNECTION)
-            .map_or(false, |value| value.eq_ignore_ascii_case(b"close"))
+        self.get_all(header::CONNECTION).iter().any(|value| {
+            value
+                .split(|&b| b == b',')
+                .any(|token| trim_ows(token).eq_ignore_ascii_case(b"close"))
+        })
+    }
+
+    fn is_connection_keep_alive(&self) -> bool {
+        self.get_all(header::CONNECTION).iter().any(|value| {
+            value
+                .split(|&b| b == b',')
+                .any(|token| trim_ows(token).eq_ignore_ascii_case(b"keep-alive"))
+        })
     }
 
     fn is_chunked_transfer_encoding(&self) -> bool {
@@ -41,9 +60,103 @@ impl HeadersExt for HeaderMap<Piece> {
         self.get(header::EXPECT)
             .map_or(false, |value| value.eq_ignore_ascii_case(b"100-continue"))
     }
+
+    fn accepts_trailers(&self) -> bool {
+        self.get_all(header::TE).iter().any(|value| {
+            value
+                .split(|&b| b == b',')
+                .any(|token| trim_ows(token).eq_ignore_ascii_case(b"trailers"))
+        })
+    }
+}
+
+/// Fields that must not be sent as trailers, cf.
+/// <https://httpwg.org/specs/rfc9110.html#trailers.limitations>: message
+/// framing, routing, request modifiers, authentication, and response control
+/// data or content metadata that recipients need before the content.
+const FORBIDDEN_TRAILERS: &[HeaderName] = &[
+    // framing
+    header::CONTENT_LENGTH,
+    header::TRANSFER_ENCODING,
+    header::TRAILER,
+    header::CONNECTION,
+    header::TE,
+    // routing
+    header::HOST,
+    // request modifiers
+    header::CACHE_CONTROL,
+    header::EXPECT,
+    header::MAX_FORWARDS,
+    header::PRAGMA,
+    header::RANGE,
+    header::IF_MATCH,
+    header::IF_NONE_MATCH,
+    header::IF_MODIFIED_SINCE,
+    header::IF_UNMODIFIED_SINCE,
+    header::IF_RANGE,
+    // authentication
+    header::AUTHORIZATION,
+    header::PROXY_AUTHORIZATION,
+    header::WWW_AUTHENTICATE,
+    header::PROXY_AUTHENTICATE,
+    header::COOKIE,
+    header::SET_COOKIE,
+    // response control data & content metadata
+    header::AGE,
+    header::DATE,
+    header::EXPIRES,
+    header::LOCATION,
+    header::RETRY_AFTER,
+    header::VARY,
+    header::WARNING,
+    header::CONTENT_ENCODING,
+    header::CONTENT_TYPE,
+    header::CONTENT_RANGE,
+];
+
+/// Returns true if `name` may not be sent as a trailer field, see
+/// [FORBIDDEN_TRAILERS]
+pub fn is_forbidden_trailer(name: &HeaderName) -> bool {
+    FORBIDDEN_TRAILERS.contains(name)
+}
+
+/// Returns true if `name` only makes sense for a single HTTP/1.1 connection,
+/// and must not be sent over HTTP/2, cf. <https://httpwg.org/specs/rfc9113.html#ConnectionSpecific>
+#[cfg(feature = "h2")]
+pub(crate) fn is_connection_specific(name: &HeaderName) -> bool {
+    matches!(
+        *name,
+        header::CONNECTION | header::TRANSFER_ENCODING | header::UPGRADE
+    ) || matches!(name.as_str(), "keep-alive" | "proxy-connection")
+}
+
+/// Drops trailer fields that may not be sent as trailers, unless they're
+/// explicitly listed in `allowed`.
+#[cfg(feature = "h1")]
+pub(crate) fn retain_allowed_trailers(trailers: &mut Headers, allowed: &[HeaderName]) {
+    let forbidden: Vec<HeaderName> = trailers
+        .keys()
+        .filter(|name| is_forbidden_trailer(name) && !allowed.contains(name))
+        .cloned()
+        .collect();
+    for name in forbidden {
+        tracing::debug!(%name, "dropping forbidden trailer");
+        trailers.remove(name);
+    }
+}
+
+/// Trims optional whitespace (spaces and tabs) around a list element
+pub(crate) fn trim_ows(mut bytes: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t', rest @ ..] = bytes {
+        bytes = rest;
+    }
+    while let [rest @ .., b' ' | b'\t'] = bytes {
+        bytes = rest;
+    }
+    bytes
 }
 
-fn from_digits(bytes: &[u8]) -> Option<u64> {
+pub(crate) fn from_digits(bytes: &[u8]) -> Option<u64> {
     // cannot use FromStr for u64, since it allows a signed prefix
     let mut result = 0u64;
     const RADIX: u64 = 10;
@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+/// Limits enforced on a single request. They start out from the server
+/// configuration, and drivers can adjust them for each request with
+/// [ServerDriver::request_limits](crate::ServerDriver::request_limits), e.g.
+/// to allow large bodies, or more time between pieces of them, on an upload
+/// route only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestLimits {
+    /// Max length of a request body announced with `content-length`. Larger
+    /// requests get a 413 Content Too Large response without reaching the
+    /// driver.
+    pub max_request_body_len: u64,
+
+    /// HTTP/1.1 only: how much of the request body we're willing to read and
+    /// throw away if the response goes out before it was read in full, to
+    /// keep the connection alive. Past that, the connection gets closed.
+    pub max_drain_len: u64,
+
+    /// HTTP/2 only: how long the stream may go without the peer sending any
+    /// of its request body before it's reset, `None` for no limit.
+    pub body_idle_timeout: Option<Duration>,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_request_body_len: u64::MAX,
+            max_drain_len: 64 * 1024,
+            body_idle_timeout: None,
+        }
+    }
+}
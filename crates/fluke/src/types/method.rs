@@ -3,14 +3,25 @@ use std::fmt;
 use fluke_buffet::{Piece, PieceStr};
 
 /// An HTTP method, see <https://httpwg.org/specs/rfc9110.html#methods>
+///
+/// Methods are case-sensitive: `get` is not `GET`, and ends up as
+/// [Method::Other]. Extension methods (e.g. WebDAV's `PROPFIND`) keep
+/// pointing into the buffer they were parsed from, so they don't allocate.
 #[derive(Clone)]
 pub enum Method {
     Get,
+    /// Responses to `HEAD` never have a body, even if they announce a
+    /// `content-length`, cf. <https://httpwg.org/specs/rfc9110.html#HEAD>
     Head,
     Post,
     Put,
     Delete,
+    /// Turns the connection (or h2 stream) into a tunnel: the request target
+    /// is an authority, and 2xx responses have no body framing, cf.
+    /// <https://httpwg.org/specs/rfc9110.html#CONNECT>
     Connect,
+    /// May target the server as a whole with `*` instead of a path, cf.
+    /// <https://httpwg.org/specs/rfc9110.html#OPTIONS>
     Options,
     Trace,
     Other(PieceStr),
@@ -25,7 +36,27 @@ impl fmt::Debug for Method {
 
 impl fmt::Display for Method {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let s = match self {
+        f.pad(self.as_str())
+    }
+}
+
+impl PartialEq for Method {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for Method {}
+
+impl std::hash::Hash for Method {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
             Method::Get => "GET",
             Method::Head => "HEAD",
             Method::Post => "POST",
@@ -35,12 +66,9 @@ impl fmt::Display for Method {
             Method::Options => "OPTIONS",
             Method::Trace => "TRACE",
             Method::Other(s) => s,
-        };
-        f.pad(s)
+        }
     }
-}
 
-impl Method {
     pub fn into_chunk(self) -> Piece {
         let s = match self {
             Method::Get => "GET",
@@ -72,3 +100,9 @@ impl From<PieceStr> for Method {
         }
     }
 }
+
+impl From<&'static str> for Method {
+    fn from(s: &'static str) -> Self {
+        PieceStr::from(s).into()
+    }
+}
@@ -1,9 +1,16 @@
-use std::fmt::{self, Debug};
+use std::{
+    fmt::{self, Debug},
+    net::IpAddr,
+    rc::Rc,
+};
 
-use http::{StatusCode, Uri, Version};
+use http::{StatusCode, Version};
 use tracing::debug;
 
-use fluke_buffet::Piece;
+use fluke_buffet::{Piece, PieceStr};
+use fluke_maybe_uring::io::ConnInfo;
+
+use crate::{CorrelationId, RequestId};
 
 mod headers;
 pub use headers::*;
@@ -11,40 +18,111 @@ pub use headers::*;
 mod method;
 pub use method::*;
 
+mod uri;
+pub use uri::*;
+
+mod limits;
+pub use limits::*;
+
 /// An HTTP request
 #[derive(Clone)]
 pub struct Request {
     pub method: Method,
 
     /// Requested entity
-    pub uri: Uri,
+    pub uri: RequestUri,
+
+    /// The `:protocol` pseudo-header of an extended CONNECT request over
+    /// HTTP/2 (e.g. `websocket`), cf. <https://www.rfc-editor.org/rfc/rfc8441>.
+    /// The stream then carries that protocol: the request body is what the
+    /// client sends, and the response body what it receives.
+    pub protocol: Option<PieceStr>,
 
     /// The HTTP version used
     pub version: Version,
 
     /// Request headers
     pub headers: Headers,
+
+    /// Security of the transport the request was received over. This comes
+    /// from the server configuration, not from anything the peer sent, so
+    /// unlike `uri.scheme()`, it can be trusted.
+    pub transport_security: TransportSecurity,
+
+    /// What the transport the request was received over knows about its
+    /// connection (addresses, TLS), shared by all requests on it. Empty for
+    /// requests that weren't received by a server.
+    pub conn_info: Rc<ConnInfo>,
+
+    /// Assigned by the server, for logs. All zeroes for requests that
+    /// weren't received by a server.
+    pub id: RequestId,
+
+    /// Set if the server is configured with a `request_id_header`, see
+    /// [CorrelationId]
+    pub correlation_id: Option<CorrelationId>,
+
+    /// Who sent the request: the peer, or whoever it forwarded the request
+    /// for if it's one of the server's `trusted_proxies`, see
+    /// [TrustedProxies](crate::TrustedProxies). `None` for requests that
+    /// weren't received by a server, or not over IP.
+    pub client_addr: Option<IpAddr>,
+
+    /// The HTTP/2 stream the request was received on, `None` for requests
+    /// received over HTTP/1.1 (or not received by a server). Along with
+    /// `version`, `conn_info` and `id`, it's what handlers get to know about
+    /// where the request came from.
+    pub stream_id: Option<u32>,
 }
 
 impl Default for Request {
     fn default() -> Self {
         Self {
             method: Method::Get,
-            uri: "/".parse().unwrap(),
+            uri: RequestUri::new(None, None, "/".into()),
+            protocol: None,
             version: Version::HTTP_11,
             headers: Default::default(),
+            transport_security: Default::default(),
+            conn_info: Default::default(),
+            id: Default::default(),
+            correlation_id: None,
+            client_addr: None,
+            stream_id: None,
         }
     }
 }
 
+/// Whether a connection is encrypted, as far as the server is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransportSecurity {
+    /// Cleartext TCP (or any other unencrypted transport), e.g. h2c with
+    /// prior knowledge.
+    #[default]
+    Plaintext,
+
+    /// TLS was terminated by whoever accepted the connection (e.g. h2
+    /// negotiated over ALPN).
+    Tls,
+}
+
 impl fmt::Debug for Request {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // TODO: make this better
 
         f.debug_struct("Request")
+            .field("id", &format_args!("{}", self.id))
             .field("method", &self.method)
             .field("uri", &self.uri)
             .field("version", &self.version)
+            .field("transport_security", &self.transport_security)
+            .field("peer_addr", &self.conn_info.peer_addr)
+            .field("client_addr", &self.client_addr)
+            .field("stream_id", &self.stream_id)
+            .field(
+                "correlation_id",
+                &self.correlation_id.as_ref().map(|c| &c.value),
+            )
             .finish()?;
 
         for (name, value) in &self.headers {
@@ -79,6 +157,7 @@ impl Default for Response {
 }
 
 impl Response {
+    #[cfg(feature = "client")]
     pub(crate) fn debug_print(&self) {
         debug!(code = %self.status, version = ?self.version, "got response");
         for (name, value) in &self.headers {
@@ -0,0 +1,118 @@
+use std::{fmt, str::FromStr};
+
+use http::{uri::Scheme, Uri};
+
+use fluke_buffet::PieceStr;
+
+/// The target of a request, see <https://httpwg.org/specs/rfc9110.html#target.resource>
+///
+/// This keeps the parts of the target as they were received, without
+/// validating them. Most handlers only need [RequestUri::path], and
+/// building an [http::Uri] allocates, so that only happens when asked for
+/// via [RequestUri::to_uri].
+#[derive(Clone)]
+pub struct RequestUri {
+    scheme: Option<Scheme>,
+    authority: Option<PieceStr>,
+    path_and_query: PieceStr,
+}
+
+impl RequestUri {
+    pub fn new(
+        scheme: Option<Scheme>,
+        authority: Option<PieceStr>,
+        path_and_query: PieceStr,
+    ) -> Self {
+        Self {
+            scheme,
+            authority,
+            path_and_query,
+        }
+    }
+
+    /// The scheme (`http`, `https`), if the client specified one
+    pub fn scheme(&self) -> Option<&Scheme> {
+        self.scheme.as_ref()
+    }
+
+    /// The authority (`host:port`), if the client specified one
+    pub fn authority(&self) -> Option<&str> {
+        self.authority.as_deref()
+    }
+
+    /// The path, without the query
+    pub fn path(&self) -> &str {
+        match self.path_and_query.find('?') {
+            Some(index) => &self.path_and_query[..index],
+            None => &self.path_and_query,
+        }
+    }
+
+    /// The query, without the leading `?`
+    pub fn query(&self) -> Option<&str> {
+        self.path_and_query
+            .find('?')
+            .map(|index| &self.path_and_query[index + 1..])
+    }
+
+    /// The path followed by the query, if any, as received
+    pub fn path_and_query(&self) -> &str {
+        &self.path_and_query
+    }
+
+    /// Parses and validates all parts into an [http::Uri]
+    pub fn to_uri(&self) -> Result<Uri, http::Error> {
+        let mut builder = Uri::builder();
+        if let Some(scheme) = &self.scheme {
+            builder = builder.scheme(scheme.clone());
+        }
+        if let Some(authority) = &self.authority {
+            builder = builder.authority(&authority[..]);
+        }
+        if !self.path_and_query.is_empty() {
+            builder = builder.path_and_query(&self.path_and_query[..]);
+        }
+        builder.build()
+    }
+}
+
+impl From<Uri> for RequestUri {
+    fn from(uri: Uri) -> Self {
+        let parts = uri.into_parts();
+        Self {
+            scheme: parts.scheme,
+            authority: parts.authority.map(|a| a.as_str().to_owned().into()),
+            path_and_query: parts
+                .path_and_query
+                .map(|pq| pq.as_str().to_owned().into())
+                .unwrap_or_else(|| "".into()),
+        }
+    }
+}
+
+impl FromStr for RequestUri {
+    type Err = http::uri::InvalidUri;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(s.parse::<Uri>()?.into())
+    }
+}
+
+impl fmt::Display for RequestUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(scheme) = &self.scheme {
+            write!(f, "{scheme}://")?;
+        }
+        if let Some(authority) = &self.authority {
+            f.write_str(authority)?;
+        }
+        f.write_str(&self.path_and_query)
+    }
+}
+
+impl fmt::Debug for RequestUri {
+    // forward to display, like `http::Uri` does
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
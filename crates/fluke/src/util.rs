@@ -7,6 +7,14 @@ use fluke_buffet::{Roll, RollMut};
 use fluke_maybe_uring::io::ReadOwned;
 
 /// Returns `None` on EOF, error if partially parsed message.
+///
+/// Parsers see what's buffered as one contiguous [Roll]: a unit that
+/// outgrows `buf` (a large request head, a frame payload) is moved to a
+/// larger buffer, once if the parser says how much it needs.
+///
+/// TODO: a window sliding across a chain of pool buffers would save that
+/// copy, but needs parsers that take non-contiguous input, which nom parsers
+/// over [Roll] don't.
 pub(crate) async fn read_and_parse<Parser, Output>(
     parser: Parser,
     stream: &mut impl ReadOwned,
@@ -36,12 +44,19 @@ where
                     }
 
                     let res;
-                    let read_limit = max_len - buf.len();
                     if buf.len() >= max_len {
                         return Err(SemanticError::BufferLimitReachedWhileParsing.into());
                     }
+                    let read_limit = max_len - buf.len();
 
-                    if buf.cap() == 0 {
+                    if let nom::Err::Incomplete(nom::Needed::Size(needed)) = err {
+                        // the parser knows exactly how much more it wants (e.g.
+                        // a frame payload): make room for all of it at once,
+                        // rather than growing (and copying) one step at a time.
+                        let needed = std::cmp::min(needed.get(), read_limit);
+                        trace!(%needed, "parser told us how much it needs, reserving");
+                        buf.reserve_at_least(needed)?;
+                    } else if buf.cap() == 0 {
                         trace!("buf had zero cap, reserving");
                         buf.reserve()?;
                     }
@@ -68,29 +83,115 @@ where
 
                     continue;
                 } else {
+                    debug!(?err, "parsing error");
                     if let nom::Err::Error(e) = &err {
-                        debug!(?err, "parsing error");
                         debug!(input = %e.input.to_string_lossy(), "input was");
                     }
-                    return Err(eyre::eyre!("parsing error: {err}"));
+                    return Err(SemanticError::Malformed.into());
                 }
             }
         };
     }
 }
 
+/// How much we read from connections that have nothing buffered, see
+/// [read_when_idle]
+const IDLE_READ_LEN: usize = 1024;
+
+/// Waits for the peer to send something, without holding on to a buffer from
+/// the pool while doing so: what it sends first is read into a small heap
+/// allocation, then moved into a freshly allocated [RollMut]. Reading into
+/// a [RollMut] directly would pin one of the pool's buffers for as long as
+/// the connection stays idle.
+///
+/// Returns `None` on EOF.
+pub(crate) async fn read_when_idle(stream: &mut impl ReadOwned) -> eyre::Result<Option<RollMut>> {
+    let (res, idle_buf) = stream.read(Vec::with_capacity(IDLE_READ_LEN)).await;
+    let n = res.wrap_err("reading from idle connection")?;
+    if n == 0 {
+        return Ok(None);
+    }
+
+    let mut buf = RollMut::alloc()?;
+    buf.put(&idle_buf[..n])?;
+    Ok(Some(buf))
+}
+
 #[derive(thiserror::Error, Debug)]
 pub(crate) enum SemanticError {
     #[error("buffering limit reached while parsing")]
     BufferLimitReachedWhileParsing,
+
+    /// What was read doesn't parse, e.g. a request head with a folded line,
+    /// or a space between a field name and its colon
+    #[error("malformed message")]
+    Malformed,
+
+    #[cfg(feature = "h1")]
+    #[error("request target is longer than the configured limit")]
+    UriTooLong,
+
+    /// `content-length` and `transfer-encoding` that different parsers could
+    /// read differently, see [H1BodyKind::from_headers](crate::h1::body::H1BodyKind::from_headers)
+    #[cfg(feature = "h1")]
+    #[error("ambiguous message framing")]
+    AmbiguousFraming,
+
+    #[cfg(feature = "h1")]
+    #[error("transfer coding other than chunked")]
+    UnsupportedTransferCoding,
 }
 
+#[cfg(feature = "h1")]
 impl SemanticError {
     pub(crate) fn as_http_response(&self) -> &'static [u8] {
         match self {
             Self::BufferLimitReachedWhileParsing => {
                 b"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n"
             }
+            Self::Malformed => b"HTTP/1.1 400 Bad Request\r\n\r\n",
+            Self::UriTooLong => b"HTTP/1.1 414 URI Too Long\r\n\r\n",
+            Self::AmbiguousFraming => b"HTTP/1.1 400 Bad Request\r\n\r\n",
+            Self::UnsupportedTransferCoding => b"HTTP/1.1 501 Not Implemented\r\n\r\n",
         }
     }
 }
+
+#[cfg(all(test, feature = "h1"))]
+mod tests {
+    use fluke_buffet::{RollMut, BUF_SIZE};
+    use fluke_maybe_uring::io::ChanRead;
+    use http::header;
+
+    use super::read_and_parse;
+    use crate::h1::parse::{complete_head, request};
+
+    #[test]
+    fn test_read_and_parse_head_outgrowing_buffer() {
+        crate::maybe_uring::start(async move {
+            let value = "a".repeat(BUF_SIZE as usize);
+            let head = format!("GET / HTTP/1.1\r\nx-big: {value}\r\nhost: example.org\r\n\r\n");
+            let (first, second) = head.as_bytes().split_at(BUF_SIZE as usize - 100);
+            let (first, second) = (first.to_vec(), second.to_vec());
+
+            let (tx, mut read) = ChanRead::new();
+            crate::maybe_uring::spawn(async move {
+                tx.send(first).await.unwrap();
+                tx.send(second).await.unwrap();
+            });
+
+            let (buf, req) = read_and_parse(
+                complete_head(request),
+                &mut read,
+                RollMut::alloc().unwrap(),
+                64 * 1024,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            assert!(buf.is_empty());
+            assert_eq!(&req.headers["x-big"][..], value.as_bytes());
+            assert_eq!(&req.headers[header::HOST][..], b"example.org");
+        });
+    }
+}
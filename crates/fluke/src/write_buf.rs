@@ -0,0 +1,357 @@
+use std::{
+    net::Shutdown,
+    time::{Duration, Instant},
+};
+
+use fluke_maybe_uring::{buf::IoBuf, io::WriteOwned, BufResult};
+
+use crate::stall::{PendingWrite, PendingWriteGuard};
+
+/// Gathers small writes into one buffer, so that e.g. a handful of h2 frames
+/// go out in a single write rather than one write each. Buffered bytes are
+/// written out:
+///
+///   * when the next write doesn't fit in the buffer
+///   * on the first write after they've been buffered for `flush_after`
+///   * when [WriteOwned::flush] is called, which protocols do whenever
+///     they run out of immediate work (the end of their "turn")
+///
+/// Writes larger than the buffer itself aren't copied: they go out in the
+/// same `writev` as whatever was buffered before them, so e.g. HEADERS
+/// followed by a full-size DATA frame still cost a single syscall.
+///
+/// With a `max_len` of zero, writes go straight to the inner transport.
+///
+/// Writes to the inner transport are tracked in [BufferedWrite::pending_write],
+/// so that stalls can be detected.
+pub(crate) struct BufferedWrite<W: WriteOwned> {
+    inner: W,
+    buf: Vec<u8>,
+    max_len: usize,
+    flush_after: Duration,
+
+    /// When the first byte currently in `buf` was buffered
+    oldest: Option<Instant>,
+
+    pending: PendingWrite,
+}
+
+impl<W: WriteOwned> BufferedWrite<W> {
+    pub(crate) fn new(inner: W, max_len: usize, flush_after: Duration) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            max_len,
+            flush_after,
+            oldest: None,
+            pending: Default::default(),
+        }
+    }
+
+    /// When the write currently pending on the inner transport started
+    pub(crate) fn pending_write(&self) -> PendingWrite {
+        self.pending.clone()
+    }
+
+    /// Gives the inner transport back, e.g. when switching protocols.
+    /// Buffered bytes must have been flushed.
+    #[cfg(feature = "h2")]
+    pub(crate) fn into_inner(self) -> W {
+        debug_assert!(self.buf.is_empty(), "buffered bytes were never flushed");
+        self.inner
+    }
+
+    /// Whether some bytes are waiting for [WriteOwned::flush]
+    #[cfg(any(feature = "h2", test))]
+    pub(crate) fn has_buffered(&self) -> bool {
+        !self.buf.is_empty()
+    }
+
+    /// Copies `bytes` into the buffer, returns false if they're larger than
+    /// the buffer itself, see [BufferedWrite::write_through]
+    async fn buffer(&mut self, bytes: &[&[u8]]) -> std::io::Result<bool> {
+        let len: usize = bytes.iter().map(|b| b.len()).sum();
+        if len > self.max_len {
+            return Ok(false);
+        }
+        if self.buf.len() + len > self.max_len {
+            self.flush().await?;
+        }
+
+        if self.buf.capacity() == 0 {
+            self.buf.reserve_exact(self.max_len);
+        }
+        for b in bytes {
+            self.buf.extend_from_slice(b);
+        }
+
+        match self.oldest {
+            None => self.oldest = Some(Instant::now()),
+            Some(oldest) => {
+                if oldest.elapsed() >= self.flush_after {
+                    self.flush().await?;
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Writes `list` to the inner transport, in the same `writev` as what's
+    /// buffered, if anything is. Like any `writev`, this may be a partial
+    /// write of `list`, but buffered bytes are always written out in full.
+    async fn write_through<B: IoBuf>(&mut self, list: Vec<B>) -> BufResult<usize, Vec<B>> {
+        let _pending = PendingWriteGuard::start(&self.pending);
+        if self.buf.is_empty() {
+            return self.inner.writev(list).await;
+        }
+
+        let buffered = std::mem::take(&mut self.buf);
+        let buffered_len = buffered.len();
+        self.oldest = None;
+
+        let mut combined = Vec::with_capacity(list.len() + 1);
+        combined.push(Through::Buffered(buffered));
+        combined.extend(list.into_iter().map(Through::Caller));
+        let (res, combined) = self.inner.writev(combined).await;
+
+        let mut combined = combined.into_iter();
+        let Some(Through::Buffered(mut buffered)) = combined.next() else {
+            unreachable!("writev gives buffers back in order")
+        };
+        let list: Vec<B> = combined
+            .map(|b| match b {
+                Through::Caller(b) => b,
+                Through::Buffered(_) => unreachable!("writev gives buffers back in order"),
+            })
+            .collect();
+
+        let n = match res {
+            Ok(n) => n,
+            Err(e) => return (Err(e), list),
+        };
+        if n < buffered_len {
+            // what was buffered comes before `list`, no matter what
+            if let Err(e) = self.inner.write_all(buffered.slice(n..)).await {
+                return (Err(e), list);
+            }
+            return self.inner.writev(list).await;
+        }
+
+        // keep the allocation around for the next writes
+        buffered.clear();
+        self.buf = buffered;
+        match n - buffered_len {
+            // callers take a zero-length write to mean the transport is gone
+            0 => self.inner.writev(list).await,
+            n => (Ok(n), list),
+        }
+    }
+}
+
+/// What [BufferedWrite] buffered, or a buffer it was given, so that both can
+/// go out in the same `writev`
+enum Through<B> {
+    Buffered(Vec<u8>),
+    Caller(B),
+}
+
+unsafe impl<B: IoBuf> IoBuf for Through<B> {
+    fn stable_ptr(&self) -> *const u8 {
+        match self {
+            Through::Buffered(b) => b.stable_ptr(),
+            Through::Caller(b) => b.stable_ptr(),
+        }
+    }
+
+    fn bytes_init(&self) -> usize {
+        match self {
+            Through::Buffered(b) => b.bytes_init(),
+            Through::Caller(b) => b.bytes_init(),
+        }
+    }
+
+    fn bytes_total(&self) -> usize {
+        match self {
+            Through::Buffered(b) => b.bytes_total(),
+            Through::Caller(b) => b.bytes_total(),
+        }
+    }
+}
+
+fn init_slice<B: IoBuf>(buf: &B) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(buf.stable_ptr(), buf.bytes_init()) }
+}
+
+impl<W: WriteOwned> WriteOwned for BufferedWrite<W> {
+    async fn write<B: IoBuf>(&mut self, buf: B) -> BufResult<usize, B> {
+        if self.max_len == 0 {
+            let _pending = PendingWriteGuard::start(&self.pending);
+            return self.inner.write(buf).await;
+        }
+
+        let len = buf.bytes_init();
+        match self.buffer(&[init_slice(&buf)]).await {
+            Ok(true) => (Ok(len), buf),
+            Ok(false) => {
+                let (res, mut list) = self.write_through(vec![buf]).await;
+                (res, list.pop().unwrap())
+            }
+            Err(e) => (Err(e), buf),
+        }
+    }
+
+    async fn writev<B: IoBuf>(&mut self, list: Vec<B>) -> BufResult<usize, Vec<B>> {
+        if self.max_len == 0 {
+            let _pending = PendingWriteGuard::start(&self.pending);
+            return self.inner.writev(list).await;
+        }
+
+        let slices: Vec<&[u8]> = list.iter().map(init_slice).collect();
+        let len = slices.iter().map(|s| s.len()).sum();
+        match self.buffer(&slices).await {
+            Ok(true) => (Ok(len), list),
+            Ok(false) => self.write_through(list).await,
+            Err(e) => (Err(e), list),
+        }
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf = std::mem::take(&mut self.buf);
+        let len = buf.len();
+        let mut written = 0;
+        let mut res = Ok(());
+        let pending = PendingWriteGuard::start(&self.pending);
+        while written < len {
+            let (write_res, slice) = self.inner.write(buf.slice(written..len)).await;
+            buf = slice.into_inner();
+            match write_res {
+                Ok(0) => {
+                    res = Err(std::io::Error::new(
+                        std::io::ErrorKind::WriteZero,
+                        "write zero",
+                    ));
+                    break;
+                }
+                Ok(n) => written += n,
+                Err(e) => {
+                    res = Err(e);
+                    break;
+                }
+            }
+        }
+
+        // keep the allocation around for the next writes
+        buf.clear();
+        self.buf = buf;
+        self.oldest = None;
+        res?;
+
+        self.inner.flush().await?;
+        drop(pending);
+        Ok(())
+    }
+
+    fn set_corked(&mut self, corked: bool) -> std::io::Result<()> {
+        self.inner.set_corked(corked)
+    }
+
+    async fn shutdown(&mut self, how: Shutdown) -> std::io::Result<()> {
+        self.flush().await?;
+        let _pending = PendingWriteGuard::start(&self.pending);
+        self.inner.shutdown(how).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, net::Shutdown, rc::Rc, time::Duration};
+
+    use fluke_maybe_uring::{buf::IoBuf, io::WriteOwned, BufResult};
+
+    use super::{init_slice, BufferedWrite};
+
+    /// Records every write that makes it to the transport, a `writev` being
+    /// one write. Writes are cut short past `max_write` bytes.
+    #[derive(Clone)]
+    struct Writes(Rc<RefCell<Vec<Vec<u8>>>>, usize);
+
+    impl Default for Writes {
+        fn default() -> Self {
+            Self(Default::default(), usize::MAX)
+        }
+    }
+
+    impl WriteOwned for Writes {
+        async fn write<B: IoBuf>(&mut self, buf: B) -> BufResult<usize, B> {
+            let n = buf.bytes_init().min(self.1);
+            self.0.borrow_mut().push(init_slice(&buf)[..n].to_vec());
+            (Ok(n), buf)
+        }
+
+        async fn writev<B: IoBuf>(&mut self, list: Vec<B>) -> BufResult<usize, Vec<B>> {
+            let mut bytes: Vec<u8> = list.iter().flat_map(|b| init_slice(b).to_vec()).collect();
+            bytes.truncate(self.1);
+            let n = bytes.len();
+            self.0.borrow_mut().push(bytes);
+            (Ok(n), list)
+        }
+
+        async fn shutdown(&mut self, _how: Shutdown) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_buffered_write() {
+        crate::maybe_uring::start(async move {
+            let writes = Writes::default();
+            let mut w = BufferedWrite::new(writes.clone(), 8, Duration::from_secs(60));
+
+            w.write_all("abc").await.unwrap();
+            w.writev_all(vec!["de", "f"]).await.unwrap();
+            assert!(writes.0.borrow().is_empty());
+            assert!(w.has_buffered());
+
+            // doesn't fit: what's buffered goes first, then this is buffered
+            w.write_all("ghij").await.unwrap();
+            assert_eq!(*writes.0.borrow(), vec![b"abcdef".to_vec()]);
+
+            // larger than the buffer: goes straight through, along with
+            // what was buffered
+            w.write_all("klmnopqrst").await.unwrap();
+            assert_eq!(
+                *writes.0.borrow(),
+                vec![b"abcdef".to_vec(), b"ghijklmnopqrst".to_vec()]
+            );
+            assert!(!w.has_buffered());
+
+            w.write_all("uv").await.unwrap();
+            w.flush().await.unwrap();
+            assert!(!w.has_buffered());
+            assert_eq!(writes.0.borrow().last().unwrap(), b"uv");
+
+            // past `flush_after`, the next write flushes
+            let writes = Writes::default();
+            let mut w = BufferedWrite::new(writes.clone(), 8, Duration::ZERO);
+            w.write_all("a").await.unwrap();
+            assert!(writes.0.borrow().is_empty());
+            w.write_all("b").await.unwrap();
+            assert_eq!(*writes.0.borrow(), vec![b"ab".to_vec()]);
+
+            // partial writes: what was buffered still goes out first, and
+            // in full
+            let writes = Writes(Default::default(), 3);
+            let mut w = BufferedWrite::new(writes.clone(), 8, Duration::from_secs(60));
+            w.write_all("abcde").await.unwrap();
+            w.writev_all(vec!["fghij", "klmno"]).await.unwrap();
+            w.flush().await.unwrap();
+            assert_eq!(writes.0.borrow().concat(), b"abcdefghijklmno".to_vec());
+            assert_eq!(writes.0.borrow()[0], b"abc");
+            assert_eq!(writes.0.borrow()[1], b"de");
+        });
+    }
+}
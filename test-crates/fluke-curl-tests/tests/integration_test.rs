@@ -147,10 +147,6 @@ fn request_api() {
         impl h1::ClientDriver for TestDriver {
             type Return = ();
 
-            async fn on_informational_response(&mut self, _res: Response) -> eyre::Result<()> {
-                todo!("got informational response!")
-            }
-
             async fn on_final_response(
                 self,
                 res: Response,
@@ -1147,3 +1143,205 @@ fn h2_basic_get() {
         Ok(())
     });
 }
+
+#[test]
+fn h2_interim_responses() {
+    fn client(ln_addr: SocketAddr) -> eyre::Result<Vec<String>> {
+        let mut res_headers = Vec::new();
+
+        let mut handle = Easy::new();
+        handle.http_version(HttpVersion::V2PriorKnowledge)?;
+        handle.url(&format!("http://{ln_addr}/"))?;
+
+        {
+            let mut transfer = handle.transfer();
+            transfer.header_function(|h| {
+                res_headers.push(String::from_utf8_lossy(h).trim_end().to_string());
+                true
+            })?;
+            transfer.perform()?;
+        }
+
+        assert_eq!(handle.response_code()?, 200);
+        Ok(res_headers)
+    }
+
+    struct TestDriver;
+
+    impl ServerDriver for TestDriver {
+        async fn handle<E: Encoder>(
+            &self,
+            req: Request,
+            _req_body: &mut impl Body,
+            mut respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            assert_eq!(req.version, http::Version::HTTP_2);
+            assert_eq!(req.stream_id, Some(1));
+
+            let mut headers = Headers::default();
+            headers.insert(header::LINK, "</style.css>; rel=preload; as=style".into());
+            respond
+                .write_interim_response(Response {
+                    status: StatusCode::from_u16(103)?,
+                    headers,
+                    ..Default::default()
+                })
+                .await?;
+
+            // there's no upgrading an h2 stream
+            assert!(respond
+                .write_interim_response(Response {
+                    status: StatusCode::SWITCHING_PROTOCOLS,
+                    ..Default::default()
+                })
+                .await
+                .is_err());
+
+            respond
+                .write_final_response_with_body(
+                    Response {
+                        status: StatusCode::OK,
+                        ..Default::default()
+                    },
+                    &mut (),
+                )
+                .await
+        }
+    }
+
+    helpers::run(async move {
+        let ln = fluke::maybe_uring::net::TcpListener::bind("127.0.0.1:0".parse()?).await?;
+        let ln_addr = ln.local_addr()?;
+
+        let server_fut = async move {
+            let (transport, _) = ln.accept().await?;
+            h2::serve(
+                transport.into_halves(),
+                Rc::new(h2::ServerConf::default()),
+                RollMut::alloc()?,
+                Rc::new(TestDriver),
+            )
+            .await?;
+            Ok::<_, eyre::Report>(())
+        };
+        let client_fut = async move {
+            tokio::task::spawn_blocking(move || client(ln_addr))
+                .await
+                .unwrap()
+        };
+
+        let (_, res_headers) = tokio::try_join!(server_fut, client_fut)?;
+        debug!("curl read headers: {res_headers:#?}");
+
+        let early_hints = res_headers
+            .iter()
+            .position(|h| h.starts_with("HTTP/2 103"))
+            .expect("no 103 Early Hints");
+        let ok = res_headers
+            .iter()
+            .position(|h| h.starts_with("HTTP/2 200"))
+            .expect("no final response");
+        assert!(early_hints < ok);
+        assert!(res_headers[early_hints..ok]
+            .iter()
+            .any(|h| h == "link: </style.css>; rel=preload; as=style"));
+
+        Ok(())
+    });
+}
+
+#[test]
+fn h2_handler_errors() {
+    /// Returns the status and body, or curl's error if the stream was reset
+    fn client(ln_addr: SocketAddr, path: &str) -> eyre::Result<Result<(u32, String), String>> {
+        let mut body = Vec::new();
+
+        let mut handle = Easy::new();
+        handle.http_version(HttpVersion::V2PriorKnowledge)?;
+        handle.url(&format!("http://{ln_addr}{path}"))?;
+
+        let res = {
+            let mut transfer = handle.transfer();
+            transfer.write_function(|data| {
+                body.extend_from_slice(data);
+                Ok(data.len())
+            })?;
+            transfer.perform()
+        };
+
+        Ok(match res {
+            Ok(()) => Ok((
+                handle.response_code()?,
+                String::from_utf8_lossy(&body).into_owned(),
+            )),
+            Err(e) => Err(e.to_string()),
+        })
+    }
+
+    struct TestDriver;
+
+    impl ServerDriver for TestDriver {
+        async fn handle<E: Encoder>(
+            &self,
+            req: Request,
+            _req_body: &mut impl Body,
+            respond: Responder<E, ExpectResponseHeaders>,
+        ) -> eyre::Result<Responder<E, ResponseDone>> {
+            if req.uri.path() == "/early" {
+                return Err(eyre::eyre!("backend unavailable"));
+            }
+
+            let mut respond = respond
+                .write_final_response(Response {
+                    status: StatusCode::OK,
+                    ..Default::default()
+                })
+                .await?;
+            respond.write_chunk("partial".into()).await?;
+            Err(eyre::eyre!("backend went away"))
+        }
+
+        fn error_response(&self, err: &eyre::Report) -> (Response, Piece) {
+            let res = Response {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                ..Default::default()
+            };
+            (res, format!("sorry: {err}").into_bytes().into())
+        }
+    }
+
+    helpers::run(async move {
+        let ln = fluke::maybe_uring::net::TcpListener::bind("127.0.0.1:0".parse()?).await?;
+        let ln_addr = ln.local_addr()?;
+
+        let server_fut = async move {
+            for _ in 0..2 {
+                let (transport, _) = ln.accept().await?;
+                h2::serve(
+                    transport.into_halves(),
+                    Rc::new(h2::ServerConf::default()),
+                    RollMut::alloc()?,
+                    Rc::new(TestDriver),
+                )
+                .await?;
+            }
+            Ok::<_, eyre::Report>(())
+        };
+        let client_fut = async move {
+            tokio::task::spawn_blocking(move || {
+                Ok::<_, eyre::Report>((client(ln_addr, "/early")?, client(ln_addr, "/late")?))
+            })
+            .await
+            .unwrap()
+        };
+
+        let (_, (early, late)) = tokio::try_join!(server_fut, client_fut)?;
+
+        // failing before responding gets the driver's error response
+        assert_eq!(early, Ok((503, "sorry: backend unavailable".to_string())));
+        // failing halfway through resets the stream
+        assert!(late.is_err(), "{late:?}");
+
+        Ok(())
+    });
+}
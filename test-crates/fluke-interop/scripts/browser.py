@@ -0,0 +1,61 @@
+"""Loads a page and runs fetches from a real browser, exits non-zero if any
+of them fail. Meant to run in a Playwright container.
+
+Usage: browser.py <base url> <chromium|firefox> <large body length>
+"""
+
+import sys
+
+from playwright.sync_api import sync_playwright
+
+# runs in the page: every check at once, so failures come back together
+CHECKS = """
+async (length) => {
+    const pattern = (n) => {
+        const bytes = new Uint8Array(n);
+        for (let i = 0; i < n; i++) bytes[i] = 97 + (i % 26);
+        return bytes;
+    };
+    const equal = (a, b) => a.length === b.length && a.every((v, i) => v === b[i]);
+    const failures = [];
+    const check = (ok, what) => { if (!ok) failures.push(what); };
+
+    let res = await fetch("/large/" + length);
+    check(res.ok && equal(new Uint8Array(await res.arrayBuffer()), pattern(length)),
+          "large download");
+
+    const body = pattern(length);
+    res = await fetch("/echo", { method: "POST", body });
+    check(res.ok && equal(new Uint8Array(await res.arrayBuffer()), body), "large upload");
+
+    res = await fetch("/trailers");
+    check(res.ok && (await res.text()) === "hello", "body of a response with trailers");
+
+    res = await fetch("/status/204");
+    check(res.status === 204 && (await res.text()) === "", "no content");
+
+    const many = await Promise.all(
+        Array.from({ length: 100 }, () => fetch("/hello").then((r) => r.text())));
+    check(many.every((text) => text === "hello, world"), "concurrent requests");
+
+    return failures;
+}
+"""
+
+
+def main(url, browser_name, length):
+    with sync_playwright() as p:
+        browser = getattr(p, browser_name).launch()
+        page = browser.new_page()
+        res = page.goto(f"{url}/hello")
+        assert res.ok, f"loading the page: {res.status}"
+        assert page.content().find("hello, world") != -1, page.content()
+
+        failures = page.evaluate(CHECKS, length)
+        browser.close()
+        assert not failures, f"failed: {', '.join(failures)}"
+
+
+if __name__ == "__main__":
+    url, browser_name, length = sys.argv[1:]
+    main(url, browser_name, int(length))
@@ -0,0 +1,80 @@
+"""Runs one interop scenario with httpx, exits non-zero if it fails.
+
+Usage: httpx_client.py <base url> <http1|http2> <scenario> <large body length>
+"""
+
+import asyncio
+import sys
+
+import httpx
+
+
+def pattern(length):
+    """The bytes of large bodies, as the server makes them (see driver.rs)"""
+    alphabet = bytes(range(ord("a"), ord("z") + 1))
+    return (alphabet * (length // 26 + 1))[:length]
+
+
+def client(version):
+    # over plain http, http2=True alone would still speak h1: no h1 at all
+    # makes httpx use prior knowledge
+    if version == "http2":
+        return httpx.AsyncClient(http1=False, http2=True, timeout=30)
+    return httpx.AsyncClient(timeout=30)
+
+
+def check(res, version, status=200):
+    expected = "HTTP/2" if version == "http2" else "HTTP/1.1"
+    assert res.http_version == expected, f"spoke {res.http_version}, not {expected}"
+    assert res.status_code == status, f"got {res.status_code}, not {status}"
+
+
+async def hello(url, version, _length):
+    async with client(version) as c:
+        res = await c.get(f"{url}/hello")
+        check(res, version)
+        assert res.text == "hello, world", repr(res.text)
+
+
+async def large_download(url, version, length):
+    async with client(version) as c:
+        res = await c.get(f"{url}/large/{length}")
+        check(res, version)
+        assert res.content == pattern(length), "body doesn't match"
+
+
+async def large_upload(url, version, length):
+    body = pattern(length)
+
+    async def chunks():
+        for i in range(0, length, 100_000):
+            yield body[i : i + 100_000]
+
+    async with client(version) as c:
+        # streamed, so no content-length: chunked over h1
+        res = await c.post(f"{url}/echo", content=chunks())
+        check(res, version)
+        assert res.content == body, "echoed body doesn't match"
+
+
+async def concurrency(url, version, _length):
+    async with client(version) as c:
+        responses = await asyncio.gather(*(c.get(f"{url}/hello") for _ in range(200)))
+        for res in responses:
+            check(res, version)
+            assert res.text == "hello, world", repr(res.text)
+        res = await c.get(f"{url}/status/404")
+        check(res, version, status=404)
+
+
+SCENARIOS = {
+    "hello": hello,
+    "large-download": large_download,
+    "large-upload": large_upload,
+    "concurrency": concurrency,
+}
+
+
+if __name__ == "__main__":
+    url, version, scenario, length = sys.argv[1:]
+    asyncio.run(SCENARIOS[scenario](url, version, int(length)))
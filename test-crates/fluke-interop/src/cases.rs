@@ -0,0 +1,411 @@
+use std::{fmt, path::PathBuf, process::Output, time::Duration};
+
+use tokio::process::Command;
+
+/// Size of large bodies, both ways
+pub const LARGE_LEN: usize = 16 * 1024 * 1024;
+
+/// How many requests concurrency scenarios make
+const MANY: usize = 2000;
+
+/// Where a case runs: the server's base URL, and the files it may need
+pub struct Env {
+    pub url: String,
+    /// [LARGE_LEN] bytes of [pattern](crate::driver::pattern)
+    pub upload_file: PathBuf,
+    pub scripts_dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Proto {
+    H1,
+    H2,
+}
+
+impl fmt::Display for Proto {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Proto::H1 => f.write_str("h1"),
+            Proto::H2 => f.write_str("h2"),
+        }
+    }
+}
+
+/// What a client needs to be installed for its cases to run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Requirement {
+    Binary(&'static str),
+    /// `httpx` and `h2`, importable from `python3`
+    Httpx,
+    /// A working `docker`, to run browsers in
+    Docker,
+}
+
+impl Requirement {
+    pub async fn is_met(self) -> bool {
+        let (program, args): (&str, &[&str]) = match self {
+            Requirement::Binary(name) => return which::which(name).is_ok(),
+            Requirement::Httpx => ("python3", &["-c", "import httpx, h2"]),
+            Requirement::Docker => ("docker", &["info"]),
+        };
+        Command::new(program)
+            .args(args)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .await
+            .is_ok_and(|status| status.success())
+    }
+}
+
+type Check = Box<dyn Fn(&Output) -> Result<(), String>>;
+
+pub struct Case {
+    pub client: &'static str,
+    pub proto: Proto,
+    pub scenario: &'static str,
+    pub requires: Requirement,
+    pub timeout: Duration,
+    pub command: Command,
+    /// Only called for commands that exited successfully
+    pub check: Check,
+}
+
+impl Case {
+    pub fn name(&self) -> String {
+        format!("{}/{}/{}", self.client, self.proto, self.scenario)
+    }
+}
+
+/// All cases, in the order they run
+pub fn all(env: &Env) -> Vec<Case> {
+    let mut cases = Vec::new();
+    for proto in [Proto::H1, Proto::H2] {
+        cases.extend(curl(env, proto));
+        cases.extend(h2load(env, proto));
+        cases.extend(httpx(env, proto));
+    }
+    cases.extend(nghttp(env));
+    cases.extend(browsers(env));
+    cases
+}
+
+fn case(
+    client: &'static str,
+    proto: Proto,
+    scenario: &'static str,
+    requires: Requirement,
+    command: Command,
+    check: impl Fn(&Output) -> Result<(), String> + 'static,
+) -> Case {
+    Case {
+        client,
+        proto,
+        scenario,
+        requires,
+        timeout: Duration::from_secs(30),
+        command,
+        check: Box::new(check),
+    }
+}
+
+fn command(program: &str, args: &[&str]) -> Command {
+    let mut command = Command::new(program);
+    command.args(args);
+    command
+}
+
+fn stdout_is(expected: impl AsRef<[u8]> + 'static) -> impl Fn(&Output) -> Result<(), String> {
+    move |output| {
+        let expected = expected.as_ref();
+        if output.stdout == expected {
+            Ok(())
+        } else {
+            Err(format!(
+                "expected {:?} on stdout, got {:?}",
+                String::from_utf8_lossy(expected),
+                String::from_utf8_lossy(&output.stdout[..output.stdout.len().min(256)])
+            ))
+        }
+    }
+}
+
+fn stdout_contains(needle: impl Into<String>) -> impl Fn(&Output) -> Result<(), String> {
+    let needle = needle.into();
+    move |output| {
+        if String::from_utf8_lossy(&output.stdout).contains(&needle) {
+            Ok(())
+        } else {
+            Err(format!("expected {needle:?} on stdout"))
+        }
+    }
+}
+
+fn stdout_len_is(expected: usize) -> impl Fn(&Output) -> Result<(), String> {
+    move |output| match output.stdout.len() {
+        len if len == expected => Ok(()),
+        len => Err(format!("expected {expected} bytes on stdout, got {len}")),
+    }
+}
+
+fn curl(env: &Env, proto: Proto) -> Vec<Case> {
+    let requires = Requirement::Binary("curl");
+    let version = match proto {
+        Proto::H1 => "--http1.1",
+        Proto::H2 => "--http2-prior-knowledge",
+    };
+    let curl = |args: &[&str]| {
+        let mut command = command("curl", &["-sS", "--fail", version]);
+        command.args(args);
+        command
+    };
+    let upload = format!("@{}", env.upload_file.display());
+    let url = &env.url;
+
+    vec![
+        case(
+            "curl",
+            proto,
+            "hello",
+            requires,
+            curl(&[&format!("{url}/hello")]),
+            stdout_is("hello, world"),
+        ),
+        case(
+            "curl",
+            proto,
+            "trailers",
+            requires,
+            // trailers are dumped along with headers
+            curl(&["-D", "-", "-o", "/dev/null", &format!("{url}/trailers")]),
+            stdout_contains("x-checksum: abc123"),
+        ),
+        case(
+            "curl",
+            proto,
+            "100-continue",
+            requires,
+            // waits longer than the case may take for `100 Continue`, so
+            // that not getting one fails
+            curl(&[
+                "-H",
+                "expect: 100-continue",
+                "--expect100-timeout",
+                "60",
+                "--data-binary",
+                &upload,
+                "-o",
+                "/dev/null",
+                "-w",
+                "%{http_code} %{size_download}",
+                &format!("{url}/echo"),
+            ]),
+            stdout_is(format!("200 {LARGE_LEN}")),
+        ),
+        case(
+            "curl",
+            proto,
+            "large-download",
+            requires,
+            curl(&[
+                "-o",
+                "/dev/null",
+                "-w",
+                "%{http_code} %{size_download}",
+                &format!("{url}/large/{LARGE_LEN}"),
+            ]),
+            stdout_is(format!("200 {LARGE_LEN}")),
+        ),
+        case(
+            "curl",
+            proto,
+            "large-upload",
+            requires,
+            // no `expect: 100-continue`, which curl sends for large bodies
+            curl(&[
+                "-H",
+                "expect:",
+                "--data-binary",
+                &upload,
+                "-o",
+                "/dev/null",
+                "-w",
+                "%{http_code} %{size_download}",
+                &format!("{url}/echo"),
+            ]),
+            stdout_is(format!("200 {LARGE_LEN}")),
+        ),
+    ]
+}
+
+fn nghttp(env: &Env) -> Vec<Case> {
+    let requires = Requirement::Binary("nghttp");
+    let upload = env.upload_file.display().to_string();
+    let url = &env.url;
+
+    vec![
+        case(
+            "nghttp",
+            Proto::H2,
+            "hello",
+            requires,
+            command("nghttp", &[&format!("{url}/hello")]),
+            stdout_is("hello, world"),
+        ),
+        case(
+            "nghttp",
+            Proto::H2,
+            "trailers",
+            requires,
+            // received frames are printed, trailers included
+            command("nghttp", &["-v", &format!("{url}/trailers")]),
+            stdout_contains("x-checksum: abc123"),
+        ),
+        case(
+            "nghttp",
+            Proto::H2,
+            "100-continue",
+            requires,
+            command(
+                "nghttp",
+                &["--expect-continue", "-d", &upload, &format!("{url}/echo")],
+            ),
+            stdout_len_is(LARGE_LEN),
+        ),
+        case(
+            "nghttp",
+            Proto::H2,
+            "large-download",
+            requires,
+            command("nghttp", &[&format!("{url}/large/{LARGE_LEN}")]),
+            stdout_len_is(LARGE_LEN),
+        ),
+        case(
+            "nghttp",
+            Proto::H2,
+            "large-upload",
+            requires,
+            command("nghttp", &["-d", &upload, &format!("{url}/echo")]),
+            stdout_len_is(LARGE_LEN),
+        ),
+    ]
+}
+
+fn h2load(env: &Env, proto: Proto) -> Vec<Case> {
+    let requires = Requirement::Binary("h2load");
+    let h2load = |args: &[&str]| {
+        let mut command = command("h2load", args);
+        if proto == Proto::H1 {
+            command.arg("--h1");
+        }
+        command
+    };
+    let upload = env.upload_file.display().to_string();
+    let url = &env.url;
+
+    vec![
+        case(
+            "h2load",
+            proto,
+            "concurrency",
+            requires,
+            h2load(&[
+                "-n",
+                &MANY.to_string(),
+                "-c",
+                "20",
+                "-m",
+                "10",
+                &format!("{url}/hello"),
+            ]),
+            stdout_contains(format!("{MANY} succeeded, 0 failed, 0 errored")),
+        ),
+        case(
+            "h2load",
+            proto,
+            "concurrent-uploads",
+            requires,
+            h2load(&[
+                "-n",
+                "20",
+                "-c",
+                "4",
+                "-m",
+                "2",
+                "-d",
+                &upload,
+                &format!("{url}/echo"),
+            ]),
+            stdout_contains("20 succeeded, 0 failed, 0 errored"),
+        ),
+    ]
+}
+
+fn httpx(env: &Env, proto: Proto) -> Vec<Case> {
+    let script = env
+        .scripts_dir
+        .join("httpx_client.py")
+        .display()
+        .to_string();
+    let version = match proto {
+        Proto::H1 => "http1",
+        Proto::H2 => "http2",
+    };
+    // the script checks responses itself
+    ["hello", "large-download", "large-upload", "concurrency"]
+        .into_iter()
+        .map(|scenario| {
+            case(
+                "httpx",
+                proto,
+                scenario,
+                Requirement::Httpx,
+                command(
+                    "python3",
+                    &[&script, &env.url, version, scenario, &LARGE_LEN.to_string()],
+                ),
+                |_| Ok(()),
+            )
+        })
+        .collect()
+}
+
+fn browsers(env: &Env) -> Vec<Case> {
+    let image = std::env::var("FLUKE_INTEROP_PLAYWRIGHT_IMAGE")
+        .unwrap_or_else(|_| "mcr.microsoft.com/playwright/python:v1.44.0-jammy".into());
+    let scripts = format!("{}:/scripts:ro", env.scripts_dir.display());
+
+    ["chromium", "firefox"]
+        .into_iter()
+        .map(|browser| {
+            let mut case = case(
+                browser,
+                Proto::H1,
+                "fetch",
+                Requirement::Docker,
+                // the host's network, so that the server is on localhost
+                command(
+                    "docker",
+                    &[
+                        "run",
+                        "--rm",
+                        "--network",
+                        "host",
+                        "-v",
+                        &scripts,
+                        &image,
+                        "python3",
+                        "/scripts/browser.py",
+                        &env.url,
+                        browser,
+                        &LARGE_LEN.to_string(),
+                    ],
+                ),
+                |_| Ok(()),
+            );
+            // the image may have to be pulled first
+            case.timeout = Duration::from_secs(600);
+            case
+        })
+        .collect()
+}
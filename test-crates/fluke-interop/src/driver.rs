@@ -0,0 +1,108 @@
+use fluke::{
+    http::{header, StatusCode},
+    Body, BodyChunk, Encoder, ExpectResponseHeaders, Headers, HeadersExt, Request, Responder,
+    Response, ResponseDone, ServerDriver,
+};
+
+/// Large bodies are written in chunks of that size
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// What clients are run against, the same over h1 and h2:
+///
+/// - `GET /hello`: `hello, world`, with a `content-length`
+/// - `POST /echo`: the request body, as it was received
+/// - `GET /trailers`: `hello`, streamed, then an `x-checksum: abc123` trailer
+/// - `GET /large/{len}`: `len` bytes of [pattern], streamed
+/// - `GET /status/{code}`: an empty response with that status
+///
+/// `100 Continue` is sent to requests that expect it, before reading their
+/// body.
+pub struct InteropDriver;
+
+impl ServerDriver for InteropDriver {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        mut respond: Responder<E, ExpectResponseHeaders>,
+    ) -> color_eyre::Result<Responder<E, ResponseDone>> {
+        if req.headers.expects_100_continue() {
+            let res = Response {
+                status: StatusCode::CONTINUE,
+                ..Default::default()
+            };
+            respond.write_interim_response(res).await?;
+        }
+
+        let mut body = Vec::new();
+        while let BodyChunk::Chunk(chunk) = req_body.next_chunk().await? {
+            body.extend_from_slice(&chunk[..]);
+        }
+        tracing::debug!(method = %req.method, uri = %req.uri, body_len = body.len(), "handling");
+
+        let mut res = Response {
+            version: req.version,
+            ..Default::default()
+        };
+        let path = req.uri.path();
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        match segments.as_slice() {
+            ["hello"] => {
+                res.headers
+                    .insert(header::CONTENT_TYPE, "text/plain".into());
+                res.headers.insert(header::CONTENT_LENGTH, "12".into());
+                respond
+                    .write_final_response(res)
+                    .await?
+                    .write_last_chunk("hello, world".into())
+                    .await
+            }
+            ["echo"] => {
+                res.headers.insert(
+                    header::CONTENT_LENGTH,
+                    body.len().to_string().into_bytes().into(),
+                );
+                let mut respond = respond.write_final_response(res).await?;
+                for chunk in body.chunks(CHUNK_SIZE) {
+                    respond.write_chunk(chunk.to_vec().into()).await?;
+                }
+                respond.finish_body(None).await
+            }
+            ["trailers"] => {
+                let mut respond = respond.write_final_response(res).await?;
+                respond.write_chunk("hello".into()).await?;
+                let mut trailers = Headers::default();
+                trailers.insert("x-checksum", "abc123".into());
+                respond.finish_body(Some(Box::new(trailers))).await
+            }
+            ["large", len] if len.parse::<usize>().is_ok() => {
+                let len: usize = len.parse()?;
+                res.headers
+                    .insert(header::CONTENT_LENGTH, len.to_string().into_bytes().into());
+                let mut respond = respond.write_final_response(res).await?;
+                let mut written = 0;
+                while written < len {
+                    let chunk: Vec<u8> =
+                        pattern(written..std::cmp::min(written + CHUNK_SIZE, len)).collect();
+                    written += chunk.len();
+                    respond.write_chunk(chunk.into()).await?;
+                }
+                respond.finish_body(None).await
+            }
+            ["status", code] if code.parse::<StatusCode>().is_ok() => {
+                res.status = code.parse()?;
+                respond.write_final_response_with_body(res, &mut ()).await
+            }
+            _ => {
+                res.status = StatusCode::NOT_FOUND;
+                respond.write_final_response_with_body(res, &mut ()).await
+            }
+        }
+    }
+}
+
+/// The bytes of large bodies, at the given offsets: lowercase letters, in
+/// order. Scripts check bodies against it.
+pub fn pattern(range: std::ops::Range<usize>) -> impl Iterator<Item = u8> {
+    range.map(|i| b'a' + (i % 26) as u8)
+}
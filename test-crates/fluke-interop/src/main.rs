@@ -0,0 +1,143 @@
+use std::{net::SocketAddr, path::Path, process::Stdio, rc::Rc, time::Instant};
+
+use fluke::{buffet::RollMut, maybe_uring::net::TcpListener, AutoConf};
+use tracing_subscriber::EnvFilter;
+
+mod cases;
+mod driver;
+
+use cases::{Case, Env, LARGE_LEN};
+
+fn main() {
+    color_eyre::install().unwrap();
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|e| {
+            eprintln!("Couldn't parse RUST_LOG: {e}");
+            EnvFilter::try_new("info").unwrap()
+        }))
+        .init();
+
+    // cases whose name contains any of these run, all of them if there are none
+    let filters: Vec<String> = std::env::args().skip(1).filter(|arg| arg != "--").collect();
+    let strict = std::env::var("FLUKE_INTEROP_STRICT").is_ok_and(|v| v == "1");
+
+    let passed =
+        fluke::maybe_uring::start(async move { real_main(filters, strict).await.unwrap() });
+    if !passed {
+        std::process::exit(1);
+    }
+}
+
+enum Outcome {
+    Pass,
+    Fail(String),
+    Skip(String),
+}
+
+async fn real_main(filters: Vec<String>, strict: bool) -> color_eyre::Result<bool> {
+    let addr = spawn_server("127.0.0.1:0".parse()?).await?;
+
+    let upload_file = std::env::temp_dir().join(format!("fluke-interop-{}.bin", addr.port()));
+    std::fs::write(
+        &upload_file,
+        driver::pattern(0..LARGE_LEN).collect::<Vec<_>>(),
+    )?;
+    let env = Env {
+        url: format!("http://{addr}"),
+        upload_file: upload_file.clone(),
+        scripts_dir: Path::new(env!("CARGO_MANIFEST_DIR")).join("scripts"),
+    };
+
+    let mut cases: Vec<Case> = cases::all(&env)
+        .into_iter()
+        .filter(|case| filters.is_empty() || filters.iter().any(|f| case.name().contains(f)))
+        .collect();
+
+    let mut failures = 0;
+    for case in &mut cases {
+        let start = Instant::now();
+        let outcome = run(case).await;
+        let elapsed = start.elapsed();
+        let name = case.name();
+        match outcome {
+            Outcome::Pass => println!("PASS {name} ({elapsed:.2?})"),
+            Outcome::Skip(reason) if !strict => println!("SKIP {name}: {reason}"),
+            Outcome::Skip(reason) | Outcome::Fail(reason) => {
+                failures += 1;
+                println!("FAIL {name} ({elapsed:.2?}): {reason}");
+            }
+        }
+    }
+    println!("{} cases, {failures} failed", cases.len());
+
+    _ = std::fs::remove_file(upload_file);
+    Ok(failures == 0)
+}
+
+async fn run(case: &mut Case) -> Outcome {
+    if !case.requires.is_met().await {
+        return Outcome::Skip(format!("{:?} not available", case.requires));
+    }
+
+    let child = case
+        .command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn();
+    let child = match child {
+        Ok(child) => child,
+        Err(e) => return Outcome::Fail(format!("couldn't spawn: {e}")),
+    };
+
+    let output = match tokio::time::timeout(case.timeout, child.wait_with_output()).await {
+        Err(_) => return Outcome::Fail(format!("timed out after {:?}", case.timeout)),
+        Ok(Err(e)) => return Outcome::Fail(format!("couldn't wait: {e}")),
+        Ok(Ok(output)) => output,
+    };
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        return Outcome::Fail(format!(
+            "{}\n--- stdout\n{}\n--- stderr\n{}",
+            output.status,
+            tail(&stdout),
+            tail(&stderr)
+        ));
+    }
+    match (case.check)(&output) {
+        Ok(()) => Outcome::Pass,
+        Err(reason) => Outcome::Fail(reason),
+    }
+}
+
+/// The last few lines of a command's output, for failure reports
+fn tail(output: &str) -> String {
+    let lines: Vec<&str> = output.lines().collect();
+    lines[lines.len().saturating_sub(20)..].join("\n")
+}
+
+async fn spawn_server(addr: SocketAddr) -> color_eyre::Result<SocketAddr> {
+    let ln = TcpListener::bind(addr).await?;
+    let addr = ln.local_addr()?;
+    tracing::info!("Listening on {addr}");
+
+    let conf = Rc::new(AutoConf::default());
+    let driver = Rc::new(driver::InteropDriver);
+    tokio::task::spawn_local(async move {
+        loop {
+            let (stream, peer) = ln.accept().await.unwrap();
+            let conf = conf.clone();
+            let driver = driver.clone();
+            tokio::task::spawn_local(async move {
+                let client_buf = RollMut::alloc().unwrap();
+                if let Err(e) = fluke::serve_auto(stream, conf, client_buf, driver).await {
+                    tracing::debug!("error serving {peer}: {e:?}");
+                }
+            });
+        }
+    });
+
+    Ok(addr)
+}
@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use fluke::{
+    http::{header, StatusCode},
+    Body, BodyChunk, Encoder, ExpectResponseHeaders, Request, Responder, Response, ResponseDone,
+    ServerDriver,
+};
+
+/// Large bodies are written in chunks of that size
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// What the load is made of, the same over h1 and h2:
+///
+/// - `GET /hello`: `hello, world`, with a `content-length`
+/// - `POST /echo`: the request body, streamed back as it's read
+/// - `GET /large/{len}`: `len` bytes of [pattern], streamed
+/// - `GET /slow/{ms}`: `done`, after that many milliseconds
+pub struct SoakDriver;
+
+impl ServerDriver for SoakDriver {
+    async fn handle<E: Encoder>(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> color_eyre::Result<Responder<E, ResponseDone>> {
+        let mut res = Response {
+            version: req.version,
+            ..Default::default()
+        };
+        let path = req.uri.path();
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        match segments.as_slice() {
+            ["hello"] => {
+                res.headers.insert(header::CONTENT_LENGTH, "12".into());
+                respond
+                    .write_final_response(res)
+                    .await?
+                    .write_last_chunk("hello, world".into())
+                    .await
+            }
+            ["echo"] => {
+                if let Some(len) = req_body.content_len() {
+                    res.headers
+                        .insert(header::CONTENT_LENGTH, len.to_string().into_bytes().into());
+                }
+                let mut respond = respond.write_final_response(res).await?;
+                while let BodyChunk::Chunk(chunk) = req_body.next_chunk().await? {
+                    respond.write_chunk(chunk).await?;
+                }
+                respond.finish_body(None).await
+            }
+            ["large", len] if len.parse::<usize>().is_ok() => {
+                let len: usize = len.parse()?;
+                res.headers
+                    .insert(header::CONTENT_LENGTH, len.to_string().into_bytes().into());
+                let mut respond = respond.write_final_response(res).await?;
+                let mut written = 0;
+                while written < len {
+                    let chunk: Vec<u8> =
+                        pattern(written..std::cmp::min(written + CHUNK_SIZE, len)).collect();
+                    written += chunk.len();
+                    respond.write_chunk(chunk.into()).await?;
+                }
+                respond.finish_body(None).await
+            }
+            ["slow", ms] if ms.parse::<u64>().is_ok() => {
+                tokio::time::sleep(Duration::from_millis(ms.parse()?)).await;
+                res.headers.insert(header::CONTENT_LENGTH, "4".into());
+                respond
+                    .write_final_response(res)
+                    .await?
+                    .write_last_chunk("done".into())
+                    .await
+            }
+            _ => {
+                res.status = StatusCode::NOT_FOUND;
+                respond.write_final_response_with_body(res, &mut ()).await
+            }
+        }
+    }
+}
+
+/// The bytes of large bodies, at the given offsets: lowercase letters, in
+/// order
+pub fn pattern(range: std::ops::Range<usize>) -> impl Iterator<Item = u8> {
+    range.map(|i| b'a' + (i % 26) as u8)
+}
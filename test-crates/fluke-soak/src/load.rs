@@ -0,0 +1,284 @@
+use std::{cell::Cell, net::SocketAddr, rc::Rc, time::Duration};
+
+use fluke::{
+    buffet::Piece,
+    h1::ClientDriver,
+    h2::{ClientConf, ClientConnection},
+    http::{header, StatusCode},
+    maybe_uring::{
+        io::IntoHalves,
+        net::{TcpReadHalf, TcpStream, TcpWriteHalf},
+    },
+    Body, BodyChunk, Method, Request, Response,
+};
+use tokio::time::error::Elapsed;
+
+use crate::driver::pattern;
+
+/// Size of `/echo` request bodies
+const ECHO_LEN: usize = 64 * 1024;
+
+/// Size of `/large` responses
+const LARGE_LEN: usize = 1024 * 1024;
+
+/// Clients close their connection and open a new one after that many
+/// requests, so that connection setup and teardown are part of the load
+const RECONNECT_EVERY: usize = 200;
+
+/// What workers send, in turn
+const MIX: [Kind; 10] = [
+    Kind::Hello,
+    Kind::Hello,
+    Kind::Echo,
+    Kind::Hello,
+    Kind::Large,
+    Kind::Hello,
+    Kind::Echo,
+    Kind::Abandoned,
+    Kind::Hello,
+    Kind::Cancelled,
+];
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    /// A small response
+    Hello,
+    /// A request body, echoed back
+    Echo,
+    /// A large response, read to the end
+    Large,
+    /// A large response, dropped after its first chunk
+    Abandoned,
+    /// A slow response, given up on before it comes
+    Cancelled,
+}
+
+impl Kind {
+    fn request(self) -> (Request, PatternBody) {
+        let (method, path, body_len) = match self {
+            Kind::Hello => (Method::Get, "/hello".to_string(), 0),
+            Kind::Echo => (Method::Post, "/echo".to_string(), ECHO_LEN),
+            Kind::Large | Kind::Abandoned => (Method::Get, format!("/large/{LARGE_LEN}"), 0),
+            Kind::Cancelled => (Method::Get, "/slow/200".to_string(), 0),
+        };
+        let mut req = Request {
+            method,
+            uri: path.parse().unwrap(),
+            ..Default::default()
+        };
+        req.headers.insert(header::HOST, "soak".into());
+        let body = PatternBody {
+            len: body_len,
+            written: 0,
+        };
+        (req, body)
+    }
+
+    fn timeout(self) -> Duration {
+        match self {
+            Kind::Cancelled => Duration::from_millis(20),
+            _ => Duration::from_secs(30),
+        }
+    }
+
+    fn driver(self) -> Consume {
+        Consume {
+            limit: match self {
+                Kind::Abandoned => Some(1),
+                _ => None,
+            },
+        }
+    }
+
+    fn check(
+        self,
+        res: Result<color_eyre::Result<(StatusCode, usize)>, Elapsed>,
+    ) -> Result<(), String> {
+        let expected_len = match self {
+            Kind::Hello => Some(12),
+            Kind::Echo => Some(ECHO_LEN),
+            Kind::Large => Some(LARGE_LEN),
+            Kind::Abandoned => None,
+            Kind::Cancelled => {
+                return match res {
+                    Err(_) => Ok(()),
+                    Ok(_) => Err("came back before it could be cancelled".into()),
+                }
+            }
+        };
+        match res {
+            Err(_) => Err(format!("timed out after {:?}", self.timeout())),
+            Ok(Err(e)) => Err(format!("{e:?}")),
+            Ok(Ok((status, _))) if status != StatusCode::OK => Err(format!("got {status}")),
+            Ok(Ok((_, len))) if expected_len.is_some_and(|expected| len != expected) => Err(
+                format!("got {len} bytes, expected {}", expected_len.unwrap()),
+            ),
+            Ok(Ok(_)) => Ok(()),
+        }
+    }
+}
+
+/// Requests sent by all workers so far
+#[derive(Default)]
+pub struct LoadStats {
+    pub requests: Cell<u64>,
+    pub failures: Cell<u64>,
+}
+
+impl LoadStats {
+    fn record(&self, kind: Kind, outcome: Result<(), String>) {
+        match outcome {
+            Ok(()) => self.requests.set(self.requests.get() + 1),
+            Err(e) => self.fail(format!("{kind:?} request failed: {e}")),
+        }
+    }
+
+    fn fail(&self, reason: String) {
+        self.requests.set(self.requests.get() + 1);
+        self.failures.set(self.failures.get() + 1);
+        tracing::warn!("{reason}");
+    }
+}
+
+/// Sends requests over HTTP/1.1, one at a time, keeping the connection alive
+/// in between when it can, until `stop` is set
+pub async fn h1_worker(addr: SocketAddr, stats: Rc<LoadStats>, stop: Rc<Cell<bool>>) {
+    let mut conn: Option<(TcpReadHalf, TcpWriteHalf)> = None;
+    let mut i = 0;
+    while !stop.get() {
+        let kind = MIX[i % MIX.len()];
+        i += 1;
+        if i % RECONNECT_EVERY == 0 {
+            conn = None;
+        }
+
+        let transport = match conn.take() {
+            Some(halves) => halves,
+            None => match TcpStream::connect(addr).await {
+                Ok(stream) => stream.into_halves(),
+                Err(e) => {
+                    stats.fail(format!("connecting: {e}"));
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+        };
+        let (req, mut body) = kind.request();
+        let res = tokio::time::timeout(
+            kind.timeout(),
+            fluke::h1::request(transport, req, &mut body, kind.driver()),
+        )
+        .await
+        .map(|res| {
+            res.map(|(halves, ret)| {
+                // only there if the connection can be reused
+                conn = halves;
+                ret
+            })
+        });
+        stats.record(kind, kind.check(res));
+    }
+}
+
+/// Sends requests over HTTP/2, `concurrency` at a time on the same
+/// connection, until `stop` is set
+pub async fn h2_worker(
+    addr: SocketAddr,
+    concurrency: usize,
+    stats: Rc<LoadStats>,
+    stop: Rc<Cell<bool>>,
+) {
+    let conf = Rc::new(ClientConf::default());
+    let mut i = 0;
+    while !stop.get() {
+        let client = match TcpStream::connect(addr).await {
+            Ok(stream) => ClientConnection::connect(stream, conf.clone()).await,
+            Err(e) => Err(e.into()),
+        };
+        let client = match client {
+            Ok(client) => client,
+            Err(e) => {
+                stats.fail(format!("connecting: {e:?}"));
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        for _ in 0..(RECONNECT_EVERY / concurrency).max(1) {
+            if stop.get() {
+                break;
+            }
+            let mut tasks = Vec::with_capacity(concurrency);
+            for _ in 0..concurrency {
+                let kind = MIX[i % MIX.len()];
+                i += 1;
+                let send = client.send_request();
+                let stats = stats.clone();
+                tasks.push(tokio::task::spawn_local(async move {
+                    let (req, mut body) = kind.request();
+                    let res = tokio::time::timeout(
+                        kind.timeout(),
+                        send.request(req, &mut body, kind.driver()),
+                    )
+                    .await;
+                    stats.record(kind, kind.check(res));
+                }));
+            }
+            for task in tasks {
+                _ = task.await;
+            }
+        }
+    }
+}
+
+/// Reads response bodies, up to `limit` bytes if there's one, and returns
+/// the status and how much was read
+struct Consume {
+    limit: Option<usize>,
+}
+
+impl ClientDriver for Consume {
+    type Return = (StatusCode, usize);
+
+    async fn on_final_response(
+        self,
+        res: Response,
+        body: &mut impl Body,
+    ) -> color_eyre::Result<Self::Return> {
+        let mut len = 0;
+        while let BodyChunk::Chunk(chunk) = body.next_chunk().await? {
+            len += chunk.len();
+            if self.limit.is_some_and(|limit| len >= limit) {
+                break;
+            }
+        }
+        Ok((res.status, len))
+    }
+}
+
+/// `len` bytes of [pattern], in chunks
+#[derive(Debug)]
+struct PatternBody {
+    len: usize,
+    written: usize,
+}
+
+impl Body for PatternBody {
+    fn content_len(&self) -> Option<u64> {
+        Some(self.len as u64)
+    }
+
+    fn eof(&self) -> bool {
+        self.written == self.len
+    }
+
+    async fn next_chunk(&mut self) -> color_eyre::Result<BodyChunk> {
+        if self.eof() {
+            return Ok(BodyChunk::Done { trailers: None });
+        }
+        let end = std::cmp::min(self.written + 16 * 1024, self.len);
+        let chunk: Vec<u8> = pattern(self.written..end).collect();
+        self.written = end;
+        Ok(BodyChunk::Chunk(Piece::from(chunk)))
+    }
+}
@@ -0,0 +1,309 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    net::SocketAddr,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use fluke::{
+    buffet::{num_free_bufs, RollMut, NUM_BUF},
+    h2::ConnectionHandle,
+    maybe_uring::net::TcpListener,
+};
+use tracing_subscriber::EnvFilter;
+
+mod driver;
+mod load;
+mod samples;
+
+use load::LoadStats;
+use samples::Sample;
+
+const USAGE: &str = "\
+Usage: fluke-soak [options]
+
+  --duration SECS       how long the load runs (default: 60)
+  --interval SECS       time between samples (default: 1)
+  --warmup SECS         samples taken before that are ignored (default: a fifth of the duration)
+  --connections N       client connections per protocol (default: 4)
+  --concurrency N       requests in flight per h2 connection (default: 8)
+  --rss-slack-mib MIB   RSS growth that isn't considered a leak (default: 16)";
+
+struct Args {
+    duration: Duration,
+    interval: Duration,
+    warmup: Option<Duration>,
+    connections: usize,
+    concurrency: usize,
+    rss_slack_mib: f64,
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut args = Args {
+            duration: Duration::from_secs(60),
+            interval: Duration::from_secs(1),
+            warmup: None,
+            connections: 4,
+            concurrency: 8,
+            rss_slack_mib: 16.0,
+        };
+        let secs = |value: &str| {
+            value
+                .parse::<f64>()
+                .ok()
+                .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                .ok_or_else(|| format!("invalid number of seconds: {value:?}"))
+        };
+        let count = |value: &str| match value.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("invalid count: {value:?}")),
+        };
+
+        let mut iter = std::env::args().skip(1).filter(|arg| arg != "--");
+        while let Some(flag) = iter.next() {
+            if flag == "--help" {
+                println!("{USAGE}");
+                std::process::exit(0);
+            }
+            let value = iter
+                .next()
+                .ok_or_else(|| format!("missing value for {flag}"))?;
+            match flag.as_str() {
+                "--duration" => args.duration = secs(&value)?,
+                "--interval" => args.interval = secs(&value)?,
+                "--warmup" => args.warmup = Some(secs(&value)?),
+                "--connections" => args.connections = count(&value)?,
+                "--concurrency" => args.concurrency = count(&value)?,
+                "--rss-slack-mib" => {
+                    args.rss_slack_mib = value
+                        .parse()
+                        .map_err(|_| format!("invalid size: {value:?}"))?
+                }
+                _ => return Err(format!("unknown option {flag}")),
+            }
+        }
+        if args.interval.is_zero() {
+            return Err("the interval can't be zero".into());
+        }
+        Ok(args)
+    }
+}
+
+fn main() {
+    color_eyre::install().unwrap();
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|e| {
+            eprintln!("Couldn't parse RUST_LOG: {e}");
+            EnvFilter::try_new("warn").unwrap()
+        }))
+        .init();
+
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+
+    let passed = fluke::maybe_uring::start(async move { real_main(args).await.unwrap() });
+    if !passed {
+        std::process::exit(1);
+    }
+}
+
+async fn real_main(args: Args) -> color_eyre::Result<bool> {
+    let server = Server::spawn().await?;
+    let stats = Rc::new(LoadStats::default());
+    let stop = Rc::new(Cell::new(false));
+    let start = Instant::now();
+    let baseline = server.sample(start, &stats);
+
+    let mut workers = Vec::new();
+    for _ in 0..args.connections {
+        workers.push(tokio::task::spawn_local(load::h1_worker(
+            server.h1_addr,
+            stats.clone(),
+            stop.clone(),
+        )));
+        workers.push(tokio::task::spawn_local(load::h2_worker(
+            server.h2_addr,
+            args.concurrency,
+            stats.clone(),
+            stop.clone(),
+        )));
+    }
+
+    println!(
+        "Soaking for {:?}: {} h1 and {} h2 connections, against {} and {}",
+        args.duration, args.connections, args.connections, server.h1_addr, server.h2_addr
+    );
+    let mut samples = Vec::new();
+    let mut interval = tokio::time::interval(args.interval);
+    // the first tick is immediate
+    interval.tick().await;
+    while start.elapsed() < args.duration {
+        interval.tick().await;
+        let sample = server.sample(start, &stats);
+        println!("{sample}");
+        samples.push(sample);
+    }
+
+    stop.set(true);
+    for worker in workers {
+        _ = worker.await;
+    }
+    // connections take a moment to notice their peer is gone
+    let settle_deadline = Instant::now() + Duration::from_secs(5);
+    let after = loop {
+        let sample = server.sample(start, &stats);
+        let settled = sample.connections == 0 && sample.bufs_in_use <= baseline.bufs_in_use;
+        if settled || Instant::now() >= settle_deadline {
+            break sample;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+    println!("after the load: {after}");
+
+    let mut failures = Vec::new();
+
+    let warmup = args.warmup.unwrap_or(args.duration / 5);
+    let steady: Vec<Sample> = samples
+        .into_iter()
+        .filter(|sample| sample.elapsed >= warmup)
+        .collect();
+    for metric in samples::metrics(args.rss_slack_mib) {
+        match samples::grows(&metric, &steady) {
+            Some([a, b, c]) => failures.push(format!(
+                "{} keeps growing: {a:.1}, then {b:.1}, then {c:.1} on average",
+                metric.name
+            )),
+            None => println!("{}: no steady growth", metric.name),
+        }
+    }
+    if steady.len() < 6 {
+        println!(
+            "WARN only {} samples after warmup, too few to look for growth: run longer",
+            steady.len()
+        );
+    }
+
+    // nothing should be left once the clients are gone
+    if after.connections != 0 {
+        failures.push(format!(
+            "{} connections still served after the load stopped",
+            after.connections
+        ));
+    }
+    if after.bufs_in_use > baseline.bufs_in_use {
+        failures.push(format!(
+            "{} buffers still in use after the load stopped, {} before it started",
+            after.bufs_in_use, baseline.bufs_in_use
+        ));
+    }
+    if stats.failures.get() > 0 {
+        failures.push(format!(
+            "{} requests out of {} failed",
+            stats.failures.get(),
+            stats.requests.get()
+        ));
+    }
+
+    for failure in &failures {
+        println!("FAIL {failure}");
+    }
+    println!(
+        "{} requests in {:?}, {}",
+        stats.requests.get(),
+        args.duration,
+        if failures.is_empty() { "PASS" } else { "FAIL" }
+    );
+    Ok(failures.is_empty())
+}
+
+/// An h1 listener and an h2 (prior knowledge) one, serving [driver::SoakDriver]
+struct Server {
+    h1_addr: SocketAddr,
+    h2_addr: SocketAddr,
+    /// Handles of the h2 connections being served, by connection number
+    h2_handles: Rc<RefCell<HashMap<u64, ConnectionHandle>>>,
+    /// Connections being served, over both protocols
+    connections: Rc<Cell<usize>>,
+}
+
+impl Server {
+    async fn spawn() -> color_eyre::Result<Self> {
+        let h1_ln = TcpListener::bind("127.0.0.1:0".parse()?).await?;
+        let h2_ln = TcpListener::bind("127.0.0.1:0".parse()?).await?;
+        let server = Server {
+            h1_addr: h1_ln.local_addr()?,
+            h2_addr: h2_ln.local_addr()?,
+            h2_handles: Default::default(),
+            connections: Default::default(),
+        };
+        let driver = Rc::new(driver::SoakDriver);
+
+        let h1_conf = Rc::new(fluke::h1::ServerConf::default());
+        let connections = server.connections.clone();
+        let h1_driver = driver.clone();
+        tokio::task::spawn_local(async move {
+            loop {
+                let (stream, peer) = h1_ln.accept().await.unwrap();
+                let conf = h1_conf.clone();
+                let driver = h1_driver.clone();
+                let connections = connections.clone();
+                tokio::task::spawn_local(async move {
+                    connections.set(connections.get() + 1);
+                    let client_buf = RollMut::alloc().unwrap();
+                    if let Err(e) = fluke::h1::serve(stream, conf, client_buf, driver).await {
+                        tracing::debug!("error serving {peer} over h1: {e:?}");
+                    }
+                    connections.set(connections.get() - 1);
+                });
+            }
+        });
+
+        let h2_conf = Rc::new(fluke::h2::ServerConf::default());
+        let connections = server.connections.clone();
+        let handles = server.h2_handles.clone();
+        tokio::task::spawn_local(async move {
+            for id in 0.. {
+                let (stream, peer) = h2_ln.accept().await.unwrap();
+                let conf = h2_conf.clone();
+                let driver = driver.clone();
+                let connections = connections.clone();
+                let handles = handles.clone();
+                tokio::task::spawn_local(async move {
+                    connections.set(connections.get() + 1);
+                    let handle = ConnectionHandle::default();
+                    handles.borrow_mut().insert(id, handle.clone());
+                    let client_buf = RollMut::alloc().unwrap();
+                    if let Err(e) =
+                        fluke::h2::serve_with_handle(stream, conf, client_buf, driver, handle).await
+                    {
+                        tracing::debug!("error serving {peer} over h2: {e:?}");
+                    }
+                    handles.borrow_mut().remove(&id);
+                    connections.set(connections.get() - 1);
+                });
+            }
+        });
+
+        Ok(server)
+    }
+
+    fn sample(&self, start: Instant, stats: &LoadStats) -> Sample {
+        let handles = self.h2_handles.borrow();
+        Sample {
+            elapsed: start.elapsed(),
+            rss: samples::rss(),
+            bufs_in_use: NUM_BUF as usize - num_free_bufs(),
+            open_streams: handles.values().map(|h| h.open_streams()).sum(),
+            running_handlers: handles.values().map(|h| h.running_handlers()).sum(),
+            connections: self.connections.get(),
+            requests: stats.requests.get(),
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::{fmt, time::Duration};
+
+/// What's measured every sampling interval
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub elapsed: Duration,
+    /// Resident set size of the process, in bytes, if the platform tells
+    pub rss: Option<u64>,
+    /// Buffers taken from the pool, client and server side alike
+    pub bufs_in_use: usize,
+    /// Summed over the h2 connections being served
+    pub open_streams: usize,
+    /// Summed over the h2 connections being served
+    pub running_handlers: usize,
+    /// Connections being served, over both protocols
+    pub connections: usize,
+    /// Requests sent so far, failed ones included
+    pub requests: u64,
+}
+
+impl fmt::Display for Sample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>7.1?}", self.elapsed)?;
+        match self.rss {
+            Some(rss) => write!(f, " rss={:>8.1}MiB", rss as f64 / MIB)?,
+            None => write!(f, " rss=?")?,
+        }
+        write!(
+            f,
+            " bufs={:>6} streams={:>4} handlers={:>4} conns={:>3} requests={}",
+            self.bufs_in_use,
+            self.open_streams,
+            self.running_handlers,
+            self.connections,
+            self.requests
+        )
+    }
+}
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+/// The resident set size of this process, from `/proc/self/status`
+pub fn rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: u64 = line
+        .trim_start_matches("VmRSS:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+/// A metric that must not keep growing while the load is steady
+pub struct Metric {
+    pub name: &'static str,
+    pub get: fn(&Sample) -> Option<f64>,
+    /// Growth below this is noise (allocator caches, windows opening up)
+    pub slack: f64,
+}
+
+pub fn metrics(rss_slack_mib: f64) -> [Metric; 4] {
+    [
+        Metric {
+            name: "rss",
+            get: |s| s.rss.map(|rss| rss as f64 / MIB),
+            slack: rss_slack_mib,
+        },
+        Metric {
+            name: "bufs in use",
+            get: |s| Some(s.bufs_in_use as f64),
+            slack: 256.0,
+        },
+        Metric {
+            name: "open streams",
+            get: |s| Some(s.open_streams as f64),
+            slack: 4.0,
+        },
+        Metric {
+            name: "running handlers",
+            get: |s| Some(s.running_handlers as f64),
+            slack: 4.0,
+        },
+    ]
+}
+
+/// Checks for monotonic growth: the samples are split in three windows, and
+/// a metric grows if each window's average is above the previous one's, by
+/// more than `slack` overall. Samples taken during warmup must be left out:
+/// pools and caches fill up then.
+///
+/// Returns the averages if the metric grows, and `None` if it doesn't or if
+/// there aren't enough samples to tell.
+pub fn grows(metric: &Metric, samples: &[Sample]) -> Option<[f64; 3]> {
+    let values: Vec<f64> = samples.iter().filter_map(metric.get).collect();
+    if values.len() < 6 {
+        return None;
+    }
+    let third = values.len() / 3;
+    let avg = |window: &[f64]| window.iter().sum::<f64>() / window.len() as f64;
+    let avgs = [
+        avg(&values[..third]),
+        avg(&values[third..values.len() - third]),
+        avg(&values[values.len() - third..]),
+    ];
+    let grows = avgs[0] < avgs[1] && avgs[1] < avgs[2] && avgs[2] - avgs[0] > metric.slack;
+    grows.then_some(avgs)
+}
@@ -10,8 +10,12 @@ use color_eyre::eyre;
 use fluke::{
     buffet::RollMut,
     h1, h2,
-    maybe_uring::{io::IntoHalves, tokio_uring::net::TcpStream},
+    maybe_uring::{
+        io::{IntoHalves, TlsInfo, WithTls},
+        tokio_uring::net::TcpStream,
+    },
     Body, Encoder, ExpectResponseHeaders, Method, Request, Responder, ResponseDone, ServerDriver,
+    TransportSecurity,
 };
 use http::Version;
 use ktls::CorkStream;
@@ -71,6 +75,15 @@ async fn async_main() -> eyre::Result<()> {
     let h1_conf = Rc::new(h1::ServerConf::default());
     let h2_conf = Rc::new(h2::ServerConf::default());
 
+    let tls_h1_conf = Rc::new(h1::ServerConf {
+        transport_security: TransportSecurity::Tls,
+        ..Default::default()
+    });
+    let tls_h2_conf = Rc::new(h2::ServerConf {
+        transport_security: TransportSecurity::Tls,
+        ..Default::default()
+    });
+
     let pt_h1_loop = {
         let h1_conf = h1_conf.clone();
 
@@ -117,8 +130,8 @@ async fn async_main() -> eyre::Result<()> {
         while let Ok((stream, remote_addr)) = tls_ln.accept().await {
             fluke::maybe_uring::spawn({
                 let acceptor = acceptor.clone();
-                let h1_conf = h1_conf.clone();
-                let h2_conf = h2_conf.clone();
+                let h1_conf = tls_h1_conf.clone();
+                let h2_conf = tls_h2_conf.clone();
                 async move {
                     if let Err(e) =
                         handle_tls_conn(acceptor, stream, remote_addr, h1_conf, h2_conf).await
@@ -182,6 +195,10 @@ async fn handle_tls_conn(
         .alpn_protocol()
         .and_then(|p| std::str::from_utf8(p).ok().map(|s| s.to_string()));
     debug!(?alpn_proto, "Performed TLS handshake");
+    let tls = TlsInfo {
+        alpn_protocol: sc.alpn_protocol().map(|p| p.to_vec()),
+        server_name: sc.server_name().map(|s| s.to_string()),
+    };
 
     let stream = ktls::config_ktls_server(stream).await?;
 
@@ -190,7 +207,12 @@ async fn handle_tls_conn(
     let drained = drained.unwrap_or_default();
     debug!("{} bytes already decoded by rustls", drained.len());
 
-    let stream = stream.to_uring_tcp_stream()?;
+    // the kernel decrypts from now on, so this is a plain TCP socket as
+    // far as fluke can tell
+    let stream = WithTls {
+        inner: stream.to_uring_tcp_stream()?,
+        tls,
+    };
 
     let mut buf = RollMut::alloc()?;
     buf.put(&drained[..])?;
@@ -200,11 +222,11 @@ async fn handle_tls_conn(
     match alpn_proto.as_deref() {
         Some("h2") => {
             info!("Using HTTP/2");
-            fluke::h2::serve(stream.into_halves(), h2_conf, buf, Rc::new(driver)).await?;
+            fluke::h2::serve(stream, h2_conf, buf, Rc::new(driver)).await?;
         }
         Some("http/1.1") | None => {
             info!("Using HTTP/1.1");
-            fluke::h1::serve(stream.into_halves(), h1_conf, buf, driver).await?;
+            fluke::h1::serve(stream, h1_conf, buf, driver).await?;
         }
         Some(other) => return Err(eyre::eyre!("Unsupported ALPN protocol: {}", other)),
     }
@@ -257,12 +279,6 @@ where
 {
     type Return = Responder<E, ResponseDone>;
 
-    async fn on_informational_response(&mut self, _res: fluke::Response) -> eyre::Result<()> {
-        // ignore informational responses
-
-        Ok(())
-    }
-
     async fn on_final_response(
         self,
         res: fluke::Response,
@@ -296,12 +312,6 @@ struct SampleCDriver {}
 impl h1::ClientDriver for SampleCDriver {
     type Return = ();
 
-    async fn on_informational_response(&mut self, _res: fluke::Response) -> eyre::Result<()> {
-        // ignore informational responses
-
-        Ok(())
-    }
-
     async fn on_final_response(
         self,
         res: fluke::Response,
@@ -339,8 +349,13 @@ async fn sample_http_request() -> color_eyre::Result<()> {
     let req = Request {
         method: Method::Get,
         uri: "http://httpbingo.org/image/jpeg".parse().unwrap(),
+        protocol: None,
         version: Version::HTTP_11,
         headers: Default::default(),
+        transport_security: Default::default(),
+        conn_info: Default::default(),
+        id: Default::default(),
+        correlation_id: None,
     };
 
     let (transport, _) = h1::request(transport.into_halves(), req, &mut (), driver).await?;